@@ -0,0 +1,32 @@
+fn main() {
+    // Node addons resolve the N-API symbols from the host process at load time
+    #[cfg(feature = "node")]
+    napi_build::setup();
+
+    // protox compiles the contract in Rust, so building the gRPC server doesn't need protoc
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/heatmap.proto");
+        let descriptors = protox::compile(["proto/heatmap.proto"], ["proto"]).expect("invalid proto/heatmap.proto");
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("failed to generate gRPC code");
+    }
+
+    // Clients of the Triton and TF Serving subsets for model servers called over gRPC
+    #[cfg(feature = "grpc-client")]
+    {
+        println!("cargo:rerun-if-changed=proto/triton.proto");
+        println!("cargo:rerun-if-changed=proto/tensorflow_serving.proto");
+        let descriptors = protox::compile(["proto/triton.proto", "proto/tensorflow_serving.proto"], ["proto"])
+            .expect("invalid model server protos");
+        tonic_prost_build::configure()
+            .build_server(false)
+            .build_transport(false)
+            // Output maps iterate in name order
+            .btree_map(".")
+            .compile_fds(descriptors)
+            .expect("failed to generate gRPC client code");
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * C API for rust-dl-heatmap-processing.
+ *
+ * Build the shared library with `cargo build --release --lib` and link against
+ * target/release/librust_dl_heatmap_processing.{so,dylib} (or the .dll on Windows).
+ */
+#ifndef HEATMAP_PROCESSING_H
+#define HEATMAP_PROCESSING_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes of hm_process */
+#define HM_OK                   0
+#define HM_ERR_INVALID_ARGUMENT 1
+#define HM_ERR_DICOM_DECODE     2
+#define HM_ERR_HEATMAP_LOAD     3
+#define HM_ERR_SHAPE_MISMATCH   4
+#define HM_ERR_RENDER           5
+#define HM_ERR_IO               6
+#define HM_ERR_SERVICE          7
+#define HM_ERR_CANCELLED        8
+#define HM_ERR_SERVER           9
+#define HM_ERR_TIMEOUT          10
+#define HM_ERR_TOO_LARGE        11
+#define HM_ERR_FORBIDDEN        12
+#define HM_ERR_INFERENCE        13
+#define HM_ERR_PLUGIN           14
+#define HM_ERR_QUALITY_CONTROL  15
+#define HM_ERR_PANIC            99
+
+/* Processing options. NULL strings select the defaults. */
+typedef struct hm_options {
+    const char *colormap;       /* red (default), hot, jet, viridis, plasma, coolwarm */
+    const char *normalization;  /* minmax (default), zscore, percentile */
+    const char *heatmap_format; /* json (default), csv, bin */
+    const char *blend;          /* alpha (default), additive, screen */
+    float opacity;              /* 0.0 to 1.0 */
+    float threshold;            /* hide normalized values below this; negative disables */
+} hm_options;
+
+/* Fill options with the library defaults. */
+void hm_options_default(hm_options *options);
+
+/*
+ * Decode a DICOM Part 10 file, overlay the heatmap and return the fused PNG.
+ * An empty heatmap (heatmap_len == 0) renders the default gradient.
+ * options may be NULL. On HM_OK, *out_png must be released with hm_free_buffer.
+ */
+int hm_process(const uint8_t *dicom_bytes, size_t dicom_len,
+               const uint8_t *heatmap_bytes, size_t heatmap_len,
+               const hm_options *options,
+               uint8_t **out_png, size_t *out_len);
+
+/* Release a buffer returned by hm_process. */
+void hm_free_buffer(uint8_t *data, size_t len);
+
+/* Message for the last error on this thread, or NULL. Valid until the next call. */
+const char *hm_last_error(void);
+
+/* Library version string. */
+const char *hm_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* HEATMAP_PROCESSING_H */
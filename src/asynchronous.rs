@@ -0,0 +1,188 @@
+//! Tokio-based async variants of the IO-heavy stages, built with `--features async`.
+//!
+//! File reads go through `tokio::fs`; DICOM parsing, rendering and encoding run on the
+//! blocking thread pool so callers never stall the async runtime, optionally bounded by a
+//! [`CpuPool`].
+
+use image::RgbaImage;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+use crate::dicom_io::{open_dicom_bytes, DicomFile};
+use crate::error::{Error, Result};
+use crate::heatmap::{HeatmapRegistry, LoadedHeatmap};
+use crate::output::encode_png;
+use crate::pipeline::{HeatmapInput, HeatmapPipeline, ImageSource, PipelineResult, Prefetched, PrefetchedHeatmap};
+use crate::progress::Stage;
+
+async fn read(path: &Path) -> Result<Vec<u8>> {
+    tokio::fs::read(path).await.map_err(|e| Error::io(path, e))
+}
+
+/// Run CPU-bound work on the blocking pool, reporting a panicked task as a render error
+async fn blocking<T: Send + 'static>(task: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    // Stage spans created on the blocking thread belong to the caller's trace
+    #[cfg(feature = "telemetry")]
+    let task = {
+        let cx = opentelemetry::Context::current();
+        move || {
+            let _attached = cx.attach();
+            task()
+        }
+    };
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| Error::Render(format!("Background task failed: {}", e)))?
+}
+
+/// Bound on the CPU-bound tasks run at once on the blocking pool; cheap to clone, and clones
+/// share their slots. The default runs every task right away.
+#[derive(Debug, Clone, Default)]
+pub struct CpuPool {
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl CpuPool {
+    /// Pool running at most `threads` tasks at once, the others waiting for a free slot
+    pub fn new(threads: usize) -> Self {
+        CpuPool { slots: Some(Arc::new(Semaphore::new(threads.max(1)))) }
+    }
+
+    /// Run `task` on the blocking pool once a slot is free
+    pub async fn run<T: Send + 'static>(&self, task: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+        let _slot = match &self.slots {
+            Some(slots) => slots.acquire().await.ok(),
+            None => None,
+        };
+        blocking(task).await
+    }
+}
+
+/// Read and parse a DICOM file
+pub async fn open_dicom(path: &Path) -> Result<DicomFile> {
+    let bytes = read(path).await?;
+    blocking(move || open_dicom_bytes(&bytes)).await
+}
+
+/// Read a heatmap file and parse it with the source registered for its extension
+pub async fn load_heatmap(registry: Arc<HeatmapRegistry>, path: &Path) -> Result<LoadedHeatmap> {
+    let origin = path.display().to_string();
+    let format = path.extension()
+        .and_then(|ext| ext.to_str())
+        .ok_or_else(|| Error::heatmap(&origin, "Could not determine file extension"))?
+        .to_string();
+    let bytes = read(path).await?;
+    blocking(move || registry.load_bytes(&bytes, &format, &origin)).await
+}
+
+/// Encode an image as PNG and write it to disk
+pub async fn save_png(image: RgbaImage, png_path: PathBuf) -> Result<()> {
+    let bytes = blocking(move || encode_png(&image)).await?;
+    tokio::fs::write(&png_path, bytes).await.map_err(|e| Error::io(&png_path, e))?;
+    info!("Saved fused PNG: {}", png_path.display());
+    Ok(())
+}
+
+impl HeatmapPipeline {
+    /// Async version of [`HeatmapPipeline::run`]: inputs are read with `tokio::fs` and service
+    /// heatmaps are fetched concurrently, then the remaining stages and outputs run on the blocking pool
+    pub async fn run_async(&self) -> Result<PipelineResult> {
+        let mut prefetched = Prefetched::default();
+        if let ImageSource::DicomFile(path) = self.source() {
+            prefetched.source = Some(read(path).await?);
+        }
+        let mut inputs = self.heatmap().into_iter()
+            .chain(self.overlays().iter().map(|overlay| &overlay.heatmap))
+            .chain(self.baseline());
+        let dicom = match (self.source(), &prefetched.source) {
+            (ImageSource::DicomBytes(bytes), _) | (ImageSource::DicomFile(_), Some(bytes)) if inputs.any(is_service) => {
+                Some(Arc::new(bytes.clone()))
+            }
+            _ => None,
+        };
+
+        // Start every read and service call before waiting on any of them
+        let primary = self.heatmap().map(|input| self.prefetch(input, &dicom));
+        let overlays: Vec<_> = self.overlays().iter().map(|overlay| self.prefetch(&overlay.heatmap, &dicom)).collect();
+        let baseline = self.baseline().map(|input| self.prefetch(input, &dicom));
+        if let Some(task) = primary {
+            prefetched.heatmap = joined(task).await?;
+        }
+        for task in overlays {
+            prefetched.overlays.push(joined(task).await?);
+        }
+        if let Some(task) = baseline {
+            prefetched.baseline = joined(task).await?;
+        }
+        if self.deadline().is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::Timeout { stage: Stage::Heatmap.name() });
+        }
+
+        let pipeline = self.clone();
+        self.cpu_pool().run(move || pipeline.run_prefetched(prefetched)).await
+    }
+
+    /// Read a heatmap file or call a service in the background, for every member of an ensemble
+    /// too; other inputs need no prefetching
+    #[cfg_attr(not(feature = "service"), allow(clippy::only_used_in_recursion))]
+    fn prefetch(&self, input: &HeatmapInput, dicom: &Option<Arc<Vec<u8>>>) -> JoinHandle<PrefetchedHeatmap> {
+        match input {
+            HeatmapInput::File(path) => {
+                let path = path.clone();
+                spawn(async move { PrefetchedHeatmap { file: Some(read(&path).await), ..PrefetchedHeatmap::default() } })
+            }
+            #[cfg(feature = "service")]
+            HeatmapInput::Service(service) => {
+                let (client, service, registry) = (self.client().clone(), service.clone(), self.heatmap_registry().clone());
+                let (dicom, deadline) = (dicom.clone(), self.deadline());
+                spawn(async move {
+                    let fetched = match dicom {
+                        Some(dicom) => client.fetch_heatmap_until(&service, dicom, &registry, deadline).await,
+                        None => Err(Error::InvalidOption(format!("Service {} requires a DICOM source", service.name))),
+                    };
+                    PrefetchedHeatmap { fetched: Some(fetched), ..PrefetchedHeatmap::default() }
+                })
+            }
+            HeatmapInput::Ensemble { members, .. } => {
+                let tasks: Vec<_> = members.iter().map(|(member, _)| self.prefetch(member, dicom)).collect();
+                spawn(async move {
+                    let mut members = Vec::with_capacity(tasks.len());
+                    for task in tasks {
+                        // A member whose task panicked is loaded as if nothing was prefetched
+                        members.push(task.await.unwrap_or_default());
+                    }
+                    PrefetchedHeatmap { members, ..PrefetchedHeatmap::default() }
+                })
+            }
+            _ => tokio::spawn(async { PrefetchedHeatmap::default() }),
+        }
+    }
+}
+
+fn is_service(input: &HeatmapInput) -> bool {
+    match input {
+        #[cfg(feature = "service")]
+        HeatmapInput::Service(_) => true,
+        HeatmapInput::Ensemble { members, .. } => members.iter().any(|(member, _)| is_service(member)),
+        _ => false,
+    }
+}
+
+/// `tokio::spawn` keeping the current trace context in the spawned task
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(feature = "telemetry")]
+    let future = opentelemetry::context::FutureExt::with_context(future, opentelemetry::Context::current());
+    tokio::spawn(future)
+}
+
+async fn joined<T>(task: JoinHandle<T>) -> Result<T> {
+    task.await.map_err(|e| Error::Render(format!("Background task failed: {}", e)))
+}
@@ -0,0 +1,125 @@
+//! Reproducibility audit log: one JSON line appended per rendering, recording who produced it
+//! and when, the SHA-256 of its inputs and outputs, the models behind its heatmaps and every
+//! parameter of the rendering, for the clinical traceability of AI-derived images.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dicom_io::{utc_date, utc_time};
+use crate::error::{Error, Result};
+use crate::heatmap::HeatmapMetadata;
+use crate::provenance::HashedInput;
+use crate::spec::PipelineSpec;
+
+/// Heatmap attributes that identify the model, service or plugin that produced a heatmap
+pub const MODEL_ATTRIBUTES: [&str; 12] = [
+    "model", "model_version", "model_sha256", "service", "method", "precision", "preprocess", "tta", "fusion", "members",
+    "plugin", "plugin_sha256",
+];
+
+/// Append-only JSONL file the audit records of renderings are written to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditLog {
+    pub path: PathBuf,
+    /// Operator recorded with each rendering; None records the `USER` (or `USERNAME`) of the
+    /// process
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditLog { path: path.into(), user: None }
+    }
+
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Operator of the renderings: the configured user, or else the user running the process
+    pub fn operator(&self) -> Option<String> {
+        self.user.clone()
+            .or_else(|| std::env::var("USER").ok())
+            .or_else(|| std::env::var("USERNAME").ok())
+            .filter(|user| !user.is_empty())
+    }
+
+    /// Append `record` as one line; existing records are never rewritten
+    pub fn append(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)
+            .map_err(|e| Error::Render(format!("Failed to serialize audit record: {}", e)))?;
+        line.push('\n');
+        // One write per record, so records of concurrent renderings don't interleave
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(|e| Error::io(&self.path, e))?;
+        file.write_all(line.as_bytes()).map_err(|e| Error::io(&self.path, e))
+    }
+}
+
+/// Model behind one heatmap layer of a rendering
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelRecord {
+    /// `heatmap`, `overlay 1`, ... or `baseline`
+    pub layer: String,
+    pub format: String,
+    /// Heatmap file, model or service the layer was loaded from
+    pub path: String,
+    /// The layer's [`MODEL_ATTRIBUTES`]
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl ModelRecord {
+    pub fn new(layer: &str, metadata: &HeatmapMetadata) -> Self {
+        ModelRecord {
+            layer: layer.to_string(),
+            format: metadata.format.clone(),
+            path: metadata.path.clone(),
+            attributes: metadata.attributes.iter()
+                .filter(|(key, _)| MODEL_ATTRIBUTES.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Audit record of one rendering
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// UTC time the rendering finished, as `YYYY-MM-DDTHH:MM:SSZ`
+    pub timestamp: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    /// Version of this tool
+    pub version: String,
+    /// Instance UIDs of a DICOM source
+    pub study_instance_uid: Option<String>,
+    pub series_instance_uid: Option<String>,
+    pub sop_instance_uid: Option<String>,
+    pub inputs: Vec<HashedInput>,
+    pub models: Vec<ModelRecord>,
+    /// Every parameter of the rendering
+    pub parameters: PipelineSpec,
+    /// SHA-256 of each file written, by path
+    pub outputs: BTreeMap<PathBuf, String>,
+    pub warnings: Vec<String>,
+}
+
+/// UTC time now as `YYYY-MM-DDTHH:MM:SSZ`
+pub fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    let (date, time) = (utc_date(now), utc_time(now));
+    format!("{}-{}-{}T{}:{}:{}Z", &date[..4], &date[4..6], &date[6..], &time[..2], &time[2..4], &time[4..])
+}
+
+/// Name of the machine, from `HOSTNAME`, `/etc/hostname` or `COMPUTERNAME`
+pub fn host_name() -> Option<String> {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+}
@@ -0,0 +1,526 @@
+//! Batch processing of DICOM directories with incremental results and a failures report.
+
+use image::RgbaImage;
+use log::{info, warn};
+use ndarray::Array2;
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::buffers::BufferPool;
+use crate::colormap::ColorMap;
+use crate::dicom_io::{decode_dicom_pixel_data_into, image_dimensions, open_dicom, DicomFile};
+#[cfg(feature = "onnx")]
+use crate::dicom_io::decode_dicom_pixel_data;
+#[cfg(feature = "dicomweb")]
+use crate::dicom_io::open_dicom_bytes;
+#[cfg(feature = "dimse")]
+use crate::config::DimseDestination;
+#[cfg(feature = "dimse")]
+use crate::dicom_io::{secondary_capture, InstanceUids};
+#[cfg(feature = "dimse")]
+use crate::dimse;
+#[cfg(feature = "dicomweb")]
+use crate::dicomweb::{DicomWebClient, InstanceRef};
+use crate::error::{Error, Result};
+use crate::heatmap::load_heatmap_data;
+use crate::provenance::HashedInput;
+#[cfg(feature = "dimse")]
+use crate::provenance::sha256_dicom;
+#[cfg(feature = "onnx")]
+use crate::inference::OnnxModel;
+use crate::normalize::Normalization;
+use crate::output::{save_png_with, PngOptions};
+use crate::render::render_heatmap_overlay_with;
+
+pub const BATCH_RESULTS_FILE: &str = "results.jsonl";
+
+pub const BATCH_FAILURES_FILE: &str = "failures.json";
+
+/// Settings shared by every item of a batch run
+pub struct BatchSettings {
+    pub output_dir: PathBuf,
+    pub heatmap_dir: Option<PathBuf>,
+    pub colormap: ColorMap,
+    pub normalization: Normalization,
+    pub opacity: f32,
+    pub resume: bool,
+    /// How the fused images are encoded
+    pub png: PngOptions,
+    /// Destinations every result is also sent to as a Secondary Capture instance
+    #[cfg(feature = "dimse")]
+    pub store_to: Vec<DimseDestination>,
+}
+
+/// Counts reported at the end of a batch run
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Processing stage of a batch item, recorded with each failure
+#[derive(Debug, Clone, Copy)]
+enum BatchStage {
+    #[cfg(feature = "dicomweb")]
+    Retrieve,
+    Open,
+    Heatmap,
+    Decode,
+    #[cfg(feature = "onnx")]
+    Infer,
+    Render,
+    Save,
+    #[cfg(feature = "dimse")]
+    Store,
+}
+
+impl BatchStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "dicomweb")]
+            BatchStage::Retrieve => "retrieve",
+            BatchStage::Open => "open",
+            BatchStage::Heatmap => "heatmap",
+            BatchStage::Decode => "decode",
+            #[cfg(feature = "onnx")]
+            BatchStage::Infer => "infer",
+            BatchStage::Render => "render",
+            BatchStage::Save => "save",
+            #[cfg(feature = "dimse")]
+            BatchStage::Store => "store",
+        }
+    }
+}
+
+/// Process every DICOM file in `input_dir`, appending one JSON line per item to
+/// results.jsonl as soon as it finishes and writing failures.json at the end
+pub fn run_batch(input_dir: &Path, settings: &BatchSettings) -> Result<BatchSummary> {
+    let items = file_items(input_dir)?;
+    info!("Batch mode: {} input file(s) in {}", items.len(), input_dir.display());
+    run_items("file", items, settings, |input, stem, output, stage, buffers| {
+        stage.set(BatchStage::Open);
+        let obj = open_dicom(input)?;
+        let heatmap = find_batch_heatmap(stem, settings.heatmap_dir.as_deref());
+        let source = || HashedInput::file("source", input);
+        process_batch_item(&obj, source, heatmap.as_deref(), output, settings, stage, buffers)
+    })
+}
+
+/// Run `model` on every DICOM file in `input_dir`, `batch_size` images per call, and render the
+/// heatmap of class `target` or else of each image's top class, with the same results.jsonl and
+/// failures.json as [`run_batch`]
+///
+/// Each result records the top class and score under `class` and `score`, and the class drawn
+/// under `heatmap_class`. Files that can't be decoded drop out of their batch; a failed model
+/// run fails every item of the batch.
+#[cfg(feature = "onnx")]
+pub fn run_inference_batch(
+    input_dir: &Path,
+    model: &OnnxModel,
+    batch_size: usize,
+    target: Option<usize>,
+    settings: &BatchSettings,
+) -> Result<BatchSummary> {
+    let items = file_items(input_dir)?;
+    info!("Inference batch: {} input file(s) in {}, {} per run", items.len(), input_dir.display(), batch_size);
+    let mut log = BatchLog::open("file", settings)?;
+    let items: Vec<BatchItem<PathBuf>> = items.into_iter().filter(|item| log.start(&item.name)).collect();
+    let mut buffers = BatchBuffers::new();
+    
+    for chunk in items.chunks(batch_size.max(1)) {
+        let mut decoded = Vec::with_capacity(chunk.len());
+        for item in chunk {
+            let output = settings.output_dir.join(format!("{}.png", item.stem));
+            let stage = Cell::new(BatchStage::Open);
+            let outcome = guarded(|| {
+                let obj = open_dicom(&item.source)?;
+                let (rows, columns) = image_dimensions(&obj)?;
+                stage.set(BatchStage::Decode);
+                let image = decode_dicom_pixel_data(&obj, rows, columns)?;
+                Ok((obj, image))
+            });
+            match outcome {
+                Ok((obj, image)) => decoded.push((item, output, obj, image)),
+                Err(failure) => log.record(&item.name, &output, Err(failure), stage.get())?,
+            }
+        }
+        
+        let grays: Vec<_> = decoded.iter().map(|(_, _, _, image)| image::imageops::grayscale(image)).collect();
+        let inferences = match guarded(|| model.infer_batch(&grays, target)) {
+            Ok(inferences) => inferences,
+            Err(failure) => {
+                for (item, output, _, _) in &decoded {
+                    log.record(&item.name, output, Err(failure.clone()), BatchStage::Infer)?;
+                }
+                continue;
+            }
+        };
+        for ((item, output, obj, image), inference) in decoded.into_iter().zip(inferences) {
+            let top = inference.top_score();
+            let mut fields = Map::new();
+            fields.insert("class".to_string(), top.label.clone().into());
+            fields.insert("score".to_string(), top.score.into());
+            fields.insert("heatmap_class".to_string(), inference.scores[inference.class].label.clone().into());
+            let stage = Cell::new(BatchStage::Render);
+            let inputs = || vec![HashedInput::file("source", &item.source)];
+            let outcome = guarded(|| finish_batch_item(&obj, inputs, image, Some(inference.heatmap.data), &output, settings, &stage, &mut buffers));
+            log.record(&item.name, &output, outcome.map(|()| fields), stage.get())?;
+        }
+    }
+    
+    log.finish()
+}
+
+/// Process every instance matching the QIDO-RS `query` (e.g. `Modality=CR&StudyDate=today`),
+/// retrieving each with WADO-RS, with the same results.jsonl and failures.json as [`run_batch`]
+///
+/// Items are named by their WADO-RS path, and heatmaps are looked up by SOP Instance UID.
+#[cfg(feature = "dicomweb")]
+pub fn run_query_batch(client: &DicomWebClient, query: &str, settings: &BatchSettings) -> Result<BatchSummary> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::Server(format!("Failed to start async runtime: {}", e)))?;
+    let instances = runtime.block_on(client.search_instances(query))?;
+    info!("Query batch: {} instance(s) match '{}' on {}", instances.len(), query, client.url());
+    
+    let items: Vec<BatchItem<InstanceRef>> = instances
+        .into_iter()
+        .map(|instance| BatchItem { name: instance.path(), stem: instance.instance.clone(), source: instance })
+        .collect();
+    run_items("instance", items, settings, |instance, stem, output, stage, buffers| {
+        stage.set(BatchStage::Retrieve);
+        let bytes = runtime.block_on(client.retrieve(instance))?;
+        stage.set(BatchStage::Open);
+        let obj = open_dicom_bytes(&bytes)?;
+        let heatmap = find_batch_heatmap(stem, settings.heatmap_dir.as_deref());
+        let source = || HashedInput::bytes("source", &bytes);
+        process_batch_item(&obj, source, heatmap.as_deref(), output, settings, stage, buffers)
+    })
+}
+
+/// Process instances as they are received until `instances` ends, with the same results.jsonl
+/// and failures.json as [`run_batch`]
+///
+/// Items are named by their WADO-RS path as in [`run_query_batch`], so records from both line up.
+#[cfg(feature = "dimse")]
+pub fn run_received(instances: impl IntoIterator<Item = (InstanceUids, DicomFile)>, settings: &BatchSettings) -> Result<BatchSummary> {
+    let items = instances.into_iter().map(|(uids, obj)| BatchItem {
+        name: format!("studies/{}/series/{}/instances/{}", uids.study, uids.series, uids.sop_instance),
+        stem: uids.sop_instance,
+        source: obj,
+    });
+    run_items("instance", items, settings, |obj, stem, output, stage, buffers| {
+        let heatmap = find_batch_heatmap(stem, settings.heatmap_dir.as_deref());
+        // Received instances are in memory only, so their encoding is hashed
+        let source = || HashedInput { role: "source".to_string(), path: None, sha256: sha256_dicom(obj).ok() };
+        process_batch_item(obj, source, heatmap.as_deref(), output, settings, stage, buffers)
+    })
+}
+
+/// One input of a batch and where its result goes
+struct BatchItem<T> {
+    /// Name recorded in results.jsonl and matched when resuming
+    name: String,
+    /// Output file name without the `.png` extension
+    stem: String,
+    source: T,
+}
+
+/// Every DICOM file in `input_dir` as a batch item, sorted by path
+fn file_items(input_dir: &Path) -> Result<Vec<BatchItem<PathBuf>>> {
+    let mut inputs: Vec<PathBuf> = fs::read_dir(input_dir)
+        .map_err(|e| Error::io(input_dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_dicom_candidate(path))
+        .collect();
+    inputs.sort();
+    
+    Ok(inputs
+        .into_iter()
+        .map(|input| BatchItem {
+            name: input.display().to_string(),
+            stem: input.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default(),
+            source: input,
+        })
+        .collect())
+}
+
+/// Run `process` on every item with its stem and output path, skipping names already recorded
+/// as successful under `key` when resuming
+fn run_items<T>(
+    key: &str,
+    items: impl IntoIterator<Item = BatchItem<T>>,
+    settings: &BatchSettings,
+    process: impl Fn(&T, &str, &Path, &Cell<BatchStage>, &mut BatchBuffers) -> Result<()>,
+) -> Result<BatchSummary> {
+    let mut log = BatchLog::open(key, settings)?;
+    let mut buffers = BatchBuffers::new();
+    for BatchItem { name, stem, source } in items {
+        if !log.start(&name) {
+            continue;
+        }
+        let output = settings.output_dir.join(format!("{}.png", stem));
+        let stage = Cell::new(BatchStage::Open);
+        let outcome = guarded(|| process(&source, &stem, &output, &stage, &mut buffers));
+        log.record(&name, &output, outcome.map(|()| Map::new()), stage.get())?;
+    }
+    log.finish()
+}
+
+/// Buffers handed on from one batch item to the next, so consecutive images of the same size
+/// are decoded, resized and normalized without allocating them again
+struct BatchBuffers {
+    /// RGBA pixels of the previous item's fused image
+    pixels: Vec<u8>,
+    /// Resized heatmap and percentile scratch buffers
+    heatmaps: BufferPool,
+}
+
+impl BatchBuffers {
+    fn new() -> Self {
+        BatchBuffers { pixels: Vec::new(), heatmaps: BufferPool::new(2) }
+    }
+}
+
+/// Error kind and message of a failed item
+type Failure = (&'static str, String);
+
+/// Run `process`, catching panics so one bad item cannot take the rest of the batch down with it
+fn guarded<R>(process: impl FnOnce() -> Result<R>) -> std::result::Result<R, Failure> {
+    panic::catch_unwind(AssertUnwindSafe(process))
+        .map(|result| result.map_err(|e| (e.kind(), e.to_string())))
+        .unwrap_or_else(|payload| Err(("panic", format!("panic: {}", panic_message(payload.as_ref())))))
+}
+
+/// results.jsonl of a running batch, and the failures and counts for failures.json
+struct BatchLog<'a> {
+    /// Field items are named under
+    key: &'a str,
+    output_dir: &'a Path,
+    completed: HashSet<String>,
+    results_path: PathBuf,
+    results_file: File,
+    summary: BatchSummary,
+    failures: Vec<Value>,
+    total: usize,
+}
+
+impl<'a> BatchLog<'a> {
+    /// Start results.jsonl in the output directory, or continue it when resuming
+    fn open(key: &'a str, settings: &'a BatchSettings) -> Result<Self> {
+        fs::create_dir_all(&settings.output_dir).map_err(|e| Error::io(&settings.output_dir, e))?;
+        let results_path = settings.output_dir.join(BATCH_RESULTS_FILE);
+        
+        let completed = if settings.resume {
+            read_completed_items(&results_path, key)?
+        } else {
+            HashSet::new()
+        };
+        
+        let results_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(settings.resume)
+            .truncate(!settings.resume)
+            .open(&results_path)
+            .map_err(|e| Error::io(&results_path, e))?;
+        
+        Ok(BatchLog {
+            key,
+            output_dir: &settings.output_dir,
+            completed,
+            results_path,
+            results_file,
+            summary: BatchSummary { succeeded: 0, failed: 0, skipped: 0 },
+            failures: Vec::new(),
+            total: 0,
+        })
+    }
+    
+    /// Count item `name`, returning false if it is skipped as already processed
+    fn start(&mut self, name: &str) -> bool {
+        self.total += 1;
+        if self.completed.contains(name) {
+            info!("Skipping already processed item: {}", name);
+            self.summary.skipped += 1;
+            return false;
+        }
+        true
+    }
+    
+    /// Record the outcome of item `name`, with `fields` added to a successful record and the
+    /// stage it was in when it failed
+    fn record(&mut self, name: &str, output: &Path, outcome: std::result::Result<Map<String, Value>, Failure>, stage: BatchStage) -> Result<()> {
+        let record = match outcome {
+            Ok(fields) => {
+                self.summary.succeeded += 1;
+                let mut record = serde_json::json!({
+                    self.key: name,
+                    "status": "ok",
+                    "output": output.display().to_string(),
+                });
+                for (field, value) in fields {
+                    record[field] = value;
+                }
+                record
+            }
+            Err((kind, error)) => {
+                warn!("Batch item {} failed during {}: {}", name, stage.as_str(), error);
+                self.summary.failed += 1;
+                let failure = serde_json::json!({
+                    self.key: name,
+                    "stage": stage.as_str(),
+                    "kind": kind,
+                    "error": error,
+                });
+                self.failures.push(failure.clone());
+                let mut record = failure;
+                record["status"] = "failed".into();
+                record
+            }
+        };
+        
+        // Write and flush each result immediately so progress survives a crash
+        writeln!(self.results_file, "{}", record)
+            .and_then(|_| self.results_file.flush())
+            .map_err(|e| Error::io(&self.results_path, e))
+    }
+    
+    /// Write failures.json
+    fn finish(self) -> Result<BatchSummary> {
+        let failures_path = self.output_dir.join(BATCH_FAILURES_FILE);
+        let report = serde_json::json!({
+            "total": self.total,
+            "succeeded": self.summary.succeeded,
+            "failed": self.summary.failed,
+            "skipped": self.summary.skipped,
+            "failures": self.failures,
+        });
+        fs::write(&failures_path, format!("{:#}", report)).map_err(|e| Error::io(&failures_path, e))?;
+        info!("Failures report written to: {}", failures_path.display());
+        
+        Ok(self.summary)
+    }
+}
+
+/// Run the full pipeline for a single opened batch item, updating `stage` as it progresses;
+/// `source` hashes the item for the provenance of stored results
+fn process_batch_item(
+    obj: &DicomFile,
+    source: impl FnOnce() -> HashedInput,
+    heatmap: Option<&Path>,
+    output: &Path,
+    settings: &BatchSettings,
+    stage: &Cell<BatchStage>,
+    buffers: &mut BatchBuffers,
+) -> Result<()> {
+    let (rows, columns) = image_dimensions(obj)?;
+    
+    stage.set(BatchStage::Heatmap);
+    let heatmap_data = match heatmap {
+        Some(heatmap_path) => Some(load_heatmap_data(&heatmap_path.display().to_string())?),
+        None => None,
+    };
+    
+    stage.set(BatchStage::Decode);
+    let base_image = decode_dicom_pixel_data_into(obj, rows, columns, std::mem::take(&mut buffers.pixels))?;
+    
+    let inputs = || std::iter::once(source())
+        .chain(heatmap.map(|heatmap| HashedInput::file("heatmap", heatmap)))
+        .collect();
+    finish_batch_item(obj, inputs, base_image, heatmap_data, output, settings, stage, buffers)
+}
+
+/// Render, save and store a decoded batch item; `inputs` hashes its inputs, only when the result
+/// is stored
+#[allow(clippy::too_many_arguments)]
+fn finish_batch_item(
+    #[cfg_attr(not(feature = "dimse"), allow(unused_variables))] obj: &DicomFile,
+    #[cfg_attr(not(feature = "dimse"), allow(unused_variables))] inputs: impl FnOnce() -> Vec<HashedInput>,
+    base_image: RgbaImage,
+    heatmap_data: Option<Array2<f32>>,
+    output: &Path,
+    settings: &BatchSettings,
+    stage: &Cell<BatchStage>,
+    buffers: &mut BatchBuffers,
+) -> Result<()> {
+    stage.set(BatchStage::Render);
+    let fused_image = render_heatmap_overlay_with(
+        base_image,
+        heatmap_data,
+        &settings.colormap,
+        &settings.normalization,
+        settings.opacity,
+        &buffers.heatmaps,
+    );
+    
+    stage.set(BatchStage::Save);
+    save_png_with(&fused_image, output, &settings.png)?;
+    
+    #[cfg(feature = "dimse")]
+    if !settings.store_to.is_empty() {
+        stage.set(BatchStage::Store);
+        let capture = secondary_capture(obj, &fused_image, &inputs())?;
+        for destination in &settings.store_to {
+            dimse::store(destination, &capture)?;
+        }
+    }
+    
+    buffers.pixels = fused_image.into_raw();
+    Ok(())
+}
+
+/// Files considered DICOM inputs: `.dcm`/`.dicom` extensions or no extension at all
+pub(crate) fn is_dicom_candidate(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => matches!(ext.to_lowercase().as_str(), "dcm" | "dicom"),
+        None => true,
+    }
+}
+
+/// Locate the heatmap for a batch item by matching its output stem in the heatmap directory
+fn find_batch_heatmap(stem: &str, heatmap_dir: Option<&Path>) -> Option<PathBuf> {
+    let heatmap_dir = heatmap_dir?;
+    ["json", "csv", "bin", "npy"]
+        .iter()
+        .map(|ext| heatmap_dir.join(format!("{}.{}", stem, ext)))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Read the set of items (named under `key`) already recorded as successful in a previous run
+fn read_completed_items(results_path: &Path, key: &str) -> Result<HashSet<String>> {
+    let mut completed = HashSet::new();
+    if !results_path.exists() {
+        return Ok(completed);
+    }
+    
+    let file = File::open(results_path).map_err(|e| Error::io(results_path, e))?;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| Error::io(results_path, e))?;
+        // A crash mid-write can leave a truncated last line; ignore anything unparsable
+        if let Ok(record) = serde_json::from_str::<serde_json::Value>(&line)
+            && record["status"] == "ok"
+            && let Some(name) = record[key].as_str()
+        {
+            completed.insert(name.to_string());
+        }
+    }
+    
+    Ok(completed)
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
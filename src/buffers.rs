@@ -0,0 +1,80 @@
+//! Reusable heatmap buffers: pipelines resize and normalize heatmaps in buffers taken from a
+//! [`BufferPool`] and hand them back when the image is rendered, so a server rendering images of
+//! similar sizes stops allocating them once the pool is warm.
+
+use std::sync::{Arc, Mutex};
+
+/// Heatmap buffers kept for reuse; cheap to clone, and clones share their buffers. The default
+/// keeps none, so every buffer is allocated and freed as before.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    shared: Option<Arc<Shared>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    buffers: Mutex<Vec<Vec<f32>>>,
+    max_buffers: usize,
+}
+
+/// Buffers kept by a pool and the values they hold room for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub buffers: usize,
+    pub capacity: usize,
+}
+
+impl BufferPool {
+    /// Pool keeping up to `max_buffers` buffers handed back to it
+    pub fn new(max_buffers: usize) -> Self {
+        let shared = Shared { buffers: Mutex::new(Vec::with_capacity(max_buffers)), max_buffers };
+        BufferPool { shared: (max_buffers > 0).then(|| Arc::new(shared)) }
+    }
+
+    /// Empty buffer with room for at least `len` values: the smallest kept one that is big
+    /// enough, else the biggest kept one (which grows on first use), else a new one
+    pub fn take(&self, len: usize) -> Vec<f32> {
+        let Some(shared) = &self.shared else {
+            return Vec::with_capacity(len);
+        };
+        let mut buffers = shared.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let fitting = buffers.iter().enumerate()
+            .filter(|(_, buffer)| buffer.capacity() >= len)
+            .min_by_key(|(_, buffer)| buffer.capacity())
+            .or_else(|| buffers.iter().enumerate().max_by_key(|(_, buffer)| buffer.capacity()))
+            .map(|(index, _)| index);
+        let mut buffer = fitting.map(|index| buffers.swap_remove(index)).unwrap_or_default();
+        drop(buffers);
+        buffer.clear();
+        buffer.reserve(len);
+        buffer
+    }
+
+    /// Keep `buffer` for a later [`take`](Self::take); when the pool is full the smallest
+    /// buffer is dropped instead
+    pub fn give(&self, buffer: Vec<f32>) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffers = shared.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buffers.len() < shared.max_buffers {
+            buffers.push(buffer);
+        } else if let Some(smallest) = buffers.iter_mut().min_by_key(|kept| kept.capacity())
+            && smallest.capacity() < buffer.capacity()
+        {
+            *smallest = buffer;
+        }
+    }
+
+    /// Buffers kept right now
+    pub fn stats(&self) -> PoolStats {
+        let Some(shared) = &self.shared else {
+            return PoolStats::default();
+        };
+        let buffers = shared.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        PoolStats { buffers: buffers.len(), capacity: buffers.iter().map(Vec::capacity).sum() }
+    }
+}
@@ -0,0 +1,156 @@
+//! Bounded caches of results for repeat requests, keyed by the instance, the model versions and
+//! a hash of the pipeline settings, and of decoded images, keyed by the instance and frame.
+
+use image::GrayImage;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+
+/// What a result depends on: re-rendering the same instance with the same models and settings
+/// gives the same result
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub sop_instance: String,
+    /// Model version (or URL) of every service involved, or the hash of an uploaded heatmap
+    pub models: Vec<String>,
+    /// Hash of the settings applied to the results of the models
+    pub spec: u64,
+    /// Whose instance it is, the same as [`ImageKey::scope`]: the UID alone doesn't name the
+    /// same pixels when any client can send any UID
+    pub scope: String,
+}
+
+/// What a decoded image depends on: the same frame of the same instance decodes the same
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageKey {
+    pub sop_instance: String,
+    /// Frame of a multi-frame instance, from 0
+    pub frame: u32,
+    /// Whose instance it is, when the UID alone can't be trusted to name the same pixels, e.g.
+    /// the digest of an upload
+    pub scope: Option<String>,
+}
+
+/// Decoded 8-bit grayscale images, so re-rendering an instance with other colormaps or blend
+/// options skips decoding its pixel data
+pub type DecodedImageCache = ResultCache<Arc<GrayImage>, ImageKey>;
+
+/// Stable hash of `value`, the same across calls and processes of one build
+pub fn stable_hash(value: &impl Hash) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(value)
+}
+
+struct Entry<V> {
+    value: V,
+    stored: Instant,
+    /// Cache tick of the last lookup, so the least recently used entry goes first
+    used: u64,
+}
+
+struct Entries<V, K> {
+    entries: HashMap<K, Entry<V>>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Size and effectiveness of a [`ResultCache`] since it was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Results stored, including expired ones not dropped yet
+    pub entries: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    /// Lookups that found nothing or an expired result
+    pub misses: u64,
+}
+
+/// Results by [`CacheKey`] (or another key), at most `entries` of them and each for at most `ttl`
+pub struct ResultCache<V, K = CacheKey> {
+    config: CacheConfig,
+    entries: Mutex<Entries<V, K>>,
+}
+
+impl<V, K> std::fmt::Debug for ResultCache<V, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f.debug_struct("ResultCache").field("config", &self.config).field("len", &entries.entries.len()).finish()
+    }
+}
+
+impl<V: Clone, K: Clone + Eq + Hash> Default for ResultCache<V, K> {
+    /// A disabled cache, which stores nothing
+    fn default() -> Self {
+        ResultCache::new(&CacheConfig { entries: 0, ttl: Duration::ZERO, decoded_images: 0 })
+    }
+}
+
+impl<V: Clone, K: Clone + Eq + Hash> ResultCache<V, K> {
+    pub fn new(config: &CacheConfig) -> Self {
+        ResultCache { config: config.clone(), entries: Mutex::new(Entries { entries: HashMap::new(), tick: 0, hits: 0, misses: 0 }) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.entries > 0 && !self.config.ttl.is_zero()
+    }
+
+    /// The result stored under `key`, unless it expired
+    pub fn get(&self, key: &K) -> Option<V> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.tick += 1;
+        let tick = entries.tick;
+        let Some(entry) = entries.entries.get_mut(key) else {
+            entries.misses += 1;
+            return None;
+        };
+        if entry.stored.elapsed() >= self.config.ttl {
+            entries.entries.remove(key);
+            entries.misses += 1;
+            return None;
+        }
+        entry.used = tick;
+        let value = entry.value.clone();
+        entries.hits += 1;
+        Some(value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        CacheStats {
+            entries: entries.entries.len(),
+            capacity: self.config.entries,
+            ttl_secs: self.config.ttl.as_secs(),
+            hits: entries.hits,
+            misses: entries.misses,
+        }
+    }
+
+    /// Store `value` under `key`, dropping expired entries and then the least recently used
+    /// ones to stay within the configured size
+    pub fn insert(&self, key: K, value: V) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.tick += 1;
+        let tick = entries.tick;
+        let ttl = self.config.ttl;
+        entries.entries.retain(|_, entry| entry.stored.elapsed() < ttl);
+        entries.entries.insert(key, Entry { value, stored: Instant::now(), used: tick });
+        while entries.entries.len() > self.config.entries {
+            let Some(oldest) = entries.entries.iter().min_by_key(|(_, entry)| entry.used).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            entries.entries.remove(&oldest);
+        }
+    }
+}
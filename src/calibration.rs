@@ -0,0 +1,213 @@
+//! Calibration of class scores across a labeled batch: the reliability diagram compares the mean
+//! score in each score bin with the share of positive cases there, summarized as the expected
+//! calibration error (ECE), and can be rendered as a plot for deployment sign-off.
+
+use image::{Rgba, RgbaImage};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::Result;
+use crate::output::save_png;
+use crate::render::{blend_pixel, draw_annotations, Annotation, BlendMode};
+use crate::validation::CaseScore;
+
+/// Equal-width score bins of the reliability diagram when none are given
+pub const DEFAULT_CALIBRATION_BINS: usize = 10;
+
+/// Cases whose scores fall into one bin of the reliability diagram
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+    /// Mean score of the bin's cases (its confidence), None for an empty bin
+    pub mean_score: Option<f64>,
+    /// Share of the bin's cases that are positive (its accuracy), None for an empty bin
+    pub positive_rate: Option<f64>,
+}
+
+/// Reliability diagram and calibration errors of a batch of scores
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Bins from 0.0 to 1.0, the last one closed
+    pub bins: Vec<CalibrationBin>,
+    /// Expected calibration error: the case-weighted mean gap between each bin's mean score and
+    /// positive rate
+    pub ece: f64,
+    /// Maximum calibration error: the largest gap of a nonempty bin
+    pub mce: f64,
+    /// Mean squared difference between the scores and the labels
+    pub brier: f64,
+    /// Scores outside 0.0-1.0, counted in the first or last bin
+    pub out_of_range: u64,
+    /// Scores that aren't finite, such as NaN, left out of the bins and errors
+    #[serde(default)]
+    pub non_finite: u64,
+}
+
+/// Reliability diagram of the `cases` in `bins` equal-width bins (at least one) over 0.0-1.0;
+/// scores outside that range, e.g. logits, don't calibrate meaningfully and are clamped with a
+/// warning, and scores that aren't finite are left out. None without finite scores.
+pub fn calibrate(cases: &[CaseScore], bins: usize) -> Option<Calibration> {
+    let non_finite = cases.iter().filter(|case| !case.score.is_finite()).count() as u64;
+    if non_finite > 0 {
+        warn!("{} score(s) that aren't finite were left out of the calibration", non_finite);
+    }
+    let cases: Vec<&CaseScore> = cases.iter().filter(|case| case.score.is_finite()).collect();
+    if cases.is_empty() {
+        return None;
+    }
+    let bins = bins.max(1);
+    // cases, sum of scores and positives per bin
+    let mut sums = vec![(0u64, 0.0f64, 0u64); bins];
+    let (mut squared_error, mut out_of_range) = (0.0, 0);
+    for case in &cases {
+        let score = f64::from(case.score);
+        if !(0.0..=1.0).contains(&score) {
+            out_of_range += 1;
+        }
+        let score = score.clamp(0.0, 1.0);
+        let (count, sum, positives) = &mut sums[((score * bins as f64) as usize).min(bins - 1)];
+        *count += 1;
+        *sum += score;
+        *positives += u64::from(case.positive);
+        squared_error += (score - f64::from(u8::from(case.positive))).powi(2);
+    }
+    if out_of_range > 0 {
+        warn!("{} score(s) outside 0.0-1.0 were clamped for calibration; the scores may not be probabilities", out_of_range);
+    }
+
+    let total = cases.len() as f64;
+    let (mut ece, mut mce) = (0.0, 0.0f64);
+    let bins: Vec<CalibrationBin> = sums.into_iter().enumerate()
+        .map(|(index, (count, sum, positives))| {
+            let (mean_score, positive_rate) = match count {
+                0 => (None, None),
+                _ => (Some(sum / count as f64), Some(positives as f64 / count as f64)),
+            };
+            if let Some((score, rate)) = mean_score.zip(positive_rate) {
+                ece += count as f64 / total * (score - rate).abs();
+                mce = mce.max((score - rate).abs());
+            }
+            CalibrationBin {
+                lower: index as f64 / bins as f64,
+                upper: (index + 1) as f64 / bins as f64,
+                count,
+                mean_score,
+                positive_rate,
+            }
+        })
+        .collect();
+    Some(Calibration { bins, ece, mce, brier: squared_error / total, out_of_range, non_finite })
+}
+
+/// Side of the calibration plot in pixels
+const PLOT_SIZE: u32 = 480;
+
+/// Render the reliability diagram: the positive rate of each bin as a bar over the diagonal of
+/// perfect calibration, each bin's mean score and positive rate as a marker, the share of cases
+/// per bin along the bottom, and the ECE
+pub fn draw_calibration_plot(calibration: &Calibration) -> RgbaImage {
+    let mut image = RgbaImage::from_pixel(PLOT_SIZE, PLOT_SIZE, Rgba([255, 255, 255, 255]));
+    let (left, top, side) = (48u32, 32u32, PLOT_SIZE - 80);
+    let bottom = top + side;
+    // Plot coordinates of a score and rate, rate 1.0 at the top
+    let at = |score: f64, rate: f64| {
+        (left + (score.clamp(0.0, 1.0) * f64::from(side)).round() as u32, bottom - (rate.clamp(0.0, 1.0) * f64::from(side)).round() as u32)
+    };
+
+    let fullest = calibration.bins.iter().map(|bin| bin.count).max().unwrap_or_default().max(1);
+    for bin in &calibration.bins {
+        let (x0, _) = at(bin.lower, 0.0);
+        let (x1, _) = at(bin.upper, 0.0);
+        if let Some(rate) = bin.positive_rate {
+            let (_, y) = at(0.0, rate);
+            fill(&mut image, x0 + 1, y, x1.saturating_sub(x0 + 1), bottom - y, [70, 110, 200, 140]);
+        }
+        // Cases per bin, up to a tenth of the plot high
+        let height = (bin.count as f64 / fullest as f64 * f64::from(side) / 10.0).round() as u32;
+        fill(&mut image, x0 + 1, bottom - height, x1.saturating_sub(x0 + 1), height, [60, 60, 60, 110]);
+    }
+    for step in 0..=side {
+        let (x, y) = at(f64::from(step) / f64::from(side), f64::from(step) / f64::from(side));
+        if step % 8 < 5 {
+            fill(&mut image, x, y.saturating_sub(1), 2, 2, [120, 120, 120, 255]);
+        }
+    }
+
+    let black = Rgba([0, 0, 0, 255]);
+    let mut annotations = vec![Annotation::Rectangle { x: left, y: top, width: side + 1, height: side + 1, color: black, thickness: 1 }];
+    for bin in &calibration.bins {
+        if let Some((score, rate)) = bin.mean_score.zip(bin.positive_rate) {
+            let (x, y) = at(score, rate);
+            annotations.push(Annotation::Marker { x, y, size: 9, color: Rgba([200, 30, 30, 255]) });
+        }
+    }
+    let text = |x: u32, y: u32, text: &str| Annotation::Text { x, y, text: text.to_string(), color: black, scale: 1 };
+    annotations.extend([
+        text(left, 8, &format!("Reliability  ECE {:.3}  MCE {:.3}", calibration.ece, calibration.mce)),
+        text(left - 12, bottom + 6, "0"),
+        text(left + side - 4, bottom + 6, "1"),
+        text(left - 12, top - 4, "1"),
+        text(left + side / 2 - 20, bottom + 20, "Score"),
+        text(4, top + side / 2, "Pos."),
+    ]);
+    draw_annotations(&mut image, &annotations);
+    image
+}
+
+/// Write the reliability diagram of `calibration` as a PNG
+pub fn save_calibration_plot(calibration: &Calibration, path: &Path) -> Result<()> {
+    save_png(&draw_calibration_plot(calibration), path)
+}
+
+fn fill(image: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: [u8; 4]) {
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            blend_pixel(&mut image.get_pixel_mut(px, py).0, color, BlendMode::Alpha);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfectly_calibrated_scores_have_no_error() {
+        let mut scores = vec![(0.25, true), (0.25, false), (0.25, false), (0.25, false)];
+        scores.extend([(0.75, true), (0.75, true), (0.75, true), (0.75, false)]);
+        let calibration = calibrate(&CaseScore::scored(&scores), 10).expect("calibration");
+        assert_eq!((calibration.ece, calibration.mce), (0.0, 0.0));
+        // Each bin: one case off by 0.75 and three by 0.25
+        assert_eq!(calibration.brier, 0.1875);
+        assert_eq!(calibration.bins[2].positive_rate, Some(0.25));
+        assert_eq!(calibration.bins[7].mean_score, Some(0.75));
+    }
+
+    #[test]
+    fn ece_weights_the_gap_of_each_bin() {
+        let scores = [(0.875, true), (0.875, false), (0.125, false), (0.125, false)];
+        let calibration = calibrate(&CaseScore::scored(&scores), 10).expect("calibration");
+        // Gaps of 0.375 (0.875 against 0.5) and 0.125 (0.125 against 0.0), half the cases each
+        assert_eq!(calibration.ece, 0.25);
+        assert_eq!(calibration.mce, 0.375);
+    }
+
+    #[test]
+    fn bounds_fall_into_the_first_and_last_bin() {
+        let calibration = calibrate(&CaseScore::scored(&[(1.0, true), (0.0, false), (1.5, true)]), 4).expect("calibration");
+        assert_eq!(calibration.bins.iter().map(|bin| bin.count).collect::<Vec<_>>(), vec![1, 0, 0, 2]);
+        assert_eq!(calibration.out_of_range, 1);
+    }
+
+    #[test]
+    fn non_finite_scores_are_left_out() {
+        let calibration = calibrate(&CaseScore::scored(&[(f32::NAN, true), (0.5, true), (f32::INFINITY, false)]), 10).expect("calibration");
+        assert_eq!((calibration.non_finite, calibration.out_of_range), (2, 0));
+        assert_eq!(calibration.bins.iter().map(|bin| bin.count).sum::<u64>(), 1);
+        assert_eq!(calibration.brier, 0.25);
+        assert!(calibrate(&CaseScore::scored(&[(f32::NAN, true)]), 10).is_none());
+    }
+}
@@ -0,0 +1,63 @@
+//! Class activation maps from the activations of a convolutional layer and the gradients of a
+//! class score with respect to them (Grad-CAM and Grad-CAM++).
+
+use ndarray::{Array2, Array3, Axis, Zip};
+use std::str::FromStr;
+
+/// How the activation channels are weighted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CamMethod {
+    /// Channels weighted by their mean gradient (Selvaraju et al.)
+    #[default]
+    GradCam,
+    /// Channels weighted by their positive gradients with per-pixel coefficients, which
+    /// localizes several instances of a class better (Chattopadhay et al.)
+    GradCamPlusPlus,
+}
+
+impl FromStr for CamMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gradcam" | "grad-cam" => Ok(CamMethod::GradCam),
+            "gradcam++" | "grad-cam++" | "gradcampp" => Ok(CamMethod::GradCamPlusPlus),
+            _ => Err(format!("Unknown CAM method: {}. Available: gradcam, gradcam++", s)),
+        }
+    }
+}
+
+impl CamMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            CamMethod::GradCam => "gradcam",
+            CamMethod::GradCamPlusPlus => "gradcam++",
+        }
+    }
+}
+
+/// Class activation map of one image from activations and gradients of shape (channels, height,
+/// width), with negative evidence clipped to 0.0
+pub fn class_activation_map(activations: &Array3<f32>, gradients: &Array3<f32>, method: CamMethod) -> Array2<f32> {
+    let (_, height, width) = activations.dim();
+    let mut cam = Array2::<f32>::zeros((height, width));
+    for (activation, gradient) in activations.axis_iter(Axis(0)).zip(gradients.axis_iter(Axis(0))) {
+        let weight = match method {
+            CamMethod::GradCam => gradient.mean().unwrap_or(0.0),
+            CamMethod::GradCamPlusPlus => {
+                // Higher-order derivatives as powers of the first, which holds for an exponential
+                // of the class score
+                let activation_sum = activation.sum();
+                Zip::from(&gradient).fold(0.0, |weight, &g| {
+                    let g2 = g * g;
+                    let denominator = 2.0 * g2 + activation_sum * g2 * g;
+                    let alpha = if denominator != 0.0 { g2 / denominator } else { 0.0 };
+                    weight + alpha * g.max(0.0)
+                })
+            }
+        };
+        cam.scaled_add(weight, &activation);
+    }
+    cam.mapv_inplace(|value| value.max(0.0));
+    cam
+}
@@ -0,0 +1,177 @@
+//! Per-service circuit breakers that fail fast while an upstream DL service is down.
+
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::{CircuitPolicy, ServiceConfig};
+use crate::heatmap::LoadedHeatmap;
+
+/// Heatmaps kept per service for [`CircuitPolicy::fallback_to_cache`]
+const FALLBACK_ENTRIES: usize = 32;
+
+/// Observable state of a service's circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls go through
+    Closed,
+    /// Calls fail immediately until the open period ends
+    Open,
+    /// The open period ended and the next call is a probe
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct Circuit {
+    /// Recent outcomes, true for a failure
+    outcomes: VecDeque<bool>,
+    opened_at: Option<Instant>,
+    /// A half-open probe is in flight
+    probing: bool,
+    fallback: VecDeque<(u64, LoadedHeatmap)>,
+}
+
+impl Circuit {
+    fn state(&self, policy: &CircuitPolicy) -> CircuitState {
+        match self.opened_at {
+            Some(opened) if opened.elapsed() < policy.open_for => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+
+    fn failure_rate(&self) -> f64 {
+        let failures = self.outcomes.iter().filter(|failed| **failed).count();
+        failures as f64 / self.outcomes.len().max(1) as f64
+    }
+}
+
+/// Circuits for every service a [`ServiceClient`](crate::service::ServiceClient) has called
+#[derive(Default)]
+pub(crate) struct CircuitBreakers {
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl std::fmt::Debug for CircuitBreakers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let circuits = self.circuits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f.debug_set().entries(circuits.keys()).finish()
+    }
+}
+
+impl CircuitBreakers {
+    fn with<T>(&self, service: &ServiceConfig, f: impl FnOnce(&mut Circuit) -> T) -> T {
+        let mut circuits = self.circuits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(circuits.entry(service.name.clone()).or_default())
+    }
+
+    pub fn state(&self, service: &ServiceConfig) -> CircuitState {
+        self.with(service, |circuit| circuit.state(&service.circuit))
+    }
+
+    /// Allow a call, whose outcome is recorded through the returned [`CircuitCall`], or explain
+    /// why it is rejected
+    pub fn acquire<'a>(&'a self, service: &'a ServiceConfig) -> std::result::Result<CircuitCall<'a>, String> {
+        let policy = &service.circuit;
+        let call = |probe| CircuitCall { breakers: self, service, probe };
+        if policy.window == 0 {
+            return Ok(call(false));
+        }
+        self.with(service, |circuit| match circuit.state(policy) {
+            CircuitState::Closed => Ok(call(false)),
+            CircuitState::HalfOpen if !circuit.probing => {
+                circuit.probing = true;
+                Ok(call(true))
+            }
+            state => {
+                let remaining = circuit.opened_at
+                    .map(|opened| policy.open_for.saturating_sub(opened.elapsed()).as_secs_f64().ceil() as u64)
+                    .unwrap_or_default();
+                Err(match state {
+                    CircuitState::HalfOpen => "circuit half-open, waiting for the probe call to finish".to_string(),
+                    _ => format!("circuit open after {:.0}% of recent calls failed; next attempt in {}s",
+                                 circuit.failure_rate() * 100.0, remaining),
+                })
+            }
+        })
+    }
+
+    /// Record the outcome of a call allowed by [`CircuitBreakers::acquire`]; true if it opened the circuit
+    fn record(&self, service: &ServiceConfig, probe: bool, failed: bool) -> bool {
+        let policy = &service.circuit;
+        if policy.window == 0 {
+            return false;
+        }
+        self.with(service, |circuit| {
+            // Once tripped, only the probe decides whether the circuit closes; stragglers are ignored
+            if circuit.opened_at.is_some() && !probe {
+                return false;
+            }
+            if probe {
+                circuit.probing = false;
+                circuit.outcomes.clear();
+                circuit.opened_at = failed.then(Instant::now);
+                if failed {
+                    circuit.outcomes.push_back(true);
+                }
+                return failed;
+            }
+
+            circuit.outcomes.push_back(failed);
+            while circuit.outcomes.len() > policy.window {
+                circuit.outcomes.pop_front();
+            }
+            let trips = circuit.outcomes.len() >= policy.min_calls.max(1) && circuit.failure_rate() >= policy.failure_rate;
+            if trips {
+                circuit.opened_at = Some(Instant::now());
+            }
+            trips
+        })
+    }
+
+    /// Heatmap previously returned for the same request
+    pub fn cached(&self, service: &ServiceConfig, key: u64) -> Option<LoadedHeatmap> {
+        self.with(service, |circuit| {
+            circuit.fallback.iter().find(|(cached, _)| *cached == key).map(|(_, heatmap)| heatmap.clone())
+        })
+    }
+
+    pub fn remember(&self, service: &ServiceConfig, key: u64, heatmap: &LoadedHeatmap) {
+        self.with(service, |circuit| {
+            circuit.fallback.retain(|(cached, _)| *cached != key);
+            circuit.fallback.push_back((key, heatmap.clone()));
+            while circuit.fallback.len() > FALLBACK_ENTRIES {
+                circuit.fallback.pop_front();
+            }
+        })
+    }
+}
+
+/// Call allowed by [`CircuitBreakers::acquire`]. A half-open probe dropped before its outcome is
+/// recorded, e.g. when the call is cancelled, counts as failed, so the circuit opens again rather
+/// than waiting for the probe forever.
+pub(crate) struct CircuitCall<'a> {
+    breakers: &'a CircuitBreakers,
+    service: &'a ServiceConfig,
+    probe: bool,
+}
+
+impl CircuitCall<'_> {
+    /// Record the outcome of the call; true if it opened the circuit
+    pub fn record(mut self, failed: bool) -> bool {
+        let probe = std::mem::take(&mut self.probe);
+        self.breakers.record(self.service, probe, failed)
+    }
+}
+
+impl Drop for CircuitCall<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breakers.record(self.service, true, true);
+            warn!("Probe call to service {} was cancelled; circuit opened again", self.service.name);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Scientific colormaps that turn normalized heatmap values into RGBA pixels.
+
+use image::RgbaImage;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::parallel::for_each_row;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMap {
+    Red,
+    Hot,
+    Jet,
+    Viridis,
+    Plasma,
+    /// Diverging blue -> light gray -> red, for signed values mapped around 0.5
+    Coolwarm,
+}
+
+impl FromStr for ColorMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "red" => Ok(ColorMap::Red),
+            "hot" => Ok(ColorMap::Hot),
+            "jet" => Ok(ColorMap::Jet),
+            "viridis" => Ok(ColorMap::Viridis),
+            "plasma" => Ok(ColorMap::Plasma),
+            "coolwarm" => Ok(ColorMap::Coolwarm),
+            _ => Err(format!("Unknown colormap: {}. Available: red, hot, jet, viridis, plasma, coolwarm", s)),
+        }
+    }
+}
+
+/// Apply colormap to normalized heatmap data, one row per task with the `parallel` feature
+pub fn apply_colormap(normalized_data: &Array2<f32>, colormap: &ColorMap, opacity: f32) -> RgbaImage {
+    let (rows, cols) = normalized_data.dim();
+    let mut heatmap_rgba = RgbaImage::new(cols as u32, rows as u32);
+    let alpha = (opacity * 255.0) as u8;
+
+    for_each_row(&mut heatmap_rgba, cols * 4, |row, pixels| {
+        for (pixel, &value) in pixels.chunks_exact_mut(4).zip(normalized_data.row(row)) {
+            let color = get_color_from_value(value, colormap);
+            pixel.copy_from_slice(&[color.0, color.1, color.2, alpha]);
+        }
+    });
+
+    heatmap_rgba
+}
+
+/// Get RGB color from normalized value [0,1] using specified colormap
+pub fn get_color_from_value(value: f32, colormap: &ColorMap) -> (u8, u8, u8) {
+    let value = value.clamp(0.0, 1.0); // Clamp to [0,1]
+    
+    match colormap {
+        ColorMap::Red => {
+            // Simple red gradient
+            let intensity = (value * 255.0) as u8;
+            (intensity, 0, 0)
+        }
+        ColorMap::Hot => {
+            // Hot colormap: black -> red -> yellow -> white
+            if value < 0.33 {
+                let t = value / 0.33;
+                ((t * 255.0) as u8, 0, 0)
+            } else if value < 0.66 {
+                let t = (value - 0.33) / 0.33;
+                (255, (t * 255.0) as u8, 0)
+            } else {
+                let t = (value - 0.66) / 0.34;
+                (255, 255, (t * 255.0) as u8)
+            }
+        }
+        ColorMap::Jet => {
+            // Jet colormap: blue -> cyan -> yellow -> red
+            if value < 0.25 {
+                let t = value / 0.25;
+                (0, (t * 255.0) as u8, 255)
+            } else if value < 0.5 {
+                let t = (value - 0.25) / 0.25;
+                (0, 255, (255.0 * (1.0 - t)) as u8)
+            } else if value < 0.75 {
+                let t = (value - 0.5) / 0.25;
+                ((t * 255.0) as u8, 255, 0)
+            } else {
+                let t = (value - 0.75) / 0.25;
+                (255, (255.0 * (1.0 - t)) as u8, 0)
+            }
+        }
+        ColorMap::Viridis => {
+            // Simplified viridis: purple -> blue -> green -> yellow
+            if value < 0.33 {
+                let t = value / 0.33;
+                ((68.0 + t * (59.0 - 68.0)) as u8, (1.0 + t * (82.0 - 1.0)) as u8, (84.0 + t * (139.0 - 84.0)) as u8)
+            } else if value < 0.66 {
+                let t = (value - 0.33) / 0.33;
+                ((59.0 + t * (33.0 - 59.0)) as u8, (82.0 + t * (144.0 - 82.0)) as u8, (139.0 + t * (140.0 - 139.0)) as u8)
+            } else {
+                let t = (value - 0.66) / 0.34;
+                ((33.0 + t * (253.0 - 33.0)) as u8, (144.0 + t * (231.0 - 144.0)) as u8, (140.0 + t * (37.0 - 140.0)) as u8)
+            }
+        }
+        ColorMap::Plasma => {
+            // Simplified plasma: purple -> pink -> yellow
+            if value < 0.5 {
+                let t = value / 0.5;
+                ((13.0 + t * (190.0 - 13.0)) as u8, (8.0 + t * (84.0 - 8.0)) as u8, (135.0 + t * (160.0 - 135.0)) as u8)
+            } else {
+                let t = (value - 0.5) / 0.5;
+                ((190.0 + t * (240.0 - 190.0)) as u8, (84.0 + t * (249.0 - 84.0)) as u8, (160.0 + t * (33.0 - 160.0)) as u8)
+            }
+        }
+        ColorMap::Coolwarm => {
+            // Simplified coolwarm: blue -> light gray -> red
+            if value < 0.5 {
+                let t = value / 0.5;
+                ((59.0 + t * (221.0 - 59.0)) as u8, (76.0 + t * (221.0 - 76.0)) as u8, (192.0 + t * (221.0 - 192.0)) as u8)
+            } else {
+                let t = (value - 0.5) / 0.5;
+                ((221.0 + t * (180.0 - 221.0)) as u8, (221.0 + t * (4.0 - 221.0)) as u8, (221.0 + t * (38.0 - 221.0)) as u8)
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Comparison of two heatmaps of the same image, e.g. of two versions of a model: the difference
+//! of the normalized maps is drawn with a diverging colormap, and their similarity is measured
+//! with the correlation and SSIM, for reviewing model upgrades.
+
+use image::RgbaImage;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::colormap::{get_color_from_value, ColorMap};
+use crate::parallel::for_each_row;
+use crate::render::BlendOptions;
+
+/// Side of the square windows SSIM is computed in, as in scikit-image's default
+pub const SSIM_WINDOW: usize = 7;
+
+/// Similarity of a normalized heatmap to a normalized baseline heatmap of the same shape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapComparison {
+    /// Pearson correlation of the values; None when either map is constant
+    pub correlation: Option<f64>,
+    /// Mean structural similarity over 7×7 windows, 1.0 for identical maps
+    pub ssim: f64,
+    /// Mean of heatmap minus baseline, positive when activation increased overall
+    pub mean_difference: f64,
+    pub mean_absolute_difference: f64,
+    pub max_absolute_difference: f32,
+}
+
+/// Similarity of `normalized` to `baseline`, both normalized and of the same shape
+pub fn compare_heatmaps(normalized: &Array2<f32>, baseline: &Array2<f32>) -> HeatmapComparison {
+    assert_eq!(normalized.dim(), baseline.dim(), "compared heatmaps have the same shape");
+    let count = normalized.len().max(1) as f64;
+    let (mut sum, mut sum_abs, mut max_abs) = (0.0, 0.0, 0.0f32);
+    for (&value, &base) in normalized.iter().zip(baseline) {
+        let difference = value - base;
+        sum += f64::from(difference);
+        sum_abs += f64::from(difference.abs());
+        max_abs = max_abs.max(difference.abs());
+    }
+    HeatmapComparison {
+        correlation: correlation(normalized, baseline),
+        ssim: ssim(normalized, baseline),
+        mean_difference: sum / count,
+        mean_absolute_difference: sum_abs / count,
+        max_absolute_difference: max_abs,
+    }
+}
+
+fn correlation(a: &Array2<f32>, b: &Array2<f32>) -> Option<f64> {
+    let count = a.len() as f64;
+    let mean_a = a.iter().map(|&value| f64::from(value)).sum::<f64>() / count;
+    let mean_b = b.iter().map(|&value| f64::from(value)).sum::<f64>() / count;
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (&x, &y) in a.iter().zip(b) {
+        let (dx, dy) = (f64::from(x) - mean_a, f64::from(y) - mean_b);
+        covariance += dx * dy;
+        variance_a += dx * dx;
+        variance_b += dy * dy;
+    }
+    (variance_a > 0.0 && variance_b > 0.0).then(|| covariance / (variance_a * variance_b).sqrt())
+}
+
+/// Mean SSIM of two maps with values in 0.0-1.0, over every window of `SSIM_WINDOW` pixels
+/// square (smaller for smaller maps) inside them, with the sample (co)variances of each window
+pub fn ssim(a: &Array2<f32>, b: &Array2<f32>) -> f64 {
+    const C1: f64 = 0.01 * 0.01;
+    const C2: f64 = 0.03 * 0.03;
+    let (height, width) = a.dim();
+    let window = SSIM_WINDOW.min(height).min(width);
+    if window == 0 {
+        return 1.0;
+    }
+
+    // Summed-area tables of a, b, a², b² and ab, one row and column larger than the maps
+    let stride = width + 1;
+    let mut tables = vec![[0.0f64; 5]; (height + 1) * stride];
+    for y in 0..height {
+        let mut row = [0.0f64; 5];
+        for x in 0..width {
+            let (va, vb) = (f64::from(a[[y, x]]), f64::from(b[[y, x]]));
+            for (total, value) in row.iter_mut().zip([va, vb, va * va, vb * vb, va * vb]) {
+                *total += value;
+            }
+            let above = tables[y * stride + x + 1];
+            tables[(y + 1) * stride + x + 1] = std::array::from_fn(|index| above[index] + row[index]);
+        }
+    }
+
+    let n = (window * window) as f64;
+    let bessel = if n > 1.0 { n / (n - 1.0) } else { 1.0 };
+    let mut total = 0.0;
+    for y in 0..=height - window {
+        for x in 0..=width - window {
+            let corner = |dy: usize, dx: usize| tables[(y + dy) * stride + x + dx];
+            let (top_left, top_right) = (corner(0, 0), corner(0, window));
+            let (bottom_left, bottom_right) = (corner(window, 0), corner(window, window));
+            let [sa, sb, saa, sbb, sab]: [f64; 5] =
+                std::array::from_fn(|index| bottom_right[index] - bottom_left[index] - top_right[index] + top_left[index]);
+            let (mean_a, mean_b) = (sa / n, sb / n);
+            let variance_a = (saa / n - mean_a * mean_a) * bessel;
+            let variance_b = (sbb / n - mean_b * mean_b) * bessel;
+            let covariance = (sab / n - mean_a * mean_b) * bessel;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+        }
+    }
+    total / ((height - window + 1) * (width - window + 1)) as f64
+}
+
+/// RGBA layer of `normalized` minus `baseline` in the coolwarm colormap, red where the heatmap
+/// is higher and blue where it is lower, with the blend opacity scaled by the size of the
+/// difference; differences smaller than the blend threshold are left transparent
+pub fn difference_layer(normalized: &Array2<f32>, baseline: &Array2<f32>, blend: &BlendOptions) -> RgbaImage {
+    let (rows, cols) = normalized.dim();
+    let mut layer = RgbaImage::new(cols as u32, rows as u32);
+    let threshold = blend.threshold.unwrap_or(0.0);
+
+    for_each_row(&mut layer, cols * 4, |row, pixels| {
+        for (pixel, (&value, &base)) in pixels.chunks_exact_mut(4).zip(normalized.row(row).iter().zip(baseline.row(row))) {
+            let difference = (value - base).clamp(-1.0, 1.0);
+            if difference.abs() < threshold {
+                continue;
+            }
+            let color = get_color_from_value((difference + 1.0) / 2.0, &ColorMap::Coolwarm);
+            let alpha = (blend.opacity * difference.abs() * 255.0) as u8;
+            pixel.copy_from_slice(&[color.0, color.1, color.2, alpha]);
+        }
+    });
+
+    layer
+}
@@ -0,0 +1,112 @@
+//! Coverage of the anatomy by a heatmap: the share of its high activation that falls outside a
+//! body or lung mask, which is large for heatmaps misregistered to their image, e.g. transposed
+//! or computed for another crop.
+
+use log::warn;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::heatmap::resize_indices;
+
+/// Share of the high activation outside the anatomy mask above which a heatmap is reported
+pub const DEFAULT_MAX_OUTSIDE_FRACTION: f64 = 0.2;
+
+/// Anatomy mask the high activation of a heatmap is expected inside, and what a heatmap that
+/// mostly falls outside it does
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoverageOptions {
+    /// Body or lung mask, a PNG or `.npy` with any nonzero value inside
+    pub mask: PathBuf,
+    /// Normalized level from which the activation is high; None uses the blend threshold, or
+    /// else 0.5
+    #[serde(default)]
+    pub threshold: Option<f32>,
+    #[serde(default = "default_max_outside")]
+    pub max_outside: f64,
+    /// Fail the run with `Error::QualityControl` when the share is exceeded, instead of warning
+    #[serde(default)]
+    pub strict: bool,
+}
+
+fn default_max_outside() -> f64 {
+    DEFAULT_MAX_OUTSIDE_FRACTION
+}
+
+impl CoverageOptions {
+    pub fn new(mask: impl Into<PathBuf>) -> Self {
+        CoverageOptions { mask: mask.into(), threshold: None, max_outside: DEFAULT_MAX_OUTSIDE_FRACTION, strict: false }
+    }
+
+    pub fn threshold(mut self, threshold: f32) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    pub fn max_outside(mut self, fraction: f64) -> Self {
+        self.max_outside = fraction;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+/// How much of the high activation of a heatmap falls outside the anatomy mask
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Coverage {
+    /// Normalized level at and above which the activation is high
+    pub threshold: f32,
+    /// Pixels of high activation, and those of them outside the mask
+    pub active_pixels: u64,
+    pub outside_pixels: u64,
+    /// Share of the high activation, weighted by its values, outside the mask; None without any
+    pub outside_fraction: Option<f64>,
+    pub max_outside: f64,
+    /// Whether the share outside the mask is above `max_outside`
+    pub exceeded: bool,
+}
+
+impl Coverage {
+    /// Warning for a heatmap whose activation mostly falls outside the mask
+    pub fn message(&self) -> String {
+        format!("{:.1}% of the heatmap's activation at or above {} falls outside the anatomy mask (at most {:.1}% expected)",
+                self.outside_fraction.unwrap_or_default() * 100.0, self.threshold, self.max_outside * 100.0)
+    }
+}
+
+/// Share of the activation of `normalized` at or above `threshold` outside `mask`, which is
+/// resized to the heatmap with nearest-neighbor sampling if their shapes differ
+pub fn activation_coverage(normalized: &Array2<f32>, mask: &Array2<bool>, threshold: f32, max_outside: f64) -> Coverage {
+    let (height, width) = normalized.dim();
+    if mask.dim() != (height, width) {
+        warn!("Anatomy mask dimensions ({}x{}) don't match the heatmap ({}x{}), resizing...",
+              mask.nrows(), mask.ncols(), height, width);
+    }
+    let (rows, cols) = resize_indices(mask.dim(), width, height);
+
+    let (mut active_pixels, mut outside_pixels) = (0, 0);
+    let (mut activation, mut outside) = (0.0, 0.0);
+    for ((y, x), &value) in normalized.indexed_iter() {
+        if value < threshold {
+            continue;
+        }
+        active_pixels += 1;
+        activation += f64::from(value);
+        if !mask[[rows[y], cols[x]]] {
+            outside_pixels += 1;
+            outside += f64::from(value);
+        }
+    }
+    let outside_fraction = (activation > 0.0).then(|| outside / activation);
+    Coverage {
+        threshold,
+        active_pixels,
+        outside_pixels,
+        outside_fraction,
+        max_outside,
+        exceeded: outside_fraction.is_some_and(|fraction| fraction > max_outside),
+    }
+}
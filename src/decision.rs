@@ -0,0 +1,97 @@
+//! Positive/negative classification decisions from model scores at configured operating points.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::heatmap::HeatmapMetadata;
+
+/// Score thresholds at which a class is called positive, written as `0.5` for every class,
+/// `tuberculosis=0.42,pneumonia=0.6` per class, or both as `0.5,tuberculosis=0.42`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OperatingPoints {
+    /// Threshold of the classes without their own
+    pub default: Option<f32>,
+    /// Thresholds by class label
+    pub classes: BTreeMap<String, f32>,
+}
+
+impl FromStr for OperatingPoints {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut points = OperatingPoints::default();
+        for item in s.split([',', ';']).map(str::trim).filter(|item| !item.is_empty()) {
+            let (class, value) = match item.rsplit_once('=') {
+                Some((class, value)) => (Some(class.trim()), value.trim()),
+                None => (None, item),
+            };
+            let threshold: f32 = value.parse().ok()
+                .filter(|threshold: &f32| threshold.is_finite())
+                .ok_or_else(|| format!("expected a threshold like 0.5 or class=0.5, found '{}'", item))?;
+            match class {
+                Some("") => return Err(format!("missing class name in '{}'", item)),
+                Some(class) => {
+                    points.classes.insert(class.to_string(), threshold);
+                }
+                None if points.default.is_some() => return Err("only one threshold without a class name can be given".to_string()),
+                None => points.default = Some(threshold),
+            }
+        }
+        if points.is_empty() {
+            return Err("expected at least one threshold".to_string());
+        }
+        Ok(points)
+    }
+}
+
+impl OperatingPoints {
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.classes.is_empty()
+    }
+
+    /// Threshold of `class`, or the default one
+    pub fn threshold(&self, class: Option<&str>) -> Option<f32> {
+        class.and_then(|class| self.classes.get(class).copied()).or(self.default)
+    }
+
+    /// Decision for the `score` of the heatmap's `class`; None when the heatmap has no score or
+    /// its class no threshold
+    pub fn decide(&self, metadata: &HeatmapMetadata) -> Option<Decision> {
+        let score: f32 = metadata.attributes.get("score")?.parse().ok()?;
+        let class = metadata.attributes.get("class").cloned();
+        let threshold = self.threshold(class.as_deref())?;
+        Some(Decision { class, score, threshold, positive: score >= threshold })
+    }
+}
+
+/// Outcome of comparing a class score with its operating point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Decision {
+    /// Class the score belongs to; None when the heatmap's source doesn't name it
+    pub class: Option<String>,
+    pub score: f32,
+    pub threshold: f32,
+    /// Whether the score reaches the threshold
+    pub positive: bool,
+}
+
+impl Decision {
+    pub fn name(&self) -> &'static str {
+        match self.positive {
+            true => "positive",
+            false => "negative",
+        }
+    }
+
+    /// One-line summary drawn onto the image, e.g. `tuberculosis POSITIVE 0.87 (>= 0.42)`
+    pub fn label(&self) -> String {
+        let comparison = if self.positive { ">=" } else { "<" };
+        let verdict = format!("{} {:.2} ({} {:.2})", self.name().to_uppercase(), self.score, comparison, self.threshold);
+        match &self.class {
+            Some(class) => format!("{} {}", class, verdict),
+            None => verdict,
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Seeded synthetic demo data used when no real DICOM input is available.
+
+use image::{DynamicImage, GrayImage, ImageBuffer, RgbaImage};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DemoPattern {
+    Gradient,
+    Blobs,
+    Phantom,
+    Noise,
+}
+
+impl FromStr for DemoPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "gradient" => Ok(DemoPattern::Gradient),
+            "blobs" => Ok(DemoPattern::Blobs),
+            "phantom" => Ok(DemoPattern::Phantom),
+            "noise" => Ok(DemoPattern::Noise),
+            _ => Err(format!("Unknown demo pattern: {}. Available: gradient, blobs, phantom, noise", s)),
+        }
+    }
+}
+
+/// Settings for synthetic demo data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DemoOptions {
+    pub width: u32,
+    pub height: u32,
+    pub seed: u64,
+    pub pattern: DemoPattern,
+}
+
+impl Default for DemoOptions {
+    fn default() -> Self {
+        DemoOptions {
+            width: 512,
+            height: 512,
+            seed: 0,
+            pattern: DemoPattern::Gradient,
+        }
+    }
+}
+
+/// Small SplitMix64 generator so demo output is identical across platforms and releases
+struct DemoRng {
+    state: u64,
+}
+
+impl DemoRng {
+    fn new(seed: u64) -> Self {
+        DemoRng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform value in [low, high)
+    fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f32()
+    }
+}
+
+/// Synthetic base image and raw heatmap values (None for the gradient pattern)
+pub type DemoData = (RgbaImage, Option<Array2<f32>>);
+
+/// Generate the synthetic base image and raw heatmap values for a demo run
+/// The heatmap is None for the gradient pattern, which uses the default gradient overlay
+pub fn generate_demo_data(options: &DemoOptions) -> Result<DemoData> {
+    let mut rng = DemoRng::new(options.seed);
+    
+    // Create a synthetic base image (simulating DICOM data)
+    let image_data_u8 = generate_demo_base_image(options, &mut rng);
+
+    // Create a grayscale image from the simulated data
+    let gray_image: GrayImage = ImageBuffer::from_raw(options.width, options.height, image_data_u8)
+        .ok_or_else(|| Error::Render("Failed to create GrayImage from simulated data".to_string()))?;
+
+    // Convert grayscale to RGBA to allow for color overlay
+    let base_rgba_image: RgbaImage = DynamicImage::ImageLuma8(gray_image).to_rgba8();
+    
+    let heatmap_data = generate_demo_heatmap_data(options, &mut rng);
+    
+    Ok((base_rgba_image, heatmap_data))
+}
+
+/// Generate the grayscale base image for the selected demo pattern
+fn generate_demo_base_image(options: &DemoOptions, rng: &mut DemoRng) -> Vec<u8> {
+    let (columns, rows) = (options.width, options.height);
+    let mut image_data_u8: Vec<u8> = Vec::with_capacity((rows * columns) as usize);
+    
+    match options.pattern {
+        DemoPattern::Gradient | DemoPattern::Blobs => {
+            for y in 0..rows {
+                for x in 0..columns {
+                    // Create a simple gradient pattern
+                    let intensity = ((x + y) as f32 / (columns + rows) as f32 * 255.0) as u8;
+                    image_data_u8.push(intensity);
+                }
+            }
+        }
+        DemoPattern::Phantom => {
+            // Chest-like phantom: body ellipse, two darker lungs and a bright spine
+            let jitter = rng.range(-0.02, 0.02);
+            for y in 0..rows {
+                for x in 0..columns {
+                    let u = x as f32 / columns as f32;
+                    let v = y as f32 / rows as f32;
+                    let mut intensity = 20.0;
+                    if in_ellipse(u, v, 0.5, 0.52, 0.44, 0.46) {
+                        intensity = 170.0;
+                    }
+                    if in_ellipse(u, v, 0.32 + jitter, 0.48, 0.13, 0.3)
+                        || in_ellipse(u, v, 0.68 - jitter, 0.48, 0.13, 0.3)
+                    {
+                        intensity = 60.0;
+                    }
+                    if (u - 0.5).abs() < 0.03 && v > 0.12 && v < 0.92 {
+                        intensity = 230.0;
+                    }
+                    let noise = rng.range(-8.0, 8.0);
+                    image_data_u8.push((intensity + noise).clamp(0.0, 255.0) as u8);
+                }
+            }
+        }
+        DemoPattern::Noise => {
+            for _ in 0..(rows * columns) {
+                image_data_u8.push((rng.next_f32() * 255.0) as u8);
+            }
+        }
+    }
+    
+    image_data_u8
+}
+
+/// Generate raw heatmap values for the selected demo pattern
+/// Returns None for the gradient pattern, which uses the default gradient overlay
+fn generate_demo_heatmap_data(options: &DemoOptions, rng: &mut DemoRng) -> Option<Array2<f32>> {
+    let (width, height) = (options.width as usize, options.height as usize);
+    
+    match options.pattern {
+        DemoPattern::Gradient => None,
+        DemoPattern::Blobs => {
+            let blobs: Vec<(f32, f32, f32, f32)> = (0..4)
+                .map(|_| (rng.range(0.15, 0.85), rng.range(0.15, 0.85), rng.range(0.04, 0.12), rng.range(0.5, 1.0)))
+                .collect();
+            Some(gaussian_blobs(width, height, &blobs))
+        }
+        DemoPattern::Phantom => {
+            // Place findings inside the lung fields of the phantom
+            let blobs: Vec<(f32, f32, f32, f32)> = (0..2)
+                .map(|i| {
+                    let center_x = if i == 0 { 0.32 } else { 0.68 };
+                    (center_x + rng.range(-0.06, 0.06), rng.range(0.3, 0.65), rng.range(0.03, 0.07), rng.range(0.6, 1.0))
+                })
+                .collect();
+            Some(gaussian_blobs(width, height, &blobs))
+        }
+        DemoPattern::Noise => {
+            Some(Array2::from_shape_fn((height, width), |_| rng.next_f32()))
+        }
+    }
+}
+
+/// Sum of gaussian blobs given as (center_x, center_y, sigma, amplitude) in relative coordinates
+fn gaussian_blobs(width: usize, height: usize, blobs: &[(f32, f32, f32, f32)]) -> Array2<f32> {
+    Array2::from_shape_fn((height, width), |(row, col)| {
+        let u = col as f32 / width as f32;
+        let v = row as f32 / height as f32;
+        blobs.iter()
+            .map(|&(cx, cy, sigma, amplitude)| {
+                let dist_sq = (u - cx).powi(2) + (v - cy).powi(2);
+                amplitude * (-dist_sq / (2.0 * sigma * sigma)).exp()
+            })
+            .sum()
+    })
+}
+
+/// Check whether relative coordinates fall inside an axis-aligned ellipse
+fn in_ellipse(u: f32, v: f32, cx: f32, cy: f32, rx: f32, ry: f32) -> bool {
+    ((u - cx) / rx).powi(2) + ((v - cy) / ry).powi(2) <= 1.0
+}
@@ -0,0 +1,700 @@
+//! DICOM pixel data decoding into 8-bit grayscale/RGBA images.
+
+use dicom::dictionary_std::tags;
+use dicom::object::{from_reader, open_file, FileDicomObject, InMemDicomObject, OpenFileOptions};
+use dicom_pixeldata::{DecodedPixelData, PixelDecoder, PixelRepresentation};
+use image::{DynamicImage, GrayImage, RgbaImage};
+use log::info;
+use ndarray::Array2;
+use std::path::Path;
+
+#[cfg(feature = "dimse")]
+use crate::decision::Decision;
+use crate::error::{Error, Result};
+#[cfg(feature = "dimse")]
+use crate::provenance::HashedInput;
+#[cfg(feature = "dimse")]
+use crate::regions::RegionReport;
+use crate::simd::{min_max_u16, scale_u16_into};
+
+/// DICOM Part 10 object held in memory
+pub type DicomFile = FileDicomObject<InMemDicomObject>;
+
+/// Open a DICOM file from disk
+pub fn open_dicom(path: &Path) -> Result<DicomFile> {
+    open_file(path).map_err(|e| Error::dicom(format!("open {}", path.display()), e))
+}
+
+/// Parse a DICOM Part 10 object from bytes, with or without the 128-byte preamble
+pub fn open_dicom_bytes(bytes: &[u8]) -> Result<DicomFile> {
+    from_reader(bytes).map_err(|e| Error::dicom("parse in-memory object", e))
+}
+
+/// Open the attributes of a DICOM file for operations that don't need its image: reading stops
+/// before the pixel data, which is never loaded, and private elements are dropped
+pub fn open_dicom_header(path: &Path) -> Result<DicomFile> {
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .map_err(|e| Error::dicom(format!("open {}", path.display()), e))?;
+    Ok(without_private_elements(obj))
+}
+
+/// [`open_dicom_header`] of a DICOM Part 10 object in memory, with or without the preamble
+pub fn open_dicom_header_bytes(bytes: &[u8]) -> Result<DicomFile> {
+    let obj = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .from_reader(bytes)
+        .map_err(|e| Error::dicom("parse in-memory object", e))?;
+    Ok(without_private_elements(obj))
+}
+
+/// `obj` without the elements of odd (private) groups
+fn without_private_elements(mut obj: DicomFile) -> DicomFile {
+    obj.retain(|element| element.header().tag.group() % 2 == 0);
+    obj
+}
+
+/// Bytes needed to tell whether a stream is a DICOM Part 10 object: the 128-byte preamble and `DICM`
+pub const DICOM_PREFIX_LEN: usize = 132;
+
+/// Whether `bytes` open with the `DICM` prefix of a DICOM Part 10 object, after the preamble or
+/// without one
+pub fn has_dicom_prefix(bytes: &[u8]) -> bool {
+    bytes.get(128..DICOM_PREFIX_LEN) == Some(b"DICM") || bytes.starts_with(b"DICM")
+}
+
+/// Identifiers of a DICOM instance within its study and series
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceUids {
+    pub study: String,
+    pub series: String,
+    pub sop_class: String,
+    pub sop_instance: String,
+}
+
+/// Read the study, series, SOP class and SOP instance UIDs
+pub fn instance_uids(obj: &DicomFile) -> Result<InstanceUids> {
+    let uid = |name: &str| -> Result<String> {
+        let value = obj.element_by_name(name).map_err(|e| Error::dicom(format!("read {}", name), e))?
+            .to_str().map_err(|e| Error::dicom(format!("read {}", name), e))?;
+        Ok(value.trim_end_matches(['\0', ' ']).to_string())
+    };
+    Ok(InstanceUids {
+        study: uid("StudyInstanceUID")?,
+        series: uid("SeriesInstanceUID")?,
+        sop_class: uid("SOPClassUID")?,
+        sop_instance: uid("SOPInstanceUID")?,
+    })
+}
+
+/// SOP Instance UID of a DICOM Part 10 object, reading nothing past the header
+pub fn sop_instance_uid(bytes: &[u8]) -> Result<String> {
+    object_sop_instance_uid(&open_dicom_header_bytes(bytes)?)
+}
+
+/// SOP Instance UID of a parsed DICOM object
+pub fn object_sop_instance_uid(obj: &DicomFile) -> Result<String> {
+    let value = obj.element(tags::SOP_INSTANCE_UID).map_err(|e| Error::dicom("read SOPInstanceUID", e))?
+        .to_str().map_err(|e| Error::dicom("read SOPInstanceUID", e))?;
+    Ok(value.trim_end_matches(['\0', ' ']).to_string())
+}
+
+/// Read the image dimensions as (rows, columns)
+pub fn image_dimensions(obj: &DicomFile) -> Result<(u32, u32)> {
+    let rows = obj.element_by_name("Rows").map_err(|e| Error::dicom("read Rows", e))?
+        .to_int::<u32>().map_err(|e| Error::dicom("read Rows", e))?;
+    let columns = obj.element_by_name("Columns").map_err(|e| Error::dicom("read Columns", e))?
+        .to_int::<u32>().map_err(|e| Error::dicom("read Columns", e))?;
+    Ok((rows, columns))
+}
+
+/// Decode the pixel data of a DICOM object into an RGBA image ready for overlay
+pub fn decode_dicom_pixel_data(obj: &DicomFile, rows: u32, columns: u32) -> Result<RgbaImage> {
+    let gray_image = decode_dicom_grayscale(obj, rows, columns)?;
+    
+    // Convert grayscale to RGBA for overlay
+    let rgba_image = DynamicImage::ImageLuma8(gray_image).to_rgba8();
+    
+    Ok(rgba_image)
+}
+
+/// [`decode_dicom_pixel_data`] into `buffer`, e.g. the pixels of the previous image of a batch,
+/// which grows only if it is too small for the image
+pub fn decode_dicom_pixel_data_into(obj: &DicomFile, rows: u32, columns: u32, mut buffer: Vec<u8>) -> Result<RgbaImage> {
+    let gray_image = decode_dicom_grayscale(obj, rows, columns)?;
+    buffer.clear();
+    buffer.extend(gray_image.as_raw().iter().flat_map(|&luma| [luma, luma, luma, u8::MAX]));
+    let (width, height) = gray_image.dimensions();
+    RgbaImage::from_raw(width, height, buffer)
+        .ok_or_else(|| Error::Render("Failed to create RGBA image from DICOM data".to_string()))
+}
+
+/// Decode the pixel data of a DICOM object into an 8-bit grayscale image, a quarter of the
+/// memory of the RGBA image
+pub fn decode_dicom_grayscale(obj: &DicomFile, rows: u32, columns: u32) -> Result<GrayImage> {
+    if let Some(gray_image) = native_monochrome8_frame(obj, rows, columns, 0) {
+        return Ok(gray_image);
+    }
+    // Decode pixel data using dicom-pixeldata
+    let decoded_pixel_data = obj.decode_pixel_data().map_err(|e| Error::dicom("decode pixel data", e))?;
+    grayscale_image(&decoded_pixel_data, rows, columns)
+}
+
+/// Frames in the pixel data of a DICOM object (NumberOfFrames), 1 for single-frame objects
+pub fn frame_count(obj: &DicomFile) -> Result<u32> {
+    match obj.element_opt(tags::NUMBER_OF_FRAMES).map_err(|e| Error::dicom("read NumberOfFrames", e))? {
+        Some(element) => Ok(element.to_int::<u32>().map_err(|e| Error::dicom("read NumberOfFrames", e))?.max(1)),
+        None => Ok(1),
+    }
+}
+
+/// Fail unless the DICOM object has a frame `frame`, counted from 0
+pub fn check_frame(obj: &DicomFile, frame: u32) -> Result<()> {
+    let frames = frame_count(obj)?;
+    if frame >= frames {
+        return Err(Error::InvalidOption(format!("Frame {} is out of range: the instance has {} frame(s)", frame, frames)));
+    }
+    Ok(())
+}
+
+/// Row and column spacing of the pixels in mm, from PixelSpacing or else ImagerPixelSpacing;
+/// None when neither holds two positive values
+pub fn pixel_spacing(obj: &DicomFile) -> Option<(f64, f64)> {
+    [tags::PIXEL_SPACING, tags::IMAGER_PIXEL_SPACING].into_iter().find_map(|tag| {
+        let values = obj.element_opt(tag).ok().flatten()?.to_multi_float64().ok()?;
+        match values[..] {
+            [row, column] if row > 0.0 && column > 0.0 => Some((row, column)),
+            _ => None,
+        }
+    })
+}
+
+/// Distance of neighboring slices in mm, from SpacingBetweenSlices or else SliceThickness; None
+/// when neither holds a positive value
+pub fn slice_spacing(obj: &DicomFile) -> Option<f64> {
+    [tags::SPACING_BETWEEN_SLICES, tags::SLICE_THICKNESS].into_iter()
+        .find_map(|tag| obj.element_opt(tag).ok().flatten()?.to_float64().ok().filter(|spacing| *spacing > 0.0))
+}
+
+/// Decode one frame of a DICOM object, from 0, into an 8-bit grayscale image; 16-bit frames
+/// are scaled by their own value range
+pub fn decode_dicom_frame(obj: &DicomFile, rows: u32, columns: u32, frame: u32) -> Result<GrayImage> {
+    check_frame(obj, frame)?;
+    if let Some(gray_image) = native_monochrome8_frame(obj, rows, columns, frame) {
+        return Ok(gray_image);
+    }
+    let decoded_pixel_data = obj.decode_pixel_data_frame(frame)
+        .map_err(|e| Error::dicom(format!("decode pixel data of frame {}", frame), e))?;
+    grayscale_image(&decoded_pixel_data, rows, columns)
+}
+
+/// Decode one frame of a DICOM object, from 0, into its values (rows, columns) after the
+/// modality rescale, e.g. Hounsfield units, unlike [`decode_dicom_frame`] comparable across
+/// frames and instances; color frames give their luma
+pub fn decode_dicom_values(obj: &DicomFile, rows: u32, columns: u32, frame: u32) -> Result<Array2<f32>> {
+    check_frame(obj, frame)?;
+    let decoded_pixel_data = obj.decode_pixel_data_frame(frame)
+        .map_err(|e| Error::dicom(format!("decode pixel data of frame {}", frame), e))?;
+    let values: Vec<f32> = match decoded_pixel_data.samples_per_pixel() {
+        1 => decoded_pixel_data.to_vec_frame(0).map_err(|e| Error::dicom(format!("read pixel values of frame {}", frame), e))?,
+        3 => grayscale_image(&decoded_pixel_data, rows, columns)?.into_raw().into_iter().map(f32::from).collect(),
+        samples => return Err(Error::dicom("decode pixel data", format!("Unsupported samples per pixel: {}", samples))),
+    };
+    let found = values.len();
+    Array2::from_shape_vec((rows as usize, columns as usize), values).map_err(|_| Error::ShapeMismatch {
+        context: format!("pixel values of frame {}", frame),
+        expected: rows as usize * columns as usize,
+        found,
+    })
+}
+
+/// Transfer syntaxes whose native pixel data is laid out byte by byte as decoded: implicit and
+/// explicit VR little endian, and deflated explicit VR little endian
+const LITTLE_ENDIAN_NATIVE: [&str; 3] = ["1.2.840.10008.1.2", "1.2.840.10008.1.2.1", "1.2.840.10008.1.2.1.99"];
+
+/// A frame of unsigned 8-bit MONOCHROME2 pixel data stored natively, without rescaling or LUTs,
+/// copied straight out of the pixel data element: the pixels the generic decode would produce,
+/// without decoding or converting them. None for every other image, which takes the generic path
+fn native_monochrome8_frame(obj: &DicomFile, rows: u32, columns: u32, frame: u32) -> Option<GrayImage> {
+    let text = |tag| obj.element_opt(tag).ok().flatten().and_then(|element| element.to_str().ok().map(|value| value.trim().to_string()));
+    let int = |tag| obj.element_opt(tag).ok().flatten().map(|element| element.to_int::<i32>().ok());
+    let float = |tag| obj.element_opt(tag).ok().flatten().map(|element| element.to_float64().ok());
+
+    let transfer_syntax = obj.meta().transfer_syntax().trim_end_matches(['\0', ' ']);
+    let monochrome8 = LITTLE_ENDIAN_NATIVE.contains(&transfer_syntax)
+        && text(tags::PHOTOMETRIC_INTERPRETATION).as_deref() == Some("MONOCHROME2")
+        && int(tags::SAMPLES_PER_PIXEL).is_none_or(|samples| samples == Some(1))
+        && int(tags::BITS_ALLOCATED) == Some(Some(8))
+        && int(tags::BITS_STORED).is_none_or(|bits| bits == Some(8))
+        && int(tags::PIXEL_REPRESENTATION).is_none_or(|representation| representation == Some(0))
+        && float(tags::RESCALE_SLOPE).is_none_or(|slope| slope == Some(1.0))
+        && float(tags::RESCALE_INTERCEPT).is_none_or(|intercept| intercept == Some(0.0))
+        && [tags::MODALITY_LUT_SEQUENCE, tags::VOILUT_SEQUENCE].iter().all(|&tag| matches!(obj.element_opt(tag), Ok(None)));
+    if !monochrome8 {
+        return None;
+    }
+
+    // Encapsulated pixel data has no primitive value
+    let pixel_data = obj.element_opt(tags::PIXEL_DATA).ok().flatten()?.value().primitive()?.to_bytes();
+    let frame_len = rows as usize * columns as usize;
+    let start = frame as usize * frame_len;
+    let pixels = pixel_data.get(start..start + frame_len)?;
+    info!("Copying native 8-bit MONOCHROME2 pixel data of frame {}", frame);
+    GrayImage::from_raw(columns, rows, pixels.to_vec())
+}
+
+fn grayscale_image(decoded_pixel_data: &DecodedPixelData, rows: u32, columns: u32) -> Result<GrayImage> {
+    info!("Pixel data info: {} bits allocated, {} samples per pixel", 
+          decoded_pixel_data.bits_allocated(), 
+          decoded_pixel_data.samples_per_pixel());
+    
+    // Convert decoded pixel data to grayscale image
+    let gray_image = match decoded_pixel_data.samples_per_pixel() {
+        1 => {
+            // Grayscale image
+            convert_to_grayscale_image(decoded_pixel_data, rows, columns)?
+        }
+        3 => {
+            // RGB image - convert to grayscale
+            let rgb_data = decoded_pixel_data.to_dynamic_image(0).map_err(|e| Error::dicom("convert RGB pixel data", e))?;
+            rgb_data.to_luma8()
+        }
+        _ => {
+            return Err(Error::dicom("decode pixel data", format!("Unsupported samples per pixel: {}", 
+                                                                 decoded_pixel_data.samples_per_pixel())));
+        }
+    };
+    
+    Ok(gray_image)
+}
+
+/// Orders 16-bit samples as unsigned levels: the stored bits only, with signed values offset by
+/// 32768 so the smallest one is level 0
+#[derive(Debug, Clone, Copy)]
+struct SampleLevels {
+    bits_stored: u16,
+    signed: bool,
+}
+
+impl SampleLevels {
+    fn of(decoded_data: &DecodedPixelData) -> Self {
+        SampleLevels {
+            bits_stored: decoded_data.bits_stored().clamp(1, 16),
+            signed: decoded_data.pixel_representation() == PixelRepresentation::Signed,
+        }
+    }
+
+    fn level(self, sample: u16) -> u16 {
+        let shift = 16 - self.bits_stored;
+        if self.signed {
+            ((((sample << shift) as i16) >> shift) as u16) ^ 0x8000
+        } else {
+            (sample << shift) >> shift
+        }
+    }
+
+    /// Stored value of `level`
+    fn value(self, level: u16) -> i32 {
+        if self.signed { i32::from(level) - 32768 } else { i32::from(level) }
+    }
+}
+
+fn convert_to_grayscale_image(
+    decoded_data: &DecodedPixelData,
+    rows: u32,
+    columns: u32,
+) -> Result<GrayImage> {
+    // Handle different bit depths
+    match decoded_data.bits_allocated() {
+        8 => {
+            // 8-bit data
+            let pixel_data: Vec<u8> = decoded_data.to_vec().map_err(|e| Error::dicom("read 8-bit pixel data", e))?;
+            GrayImage::from_raw(columns, rows, pixel_data)
+                .ok_or_else(|| Error::Render("Failed to create GrayImage from 8-bit DICOM data".to_string()))
+        }
+        16 => {
+            // 16-bit data - scaled to 8-bit straight from the decoded bytes into the image
+            let samples = decoded_data.frame_data(0).map_err(|e| Error::dicom("read 16-bit pixel data", e))?;
+            let samples = samples.as_chunks::<2>().0.get(..rows as usize * columns as usize)
+                .ok_or_else(|| Error::Render("Failed to create GrayImage from 16-bit DICOM data".to_string()))?;
+            let levels = SampleLevels::of(decoded_data);
+            let level = |sample| levels.level(sample);
+            
+            // Apply basic windowing: scale the stored range to 8-bit range. The modality rescale
+            // is linear, so this matches scaling the rescaled values by their range
+            // For medical images, proper windowing using Window Center/Width would be better
+            let (min_level, max_level) = min_max_u16(samples, level).unwrap_or((0, 0));
+            let (min_val, max_val) = (f32::from(min_level), f32::from(max_level));
+            let range = if max_val > min_val { max_val - min_val } else { 1.0 };
+            
+            info!("16-bit data range: {} - {}", levels.value(min_level), levels.value(max_level));
+            
+            let mut gray_image = GrayImage::new(columns, rows);
+            scale_u16_into(samples, level, min_val, range, &mut gray_image);
+            Ok(gray_image)
+        }
+        bits => {
+            Err(Error::dicom("decode pixel data", format!("Unsupported bit depth: {} bits", bits)))
+        }
+    }
+}
+
+/// Part 10 bytes of `source` with its pixel data replaced by `image` as 8-bit MONOCHROME2, in
+/// Explicit VR Little Endian, e.g. an edited copy of the decoded image for a DL service
+///
+/// Rescaling, windowing and LUTs are dropped, as `image` is already windowed.
+pub fn with_grayscale_pixels(source: &DicomFile, image: &GrayImage) -> Result<Vec<u8>> {
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+
+    let mut obj = source.clone();
+    for tag in [
+        tags::RESCALE_SLOPE,
+        tags::RESCALE_INTERCEPT,
+        tags::RESCALE_TYPE,
+        tags::MODALITY_LUT_SEQUENCE,
+        tags::WINDOW_CENTER,
+        tags::WINDOW_WIDTH,
+        tags::VOILUT_FUNCTION,
+        tags::VOILUT_SEQUENCE,
+        tags::NUMBER_OF_FRAMES,
+        tags::PLANAR_CONFIGURATION,
+        tags::PIXEL_PADDING_VALUE,
+        tags::SMALLEST_IMAGE_PIXEL_VALUE,
+        tags::LARGEST_IMAGE_PIXEL_VALUE,
+    ] {
+        obj.remove_element(tag);
+    }
+    let ushort = |tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+    obj.put(ushort(tags::SAMPLES_PER_PIXEL, 1));
+    obj.put(DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, PrimitiveValue::from("MONOCHROME2")));
+    obj.put(ushort(tags::ROWS, image.height() as u16));
+    obj.put(ushort(tags::COLUMNS, image.width() as u16));
+    obj.put(ushort(tags::BITS_ALLOCATED, 8));
+    obj.put(ushort(tags::BITS_STORED, 8));
+    obj.put(ushort(tags::HIGH_BIT, 7));
+    obj.put(ushort(tags::PIXEL_REPRESENTATION, 0));
+    obj.put(DataElement::new(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(image.as_raw().clone())));
+    obj.update_meta(|meta| meta.set_transfer_syntax(&dicom::transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN));
+
+    let mut bytes = Vec::new();
+    obj.write_all(&mut bytes).map_err(|e| Error::dicom("write grayscale copy", e))?;
+    Ok(bytes)
+}
+
+/// DICOM DA value (YYYYMMDD) of a Unix timestamp, with the days-to-civil conversion
+#[cfg(feature = "fs")]
+pub(crate) fn utc_date(secs: u64) -> String {
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// DICOM TM value (HHMMSS) of a Unix timestamp
+#[cfg(feature = "fs")]
+pub(crate) fn utc_time(secs: u64) -> String {
+    format!("{:02}{:02}{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+/// Series Description of the Secondary Capture instances created from fused images
+#[cfg(feature = "dimse")]
+pub const SECONDARY_CAPTURE_DESCRIPTION: &str = "Heatmap overlay";
+
+/// New UID in the UUID-derived `2.25` root
+#[cfg(feature = "dimse")]
+pub fn generate_uid() -> String {
+    format!("2.25.{}", uuid::Uuid::new_v4().as_u128())
+}
+
+/// Series Description of the Enhanced SR instances created from classification decisions
+#[cfg(feature = "dimse")]
+pub const DECISION_REPORT_DESCRIPTION: &str = "Heatmap classification";
+
+/// Coding scheme of the concepts in decision reports that have no standard code
+#[cfg(feature = "dimse")]
+pub const PRIVATE_CODING_SCHEME: &str = "99HEATMAP";
+
+/// New instance of `sop_class` in a new series of the source's study, with the patient and study
+/// attributes copied from `source`; returns it with its SOP Instance UID
+#[cfg(feature = "dimse")]
+fn derived_instance(source: &DicomFile, sop_class: &str, modality: &str, description: &str) -> (InMemDicomObject, String) {
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+
+    let mut obj = InMemDicomObject::new_empty();
+    for tag in [
+        tags::SPECIFIC_CHARACTER_SET,
+        tags::PATIENT_NAME,
+        tags::PATIENT_ID,
+        tags::PATIENT_BIRTH_DATE,
+        tags::PATIENT_SEX,
+        tags::STUDY_INSTANCE_UID,
+        tags::STUDY_DATE,
+        tags::STUDY_TIME,
+        tags::STUDY_ID,
+        tags::ACCESSION_NUMBER,
+        tags::REFERRING_PHYSICIAN_NAME,
+    ] {
+        if let Ok(element) = source.element(tag) {
+            obj.put(element.clone());
+        }
+    }
+    let sop_instance = generate_uid();
+    let text = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+    obj.put(text(tags::SOP_CLASS_UID, VR::UI, sop_class));
+    obj.put(text(tags::SOP_INSTANCE_UID, VR::UI, &sop_instance));
+    obj.put(text(tags::MODALITY, VR::CS, modality));
+    obj.put(text(tags::SERIES_INSTANCE_UID, VR::UI, &generate_uid()));
+    obj.put(text(tags::SERIES_DESCRIPTION, VR::LO, description));
+    obj.put(text(tags::SERIES_NUMBER, VR::IS, "999"));
+    obj.put(text(tags::INSTANCE_NUMBER, VR::IS, "1"));
+    (obj, sop_instance)
+}
+
+/// Private creator of the block, in group 0011, recording the digests of a rendering's inputs
+#[cfg(feature = "dimse")]
+pub const PROVENANCE_CREATOR: &str = "HEATMAP PROVENANCE";
+
+/// Record this tool and the SHA-256 of the `inputs` a derived instance was rendered from
+///
+/// An item naming this tool as Processing Equipment, with the digests in its Contribution
+/// Description as far as they fit, is appended to the Contributing Equipment Sequence copied
+/// from `source`, so the chain of equipment that handled the image is kept. The roles and digests are repeated as
+/// multi-valued LO in the private block of [`PROVENANCE_CREATOR`]: (0011,1001) and (0011,1002).
+/// Paths are left out, as they may name the patient.
+#[cfg(feature = "dimse")]
+fn put_provenance(obj: &mut InMemDicomObject, source: &DicomFile, inputs: &[HashedInput]) {
+    use dicom::core::value::DataSetSequence;
+    use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let text = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+    let sequence = |tag, items: Vec<InMemDicomObject>| DataElement::new(tag, VR::SQ, DataSetSequence::from(items));
+    let digest = |input: &HashedInput| input.sha256.clone().unwrap_or_default();
+    // ST holds at most 1024 characters; the private block lists every input
+    let mut description = String::new();
+    for input in inputs {
+        let entry = format!("{} sha256:{}", input.role, input.sha256.as_deref().unwrap_or("unavailable"));
+        if description.len() + entry.len() + 7 > 1024 {
+            description.push_str("; ...");
+            break;
+        }
+        if !description.is_empty() {
+            description.push_str("; ");
+        }
+        description.push_str(&entry);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    let purpose = InMemDicomObject::from_element_iter([
+        text(tags::CODE_VALUE, VR::SH, "109102"),
+        text(tags::CODING_SCHEME_DESIGNATOR, VR::SH, "DCM"),
+        text(tags::CODE_MEANING, VR::LO, "Processing Equipment"),
+    ]);
+    let equipment = InMemDicomObject::from_element_iter([
+        text(tags::MANUFACTURER, VR::LO, env!("CARGO_PKG_NAME")),
+        text(tags::MANUFACTURER_MODEL_NAME, VR::LO, SECONDARY_CAPTURE_DESCRIPTION),
+        text(tags::SOFTWARE_VERSIONS, VR::LO, env!("CARGO_PKG_VERSION")),
+        text(tags::CONTRIBUTION_DATE_TIME, VR::DT, &format!("{}{}", utc_date(now), utc_time(now))),
+        text(tags::CONTRIBUTION_DESCRIPTION, VR::ST, &description),
+        sequence(tags::PURPOSE_OF_REFERENCE_CODE_SEQUENCE, vec![purpose]),
+    ]);
+    let mut chain: Vec<InMemDicomObject> = source.element(tags::CONTRIBUTING_EQUIPMENT_SEQUENCE).ok()
+        .and_then(|element| element.items())
+        .map(<[InMemDicomObject]>::to_vec)
+        .unwrap_or_default();
+    chain.push(equipment);
+    obj.put(sequence(tags::CONTRIBUTING_EQUIPMENT_SEQUENCE, chain));
+
+    let values = |values: Vec<String>| PrimitiveValue::Strs(values.into());
+    obj.put(text(Tag(0x0011, 0x0010), VR::LO, PROVENANCE_CREATOR));
+    obj.put(DataElement::new(Tag(0x0011, 0x1001), VR::LO, values(inputs.iter().map(|input| input.role.clone()).collect())));
+    obj.put(DataElement::new(Tag(0x0011, 0x1002), VR::LO, values(inputs.iter().map(digest).collect())));
+}
+
+/// Part 10 file of a derived instance in Explicit VR Little Endian
+#[cfg(feature = "dimse")]
+fn with_meta(obj: InMemDicomObject, sop_class: &str, sop_instance: &str, what: &str) -> Result<DicomFile> {
+    let meta = dicom::object::FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class)
+        .media_storage_sop_instance_uid(sop_instance)
+        .transfer_syntax(dicom::transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN.uid())
+        .build()
+        .map_err(|e| Error::dicom(what, e))?;
+    Ok(obj.with_exact_meta(meta))
+}
+
+/// Secondary Capture instance holding `image` as RGB, in a new series of the source's study
+///
+/// Patient and study attributes are copied from `source`, which is also referenced in the
+/// Source Image Sequence. The SHA-256 of the `inputs` of the rendering are recorded as by
+/// `put_provenance`.
+#[cfg(feature = "dimse")]
+pub fn secondary_capture(source: &DicomFile, image: &RgbaImage, inputs: &[HashedInput]) -> Result<DicomFile> {
+    use dicom::core::value::DataSetSequence;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+
+    let source_uids = instance_uids(source)?;
+    let sop_class = uids::SECONDARY_CAPTURE_IMAGE_STORAGE;
+    let (mut obj, sop_instance) = derived_instance(source, sop_class, "OT", SECONDARY_CAPTURE_DESCRIPTION);
+    let text = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+    let ushort = |tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+    obj.put(text(tags::CONVERSION_TYPE, VR::CS, "WSD"));
+    obj.put(DataElement::new(tags::IMAGE_TYPE, VR::CS, PrimitiveValue::Strs(["DERIVED".to_string(), "SECONDARY".to_string()].into())));
+    obj.put(text(tags::DERIVATION_DESCRIPTION, VR::ST, "Heatmap overlay fused onto the source image"));
+    obj.put(text(tags::BURNED_IN_ANNOTATION, VR::CS, "NO"));
+    let reference = InMemDicomObject::from_element_iter([
+        text(tags::REFERENCED_SOP_CLASS_UID, VR::UI, &source_uids.sop_class),
+        text(tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, &source_uids.sop_instance),
+    ]);
+    obj.put(DataElement::new(tags::SOURCE_IMAGE_SEQUENCE, VR::SQ, DataSetSequence::from(vec![reference])));
+    put_provenance(&mut obj, source, inputs);
+
+    // The fused image is opaque, so alpha is dropped
+    let rgb = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+    obj.put(ushort(tags::SAMPLES_PER_PIXEL, 3));
+    obj.put(text(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "RGB"));
+    obj.put(ushort(tags::PLANAR_CONFIGURATION, 0));
+    obj.put(ushort(tags::ROWS, rgb.height() as u16));
+    obj.put(ushort(tags::COLUMNS, rgb.width() as u16));
+    obj.put(ushort(tags::BITS_ALLOCATED, 8));
+    obj.put(ushort(tags::BITS_STORED, 8));
+    obj.put(ushort(tags::HIGH_BIT, 7));
+    obj.put(ushort(tags::PIXEL_REPRESENTATION, 0));
+    obj.put(DataElement::new(tags::PIXEL_DATA, VR::OB, PrimitiveValue::from(rgb.into_raw())));
+
+    with_meta(obj, sop_class, &sop_instance, "create Secondary Capture")
+}
+
+/// Make a derived instance of `source` the slice `instance_number`, from 1, of the series
+/// `series_uid`, with the image plane of `source`, so viewers stack the slices like the source
+/// series
+#[cfg(feature = "dimse")]
+pub fn in_series(obj: &mut DicomFile, source: &DicomFile, series_uid: &str, instance_number: u32) {
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+
+    obj.put(DataElement::new(tags::SERIES_INSTANCE_UID, VR::UI, PrimitiveValue::from(series_uid)));
+    obj.put(DataElement::new(tags::INSTANCE_NUMBER, VR::IS, PrimitiveValue::from(instance_number.to_string())));
+    for tag in [
+        tags::FRAME_OF_REFERENCE_UID,
+        tags::IMAGE_POSITION_PATIENT,
+        tags::IMAGE_ORIENTATION_PATIENT,
+        tags::PIXEL_SPACING,
+        tags::SLICE_THICKNESS,
+        tags::SPACING_BETWEEN_SLICES,
+        tags::SLICE_LOCATION,
+    ] {
+        if let Ok(element) = source.element(tag) {
+            obj.put(element.clone());
+        }
+    }
+}
+
+/// Enhanced SR instance reporting a classification decision and per-region heatmap statistics on
+/// `source`, in a new series of its study
+///
+/// The root container (LOINC 18748-4, Diagnostic Imaging Report) holds the finding's class, the
+/// score and threshold as numbers and the positive or negative decision, if there is one; then a
+/// container per region with its name (as Finding Site) and its mean, peak and share of the
+/// activation, followed by the predominant region of each mask; and last the source image the
+/// heatmap was computed for. The SHA-256 of the `inputs` of the rendering are recorded as by
+/// `put_provenance`.
+#[cfg(feature = "dimse")]
+pub fn decision_report(source: &DicomFile, decision: Option<&Decision>, regions: Option<&RegionReport>, inputs: &[HashedInput]) -> Result<DicomFile> {
+    use dicom::core::value::DataSetSequence;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let source_uids = instance_uids(source)?;
+    let sop_class = uids::ENHANCED_SR_STORAGE;
+    let (mut obj, sop_instance) = derived_instance(source, sop_class, "SR", DECISION_REPORT_DESCRIPTION);
+    let text = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+    let sequence = |tag, items: Vec<InMemDicomObject>| DataElement::new(tag, VR::SQ, DataSetSequence::from(items));
+    let code = |value: &str, scheme: &str, meaning: &str| InMemDicomObject::from_element_iter([
+        text(tags::CODE_VALUE, VR::SH, value),
+        text(tags::CODING_SCHEME_DESIGNATOR, VR::SH, scheme),
+        text(tags::CODE_MEANING, VR::LO, meaning),
+    ]);
+    let item = |value_type: &str, concept: InMemDicomObject| InMemDicomObject::from_element_iter([
+        text(tags::RELATIONSHIP_TYPE, VR::CS, "CONTAINS"),
+        text(tags::VALUE_TYPE, VR::CS, value_type),
+        sequence(tags::CONCEPT_NAME_CODE_SEQUENCE, vec![concept]),
+    ]);
+    let text_item = |concept: InMemDicomObject, value: &str| {
+        let mut item = item("TEXT", concept);
+        item.put(text(tags::TEXT_VALUE, VR::UT, value));
+        item
+    };
+    let number_item = |concept: InMemDicomObject, value: f32| {
+        let mut item = item("NUM", concept);
+        let measured = InMemDicomObject::from_element_iter([
+            text(tags::NUMERIC_VALUE, VR::DS, &format!("{:.6}", value)),
+            sequence(tags::MEASUREMENT_UNITS_CODE_SEQUENCE, vec![code("1", "UCUM", "no units")]),
+        ]);
+        item.put(sequence(tags::MEASURED_VALUE_SEQUENCE, vec![measured]));
+        item
+    };
+    let reference = || InMemDicomObject::from_element_iter([
+        text(tags::REFERENCED_SOP_CLASS_UID, VR::UI, &source_uids.sop_class),
+        text(tags::REFERENCED_SOP_INSTANCE_UID, VR::UI, &source_uids.sop_instance),
+    ]);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    obj.put(text(tags::CONTENT_DATE, VR::DA, &utc_date(now)));
+    obj.put(text(tags::CONTENT_TIME, VR::TM, &utc_time(now)));
+    obj.put(text(tags::COMPLETION_FLAG, VR::CS, "COMPLETE"));
+    obj.put(text(tags::VERIFICATION_FLAG, VR::CS, "UNVERIFIED"));
+    obj.put(sequence(tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE, Vec::new()));
+    obj.put(sequence(tags::PERFORMED_PROCEDURE_CODE_SEQUENCE, Vec::new()));
+    let series = InMemDicomObject::from_element_iter([
+        text(tags::SERIES_INSTANCE_UID, VR::UI, &source_uids.series),
+        sequence(tags::REFERENCED_SOP_SEQUENCE, vec![reference()]),
+    ]);
+    let evidence = InMemDicomObject::from_element_iter([
+        text(tags::STUDY_INSTANCE_UID, VR::UI, &source_uids.study),
+        sequence(tags::REFERENCED_SERIES_SEQUENCE, vec![series]),
+    ]);
+    obj.put(sequence(tags::CURRENT_REQUESTED_PROCEDURE_EVIDENCE_SEQUENCE, vec![evidence]));
+    put_provenance(&mut obj, source, inputs);
+
+    obj.put(text(tags::VALUE_TYPE, VR::CS, "CONTAINER"));
+    obj.put(sequence(tags::CONCEPT_NAME_CODE_SEQUENCE, vec![code("18748-4", "LN", "Diagnostic Imaging Report")]));
+    obj.put(text(tags::CONTINUITY_OF_CONTENT, VR::CS, "SEPARATE"));
+    let mut content = Vec::new();
+    if let Some(decision) = decision {
+        if let Some(class) = &decision.class {
+            content.push(text_item(code("121071", "DCM", "Finding"), class));
+        }
+        content.push(number_item(code("SCORE", PRIVATE_CODING_SCHEME, "Classification score"), decision.score));
+        content.push(number_item(code("THRESHOLD", PRIVATE_CODING_SCHEME, "Operating point threshold"), decision.threshold));
+        content.push(text_item(code("DECISION", PRIVATE_CODING_SCHEME, "Classification decision"), decision.name()));
+    }
+    for region in regions.map(|regions| regions.regions.as_slice()).unwrap_or_default() {
+        let mut container = item("CONTAINER", code("REGION", PRIVATE_CODING_SCHEME, "Region heatmap statistics"));
+        container.put(text(tags::CONTINUITY_OF_CONTENT, VR::CS, "SEPARATE"));
+        container.put(sequence(tags::CONTENT_SEQUENCE, vec![
+            text_item(code("363698007", "SCT", "Finding site"), &region.name),
+            number_item(code("MEAN_ACTIVATION", PRIVATE_CODING_SCHEME, "Mean activation"), region.mean as f32),
+            number_item(code("PEAK_ACTIVATION", PRIVATE_CODING_SCHEME, "Peak activation"), region.max),
+            number_item(code("ACTIVATION_SHARE", PRIVATE_CODING_SCHEME, "Share of activation"), region.activation_share as f32),
+        ]));
+        content.push(container);
+    }
+    for predominant in regions.map(|regions| regions.predominant.as_slice()).unwrap_or_default() {
+        content.push(text_item(code("PREDOMINANT_REGION", PRIVATE_CODING_SCHEME, "Predominant activation region"), predominant));
+    }
+    let mut image = item("IMAGE", code("121112", "DCM", "Source of Measurement"));
+    image.put(sequence(tags::REFERENCED_SOP_SEQUENCE, vec![reference()]));
+    content.push(image);
+    obj.put(sequence(tags::CONTENT_SEQUENCE, content));
+
+    with_meta(obj, sop_class, &sop_instance, "create decision report")
+}
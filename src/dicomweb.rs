@@ -0,0 +1,214 @@
+//! DICOMweb client for a PACS or VNA: QIDO-RS instance search and WADO-RS retrieval by UID.
+
+use log::info;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde::Deserialize;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::DicomWebConfig;
+use crate::dicom_io::utc_date;
+use crate::error::{Error, Result};
+
+/// WADO-RS media type for instances in their stored transfer syntax
+const ACCEPT_DICOM: &str = "multipart/related; type=\"application/dicom\"; transfer-syntax=*";
+/// QIDO-RS media type for search results
+const ACCEPT_DICOM_JSON: &str = "application/dicom+json";
+/// Instances requested per QIDO-RS page when the query sets no `limit`
+const QIDO_PAGE_SIZE: usize = 100;
+
+/// Study, series and SOP instance UID of an instance to retrieve
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceRef {
+    pub study: String,
+    pub series: String,
+    pub instance: String,
+}
+
+impl InstanceRef {
+    /// Reject anything but digits and dots, so UIDs from requests can't change the URL path
+    pub fn validate(&self) -> Result<()> {
+        for (name, uid) in [("study", &self.study), ("series", &self.series), ("instance", &self.instance)] {
+            if uid.is_empty() || uid.len() > 64 || !uid.bytes().all(|byte| byte.is_ascii_digit() || byte == b'.') {
+                return Err(Error::InvalidOption(format!("Invalid {} UID: '{}'", name, uid)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the instance below the DICOMweb base URL
+    pub fn path(&self) -> String {
+        format!("studies/{}/series/{}/instances/{}", self.study, self.series, self.instance)
+    }
+}
+
+/// Client for one DICOMweb server; cheap to clone, and clones share connections
+#[derive(Debug, Clone)]
+pub struct DicomWebClient {
+    config: DicomWebConfig,
+    http: reqwest::Client,
+    headers: HeaderMap,
+}
+
+fn dicomweb_error(message: impl std::fmt::Display) -> Error {
+    Error::Service { service: "dicomweb".to_string(), message: message.to_string() }
+}
+
+impl DicomWebClient {
+    pub fn new(config: DicomWebConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| Error::InvalidOption(format!("Invalid DICOMweb header name {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| Error::InvalidOption(format!("Invalid value for DICOMweb header {}: {}", name, e)))?;
+            headers.insert(name, value);
+        }
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| dicomweb_error(format!("failed to create HTTP client: {}", e)))?;
+        Ok(DicomWebClient { config, http, headers })
+    }
+
+    /// Base URL of the server
+    pub fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    /// Fetch one instance as DICOM Part 10 bytes with WADO-RS
+    pub async fn retrieve(&self, instance: &InstanceRef) -> Result<Vec<u8>> {
+        instance.validate()?;
+        let url = format!("{}/{}", self.config.url, instance.path());
+        let started = Instant::now();
+        let response = self.http.get(&url)
+            .headers(self.headers.clone())
+            .header(ACCEPT, ACCEPT_DICOM)
+            .send()
+            .await
+            .map_err(|e| dicomweb_error(format!("GET {}: {}", url, e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(dicomweb_error(format!("GET {} returned HTTP {}: {}", url, status.as_u16(), body.trim())));
+        }
+        let content_type = response.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = response.bytes().await.map_err(|e| dicomweb_error(format!("GET {}: {}", url, e)))?;
+        let dicom = match multipart_boundary(&content_type) {
+            Some(boundary) => multipart_parts(&body, &boundary)
+                .into_iter()
+                .next()
+                .ok_or_else(|| dicomweb_error(format!("GET {} returned no instance", url)))?
+                .to_vec(),
+            // Some servers answer with the bare instance
+            None => body.to_vec(),
+        };
+        info!("Retrieved {} ({} bytes) in {}ms", instance.path(), dicom.len(), started.elapsed().as_millis());
+        Ok(dicom)
+    }
+
+    /// Find instances with a QIDO-RS query string such as `Modality=CR&StudyDate=today`
+    ///
+    /// `today` as a value stands for the current UTC date. Without a `limit` in the query,
+    /// results are fetched page by page until the server returns a short page.
+    pub async fn search_instances(&self, query: &str) -> Result<Vec<InstanceRef>> {
+        let query = expand_query(query);
+        let paged = !query.split('&').any(|param| param.split('=').next().is_some_and(|name| name.eq_ignore_ascii_case("limit")));
+        let mut instances = Vec::new();
+        loop {
+            let mut url = format!("{}/instances?{}", self.config.url, query);
+            if paged {
+                url.push_str(&format!("{}limit={}&offset={}", if query.is_empty() { "" } else { "&" }, QIDO_PAGE_SIZE, instances.len()));
+            }
+            let response = self.http.get(&url)
+                .headers(self.headers.clone())
+                .header(ACCEPT, ACCEPT_DICOM_JSON)
+                .send()
+                .await
+                .map_err(|e| dicomweb_error(format!("GET {}: {}", url, e)))?;
+            let status = response.status();
+            // QIDO-RS answers an empty match with 204 No Content
+            if status == reqwest::StatusCode::NO_CONTENT {
+                break;
+            }
+            if !status.is_success() {
+                let body = response.text().await.unwrap_or_default();
+                return Err(dicomweb_error(format!("GET {} returned HTTP {}: {}", url, status.as_u16(), body.trim())));
+            }
+            let body = response.bytes().await.map_err(|e| dicomweb_error(format!("GET {}: {}", url, e)))?;
+            let datasets: Vec<serde_json::Value> = serde_json::from_slice(&body)
+                .map_err(|e| dicomweb_error(format!("GET {} returned invalid DICOM JSON: {}", url, e)))?;
+            let count = datasets.len();
+            for dataset in &datasets {
+                instances.push(instance_ref(dataset).ok_or_else(|| dicomweb_error(format!("GET {} returned an instance without UIDs", url)))?);
+            }
+            if !paged || count < QIDO_PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(instances)
+    }
+}
+
+/// Study, series and SOP instance UID of a QIDO-RS result
+fn instance_ref(dataset: &serde_json::Value) -> Option<InstanceRef> {
+    let uid = |tag: &str| dataset[tag]["Value"][0].as_str().map(String::from);
+    Some(InstanceRef { study: uid("0020000D")?, series: uid("0020000E")?, instance: uid("00080018")? })
+}
+
+/// Replace `today` values with the current UTC date as YYYYMMDD
+fn expand_query(query: &str) -> String {
+    let today = utc_date(SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default());
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) if value.eq_ignore_ascii_case("today") => format!("{}={}", name, today),
+            _ => param.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Boundary parameter of a `multipart/*` content type
+pub(crate) fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Bodies of the parts of a complete multipart message
+pub(crate) fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+    let mut rest = match find(body, &delimiter) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return parts,
+    };
+    // Each part follows a delimiter line and runs up to the CRLF before the next delimiter
+    while !rest.starts_with(b"--") {
+        let Some(end) = find(rest, &delimiter) else {
+            break;
+        };
+        let part = &rest[..end];
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        if let Some(headers_end) = find(part, b"\r\n\r\n") {
+            parts.push(&part[headers_end + 4..]);
+        }
+        rest = &rest[end + delimiter.len()..];
+    }
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
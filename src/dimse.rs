@@ -0,0 +1,608 @@
+//! DICOM network services (DIMSE): a C-STORE SCP that queues received instances for processing,
+//! a C-STORE SCU that pushes results to remote AEs, and C-FIND/C-MOVE to pull studies from a PACS.
+
+use dicom::core::dictionary::{DataDictionary, DataDictionaryEntry};
+use dicom::core::{dicom_value, DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::{tags, uids, StandardDataDictionary};
+use dicom::encoding::{TransferSyntax, TransferSyntaxIndex};
+use dicom::object::mem::InMemElement;
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use dicom::transfer_syntax::{entries, TransferSyntaxRegistry};
+use dicom::ul::association::server::AcceptCalledAeTitle;
+use dicom::ul::pdu::{PDataValue, PDataValueType};
+use dicom::ul::{ClientAssociation, ClientAssociationOptions, Pdu, ServerAssociation, ServerAssociationOptions};
+use log::{info, warn};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use crate::batch::{run_received, BatchSettings, BatchSummary};
+use crate::config::{DimseConfig, DimseDestination};
+use crate::dicom_io::{instance_uids, DicomFile, InstanceUids};
+use crate::error::{Error, Result};
+
+const C_STORE_RQ: u16 = 0x0001;
+const C_FIND_RQ: u16 = 0x0020;
+const C_MOVE_RQ: u16 = 0x0021;
+const C_ECHO_RQ: u16 = 0x0030;
+/// Bit set in the Command Field of every response
+const RESPONSE: u16 = 0x8000;
+/// Command Data Set Type (0000,0800) of a message followed by a data set
+const DATA_SET: u16 = 0x0000;
+/// Command Data Set Type of a message without a data set
+const NO_DATA_SET: u16 = 0x0101;
+/// Priority (0000,0700) MEDIUM
+const MEDIUM: u16 = 0x0000;
+
+const SUCCESS: u16 = 0x0000;
+/// Refused: Out of Resources, when the processing queue is full
+const OUT_OF_RESOURCES: u16 = 0xA700;
+/// Error: Cannot Understand, when the data set can't be parsed or lacks its UIDs
+const CANNOT_UNDERSTAND: u16 = 0xC000;
+/// Unrecognized Operation, for commands other than C-STORE and C-ECHO
+const UNRECOGNIZED_OPERATION: u16 = 0x0211;
+/// C-FIND and C-MOVE statuses of responses followed by more responses
+const PENDING: u16 = 0xFF00;
+const PENDING_WARNING: u16 = 0xFF01;
+/// C-MOVE warning: one or more sub-operations failed
+const SUB_OPERATIONS_FAILED: u16 = 0xB000;
+
+/// Transfer syntaxes proposed for C-FIND and C-MOVE identifiers, in order of preference
+const QUERY_TRANSFER_SYNTAXES: &[&str] = &[uids::EXPLICIT_VR_LITTLE_ENDIAN, uids::IMPLICIT_VR_LITTLE_ENDIAN];
+
+/// Image storage SOP classes accepted, and Verification for C-ECHO
+const ABSTRACT_SYNTAXES: &[&str] = &[
+    uids::VERIFICATION,
+    uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::CT_IMAGE_STORAGE,
+    uids::ENHANCED_CT_IMAGE_STORAGE,
+    uids::MR_IMAGE_STORAGE,
+    uids::ENHANCED_MR_IMAGE_STORAGE,
+    uids::ULTRASOUND_IMAGE_STORAGE,
+    uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE,
+    uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_BYTE_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_WORD_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_TRUE_COLOR_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::X_RAY_ANGIOGRAPHIC_IMAGE_STORAGE,
+    uids::X_RAY_RADIOFLUOROSCOPIC_IMAGE_STORAGE,
+    uids::NUCLEAR_MEDICINE_IMAGE_STORAGE,
+    uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::VL_PHOTOGRAPHIC_IMAGE_STORAGE,
+];
+
+type Association = ServerAssociation<TcpStream>;
+type Queue = SyncSender<(InstanceUids, DicomFile)>;
+
+fn dimse_error(message: impl std::fmt::Display) -> Error {
+    Error::Server(format!("DIMSE: {}", message))
+}
+
+fn destination_error(destination: &DimseDestination, message: impl std::fmt::Display) -> Error {
+    Error::Service { service: destination.name.clone(), message: message.to_string() }
+}
+
+/// Accept C-STORE associations addressed to `config.ae_title` and process every received
+/// instance with `settings`, one at a time in arrival order
+///
+/// Associations are served on their own threads while the calling thread renders. Each instance
+/// is acknowledged once queued, and refused with Out of Resources while `config.max_pending`
+/// instances are waiting. Runs until the process is stopped.
+pub fn listen(config: &DimseConfig, settings: &BatchSettings) -> Result<BatchSummary> {
+    let listener = bind(config)?;
+    let (queue, received) = mpsc::sync_channel(config.max_pending);
+    accept_associations(config, listener, queue, Arc::new(AtomicBool::new(false)));
+    run_received(received, settings)
+}
+
+/// Pull the studies on `source` matching a C-FIND `query` and process their instances with
+/// `settings`, returning once every moved instance is processed
+///
+/// The listener of [`listen`] is started first, then each study is retrieved with C-MOVE to
+/// `config.ae_title`, so `source` must know that AE title's address. Studies that fail to move
+/// are logged and fail the pull once the others are processed.
+pub fn pull(config: &DimseConfig, settings: &BatchSettings, source: &DimseDestination, query: &str) -> Result<BatchSummary> {
+    let identifier = query_identifier(query)?;
+    let listener = bind(config)?;
+    let address = listener.local_addr().map_err(|e| Error::Server(format!("Failed to read the listener address: {}", e)))?;
+    let (queue, received) = mpsc::sync_channel(config.max_pending);
+    let stopped = Arc::new(AtomicBool::new(false));
+    accept_associations(config, listener, queue, stopped.clone());
+
+    let (source, move_destination) = (source.clone(), config.ae_title.clone());
+    let mover = thread::spawn(move || {
+        let moved = find_studies(&source, &identifier).and_then(|studies| move_studies(&source, &studies, &move_destination));
+        // Once the moves are done no more instances are expected: wake the acceptor so it stops
+        // and drops its queue handle, which ends the processing loop after the last instance
+        stopped.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(loopback(address));
+        moved
+    });
+    let summary = run_received(received, settings)?;
+    mover.join().map_err(|_| dimse_error("the C-MOVE thread panicked"))??;
+    Ok(summary)
+}
+
+fn bind(config: &DimseConfig) -> Result<TcpListener> {
+    config.validate()?;
+    let address = config.bind_address();
+    let listener = TcpListener::bind(&address).map_err(|e| Error::Server(format!("Failed to bind {}: {}", address, e)))?;
+    info!("DIMSE listener {} on {}", config.ae_title, address);
+    Ok(listener)
+}
+
+/// Serve every association on its own thread until `stopped` is set
+fn accept_associations(config: &DimseConfig, listener: TcpListener, queue: Queue, stopped: Arc<AtomicBool>) {
+    let mut options = ServerAssociationOptions::new()
+        .accept_called_ae_title()
+        .ae_title(config.ae_title.clone())
+        .timeout(config.timeout);
+    for uid in ABSTRACT_SYNTAXES {
+        options = options.with_abstract_syntax(*uid);
+    }
+    for ts in TransferSyntaxRegistry.iter().filter(|ts| !ts.is_unsupported()) {
+        options = options.with_transfer_syntax(ts.uid());
+    }
+    let options = Arc::new(options);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let (options, queue) = (options.clone(), queue.clone());
+                    thread::spawn(move || serve_association(&options, stream, &queue));
+                }
+                Err(e) => warn!("Failed to accept a DIMSE connection: {}", e),
+            }
+        }
+    });
+}
+
+/// Address to reach a listener bound to `address`, which may be the unspecified address
+fn loopback(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port()),
+        _ => address,
+    }
+}
+
+/// Negotiate an association and answer its messages until the peer releases or aborts it
+fn serve_association(options: &ServerAssociationOptions<'static, AcceptCalledAeTitle>, stream: TcpStream, queue: &Queue) {
+    let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    let mut association = match options.establish(stream) {
+        Ok(association) => association,
+        Err(e) => {
+            warn!("Rejected association from {}: {}", peer, e);
+            return;
+        }
+    };
+    let calling = association.client_ae_title().to_string();
+    info!("Association from {} ({})", calling, peer);
+    match receive_messages(&mut association, &calling, queue) {
+        Ok(()) => info!("Association from {} released", calling),
+        Err(e) => warn!("Association from {} ended: {}", calling, e),
+    }
+}
+
+fn receive_messages(association: &mut Association, calling: &str, queue: &Queue) -> Result<()> {
+    let (mut command, mut data) = (Vec::new(), Vec::new());
+    // Request waiting for its data set
+    let mut request: Option<InMemDicomObject> = None;
+    loop {
+        match association.receive().map_err(dimse_error)? {
+            Pdu::PData { data: values } => {
+                for PDataValue { presentation_context_id, value_type, is_last, data: fragment } in values {
+                    match value_type {
+                        PDataValueType::Command => {
+                            command.extend_from_slice(&fragment);
+                            if !is_last {
+                                continue;
+                            }
+                            let message = InMemDicomObject::read_dataset_with_ts(command.as_slice(), &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased())
+                                .map_err(dimse_error)?;
+                            command.clear();
+                            if ushort(&message, tags::COMMAND_DATA_SET_TYPE) != Some(NO_DATA_SET) {
+                                data.clear();
+                                request = Some(message);
+                                continue;
+                            }
+                            let status = match ushort(&message, tags::COMMAND_FIELD) {
+                                Some(C_ECHO_RQ) => SUCCESS,
+                                _ => UNRECOGNIZED_OPERATION,
+                            };
+                            respond(association, presentation_context_id, &message, status)?;
+                        }
+                        PDataValueType::Data => {
+                            data.extend_from_slice(&fragment);
+                            if !is_last {
+                                continue;
+                            }
+                            let Some(message) = request.take() else {
+                                warn!("Ignoring a data set from {} without a command", calling);
+                                data.clear();
+                                continue;
+                            };
+                            let status = match ushort(&message, tags::COMMAND_FIELD) {
+                                Some(C_STORE_RQ) => accept_instance(association, presentation_context_id, &message, &data, calling, queue),
+                                _ => UNRECOGNIZED_OPERATION,
+                            };
+                            data.clear();
+                            respond(association, presentation_context_id, &message, status)?;
+                        }
+                    }
+                }
+            }
+            Pdu::ReleaseRQ => {
+                association.send(&Pdu::ReleaseRP).map_err(dimse_error)?;
+                return Ok(());
+            }
+            Pdu::AbortRQ { source } => return Err(dimse_error(format!("aborted by {:?}", source))),
+            _ => {}
+        }
+    }
+}
+
+/// Parse a received data set and queue it, answering with the C-STORE status
+fn accept_instance(association: &Association, presentation_context_id: u8, request: &InMemDicomObject, data: &[u8], calling: &str, queue: &Queue) -> u16 {
+    let transfer_syntax = association.presentation_contexts()
+        .iter()
+        .find(|context| context.id == presentation_context_id)
+        .map(|context| context.transfer_syntax.trim_end_matches('\0').to_string())
+        .unwrap_or_default();
+    let instance = TransferSyntaxRegistry.get(&transfer_syntax)
+        .ok_or_else(|| dimse_error(format!("unknown transfer syntax {}", transfer_syntax)))
+        .and_then(|ts| InMemDicomObject::read_dataset_with_ts(data, ts).map_err(dimse_error))
+        .and_then(|dataset| {
+            let meta = FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uid(request, tags::AFFECTED_SOP_CLASS_UID).unwrap_or_default())
+                .media_storage_sop_instance_uid(uid(request, tags::AFFECTED_SOP_INSTANCE_UID).unwrap_or_default())
+                .transfer_syntax(&transfer_syntax)
+                .build()
+                .map_err(dimse_error)?;
+            let obj = dataset.with_exact_meta(meta);
+            Ok((instance_uids(&obj)?, obj))
+        });
+    let (uids, obj) = match instance {
+        Ok(instance) => instance,
+        Err(e) => {
+            warn!("Rejected an instance from {}: {}", calling, e);
+            return CANNOT_UNDERSTAND;
+        }
+    };
+    let sop_instance = uids.sop_instance.clone();
+    match queue.try_send((uids, obj)) {
+        Ok(()) => {
+            info!("Received {} from {}", sop_instance, calling);
+            SUCCESS
+        }
+        Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+            warn!("Refused {} from {}: the processing queue is full", sop_instance, calling);
+            OUT_OF_RESOURCES
+        }
+    }
+}
+
+/// Send the response to `request`, echoing its message ID and affected SOP
+fn respond(association: &mut Association, presentation_context_id: u8, request: &InMemDicomObject, status: u16) -> Result<()> {
+    let command_field = ushort(request, tags::COMMAND_FIELD).unwrap_or_default() | RESPONSE;
+    let message_id = ushort(request, tags::MESSAGE_ID).unwrap_or_default();
+    let mut elements = vec![
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [command_field])),
+        DataElement::new(tags::MESSAGE_ID_BEING_RESPONDED_TO, VR::US, dicom_value!(U16, [message_id])),
+        DataElement::new(tags::COMMAND_DATA_SET_TYPE, VR::US, dicom_value!(U16, [NO_DATA_SET])),
+        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [status])),
+    ];
+    for tag in [tags::AFFECTED_SOP_CLASS_UID, tags::AFFECTED_SOP_INSTANCE_UID] {
+        if let Some(value) = uid(request, tag) {
+            elements.push(DataElement::new(tag, VR::UI, PrimitiveValue::from(value)));
+        }
+    }
+    let mut bytes = Vec::new();
+    InMemDicomObject::command_from_element_iter(elements)
+        .write_dataset_with_ts(&mut bytes, &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased())
+        .map_err(dimse_error)?;
+    association
+        .send(&Pdu::PData {
+            data: vec![PDataValue { presentation_context_id, value_type: PDataValueType::Command, is_last: true, data: bytes }],
+        })
+        .map_err(dimse_error)
+}
+
+/// Send `obj` to `destination` with C-STORE on a new association
+///
+/// One presentation context is proposed per configured transfer syntax, and the accepted one
+/// highest in the destination's preference order is used. Only transfer syntaxes with native
+/// pixel data are offered, as the instance is not re-encoded. Warning statuses are logged and
+/// count as stored.
+pub fn store(destination: &DimseDestination, obj: &DicomFile) -> Result<()> {
+    let sop_class = obj.meta().media_storage_sop_class_uid().trim_end_matches('\0').to_string();
+    let sop_instance = obj.meta().media_storage_sop_instance_uid().trim_end_matches('\0').to_string();
+    let offered: Vec<&str> = destination.transfer_syntaxes
+        .iter()
+        .map(String::as_str)
+        .filter(|uid| TransferSyntaxRegistry.get(uid).is_some_and(|ts| ts.is_codec_free()))
+        .collect();
+    if offered.is_empty() {
+        return Err(Error::InvalidOption(format!(
+            "Destination {} offers no uncompressed transfer syntax: {}", destination.name, destination.transfer_syntaxes.join(", "))));
+    }
+
+    let (mut association, context, ts) = connect(destination, &sop_class, &offered)?;
+    let status = send_store(&mut association, context, &sop_class, &sop_instance, obj, ts)
+        .map_err(|e| destination_error(destination, e));
+    let _ = association.release();
+    match status? {
+        SUCCESS => info!("Stored {} at {}", sop_instance, destination.name),
+        // 0xB000, 0xB006 and 0xB007: stored with coercion or some elements discarded
+        status if status & 0xF000 == 0xB000 => warn!("Stored {} at {} with warning status {:04X}", sop_instance, destination.name, status),
+        status => return Err(destination_error(destination, format!("C-STORE of {} failed with status {:04X}", sop_instance, status))),
+    }
+    Ok(())
+}
+
+/// Send the C-STORE request and its data set, returning the response status
+fn send_store(
+    association: &mut ClientAssociation<TcpStream>,
+    presentation_context_id: u8,
+    sop_class: &str,
+    sop_instance: &str,
+    obj: &DicomFile,
+    ts: &TransferSyntax,
+) -> Result<u16> {
+    let mut data = Vec::new();
+    obj.write_dataset_with_ts(&mut data, ts).map_err(dimse_error)?;
+    send_request(association, presentation_context_id, [
+        DataElement::new(tags::AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(sop_class)),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [C_STORE_RQ])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [1])),
+        DataElement::new(tags::PRIORITY, VR::US, dicom_value!(U16, [MEDIUM])),
+        DataElement::new(tags::AFFECTED_SOP_INSTANCE_UID, VR::UI, PrimitiveValue::from(sop_instance)),
+    ], Some(&data))?;
+    let (response, _) = receive_response(association)?;
+    ushort(&response, tags::STATUS).ok_or_else(|| dimse_error("C-STORE response without a status"))
+}
+
+/// Study-level C-FIND identifier of a query such as `PatientID=12345&ModalitiesInStudy=CR`
+///
+/// Keys are attribute keywords; an empty value asks for the attribute to be returned.
+fn query_identifier(query: &str) -> Result<InMemDicomObject> {
+    let mut identifier = InMemDicomObject::new_empty();
+    identifier.put(DataElement::new(tags::QUERY_RETRIEVE_LEVEL, VR::CS, PrimitiveValue::from("STUDY")));
+    identifier.put(DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, PrimitiveValue::Empty));
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let entry = StandardDataDictionary.by_name(name)
+            .ok_or_else(|| Error::InvalidOption(format!("Unknown C-FIND attribute: {}", name)))?;
+        if entry.tag() == tags::QUERY_RETRIEVE_LEVEL {
+            return Err(Error::InvalidOption("C-FIND queries are study level, QueryRetrieveLevel can't be set".to_string()));
+        }
+        let value = if value.is_empty() { PrimitiveValue::Empty } else { PrimitiveValue::from(value) };
+        identifier.put(DataElement::new(entry.tag(), entry.vr().relaxed(), value));
+    }
+    Ok(identifier)
+}
+
+/// Study Instance UIDs of the studies on `source` matching `identifier`, with C-FIND in the
+/// Study Root information model
+fn find_studies(source: &DimseDestination, identifier: &InMemDicomObject) -> Result<Vec<String>> {
+    let (mut association, context, ts) = connect(source, uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND, QUERY_TRANSFER_SYNTAXES)?;
+    let studies = send_find(&mut association, context, identifier, ts).map_err(|e| destination_error(source, e));
+    let _ = association.release();
+    let studies = studies?;
+    info!("C-FIND on {} matched {} studies", source.name, studies.len());
+    Ok(studies)
+}
+
+fn send_find(association: &mut ClientAssociation<TcpStream>, presentation_context_id: u8, identifier: &InMemDicomObject, ts: &TransferSyntax) -> Result<Vec<String>> {
+    let mut data = Vec::new();
+    identifier.write_dataset_with_ts(&mut data, ts).map_err(dimse_error)?;
+    send_request(association, presentation_context_id, [
+        DataElement::new(tags::AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND)),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [C_FIND_RQ])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [1])),
+        DataElement::new(tags::PRIORITY, VR::US, dicom_value!(U16, [MEDIUM])),
+    ], Some(&data))?;
+    let mut studies = Vec::new();
+    loop {
+        let (response, data) = receive_response(association)?;
+        match ushort(&response, tags::STATUS) {
+            Some(PENDING) | Some(PENDING_WARNING) => {
+                let study = data
+                    .map(|data| InMemDicomObject::read_dataset_with_ts(data.as_slice(), ts).map_err(dimse_error))
+                    .transpose()?
+                    .and_then(|matched| uid(&matched, tags::STUDY_INSTANCE_UID))
+                    .filter(|study| !study.is_empty());
+                match study {
+                    Some(study) => studies.push(study),
+                    None => warn!("Ignoring a C-FIND match without a Study Instance UID"),
+                }
+            }
+            Some(SUCCESS) => return Ok(studies),
+            Some(status) => return Err(dimse_error(format!("C-FIND failed with status {:04X}", status))),
+            None => return Err(dimse_error("C-FIND response without a status")),
+        }
+    }
+}
+
+/// Retrieve `studies` from `source` with C-MOVE to `move_destination`, one after the other on a
+/// single association
+fn move_studies(source: &DimseDestination, studies: &[String], move_destination: &str) -> Result<()> {
+    if studies.is_empty() {
+        return Ok(());
+    }
+    let (mut association, context, ts) = connect(source, uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_MOVE, QUERY_TRANSFER_SYNTAXES)?;
+    let mut failed = 0;
+    for (index, study) in studies.iter().enumerate() {
+        let response = match send_move(&mut association, context, index as u16 + 1, study, move_destination, ts) {
+            Ok(response) => response,
+            Err(e) => {
+                let _ = association.abort();
+                return Err(destination_error(source, format!("C-MOVE of study {} failed: {}", study, e)));
+            }
+        };
+        let count = |tag| ushort(&response, tag).unwrap_or_default();
+        match ushort(&response, tags::STATUS).unwrap_or_default() {
+            SUCCESS => info!("Moved study {}: {} instance(s)", study, count(tags::NUMBER_OF_COMPLETED_SUBOPERATIONS)),
+            SUB_OPERATIONS_FAILED => warn!("Moved study {}: {} instance(s), {} failed", study,
+                count(tags::NUMBER_OF_COMPLETED_SUBOPERATIONS) + count(tags::NUMBER_OF_WARNING_SUBOPERATIONS),
+                count(tags::NUMBER_OF_FAILED_SUBOPERATIONS)),
+            status => {
+                warn!("C-MOVE of study {} from {} failed with status {:04X}", study, source.name, status);
+                failed += 1;
+            }
+        }
+    }
+    let _ = association.release();
+    if failed > 0 {
+        return Err(destination_error(source, format!("C-MOVE failed for {} of {} studies", failed, studies.len())));
+    }
+    Ok(())
+}
+
+/// Move one study, returning the final response once the sub-operations are done
+fn send_move(
+    association: &mut ClientAssociation<TcpStream>,
+    presentation_context_id: u8,
+    message_id: u16,
+    study: &str,
+    move_destination: &str,
+    ts: &TransferSyntax,
+) -> Result<InMemDicomObject> {
+    let identifier = InMemDicomObject::from_element_iter([
+        DataElement::new(tags::QUERY_RETRIEVE_LEVEL, VR::CS, PrimitiveValue::from("STUDY")),
+        DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, PrimitiveValue::from(study)),
+    ]);
+    let mut data = Vec::new();
+    identifier.write_dataset_with_ts(&mut data, ts).map_err(dimse_error)?;
+    send_request(association, presentation_context_id, [
+        DataElement::new(tags::AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_MOVE)),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [C_MOVE_RQ])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [message_id])),
+        DataElement::new(tags::PRIORITY, VR::US, dicom_value!(U16, [MEDIUM])),
+        DataElement::new(tags::MOVE_DESTINATION, VR::AE, PrimitiveValue::from(move_destination)),
+    ], Some(&data))?;
+    loop {
+        let (response, _) = receive_response(association)?;
+        match ushort(&response, tags::STATUS) {
+            Some(PENDING) | Some(PENDING_WARNING) => continue,
+            Some(_) => return Ok(response),
+            None => return Err(dimse_error("C-MOVE response without a status")),
+        }
+    }
+}
+
+/// Open an association with `destination` proposing `abstract_syntax` with each of
+/// `transfer_syntaxes` in its own presentation context, returning the accepted context that comes
+/// first in `transfer_syntaxes` and its transfer syntax
+fn connect(destination: &DimseDestination, abstract_syntax: &str, transfer_syntaxes: &[&str])
+    -> Result<(ClientAssociation<TcpStream>, u8, &'static TransferSyntax)> {
+    let mut options = ClientAssociationOptions::new()
+        .calling_ae_title(destination.calling_ae_title.clone())
+        .called_ae_title(destination.ae_title.clone())
+        .connection_timeout(destination.timeout)
+        .read_timeout(destination.timeout)
+        .write_timeout(destination.timeout);
+    for uid in transfer_syntaxes {
+        options = options.with_presentation_context(abstract_syntax.to_string(), vec![uid.to_string()]);
+    }
+    let association = options
+        .establish(destination.address.as_str())
+        .map_err(|e| destination_error(destination, format!("association with {}@{} failed: {}", destination.ae_title, destination.address, e)))?;
+
+    let accepted = association.presentation_contexts().to_vec();
+    let chosen = transfer_syntaxes.iter().find_map(|uid| {
+        accepted.iter().find(|context| {
+            context.reason == dicom::ul::pdu::PresentationContextResultReason::Acceptance
+                && context.transfer_syntax.trim_end_matches('\0') == *uid
+        })
+    });
+    let Some(context) = chosen else {
+        let _ = association.abort();
+        return Err(destination_error(destination, format!("{} accepts none of the offered transfer syntaxes for {}", destination.ae_title, abstract_syntax)));
+    };
+    let ts = TransferSyntaxRegistry.get(context.transfer_syntax.trim_end_matches('\0'))
+        .ok_or_else(|| destination_error(destination, format!("unknown transfer syntax {}", context.transfer_syntax)))?;
+    Ok((association, context.id, ts))
+}
+
+/// Send a request command, with the Command Data Set Type set from `data`, followed by `data`
+fn send_request(
+    association: &mut ClientAssociation<TcpStream>,
+    presentation_context_id: u8,
+    elements: impl IntoIterator<Item = InMemElement>,
+    data: Option<&[u8]>,
+) -> Result<()> {
+    let data_set_type = if data.is_some() { DATA_SET } else { NO_DATA_SET };
+    let message = InMemDicomObject::command_from_element_iter(elements.into_iter()
+        .chain([DataElement::new(tags::COMMAND_DATA_SET_TYPE, VR::US, dicom_value!(U16, [data_set_type]))]));
+    let mut command = Vec::new();
+    message.write_dataset_with_ts(&mut command, &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased()).map_err(dimse_error)?;
+    association
+        .send(&Pdu::PData {
+            data: vec![PDataValue { presentation_context_id, value_type: PDataValueType::Command, is_last: true, data: command }],
+        })
+        .map_err(dimse_error)?;
+    if let Some(data) = data {
+        // The writer splits the data set into PDUs of the size the peer accepts
+        let mut writer = association.send_pdata(presentation_context_id);
+        writer.write_all(data).and_then(|_| writer.finish()).map_err(dimse_error)?;
+    }
+    Ok(())
+}
+
+/// Next response on a client association, with the data set that follows it if any
+fn receive_response(association: &mut ClientAssociation<TcpStream>) -> Result<(InMemDicomObject, Option<Vec<u8>>)> {
+    let (mut command, mut data) = (Vec::new(), Vec::new());
+    // Response waiting for its data set
+    let mut response: Option<InMemDicomObject> = None;
+    loop {
+        match association.receive().map_err(dimse_error)? {
+            Pdu::PData { data: values } => {
+                for value in values {
+                    match value.value_type {
+                        PDataValueType::Command => {
+                            command.extend_from_slice(&value.data);
+                            if !value.is_last {
+                                continue;
+                            }
+                            let message = InMemDicomObject::read_dataset_with_ts(command.as_slice(), &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased())
+                                .map_err(dimse_error)?;
+                            if ushort(&message, tags::COMMAND_DATA_SET_TYPE) == Some(NO_DATA_SET) {
+                                return Ok((message, None));
+                            }
+                            command.clear();
+                            response = Some(message);
+                        }
+                        PDataValueType::Data => {
+                            data.extend_from_slice(&value.data);
+                            if value.is_last && let Some(message) = response.take() {
+                                return Ok((message, Some(data)));
+                            }
+                        }
+                    }
+                }
+            }
+            Pdu::AbortRQ { source } => return Err(dimse_error(format!("aborted by {:?}", source))),
+            _ => {}
+        }
+    }
+}
+
+fn ushort(message: &InMemDicomObject, tag: Tag) -> Option<u16> {
+    message.element(tag).ok()?.to_int().ok()
+}
+
+fn uid(message: &InMemDicomObject, tag: Tag) -> Option<String> {
+    Some(message.element(tag).ok()?.to_str().ok()?.trim_end_matches(['\0', ' ']).to_string())
+}
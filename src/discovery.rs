@@ -0,0 +1,212 @@
+//! Service discovery for DL service URLs: `http+srv://<record>/path` names a DNS SRV record and
+//! `http+consul://<service>/path` a Consul service (either with `https+`), resolved at call time
+//! to one of the service's healthy instances.
+
+use hickory_resolver::proto::rr::RData;
+use hickory_resolver::TokioResolver;
+use log::{info, warn};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long the instances found for a URL are used before they are looked up again
+const REFRESH: Duration = Duration::from_secs(10);
+/// How long an instance that failed a call is passed over
+const EJECT_FOR: Duration = Duration::from_secs(30);
+/// Agent queried when `CONSUL_HTTP_ADDR` is not set
+const DEFAULT_CONSUL_ADDR: &str = "http://127.0.0.1:8500";
+
+/// Parts of a discovery URL, e.g. `https+consul://tb-model/infer`
+struct Target<'a> {
+    /// `http` or `https`
+    scheme: &'a str,
+    /// `srv` or `consul`
+    registry: &'a str,
+    name: &'a str,
+    /// Path and query, starting with `/` unless empty
+    rest: &'a str,
+}
+
+fn parse(url: &str) -> Option<Target<'_>> {
+    let (scheme, rest) = url.split_once("://")?;
+    let (scheme, registry) = scheme.split_once('+')?;
+    if !matches!(scheme, "http" | "https") || !matches!(registry, "srv" | "consul") {
+        return None;
+    }
+    let split = rest.find(['/', '?']).unwrap_or(rest.len());
+    Some(Target { scheme, registry, name: &rest[..split], rest: &rest[split..] })
+}
+
+/// Whether `url` names its instances through a registry rather than directly
+pub fn is_discovered(url: &str) -> bool {
+    parse(url).is_some()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Instance {
+    host: String,
+    port: u16,
+    /// Lower is preferred; only SRV records have more than one
+    priority: u16,
+    weight: u16,
+}
+
+impl Instance {
+    fn address(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Resolved {
+    instances: Vec<Instance>,
+    until: Instant,
+}
+
+/// The instance a call went to; report it with [`Discovery::failed`] when it could not serve
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub url: String,
+    pub(crate) address: String,
+}
+
+/// Instances found for discovery URLs, cached for [`REFRESH`], and the instances that recently failed
+#[derive(Debug, Default)]
+pub struct Discovery {
+    resolver: OnceLock<Result<TokioResolver, String>>,
+    consul: OnceLock<reqwest::Client>,
+    resolved: Mutex<HashMap<String, Resolved>>,
+    ejected: Mutex<HashMap<String, Instant>>,
+}
+
+impl Discovery {
+    /// URL of one instance for `url`, preferring the lowest SRV priority and spreading calls by
+    /// weight; instances that failed within [`EJECT_FOR`] are skipped unless no other is left
+    pub async fn endpoint(&self, url: &str) -> Result<Endpoint, String> {
+        let Some(target) = parse(url) else {
+            return Err(format!("not a discovery URL: {}", url));
+        };
+        let key = format!("{}+{}://{}", target.scheme, target.registry, target.name);
+        let cached = {
+            let resolved = self.resolved.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            resolved.get(&key).filter(|resolved| resolved.until > Instant::now()).map(|resolved| resolved.instances.clone())
+        };
+        let instances = match cached {
+            Some(instances) => instances,
+            None => {
+                let instances = match target.registry {
+                    "srv" => self.lookup_srv(target.name).await?,
+                    _ => self.lookup_consul(target.name).await?,
+                };
+                let mut resolved = self.resolved.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                if resolved.get(&key).is_none_or(|previous| previous.instances != instances) {
+                    let addresses: Vec<String> = instances.iter().map(Instance::address).collect();
+                    info!("Discovered {} instance(s) for {}: {}", instances.len(), key, addresses.join(", "));
+                }
+                resolved.insert(key.clone(), Resolved { instances: instances.clone(), until: Instant::now() + REFRESH });
+                instances
+            }
+        };
+        if instances.is_empty() {
+            return Err(format!("no instances found for {}", key));
+        }
+        let instance = self.pick(&instances);
+        let address = instance.address();
+        Ok(Endpoint { url: format!("{}://{}{}", target.scheme, address, target.rest), address })
+    }
+
+    /// Pass over the instance of `endpoint` for a while, after a connection error or 5xx response
+    pub fn failed(&self, endpoint: &Endpoint) {
+        let mut ejected = self.ejected.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if ejected.insert(endpoint.address.clone(), Instant::now() + EJECT_FOR).is_none_or(|until| until <= Instant::now()) {
+            warn!("Passing over {} for {}s after a failed call", endpoint.address, EJECT_FOR.as_secs());
+        }
+    }
+
+    fn pick<'a>(&self, instances: &'a [Instance]) -> &'a Instance {
+        let now = Instant::now();
+        let healthy: Vec<&Instance> = {
+            let mut ejected = self.ejected.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            ejected.retain(|_, until| *until > now);
+            instances.iter().filter(|instance| !ejected.contains_key(&instance.address())).collect()
+        };
+        let candidates = if healthy.is_empty() { instances.iter().collect() } else { healthy };
+        let priority = candidates.iter().map(|instance| instance.priority).min().unwrap_or_default();
+        let group: Vec<&Instance> = candidates.into_iter().filter(|instance| instance.priority == priority).collect();
+        // Weight 0 still gets an occasional call, as RFC 2782 asks
+        let total: u64 = group.iter().map(|instance| u64::from(instance.weight) + 1).sum();
+        let mut point = RandomState::new().hash_one(now) % total;
+        for instance in &group {
+            let weight = u64::from(instance.weight) + 1;
+            if point < weight {
+                return instance;
+            }
+            point -= weight;
+        }
+        group[0]
+    }
+
+    /// Targets of the SRV record `name` from the system resolver
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<Instance>, String> {
+        let resolver = self.resolver
+            .get_or_init(|| TokioResolver::builder_tokio().and_then(|builder| builder.build()).map_err(|e| e.to_string()))
+            .as_ref()
+            .map_err(|e| format!("failed to set up the DNS resolver: {}", e))?;
+        let lookup = resolver.srv_lookup(name).await.map_err(|e| format!("SRV lookup of {} failed: {}", name, e))?;
+        Ok(lookup.answers()
+            .iter()
+            .filter_map(|record| match &record.data {
+                RData::SRV(srv) => Some(srv),
+                _ => None,
+            })
+            // A target of "." means the service is deliberately unavailable
+            .filter(|srv| !srv.target.is_root())
+            .map(|srv| Instance {
+                host: srv.target.to_utf8().trim_end_matches('.').to_string(),
+                port: srv.port,
+                priority: srv.priority,
+                weight: srv.weight,
+            })
+            .collect())
+    }
+
+    /// Instances of `name` passing their health checks, from the agent at `CONSUL_HTTP_ADDR`
+    /// with the ACL token in `CONSUL_HTTP_TOKEN`
+    async fn lookup_consul(&self, name: &str) -> Result<Vec<Instance>, String> {
+        let address = std::env::var("CONSUL_HTTP_ADDR").unwrap_or_else(|_| DEFAULT_CONSUL_ADDR.to_string());
+        let address = match address.contains("://") {
+            true => address.trim_end_matches('/').to_string(),
+            false => format!("http://{}", address.trim_end_matches('/')),
+        };
+        let url = format!("{}/v1/health/service/{}?passing=true", address, name);
+        let mut request = self.consul.get_or_init(reqwest::Client::new).get(&url).timeout(REFRESH);
+        if let Ok(token) = std::env::var("CONSUL_HTTP_TOKEN") {
+            request = request.header("X-Consul-Token", token);
+        }
+        let failed = |e: &dyn std::fmt::Display| format!("Consul lookup of {} at {} failed: {}", name, address, e);
+        let response = request.send().await.map_err(|e| failed(&e))?;
+        if !response.status().is_success() {
+            return Err(failed(&format!("HTTP {}", response.status().as_u16())));
+        }
+        let body = response.bytes().await.map_err(|e| failed(&e))?;
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&body).map_err(|e| failed(&e))?;
+        Ok(entries.iter()
+            .filter_map(|entry| {
+                let service = &entry["Service"];
+                // Services registered without an address use their node's
+                let host = service["Address"].as_str().filter(|host| !host.is_empty()).or_else(|| entry["Node"]["Address"].as_str())?;
+                Some(Instance {
+                    host: host.to_string(),
+                    port: u16::try_from(service["Port"].as_u64()?).ok()?,
+                    priority: 0,
+                    weight: service["Weights"]["Passing"].as_u64().map(|weight| weight.min(u64::from(u16::MAX)) as u16).unwrap_or(1),
+                })
+            })
+            .collect())
+    }
+}
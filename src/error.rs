@@ -0,0 +1,122 @@
+//! Typed errors shared by every pipeline stage.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Boxed underlying cause carried by the contextual variants
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The DICOM object could not be parsed or its pixel data decoded
+    #[error("DICOM decode error ({context}): {source}")]
+    DicomDecode {
+        context: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// Heatmap data could not be read or parsed
+    #[error("failed to load heatmap {path}: {source}")]
+    HeatmapLoad {
+        path: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// Array dimensions don't agree with the declared or expected shape
+    #[error("shape mismatch in {context}: expected {expected} values, found {found}")]
+    ShapeMismatch {
+        context: String,
+        expected: usize,
+        found: usize,
+    },
+
+    /// Building, blending or encoding an image failed
+    #[error("render error: {0}")]
+    Render(String),
+
+    #[error("I/O error on {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// An upstream DL service call failed
+    #[error("service '{service}' error: {message}")]
+    Service { service: String, message: String },
+
+    /// A processing option is out of range or unknown
+    #[error("invalid option: {0}")]
+    InvalidOption(String),
+
+    /// The HTTP server could not start or stopped unexpectedly
+    #[error("server error: {0}")]
+    Server(String),
+
+    /// The run was cancelled through its `CancellationToken`
+    #[error("cancelled before {stage}")]
+    Cancelled { stage: &'static str },
+
+    /// The run's deadline passed at `stage`
+    #[error("deadline exceeded at {stage}")]
+    Timeout { stage: &'static str },
+
+    /// An input is bigger than the configured limit
+    #[error("{what} exceeds the {limit}-byte limit")]
+    TooLarge { what: String, limit: usize },
+
+    /// The caller's tenant policy doesn't allow the request
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    /// A local model could not be loaded or run
+    #[error("inference error: {0}")]
+    Inference(String),
+
+    /// A post-processing plugin could not be compiled or failed while running
+    #[error("plugin error: {0}")]
+    Plugin(String),
+
+    /// The input failed quality control, and processing is gated on it
+    #[error("quality control failed: {0}")]
+    QualityControl(String),
+}
+
+impl Error {
+    pub fn dicom(context: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Error::DicomDecode { context: context.into(), source: source.into() }
+    }
+
+    pub fn heatmap(path: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Error::HeatmapLoad { path: path.into(), source: source.into() }
+    }
+
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::Io { path: path.into(), source }
+    }
+
+    /// Stable machine-readable name of the failure category
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::DicomDecode { .. } => "dicom_decode",
+            Error::HeatmapLoad { .. } => "heatmap_load",
+            Error::ShapeMismatch { .. } => "shape_mismatch",
+            Error::Render(_) => "render",
+            Error::Io { .. } => "io",
+            Error::Service { .. } => "service",
+            Error::InvalidOption(_) => "invalid_option",
+            Error::Server(_) => "server",
+            Error::Cancelled { .. } => "cancelled",
+            Error::Timeout { .. } => "timeout",
+            Error::TooLarge { .. } => "too_large",
+            Error::Forbidden(_) => "forbidden",
+            Error::Inference(_) => "inference",
+            Error::Plugin(_) => "plugin",
+            Error::QualityControl(_) => "quality_control",
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Evaluation of a heatmap against a ground-truth segmentation mask: the normalized heatmap is
+//! thresholded, and its positive pixels are scored against the mask with Dice, IoU, sensitivity
+//! and specificity.
+
+use log::warn;
+use image::DynamicImage;
+use ndarray::{Array2, ArrayD, IxDyn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::heatmap::resize_indices;
+
+/// Normalized level heatmap pixels count as positive from when the blend sets no threshold
+pub const DEFAULT_MASK_THRESHOLD: f32 = 0.5;
+
+/// Scores of a thresholded heatmap against a ground-truth mask; a score is None when its
+/// denominator is zero, e.g. the sensitivity of a mask without positive pixels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaskEvaluation {
+    /// Normalized level at and above which heatmap pixels are positive
+    pub threshold: f32,
+    pub dice: Option<f64>,
+    pub iou: Option<f64>,
+    pub sensitivity: Option<f64>,
+    pub specificity: Option<f64>,
+    pub true_positives: u64,
+    pub false_positives: u64,
+    pub false_negatives: u64,
+    pub true_negatives: u64,
+}
+
+/// Score the pixels of `normalized` at or above `threshold` against `mask`, which is resized
+/// to the heatmap with nearest-neighbor sampling if their shapes differ
+pub fn evaluate_mask(normalized: &Array2<f32>, mask: &Array2<bool>, threshold: f32) -> MaskEvaluation {
+    let (height, width) = normalized.dim();
+    if mask.dim() != (height, width) {
+        warn!("Ground-truth mask dimensions ({}x{}) don't match the heatmap ({}x{}), resizing...",
+              mask.nrows(), mask.ncols(), height, width);
+    }
+    let (rows, cols) = resize_indices(mask.dim(), width, height);
+
+    let mut counts = [[0u64; 2]; 2];
+    for ((y, x), &value) in normalized.indexed_iter() {
+        let predicted = value >= threshold;
+        let actual = mask[[rows[y], cols[x]]];
+        counts[usize::from(predicted)][usize::from(actual)] += 1;
+    }
+    let [[true_negatives, false_negatives], [false_positives, true_positives]] = counts;
+
+    let ratio = |numerator: u64, denominator: u64| (denominator > 0).then(|| numerator as f64 / denominator as f64);
+    MaskEvaluation {
+        threshold,
+        dice: ratio(2 * true_positives, 2 * true_positives + false_positives + false_negatives),
+        iou: ratio(true_positives, true_positives + false_positives + false_negatives),
+        sensitivity: ratio(true_positives, true_positives + false_negatives),
+        specificity: ratio(true_negatives, true_negatives + false_positives),
+        true_positives,
+        false_positives,
+        false_negatives,
+        true_negatives,
+    }
+}
+
+/// Load a ground-truth mask from a PNG (any nonzero gray level is inside) or a 2-D NumPy
+/// `.npy` array of booleans, integers or floats (any nonzero value is inside)
+pub fn load_mask(path: &Path) -> Result<Array2<bool>> {
+    Ok(load_mask_values(path, "ground-truth mask")?.mapv(|value| value.abs() > 0.0))
+}
+
+/// Load a labeled region mask from a PNG whose 8- or 16-bit gray levels are the labels, or a
+/// 2-D NumPy `.npy` array of non-negative integers; label 0 is outside every region
+pub fn load_label_mask(path: &Path) -> Result<Array2<u32>> {
+    let values = load_mask_values(path, "region mask")?;
+    if let Some(value) = values.iter().find(|&&value| !(value >= 0.0 && value.fract() == 0.0 && value <= f64::from(u32::MAX))) {
+        return Err(Error::InvalidOption(format!("Invalid region mask {}: label {} is not a non-negative integer", path.display(), value)));
+    }
+    Ok(values.mapv(|value| value as u32))
+}
+
+/// Values of a PNG or `.npy` mask, named `what` in errors; a mask without pixels is rejected, as
+/// nothing can be resized onto it
+fn load_mask_values(path: &Path, what: &str) -> Result<Array2<f64>> {
+    let invalid = |e: &dyn std::fmt::Display| Error::InvalidOption(format!("Invalid {} {}: {}", what, path.display(), e));
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+    let values: Array2<f64> = match extension.as_deref() {
+        Some("png") => {
+            let image = image::open(path).map_err(|e| invalid(&e))?;
+            let (width, height) = (image.width(), image.height());
+            let values = match image {
+                DynamicImage::ImageLuma16(image) => image.into_raw().into_iter().map(f64::from).collect(),
+                image => image.to_luma8().into_raw().into_iter().map(f64::from).collect(),
+            };
+            Array2::from_shape_vec((height as usize, width as usize), values).expect("one value per pixel")
+        }
+        Some("npy") => {
+            let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+            let values = parse_npy(&bytes).map_err(|e| invalid(&e))?;
+            let shape: Vec<String> = values.shape().iter().map(usize::to_string).collect();
+            values.into_dimensionality().map_err(|_| invalid(&format!("expected a 2-D array, found shape ({})", shape.join(", "))))?
+        }
+        _ => return Err(Error::InvalidOption(format!("Unsupported {} format: {}. Supported: .png, .npy", what, path.display()))),
+    };
+    if values.is_empty() {
+        let (rows, cols) = values.dim();
+        return Err(invalid(&format!("mask is empty ({} x {})", rows, cols)));
+    }
+    Ok(values)
+}
+
+/// Elements of an array in the NumPy `.npy` format, versions 1 to 3
+pub(crate) fn parse_npy(bytes: &[u8]) -> std::result::Result<ArrayD<f64>, String> {
+    let rest = bytes.strip_prefix(b"\x93NUMPY").ok_or("not a .npy file")?;
+    let (header_len, rest) = match rest {
+        [1, _, a, b, rest @ ..] => (usize::from(u16::from_le_bytes([*a, *b])), rest),
+        [2 | 3, _, a, b, c, d, rest @ ..] => (u32::from_le_bytes([*a, *b, *c, *d]) as usize, rest),
+        _ => return Err("unsupported .npy version".to_string()),
+    };
+    let header = rest.get(..header_len).ok_or("truncated header")?;
+    let header = std::str::from_utf8(header).map_err(|_| "header is not text")?;
+    let data = &rest[header_len..];
+
+    let descr = header_value(header, "descr")
+        .and_then(|descr| descr.strip_prefix('\'')?.split('\'').next())
+        .ok_or("no descr in header")?;
+    if header_value(header, "fortran_order").is_some_and(|order| order.starts_with("True")) {
+        return Err("Fortran-ordered arrays are not supported".to_string());
+    }
+    let shape = header_value(header, "shape").ok_or("no shape in header")?;
+    let shape = shape.strip_prefix('(').and_then(|shape| shape.split(')').next()).ok_or("malformed shape")?;
+    let dims: Vec<usize> = shape.split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse().map_err(|_| format!("malformed shape ({})", shape)))
+        .collect::<std::result::Result<_, _>>()?;
+
+    let (order, kind, size) = match descr.as_bytes() {
+        [order @ (b'<' | b'>' | b'|' | b'='), kind, size @ ..] => (*order, *kind, std::str::from_utf8(size).ok().and_then(|size| size.parse::<usize>().ok())),
+        _ => return Err(format!("unsupported dtype {}", descr)),
+    };
+    let size = size.filter(|size| matches!((kind, size), (b'b' | b'u' | b'i', 1 | 2 | 4 | 8) | (b'f', 4 | 8)))
+        .ok_or_else(|| format!("unsupported dtype {}", descr))?;
+    let count = dims.iter().try_fold(1usize, |count, &dim| count.checked_mul(dim)).ok_or("shape too large")?;
+    let values = data.get(..count.saturating_mul(size)).ok_or("truncated data")?;
+
+    let elements = values.chunks_exact(size).map(|element| {
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(element);
+        if order == b'>' {
+            bytes[..size].reverse();
+        }
+        match (kind, size) {
+            (b'f', 4) => f64::from(f32::from_le_bytes(bytes[..4].try_into().expect("4 bytes"))),
+            (b'f', _) => f64::from_le_bytes(bytes),
+            // Sign-extend the integer from its top byte
+            (b'i', _) if bytes[size - 1] & 0x80 != 0 => {
+                bytes[size..].fill(0xff);
+                i64::from_le_bytes(bytes) as f64
+            }
+            _ => u64::from_le_bytes(bytes) as f64,
+        }
+    });
+    Ok(ArrayD::from_shape_vec(IxDyn(&dims), elements.collect()).expect("one value per element"))
+}
+
+/// Text after `'key':` in a `.npy` header dictionary, up to the end of the header
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    Some(header[start..].trim_start().strip_prefix(':')?.trim_start())
+}
@@ -0,0 +1,107 @@
+//! Fan-out of one DICOM to several DL services, rendered as one overlay per service, combined or
+//! fused into one ensemble heatmap.
+
+use log::info;
+use std::str::FromStr;
+
+use crate::asynchronous::spawn;
+use crate::colormap::ColorMap;
+use crate::config::ServiceConfig;
+use crate::error::{Error, Result};
+use crate::heatmap::FusionMethod;
+use crate::pipeline::{HeatmapInput, HeatmapPipeline, Overlay, PipelineResult};
+
+/// Colormaps given in order to the layers of a combined render whose service doesn't set one
+pub const LAYER_COLORMAPS: [ColorMap; 5] = [ColorMap::Red, ColorMap::Viridis, ColorMap::Plasma, ColorMap::Hot, ColorMap::Jet];
+
+/// How the heatmaps of several services are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FanoutMode {
+    /// One image per service, with `_<service>` appended to the output file names
+    #[default]
+    Separate,
+    /// One image with every service's heatmap layered in its own colormap
+    Combined,
+    /// One image with the services' heatmaps fused into one, weighted by their `weight`
+    Fused(FusionMethod),
+}
+
+impl FromStr for FanoutMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "separate" => Ok(FanoutMode::Separate),
+            "combined" => Ok(FanoutMode::Combined),
+            "fused" => Ok(FanoutMode::Fused(FusionMethod::default())),
+            _ => Err(format!("Unknown fan-out mode: {}. Available: separate, combined, fused", s)),
+        }
+    }
+}
+
+impl FanoutMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FanoutMode::Separate => "separate",
+            FanoutMode::Combined => "combined",
+            FanoutMode::Fused(_) => "fused",
+        }
+    }
+}
+
+impl HeatmapPipeline {
+    /// Send the DICOM source to every service concurrently and render their heatmaps
+    ///
+    /// Replaces the pipeline's own heatmap and overlays. Returns one result per service in
+    /// separate mode, or a single result named `a+b+...` in combined and fused mode. In combined
+    /// mode the first service is drawn with the pipeline colormap unless it configures its own;
+    /// the fused heatmap always uses the pipeline colormap. A single service in separate mode
+    /// keeps the configured output names.
+    pub async fn run_fanout(&self, services: &[ServiceConfig], mode: FanoutMode) -> Result<Vec<(String, PipelineResult)>> {
+        let Some(first) = services.first() else {
+            return Err(Error::InvalidOption("Fan-out requires at least one service".to_string()));
+        };
+        let names: Vec<&str> = services.iter().map(|service| service.name.as_str()).collect();
+        info!("Fanning out to {} service(s) ({}): {}", services.len(), mode.name(), names.join(", "));
+
+        if let FanoutMode::Fused(fusion) = mode {
+            let members = services.iter()
+                .map(|service| (HeatmapInput::Service(Box::new(service.clone())), service.weight))
+                .collect();
+            let pipeline = self.with_layers(HeatmapInput::Ensemble { members, fusion }, None, Vec::new());
+            return Ok(vec![(names.join("+"), pipeline.run_async().await?)]);
+        }
+
+        if mode == FanoutMode::Combined && services.len() > 1 {
+            let overlays = services.iter()
+                .enumerate()
+                .skip(1)
+                .map(|(index, service)| Overlay {
+                    heatmap: HeatmapInput::Service(Box::new(service.clone())),
+                    colormap: service.colormap.clone().unwrap_or_else(|| LAYER_COLORMAPS[index % LAYER_COLORMAPS.len()].clone()),
+                })
+                .collect();
+            let pipeline = self.with_layers(HeatmapInput::Service(Box::new(first.clone())), first.colormap.clone(), overlays);
+            for (service, overlay) in services.iter().skip(1).zip(pipeline.overlays()) {
+                info!("Service {} drawn with {} colormap", service.name, format!("{:?}", overlay.colormap).to_lowercase());
+            }
+            return Ok(vec![(names.join("+"), pipeline.run_async().await?)]);
+        }
+
+        let tasks: Vec<_> = services.iter()
+            .map(|service| {
+                let mut pipeline = self.with_layers(HeatmapInput::Service(Box::new(service.clone())), service.colormap.clone(), Vec::new());
+                if services.len() > 1 {
+                    pipeline = pipeline.with_output_suffix(&format!("_{}", service.name));
+                }
+                spawn(async move { pipeline.run_async().await })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(tasks.len());
+        for (service, task) in services.iter().zip(tasks) {
+            let result = task.await.map_err(|e| Error::Render(format!("Background task failed: {}", e)))??;
+            results.push((service.name.clone(), result));
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,251 @@
+//! Stable C API for embedding the renderer, exported from the `cdylib` build.
+//!
+//! See `include/heatmap_processing.h` for the matching declarations. All strings are
+//! NUL-terminated UTF-8; buffers returned by the library must be released with
+//! `hm_free_buffer`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::output::encode_png;
+use crate::pipeline::{HeatmapInput, HeatmapPipeline, ImageSource};
+use crate::render::{BlendMode, BlendOptions};
+use crate::{ColorMap, Normalization};
+
+pub const HM_OK: c_int = 0;
+pub const HM_ERR_INVALID_ARGUMENT: c_int = 1;
+pub const HM_ERR_DICOM_DECODE: c_int = 2;
+pub const HM_ERR_HEATMAP_LOAD: c_int = 3;
+pub const HM_ERR_SHAPE_MISMATCH: c_int = 4;
+pub const HM_ERR_RENDER: c_int = 5;
+pub const HM_ERR_IO: c_int = 6;
+pub const HM_ERR_SERVICE: c_int = 7;
+pub const HM_ERR_CANCELLED: c_int = 8;
+pub const HM_ERR_SERVER: c_int = 9;
+pub const HM_ERR_TIMEOUT: c_int = 10;
+pub const HM_ERR_TOO_LARGE: c_int = 11;
+pub const HM_ERR_FORBIDDEN: c_int = 12;
+pub const HM_ERR_INFERENCE: c_int = 13;
+pub const HM_ERR_PLUGIN: c_int = 14;
+pub const HM_ERR_QUALITY_CONTROL: c_int = 15;
+pub const HM_ERR_PANIC: c_int = 99;
+
+/// Processing options; NULL strings select the defaults
+#[repr(C)]
+pub struct HmOptions {
+    /// red, hot, jet, viridis, plasma, coolwarm
+    pub colormap: *const c_char,
+    /// minmax, zscore, percentile
+    pub normalization: *const c_char,
+    /// Format of `heatmap_bytes`: json, csv, bin or npy
+    pub heatmap_format: *const c_char,
+    /// alpha, additive, screen
+    pub blend: *const c_char,
+    /// 0.0 to 1.0
+    pub opacity: f32,
+    /// Normalized cut-off below which the heatmap is hidden; negative disables it
+    pub threshold: f32,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn error_code(error: &Error) -> c_int {
+    match error {
+        Error::InvalidOption(_) => HM_ERR_INVALID_ARGUMENT,
+        Error::DicomDecode { .. } => HM_ERR_DICOM_DECODE,
+        Error::HeatmapLoad { .. } => HM_ERR_HEATMAP_LOAD,
+        Error::ShapeMismatch { .. } => HM_ERR_SHAPE_MISMATCH,
+        Error::Render(_) => HM_ERR_RENDER,
+        Error::Io { .. } => HM_ERR_IO,
+        Error::Service { .. } => HM_ERR_SERVICE,
+        Error::Cancelled { .. } => HM_ERR_CANCELLED,
+        Error::Server(_) => HM_ERR_SERVER,
+        Error::Timeout { .. } => HM_ERR_TIMEOUT,
+        Error::TooLarge { .. } => HM_ERR_TOO_LARGE,
+        Error::Forbidden(_) => HM_ERR_FORBIDDEN,
+        Error::Inference(_) => HM_ERR_INFERENCE,
+        Error::Plugin(_) => HM_ERR_PLUGIN,
+        Error::QualityControl(_) => HM_ERR_QUALITY_CONTROL,
+    }
+}
+
+/// Read an optional C string, falling back to `default` for NULL
+unsafe fn option_str<'a>(value: *const c_char, default: &'a str, name: &str) -> Result<&'a str> {
+    if value.is_null() {
+        return Ok(default);
+    }
+    // SAFETY: the caller guarantees non-NULL option strings are NUL-terminated and outlive the call
+    unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .map_err(|_| Error::InvalidOption(format!("{} is not valid UTF-8", name)))
+}
+
+/// SAFETY: `data` must be NULL (with `len == 0`) or point to `len` readable bytes
+unsafe fn input_bytes<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8]> {
+    if data.is_null() {
+        return if len == 0 {
+            Ok(&[])
+        } else {
+            Err(Error::InvalidOption(format!("{} is NULL", name)))
+        };
+    }
+    Ok(unsafe { std::slice::from_raw_parts(data, len) })
+}
+
+/// Fill `options` with the library defaults
+///
+/// # Safety
+/// `options` must point to writable memory for one `HmOptions`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hm_options_default(options: *mut HmOptions) {
+    if options.is_null() {
+        return;
+    }
+    let defaults = BlendOptions::default();
+    unsafe {
+        options.write(HmOptions {
+            colormap: ptr::null(),
+            normalization: ptr::null(),
+            heatmap_format: ptr::null(),
+            blend: ptr::null(),
+            opacity: defaults.opacity,
+            threshold: -1.0,
+        });
+    }
+}
+
+/// Decode a DICOM file, overlay the heatmap and return the fused PNG
+///
+/// On success `*out_png`/`*out_len` receive a buffer owned by the caller, to be released
+/// with `hm_free_buffer`. Passing an empty heatmap renders the default gradient. On failure
+/// an `HM_ERR_*` code is returned and `hm_last_error` describes the problem.
+///
+/// # Safety
+/// Input pointers must reference `*_len` readable bytes, `options` may be NULL or point to a
+/// valid `HmOptions`, and `out_png`/`out_len` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hm_process(
+    dicom_bytes: *const u8,
+    dicom_len: usize,
+    heatmap_bytes: *const u8,
+    heatmap_len: usize,
+    options: *const HmOptions,
+    out_png: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    if out_png.is_null() || out_len.is_null() {
+        set_last_error("out_png and out_len must not be NULL".to_string());
+        return HM_ERR_INVALID_ARGUMENT;
+    }
+    unsafe {
+        *out_png = ptr::null_mut();
+        *out_len = 0;
+    }
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+        process(dicom_bytes, dicom_len, heatmap_bytes, heatmap_len, options)
+    }));
+
+    match outcome {
+        Ok(Ok(png)) => {
+            let mut png = png.into_boxed_slice();
+            unsafe {
+                *out_len = png.len();
+                *out_png = png.as_mut_ptr();
+            }
+            std::mem::forget(png);
+            HM_OK
+        }
+        Ok(Err(error)) => {
+            let code = error_code(&error);
+            set_last_error(error.to_string());
+            code
+        }
+        Err(_) => {
+            set_last_error("internal panic while processing".to_string());
+            HM_ERR_PANIC
+        }
+    }
+}
+
+unsafe fn process(
+    dicom_bytes: *const u8,
+    dicom_len: usize,
+    heatmap_bytes: *const u8,
+    heatmap_len: usize,
+    options: *const HmOptions,
+) -> Result<Vec<u8>> {
+    let dicom = unsafe { input_bytes(dicom_bytes, dicom_len, "dicom_bytes")? };
+    let heatmap = unsafe { input_bytes(heatmap_bytes, heatmap_len, "heatmap_bytes")? };
+    if dicom.is_empty() {
+        return Err(Error::InvalidOption("dicom_bytes is empty".to_string()));
+    }
+
+    let defaults = BlendOptions::default();
+    let (colormap, normalization, format, blend, opacity, threshold) = match unsafe { options.as_ref() } {
+        Some(options) => unsafe {
+            (
+                option_str(options.colormap, "red", "colormap")?,
+                option_str(options.normalization, "minmax", "normalization")?,
+                option_str(options.heatmap_format, "json", "heatmap_format")?,
+                option_str(options.blend, "alpha", "blend")?,
+                options.opacity,
+                (options.threshold >= 0.0).then_some(options.threshold),
+            )
+        },
+        None => ("red", "minmax", "json", "alpha", defaults.opacity, None),
+    };
+
+    let mut builder = HeatmapPipeline::builder()
+        .source(ImageSource::DicomBytes(dicom.to_vec()))
+        .colormap(ColorMap::from_str(colormap).map_err(Error::InvalidOption)?)
+        .normalization(Normalization::from_str(normalization).map_err(Error::InvalidOption)?)
+        .blend(BlendOptions {
+            opacity,
+            mode: BlendMode::from_str(blend).map_err(Error::InvalidOption)?,
+            threshold,
+        });
+    if !heatmap.is_empty() {
+        builder = builder.heatmap(HeatmapInput::Bytes { bytes: heatmap.to_vec(), format: format.to_string() });
+    }
+
+    let result = builder.build()?.run()?;
+    encode_png(&result.image)
+}
+
+/// Release a buffer returned by `hm_process`
+///
+/// # Safety
+/// `data`/`len` must come from a single successful `hm_process` call and not be freed twice.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hm_free_buffer(data: *mut u8, len: usize) {
+    if data.is_null() {
+        return;
+    }
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)) });
+}
+
+/// Message for the last error on the calling thread, or NULL if there was none
+///
+/// The pointer stays valid until the next `hm_process` call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn hm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Library version as a static NUL-terminated string
+#[unsafe(no_mangle)]
+pub extern "C" fn hm_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
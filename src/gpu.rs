@@ -0,0 +1,256 @@
+//! GPU backend: heatmaps are resized, normalized, looked up in a colormap table and blended into
+//! the image by wgpu compute shaders, for servers where per-image latency matters.
+//!
+//! The normalization statistics come from the source heatmap, as in tiled rendering, so only the
+//! source values, the resize indices and a colormap table are uploaded next to the image. Images
+//! larger than the device's storage buffers are processed in bands of rows. Pixels are within a
+//! color level of the CPU backend (the table has 4096 entries and shader arithmetic rounds a little
+//! differently).
+
+use image::RgbaImage;
+use log::info;
+use ndarray::Array2;
+use std::sync::mpsc;
+
+use crate::colormap::{get_color_from_value, ColorMap};
+use crate::error::{Error, Result};
+use crate::heatmap::resize_indices;
+use crate::normalize::{resized_normalizer, Normalization};
+use crate::render::{BlendMode, BlendOptions};
+
+pub use crate::render::Backend;
+
+/// Entries of the colormap table the shader looks normalized values up in
+const LUT_SIZE: usize = 4096;
+
+/// Side of the square workgroups both shaders run in
+const WORKGROUP_SIZE: u32 = 16;
+
+const SHADER: &str = include_str!("gpu.wgsl");
+
+/// Device and compiled shaders of the GPU backend, shared by every pipeline rendering on it
+pub struct GpuRenderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    layout: wgpu::BindGroupLayout,
+    normalize: wgpu::ComputePipeline,
+    blend: wgpu::ComputePipeline,
+    adapter: String,
+    /// Largest storage buffer binding, which bounds the rows of a band
+    max_binding: u64,
+}
+
+impl std::fmt::Debug for GpuRenderer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GpuRenderer")
+            .field("adapter", &self.adapter)
+            .field("max_binding", &self.max_binding)
+            .finish()
+    }
+}
+
+impl GpuRenderer {
+    /// Open the high-performance adapter (`WGPU_BACKEND` and `WGPU_ADAPTER_NAME` narrow the
+    /// choice) and compile the shaders
+    pub fn new() -> Result<Self> {
+        pollster::block_on(Self::open())
+    }
+
+    async fn open() -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let options = wgpu::RequestAdapterOptions { power_preference: wgpu::PowerPreference::HighPerformance, ..Default::default() };
+        let adapter = instance.request_adapter(&options).await
+            .map_err(|e| Error::Render(format!("No GPU adapter available: {}", e)))?;
+        let name = adapter.get_info().name;
+        if !adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS) {
+            return Err(Error::Render(format!("GPU adapter {} doesn't support compute shaders", name)));
+        }
+        let limits = adapter.limits();
+        let descriptor = wgpu::DeviceDescriptor { label: Some("heatmap"), required_limits: limits.clone(), ..Default::default() };
+        let (device, queue) = adapter.request_device(&descriptor).await
+            .map_err(|e| Error::Render(format!("Failed to open GPU adapter {}: {}", name, e)))?;
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("heatmap"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { read_only }, has_dynamic_offset: false, min_binding_size: None },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("heatmap"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Uniform, has_dynamic_offset: false, min_binding_size: None },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, true),
+                storage(3, false),
+                storage(4, false),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("heatmap"),
+            bind_group_layouts: &[Some(&layout)],
+            immediate_size: 0,
+        });
+        let pipeline = |entry_point| device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: Some(entry_point),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let (normalize, blend) = (pipeline("resize_normalize"), pipeline("colorize_blend"));
+
+        info!("GPU backend on {} ({:?})", name, adapter.get_info().backend);
+        Ok(GpuRenderer {
+            normalize,
+            blend,
+            layout,
+            device,
+            queue,
+            adapter: name,
+            max_binding: limits.max_storage_buffer_binding_size.min(limits.max_buffer_size),
+        })
+    }
+
+    /// Name of the adapter the shaders run on
+    pub fn adapter(&self) -> &str {
+        &self.adapter
+    }
+
+    /// Resize, normalize and colorize heatmap values and blend them into the base image in
+    /// place, like [`crate::render::blend_heatmap`]
+    pub fn blend_heatmap(
+        &self,
+        base_rgba_image: &mut RgbaImage,
+        data: &Array2<f32>,
+        colormap: &ColorMap,
+        normalization: &Normalization,
+        blend: &BlendOptions,
+    ) -> Result<()> {
+        let (width, height) = base_rgba_image.dimensions();
+        if data.is_empty() {
+            return Err(Error::Render("Heatmap has no values".to_string()));
+        }
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        let row_bytes = u64::from(width) * 4;
+        let band_rows = (self.max_binding / row_bytes).min(u64::from(height)) as u32;
+        let values = data.as_standard_layout();
+        let values: &[f32] = values.as_slice().expect("standard layout arrays are contiguous");
+        if band_rows == 0 || std::mem::size_of_val(values) as u64 > self.max_binding {
+            return Err(Error::Render(format!(
+                "Image of {}x{} with a heatmap of {}x{} exceeds the {}-byte buffers of GPU adapter {}",
+                width, height, data.nrows(), data.ncols(), self.max_binding, self.adapter
+            )));
+        }
+
+        // Resize indices and the colormap table share one buffer: rows, then columns, then colors
+        let (rows, cols) = resize_indices(data.dim(), width as usize, height as usize);
+        let normalizer = resized_normalizer(data, &rows, &cols, normalization);
+        let mut tables: Vec<u32> = rows.iter().chain(&cols).map(|&index| index as u32).collect();
+        tables.extend((0..LUT_SIZE).map(|entry| {
+            let (r, g, b) = get_color_from_value(entry as f32 / (LUT_SIZE - 1) as f32, colormap);
+            u32::from_le_bytes([r, g, b, 0])
+        }));
+
+        let buffer = |label, contents: &[u8], usage| {
+            let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: contents.len().max(4) as u64,
+                usage: usage | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.queue.write_buffer(&buffer, 0, contents);
+            buffer
+        };
+        let band_bytes = row_bytes * u64::from(band_rows);
+        let scratch = |label, usage| self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: band_bytes,
+            usage,
+            mapped_at_creation: false,
+        });
+        let params = buffer("params", &[0; 48], wgpu::BufferUsages::UNIFORM);
+        let heatmap = buffer("heatmap", bytemuck::cast_slice(values), wgpu::BufferUsages::STORAGE);
+        let tables = buffer("tables", bytemuck::cast_slice(&tables), wgpu::BufferUsages::STORAGE);
+        let normalized = scratch("normalized", wgpu::BufferUsages::STORAGE);
+        let pixels = scratch("pixels", wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC);
+        let readback = scratch("readback", wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST);
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("heatmap"),
+            layout: &self.layout,
+            entries: &[&params, &heatmap, &tables, &normalized, &pixels]
+                .iter()
+                .enumerate()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry { binding: binding as u32, resource: buffer.as_entire_binding() })
+                .collect::<Vec<_>>(),
+        });
+
+        let normalizer = normalizer.unwrap_or(crate::normalize::Normalizer { offset: 0.0, scale: 1.0, clamp: false });
+        let mode = match blend.mode {
+            BlendMode::Alpha => 0,
+            BlendMode::Additive => 1,
+            BlendMode::Screen => 2,
+        };
+        for top in (0..height).step_by(band_rows as usize) {
+            let rows = band_rows.min(height - top);
+            let band = &mut base_rgba_image.as_mut()[(row_bytes * u64::from(top)) as usize..(row_bytes * u64::from(top + rows)) as usize];
+            let uniform = [
+                width,
+                height,
+                top,
+                rows,
+                data.ncols() as u32,
+                normalizer.offset.to_bits(),
+                normalizer.scale.to_bits(),
+                u32::from(normalizer.clamp),
+                blend.threshold.unwrap_or(f32::NEG_INFINITY).to_bits(),
+                u32::from((blend.opacity * 255.0) as u8),
+                mode,
+                (LUT_SIZE - 1) as u32,
+            ];
+            self.queue.write_buffer(&params, 0, bytemuck::cast_slice(&uniform));
+            self.queue.write_buffer(&pixels, 0, band);
+
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("heatmap") });
+            for pipeline in [&self.normalize, &self.blend] {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None, timestamp_writes: None });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(width.div_ceil(WORKGROUP_SIZE), rows.div_ceil(WORKGROUP_SIZE), 1);
+            }
+            encoder.copy_buffer_to_buffer(&pixels, 0, &readback, 0, band.len() as u64);
+            self.queue.submit([encoder.finish()]);
+            self.read_back(&readback, band)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the submitted work and copy the start of `readback` into `out`
+    fn read_back(&self, readback: &wgpu::Buffer, out: &mut [u8]) -> Result<()> {
+        let fail = |e: &dyn std::fmt::Display| Error::Render(format!("GPU adapter {} failed: {}", self.adapter, e));
+        let slice = readback.slice(..out.len() as u64);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::PollType::wait_indefinitely()).map_err(|e| fail(&e))?;
+        receiver.recv()
+            .map_err(|e| fail(&e))?
+            .map_err(|e| fail(&e))?;
+        out.copy_from_slice(&slice.get_mapped_range().map_err(|e| fail(&e))?);
+        readback.unmap();
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+// Compute shaders of the GPU backend (see gpu.rs): `resize_normalize` fills the normalized
+// values of a band of image rows, `colorize_blend` looks them up in the colormap table and
+// blends them into the band's RGBA pixels, packed little-endian into u32s.
+
+struct Params {
+    width: u32,
+    height: u32,
+    // First image row of the band and the rows it covers
+    top: u32,
+    rows: u32,
+    // Columns of the source heatmap
+    cols: u32,
+    offset: f32,
+    scale: f32,
+    clamp_values: u32,
+    // Values below it are hidden; negative infinity without a threshold
+    threshold: f32,
+    alpha: u32,
+    // 0 alpha, 1 additive, 2 screen
+    mode: u32,
+    lut_last: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Row-major source heatmap
+@group(0) @binding(1) var<storage, read> heatmap: array<f32>;
+// Source row of every image row, source column of every image column, then the colormap table
+@group(0) @binding(2) var<storage, read> tables: array<u32>;
+@group(0) @binding(3) var<storage, read_write> normalized: array<f32>;
+@group(0) @binding(4) var<storage, read_write> pixels: array<u32>;
+
+@compute @workgroup_size(16, 16)
+fn resize_normalize(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.rows) {
+        return;
+    }
+    let source_row = tables[params.top + id.y];
+    let source_col = tables[params.height + id.x];
+    var value = (heatmap[source_row * params.cols + source_col] - params.offset) / params.scale;
+    if (params.clamp_values != 0u) {
+        value = clamp(value, 0.0, 1.0);
+    }
+    normalized[id.y * params.width + id.x] = value;
+}
+
+@compute @workgroup_size(16, 16)
+fn colorize_blend(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.rows) {
+        return;
+    }
+    let index = id.y * params.width + id.x;
+    let value = normalized[index];
+    var alpha = params.alpha;
+    if (value < params.threshold) {
+        alpha = 0u;
+    }
+    let entry = u32(round(clamp(value, 0.0, 1.0) * f32(params.lut_last)));
+    let color = tables[params.height + params.width + entry];
+    pixels[index] = blend_pixel(pixels[index], color, alpha);
+}
+
+fn channel(pixel: u32, index: u32) -> f32 {
+    return f32((pixel >> (index * 8u)) & 255u) / 255.0;
+}
+
+// The arithmetic of `blend_pixel` in render.rs, whose alpha mode is `Rgba::blend` of the image crate
+fn blend_pixel(base: u32, color: u32, alpha: u32) -> u32 {
+    if (params.mode == 0u) {
+        if (alpha == 0u) {
+            return base;
+        }
+        if (alpha == 255u) {
+            return color | (255u << 24u);
+        }
+        let fg_a = f32(alpha) / 255.0;
+        let bg_a = channel(base, 3u);
+        let alpha_final = bg_a + fg_a - bg_a * fg_a;
+        if (alpha_final == 0.0) {
+            return base;
+        }
+        var out = u32(255.0 * alpha_final) << 24u;
+        for (var i = 0u; i < 3u; i++) {
+            let blended = (channel(color, i) * fg_a + channel(base, i) * bg_a * (1.0 - fg_a)) / alpha_final;
+            out |= u32(255.0 * blended) << (i * 8u);
+        }
+        return out;
+    }
+
+    let weight = f32(alpha) / 255.0;
+    var out = base & 0xff000000u;
+    for (var i = 0u; i < 3u; i++) {
+        let b = channel(base, i);
+        let h = channel(color, i) * weight;
+        var blended = b + h;
+        if (params.mode == 2u) {
+            blended = 1.0 - (1.0 - b) * (1.0 - h);
+        }
+        out |= u32(clamp(blended, 0.0, 1.0) * 255.0) << (i * 8u);
+    }
+    return out;
+}
@@ -0,0 +1,616 @@
+//! Heatmap loading through a pluggable format registry (JSON, CSV and binary built in), plus
+//! resizing and the fusion of ensembles.
+
+use log::info;
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::parallel::for_each_row;
+
+/// Descriptive information about a loaded heatmap
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapMetadata {
+    /// Name of the source that parsed the file (e.g. "json")
+    pub format: String,
+    pub path: String,
+    /// (rows, cols)
+    pub shape: (usize, usize),
+    /// Extra scalar fields supplied by the format, such as model name or class
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// Heatmap values together with their metadata
+#[derive(Debug, Clone)]
+pub struct LoadedHeatmap {
+    pub data: Array2<f32>,
+    pub metadata: HeatmapMetadata,
+}
+
+/// A heatmap file format that can be registered with a [`HeatmapRegistry`]
+pub trait HeatmapSource: Send + Sync {
+    /// Short format name reported in metadata
+    fn format(&self) -> &str;
+
+    /// Lowercase file extensions handled by this source, without the dot
+    fn extensions(&self) -> &[&str];
+
+    /// Parse heatmap contents; `origin` names the data (usually its path) in errors and metadata
+    fn parse(&self, bytes: &[u8], origin: &str) -> Result<LoadedHeatmap>;
+
+    fn load(&self, path: &Path) -> Result<LoadedHeatmap> {
+        let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        self.parse(&bytes, &path.display().to_string())
+    }
+}
+
+/// Format registry dispatching heatmap files to a source by extension
+pub struct HeatmapRegistry {
+    sources: Vec<Box<dyn HeatmapSource>>,
+}
+
+impl HeatmapRegistry {
+    /// Registry without any formats
+    pub fn empty() -> Self {
+        HeatmapRegistry { sources: Vec::new() }
+    }
+
+    /// Add a source; it takes precedence over earlier sources for the same extensions
+    pub fn register(&mut self, source: impl HeatmapSource + 'static) -> &mut Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Find the source for a file extension or format name
+    pub fn find(&self, extension: &str) -> Option<&dyn HeatmapSource> {
+        let extension = extension.to_lowercase();
+        self.sources
+            .iter()
+            .rev()
+            .find(|source| source.format() == extension || source.extensions().contains(&extension.as_str()))
+            .map(|source| source.as_ref())
+    }
+
+    /// All registered extensions, in registration order
+    pub fn extensions(&self) -> Vec<&str> {
+        let mut extensions: Vec<&str> = Vec::new();
+        for ext in self.sources.iter().flat_map(|source| source.extensions().iter().copied()) {
+            if !extensions.contains(&ext) {
+                extensions.push(ext);
+            }
+        }
+        extensions
+    }
+
+    /// Load a heatmap with the source registered for its extension
+    pub fn load(&self, path: &Path) -> Result<LoadedHeatmap> {
+        let file_path = path.display().to_string();
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_else(|| Error::heatmap(&file_path, "Could not determine file extension"))?
+            .to_lowercase();
+        
+        info!("Loading heatmap data from: {} (format: {})", file_path, extension);
+        
+        self.source_for(&extension, &file_path)?.load(path)
+    }
+
+    /// Parse in-memory heatmap contents given their format name or extension
+    pub fn load_bytes(&self, bytes: &[u8], format: &str, origin: &str) -> Result<LoadedHeatmap> {
+        info!("Parsing {} bytes of heatmap data from: {} (format: {})", bytes.len(), origin, format);
+        self.source_for(format, origin)?.parse(bytes, origin)
+    }
+
+    fn source_for(&self, extension: &str, origin: &str) -> Result<&dyn HeatmapSource> {
+        match self.find(extension) {
+            Some(source) => Ok(source),
+            None => {
+                let supported: Vec<String> = self.extensions().iter().map(|ext| format!(".{}", ext)).collect();
+                Err(Error::heatmap(origin, format!("Unsupported heatmap file format: {}. Supported: {}", extension, supported.join(", "))))
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for HeatmapRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeatmapRegistry").field("extensions", &self.extensions()).finish()
+    }
+}
+
+impl Default for HeatmapRegistry {
+    /// Registry with the built-in formats enabled by the `json`, `csv`, `binary` and `fs` features
+    #[allow(unused_mut)]
+    fn default() -> Self {
+        let mut registry = HeatmapRegistry::empty();
+        #[cfg(feature = "json")]
+        registry.register(JsonHeatmapSource);
+        #[cfg(feature = "csv")]
+        registry.register(CsvHeatmapSource);
+        #[cfg(feature = "binary")]
+        registry.register(BinaryHeatmapSource);
+        #[cfg(feature = "fs")]
+        registry.register(NpyHeatmapSource);
+        registry
+    }
+}
+
+/// `{"data": [[...], ...]}` JSON heatmaps; other top-level scalars become attributes
+#[cfg(feature = "json")]
+pub struct JsonHeatmapSource;
+
+#[cfg(feature = "json")]
+impl HeatmapSource for JsonHeatmapSource {
+    fn format(&self) -> &str {
+        "json"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["json"]
+    }
+
+    fn parse(&self, bytes: &[u8], origin: &str) -> Result<LoadedHeatmap> {
+        let (data, attributes) = parse_json_heatmap(bytes, origin)?;
+        Ok(loaded(self.format(), origin, data, attributes))
+    }
+}
+
+/// Comma-separated values in row-major order
+#[cfg(feature = "csv")]
+pub struct CsvHeatmapSource;
+
+#[cfg(feature = "csv")]
+impl HeatmapSource for CsvHeatmapSource {
+    fn format(&self) -> &str {
+        "csv"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["csv"]
+    }
+
+    fn parse(&self, bytes: &[u8], origin: &str) -> Result<LoadedHeatmap> {
+        let data = parse_csv_heatmap(bytes, origin)?;
+        Ok(loaded(self.format(), origin, data, BTreeMap::new()))
+    }
+}
+
+/// Little-endian f32 values preceded by u32 rows and cols
+#[cfg(feature = "binary")]
+pub struct BinaryHeatmapSource;
+
+#[cfg(feature = "binary")]
+impl HeatmapSource for BinaryHeatmapSource {
+    fn format(&self) -> &str {
+        "bin"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bin"]
+    }
+
+    fn parse(&self, bytes: &[u8], origin: &str) -> Result<LoadedHeatmap> {
+        let data = parse_binary_heatmap(bytes, origin)?;
+        Ok(loaded(self.format(), origin, data, BTreeMap::new()))
+    }
+
+    /// Map the file into memory rather than reading it, so the values are copied once, straight
+    /// from the page cache
+    fn load(&self, path: &Path) -> Result<LoadedHeatmap> {
+        let file = std::fs::File::open(path).map_err(|e| Error::io(path, e))?;
+        // SAFETY: the map is only read while parsing; a heatmap file truncated by another process
+        // during the load would fault the read, like any mapped file
+        let map = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| Error::io(path, e))?;
+        self.parse(&map, &path.display().to_string())
+    }
+}
+
+/// 2-D NumPy `.npy` arrays of booleans, integers or floats; leading axes of length 1, as in a
+/// (1, 1, rows, cols) model output, are dropped
+#[cfg(feature = "fs")]
+pub struct NpyHeatmapSource;
+
+#[cfg(feature = "fs")]
+impl HeatmapSource for NpyHeatmapSource {
+    fn format(&self) -> &str {
+        "npy"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["npy"]
+    }
+
+    fn parse(&self, bytes: &[u8], origin: &str) -> Result<LoadedHeatmap> {
+        let array = crate::evaluation::parse_npy(bytes).map_err(|e| Error::heatmap(origin, e))?;
+        let (rows, cols) = match array.shape() {
+            [leading @ .., rows, cols] if leading.iter().all(|&dim| dim == 1) => (*rows, *cols),
+            shape => return Err(Error::heatmap(origin, format!("Expected a 2-D heatmap array, found shape {:?}", shape))),
+        };
+        if rows == 0 || cols == 0 {
+            return Err(Error::heatmap(origin, "NPY array is empty"));
+        }
+        let data = Array2::from_shape_vec((rows, cols), array.iter().map(|&value| value as f32).collect())
+            .expect("one value per pixel");
+        Ok(loaded(self.format(), origin, data, BTreeMap::new()))
+    }
+}
+
+#[cfg(any(feature = "json", feature = "csv", feature = "binary"))]
+fn loaded(format: &str, origin: &str, data: Array2<f32>, attributes: BTreeMap<String, String>) -> LoadedHeatmap {
+    let metadata = HeatmapMetadata {
+        format: format.to_string(),
+        path: origin.to_string(),
+        shape: data.dim(),
+        attributes,
+    };
+    LoadedHeatmap { data, metadata }
+}
+
+/// Load heatmap data from any file format known to the default registry
+#[cfg(feature = "fs")]
+pub fn load_heatmap_data(file_path: &str) -> Result<Array2<f32>> {
+    HeatmapRegistry::default().load(Path::new(file_path)).map(|heatmap| heatmap.data)
+}
+
+/// Parse a .json heatmap
+/// Expected format: {"data": [[1.0, 2.0], [3.0, 4.0]], "shape": [2, 2]}
+#[cfg(feature = "json")]
+fn parse_json_heatmap(bytes: &[u8], origin: &str) -> Result<(Array2<f32>, BTreeMap<String, String>)> {
+    let parsed: serde_json::Value = serde_json::from_slice(bytes).map_err(|e| Error::heatmap(origin, e))?;
+    
+    // Try to extract data as nested arrays
+    if let Some(data_array) = parsed.get("data").and_then(|v| v.as_array()) {
+        let mut flat_data = Vec::new();
+        let rows = data_array.len();
+        let mut cols = 0;
+        
+        for (i, row) in data_array.iter().enumerate() {
+            if let Some(row_array) = row.as_array() {
+                if i == 0 {
+                    cols = row_array.len();
+                } else if row_array.len() != cols {
+                    return Err(Error::ShapeMismatch {
+                        context: format!("{} row {}", origin, i),
+                        expected: cols,
+                        found: row_array.len(),
+                    });
+                }
+                for val in row_array {
+                    if let Some(num) = val.as_f64() {
+                        flat_data.push(num as f32);
+                    } else {
+                        return Err(Error::heatmap(origin, "JSON data must contain numeric values"));
+                    }
+                }
+            } else {
+                return Err(Error::heatmap(origin, "JSON data must be array of arrays"));
+            }
+        }
+        
+        let attributes = parsed.as_object()
+            .map(|object| {
+                object.iter()
+                    .filter(|(key, _)| key.as_str() != "data")
+                    .filter_map(|(key, value)| match value {
+                        serde_json::Value::String(text) => Some((key.clone(), text.clone())),
+                        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Some((key.clone(), value.to_string())),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        
+        Ok((to_array(origin, rows, cols, flat_data)?, attributes))
+    } else {
+        Err(Error::heatmap(origin, "JSON must contain 'data' field with array of arrays"))
+    }
+}
+
+/// Parse a .csv heatmap
+#[cfg(feature = "csv")]
+fn parse_csv_heatmap(bytes: &[u8], origin: &str) -> Result<Array2<f32>> {
+    let mut reader = csv::Reader::from_reader(bytes);
+    let mut data = Vec::new();
+    let mut rows = 0;
+    let mut cols = 0;
+    
+    for result in reader.records() {
+        let record = result.map_err(|e| Error::heatmap(origin, e))?;
+        if rows == 0 {
+            cols = record.len();
+        }
+        
+        for field in &record {
+            let value: f32 = field.parse()
+                .map_err(|_| Error::heatmap(origin, format!("Could not parse '{}' as number", field)))?;
+            data.push(value);
+        }
+        rows += 1;
+    }
+    
+    if data.is_empty() {
+        return Err(Error::heatmap(origin, "CSV file is empty"));
+    }
+    
+    to_array(origin, rows, cols, data)
+}
+
+/// Parse a binary heatmap (assumes f32 values in row-major order)
+/// Data should start with 8 bytes: 4 bytes for rows (u32), 4 bytes for cols (u32)
+#[cfg(feature = "binary")]
+fn parse_binary_heatmap(bytes: &[u8], origin: &str) -> Result<Array2<f32>> {
+    let header = |at: usize| bytes.get(at..at + 4)
+        .map(|field| u32::from_le_bytes([field[0], field[1], field[2], field[3]]) as usize)
+        .ok_or_else(|| Error::heatmap(origin, "Binary heatmap is shorter than its 8-byte rows and cols header"));
+    let (rows, cols) = (header(0)?, header(4)?);
+    
+    info!("Binary heatmap dimensions: {}x{}", rows, cols);
+    
+    // One bulk copy of the values; bytes past rows×cols are ignored, a short file fails the
+    // shape check
+    let values = &bytes[8..];
+    let count = rows.saturating_mul(cols).min(values.len() / 4);
+    let mut data: Vec<f32> = bytemuck::pod_collect_to_vec(&values[..count * 4]);
+    for value in &mut data {
+        *value = f32::from_bits(u32::from_le(value.to_bits()));
+    }
+    
+    to_array(origin, rows, cols, data)
+}
+
+/// Shape flat row-major values into a (rows, cols) array
+#[cfg(any(feature = "json", feature = "csv", feature = "binary"))]
+fn to_array(origin: &str, rows: usize, cols: usize, data: Vec<f32>) -> Result<Array2<f32>> {
+    let found = data.len();
+    Array2::from_shape_vec((rows, cols), data).map_err(|_| Error::ShapeMismatch {
+        context: origin.to_string(),
+        expected: rows * cols,
+        found,
+    })
+}
+
+/// Class a heatmap is drawn for: the top-scoring one, or one given by index or label, written as
+/// `top`, `2` or `pneumonia`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ClassSelector {
+    #[default]
+    Top,
+    Index(usize),
+    Label(String),
+}
+
+impl FromStr for ClassSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("Empty target class; expected top, a class index or a label".to_string());
+        }
+        if s.eq_ignore_ascii_case("top") || s.eq_ignore_ascii_case("auto") {
+            return Ok(ClassSelector::Top);
+        }
+        Ok(s.parse().map(ClassSelector::Index).unwrap_or_else(|_| ClassSelector::Label(s.to_string())))
+    }
+}
+
+impl ClassSelector {
+    pub fn name(&self) -> String {
+        match self {
+            ClassSelector::Top => "top".to_string(),
+            ClassSelector::Index(index) => index.to_string(),
+            ClassSelector::Label(label) => label.clone(),
+        }
+    }
+
+    /// Index of the selected class among `labels`, where unlabelled classes are also found by
+    /// their `class_<index>` name; None selects the top-scoring class
+    pub fn index(&self, labels: &[String]) -> std::result::Result<Option<usize>, String> {
+        match self {
+            ClassSelector::Top => Ok(None),
+            ClassSelector::Index(index) => Ok(Some(*index)),
+            ClassSelector::Label(label) => labels.iter()
+                .position(|known| known == label)
+                .or_else(|| label.strip_prefix("class_").and_then(|index| index.parse().ok()).filter(|&index| index >= labels.len()))
+                .map(Some)
+                .ok_or_else(|| match labels.is_empty() {
+                    true => format!("Unknown class: {}. The model has no class labels, select the class by index", label),
+                    false => format!("Unknown class: {}. Available: {}", label, labels.join(", ")),
+                }),
+        }
+    }
+}
+
+/// Map of class `class` from a heatmap output of the first image of the batch; an output with a
+/// single channel is the map of every class
+#[cfg(any(feature = "onnx", feature = "service"))]
+pub(crate) fn class_map(dims: &[usize], values: Vec<f32>, classes: usize, class: usize) -> Option<Array2<f32>> {
+    let (channels, height, width, channels_last) = match dims[..] {
+        [1, height, width] => (1, height, width, false),
+        [1, first, height, width] if first == classes || first == 1 => (first, height, width, false),
+        [1, height, width, last] if last == classes || last == 1 => (last, height, width, true),
+        _ => return None,
+    };
+    let class = if channels == 1 { 0 } else { class };
+    let plane = height * width;
+    if values.len() < plane * channels {
+        return None;
+    }
+    let data = match channels_last {
+        false => values[class * plane..(class + 1) * plane].to_vec(),
+        true => values.iter().skip(class).step_by(channels).take(plane).copied().collect(),
+    };
+    Array2::from_shape_vec((height, width), data).ok()
+}
+
+/// How the heatmaps of an ensemble are combined into one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FusionMethod {
+    /// Unweighted average of the members
+    #[default]
+    Mean,
+    /// Largest value of any member at each pixel
+    Max,
+    /// Average weighted by each member's weight
+    Weighted,
+}
+
+impl FromStr for FusionMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "mean" => Ok(FusionMethod::Mean),
+            "max" => Ok(FusionMethod::Max),
+            "weighted" => Ok(FusionMethod::Weighted),
+            _ => Err(format!("Unknown fusion method: {}. Available: mean, max, weighted", s)),
+        }
+    }
+}
+
+impl FusionMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FusionMethod::Mean => "mean",
+            FusionMethod::Max => "max",
+            FusionMethod::Weighted => "weighted",
+        }
+    }
+}
+
+/// Fuse the heatmaps of an ensemble, each given with its weight, into one at the largest member
+/// resolution
+///
+/// Members are resized with [`resize_heatmap`] and combined as they are, so they should share a
+/// value scale, e.g. probabilities. The result records the `fusion` method and its `members`,
+/// named after their service, model or path, plus `weight:<member>` for weighted fusion. When
+/// every member reports a `score`, the scores are fused the same way, and a `class` all members
+/// agree on is kept.
+pub fn fuse_heatmaps(members: Vec<(LoadedHeatmap, f32)>, method: FusionMethod) -> Result<LoadedHeatmap> {
+    if members.is_empty() {
+        return Err(Error::InvalidOption("An ensemble needs at least one heatmap".to_string()));
+    }
+    if let Some((_, weight)) = members.iter().find(|(_, weight)| !weight.is_finite() || *weight < 0.0) {
+        return Err(Error::InvalidOption(format!("Ensemble weights must be zero or positive, found {}", weight)));
+    }
+    let total: f32 = members.iter().map(|(_, weight)| weight).sum();
+    if method == FusionMethod::Weighted && total <= 0.0 {
+        return Err(Error::InvalidOption("Weighted fusion needs at least one member with a positive weight".to_string()));
+    }
+    let shares: Vec<f32> = members.iter()
+        .map(|(_, weight)| match method {
+            FusionMethod::Weighted => weight / total,
+            _ => 1.0 / members.len() as f32,
+        })
+        .collect();
+
+    let rows = members.iter().map(|(heatmap, _)| heatmap.data.nrows()).max().unwrap_or(0);
+    let cols = members.iter().map(|(heatmap, _)| heatmap.data.ncols()).max().unwrap_or(0);
+    let mut fused = match method {
+        FusionMethod::Max => Array2::from_elem((rows, cols), f32::NEG_INFINITY),
+        _ => Array2::zeros((rows, cols)),
+    };
+    for ((heatmap, _), share) in members.iter().zip(&shares) {
+        let resized;
+        let data = match heatmap.data.dim() == (rows, cols) {
+            true => &heatmap.data,
+            false => {
+                resized = resize_heatmap(&heatmap.data, cols, rows);
+                &resized
+            }
+        };
+        match method {
+            FusionMethod::Max => fused.zip_mut_with(data, |fused, &value| *fused = fused.max(value)),
+            _ => fused.scaled_add(*share, data),
+        }
+    }
+
+    let names: Vec<String> = members.iter()
+        .enumerate()
+        .map(|(index, (heatmap, _))| member_name(&heatmap.metadata, index))
+        .collect();
+    let mut attributes = BTreeMap::new();
+    attributes.insert("fusion".to_string(), method.name().to_string());
+    attributes.insert("members".to_string(), names.join(","));
+    if method == FusionMethod::Weighted {
+        for (name, (_, weight)) in names.iter().zip(&members) {
+            attributes.insert(format!("weight:{}", name), weight.to_string());
+        }
+    }
+    let classes: Option<Vec<&String>> = members.iter().map(|(heatmap, _)| heatmap.metadata.attributes.get("class")).collect();
+    if let Some(classes) = classes
+        && classes.windows(2).all(|pair| pair[0] == pair[1])
+        && let Some(class) = classes.first()
+    {
+        attributes.insert("class".to_string(), class.to_string());
+    }
+    let scores: Option<Vec<f32>> = members.iter()
+        .map(|(heatmap, _)| heatmap.metadata.attributes.get("score").and_then(|score| score.parse().ok()))
+        .collect();
+    if let Some(scores) = scores {
+        let score = match method {
+            FusionMethod::Max => scores.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)),
+            _ => scores.iter().zip(&shares).map(|(score, share)| score * share).sum(),
+        };
+        attributes.insert("score".to_string(), score.to_string());
+    }
+    info!("Fused {} heatmap(s) with {} fusion: {}", members.len(), method.name(), names.join(", "));
+
+    Ok(LoadedHeatmap {
+        data: fused,
+        metadata: HeatmapMetadata { format: "ensemble".to_string(), path: String::new(), shape: (rows, cols), attributes },
+    })
+}
+
+/// Name of an ensemble member: its service, model or path, else its position
+fn member_name(metadata: &HeatmapMetadata, index: usize) -> String {
+    ["service", "model"].iter()
+        .find_map(|key| metadata.attributes.get(*key).cloned())
+        .or_else(|| Some(metadata.path.clone()).filter(|path| !path.is_empty()))
+        .unwrap_or_else(|| format!("member_{}", index))
+}
+
+/// Resize heatmap data to match target dimensions using nearest neighbor interpolation, one
+/// row per task with the `parallel` feature
+pub fn resize_heatmap(data: &Array2<f32>, target_width: usize, target_height: usize) -> Array2<f32> {
+    resize_heatmap_into(data.view(), target_width, target_height, Vec::new())
+}
+
+/// [`resize_heatmap`] into `buffer`, e.g. one taken from a [`crate::buffers::BufferPool`],
+/// which grows only if it is too small for the target size
+pub fn resize_heatmap_into(data: ArrayView2<f32>, target_width: usize, target_height: usize, mut buffer: Vec<f32>) -> Array2<f32> {
+    let (src_height, src_width) = data.dim();
+    let (src_rows, src_cols) = (nearest(target_height, src_height), nearest(target_width, src_width));
+    buffer.clear();
+    buffer.resize(target_width * target_height, 0.0);
+
+    for_each_row(&mut buffer, target_width, |row, values| {
+        let src = data.row(src_rows[row]);
+        for (value, &src_col) in values.iter_mut().zip(&src_cols) {
+            *value = src[src_col];
+        }
+    });
+
+    Array2::from_shape_vec((target_height, target_width), buffer).expect("one value per pixel")
+}
+
+/// Source row of every target row and source column of every target column of a heatmap of
+/// `shape` shown at `width`×`height`: the picks of [`resize_heatmap`], or every row and column
+/// once when the heatmap already has that size and isn't resized
+#[cfg(any(feature = "png", feature = "gpu"))]
+pub(crate) fn resize_indices(shape: (usize, usize), width: usize, height: usize) -> (Vec<usize>, Vec<usize>) {
+    if shape == (height, width) {
+        return ((0..height).collect(), (0..width).collect());
+    }
+    (nearest(height, shape.0), nearest(width, shape.1))
+}
+
+fn nearest(target: usize, source: usize) -> Vec<usize> {
+    (0..target)
+        .map(|index| (((index as f32 / target as f32) * source as f32) as usize).min(source.saturating_sub(1)))
+        .collect()
+}
@@ -0,0 +1,168 @@
+//! Histograms of heatmap values, as loaded or after normalization, for auditing models that
+//! produce degenerate near-constant maps; exported as JSON or CSV and optionally drawn as a
+//! panel in the rendering.
+
+use image::RgbaImage;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::render::{blend_pixel, BlendMode};
+
+/// Bins used when none are given
+pub const DEFAULT_HISTOGRAM_BINS: usize = 32;
+
+/// Share of the values in one bin from which a heatmap counts as near-constant
+pub const DEGENERATE_PEAK_FRACTION: f64 = 0.99;
+
+/// Heatmap values a histogram is taken of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramStage {
+    /// Values as loaded, before resizing and normalization, binned over their own range
+    Raw,
+    /// Values resized to the image and normalized, binned over 0.0-1.0
+    Normalized,
+}
+
+impl FromStr for HistogramStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "raw" | "pre" => Ok(HistogramStage::Raw),
+            "normalized" | "post" => Ok(HistogramStage::Normalized),
+            _ => Err(format!("Invalid histogram stage: {}. Supported: raw, normalized", s)),
+        }
+    }
+}
+
+/// How the heatmap histogram is taken
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistogramOptions {
+    pub bins: usize,
+    pub stage: HistogramStage,
+    /// Draw the histogram as a panel in the bottom-right corner of the rendering
+    pub panel: bool,
+}
+
+impl Default for HistogramOptions {
+    fn default() -> Self {
+        HistogramOptions { bins: DEFAULT_HISTOGRAM_BINS, stage: HistogramStage::Raw, panel: false }
+    }
+}
+
+/// Counts of heatmap values in equal-width bins from `min` to `max`, the last bin closed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub stage: HistogramStage,
+    /// Lower edge of the first bin
+    pub min: f32,
+    /// Upper edge of the last bin
+    pub max: f32,
+    pub counts: Vec<u64>,
+    /// NaN and infinite values, left out of the bins
+    pub non_finite: u64,
+    /// Share of the binned values in the fullest bin, close to 1.0 for near-constant maps
+    pub peak_fraction: f64,
+}
+
+impl Histogram {
+    /// The `counts.len() + 1` bin edges from `min` to `max`
+    pub fn edges(&self) -> Vec<f32> {
+        let bins = self.counts.len();
+        (0..=bins).map(|index| self.min + (self.max - self.min) * index as f32 / bins as f32).collect()
+    }
+
+    /// Whether nearly all values fall into a single bin
+    pub fn is_degenerate(&self) -> bool {
+        self.peak_fraction >= DEGENERATE_PEAK_FRACTION
+    }
+
+    /// Write the histogram as CSV (`bin,lower,upper,count`) for `.csv` paths, as JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let csv = path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let text = if csv {
+            let edges = self.edges();
+            let rows = self.counts.iter().enumerate()
+                .map(|(bin, count)| format!("{},{},{},{}\n", bin, edges[bin], edges[bin + 1], count));
+            std::iter::once("bin,lower,upper,count\n".to_string()).chain(rows).collect()
+        } else {
+            serde_json::to_string_pretty(self)
+                .map_err(|e| Error::Render(format!("Failed to serialize histogram: {}", e)))?
+        };
+        std::fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Histogram of `values` in `bins` bins (at least one), over 0.0-1.0 for normalized values and
+/// over the finite values' own range otherwise
+pub fn compute_histogram(values: &Array2<f32>, bins: usize, stage: HistogramStage) -> Histogram {
+    let bins = bins.max(1);
+    let (min, max) = match stage {
+        HistogramStage::Normalized => (0.0, 1.0),
+        HistogramStage::Raw => {
+            let finite = values.iter().copied().filter(|value| value.is_finite());
+            finite.fold(None, |range: Option<(f32, f32)>, value| match range {
+                Some((min, max)) => Some((min.min(value), max.max(value))),
+                None => Some((value, value)),
+            })
+            .unwrap_or((0.0, 0.0))
+        }
+    };
+
+    let mut counts = vec![0u64; bins];
+    let mut non_finite = 0;
+    let width = max - min;
+    for &value in values {
+        if !value.is_finite() {
+            non_finite += 1;
+            continue;
+        }
+        let bin = if width > 0.0 { ((value - min) / width * bins as f32) as usize } else { 0 };
+        counts[bin.min(bins - 1)] += 1;
+    }
+
+    let binned: u64 = counts.iter().sum();
+    let fullest = counts.iter().copied().max().unwrap_or_default();
+    let peak_fraction = if binned > 0 { fullest as f64 / binned as f64 } else { 0.0 };
+    Histogram { stage, min, max, counts, non_finite, peak_fraction }
+}
+
+/// Draw `histogram` as a bar chart on a translucent panel in the bottom-right corner of `image`
+pub fn draw_histogram_panel(image: &mut RgbaImage, histogram: &Histogram) {
+    let (width, height) = image.dimensions();
+    let scale = (width.min(height) / 256).max(1);
+    let margin = 4 * scale;
+    let panel_width = (width / 3).clamp(width.min(32), 512);
+    let panel_height = (height / 5).clamp(height.min(16), 160);
+    let left = width.saturating_sub(panel_width + margin);
+    let top = height.saturating_sub(panel_height + margin);
+    let (panel_width, panel_height) = (panel_width.min(width - left), panel_height.min(height - top));
+
+    let inset = scale.min(panel_width / 4).min(panel_height / 4);
+    let (bars_width, bars_height) = (panel_width - 2 * inset, panel_height - 2 * inset);
+    let bins = histogram.counts.len().max(1);
+    let fullest = histogram.counts.iter().copied().max().unwrap_or_default().max(1);
+    let bar_heights: Vec<u32> = (0..bars_width)
+        .map(|column| {
+            let bin = (column as usize * bins / bars_width.max(1) as usize).min(bins - 1);
+            let count = histogram.counts.get(bin).copied().unwrap_or_default();
+            ((count as f64 / fullest as f64) * bars_height as f64).round() as u32
+        })
+        .collect();
+
+    for y in 0..panel_height {
+        for x in 0..panel_width {
+            let pixel = &mut image.get_pixel_mut(left + x, top + y).0;
+            blend_pixel(pixel, [0, 0, 0, 160], BlendMode::Alpha);
+            let (column, row) = (x.wrapping_sub(inset), y.wrapping_sub(inset));
+            if column < bars_width && row < bars_height && bars_height - row <= bar_heights[column as usize] {
+                blend_pixel(pixel, [240, 240, 240, 230], BlendMode::Alpha);
+            }
+        }
+    }
+}
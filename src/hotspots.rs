@@ -0,0 +1,174 @@
+//! Hotspot detection: the connected regions of a normalized heatmap at or above a threshold,
+//! each reported with its centroid, bounding box, peak value and area, for findings databases.
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// Normalized level hotspots start at when neither the options nor the blend set a threshold
+pub const DEFAULT_HOTSPOT_THRESHOLD: f32 = 0.5;
+
+/// How hotspots are found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotspotOptions {
+    /// Normalized level at and above which pixels belong to a hotspot; None uses the blend
+    /// threshold, or else 0.5
+    pub threshold: Option<f32>,
+    /// Regions of fewer pixels are dropped
+    pub min_area: u64,
+    /// Hotspots kept, highest peaks first; None keeps every one
+    pub max_hotspots: Option<usize>,
+}
+
+impl Default for HotspotOptions {
+    fn default() -> Self {
+        HotspotOptions { threshold: None, min_area: 1, max_hotspots: None }
+    }
+}
+
+/// Position in image pixels from the top-left corner
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Smallest rectangle holding a region, in image pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Connected region of the heatmap at or above the threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hotspot {
+    /// Mean position of the region's pixels
+    pub centroid: Position,
+    pub bounding_box: BoundingBox,
+    /// Highest normalized value in the region, a local maximum of the heatmap
+    pub peak: f32,
+    /// Pixel of the peak value
+    pub peak_position: Position,
+    pub area_pixels: u64,
+    /// Area from the image's pixel spacing, None without one
+    pub area_mm2: Option<f64>,
+}
+
+/// Hotspots of one rendering, as written by `OutputTarget::Hotspots`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HotspotReport {
+    /// SOP Instance UID of the DICOM source, when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sop_instance_uid: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub threshold: f32,
+    /// Row and column spacing of the image in mm
+    pub pixel_spacing: Option<(f64, f64)>,
+    /// Highest peaks first
+    pub hotspots: Vec<Hotspot>,
+}
+
+impl HotspotReport {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Render(format!("Failed to serialize hotspots: {}", e)))?;
+        std::fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Connected regions (8-connected) of `normalized` at or above `threshold` with at least
+/// `options.min_area` pixels, highest peaks first; areas in mm² use `pixel_spacing`, the row and
+/// column spacing in mm
+pub fn find_hotspots(normalized: &Array2<f32>, threshold: f32, options: &HotspotOptions, pixel_spacing: Option<(f64, f64)>) -> Vec<Hotspot> {
+    let (height, width) = normalized.dim();
+    let mut visited = Array2::from_elem((height, width), false);
+    let mut hotspots = Vec::new();
+    let mut stack = Vec::new();
+
+    for ((y, x), &value) in normalized.indexed_iter() {
+        if visited[[y, x]] || value < threshold {
+            continue;
+        }
+        visited[[y, x]] = true;
+        stack.push((y, x));
+        let mut region = Region::new(x, y, value);
+        while let Some((y, x)) = stack.pop() {
+            region.add(x, y, normalized[[y, x]]);
+            for (ny, nx) in neighbors(y, x, height, width) {
+                if !visited[[ny, nx]] && normalized[[ny, nx]] >= threshold {
+                    visited[[ny, nx]] = true;
+                    stack.push((ny, nx));
+                }
+            }
+        }
+        if region.pixels >= options.min_area.max(1) {
+            hotspots.push(region.hotspot(pixel_spacing));
+        }
+    }
+
+    hotspots.sort_by(|a, b| b.peak.total_cmp(&a.peak).then(b.area_pixels.cmp(&a.area_pixels)));
+    if let Some(max_hotspots) = options.max_hotspots {
+        hotspots.truncate(max_hotspots);
+    }
+    hotspots
+}
+
+/// The up to eight pixels around (y, x) inside a `height`×`width` grid
+fn neighbors(y: usize, x: usize, height: usize, width: usize) -> impl Iterator<Item = (usize, usize)> {
+    let rows = y.saturating_sub(1)..=(y + 1).min(height - 1);
+    rows.flat_map(move |ny| (x.saturating_sub(1)..=(x + 1).min(width - 1)).map(move |nx| (ny, nx)))
+        .filter(move |&neighbor| neighbor != (y, x))
+}
+
+/// Running totals of a region being flooded
+struct Region {
+    pixels: u64,
+    sum_x: f64,
+    sum_y: f64,
+    min: (usize, usize),
+    max: (usize, usize),
+    peak: f32,
+    peak_at: (usize, usize),
+}
+
+impl Region {
+    fn new(x: usize, y: usize, value: f32) -> Self {
+        Region { pixels: 0, sum_x: 0.0, sum_y: 0.0, min: (x, y), max: (x, y), peak: value, peak_at: (x, y) }
+    }
+
+    fn add(&mut self, x: usize, y: usize, value: f32) {
+        self.pixels += 1;
+        self.sum_x += x as f64;
+        self.sum_y += y as f64;
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+        if value > self.peak {
+            self.peak = value;
+            self.peak_at = (x, y);
+        }
+    }
+
+    fn hotspot(&self, pixel_spacing: Option<(f64, f64)>) -> Hotspot {
+        let count = self.pixels as f64;
+        Hotspot {
+            centroid: Position { x: self.sum_x / count, y: self.sum_y / count },
+            bounding_box: BoundingBox {
+                x: self.min.0 as u32,
+                y: self.min.1 as u32,
+                width: (self.max.0 - self.min.0 + 1) as u32,
+                height: (self.max.1 - self.min.1 + 1) as u32,
+            },
+            peak: self.peak,
+            peak_position: Position { x: self.peak_at.0 as f64, y: self.peak_at.1 as f64 },
+            area_pixels: self.pixels,
+            area_mm2: pixel_spacing.map(|(row, column)| count * row * column),
+        }
+    }
+}
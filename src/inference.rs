@@ -0,0 +1,1074 @@
+//! Local inference with ONNX Runtime: a chest X-ray classifier runs on the decoded DICOM pixels
+//! and gives class scores and a heatmap, read from the model or computed with Grad-CAM, without
+//! a DL service. Quantized models with fp16, int8 or uint8 inputs and outputs are converted to
+//! and from real values around each run.
+
+use image::GrayImage;
+use log::{info, warn};
+use ndarray::{Array2, Array3};
+use ort::execution_providers as providers;
+use ort::session::builder::SessionBuilder;
+use ort::session::Session;
+use ort::tensor::TensorElementType;
+use ort::value::{DynTensor, DynValue, Tensor, ValueType};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::cam::{class_activation_map, CamMethod};
+use crate::config::ModelConfig;
+use crate::error::{Error, Result};
+use crate::heatmap::{class_map, ClassSelector, HeatmapMetadata, LoadedHeatmap};
+use crate::occlusion::{OcclusionOptions, OcclusionPlan};
+use crate::preprocess::{Placement, PreprocessSpec, TensorLayout};
+use crate::quantization::{parse_quantization, verify_drift, Quantization, QUANTIZATION_METADATA_KEY};
+use crate::tta::{average_heatmaps, Augmentation};
+
+/// Side of the image fed to models with a dynamic input height and width and no preprocessing size
+pub const DEFAULT_INPUT_SIZE: usize = 224;
+
+/// Occluded copies scored per run by models with a dynamic batch dimension
+const OCCLUSION_BATCH: usize = 16;
+
+/// Model metadata key with the class labels, comma-separated or as a JSON array
+pub const LABELS_METADATA_KEY: &str = "labels";
+
+/// Model metadata key with the [`PreprocessSpec`] the model was trained with
+pub const PREPROCESS_METADATA_KEY: &str = "preprocess";
+
+/// Default output names of the values Grad-CAM is computed from
+pub const ACTIVATIONS_OUTPUT: &str = "activations";
+pub const GRADIENTS_OUTPUT: &str = "gradients";
+
+/// How the raw scores of the model are turned into the reported ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreActivation {
+    /// Scores as the model returns them, for models ending in their own sigmoid or softmax
+    #[default]
+    None,
+    /// Independent per-class probabilities (multi-label)
+    Sigmoid,
+    /// Probabilities over mutually exclusive classes
+    Softmax,
+}
+
+impl FromStr for ScoreActivation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ScoreActivation::None),
+            "sigmoid" => Ok(ScoreActivation::Sigmoid),
+            "softmax" => Ok(ScoreActivation::Softmax),
+            _ => Err(format!("Unknown score activation: {}. Available: none, sigmoid, softmax", s)),
+        }
+    }
+}
+
+impl ScoreActivation {
+    fn apply(self, scores: &mut [f32]) {
+        match self {
+            ScoreActivation::None => {}
+            ScoreActivation::Sigmoid => scores.iter_mut().for_each(|score| *score = 1.0 / (1.0 + (-*score).exp())),
+            ScoreActivation::Softmax => {
+                let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                scores.iter_mut().for_each(|score| *score = (*score - max).exp());
+                let sum: f32 = scores.iter().sum();
+                if sum > 0.0 {
+                    scores.iter_mut().for_each(|score| *score /= sum);
+                }
+            }
+        }
+    }
+}
+
+/// ONNX Runtime execution provider a session can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionProvider {
+    Cpu,
+    /// NVIDIA GPUs through CUDA
+    Cuda,
+    /// NVIDIA GPUs through TensorRT, which builds an optimized engine on the first run
+    TensorRt,
+    /// DirectX 12 GPUs on Windows
+    DirectMl,
+    /// Apple Neural Engine and GPUs on macOS
+    CoreMl,
+}
+
+impl FromStr for ExecutionProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cpu" => Ok(ExecutionProvider::Cpu),
+            "cuda" => Ok(ExecutionProvider::Cuda),
+            "tensorrt" | "trt" => Ok(ExecutionProvider::TensorRt),
+            "directml" | "dml" => Ok(ExecutionProvider::DirectMl),
+            "coreml" => Ok(ExecutionProvider::CoreMl),
+            _ => Err(format!("Unknown execution provider: {}. Available: cpu, cuda, tensorrt, directml, coreml", s)),
+        }
+    }
+}
+
+impl ExecutionProvider {
+    pub fn name(self) -> &'static str {
+        match self {
+            ExecutionProvider::Cpu => "cpu",
+            ExecutionProvider::Cuda => "cuda",
+            ExecutionProvider::TensorRt => "tensorrt",
+            ExecutionProvider::DirectMl => "directml",
+            ExecutionProvider::CoreMl => "coreml",
+        }
+    }
+
+    /// Register the provider on GPU `device` (ignored by the CPU and CoreML), or say why it
+    /// can't be used
+    fn register(self, builder: &mut SessionBuilder, device: i32) -> std::result::Result<(), String> {
+        let provider: Box<dyn providers::ExecutionProvider> = match self {
+            // Runs every node no other provider takes anyway
+            ExecutionProvider::Cpu => return Ok(()),
+            ExecutionProvider::Cuda => Box::new(providers::CUDAExecutionProvider::default().with_device_id(device)),
+            ExecutionProvider::TensorRt => Box::new(providers::TensorRTExecutionProvider::default().with_device_id(device)),
+            ExecutionProvider::DirectMl => Box::new(providers::DirectMLExecutionProvider::default().with_device_id(device)),
+            ExecutionProvider::CoreMl => Box::new(providers::CoreMLExecutionProvider::default()),
+        };
+        if !provider.supported_by_platform() {
+            return Err("not supported on this platform".to_string());
+        }
+        match provider.is_available() {
+            Ok(true) => provider.register(builder).map_err(|e| e.to_string()),
+            Ok(false) => Err("not included in this ONNX Runtime build".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// Which model outputs hold the scores and the heatmap, and how the scores are labelled
+#[derive(Debug, Clone, Default)]
+pub struct InferenceOptions {
+    /// Output with the class scores; None takes the first output of shape [batch, classes]
+    pub scores_output: Option<String>,
+    /// Output with the heatmap; None takes the first output with two spatial dimensions
+    pub heatmap_output: Option<String>,
+    /// Class labels in score order; None reads them from the model metadata
+    pub labels: Option<Vec<String>>,
+    pub activation: ScoreActivation,
+    /// Compute the heatmap with Grad-CAM instead of reading it from `heatmap_output`
+    pub cam: Option<CamOptions>,
+    /// Compute the heatmap from the score drops of occluded copies of the image instead, for
+    /// models with neither a heatmap output nor gradients
+    pub occlusion: Option<OcclusionOptions>,
+    /// How the image is prepared; None reads it from the model metadata, or stretches the image
+    /// to the input size and scales it to 0.0-1.0 without one
+    pub preprocess: Option<PreprocessSpec>,
+    /// Execution providers in order of preference; those unavailable on this machine are
+    /// skipped, and the CPU runs whatever the others don't
+    pub providers: Vec<ExecutionProvider>,
+    /// GPU the providers run on
+    pub device: u32,
+    /// Augmented copies of each image also run, whose scores and inverse-transformed heatmaps
+    /// are averaged with those of the image
+    pub tta: Vec<Augmentation>,
+    /// Scale and zero point of int8 and uint8 inputs and outputs by name, over those in the
+    /// model metadata
+    pub quantization: BTreeMap<String, Quantization>,
+}
+
+/// Model outputs and input Grad-CAM works with: ONNX Runtime doesn't differentiate, so the model
+/// is exported with the gradients computed in its graph
+#[derive(Debug, Clone)]
+pub struct CamOptions {
+    pub method: CamMethod,
+    /// Output with the activations of the final convolutional layer
+    pub activations_output: String,
+    /// Output with the gradients of the target class score with respect to those activations,
+    /// shaped like them, or with one more dimension after the batch for every class
+    pub gradients_output: String,
+    /// Input taking the target class as a one-hot vector of shape [batch, classes]; None uses the
+    /// model's second input, if it has one
+    pub target_input: Option<String>,
+}
+
+impl CamOptions {
+    pub fn new(method: CamMethod) -> Self {
+        CamOptions {
+            method,
+            activations_output: ACTIVATIONS_OUTPUT.to_string(),
+            gradients_output: GRADIENTS_OUTPUT.to_string(),
+            target_input: None,
+        }
+    }
+}
+
+/// Which model produced a result, recorded in its heatmap metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelInfo {
+    /// Registry name, or the file name of a model loaded by path
+    pub name: String,
+    pub version: Option<String>,
+    /// SHA-256 digest of the model file in lowercase hex
+    pub sha256: String,
+}
+
+/// Score of one class
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClassScore {
+    pub label: String,
+    pub score: f32,
+}
+
+/// Result of running the model on one image
+#[derive(Debug, Clone)]
+pub struct Inference {
+    /// In the model's class order
+    pub scores: Vec<ClassScore>,
+    /// Index into `scores` of the highest-scoring class
+    pub top: usize,
+    /// Index into `scores` of the class the heatmap shows
+    pub class: usize,
+    /// Map of `class`, or the only map of a class-agnostic model, at the model's resolution
+    pub heatmap: LoadedHeatmap,
+}
+
+impl Inference {
+    pub fn top_score(&self) -> &ClassScore {
+        &self.scores[self.top]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct InputShape {
+    /// Images per run fixed by the model, or 0 when the batch dimension is dynamic
+    batch: usize,
+    layout: TensorLayout,
+    channels: usize,
+    height: usize,
+    width: usize,
+}
+
+/// Dimensions and values of a model output
+type OutputValues = (Vec<usize>, Vec<f32>);
+
+/// Element type of the image input or an output, with the quantization of integer ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Float32,
+    Float16,
+    Int8(Quantization),
+    Uint8(Quantization),
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Float32 => "fp32",
+            Encoding::Float16 => "fp16",
+            Encoding::Int8(_) => "int8",
+            Encoding::Uint8(_) => "uint8",
+        }
+    }
+}
+
+/// Where the heatmap of a model comes from
+#[derive(Debug)]
+enum MapSource {
+    Output(String),
+    Cam { method: CamMethod, activations: String, gradients: String, target: Option<TargetInput> },
+    /// Score drops while patches of the image are masked, from the scores alone
+    Occlusion(OcclusionOptions),
+}
+
+/// One-hot input selecting the class the model computes gradients for
+#[derive(Debug)]
+struct TargetInput {
+    name: String,
+    classes: usize,
+}
+
+/// An ONNX classification model loaded into an ONNX Runtime session
+#[derive(Debug)]
+pub struct OnnxModel {
+    /// Running the session needs exclusive access
+    session: Mutex<Session>,
+    path: PathBuf,
+    info: ModelInfo,
+    input: String,
+    shape: InputShape,
+    input_encoding: Encoding,
+    scores_output: String,
+    heatmap: MapSource,
+    /// Encodings of the outputs that are read
+    output_encodings: HashMap<String, Encoding>,
+    /// May be shorter than the scores; the remaining classes are named by index
+    labels: Vec<String>,
+    activation: ScoreActivation,
+    preprocess: PreprocessSpec,
+    /// Registered execution providers in order of preference, ending with the CPU
+    providers: Vec<ExecutionProvider>,
+    tta: Vec<Augmentation>,
+    /// Largest score drift from the full-precision model measured when loading
+    drift: Option<f32>,
+}
+
+impl OnnxModel {
+    /// Fetch the registry model `model` (downloading a URL into `cache_dir` once), verify its
+    /// digest and load it, with the registry's labels, preprocessing and quantization unless
+    /// `options` set them; with a drift check, the model is refused when its scores on the
+    /// calibration set drift too far from the full-precision model's
+    pub fn from_registry(model: &ModelConfig, cache_dir: &Path, options: &InferenceOptions) -> Result<Self> {
+        let path = match model.is_remote() {
+            true => download_model(model, cache_dir)?,
+            false => PathBuf::from(&model.source),
+        };
+        let sha256 = file_sha256(&path)?;
+        if let Some(expected) = &model.sha256 && *expected != sha256 {
+            return Err(Error::Inference(format!("Model {} at {} has SHA-256 {}, expected {}", model.name, path.display(), sha256, expected)));
+        }
+        let mut options = options.clone();
+        if options.labels.is_none() && !model.labels.is_empty() {
+            options.labels = Some(model.labels.clone());
+        }
+        if options.preprocess.is_none() {
+            options.preprocess = model.preprocess.clone();
+        }
+        if options.providers.is_empty() {
+            (options.providers, options.device) = (model.providers.clone(), model.device);
+        }
+        for (name, quantization) in &model.quantization {
+            options.quantization.entry(name.clone()).or_insert(*quantization);
+        }
+        let info = ModelInfo { name: model.name.clone(), version: model.version.clone(), sha256 };
+        let mut loaded = Self::load_verified(&path, info, &options)?;
+        if let Some(check) = &model.drift {
+            loaded.drift = Some(verify_drift(&loaded, check, &options)?.max_drift);
+        }
+        Ok(loaded)
+    }
+
+    /// Load the model at `path` into ONNX Runtime, which is itself loaded from `ORT_DYLIB_PATH`
+    /// (or `libonnxruntime` on the library path) on first use
+    pub fn load(path: &Path, options: &InferenceOptions) -> Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let info = ModelInfo { name, version: None, sha256: file_sha256(path)? };
+        Self::load_verified(path, info, options)
+    }
+
+    fn load_verified(path: &Path, info: ModelInfo, options: &InferenceOptions) -> Result<Self> {
+        // ort panics when the runtime library can't be loaded
+        let session = std::panic::catch_unwind(|| -> ort::Result<_> {
+            let mut builder = Session::builder()?;
+            let providers = register_providers(&mut builder, &options.providers, options.device);
+            Ok((builder.commit_from_file(path)?, providers))
+        })
+            .map_err(|panic| {
+                let reason = panic.downcast_ref::<String>().map(String::as_str)
+                    .or_else(|| panic.downcast_ref::<&str>().copied())
+                    .unwrap_or("unknown failure");
+                Error::Inference(format!("ONNX Runtime 1.22 or newer is needed, set ORT_DYLIB_PATH to its library: {}", reason))
+            })?
+            .map_err(|e| Error::Inference(format!("Failed to load model {}: {}", path.display(), e)))?;
+        let (session, providers) = session;
+
+        let input = session.inputs.first()
+            .ok_or_else(|| Error::Inference(format!("Model {} has no inputs", path.display())))?;
+        let (mut shape, input_type) = input_shape(&input.input_type)
+            .ok_or_else(|| Error::Inference(format!(
+                "Input {} of model {} is not an fp32, fp16, int8 or uint8 image of shape [batch, channels, height, width] or [batch, height, width, channels]",
+                input.name, path.display()
+            )))?;
+        let input = input.name.clone();
+
+        let metadata = |key: &str| session.metadata().and_then(|metadata| metadata.custom(key)).ok().flatten();
+        let mut quantization = match metadata(QUANTIZATION_METADATA_KEY) {
+            Some(recorded) => parse_quantization(&recorded).map_err(|e| Error::Inference(format!(
+                "Invalid {} metadata of model {}: {}", QUANTIZATION_METADATA_KEY, path.display(), e
+            )))?,
+            None => BTreeMap::new(),
+        };
+        quantization.extend(options.quantization.iter().map(|(name, quantization)| (name.clone(), *quantization)));
+        let input_encoding = encoding(input_type, &input, &quantization, path)?;
+        let preprocess = match (&options.preprocess, metadata(PREPROCESS_METADATA_KEY)) {
+            (Some(preprocess), _) => preprocess.clone(),
+            (None, Some(recorded)) => recorded.parse().map_err(|e| Error::Inference(format!(
+                "Invalid {} metadata of model {}: {}", PREPROCESS_METADATA_KEY, path.display(), e
+            )))?,
+            (None, None) => PreprocessSpec::default(),
+        };
+        preprocess.check(shape.channels)
+            .map_err(|e| Error::InvalidOption(format!("Invalid preprocessing for model {}: {}", path.display(), e)))?;
+        // Dynamic dimensions are 0 here
+        match preprocess.size.map(|(width, height)| (width as usize, height as usize)) {
+            Some((width, height)) if (shape.width != 0 && shape.width != width) || (shape.height != 0 && shape.height != height) => {
+                return Err(Error::InvalidOption(format!("Preprocessing size {}x{} doesn't match the {}x{} input of model {}",
+                                                        width, height, shape.width, shape.height, path.display())));
+            }
+            Some((width, height)) => (shape.width, shape.height) = (width, height),
+            None => {
+                let size = |dim: usize| if dim == 0 { DEFAULT_INPUT_SIZE } else { dim };
+                (shape.width, shape.height) = (size(shape.width), size(shape.height));
+            }
+        }
+
+        let output_types: HashMap<&str, TensorElementType> = session.outputs.iter()
+            .filter_map(|output| match &output.output_type {
+                ValueType::Tensor { ty, .. } if is_real(*ty) => Some((output.name.as_str(), *ty)),
+                _ => None,
+            })
+            .collect();
+        let outputs: Vec<(&str, &[i64])> = session.outputs.iter()
+            .filter_map(|output| match &output.output_type {
+                ValueType::Tensor { ty, shape, .. } if is_real(*ty) => Some((output.name.as_str(), &shape[..])),
+                _ => None,
+            })
+            .collect();
+        let scores_output = pick_output(&outputs, options.scores_output.as_deref(), |dims| dims.len() <= 2, "scores", path)?;
+        let heatmap = match (&options.cam, options.occlusion) {
+            (Some(_), Some(_)) => return Err(Error::InvalidOption("Grad-CAM and occlusion heatmaps can't be combined".to_string())),
+            (None, Some(occlusion)) => MapSource::Occlusion(occlusion),
+            (None, None) => MapSource::Output(pick_output(&outputs, options.heatmap_output.as_deref(), |dims| dims.len() >= 3, "heatmap", path)?),
+            (Some(cam), None) => {
+                let scores_dims = outputs.iter().find(|(name, _)| *name == scores_output).map(|(_, dims)| *dims).unwrap_or_default();
+                MapSource::Cam {
+                    method: cam.method,
+                    activations: pick_output(&outputs, Some(&cam.activations_output), |_| true, "activations", path)?,
+                    gradients: pick_output(&outputs, Some(&cam.gradients_output), |_| true, "gradients", path)?,
+                    target: target_input(&session, cam.target_input.as_deref(), scores_dims, path)?,
+                }
+            }
+        };
+
+        let labels = match &options.labels {
+            Some(labels) => labels.clone(),
+            None => metadata(LABELS_METADATA_KEY).map(|labels| parse_labels(&labels)).unwrap_or_default(),
+        };
+        let map = match &heatmap {
+            MapSource::Output(name) => name.clone(),
+            MapSource::Cam { method, activations, gradients, .. } => format!("{} of {} and {}", method.name(), activations, gradients),
+            MapSource::Occlusion(_) => "occlusion".to_string(),
+        };
+        let output_encodings = output_names(&scores_output, &heatmap).into_iter()
+            .map(|name| Ok((name.to_string(), encoding(output_types[name], name, &quantization, path)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        let provider_names = providers.iter().map(|provider| provider.name()).collect::<Vec<_>>().join(",");
+        info!("Loaded model {} {} ({}, SHA-256 {}) on {} (device {}): {} input {} {:?} {}x{}x{} in batches of {}, scores from {}, heatmap from {}, {} label(s), preprocessing {}",
+              info.name, info.version.as_deref().unwrap_or("unversioned"), path.display(), info.sha256, provider_names, options.device,
+              input_encoding.name(), input, shape.layout, shape.channels, shape.height, shape.width,
+              if shape.batch == 0 { "any size".to_string() } else { shape.batch.to_string() }, scores_output, map, labels.len(), preprocess);
+
+        Ok(OnnxModel {
+            session: Mutex::new(session),
+            path: path.to_path_buf(),
+            info,
+            input,
+            shape,
+            input_encoding,
+            scores_output,
+            heatmap,
+            output_encodings,
+            labels,
+            activation: options.activation,
+            preprocess,
+            tta: options.tta.clone(),
+            providers,
+            drift: None,
+        })
+    }
+
+    /// Run the model on a grayscale image, resized to the model's input, with the heatmap of
+    /// class `target` or else of the top-scoring class
+    pub fn infer(&self, image: &GrayImage, target: Option<usize>) -> Result<Inference> {
+        self.infer_batch(std::slice::from_ref(image), target)?
+            .pop()
+            .ok_or_else(|| Error::Inference(format!("Model {} returned no result", self.path.display())))
+    }
+
+    /// Run the model on several images like [`OnnxModel::infer`], in one session run for all of
+    /// them, or in runs of the batch size the model fixes (the last one padded with blank images)
+    ///
+    /// With test-time augmentation the augmented copies run in the same batches, and each image
+    /// gets the mean scores of its copies and the mean heatmap of the class chosen from them.
+    pub fn infer_batch(&self, images: &[GrayImage], target: Option<usize>) -> Result<Vec<Inference>> {
+        if self.tta.is_empty() {
+            return self.infer_images(images, target);
+        }
+        let variants: Vec<Option<Augmentation>> = std::iter::once(None).chain(self.tta.iter().copied().map(Some)).collect();
+        let augmented: Vec<GrayImage> = images.iter()
+            .flat_map(|image| variants.iter().map(move |variant| match variant {
+                Some(augmentation) => augmentation.apply(image),
+                None => image.clone(),
+            }))
+            .collect();
+        let runs = self.infer_images(&augmented, target)?;
+
+        let mut results = Vec::with_capacity(images.len());
+        for (index, (image, runs)) in images.iter().zip(runs.chunks(variants.len())).enumerate() {
+            let scores: Vec<f32> = (0..runs[0].scores.len())
+                .map(|class| runs.iter().map(|run| run.scores.get(class).map_or(0.0, |score| score.score)).sum::<f32>() / runs.len() as f32)
+                .collect();
+            let top = scores.iter().enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index)
+                .unwrap_or_default();
+            let class = target.unwrap_or(top);
+            // Copies whose own top class differs explain the chosen class again
+            let rerun;
+            let runs = match runs.iter().all(|run| run.class == class) {
+                true => runs,
+                false => {
+                    rerun = self.infer_images(&augmented[index * variants.len()..(index + 1) * variants.len()], Some(class))?;
+                    &rerun[..]
+                }
+            };
+            results.push(self.average(runs, &variants, scores, top, class, image.dimensions()));
+        }
+        Ok(results)
+    }
+
+    /// Inference of an image from the inferences of its augmented copies, in the order of
+    /// `variants`, with the mean `scores`
+    fn average(&self, runs: &[Inference], variants: &[Option<Augmentation>], scores: Vec<f32>, top: usize, class: usize, (width, height): (u32, u32)) -> Inference {
+        let maps: Vec<_> = runs.iter().map(|run| run.heatmap.data.clone()).collect();
+        let data = average_heatmaps(&maps, variants, width, height);
+        let scores: Vec<ClassScore> = scores.into_iter().enumerate()
+            .map(|(index, score)| ClassScore { label: self.label(index), score })
+            .collect();
+
+        let mut metadata = runs[0].heatmap.metadata.clone();
+        metadata.shape = data.dim();
+        metadata.attributes.insert("class".to_string(), scores[class].label.clone());
+        metadata.attributes.insert("score".to_string(), scores[class].score.to_string());
+        for score in &scores {
+            metadata.attributes.insert(format!("score:{}", score.label), score.score.to_string());
+        }
+        metadata.attributes.insert("tta".to_string(), self.tta.iter().map(Augmentation::name).collect::<Vec<_>>().join(","));
+        Inference { scores, top, class, heatmap: LoadedHeatmap { data, metadata } }
+    }
+
+    /// [`OnnxModel::infer_batch`] without test-time augmentation
+    fn infer_images(&self, images: &[GrayImage], target: Option<usize>) -> Result<Vec<Inference>> {
+        let size = match self.shape.batch {
+            0 => images.len().max(1),
+            fixed => fixed,
+        };
+        let mut results = Vec::with_capacity(images.len());
+        for chunk in images.chunks(size) {
+            results.extend(self.infer_chunk(chunk, size, target)?);
+        }
+        Ok(results)
+    }
+
+    /// Maximum number of images per session run, or None if the model takes any number
+    pub fn batch_size(&self) -> Option<usize> {
+        (self.shape.batch != 0).then_some(self.shape.batch)
+    }
+
+    /// Run the model once on `images`, padded to `batch` images
+    fn infer_chunk(&self, images: &[GrayImage], batch: usize, target: Option<usize>) -> Result<Vec<Inference>> {
+        let (pixels, placements) = self.input_pixels(images, batch);
+        let target_input = match &self.heatmap {
+            MapSource::Cam { target: Some(input), .. } => Some(input),
+            _ => None,
+        };
+        let targets = target.map(|class| vec![class; batch]);
+        let names = output_names(&self.scores_output, &self.heatmap);
+        let mut outputs = self.run_batch(&pixels, batch, target_input.zip(targets.as_deref()), &names, images.len())?;
+
+        let mut classes = Vec::with_capacity(images.len());
+        for image_outputs in &mut outputs {
+            let scores = self.take_scores(image_outputs)?;
+            let top = scores.iter().enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index)
+                .unwrap_or_default();
+            let class = target.unwrap_or(top);
+            if class >= scores.len() {
+                return Err(Error::InvalidOption(format!("Target class {} is out of range, model {} has {} classes",
+                                                        class, self.path.display(), scores.len())));
+            }
+            classes.push((scores, top, class));
+        }
+        // The top classes are only known after a first pass
+        if let Some(input) = target_input && target.is_none() {
+            let mut targets: Vec<usize> = classes.iter().map(|(_, _, class)| *class).collect();
+            targets.resize(batch, 0);
+            outputs = self.run_batch(&pixels, batch, Some((input, &targets)), &names, images.len())?;
+        }
+        outputs.into_iter().zip(classes).zip(images.iter().zip(&placements))
+            .map(|((outputs, (scores, top, class)), (image, placement))| self.explain(outputs, scores, top, class, image, placement))
+            .collect()
+    }
+
+    /// Preprocessed pixels of `images` padded with blank ones to `batch` images, and where each
+    /// image lies in its input
+    fn input_pixels(&self, images: &[GrayImage], batch: usize) -> (Vec<f32>, Vec<Placement>) {
+        let InputShape { layout, channels, height, width, .. } = self.shape;
+        let mut pixels = Vec::with_capacity(batch * channels * height * width);
+        let mut placements = Vec::with_capacity(images.len());
+        for image in images {
+            let (resized, placement) = self.preprocess.resize(image, (width as u32, height as u32));
+            pixels.extend(self.preprocess.tensor_values(&resized, channels, layout == TensorLayout::Nhwc));
+            placements.push(placement);
+        }
+        pixels.resize(batch * channels * height * width, 0.0);
+        (pixels, placements)
+    }
+
+    /// Activated scores of each of `images` alone, without a heatmap or test-time augmentation, in
+    /// runs of the batch size the model fixes
+    pub fn score_batch(&self, images: &[GrayImage]) -> Result<Vec<Vec<f32>>> {
+        let size = self.batch_size().unwrap_or(images.len().max(1));
+        let mut scores = Vec::with_capacity(images.len());
+        for chunk in images.chunks(size) {
+            let (pixels, _) = self.input_pixels(chunk, size);
+            for mut outputs in self.run_batch(&pixels, size, None, &[&self.scores_output], chunk.len())? {
+                scores.push(self.take_scores(&mut outputs)?);
+            }
+        }
+        Ok(scores)
+    }
+
+    /// Activated scores among the outputs of one image
+    fn take_scores(&self, outputs: &mut HashMap<String, OutputValues>) -> Result<Vec<f32>> {
+        let Some((_, mut scores)) = outputs.remove(&self.scores_output).filter(|(_, scores)| !scores.is_empty()) else {
+            return Err(Error::Inference(format!("Output {} holds no scores", self.scores_output)));
+        };
+        self.activation.apply(&mut scores);
+        Ok(scores)
+    }
+
+    /// Heatmap of `class` from the drops of its `baseline` score over occluded copies of `image`,
+    /// scored in the model's batches
+    fn occlusion_map(&self, image: &GrayImage, options: &OcclusionOptions, class: usize, baseline: f32) -> Result<(Array2<f32>, OcclusionPlan)> {
+        let plan = OcclusionPlan::new(image, options);
+        let size = match self.shape.batch {
+            0 => OCCLUSION_BATCH,
+            fixed => fixed,
+        };
+        let mut scores = Vec::with_capacity(plan.len());
+        for start in (0..plan.len()).step_by(size) {
+            let occluded: Vec<GrayImage> = (start..(start + size).min(plan.len())).map(|index| plan.occluded(image, index)).collect();
+            let (pixels, _) = self.input_pixels(&occluded, size);
+            for mut outputs in self.run_batch(&pixels, size, None, &[&self.scores_output], occluded.len())? {
+                scores.push(self.take_scores(&mut outputs)?.get(class).copied().unwrap_or_default());
+            }
+        }
+        Ok((plan.assemble(baseline, &scores), plan))
+    }
+
+    /// Inference of one image from its share of the outputs and its activated scores
+    fn explain(
+        &self,
+        mut outputs: HashMap<String, OutputValues>,
+        scores: Vec<f32>,
+        top: usize,
+        class: usize,
+        image: &GrayImage,
+        placement: &Placement,
+    ) -> Result<Inference> {
+        let scores: Vec<ClassScore> = scores.into_iter().enumerate()
+            .map(|(index, score)| ClassScore { label: self.label(index), score })
+            .collect();
+
+        let mut take = |name: &str| outputs.remove(name).unwrap_or_default();
+        let mut attributes = BTreeMap::new();
+        let data = match &self.heatmap {
+            MapSource::Output(name) => {
+                let (dims, values) = take(name);
+                class_map(&dims, values, scores.len(), class).ok_or_else(|| Error::Inference(format!(
+                    "Output {} of shape {:?} is not a heatmap of shape [batch, classes, height, width], [batch, height, width, classes] or [batch, height, width]",
+                    name, dims
+                )))?
+            }
+            MapSource::Cam { method, activations, gradients, target } => {
+                let (activation_dims, activation_values) = take(activations);
+                let (mut gradient_dims, mut gradient_values) = take(gradients);
+                if gradient_dims.len() == activation_dims.len() + 1 {
+                    (gradient_dims, gradient_values) = class_slice(gradients, &gradient_dims, gradient_values, scores.len(), class)?;
+                } else if target.is_none() && class != top {
+                    return Err(Error::InvalidOption(format!(
+                        "Model {} computes gradients for its top class only; give it a target class input to explain class {}",
+                        self.path.display(), class
+                    )));
+                }
+                let activation_maps = feature_maps(activations, &activation_dims, activation_values, self.shape.layout)?;
+                let gradient_maps = feature_maps(gradients, &gradient_dims, gradient_values, self.shape.layout)?;
+                if activation_maps.dim() != gradient_maps.dim() {
+                    return Err(Error::ShapeMismatch {
+                        context: format!("gradients {} of activations {}", gradients, activations),
+                        expected: activation_maps.len(),
+                        found: gradient_maps.len(),
+                    });
+                }
+                attributes.insert("method".to_string(), method.name().to_string());
+                class_activation_map(&activation_maps, &gradient_maps, *method)
+            }
+            MapSource::Occlusion(options) => {
+                let (data, plan) = self.occlusion_map(image, options, class, scores[class].score)?;
+                attributes.insert("method".to_string(), "occlusion".to_string());
+                attributes.insert("occlusion".to_string(), format!("patch={},stride={},runs={}", plan.patch(), plan.stride(), plan.len()));
+                data
+            }
+        };
+        // Letterbox padding or cropped margins of the input aren't part of the image, which
+        // occlusion maps already cover exactly
+        let data = match self.heatmap {
+            MapSource::Occlusion(_) => data,
+            _ => self.preprocess.restore(&data, placement),
+        };
+
+        attributes.insert("model".to_string(), self.info.name.clone());
+        if let Some(version) = &self.info.version {
+            attributes.insert("model_version".to_string(), version.clone());
+        }
+        attributes.insert("model_sha256".to_string(), self.info.sha256.clone());
+        attributes.insert("preprocess".to_string(), self.preprocess.to_string());
+        attributes.insert("providers".to_string(), self.providers.iter().map(|provider| provider.name()).collect::<Vec<_>>().join(","));
+        if let Some(precision) = self.precision() {
+            attributes.insert("precision".to_string(), precision);
+        }
+        if let Some(drift) = self.drift {
+            attributes.insert("score_drift".to_string(), drift.to_string());
+        }
+        attributes.insert("class".to_string(), scores[class].label.clone());
+        attributes.insert("score".to_string(), scores[class].score.to_string());
+        for score in &scores {
+            attributes.insert(format!("score:{}", score.label), score.score.to_string());
+        }
+        let metadata = HeatmapMetadata {
+            format: "onnx".to_string(),
+            path: self.path.display().to_string(),
+            shape: data.dim(),
+            attributes,
+        };
+        Ok(Inference { scores, top, class, heatmap: LoadedHeatmap { data, metadata } })
+    }
+
+    /// Run the session on the preprocessed pixels of `batch` images, with the one-hot target
+    /// class of each if the model takes one, returning the dimensions and real values of the
+    /// outputs `names` for each of the first `count` images, with a batch dimension of 1
+    fn run_batch(
+        &self,
+        pixels: &[f32],
+        batch: usize,
+        targets: Option<(&TargetInput, &[usize])>,
+        names: &[&str],
+        count: usize,
+    ) -> Result<Vec<HashMap<String, OutputValues>>> {
+        let InputShape { layout, channels, height, width, .. } = self.shape;
+        let tensor_error = |e: ort::Error| Error::Inference(format!("Failed to create the input tensor: {}", e));
+        let dims = layout.dims(batch, channels, height, width);
+        let image: DynTensor = match self.input_encoding {
+            Encoding::Float32 => Tensor::from_array((dims, pixels.to_vec())).map_err(tensor_error)?.upcast(),
+            Encoding::Float16 => {
+                let values: Vec<half::f16> = pixels.iter().map(|&value| half::f16::from_f32(value)).collect();
+                Tensor::from_array((dims, values)).map_err(tensor_error)?.upcast()
+            }
+            Encoding::Int8(quantization) => {
+                let values: Vec<i8> = pixels.iter().map(|&value| quantization.quantize(value, i8::MIN.into(), i8::MAX.into()) as i8).collect();
+                Tensor::from_array((dims, values)).map_err(tensor_error)?.upcast()
+            }
+            Encoding::Uint8(quantization) => {
+                let values: Vec<u8> = pixels.iter().map(|&value| quantization.quantize(value, 0, u8::MAX.into()) as u8).collect();
+                Tensor::from_array((dims, values)).map_err(tensor_error)?.upcast()
+            }
+        };
+        let mut inputs = ort::inputs![self.input.as_str() => image];
+        if let Some((input, classes)) = targets {
+            let mut one_hot = vec![0.0; batch * input.classes];
+            for (row, &class) in one_hot.chunks_mut(input.classes).zip(classes) {
+                if let Some(value) = row.get_mut(class) {
+                    *value = 1.0;
+                }
+            }
+            let tensor = Tensor::from_array(([batch, input.classes], one_hot)).map_err(tensor_error)?;
+            inputs.push((input.name.as_str().into(), tensor.into()));
+        }
+
+        let mut session = self.session.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let outputs = session.run(inputs)
+            .map_err(|e| Error::Inference(format!("Model {} failed: {}", self.path.display(), e)))?;
+        let mut images = vec![HashMap::new(); count];
+        for &name in names {
+            let encoding = self.output_encodings.get(name).copied().unwrap_or(Encoding::Float32);
+            let (mut dims, values) = real_values(&outputs[name], encoding)
+                .map_err(|e| Error::Inference(format!("Output {} is not an {} tensor: {}", name, encoding.name(), e)))?;
+            // A single image may come without a batch dimension, e.g. scores of shape [classes]
+            if batch == 1 {
+                images[0].insert(name.to_string(), (dims, values));
+                continue;
+            }
+            if dims.first() != Some(&batch) || values.len() % batch != 0 {
+                return Err(Error::Inference(format!("Output {} of shape {:?} doesn't hold a batch of {} images", name, dims, batch)));
+            }
+            dims[0] = 1;
+            let size = values.len() / batch;
+            for (outputs, values) in images.iter_mut().zip(values.chunks(size)) {
+                outputs.insert(name.to_string(), (dims.clone(), values.to_vec()));
+            }
+        }
+        Ok(images)
+    }
+
+    pub fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    /// Encodings of the input and the outputs read, e.g. `uint8,int8`, or None for a model with
+    /// only fp32 ones
+    pub fn precision(&self) -> Option<String> {
+        let mut names = vec![self.input_encoding.name()];
+        for &name in &output_names(&self.scores_output, &self.heatmap) {
+            let encoding = self.output_encodings.get(name).map_or("fp32", |encoding| encoding.name());
+            if !names.contains(&encoding) {
+                names.push(encoding);
+            }
+        }
+        (names != ["fp32"]).then(|| names.join(","))
+    }
+
+    /// Largest score drift from the full-precision model on the calibration set, when the
+    /// registry configures a drift check
+    pub fn score_drift(&self) -> Option<f32> {
+        self.drift
+    }
+
+    /// Execution providers the session was created with, in order of preference, ending with
+    /// the CPU
+    pub fn providers(&self) -> &[ExecutionProvider] {
+        &self.providers
+    }
+
+    /// Class index of `selector` for [`OnnxModel::infer`], with labels looked up among the model's
+    /// labels; None for the top-scoring class
+    pub fn class_index(&self, selector: &ClassSelector) -> Result<Option<usize>> {
+        selector.index(&self.labels).map_err(|e| Error::InvalidOption(format!("{} (model {})", e, self.path.display())))
+    }
+
+    fn label(&self, index: usize) -> String {
+        self.labels.get(index).cloned().unwrap_or_else(|| format!("class_{}", index))
+    }
+}
+
+/// Register each of `providers` that ONNX Runtime can use here, warning about the others, and
+/// return the registered ones with the CPU last
+fn register_providers(builder: &mut SessionBuilder, providers: &[ExecutionProvider], device: u32) -> Vec<ExecutionProvider> {
+    let mut registered = Vec::new();
+    // Providers after the CPU would never get a node
+    for &provider in providers.iter().take_while(|&&provider| provider != ExecutionProvider::Cpu) {
+        if registered.contains(&provider) {
+            continue;
+        }
+        match provider.register(builder, device as i32) {
+            Ok(()) => registered.push(provider),
+            Err(reason) => warn!("Execution provider {} can't be used, falling back to the next one: {}", provider.name(), reason),
+        }
+    }
+    registered.push(ExecutionProvider::Cpu);
+    registered
+}
+
+/// Layout and size of an image input with its element type, with 0 for a dynamic height or width
+/// and one channel for a dynamic channel count
+fn input_shape(input: &ValueType) -> Option<(InputShape, TensorElementType)> {
+    let ValueType::Tensor { ty, shape, .. } = input else {
+        return None;
+    };
+    if !is_real(*ty) {
+        return None;
+    }
+    let [batch, a, b, c] = shape[..] else {
+        return None;
+    };
+    let size = |dim: i64| dim.max(0) as usize;
+    let batch = size(batch);
+    // Channels come first unless the last dimension looks like them and the second doesn't
+    let channels_last = matches!(c, 1 | 3) && !matches!(a, 1 | 3);
+    let shape = match channels_last {
+        true => InputShape { batch, layout: TensorLayout::Nhwc, channels: c as usize, height: size(a), width: size(b) },
+        false => InputShape { batch, layout: TensorLayout::Nchw, channels: if a > 0 { a as usize } else { 1 }, height: size(b), width: size(c) },
+    };
+    Some((shape, *ty))
+}
+
+/// Element types read as real values: floats, and integers with a quantization
+fn is_real(ty: TensorElementType) -> bool {
+    matches!(ty, TensorElementType::Float32 | TensorElementType::Float16 | TensorElementType::Int8 | TensorElementType::Uint8)
+}
+
+/// Encoding of tensor `name` of element type `ty`; int8 and uint8 need a quantization
+fn encoding(ty: TensorElementType, name: &str, quantization: &BTreeMap<String, Quantization>, path: &Path) -> Result<Encoding> {
+    let quantization = || quantization.get(name).copied().ok_or_else(|| Error::Inference(format!(
+        "Tensor {} of model {} is quantized but its scale and zero point are unknown; give them in the {} metadata, --quantization or MODEL_<NAME>_QUANTIZATION",
+        name, path.display(), QUANTIZATION_METADATA_KEY
+    )));
+    Ok(match ty {
+        TensorElementType::Float16 => Encoding::Float16,
+        TensorElementType::Int8 => Encoding::Int8(quantization()?),
+        TensorElementType::Uint8 => Encoding::Uint8(quantization()?),
+        _ => Encoding::Float32,
+    })
+}
+
+/// Outputs a run reads for the scores and the heatmap
+fn output_names<'a>(scores_output: &'a str, heatmap: &'a MapSource) -> Vec<&'a str> {
+    match heatmap {
+        MapSource::Output(name) => vec![scores_output, name],
+        MapSource::Cam { activations, gradients, .. } => vec![scores_output, activations, gradients],
+        MapSource::Occlusion(_) => vec![scores_output],
+    }
+}
+
+/// Dimensions and real values of an output, converted from half precision or dequantized
+fn real_values(value: &DynValue, encoding: Encoding) -> ort::Result<OutputValues> {
+    let dims = |shape: &[i64]| shape.iter().map(|&dim| dim.max(0) as usize).collect::<Vec<_>>();
+    Ok(match encoding {
+        Encoding::Float32 => {
+            let (shape, values) = value.try_extract_tensor::<f32>()?;
+            (dims(shape), values.to_vec())
+        }
+        Encoding::Float16 => {
+            let (shape, values) = value.try_extract_tensor::<half::f16>()?;
+            (dims(shape), values.iter().map(|value| value.to_f32()).collect())
+        }
+        Encoding::Int8(quantization) => {
+            let (shape, values) = value.try_extract_tensor::<i8>()?;
+            (dims(shape), values.iter().map(|&value| quantization.dequantize(value.into())).collect())
+        }
+        Encoding::Uint8(quantization) => {
+            let (shape, values) = value.try_extract_tensor::<u8>()?;
+            (dims(shape), values.iter().map(|&value| quantization.dequantize(value.into())).collect())
+        }
+    })
+}
+
+/// Output named `name`, or else the first output whose dimensions pass `fits`
+fn pick_output(outputs: &[(&str, &[i64])], name: Option<&str>, fits: impl Fn(&[i64]) -> bool, what: &str, path: &Path) -> Result<String> {
+    let available = || outputs.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+    match name {
+        Some(name) if outputs.iter().any(|(output, _)| *output == name) => Ok(name.to_string()),
+        Some(name) => Err(Error::Inference(format!(
+            "Model {} has no fp32, fp16, int8 or uint8 output {}. Available: {}", path.display(), name, available()
+        ))),
+        None => outputs.iter()
+            .find(|(_, dims)| fits(dims))
+            .map(|(name, _)| name.to_string())
+            .ok_or_else(|| Error::Inference(format!(
+                "Model {} has no {} output; available outputs: {}", path.display(), what, available()
+            ))),
+    }
+}
+
+/// The one-hot input of the target class, named or else the model's second input, with its
+/// number of classes, taken from the scores when the input doesn't fix it
+fn target_input(session: &Session, name: Option<&str>, scores_dims: &[i64], path: &Path) -> Result<Option<TargetInput>> {
+    let input = match name {
+        Some(name) => Some(session.inputs.iter().find(|input| input.name == name).ok_or_else(|| {
+            Error::Inference(format!("Model {} has no input {}", path.display(), name))
+        })?),
+        None => session.inputs.get(1),
+    };
+    let Some(input) = input else {
+        return Ok(None);
+    };
+    let ValueType::Tensor { ty: TensorElementType::Float32, shape, .. } = &input.input_type else {
+        return Err(Error::Inference(format!("Input {} of model {} is not a float one-hot vector", input.name, path.display())));
+    };
+    let classes = match (&shape[..], scores_dims.last()) {
+        ([_, classes], _) if *classes > 0 => *classes as usize,
+        ([_, _], Some(&classes)) if classes > 0 => classes as usize,
+        _ => return Err(Error::Inference(format!(
+            "Input {} of model {} is not a one-hot vector of shape [batch, classes] with a fixed number of classes",
+            input.name, path.display()
+        ))),
+    };
+    Ok(Some(TargetInput { name: input.name.clone(), classes }))
+}
+
+/// Labels from model metadata, a JSON array or a comma-separated list
+fn parse_labels(labels: &str) -> Vec<String> {
+    serde_json::from_str::<Vec<String>>(labels).unwrap_or_else(|_| {
+        labels.split(',').map(|label| label.trim().to_string()).filter(|label| !label.is_empty()).collect()
+    })
+}
+
+/// Gradients of class `class` from an output with the gradients of every class after the batch
+/// dimension, keeping the batch dimension
+fn class_slice(name: &str, dims: &[usize], values: Vec<f32>, classes: usize, class: usize) -> Result<(Vec<usize>, Vec<f32>)> {
+    let [batch, count, ref rest @ ..] = dims[..] else {
+        return Err(Error::Inference(format!("Output {} of shape {:?} has no class dimension", name, dims)));
+    };
+    let size: usize = rest.iter().product();
+    if batch != 1 || count != classes || values.len() < classes * size {
+        return Err(Error::Inference(format!(
+            "Output {} of shape {:?} doesn't hold the gradients of the {} classes", name, dims, classes
+        )));
+    }
+    let sliced = std::iter::once(1).chain(rest.iter().copied()).collect();
+    Ok((sliced, values[class * size..(class + 1) * size].to_vec()))
+}
+
+/// Activations or gradients of the first image as (channels, height, width), from an output in
+/// the order of the image input
+fn feature_maps(name: &str, dims: &[usize], values: Vec<f32>, layout: TensorLayout) -> Result<Array3<f32>> {
+    let shape_error = || Error::Inference(format!(
+        "Output {} of shape {:?} is not a feature map of shape [batch, channels, height, width] or [batch, height, width, channels]",
+        name, dims
+    ));
+    let [1, first, second, third] = dims[..] else {
+        return Err(shape_error());
+    };
+    let size = first * second * third;
+    if values.len() < size {
+        return Err(shape_error());
+    }
+    let maps = Array3::from_shape_vec((first, second, third), values[..size].to_vec()).map_err(|_| shape_error())?;
+    Ok(match layout {
+        TensorLayout::Nchw => maps,
+        TensorLayout::Nhwc => maps.permuted_axes([2, 0, 1]).as_standard_layout().into_owned(),
+    })
+}
+
+/// SHA-256 digest of the file at `path` in lowercase hex
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| Error::io(path, e))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Local copy of a registry model with a URL, downloaded into `cache_dir` unless a copy with the
+/// expected digest is there already
+fn download_model(model: &ModelConfig, cache_dir: &Path) -> Result<PathBuf> {
+    let expected = model.sha256.as_deref().unwrap_or_default();
+    let path = cache_dir.join(format!("{}-{}.onnx", model.name, &expected[..expected.len().min(16)]));
+    if path.exists() {
+        match file_sha256(&path) {
+            Ok(digest) if digest == expected => return Ok(path),
+            _ => warn!("Cached copy {} of model {} doesn't match its digest, downloading it again", path.display(), model.name),
+        }
+    }
+    std::fs::create_dir_all(cache_dir).map_err(|e| Error::io(cache_dir, e))?;
+    let url = crate::config::redact_url(&model.source);
+    info!("Downloading model {} from {}", model.name, url);
+    let download_error = |e: reqwest::Error| Error::Inference(format!("Failed to download model {} from {}: {}", model.name, url, e));
+    let mut response = reqwest::blocking::get(&model.source)
+        .and_then(|response| response.error_for_status())
+        .map_err(download_error)?;
+    // Written next to the cache entry and renamed, so an interrupted download is never used
+    let partial = path.with_extension("onnx.part");
+    let mut file = File::create(&partial).map_err(|e| Error::io(&partial, e))?;
+    response.copy_to(&mut file).map_err(download_error)?;
+    drop(file);
+    let digest = file_sha256(&partial)?;
+    if digest != expected {
+        let _ = std::fs::remove_file(&partial);
+        return Err(Error::Inference(format!("Model {} downloaded from {} has SHA-256 {}, expected {}", model.name, url, digest, expected)));
+    }
+    std::fs::rename(&partial, &path).map_err(|e| Error::io(&path, e))?;
+    Ok(path)
+}
@@ -0,0 +1,111 @@
+//! DICOM heatmap processing pipeline: decode medical images, load ML heatmaps,
+//! normalize and colorize them, and fuse the overlay into a PNG.
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod audit;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod batch;
+pub mod buffers;
+#[cfg(feature = "fs")]
+pub mod calibration;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod cache;
+pub mod cam;
+#[cfg(feature = "service")]
+pub mod circuit;
+pub mod colormap;
+#[cfg(feature = "fs")]
+pub mod comparison;
+pub mod config;
+#[cfg(feature = "fs")]
+pub mod coverage;
+pub mod decision;
+pub mod demo;
+#[cfg(feature = "dicomweb")]
+pub mod dicomweb;
+#[cfg(feature = "dicom")]
+pub mod dicom_io;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+#[cfg(feature = "dimse")]
+pub mod dimse;
+pub mod error;
+#[cfg(feature = "fs")]
+pub mod evaluation;
+#[cfg(feature = "service")]
+pub mod fanout;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+pub mod heatmap;
+#[cfg(feature = "fs")]
+pub mod histogram;
+#[cfg(feature = "fs")]
+pub mod hotspots;
+#[cfg(feature = "onnx")]
+pub mod inference;
+#[cfg(feature = "fs")]
+pub mod localization;
+#[cfg(feature = "service")]
+pub mod model_server;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod normalize;
+pub mod occlusion;
+#[cfg(feature = "png")]
+pub mod output;
+mod parallel;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod pipeline;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod preprocess;
+pub mod progress;
+pub mod projection;
+#[cfg(feature = "fs")]
+pub mod provenance;
+#[cfg(feature = "dicom")]
+pub mod quality;
+#[cfg(feature = "fs")]
+pub mod regions;
+#[cfg(feature = "onnx")]
+pub mod quantization;
+pub mod render;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod series;
+#[cfg(feature = "service")]
+pub mod service;
+mod simd;
+#[cfg(feature = "fs")]
+pub mod slices;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub mod spec;
+#[cfg(feature = "storage")]
+pub mod storage;
+#[cfg(feature = "fs")]
+pub mod sweep;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+#[cfg(feature = "png")]
+pub mod tiled;
+pub mod tta;
+#[cfg(feature = "fs")]
+pub mod validation;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use colormap::ColorMap;
+pub use error::{Error, Result};
+pub use demo::{DemoOptions, DemoPattern};
+pub use normalize::Normalization;
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub use pipeline::{HeatmapInput, HeatmapPipeline, ImageSource, Overlay, OutputTarget, PipelineArtifacts, PipelineResult, ReformatResult, VolumeResult};
+pub use progress::{CancellationToken, ProgressSink, Stage};
+pub use render::{Annotation, BlendMode, BlendOptions};
+#[cfg(all(feature = "dicom", feature = "fs"))]
+pub use spec::PipelineSpec;
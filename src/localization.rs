@@ -0,0 +1,151 @@
+//! Pointing-game localization: whether the maximum of a heatmap falls on one of the annotated
+//! lesions of its image, scored per image and as a hit rate over a batch.
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::hotspots::Position;
+
+/// Distance in image pixels within which a maximum next to a lesion still counts as a hit
+pub const DEFAULT_POINTING_TOLERANCE: f64 = 15.0;
+
+/// Annotated lesion, in pixels of the annotated image from its top-left corner
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Lesion {
+    Box { x: f64, y: f64, width: f64, height: f64 },
+    Point { x: f64, y: f64 },
+}
+
+impl Lesion {
+    /// Distance from `position` to the lesion, 0.0 inside a box
+    pub fn distance(&self, position: Position) -> f64 {
+        let (dx, dy) = match *self {
+            Lesion::Box { x, y, width, height } => (
+                (x - position.x).max(position.x - (x + width)).max(0.0),
+                (y - position.y).max(position.y - (y + height)).max(0.0),
+            ),
+            Lesion::Point { x, y } => (position.x - x, position.y - y),
+        };
+        dx.hypot(dy)
+    }
+}
+
+/// Lesions annotated on one image
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct LesionAnnotations {
+    /// Size of the image the lesions were annotated on, if it may differ from the heatmap's
+    /// image; their coordinates are scaled to the heatmap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    pub lesions: Vec<Lesion>,
+}
+
+impl LesionAnnotations {
+    /// With the size of the annotated image set to `width`×`height` where the file gives none
+    pub fn or_size(self, width: u32, height: u32) -> Self {
+        LesionAnnotations { width: self.width.or(Some(width)), height: self.height.or(Some(height)), ..self }
+    }
+}
+
+/// Load lesion annotations from JSON: `{"width": 2048, "height": 2048, "lesions": [...]}`, or
+/// just the array of lesions, each `{"x", "y"}` for a point or `{"x", "y", "width", "height"}`
+/// for a box
+pub fn load_lesions(path: &Path) -> Result<LesionAnnotations> {
+    let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let invalid = |e: serde_json::Error| Error::InvalidOption(format!("Invalid lesion annotations {}: {}", path.display(), e));
+    if text.trim_start().starts_with('[') {
+        Ok(LesionAnnotations { lesions: serde_json::from_str(&text).map_err(invalid)?, ..LesionAnnotations::default() })
+    } else {
+        serde_json::from_str(&text).map_err(invalid)
+    }
+}
+
+/// Lesion annotations a heatmap is scored against, and the tolerance of a hit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointingOptions {
+    /// JSON annotations, see [`load_lesions`]
+    pub lesions: PathBuf,
+    /// Distance in pixels of the annotated image within which a maximum still hits a lesion
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+}
+
+fn default_tolerance() -> f64 {
+    DEFAULT_POINTING_TOLERANCE
+}
+
+impl PointingOptions {
+    pub fn new(lesions: impl Into<PathBuf>) -> Self {
+        PointingOptions { lesions: lesions.into(), tolerance: DEFAULT_POINTING_TOLERANCE }
+    }
+
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+}
+
+/// Pointing-game outcome of one heatmap
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointingResult {
+    /// Position of the heatmap maximum, in pixels of the annotated image
+    pub maximum: Position,
+    pub peak: f32,
+    /// Whether the maximum is within the tolerance of a lesion
+    pub hit: bool,
+    /// Distance from the maximum to the nearest lesion, None without lesions
+    pub distance: Option<f64>,
+    pub tolerance: f64,
+    pub lesions: usize,
+}
+
+/// Hit rate of the pointing game over the images with at least one lesion
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PointingSummary {
+    pub images: u64,
+    pub hits: u64,
+    /// None without any image with lesions
+    pub hit_rate: Option<f64>,
+}
+
+/// Score the maximum of `heatmap` (its first pixel of the highest finite value) against the
+/// lesions, whose coordinates are scaled from the annotated image's size to the heatmap's; None
+/// when the heatmap has no finite value
+pub fn pointing_game(heatmap: &Array2<f32>, annotations: &LesionAnnotations, tolerance: f64) -> Option<PointingResult> {
+    let (height, width) = heatmap.dim();
+    let ((y, x), peak) = heatmap.indexed_iter()
+        .filter(|(_, value)| value.is_finite())
+        .fold(None, |best: Option<((usize, usize), f32)>, (index, &value)| match best {
+            Some((_, peak)) if peak >= value => best,
+            _ => Some((index, value)),
+        })?;
+
+    // Pixel centers map onto each other, so the maximum's position is in annotated pixels
+    let scale_x = annotations.width.map_or(1.0, |annotated| f64::from(annotated) / width as f64);
+    let scale_y = annotations.height.map_or(1.0, |annotated| f64::from(annotated) / height as f64);
+    let maximum = Position { x: (x as f64 + 0.5) * scale_x - 0.5, y: (y as f64 + 0.5) * scale_y - 0.5 };
+    let distance = annotations.lesions.iter().map(|lesion| lesion.distance(maximum)).min_by(f64::total_cmp);
+    Some(PointingResult {
+        maximum,
+        peak,
+        hit: distance.is_some_and(|distance| distance <= tolerance),
+        distance,
+        tolerance,
+        lesions: annotations.lesions.len(),
+    })
+}
+
+/// Hits among the `results` for images with lesions
+pub fn summarize_pointing<'a>(results: impl IntoIterator<Item = &'a PointingResult>) -> PointingSummary {
+    let (mut images, mut hits) = (0, 0);
+    for result in results.into_iter().filter(|result| result.lesions > 0) {
+        images += 1;
+        hits += u64::from(result.hit);
+    }
+    PointingSummary { images, hits, hit_rate: (images > 0).then(|| hits as f64 / images as f64) }
+}
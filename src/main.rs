@@ -1,19 +1,84 @@
-use log::{info, warn};
-use dicom::object::open_file;
-use dicom_pixeldata::{PixelDecoder, DecodedPixelData};
+use clap::{Parser, Subcommand};
+use log::info;
 use std::path::Path;
-use env_logger;
-use image::{GrayImage, RgbaImage, ImageBuffer, Rgba, imageops, DynamicImage};
-use clap::Parser;
-use ndarray::Array2;
-use std::fs::File;
-use std::io::Read;
+use std::process::ExitCode;
+use std::str::FromStr;
+#[cfg(any(feature = "plugins", feature = "gpu"))]
+use std::sync::Arc;
+
+use rust_dl_heatmap_processing::batch::{run_batch, BatchSettings, BatchSummary, BATCH_FAILURES_FILE};
+#[cfg(feature = "dicomweb")]
+use rust_dl_heatmap_processing::batch::run_query_batch;
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::batch::run_inference_batch;
+#[cfg(feature = "dicomweb")]
+use rust_dl_heatmap_processing::config::DicomWebConfig;
+#[cfg(feature = "dimse")]
+use rust_dl_heatmap_processing::config::DimseConfig;
+#[cfg(feature = "amqp")]
+use rust_dl_heatmap_processing::config::AmqpConfig;
+#[cfg(feature = "kafka")]
+use rust_dl_heatmap_processing::config::KafkaConfig;
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::config::ModelRegistryConfig;
+#[cfg(any(feature = "server", feature = "service"))]
+use rust_dl_heatmap_processing::config::OrchestrateConfig;
+#[cfg(feature = "dicomweb")]
+use rust_dl_heatmap_processing::dicomweb::{DicomWebClient, InstanceRef};
+use rust_dl_heatmap_processing::dicom_io::{decode_dicom_frame, image_dimensions, open_dicom, open_dicom_bytes};
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::dicom_io::decode_dicom_pixel_data;
+#[cfg(feature = "dimse")]
+use rust_dl_heatmap_processing::dimse;
+#[cfg(feature = "service")]
+use rust_dl_heatmap_processing::fanout::FanoutMode;
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::heatmap::ClassSelector;
+use rust_dl_heatmap_processing::audit::AuditLog;
+use rust_dl_heatmap_processing::calibration::{save_calibration_plot, DEFAULT_CALIBRATION_BINS};
+use rust_dl_heatmap_processing::coverage::{CoverageOptions, DEFAULT_MAX_OUTSIDE_FRACTION};
+use rust_dl_heatmap_processing::decision::OperatingPoints;
+use rust_dl_heatmap_processing::histogram::{HistogramOptions, HistogramStage, DEFAULT_HISTOGRAM_BINS};
+use rust_dl_heatmap_processing::hotspots::HotspotOptions;
+use rust_dl_heatmap_processing::localization::{PointingOptions, DEFAULT_POINTING_TOLERANCE};
+use rust_dl_heatmap_processing::pipeline::HeatmapPipelineBuilder;
+use rust_dl_heatmap_processing::projection::Plane;
+use rust_dl_heatmap_processing::quality::QualityOptions;
+use rust_dl_heatmap_processing::regions::RegionMask;
+use rust_dl_heatmap_processing::series::{load_series, DicomSeries};
+use rust_dl_heatmap_processing::slices::{load_heatmap_volume, SliceRange, TOP_SLICES};
+use rust_dl_heatmap_processing::sweep::SweepOptions;
+use rust_dl_heatmap_processing::validation::{validate, ValidationOptions};
+use rust_dl_heatmap_processing::heatmap::{FusionMethod, HeatmapRegistry};
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::cam::CamMethod;
+#[cfg(feature = "gpu")]
+use rust_dl_heatmap_processing::gpu::{Backend, GpuRenderer};
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::inference::{CamOptions, ExecutionProvider, InferenceOptions, OnnxModel, ScoreActivation};
+#[cfg(feature = "plugins")]
+use rust_dl_heatmap_processing::plugin::{HeatmapPlugin, DEFAULT_PLUGIN_FUEL};
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::quantization::parse_quantization;
+#[cfg(feature = "onnx")]
+use rust_dl_heatmap_processing::tta::Augmentation;
+#[cfg(feature = "server")]
+use rust_dl_heatmap_processing::server;
+use rust_dl_heatmap_processing::output::{PngCompression, PngFilter, PngOptions};
+use rust_dl_heatmap_processing::tiled::{save_tiled, TileOptions};
+use rust_dl_heatmap_processing::{
+    BlendMode, BlendOptions, ColorMap, DemoOptions, DemoPattern, Error, HeatmapInput, HeatmapPipeline, ImageSource,
+    Normalization, OutputTarget, PipelineResult, PipelineSpec, Result, VolumeResult,
+};
 
 #[derive(Parser)]
 #[command(name = "rust-dl-heatmap-processing")]
 #[command(about = "A DICOM heatmap processing tool with ML model integration")]
 #[command(version)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    
     /// Input DICOM file path
     #[arg(short, long, default_value = "sample.dcm")]
     input: String,
@@ -22,11 +87,24 @@ struct Args {
     #[arg(short, long, default_value = "output.png")]
     output: String,
     
-    /// Heatmap data file (.npy, .json, .csv, or .bin)
+    /// Heatmap data file (.npy, .json, .csv, or .bin); several (comma-separated) are fused into one
+    #[arg(long, value_delimiter = ',')]
+    heatmap: Vec<String>,
+    
+    /// Render the difference of the heatmap from this baseline heatmap of the same image (e.g. of
+    /// the previous model version) in a diverging colormap, with their correlation and SSIM
     #[arg(long)]
-    heatmap: Option<String>,
+    baseline_heatmap: Option<String>,
     
-    /// Color scheme for heatmap (red, hot, jet, viridis, plasma)
+    /// Weights of the --heatmap files for weighted fusion, in order (default: 1.0 each)
+    #[arg(long, value_delimiter = ',')]
+    heatmap_weights: Vec<f32>,
+    
+    /// How several heatmaps or fused services are combined (mean, max, weighted)
+    #[arg(long, default_value = "mean")]
+    fusion: String,
+    
+    /// Color scheme for heatmap (red, hot, jet, viridis, plasma, coolwarm)
     #[arg(long, default_value = "red")]
     colormap: String,
     
@@ -38,78 +116,626 @@ struct Args {
     #[arg(long, default_value = "minmax")]
     normalization: String,
     
+    /// Blend mode for the overlay (alpha, additive, screen)
+    #[arg(long, default_value = "alpha")]
+    blend: String,
+    
+    /// Hide heatmap values below this normalized level (0.0 to 1.0)
+    #[arg(long)]
+    threshold: Option<f32>,
+    
     /// Use demo mode with simulated data
     #[arg(short, long)]
     demo: bool,
+    
+    /// Synthetic demo pattern (gradient, blobs, phantom, noise)
+    #[arg(long, default_value = "gradient")]
+    pattern: String,
+    
+    /// Seed for reproducible demo data
+    #[arg(long, default_value = "0")]
+    seed: u64,
+    
+    /// Demo image width in pixels
+    #[arg(long, default_value = "512")]
+    width: u32,
+    
+    /// Demo image height in pixels
+    #[arg(long, default_value = "512")]
+    height: u32,
+    
+    /// Render in tiles of this many pixels, holding one row of tiles at a time, for images too
+    /// large to render whole (DICOM input with at most one --heatmap file)
+    #[arg(long)]
+    tile_size: Option<u32>,
+    
+    /// PNG compression of the fused images (none, fast, balanced, high, or a zlib level 1-9)
+    #[arg(long, default_value = "fast")]
+    png_compression: String,
+    
+    /// PNG row filter of the fused images (none, sub, up, avg, paeth, adaptive)
+    #[arg(long, default_value = "adaptive")]
+    png_filter: String,
+    
+    /// Frame of a multi-frame DICOM to render, from 0
+    #[arg(long, default_value = "0")]
+    frame: u32,
+    
+    /// Render every frame of a multi-frame DICOM, in parallel, to <output>_frame0000.png and so on
+    #[arg(long)]
+    all_frames: bool,
+    
+    /// Render only these frames of --all-frames or slices of --heatmap-volume, as START..END with
+    /// END excluded, START..=END or a single index, from 0, with an optional :STEP, e.g.
+    /// 40..120:2; a bound left out is the first or last slice
+    #[arg(long)]
+    slices: Option<String>,
+    
+    /// 3-D heatmap (.npy, .nii, .nii.gz or .json) with one slice per frame of a multi-frame DICOM,
+    /// or per instance of --input-series: every frame is rendered with its slice, as with
+    /// --all-frames, and the most suspicious slices are logged
+    #[arg(long)]
+    heatmap_volume: Option<String>,
+    
+    /// Write the statistics of each slice of the --heatmap-volume and their ranking by activation
+    /// to <output>.slices.json or .csv (json, csv)
+    #[arg(long)]
+    slice_profile: Option<String>,
+    
+    /// Render the maximum intensity projection of the frames or the --input-series across this
+    /// plane (axial, coronal, sagittal), with that of the --heatmap-volume, to <output> in place
+    /// of the slices
+    #[arg(long)]
+    mip: Option<String>,
+    
+    /// Render the axial, coronal and sagittal planes of the frames or the --input-series through
+    /// the peak of the --heatmap-volume, each with the same plane of the heatmap, to
+    /// <output>_axial.png, <output>_coronal.png and <output>_sagittal.png in place of the slices
+    #[arg(long)]
+    reformats: bool,
+    
+    /// Directory of a CT or MR series, one instance per slice, rendered slice by slice in the
+    /// order of its patient geometry with the slices of --heatmap-volume, to
+    /// <output>_slice0000.png and so on
+    #[arg(long)]
+    input_series: Option<String>,
+    
+    /// SeriesInstanceUID of the series to render when the --input-series directory holds several
+    #[arg(long)]
+    input_series_uid: Option<String>,
+    
+    /// Also write the fused slices of --input-series as one DICOM Secondary Capture series to this
+    /// directory
+    #[cfg(feature = "dimse")]
+    #[arg(long)]
+    series_output: Option<String>,
+    
+    /// Process every DICOM file in this directory (batch mode)
+    #[arg(long)]
+    input_dir: Option<String>,
+    
+    /// Output directory for batch results, progress log and failures report
+    #[arg(long, default_value = "batch_output")]
+    output_dir: String,
+    
+    /// Directory with per-item heatmaps named after the DICOM file stem
+    #[arg(long)]
+    heatmap_dir: Option<String>,
+    
+    /// Skip batch items already recorded as successful in results.jsonl
+    #[arg(long)]
+    resume: bool,
+    
+    /// Pipeline spec (.json, .toml or a previous sidecar) overriding the processing options
+    #[arg(long)]
+    spec: Option<String>,
+    
+    /// Write a JSON sidecar with the pipeline spec next to the output PNG
+    #[arg(long)]
+    sidecar: bool,
+    
+    /// Append an audit record of each rendering (operator, time, SHA-256 of the inputs and
+    /// outputs, models and parameters) to this JSONL file
+    #[arg(long)]
+    audit_log: Option<String>,
+    
+    /// Operator recorded in the audit records (default: the USER running the tool)
+    #[arg(long)]
+    audit_user: Option<String>,
+    
+    /// Call the heatmap's class positive at these score thresholds, e.g. 0.5 or tuberculosis=0.42,pneumonia=0.6
+    #[arg(long)]
+    operating_points: Option<String>,
+    
+    /// Score the heatmap, thresholded at --threshold or else 0.5, against this ground-truth mask
+    /// (.png or .npy) with Dice, IoU, sensitivity and specificity, written to the sidecar
+    #[arg(long)]
+    gt_mask: Option<String>,
+    
+    /// Summarize the heatmap within each labeled region of this mask (.png or .npy label map), in
+    /// the sidecar and decision report; name labels with FILE:1=right lung,2=left lung; repeatable
+    #[arg(long)]
+    region_mask: Vec<String>,
+    
+    /// Score whether the heatmap maximum falls on one of the lesions (points or boxes) annotated
+    /// in this JSON file, the pointing game, written to the sidecar
+    #[arg(long)]
+    lesions: Option<String>,
+    
+    /// Distance in pixels within which a maximum next to a lesion still counts as a hit
+    #[arg(long, default_value_t = DEFAULT_POINTING_TOLERANCE)]
+    pointing_tolerance: f64,
+    
+    /// Warn when much of the heatmap's activation at or above --threshold (or else 0.5) falls
+    /// outside this body or lung mask (.png or .npy), as for misregistered heatmaps
+    #[arg(long)]
+    anatomy_mask: Option<String>,
+    
+    /// Share of the activation outside --anatomy-mask above which the heatmap is reported
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTSIDE_FRACTION)]
+    max_outside_activation: f64,
+    
+    /// Fail instead of warning when the share outside --anatomy-mask is exceeded
+    #[arg(long)]
+    strict_coverage: bool,
+    
+    /// Detect the connected regions of the normalized heatmap at or above --threshold (or else
+    /// 0.5) and write their centroids, bounding boxes, peaks and areas to <output>.hotspots.json
+    #[arg(long)]
+    hotspots: bool,
+    
+    /// Smallest hotspot kept, in pixels
+    #[arg(long, default_value_t = 1)]
+    hotspot_min_area: u64,
+    
+    /// Keep only this many hotspots, highest peaks first
+    #[arg(long)]
+    max_hotspots: Option<usize>,
+    
+    /// Write the histogram of the heatmap values to <output>.histogram.json or .csv (json, csv)
+    #[arg(long)]
+    histogram: Option<String>,
+    
+    /// Bins of the heatmap histogram
+    #[arg(long, default_value_t = DEFAULT_HISTOGRAM_BINS)]
+    histogram_bins: usize,
+    
+    /// Values the histogram is taken of: raw (as loaded) or normalized
+    #[arg(long, default_value = "raw")]
+    histogram_stage: String,
+    
+    /// Draw the heatmap histogram in the bottom-right corner of the output PNG
+    #[arg(long)]
+    histogram_panel: bool,
+    
+    /// Threshold the normalized heatmap at each of the --sweep-levels and write the area above
+    /// each level, its regions of at least --hotspot-min-area pixels and, with --gt-mask, its
+    /// Dice score to <output>.sweep.json or .csv (json, csv)
+    #[arg(long)]
+    threshold_sweep: Option<String>,
+    
+    /// Levels of the threshold sweep as START:END:STEP
+    #[arg(long, default_value = "0.1:0.9:0.1")]
+    sweep_levels: String,
+    
+    /// Check the input for blank or constant pixel data, extreme exposure, missing critical
+    /// tags, a suspect aspect ratio and burned-in annotations, logged and written to the sidecar
+    #[arg(long)]
+    qc: bool,
+    
+    /// Like --qc, but fail without rendering when a check fails
+    #[arg(long)]
+    qc_gate: bool,
+    
+    /// Also write the decision and region statistics as a DICOM Enhanced SR next to the output
+    /// PNG (<output>.sr.dcm)
+    #[cfg(feature = "dimse")]
+    #[arg(long)]
+    report: bool,
+    
+    /// WebAssembly module (.wasm or .wat) post-processing the normalized heatmap and its scores
+    /// before rendering
+    #[cfg(feature = "plugins")]
+    #[arg(long)]
+    plugin: Option<String>,
+    
+    /// Fuel the plugin gets per heatmap, roughly the instructions it may execute
+    #[cfg(feature = "plugins")]
+    #[arg(long, default_value_t = DEFAULT_PLUGIN_FUEL)]
+    plugin_fuel: u64,
+    
+    /// Where heatmaps are resized, normalized, colorized and blended (cpu, gpu)
+    #[cfg(feature = "gpu")]
+    #[arg(long, default_value = "cpu")]
+    backend: String,
+    
+    /// Fetch heatmaps from these configured DL services (comma-separated) instead of --heatmap
+    #[cfg(feature = "service")]
+    #[arg(long, value_delimiter = ',')]
+    service: Vec<String>,
+    
+    /// Render several services as one image per service, as one combined image or as one fused
+    /// heatmap weighted by the services' weights (separate, combined, fused)
+    #[cfg(feature = "service")]
+    #[arg(long, default_value = "separate")]
+    fanout: String,
+    
+    /// DICOMweb base URL to retrieve the input from with WADO-RS, overriding DICOMWEB_URL
+    #[cfg(feature = "dicomweb")]
+    #[arg(long)]
+    wado_url: Option<String>,
+    
+    /// Study Instance UID of the instance to retrieve instead of --input
+    #[cfg(feature = "dicomweb")]
+    #[arg(long)]
+    study_uid: Option<String>,
+    
+    /// Series Instance UID of the instance to retrieve
+    #[cfg(feature = "dicomweb")]
+    #[arg(long)]
+    series_uid: Option<String>,
+    
+    /// SOP Instance UID of the instance to retrieve
+    #[cfg(feature = "dicomweb")]
+    #[arg(long)]
+    instance_uid: Option<String>,
+    
+    /// Process every instance matching this QIDO-RS query (batch mode), e.g. Modality=CR&StudyDate=today
+    #[cfg(feature = "dicomweb")]
+    #[arg(long)]
+    qido: Option<String>,
+    
+    /// Also send every batch or listener result as Secondary Capture to these destinations
+    /// from DIMSE_DESTINATIONS (comma-separated)
+    #[cfg(feature = "dimse")]
+    #[arg(long, value_delimiter = ',')]
+    store_to: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
-pub enum ColorMap {
-    Red,
-    Hot,
-    Jet,
-    Viridis,
-    Plasma,
+#[derive(Subcommand)]
+enum Command {
+    /// Run as an HTTP processing service (POST /process)
+    #[cfg(feature = "server")]
+    Serve {
+        /// Interface to bind, overriding ORCHESTRATE_HOST
+        #[arg(long)]
+        host: Option<String>,
+        
+        /// Port to listen on, overriding ORCHESTRATE_PORT
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    
+    /// Process requests from a Kafka topic or an AMQP queue and publish result events
+    #[cfg(any(feature = "kafka", feature = "amqp"))]
+    Worker {
+        /// Queue to consume: kafka or amqp; defaults to the one configured with KAFKA_BROKERS or AMQP_URL
+        #[arg(long)]
+        transport: Option<String>,
+    },
+    
+    /// Accept DICOM C-STORE associations and process every received instance into --output-dir
+    #[cfg(feature = "dimse")]
+    Listen {
+        #[command(flatten)]
+        listener: ListenerArgs,
+    },
+    
+    /// Find studies on a PACS with C-FIND, C-MOVE them to the listener and process their instances
+    #[cfg(feature = "dimse")]
+    Pull {
+        /// Destination from DIMSE_DESTINATIONS to query and move from
+        #[arg(long)]
+        from: String,
+        
+        /// Study-level C-FIND query, e.g. PatientID=12345&ModalitiesInStudy=CR
+        #[arg(long)]
+        find: String,
+        
+        #[command(flatten)]
+        listener: ListenerArgs,
+    },
+    
+    /// Run an ONNX classifier on --input locally, print its class scores and render its heatmap to --output
+    #[cfg(feature = "onnx")]
+    Infer {
+        #[command(flatten)]
+        model: Box<ModelArgs>,
+    },
+    
+    /// Compute the ROC curve, AUC, operating-point metrics and calibration of the class scores of
+    /// a labeled manifest, and the pointing-game hit rate of its cases with lesion annotations,
+    /// and write them to a metrics report
+    Evaluate {
+        #[command(flatten)]
+        evaluate: EvaluateArgs,
+    },
 }
 
-impl ColorMap {
-    fn from_str(s: &str) -> Result<Self, String> {
-        match s.to_lowercase().as_str() {
-            "red" => Ok(ColorMap::Red),
-            "hot" => Ok(ColorMap::Hot),
-            "jet" => Ok(ColorMap::Jet),
-            "viridis" => Ok(ColorMap::Viridis),
-            "plasma" => Ok(ColorMap::Plasma),
-            _ => Err(format!("Unknown colormap: {}. Available: red, hot, jet, viridis, plasma", s)),
-        }
-    }
+/// Manifest and report options of `evaluate`
+#[derive(clap::Args)]
+struct EvaluateArgs {
+    /// CSV manifest with a header naming its image, heatmap, score, label and annotations
+    /// columns; rows without a score take the score recorded in their heatmap file
+    #[arg(long)]
+    manifest: String,
+    
+    /// Class whose scores are evaluated, from the heatmaps' per-class scores; defaults to
+    /// their own score
+    #[arg(long)]
+    class: Option<String>,
+    
+    /// Also report the metrics at the class's threshold, e.g. 0.5 or tuberculosis=0.42
+    #[arg(long)]
+    operating_points: Option<String>,
+    
+    /// Distance in pixels within which the heatmap maximum of a case with lesion annotations
+    /// still hits a lesion
+    #[arg(long, default_value_t = DEFAULT_POINTING_TOLERANCE)]
+    pointing_tolerance: f64,
+    
+    /// Equal-width score bins of the reliability diagram
+    #[arg(long, default_value_t = DEFAULT_CALIBRATION_BINS)]
+    calibration_bins: usize,
+    
+    /// Also render the reliability diagram to this PNG
+    #[arg(long)]
+    calibration_plot: Option<String>,
+    
+    /// Metrics report to write (JSON)
+    #[arg(long, default_value = "metrics.json")]
+    metrics: String,
+}
+
+/// ONNX model options of `infer`
+#[cfg(feature = "onnx")]
+#[derive(clap::Args)]
+struct ModelArgs {
+    /// ONNX model file, or the name of a model in ORCHESTRATE_MODELS
+    #[arg(long)]
+    model: String,
+    
+    /// Class labels in score order (comma-separated), overriding the model's `labels` metadata
+    #[arg(long, value_delimiter = ',')]
+    labels: Vec<String>,
+    
+    /// Model output with the class scores; defaults to the first output of shape [batch, classes]
+    #[arg(long)]
+    scores_output: Option<String>,
+    
+    /// Model output with the heatmap; defaults to the first output with spatial dimensions
+    #[arg(long)]
+    heatmap_output: Option<String>,
+    
+    /// Activation applied to the raw scores (none, sigmoid, softmax)
+    #[arg(long, default_value = "none")]
+    activation: String,
+    
+    /// Class whose heatmap is drawn, by index or by label (from --labels or the model metadata),
+    /// or top for the top-scoring class of each image
+    #[arg(long, default_value = "top")]
+    target_class: String,
+    
+    /// Execution providers in order of preference (cpu, cuda, tensorrt, directml, coreml); those
+    /// unavailable are skipped with a warning
+    #[arg(long, value_delimiter = ',')]
+    provider: Vec<String>,
+    
+    /// GPU the execution providers run on
+    #[arg(long, default_value = "0")]
+    device: u32,
+    
+    /// Images decoded and run together with --input-dir, split further if the model fixes its batch size
+    #[arg(long, default_value = "8")]
+    batch_size: usize,
+    
+    /// Compute the heatmap from the model's activations and gradients outputs instead (gradcam, gradcam++)
+    #[arg(long)]
+    cam: Option<String>,
+    
+    /// Model output with the final convolutional layer activations for --cam
+    #[arg(long, default_value = "activations")]
+    activations_output: String,
+    
+    /// Model output with the gradients of the target class score for --cam
+    #[arg(long, default_value = "gradients")]
+    gradients_output: String,
+    
+    /// Model input taking the target class as a one-hot vector for --cam; defaults to the second input
+    #[arg(long)]
+    target_input: Option<String>,
+    
+    /// Preprocessing the model was trained with, e.g. "size=512x512;resize=letterbox;mean=0.5;std=0.25",
+    /// overriding the model's `preprocess` metadata
+    #[arg(long)]
+    preprocess: Option<String>,
+    
+    /// Compute the heatmap from score drops while patches of the image are masked, for models
+    /// without a heatmap output or gradients: "default", or e.g. "patch=64,stride=32,fill=0"
+    #[arg(long)]
+    occlusion: Option<String>,
+    
+    /// Test-time augmentations also run and averaged with each image (hflip, rotate=<degrees>),
+    /// e.g. "hflip,rotate=5,rotate=-5"
+    #[arg(long, value_delimiter = ',')]
+    tta: Vec<String>,
+    
+    /// Scale and zero point of the int8 or uint8 tensors of a quantized model, e.g.
+    /// "input=0.003921:0;heatmap=0.0078:128", overriding its `quantization` metadata
+    #[arg(long)]
+    quantization: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-pub enum Normalization {
-    MinMax,
-    ZScore,
-    Percentile,
+/// DIMSE listener options shared by `listen` and `pull`
+#[cfg(feature = "dimse")]
+#[derive(clap::Args)]
+struct ListenerArgs {
+    /// AE title callers must address, overriding DIMSE_AE_TITLE
+    #[arg(long)]
+    ae_title: Option<String>,
+    
+    /// Interface to bind, overriding DIMSE_HOST
+    #[arg(long)]
+    host: Option<String>,
+    
+    /// Port to listen on, overriding DIMSE_PORT
+    #[arg(long)]
+    port: Option<u16>,
 }
 
-impl Normalization {
-    fn from_str(s: &str) -> Result<Self, String> {
-        match s.to_lowercase().as_str() {
-            "minmax" => Ok(Normalization::MinMax),
-            "zscore" => Ok(Normalization::ZScore),
-            "percentile" => Ok(Normalization::Percentile),
-            _ => Err(format!("Unknown normalization: {}. Available: minmax, zscore, percentile", s)),
+fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    match run(Args::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
         }
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-
-    let args = Args::parse();
+fn run(mut args: Args) -> Result<ExitCode> {
+    if let Some(command) = args.command.take() {
+        return run_command(command, &args);
+    }
+    
     let dicom_path = Path::new(&args.input);
     let png_path = Path::new(&args.output);
 
     // Parse colormap and normalization options
-    let colormap = ColorMap::from_str(&args.colormap)?;
-    let normalization = Normalization::from_str(&args.normalization)?;
+    let colormap = ColorMap::from_str(&args.colormap).map_err(Error::InvalidOption)?;
+    let normalization = Normalization::from_str(&args.normalization).map_err(Error::InvalidOption)?;
+    
+    let pattern = DemoPattern::from_str(&args.pattern).map_err(Error::InvalidOption)?;
+    
+    let blend_mode = BlendMode::from_str(&args.blend).map_err(Error::InvalidOption)?;
+    
+    let fusion = FusionMethod::from_str(&args.fusion).map_err(Error::InvalidOption)?;
+    let heatmap = heatmap_input(&args.heatmap, &args.heatmap_weights, fusion)?;
+    #[cfg(feature = "service")]
+    let fanout = match FanoutMode::from_str(&args.fanout).map_err(Error::InvalidOption)? {
+        FanoutMode::Fused(_) => FanoutMode::Fused(fusion),
+        mode => mode,
+    };
+    #[cfg(feature = "service")]
+    let mut services = Vec::new();
     
     // Validate opacity range
     if args.opacity < 0.0 || args.opacity > 1.0 {
-        return Err("Opacity must be between 0.0 and 1.0".into());
+        return Err(Error::InvalidOption("Opacity must be between 0.0 and 1.0".to_string()));
     }
+    
+    // Validate demo dimensions
+    if args.width == 0 || args.height == 0 {
+        return Err(Error::InvalidOption("Demo width and height must be greater than 0".to_string()));
+    }
+    
+    let demo_options = DemoOptions {
+        width: args.width,
+        height: args.height,
+        seed: args.seed,
+        pattern,
+    };
 
-    // Force demo mode if requested
-    if args.demo {
-        info!("Demo mode requested - creating heatmap with simulated data");
-        let rows = 512u32;
-        let columns = 512u32;
-        create_demo_heatmap(rows, columns, png_path, &colormap, args.opacity)?;
-        return Ok(());
+    // Batch mode processes a whole directory or query result and reports per-item failures
+    #[cfg(feature = "dicomweb")]
+    let query = args.qido.as_deref();
+    #[cfg(not(feature = "dicomweb"))]
+    let query: Option<&str> = None;
+    if args.input_dir.is_some() || query.is_some() {
+        let settings = batch_settings(&args)?;
+        let summary = match (&args.input_dir, query) {
+            (Some(_), Some(_)) => return Err(Error::InvalidOption("--input-dir and --qido can't be combined".to_string())),
+            (Some(input_dir), None) => run_batch(Path::new(input_dir), &settings)?,
+            (None, _) => run_query(&args, &settings)?,
+        };
+        return Ok(batch_exit_code(&summary, &settings));
+    }
+
+    #[cfg(feature = "dimse")]
+    if !args.store_to.is_empty() {
+        return Err(Error::InvalidOption("--store-to needs --input-dir, --qido or the listen or pull subcommand".to_string()));
+    }
+
+    let blend = BlendOptions { opacity: args.opacity, mode: blend_mode, threshold: args.threshold };
+    if let Some(tile_size) = args.tile_size {
+        return run_tiled(&args, TileOptions { tile_size, colormap, normalization, blend, png: png_options(&args)? });
+    }
+
+    let mut builder = HeatmapPipeline::builder()
+        .frame(args.frame)
+        .colormap(colormap)
+        .normalization(normalization)
+        .blend(blend)
+        .output(OutputTarget::Png(png_path.to_path_buf()))
+        .png_options(png_options(&args)?)
+        .lenient(true);
+    if args.sidecar {
+        builder = builder.output(OutputTarget::Sidecar(png_path.with_extension("json")));
+    }
+    builder = with_audit_log(builder, &args);
+    builder = analysis_outputs(builder, &args, png_path)?;
+    builder = decision_outputs(builder, &args, png_path)?;
+    let series = input_series(&args)?;
+    let mip = volume_views(&args)?;
+    if let Some(slices) = &args.slices {
+        if !args.all_frames && args.heatmap_volume.is_none() {
+            return Err(Error::InvalidOption("--slices needs --all-frames or --heatmap-volume".to_string()));
+        }
+        builder = builder.slices(SliceRange::from_str(slices).map_err(|e| Error::InvalidOption(format!("Invalid --slices: {}", e)))?);
+    }
+    #[cfg(feature = "dimse")]
+    if let Some(dir) = &args.series_output {
+        let dir = Path::new(dir);
+        std::fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+        let name = png_path.with_extension("dcm").file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        builder = builder.output(OutputTarget::SecondaryCapture(dir.join(name)));
+    }
+    #[cfg(feature = "plugins")]
+    {
+        builder = with_plugin(builder, &args)?;
+    }
+    #[cfg(feature = "gpu")]
+    {
+        builder = with_backend(builder, &args)?;
     }
 
-    // Check if DICOM file exists
-    if !dicom_path.exists() {
+    // UIDs name an instance on the DICOMweb server in place of --input
+    #[cfg(feature = "dicomweb")]
+    let retrieved = retrieve_instance(&args)?;
+    #[cfg(not(feature = "dicomweb"))]
+    let retrieved: Option<Vec<u8>> = None;
+
+    // A spec replays a previous rendering; its recorded source wins over --input/--demo
+    let spec = args.spec.as_ref().map(|path| PipelineSpec::load(Path::new(path))).transpose()?;
+    if let Some(spec) = &spec {
+        info!("Using pipeline spec: {}", args.spec.as_deref().unwrap_or_default());
+        builder = builder.spec(spec);
+        // The recorded plugin is loaded again unless --plugin names one, which has to match it
+        #[cfg(feature = "plugins")]
+        if args.plugin.is_none()
+            && let Some(path) = spec.plugin.as_ref().and_then(|plugin| plugin.path.as_ref())
+        {
+            let plugin = HeatmapPlugin::load(path)?.with_fuel(args.plugin_fuel);
+            info!("Post-processing heatmaps with recorded plugin {} (SHA-256 {})", plugin.name(), plugin.sha256());
+            builder = builder.plugin(Arc::new(plugin));
+        }
+    }
+
+    if spec.as_ref().is_some_and(|spec| spec.source.is_some()) {
+        if let Some(heatmap) = heatmap.clone() {
+            builder = builder.heatmap(heatmap);
+        }
+    } else if let Some(series) = &series {
+        // Each slice replaces the source when the series is rendered
+        builder = builder.source(ImageSource::DicomFile(series.slices[0].path.clone()));
+    } else if args.demo {
+        // Force demo mode if requested
+        info!("Demo mode requested - creating heatmap with simulated data");
+        builder = builder.source(ImageSource::Demo(demo_options));
+    } else if retrieved.is_none() && !dicom_path.exists() {
         println!("DICOM file not found at: {}", dicom_path.display());
         println!("Please provide a valid DICOM file path using --input flag");
         println!("Or use --demo flag to create a demo heatmap with simulated data");
@@ -118,500 +744,777 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         
         // Create demo instead of failing
         info!("Falling back to demo mode...");
-        let rows = 512u32;
-        let columns = 512u32;
-        create_demo_heatmap(rows, columns, png_path, &colormap, args.opacity)?;
-        return Ok(());
+        builder = builder.source(ImageSource::Demo(demo_options));
+    } else {
+        builder = match retrieved {
+            Some(dicom) => builder.source(ImageSource::DicomBytes(dicom)),
+            None => builder.source(ImageSource::DicomFile(dicom_path.to_path_buf())),
+        };
+        if let Some(heatmap) = heatmap {
+            builder = builder.heatmap(heatmap);
+        }
+        #[cfg(feature = "service")]
+        if !args.service.is_empty() {
+            services = OrchestrateConfig::from_env()?.services(&args.service)?;
+        }
     }
-
-    let obj = open_file(&dicom_path)?;
-    
-    // Get basic image information
-    let rows = obj.element_by_name("Rows")?.to_int::<u32>()?;
-    let columns = obj.element_by_name("Columns")?.to_int::<u32>()?;
     
-    info!("DICOM image dimensions: {}x{}", columns, rows);
-    
-    // Load heatmap data if provided
-    let heatmap_data = if let Some(heatmap_path) = &args.heatmap {
-        match load_heatmap_data(heatmap_path) {
-            Ok(data) => {
-                info!("Successfully loaded heatmap data: {}x{}", data.nrows(), data.ncols());
-                Some(data)
-            }
-            Err(e) => {
-                warn!("Failed to load heatmap data: {}", e);
-                warn!("Proceeding without heatmap overlay");
-                None
-            }
+    let pipeline = builder.build()?;
+    #[cfg(feature = "service")]
+    if !services.is_empty() {
+        if args.all_frames || args.heatmap_volume.is_some() {
+            return Err(Error::InvalidOption("--all-frames and --heatmap-volume aren't supported with --service".to_string()));
         }
-    } else {
-        None
-    };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Server(format!("Failed to start async runtime: {}", e)))?;
+        for (name, result) in runtime.block_on(pipeline.run_fanout(&services, fanout))? {
+            log_result(&result, &format!(" from {}", name));
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
     
-    // Try to decode real DICOM pixel data
-    match decode_dicom_pixel_data(&obj, rows, columns) {
-        Ok(base_image) => {
-            info!("Successfully decoded DICOM pixel data");
-            create_heatmap_with_real_data(
-                base_image, 
-                png_path, 
-                heatmap_data, 
-                &colormap, 
-                &normalization, 
-                args.opacity
-            )?;
+    if let Some(volume) = &args.heatmap_volume {
+        if !args.heatmap.is_empty() {
+            return Err(Error::InvalidOption("--heatmap and --heatmap-volume can't be combined".to_string()));
         }
-        Err(e) => {
-            warn!("Failed to decode DICOM pixel data: {}", e);
-            warn!("Falling back to simulated data");
-            create_demo_heatmap(rows, columns, png_path, &colormap, args.opacity)?;
+        if args.frame > 0 {
+            return Err(Error::InvalidOption("--frame isn't supported with --heatmap-volume".to_string()));
+        }
+        let volume = load_heatmap_volume(Path::new(volume))?;
+        if let Some(plane) = mip {
+            let result = match &series {
+                Some(series) => pipeline.run_series_mip(series, &volume, plane)?,
+                None => pipeline.run_volume_mip(&volume, plane)?,
+            };
+            log_result(&result, "");
+            return Ok(ExitCode::SUCCESS);
+        }
+        if args.reformats {
+            let result = match &series {
+                Some(series) => pipeline.run_series_reformats(series, &volume)?,
+                None => pipeline.run_volume_reformats(&volume)?,
+            };
+            for (_, result) in &result.planes {
+                log_result(result, "");
+            }
+            return Ok(ExitCode::SUCCESS);
         }
+        let result = match &series {
+            Some(series) => pipeline.run_series(series, &volume)?,
+            None => pipeline.run_volume(&volume)?,
+        };
+        for frame in &result.frames {
+            log_result(frame, "");
+        }
+        log_volume_result(&result, series.as_ref());
+        return Ok(ExitCode::SUCCESS);
     }
-    
-    Ok(())
-}
-
-fn decode_dicom_pixel_data(
-    obj: &dicom::object::FileDicomObject<dicom::object::InMemDicomObject>,
-    rows: u32,
-    columns: u32,
-) -> Result<RgbaImage, Box<dyn std::error::Error>> {
-    // Decode pixel data using dicom-pixeldata
-    let decoded_pixel_data = obj.decode_pixel_data()?;
-    
-    info!("Pixel data info: {} bits allocated, {} samples per pixel", 
-          decoded_pixel_data.bits_allocated(), 
-          decoded_pixel_data.samples_per_pixel());
-    
-    // Convert decoded pixel data to grayscale image
-    let gray_image = match decoded_pixel_data.samples_per_pixel() {
-        1 => {
-            // Grayscale image
-            convert_to_grayscale_image(&decoded_pixel_data, rows, columns)?
-        }
-        3 => {
-            // RGB image - convert to grayscale
-            let rgb_data = decoded_pixel_data.to_dynamic_image(0)?;
-            rgb_data.to_luma8()
-        }
-        _ => {
-            return Err(format!("Unsupported samples per pixel: {}", 
-                             decoded_pixel_data.samples_per_pixel()).into());
+    if args.all_frames {
+        for result in pipeline.run_frames()? {
+            log_result(&result, "");
         }
-    };
-    
-    // Convert grayscale to RGBA for overlay
-    let rgba_image = DynamicImage::ImageLuma8(gray_image).to_rgba8();
-    
-    Ok(rgba_image)
+        return Ok(ExitCode::SUCCESS);
+    }
+    log_result(&pipeline.run()?, "");
+    Ok(ExitCode::SUCCESS)
 }
 
-fn convert_to_grayscale_image(
-    decoded_data: &DecodedPixelData,
-    rows: u32,
-    columns: u32,
-) -> Result<GrayImage, Box<dyn std::error::Error>> {
-    // Handle different bit depths
-    match decoded_data.bits_allocated() {
-        8 => {
-            // 8-bit data
-            let pixel_data: Vec<u8> = decoded_data.to_vec()?;
-            GrayImage::from_raw(columns, rows, pixel_data)
-                .ok_or("Failed to create GrayImage from 8-bit DICOM data".into())
-        }
-        16 => {
-            // 16-bit data - need to scale to 8-bit
-            let pixel_data_u16: Vec<u16> = decoded_data.to_vec()?;
-            
-            // Apply basic windowing: scale to 8-bit range
-            // For medical images, proper windowing using Window Center/Width would be better
-            let min_val = *pixel_data_u16.iter().min().unwrap_or(&0) as f32;
-            let max_val = *pixel_data_u16.iter().max().unwrap_or(&0) as f32;
-            let range = if max_val > min_val { max_val - min_val } else { 1.0 };
-            
-            info!("16-bit data range: {} - {}", min_val, max_val);
-            
-            let pixel_data_u8: Vec<u8> = pixel_data_u16
-                .iter()
-                .map(|&val| {
-                    let normalized = ((val as f32 - min_val) / range) * 255.0;
-                    normalized.max(0.0).min(255.0) as u8
-                })
-                .collect();
-                
-            GrayImage::from_raw(columns, rows, pixel_data_u8)
-                .ok_or("Failed to create GrayImage from 16-bit DICOM data".into())
-        }
-        bits => {
-            Err(format!("Unsupported bit depth: {} bits", bits).into())
-        }
+/// Render the --input (or retrieved) DICOM in tiles of --tile-size pixels straight to the
+/// --output PNG, with the image decoded to grayscale once and released before rendering
+fn run_tiled(args: &Args, options: TileOptions) -> Result<ExitCode> {
+    let unsupported = [
+        (args.demo, "--demo"),
+        (args.spec.is_some(), "--spec"),
+        (args.sidecar, "--sidecar"),
+        (args.audit_log.is_some(), "--audit-log"),
+        (args.operating_points.is_some(), "--operating-points"),
+        (args.gt_mask.is_some(), "--gt-mask"),
+        (!args.region_mask.is_empty(), "--region-mask"),
+        (args.lesions.is_some(), "--lesions"),
+        (args.anatomy_mask.is_some(), "--anatomy-mask"),
+        (args.hotspots, "--hotspots"),
+        (args.histogram.is_some(), "--histogram"),
+        (args.histogram_panel, "--histogram-panel"),
+        (args.threshold_sweep.is_some(), "--threshold-sweep"),
+        (args.qc, "--qc"),
+        (args.qc_gate, "--qc-gate"),
+        (args.heatmap.len() > 1, "more than one --heatmap"),
+        (args.baseline_heatmap.is_some(), "--baseline-heatmap"),
+        (args.all_frames, "--all-frames"),
+        (args.slices.is_some(), "--slices"),
+        (args.heatmap_volume.is_some(), "--heatmap-volume"),
+        (args.slice_profile.is_some(), "--slice-profile"),
+        (args.input_series.is_some(), "--input-series"),
+        (args.mip.is_some(), "--mip"),
+        (args.reformats, "--reformats"),
+        #[cfg(feature = "dimse")]
+        (args.report, "--report"),
+        #[cfg(feature = "plugins")]
+        (args.plugin.is_some(), "--plugin"),
+        #[cfg(feature = "service")]
+        (!args.service.is_empty(), "--service"),
+        #[cfg(feature = "gpu")]
+        (Backend::from_str(&args.backend).map_err(Error::InvalidOption)? == Backend::Gpu, "--backend gpu"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+        return Err(Error::InvalidOption(format!("{} isn't supported with --tile-size", flag)));
     }
-}
 
-fn create_heatmap_with_real_data(
-    mut base_rgba_image: RgbaImage,
-    png_path: &Path,
-    heatmap_data: Option<Array2<f32>>,
-    colormap: &ColorMap,
-    normalization: &Normalization,
-    opacity: f32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (width, height) = base_rgba_image.dimensions();
-    
-    info!("Creating heatmap overlay on real DICOM data ({}x{})", width, height);
-    
-    let heatmap_rgba = if let Some(data) = heatmap_data {
-        // Use real heatmap data
-        info!("Using real heatmap data with {} colormap and {} normalization", 
-              format!("{:?}", colormap).to_lowercase(), 
-              format!("{:?}", normalization).to_lowercase());
-        
-        // Resize heatmap data to match image dimensions if needed
-        let resized_data = if data.nrows() != height as usize || data.ncols() != width as usize {
-            warn!("Heatmap dimensions ({}x{}) don't match image dimensions ({}x{}), resizing...", 
-                  data.nrows(), data.ncols(), height, width);
-            resize_heatmap(&data, width as usize, height as usize)
-        } else {
-            data
+    #[cfg(feature = "dicomweb")]
+    let retrieved = retrieve_instance(args)?;
+    #[cfg(not(feature = "dicomweb"))]
+    let retrieved: Option<Vec<u8>> = None;
+    let base = {
+        let obj = match retrieved {
+            Some(dicom) => open_dicom_bytes(&dicom)?,
+            None => open_dicom(Path::new(&args.input))?,
         };
-        
-        // Normalize the data
-        let normalized_data = normalize_heatmap(&resized_data, normalization);
-        
-        // Apply colormap
-        apply_colormap(&normalized_data, colormap, opacity)
-    } else {
-        // Generate default gradient heatmap
-        info!("No heatmap data provided, generating default gradient with {} colormap", 
-              format!("{:?}", colormap).to_lowercase());
-        generate_default_heatmap(width, height, colormap, opacity)
+        let (rows, columns) = image_dimensions(&obj)?;
+        decode_dicom_frame(&obj, rows, columns, args.frame)?
     };
+    let heatmap = args.heatmap.first()
+        .map(|path| HeatmapRegistry::default().load(Path::new(path)))
+        .transpose()?
+        .map(|heatmap| heatmap.data);
 
-    // Overlay the heatmap onto the base RGBA image
-    imageops::overlay(&mut base_rgba_image, &heatmap_rgba, 0, 0);
+    let png_path = Path::new(&args.output);
+    let render = save_tiled(&base, heatmap.as_ref(), &options, png_path)?;
+    info!("Successfully created {}x{} PNG with heatmap overlay in {} tile(s): {}",
+          render.width, render.height, render.tiles, png_path.display());
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Save the resulting image
-    base_rgba_image.save_with_format(png_path, image::ImageFormat::Png)?;
+/// PNG encoder settings from --png-compression and --png-filter
+fn png_options(args: &Args) -> Result<PngOptions> {
+    Ok(PngOptions {
+        compression: PngCompression::from_str(&args.png_compression).map_err(Error::InvalidOption)?,
+        filter: PngFilter::from_str(&args.png_filter).map_err(Error::InvalidOption)?,
+    })
+}
 
-    info!("Successfully created PNG with heatmap overlay on real DICOM data: {}", png_path.display());
-    
-    Ok(())
+/// The --audit-log, recording --audit-user as the operator if given
+fn with_audit_log(builder: HeatmapPipelineBuilder, args: &Args) -> HeatmapPipelineBuilder {
+    let Some(path) = &args.audit_log else {
+        return builder;
+    };
+    let log = AuditLog::new(path);
+    builder.audit_log(match &args.audit_user {
+        Some(user) => log.user(user),
+        None => log,
+    })
 }
 
-/// Resize heatmap data to match target dimensions using nearest neighbor interpolation
-fn resize_heatmap(data: &Array2<f32>, target_width: usize, target_height: usize) -> Array2<f32> {
-    let (src_height, src_width) = data.dim();
-    let mut resized = Array2::zeros((target_height, target_width));
-    
-    for row in 0..target_height {
-        for col in 0..target_width {
-            let src_row = ((row as f32 / target_height as f32) * src_height as f32) as usize;
-            let src_col = ((col as f32 / target_width as f32) * src_width as f32) as usize;
-            
-            let src_row = src_row.min(src_height - 1);
-            let src_col = src_col.min(src_width - 1);
-            
-            resized[[row, col]] = data[[src_row, src_col]];
+/// Quality control with --qc, the difference from --baseline-heatmap, ground-truth scoring with
+/// --gt-mask, region statistics with --region-mask, the pointing game with --lesions, anatomy
+/// coverage with --anatomy-mask and, with --hotspots, --histogram, --threshold-sweep and
+/// --slice-profile, the hotspots, the heatmap histogram, the threshold sweep and the slice
+/// profile next to `png_path`
+fn analysis_outputs(mut builder: HeatmapPipelineBuilder, args: &Args, png_path: &Path) -> Result<HeatmapPipelineBuilder> {
+    if args.qc || args.qc_gate {
+        builder = builder.quality_control(QualityOptions { gate: args.qc_gate });
+    }
+    if let Some(baseline) = &args.baseline_heatmap {
+        builder = builder.baseline(HeatmapInput::File(baseline.into()));
+    }
+    if let Some(mask) = &args.gt_mask {
+        builder = builder.ground_truth(mask);
+    }
+    for mask in &args.region_mask {
+        let mask = RegionMask::from_str(mask).map_err(|e| Error::InvalidOption(format!("Invalid --region-mask: {}", e)))?;
+        builder = builder.region_mask(mask);
+    }
+    if let Some(lesions) = &args.lesions {
+        builder = builder.pointing_game(PointingOptions::new(lesions).tolerance(args.pointing_tolerance));
+    }
+    if let Some(mask) = &args.anatomy_mask {
+        let options = CoverageOptions::new(mask).max_outside(args.max_outside_activation).strict(args.strict_coverage);
+        builder = builder.anatomy_coverage(options);
+    }
+    if args.hotspots {
+        let options = HotspotOptions { threshold: None, min_area: args.hotspot_min_area, max_hotspots: args.max_hotspots };
+        builder = builder.hotspots(options).output(OutputTarget::Hotspots(png_path.with_extension("hotspots.json")));
+    }
+    if args.histogram.is_some() || args.histogram_panel {
+        let stage = HistogramStage::from_str(&args.histogram_stage).map_err(Error::InvalidOption)?;
+        builder = builder.histogram(HistogramOptions { bins: args.histogram_bins, stage, panel: args.histogram_panel });
+    }
+    if let Some(format) = &args.histogram {
+        let format = format.to_lowercase();
+        if !matches!(format.as_str(), "json" | "csv") {
+            return Err(Error::InvalidOption(format!("Invalid --histogram format: {}. Supported: json, csv", format)));
         }
+        builder = builder.output(OutputTarget::Histogram(png_path.with_extension(format!("histogram.{}", format))));
     }
-    
-    resized
+    if let Some(format) = &args.threshold_sweep {
+        let format = format.to_lowercase();
+        if !matches!(format.as_str(), "json" | "csv") {
+            return Err(Error::InvalidOption(format!("Invalid --threshold-sweep format: {}. Supported: json, csv", format)));
+        }
+        let levels = SweepOptions::from_str(&args.sweep_levels).map_err(|e| Error::InvalidOption(format!("Invalid --sweep-levels: {}", e)))?;
+        builder = builder.threshold_sweep(SweepOptions { min_area: args.hotspot_min_area, ..levels })
+            .output(OutputTarget::ThresholdSweep(png_path.with_extension(format!("sweep.{}", format))));
+    }
+    if let Some(format) = &args.slice_profile {
+        let format = format.to_lowercase();
+        if !matches!(format.as_str(), "json" | "csv") {
+            return Err(Error::InvalidOption(format!("Invalid --slice-profile format: {}. Supported: json, csv", format)));
+        }
+        if args.heatmap_volume.is_none() {
+            return Err(Error::InvalidOption("--slice-profile needs --heatmap-volume".to_string()));
+        }
+        builder = builder.output(OutputTarget::SliceProfile(png_path.with_extension(format!("slices.{}", format))));
+    }
+    Ok(builder)
 }
 
-/// Generate default gradient heatmap when no real data is provided
-fn generate_default_heatmap(width: u32, height: u32, colormap: &ColorMap, opacity: f32) -> RgbaImage {
-    let mut heatmap_rgba = RgbaImage::new(width, height);
-    
-    for y in 0..height {
-        for x in 0..width {
-            // Simple gradient: intensity increases with x and y
-            let value = ((x as f32 / width as f32) + (y as f32 / height as f32)) / 2.0;
-            let color = get_color_from_value(value, colormap);
-            let alpha = (opacity * 255.0) as u8;
-            
-            heatmap_rgba.put_pixel(x, y, Rgba([color.0, color.1, color.2, alpha]));
+/// The --operating-points and, with --report, the decision report next to `png_path`
+#[cfg_attr(not(feature = "dimse"), allow(unused_variables))]
+fn decision_outputs(mut builder: HeatmapPipelineBuilder, args: &Args, png_path: &Path) -> Result<HeatmapPipelineBuilder> {
+    if let Some(points) = &args.operating_points {
+        let points = OperatingPoints::from_str(points).map_err(|e| Error::InvalidOption(format!("Invalid --operating-points: {}", e)))?;
+        builder = builder.operating_points(points);
+    }
+    #[cfg(feature = "dimse")]
+    if args.report {
+        builder = builder.output(OutputTarget::Report(png_path.with_extension("sr.dcm")));
+    }
+    Ok(builder)
+}
+
+/// The --input-series series, checked against the flags it needs and excludes
+fn input_series(args: &Args) -> Result<Option<DicomSeries>> {
+    #[cfg(feature = "dimse")]
+    if args.series_output.is_some() && args.input_series.is_none() {
+        return Err(Error::InvalidOption("--series-output needs --input-series".to_string()));
+    }
+    let Some(dir) = &args.input_series else {
+        if args.input_series_uid.is_some() {
+            return Err(Error::InvalidOption("--input-series-uid needs --input-series".to_string()));
         }
+        return Ok(None);
+    };
+    if args.heatmap_volume.is_none() {
+        return Err(Error::InvalidOption("--input-series needs --heatmap-volume".to_string()));
     }
-    
-    heatmap_rgba
+    if args.all_frames || args.spec.is_some() {
+        return Err(Error::InvalidOption("--input-series can't be combined with --all-frames or --spec".to_string()));
+    }
+    load_series(Path::new(dir), args.input_series_uid.as_deref()).map(Some)
 }
 
-/// Load heatmap data from various file formats
-fn load_heatmap_data(file_path: &str) -> Result<Array2<f32>, Box<dyn std::error::Error>> {
-    let path = Path::new(file_path);
-    let extension = path.extension()
-        .and_then(|ext| ext.to_str())
-        .ok_or("Could not determine file extension")?
-        .to_lowercase();
-    
-    info!("Loading heatmap data from: {} (format: {})", file_path, extension);
-    
-    match extension.as_str() {
-        "npy" => Err("NPY format support coming soon! Please use .json, .csv, or .bin format for now.".into()),
-        "json" => load_json_heatmap(file_path),
-        "csv" => load_csv_heatmap(file_path),
-        "bin" => load_binary_heatmap(file_path),
-        _ => Err(format!("Unsupported heatmap file format: {}. Supported: .json, .csv, .bin", extension).into()),
+/// The --mip plane, with --mip and --reformats checked against the flags they need and exclude
+fn volume_views(args: &Args) -> Result<Option<Plane>> {
+    let flag = match (&args.mip, args.reformats) {
+        (None, false) => return Ok(None),
+        (Some(_), true) => return Err(Error::InvalidOption("--mip and --reformats can't be combined".to_string())),
+        (Some(_), false) => "--mip",
+        (None, true) => "--reformats",
+    };
+    if args.heatmap_volume.is_none() {
+        return Err(Error::InvalidOption(format!("{} needs --heatmap-volume", flag)));
     }
+    if args.slice_profile.is_some() || args.spec.is_some() {
+        return Err(Error::InvalidOption(format!("{} can't be combined with --slice-profile or --spec", flag)));
+    }
+    #[cfg(feature = "dimse")]
+    if args.series_output.is_some() {
+        return Err(Error::InvalidOption(format!("{} can't be combined with --series-output", flag)));
+    }
+    args.mip.as_deref().map(Plane::from_str).transpose().map_err(|e| Error::InvalidOption(format!("Invalid --mip: {}", e)))
 }
 
-/// Load heatmap from .json file
-/// Expected format: {"data": [[1.0, 2.0], [3.0, 4.0]], "shape": [2, 2]}
-fn load_json_heatmap(file_path: &str) -> Result<Array2<f32>, Box<dyn std::error::Error>> {
-    let mut file = File::open(file_path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    
-    let parsed: serde_json::Value = serde_json::from_str(&contents)?;
-    
-    // Try to extract data as nested arrays
-    if let Some(data_array) = parsed.get("data").and_then(|v| v.as_array()) {
-        let mut flat_data = Vec::new();
-        let rows = data_array.len();
-        let mut cols = 0;
-        
-        for (i, row) in data_array.iter().enumerate() {
-            if let Some(row_array) = row.as_array() {
-                if i == 0 {
-                    cols = row_array.len();
-                }
-                for val in row_array {
-                    if let Some(num) = val.as_f64() {
-                        flat_data.push(num as f32);
-                    } else {
-                        return Err("JSON data must contain numeric values".into());
-                    }
-                }
-            } else {
-                return Err("JSON data must be array of arrays".into());
-            }
+/// Pipeline post-processing with the --plugin module, if one is given
+#[cfg(feature = "plugins")]
+fn with_plugin(builder: HeatmapPipelineBuilder, args: &Args) -> Result<HeatmapPipelineBuilder> {
+    let Some(path) = &args.plugin else {
+        return Ok(builder);
+    };
+    let plugin = HeatmapPlugin::load(Path::new(path))?.with_fuel(args.plugin_fuel);
+    info!("Post-processing heatmaps with plugin {} (SHA-256 {})", plugin.name(), plugin.sha256());
+    Ok(builder.plugin(Arc::new(plugin)))
+}
+
+/// Pipeline rendering on the GPU with --backend gpu
+#[cfg(feature = "gpu")]
+fn with_backend(builder: HeatmapPipelineBuilder, args: &Args) -> Result<HeatmapPipelineBuilder> {
+    match Backend::from_str(&args.backend).map_err(Error::InvalidOption)? {
+        Backend::Cpu => Ok(builder),
+        Backend::Gpu => {
+            let renderer = GpuRenderer::new()?;
+            info!("Rendering heatmaps on GPU adapter {}", renderer.adapter());
+            Ok(builder.gpu(Arc::new(renderer)))
         }
-        
-        Array2::from_shape_vec((rows, cols), flat_data)
-            .map_err(|e| e.into())
-    } else {
-        Err("JSON must contain 'data' field with array of arrays".into())
     }
 }
 
-/// Load heatmap from .csv file
-fn load_csv_heatmap(file_path: &str) -> Result<Array2<f32>, Box<dyn std::error::Error>> {
-    let mut reader = csv::Reader::from_path(file_path)?;
-    let mut data = Vec::new();
-    let mut rows = 0;
-    let mut cols = 0;
-    
-    for result in reader.records() {
-        let record = result?;
-        if rows == 0 {
-            cols = record.len();
-        }
-        
-        for field in &record {
-            let value: f32 = field.parse()
-                .map_err(|_| format!("Could not parse '{}' as number", field))?;
-            data.push(value);
+/// The --heatmap file, or an ensemble of the --heatmap files with their --heatmap-weights
+fn heatmap_input(paths: &[String], weights: &[f32], fusion: FusionMethod) -> Result<Option<HeatmapInput>> {
+    if !weights.is_empty() && weights.len() != paths.len() {
+        return Err(Error::InvalidOption(format!("--heatmap-weights gives {} weight(s) for {} heatmap(s)", weights.len(), paths.len())));
+    }
+    let members: Vec<(HeatmapInput, f32)> = paths.iter()
+        .enumerate()
+        .map(|(index, path)| (HeatmapInput::File(path.into()), weights.get(index).copied().unwrap_or(1.0)))
+        .collect();
+    Ok(match members.len() {
+        0 => None,
+        1 => members.into_iter().next().map(|(heatmap, _)| heatmap),
+        _ => Some(HeatmapInput::Ensemble { members, fusion }),
+    })
+}
+
+/// Fetch the instance named by --study-uid, --series-uid and --instance-uid, if given
+#[cfg(feature = "dicomweb")]
+fn retrieve_instance(args: &Args) -> Result<Option<Vec<u8>>> {
+    let instance = match (&args.study_uid, &args.series_uid, &args.instance_uid) {
+        (None, None, None) => return Ok(None),
+        (Some(study), Some(series), Some(instance)) => {
+            InstanceRef { study: study.clone(), series: series.clone(), instance: instance.clone() }
         }
-        rows += 1;
+        _ => return Err(Error::InvalidOption("--study-uid, --series-uid and --instance-uid must be given together".to_string())),
+    };
+    let config = DicomWebConfig::from_env_with_url(args.wado_url.as_deref())?
+        .ok_or_else(|| Error::InvalidOption("--instance-uid needs --wado-url or DICOMWEB_URL".to_string()))?;
+    let client = DicomWebClient::new(config)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::Server(format!("Failed to start async runtime: {}", e)))?;
+    runtime.block_on(client.retrieve(&instance)).map(Some)
+}
+
+/// Batch settings from --output-dir, --heatmap-dir, --resume and the rendering options
+fn batch_settings(args: &Args) -> Result<BatchSettings> {
+    #[cfg(feature = "plugins")]
+    if args.plugin.is_some() {
+        return Err(Error::InvalidOption("--plugin isn't supported in batch mode".to_string()));
     }
-    
-    if data.is_empty() {
-        return Err("CSV file is empty".into());
+    if args.tile_size.is_some() {
+        return Err(Error::InvalidOption("--tile-size isn't supported in batch mode".to_string()));
     }
-    
-    Array2::from_shape_vec((rows, cols), data)
-        .map_err(|e| e.into())
+    if args.audit_log.is_some() {
+        return Err(Error::InvalidOption("--audit-log isn't supported in batch mode".to_string()));
+    }
+    if args.all_frames || args.frame > 0 || args.slices.is_some() {
+        return Err(Error::InvalidOption("--frame, --all-frames and --slices aren't supported in batch mode".to_string()));
+    }
+    if args.heatmap_volume.is_some() || args.slice_profile.is_some() {
+        return Err(Error::InvalidOption("--heatmap-volume and --slice-profile aren't supported in batch mode".to_string()));
+    }
+    if args.input_series.is_some() {
+        return Err(Error::InvalidOption("--input-series isn't supported in batch mode".to_string()));
+    }
+    if args.mip.is_some() || args.reformats {
+        return Err(Error::InvalidOption("--mip and --reformats aren't supported in batch mode".to_string()));
+    }
+    if args.baseline_heatmap.is_some() {
+        return Err(Error::InvalidOption("--baseline-heatmap isn't supported in batch mode".to_string()));
+    }
+    if args.gt_mask.is_some() {
+        return Err(Error::InvalidOption("--gt-mask isn't supported in batch mode".to_string()));
+    }
+    if !args.region_mask.is_empty() {
+        return Err(Error::InvalidOption("--region-mask isn't supported in batch mode".to_string()));
+    }
+    if args.lesions.is_some() {
+        return Err(Error::InvalidOption("--lesions isn't supported in batch mode".to_string()));
+    }
+    if args.anatomy_mask.is_some() {
+        return Err(Error::InvalidOption("--anatomy-mask isn't supported in batch mode".to_string()));
+    }
+    if args.hotspots {
+        return Err(Error::InvalidOption("--hotspots isn't supported in batch mode".to_string()));
+    }
+    if args.histogram.is_some() || args.histogram_panel {
+        return Err(Error::InvalidOption("--histogram and --histogram-panel aren't supported in batch mode".to_string()));
+    }
+    if args.threshold_sweep.is_some() {
+        return Err(Error::InvalidOption("--threshold-sweep isn't supported in batch mode".to_string()));
+    }
+    if args.qc || args.qc_gate {
+        return Err(Error::InvalidOption("--qc and --qc-gate aren't supported in batch mode".to_string()));
+    }
+    #[cfg(feature = "gpu")]
+    if Backend::from_str(&args.backend).map_err(Error::InvalidOption)? == Backend::Gpu {
+        return Err(Error::InvalidOption("--backend gpu isn't supported in batch mode".to_string()));
+    }
+    Ok(BatchSettings {
+        output_dir: Path::new(&args.output_dir).to_path_buf(),
+        heatmap_dir: args.heatmap_dir.as_ref().map(|dir| Path::new(dir).to_path_buf()),
+        colormap: ColorMap::from_str(&args.colormap).map_err(Error::InvalidOption)?,
+        normalization: Normalization::from_str(&args.normalization).map_err(Error::InvalidOption)?,
+        opacity: args.opacity,
+        resume: args.resume,
+        png: png_options(args)?,
+        #[cfg(feature = "dimse")]
+        store_to: if args.store_to.is_empty() {
+            Vec::new()
+        } else {
+            DimseConfig::from_env()?.destinations(&args.store_to)?
+        },
+    })
 }
 
-/// Normalize heatmap data using different methods
-fn normalize_heatmap(data: &Array2<f32>, method: &Normalization) -> Array2<f32> {
-    match method {
-        Normalization::MinMax => {
-            let min_val = data.iter().fold(f32::INFINITY, |a, &b| a.min(b));
-            let max_val = data.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
-            let range = max_val - min_val;
-            
-            if range == 0.0 {
-                data.clone()
-            } else {
-                data.mapv(|x| (x - min_val) / range)
-            }
+fn batch_exit_code(summary: &BatchSummary, settings: &BatchSettings) -> ExitCode {
+    info!("Batch finished: {} succeeded, {} failed, {} skipped", 
+          summary.succeeded, summary.failed, summary.skipped);
+    if summary.failed > 0 {
+        eprintln!("{} batch item(s) failed, see {}", 
+                  summary.failed, settings.output_dir.join(BATCH_FAILURES_FILE).display());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Run the --qido batch against --wado-url or DICOMWEB_URL
+#[cfg(feature = "dicomweb")]
+fn run_query(args: &Args, settings: &BatchSettings) -> Result<BatchSummary> {
+    let config = DicomWebConfig::from_env_with_url(args.wado_url.as_deref())?
+        .ok_or_else(|| Error::InvalidOption("--qido needs --wado-url or DICOMWEB_URL".to_string()))?;
+    run_query_batch(&DicomWebClient::new(config)?, args.qido.as_deref().unwrap_or_default(), settings)
+}
+
+#[cfg(not(feature = "dicomweb"))]
+fn run_query(_args: &Args, _settings: &BatchSettings) -> Result<BatchSummary> {
+    unreachable!("--qido needs the dicomweb feature")
+}
+
+fn log_result(result: &PipelineResult, origin: &str) {
+    let written: Vec<String> = result.outputs.iter().map(|path| path.display().to_string()).collect();
+    info!("Successfully created {}x{} PNG with heatmap overlay{}: {}", result.width, result.height, origin, written.join(", "));
+    if let Some(evaluation) = &result.evaluation {
+        let score = |value: Option<f64>| value.map_or("n/a".to_string(), |value| format!("{:.4}", value));
+        info!("Against the ground-truth mask at {}: Dice {}, IoU {}, sensitivity {}, specificity {}",
+              evaluation.threshold, score(evaluation.dice), score(evaluation.iou),
+              score(evaluation.sensitivity), score(evaluation.specificity));
+    }
+    if let Some(comparison) = &result.comparison {
+        let correlation = comparison.correlation.map_or("n/a".to_string(), |value| format!("{:.4}", value));
+        info!("Against the baseline heatmap: correlation {}, SSIM {:.4}, mean absolute difference {:.4}",
+              correlation, comparison.ssim, comparison.mean_absolute_difference);
+    }
+    if let Some(regions) = result.regions.as_ref().filter(|regions| !regions.predominant.is_empty()) {
+        info!("Activation predominantly in {}", regions.predominant.join(", "));
+    }
+    if let Some(pointing) = &result.pointing {
+        let distance = pointing.distance.map_or("no lesions".to_string(), |distance| format!("{:.1} px from the nearest lesion", distance));
+        info!("Pointing game: {} (maximum at ({:.1}, {:.1}), {})", if pointing.hit { "hit" } else { "miss" },
+              pointing.maximum.x, pointing.maximum.y, distance);
+    }
+    if let Some(coverage) = &result.coverage
+        && let Some(fraction) = coverage.outside_fraction
+    {
+        info!("{:.1}% of the activation at or above {} outside the anatomy mask", fraction * 100.0, coverage.threshold);
+    }
+    if let Some(sweep) = &result.threshold_sweep {
+        for level in &sweep.levels {
+            let dice = level.dice.map_or(String::new(), |dice| format!(", Dice {:.4}", dice));
+            info!("At threshold {:.2}: {} px ({:.1}%), {} region(s){}", level.threshold, level.area_pixels,
+                  level.area_fraction * 100.0, level.regions, dice);
         }
-        Normalization::ZScore => {
-            let mean = data.mean().unwrap_or(0.0);
-            let variance = data.mapv(|x| (x - mean).powi(2)).mean().unwrap_or(1.0);
-            let std_dev = variance.sqrt();
-            
-            if std_dev == 0.0 {
-                data.clone()
-            } else {
-                data.mapv(|x| (x - mean) / std_dev)
-            }
+        if let Some(threshold) = sweep.best_dice_threshold {
+            info!("Highest Dice at threshold {:.2}", threshold);
         }
-        Normalization::Percentile => {
-            let mut sorted_values: Vec<f32> = data.iter().cloned().collect();
-            sorted_values.sort_by(|a, b| a.partial_cmp(b).unwrap());
-            
-            let len = sorted_values.len();
-            let p5_idx = (0.05 * len as f32) as usize;
-            let p95_idx = (0.95 * len as f32) as usize;
-            
-            let p5_val = sorted_values[p5_idx];
-            let p95_val = sorted_values[p95_idx];
-            let range = p95_val - p5_val;
-            
-            if range == 0.0 {
-                data.clone()
-            } else {
-                data.mapv(|x| ((x - p5_val) / range).max(0.0).min(1.0))
-            }
+    }
+    if let Some(report) = &result.hotspots {
+        for (index, hotspot) in report.hotspots.iter().enumerate() {
+            let area = hotspot.area_mm2.map_or(String::new(), |area| format!(" ({:.1} mm²)", area));
+            info!("Hotspot {}: peak {:.3} at ({:.1}, {:.1}), {} px{}", index + 1, hotspot.peak,
+                  hotspot.centroid.x, hotspot.centroid.y, hotspot.area_pixels, area);
         }
     }
 }
 
-/// Apply colormap to normalized heatmap data
-fn apply_colormap(normalized_data: &Array2<f32>, colormap: &ColorMap, opacity: f32) -> RgbaImage {
-    let (rows, cols) = normalized_data.dim();
-    let mut heatmap_rgba = RgbaImage::new(cols as u32, rows as u32);
-    
-    for row in 0..rows {
-        for col in 0..cols {
-            let value = normalized_data[[row, col]];
-            let color = get_color_from_value(value, colormap);
-            let alpha = (opacity * 255.0) as u8;
-            
-            heatmap_rgba.put_pixel(
-                col as u32,
-                row as u32,
-                Rgba([color.0, color.1, color.2, alpha]),
-            );
-        }
+fn log_volume_result(result: &VolumeResult, series: Option<&DicomSeries>) {
+    let top: Vec<String> = result.profile.top(TOP_SLICES)
+        .map(|stats| {
+            let instance = series.and_then(|series| series.slices[stats.slice].instance_number)
+                .map(|number| format!("instance {}, ", number))
+                .unwrap_or_default();
+            format!("{} ({}max {:.3}, {:.1}% active)", stats.slice, instance, stats.max, stats.active_fraction * 100.0)
+        })
+        .collect();
+    if !top.is_empty() {
+        info!("Most suspicious slices: {}", top.join(", "));
     }
-    
-    heatmap_rgba
 }
 
-/// Get RGB color from normalized value [0,1] using specified colormap
-fn get_color_from_value(value: f32, colormap: &ColorMap) -> (u8, u8, u8) {
-    let value = value.max(0.0).min(1.0); // Clamp to [0,1]
-    
-    match colormap {
-        ColorMap::Red => {
-            // Simple red gradient
-            let intensity = (value * 255.0) as u8;
-            (intensity, 0, 0)
-        }
-        ColorMap::Hot => {
-            // Hot colormap: black -> red -> yellow -> white
-            if value < 0.33 {
-                let t = value / 0.33;
-                ((t * 255.0) as u8, 0, 0)
-            } else if value < 0.66 {
-                let t = (value - 0.33) / 0.33;
-                (255, (t * 255.0) as u8, 0)
-            } else {
-                let t = (value - 0.66) / 0.34;
-                (255, 255, (t * 255.0) as u8)
+#[cfg_attr(not(any(feature = "dimse", feature = "onnx")), allow(unused_variables))]
+fn run_command(command: Command, args: &Args) -> Result<ExitCode> {
+    match command {
+        #[cfg(feature = "server")]
+        Command::Serve { host, port } => {
+            let mut config = OrchestrateConfig::from_env()?;
+            if let Some(host) = host {
+                config.host = host;
             }
-        }
-        ColorMap::Jet => {
-            // Jet colormap: blue -> cyan -> yellow -> red
-            if value < 0.25 {
-                let t = value / 0.25;
-                (0, (t * 255.0) as u8, 255)
-            } else if value < 0.5 {
-                let t = (value - 0.25) / 0.25;
-                (0, 255, (255.0 * (1.0 - t)) as u8)
-            } else if value < 0.75 {
-                let t = (value - 0.5) / 0.25;
-                ((t * 255.0) as u8, 255, 0)
-            } else {
-                let t = (value - 0.75) / 0.25;
-                (255, (255.0 * (1.0 - t)) as u8, 0)
+            if let Some(port) = port {
+                config.port = port;
             }
+            server_runtime(&config)?.block_on(server::serve(config))?;
+            Ok(ExitCode::SUCCESS)
         }
-        ColorMap::Viridis => {
-            // Simplified viridis: purple -> blue -> green -> yellow
-            if value < 0.33 {
-                let t = value / 0.33;
-                ((68.0 + t * (59.0 - 68.0)) as u8, (1.0 + t * (82.0 - 1.0)) as u8, (84.0 + t * (139.0 - 84.0)) as u8)
-            } else if value < 0.66 {
-                let t = (value - 0.33) / 0.33;
-                ((59.0 + t * (33.0 - 59.0)) as u8, (82.0 + t * (144.0 - 82.0)) as u8, (139.0 + t * (140.0 - 139.0)) as u8)
-            } else {
-                let t = (value - 0.66) / 0.34;
-                ((33.0 + t * (253.0 - 33.0)) as u8, (144.0 + t * (231.0 - 144.0)) as u8, (140.0 + t * (37.0 - 140.0)) as u8)
-            }
+        #[cfg(any(feature = "kafka", feature = "amqp"))]
+        Command::Worker { transport } => run_worker(transport),
+        #[cfg(feature = "dimse")]
+        Command::Listen { listener } => {
+            let config = listener_config(listener)?;
+            let settings = listener_settings(args)?;
+            let summary = dimse::listen(&config, &settings)?;
+            Ok(batch_exit_code(&summary, &settings))
         }
-        ColorMap::Plasma => {
-            // Simplified plasma: purple -> pink -> yellow
-            if value < 0.5 {
-                let t = value / 0.5;
-                ((13.0 + t * (190.0 - 13.0)) as u8, (8.0 + t * (84.0 - 8.0)) as u8, (135.0 + t * (160.0 - 135.0)) as u8)
-            } else {
-                let t = (value - 0.5) / 0.5;
-                ((190.0 + t * (240.0 - 190.0)) as u8, (84.0 + t * (249.0 - 84.0)) as u8, (160.0 + t * (33.0 - 160.0)) as u8)
-            }
+        #[cfg(feature = "dimse")]
+        Command::Pull { from, find, listener } => {
+            let config = listener_config(listener)?;
+            let source = config.destinations(&[from])?.remove(0);
+            let settings = listener_settings(args)?;
+            let summary = dimse::pull(&config, &settings, &source, &find)?;
+            Ok(batch_exit_code(&summary, &settings))
         }
+        #[cfg(feature = "onnx")]
+        Command::Infer { model } => run_infer(*model, args),
+        Command::Evaluate { evaluate } => run_evaluate(evaluate),
     }
 }
 
-/// Load heatmap from binary file (assumes f32 values in row-major order)
-/// File should start with 8 bytes: 4 bytes for rows (u32), 4 bytes for cols (u32)
-fn load_binary_heatmap(file_path: &str) -> Result<Array2<f32>, Box<dyn std::error::Error>> {
-    use byteorder::{LittleEndian, ReadBytesExt};
-    
-    let mut file = File::open(file_path)?;
-    
-    // Read dimensions
-    let rows = file.read_u32::<LittleEndian>()? as usize;
-    let cols = file.read_u32::<LittleEndian>()? as usize;
-    
-    info!("Binary heatmap dimensions: {}x{}", rows, cols);
-    
-    // Read data
-    let mut data = Vec::with_capacity(rows * cols);
-    for _ in 0..(rows * cols) {
-        data.push(file.read_f32::<LittleEndian>()?);
+/// Validate the class scores of the --manifest cases against their labels, also at the
+/// thresholds of --operating-points, score their calibration and the pointing game of those with
+/// lesion annotations, and write the metrics report and the --calibration-plot
+fn run_evaluate(args: EvaluateArgs) -> Result<ExitCode> {
+    let operating_points = args.operating_points.as_deref()
+        .map(|points| OperatingPoints::from_str(points).map_err(|e| Error::InvalidOption(format!("Invalid --operating-points: {}", e))))
+        .transpose()?;
+    if args.calibration_bins == 0 {
+        return Err(Error::InvalidOption("--calibration-bins must be greater than 0".to_string()));
     }
-    
-    Array2::from_shape_vec((rows, cols), data)
-        .map_err(|e| e.into())
-}
+    let options = ValidationOptions { operating_points, pointing_tolerance: args.pointing_tolerance, calibration_bins: args.calibration_bins };
+    let report = validate(Path::new(&args.manifest), args.class.as_deref(), &options)?;
+    let metrics = &args.metrics;
+    report.save(Path::new(metrics))?;
 
-fn create_demo_heatmap(rows: u32, columns: u32, png_path: &Path, colormap: &ColorMap, opacity: f32) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Creating demo heatmap with simulated data ({}x{})", columns, rows);
-    
-    // Create a simple gradient as a base image (simulating DICOM data)
-    let mut image_data_u8: Vec<u8> = Vec::with_capacity((rows * columns) as usize);
-    for y in 0..rows {
-        for x in 0..columns {
-            // Create a simple gradient pattern
-            let intensity = ((x + y) as f32 / (columns + rows) as f32 * 255.0) as u8;
-            image_data_u8.push(intensity);
+    let score = |value: Option<f64>| value.map_or("n/a".to_string(), |value| format!("{:.4}", value));
+    info!("Evaluated {} positive and {} negative case(s): AUC {}", report.positives, report.negatives, score(report.auc));
+    for (name, metrics) in [("operating point", &report.operating_point), ("Youden-optimal threshold", &report.youden)] {
+        if let Some(metrics) = metrics {
+            info!("At the {} {}: sensitivity {}, specificity {}, PPV {}, NPV {}", name, metrics.threshold,
+                  score(metrics.sensitivity), score(metrics.specificity), score(metrics.ppv), score(metrics.npv));
         }
     }
+    if let Some(calibration) = &report.calibration {
+        info!("Calibration in {} bins: ECE {:.4}, MCE {:.4}, Brier score {:.4}", calibration.bins.len(),
+              calibration.ece, calibration.mce, calibration.brier);
+        if let Some(path) = &args.calibration_plot {
+            save_calibration_plot(calibration, Path::new(path))?;
+            info!("Saved calibration plot: {}", path);
+        }
+    }
+    if let Some(pointing) = &report.pointing {
+        info!("Pointing game: {} of {} case(s) with lesions hit, hit rate {}", pointing.hits, pointing.images, score(pointing.hit_rate));
+    }
+    info!("Saved metrics report: {}", metrics);
+    if !report.skipped.is_empty() {
+        eprintln!("{} manifest row(s) skipped, see {}", report.skipped.len(), metrics);
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Classify --input with the model, print the scores as JSON and render the model's heatmap for
+/// the top class with the rendering options
+#[cfg(feature = "onnx")]
+fn run_infer(model: ModelArgs, args: &Args) -> Result<ExitCode> {
+    let colormap = ColorMap::from_str(&args.colormap).map_err(Error::InvalidOption)?;
+    let normalization = Normalization::from_str(&args.normalization).map_err(Error::InvalidOption)?;
+    let blend_mode = BlendMode::from_str(&args.blend).map_err(Error::InvalidOption)?;
+    if args.opacity < 0.0 || args.opacity > 1.0 {
+        return Err(Error::InvalidOption("Opacity must be between 0.0 and 1.0".to_string()));
+    }
+    if model.batch_size == 0 {
+        return Err(Error::InvalidOption("--batch-size must be greater than 0".to_string()));
+    }
+    let options = InferenceOptions {
+        scores_output: model.scores_output,
+        heatmap_output: model.heatmap_output,
+        labels: (!model.labels.is_empty()).then_some(model.labels),
+        activation: ScoreActivation::from_str(&model.activation).map_err(Error::InvalidOption)?,
+        cam: match &model.cam {
+            Some(method) => Some(CamOptions {
+                method: CamMethod::from_str(method).map_err(Error::InvalidOption)?,
+                activations_output: model.activations_output,
+                gradients_output: model.gradients_output,
+                target_input: model.target_input,
+            }),
+            None => None,
+        },
+        occlusion: model.occlusion.as_deref()
+            .map(|occlusion| occlusion.parse().map_err(|e| Error::InvalidOption(format!("Invalid --occlusion: {}", e))))
+            .transpose()?,
+        preprocess: model.preprocess.as_deref()
+            .map(|preprocess| preprocess.parse().map_err(|e| Error::InvalidOption(format!("Invalid --preprocess: {}", e))))
+            .transpose()?,
+        providers: model.provider.iter()
+            .map(|provider| ExecutionProvider::from_str(provider).map_err(Error::InvalidOption))
+            .collect::<Result<_>>()?,
+        device: model.device,
+        tta: model.tta.iter()
+            .map(|augmentation| Augmentation::from_str(augmentation).map_err(Error::InvalidOption))
+            .collect::<Result<_>>()?,
+        quantization: model.quantization.as_deref()
+            .map(|quantization| parse_quantization(quantization).map_err(|e| Error::InvalidOption(format!("Invalid --quantization: {}", e))))
+            .transpose()?
+            .unwrap_or_default(),
+    };
+    let registry = ModelRegistryConfig::from_env()?;
+    let onnx = match registry.model(&model.model) {
+        Some(config) => OnnxModel::from_registry(config, &registry.cache_dir, &options)?,
+        None => OnnxModel::load(Path::new(&model.model), &options)?,
+    };
+    let target_class = onnx.class_index(&ClassSelector::from_str(&model.target_class).map_err(Error::InvalidOption)?)?;
 
-    // Create a grayscale image from the simulated data
-    let gray_image: GrayImage = ImageBuffer::from_raw(columns, rows, image_data_u8)
-        .ok_or_else(|| "Failed to create GrayImage from simulated data")?;
+    if let Some(input_dir) = &args.input_dir {
+        let settings = batch_settings(args)?;
+        let summary = run_inference_batch(Path::new(input_dir), &onnx, model.batch_size, target_class, &settings)?;
+        return Ok(batch_exit_code(&summary, &settings));
+    }
+
+    let obj = open_dicom(Path::new(&args.input))?;
+    let (rows, columns) = image_dimensions(&obj)?;
+    let image = decode_dicom_pixel_data(&obj, rows, columns)?;
+    let inference = onnx.infer(&image::imageops::grayscale(&image), target_class)?;
+    let top = inference.top_score();
+    info!("Top class of {}: {} ({:.4})", args.input, top.label, top.score);
+    let mut scores = serde_json::json!({
+        "model": onnx.info().name,
+        "model_version": onnx.info().version,
+        "model_sha256": onnx.info().sha256,
+        "providers": onnx.providers().iter().map(|provider| provider.name()).collect::<Vec<_>>(),
+        "input": args.input,
+        "class": top.label,
+        "score": top.score,
+        "heatmap_class": inference.scores[inference.class].label,
+        "scores": inference.scores,
+    });
+    if let Some(precision) = onnx.precision() {
+        scores["precision"] = precision.into();
+    }
+    if let Some(drift) = onnx.score_drift() {
+        scores["score_drift"] = drift.into();
+    }
+    if let Some(points) = &args.operating_points {
+        let points = OperatingPoints::from_str(points).map_err(|e| Error::InvalidOption(format!("Invalid --operating-points: {}", e)))?;
+        scores["decision"] = serde_json::json!(points.decide(&inference.heatmap.metadata));
+    }
+    println!("{}", serde_json::to_string_pretty(&scores).unwrap_or_default());
 
-    // Convert grayscale to RGBA to allow for color overlay
-    let mut base_rgba_image: RgbaImage = DynamicImage::ImageLuma8(gray_image).to_rgba8();
+    let png_path = Path::new(&args.output);
+    let mut builder = HeatmapPipeline::builder()
+        .colormap(colormap)
+        .normalization(normalization)
+        .blend(BlendOptions { opacity: args.opacity, mode: blend_mode, threshold: args.threshold })
+        .output(OutputTarget::Png(png_path.to_path_buf()))
+        .png_options(png_options(args)?)
+        .source(ImageSource::Image(image))
+        .heatmap(HeatmapInput::Loaded(Box::new(inference.heatmap)));
+    if args.sidecar {
+        builder = builder.output(OutputTarget::Sidecar(png_path.with_extension("json")));
+    }
+    builder = with_audit_log(builder, args);
+    builder = analysis_outputs(builder, args, png_path)?;
+    builder = decision_outputs(builder, args, png_path)?;
+    #[cfg(feature = "plugins")]
+    {
+        builder = with_plugin(builder, args)?;
+    }
+    #[cfg(feature = "gpu")]
+    {
+        builder = with_backend(builder, args)?;
+    }
+    log_result(&builder.build()?.run()?, &format!(" from {}", model.model));
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Generate demo heatmap with specified colormap
-    let heatmap_rgba = generate_default_heatmap(columns, rows, colormap, opacity);
+/// Multi-threaded runtime for the server and workers, with `ORCHESTRATE_IO_THREADS` worker
+/// threads when set; CPU-bound work runs on its blocking pool. The process is pinned and the
+/// render pool sized first, so the runtime's threads start on the pinned cores
+#[cfg(feature = "server")]
+fn server_runtime(config: &OrchestrateConfig) -> Result<tokio::runtime::Runtime> {
+    server::threads::configure(&config.pools)?;
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.pools.io_threads {
+        builder.worker_threads(threads);
+    }
+    builder.build().map_err(|e| Error::Server(format!("Failed to start async runtime: {}", e)))
+}
 
-    // Overlay the heatmap onto the base RGBA image
-    imageops::overlay(&mut base_rgba_image, &heatmap_rgba, 0, 0);
+/// Consume requests with `transport`, or the only queue configured in the environment
+#[cfg(any(feature = "kafka", feature = "amqp"))]
+fn run_worker(transport: Option<String>) -> Result<ExitCode> {
+    let transport = match transport {
+        Some(transport) => transport.to_lowercase(),
+        None => match (std::env::var_os("KAFKA_BROKERS").is_some(), std::env::var_os("AMQP_URL").is_some()) {
+            (true, false) => "kafka".to_string(),
+            (false, true) => "amqp".to_string(),
+            (true, true) => {
+                return Err(Error::InvalidOption("Both KAFKA_BROKERS and AMQP_URL are set, choose one with --transport".to_string()));
+            }
+            (false, false) => return Err(Error::InvalidOption("The worker subcommand needs KAFKA_BROKERS or AMQP_URL".to_string())),
+        },
+    };
+    let config = OrchestrateConfig::from_env()?;
+    let runtime = server_runtime(&config)?;
+    match transport.as_str() {
+        #[cfg(feature = "kafka")]
+        "kafka" => {
+            let kafka = KafkaConfig::from_env()?
+                .ok_or_else(|| Error::InvalidOption("--transport kafka needs KAFKA_BROKERS".to_string()))?;
+            runtime.block_on(server::kafka::consume(config, kafka))?;
+        }
+        #[cfg(feature = "amqp")]
+        "amqp" => {
+            let amqp = AmqpConfig::from_env()?
+                .ok_or_else(|| Error::InvalidOption("--transport amqp needs AMQP_URL".to_string()))?;
+            runtime.block_on(server::amqp::consume(config, amqp))?;
+        }
+        other => {
+            let available: &[&str] = &[
+                #[cfg(feature = "kafka")]
+                "kafka",
+                #[cfg(feature = "amqp")]
+                "amqp",
+            ];
+            return Err(Error::InvalidOption(format!("Unknown transport: {}. Available: {}", other, available.join(", "))));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Save the resulting image
-    base_rgba_image.save_with_format(png_path, image::ImageFormat::Png)?;
+/// DIMSE_* configuration with the listener options applied
+#[cfg(feature = "dimse")]
+fn listener_config(listener: ListenerArgs) -> Result<DimseConfig> {
+    let mut config = DimseConfig::from_env()?;
+    if let Some(ae_title) = listener.ae_title {
+        // Destinations are called from the listener's AE title
+        for destination in config.destinations.values_mut() {
+            destination.calling_ae_title = ae_title.clone();
+        }
+        config.ae_title = ae_title;
+    }
+    if let Some(host) = listener.host {
+        config.host = host;
+    }
+    if let Some(port) = listener.port {
+        config.port = port;
+    }
+    Ok(config)
+}
 
-    info!("Successfully created demo PNG with {} heatmap overlay: {}", 
-          format!("{:?}", colormap).to_lowercase(), png_path.display());
-    info!("Note: Using simulated base image. Place a real DICOM file as 'sample.dcm' to process real medical data.");
-    
-    Ok(())
+#[cfg(feature = "dimse")]
+fn listener_settings(args: &Args) -> Result<BatchSettings> {
+    if args.opacity < 0.0 || args.opacity > 1.0 {
+        return Err(Error::InvalidOption("Opacity must be between 0.0 and 1.0".to_string()));
+    }
+    batch_settings(args)
 }
@@ -0,0 +1,608 @@
+//! Protocols of the model servers DL services can run on (see [`ServiceKind`]): the image goes
+//! out as a preprocessed tensor, and the scores and heatmap come back as output tensors that
+//! are turned into the heatmap of the top class, for Triton and TF Serving. TorchServe handlers take the image payload and
+//! answer in their own JSON or multipart shapes.
+
+use image::imageops;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::cache::stable_hash;
+use crate::config::{ServiceConfig, ServiceKind};
+use crate::dicom_io::{decode_dicom_pixel_data, image_dimensions, open_dicom_bytes};
+use crate::error::Result;
+use crate::heatmap::{class_map, HeatmapMetadata, HeatmapRegistry, LoadedHeatmap};
+use crate::preprocess::{Placement, PreprocessSpec, TensorLayout};
+use crate::service::heatmap_format;
+
+/// Side of the image tensor when the service's preprocessing sets no size
+pub const DEFAULT_TENSOR_SIZE: u32 = 224;
+
+/// Header of Triton's binary tensor extension with the length of the JSON part of a body
+pub const INFERENCE_HEADER_LENGTH: &str = "inference-header-content-length";
+
+/// Content type of Triton requests with a binary tensor
+pub const TRITON_BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Output tensor of a model server, in row-major order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor {
+    pub name: String,
+    pub dims: Vec<usize>,
+    pub values: Vec<f32>,
+}
+
+/// Image tensor of one DICOM, prepared with the service's preprocessing
+#[derive(Debug, Clone)]
+pub struct ImageTensor {
+    /// Dimensions with a batch of one, in the service's input layout
+    pub dims: [usize; 4],
+    pub values: Vec<f32>,
+    pub preprocess: PreprocessSpec,
+    /// Where the image lies in the tensor, to map the heatmap back
+    pub placement: Placement,
+}
+
+impl ImageTensor {
+    /// Values as little-endian bytes, for raw tensor contents
+    pub fn raw(&self) -> Vec<u8> {
+        self.values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+}
+
+/// Decode the DICOM and prepare its grayscale image as the service's input tensor
+pub fn image_tensor(dicom: &[u8], service: &ServiceConfig) -> Result<ImageTensor> {
+    let obj = open_dicom_bytes(dicom)?;
+    let (rows, columns) = image_dimensions(&obj)?;
+    let image = imageops::grayscale(&decode_dicom_pixel_data(&obj, rows, columns)?);
+    let preprocess = service.preprocess.clone().unwrap_or_default();
+    let (width, height) = preprocess.size.unwrap_or((DEFAULT_TENSOR_SIZE, DEFAULT_TENSOR_SIZE));
+    let (resized, placement) = preprocess.resize(&image, (width, height));
+    let served = &service.served;
+    let values = preprocess.tensor_values(&resized, served.channels, served.layout == TensorLayout::Nhwc);
+    let dims = served.layout.dims(1, served.channels, height as usize, width as usize);
+    Ok(ImageTensor { dims, values, preprocess, placement })
+}
+
+/// Outputs requested from the server: the configured scores and heatmap outputs, or every
+/// output unless both are set
+fn requested_outputs(service: &ServiceConfig) -> Vec<&str> {
+    match (&service.served.scores_output, &service.served.heatmap_output) {
+        (Some(scores), Some(heatmap)) => vec![scores, heatmap],
+        _ => Vec::new(),
+    }
+}
+
+/// Path of Triton's infer endpoint for the service's model, at `model_version` if set
+pub fn triton_path(service: &ServiceConfig) -> String {
+    let model = service.served.name.as_deref().unwrap_or_default();
+    match &service.model_version {
+        Some(version) => format!("/v2/models/{}/versions/{}/infer", model, version),
+        None => format!("/v2/models/{}/infer", model),
+    }
+}
+
+/// Body of a Triton HTTP infer request, a JSON header followed by the raw image tensor, with
+/// every output requested in binary too, and the length of the header
+pub fn triton_request(service: &ServiceConfig, tensor: &ImageTensor) -> (Vec<u8>, usize) {
+    let raw = tensor.raw();
+    let mut header = json!({
+        "inputs": [{
+            "name": service.served.input,
+            "shape": tensor.dims,
+            "datatype": "FP32",
+            "parameters": { "binary_data_size": raw.len() },
+        }],
+        "parameters": { "binary_data_output": true },
+    });
+    let outputs = requested_outputs(service);
+    if !outputs.is_empty() {
+        header["outputs"] = outputs.iter()
+            .map(|name| json!({ "name": name, "parameters": { "binary_data": true } }))
+            .collect();
+    }
+    let mut body = header.to_string().into_bytes();
+    let length = body.len();
+    body.extend(raw);
+    (body, length)
+}
+
+/// Output tensors of a Triton HTTP response: JSON, or a JSON header of the length given in
+/// `Inference-Header-Content-Length` followed by the binary outputs
+pub fn triton_response(headers: &HeaderMap, body: &[u8]) -> std::result::Result<Vec<Tensor>, String> {
+    let length = match headers.get(INFERENCE_HEADER_LENGTH) {
+        Some(value) => value.to_str().ok()
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .filter(|&length| length <= body.len())
+            .ok_or_else(|| format!("invalid {} header", INFERENCE_HEADER_LENGTH))?,
+        None => body.len(),
+    };
+    let header: Value = serde_json::from_slice(&body[..length]).map_err(|e| format!("invalid response: {}", e))?;
+    let outputs = header["outputs"].as_array().ok_or("response has no outputs")?;
+    let mut binary = &body[length..];
+    let mut tensors = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let name = output["name"].as_str().ok_or("response has an output without a name")?;
+        let dims: Vec<usize> = output["shape"].as_array()
+            .map(|shape| shape.iter().filter_map(Value::as_u64).map(|dim| dim as usize).collect())
+            .unwrap_or_default();
+        let datatype = output["datatype"].as_str().unwrap_or("FP32");
+        let values = match output["parameters"]["binary_data_size"].as_u64() {
+            Some(size) => {
+                let size = size as usize;
+                if size > binary.len() {
+                    return Err(format!("output {} is cut short", name));
+                }
+                let (data, rest) = binary.split_at(size);
+                binary = rest;
+                raw_values(name, datatype, data)?
+            }
+            None => flatten(&output["data"]),
+        };
+        tensors.push(Tensor { name: name.to_string(), dims, values });
+    }
+    Ok(tensors)
+}
+
+/// Numbers of a JSON tensor, flattening nested arrays
+fn flatten(data: &Value) -> Vec<f32> {
+    match data {
+        Value::Array(values) => values.iter().flat_map(flatten).collect(),
+        Value::Number(value) => value.as_f64().map(|value| vec![value as f32]).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Values of raw little-endian tensor contents of a float datatype
+fn raw_values(name: &str, datatype: &str, data: &[u8]) -> std::result::Result<Vec<f32>, String> {
+    match datatype {
+        "FP32" => Ok(data.chunks_exact(4).map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()),
+        "FP64" => Ok(data.chunks_exact(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap_or_default()) as f32)
+            .collect()),
+        _ => Err(format!("output {} has datatype {}, expected FP32 or FP64", name, datatype)),
+    }
+}
+
+/// Heatmap of the top-scoring class from the output tensors, covering the whole input tensor
+pub fn tensor_heatmap(service: &ServiceConfig, outputs: &[Tensor]) -> std::result::Result<LoadedHeatmap, String> {
+    let served = &service.served;
+    let pick = |name: Option<&str>, fits: fn(&[usize]) -> bool, what: &str| {
+        let found = match name {
+            Some(name) => outputs.iter().find(|output| output.name == name),
+            None => outputs.iter().find(|output| fits(&output.dims)),
+        };
+        found.ok_or_else(|| {
+            let available: Vec<&str> = outputs.iter().map(|output| output.name.as_str()).collect();
+            format!("no {} output {}(available: {})", what, name.map(|name| format!("{} ", name)).unwrap_or_default(), available.join(", "))
+        })
+    };
+    let scores = pick(served.scores_output.as_deref(), |dims| dims.len() <= 2, "scores")?;
+    let heatmap = pick(served.heatmap_output.as_deref(), |dims| dims.len() >= 3, "heatmap")?;
+    if scores.values.is_empty() {
+        return Err(format!("output {} holds no scores", scores.name));
+    }
+    top_class_heatmap(service, &scores.values, &served.labels, heatmap)
+}
+
+/// Index of the highest score
+fn top_class(scores: &[f32]) -> Option<usize> {
+    scores.iter().enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b)).map(|(index, _)| index)
+}
+
+/// Map of the service's target class, or else of the top-scoring class, from a heatmap tensor
+/// with a batch of one; scores are named by `labels` in order, or by index
+fn top_class_heatmap(service: &ServiceConfig, scores: &[f32], labels: &[String], heatmap: &Tensor) -> std::result::Result<LoadedHeatmap, String> {
+    let class = match service.served.target_class.index(labels)? {
+        Some(class) if class >= scores.len() && !scores.is_empty() => {
+            return Err(format!("target class {} is out of range, the model has {} classes", class, scores.len()));
+        }
+        Some(class) => class,
+        None => top_class(scores).unwrap_or_default(),
+    };
+    let data = class_map(&heatmap.dims, heatmap.values.clone(), scores.len(), class).ok_or_else(|| format!(
+        "output {} of shape {:?} is not a heatmap of shape [batch, classes, height, width], [batch, height, width, classes] or [batch, height, width]",
+        heatmap.name, heatmap.dims
+    ))?;
+    let mut heatmap = LoadedHeatmap {
+        metadata: HeatmapMetadata {
+            format: service.kind.name().to_string(),
+            path: service.url.clone(),
+            shape: data.dim(),
+            attributes: BTreeMap::new(),
+        },
+        data,
+    };
+    record_scores(service, &mut heatmap, scores, labels, Some(class));
+    Ok(heatmap)
+}
+
+/// Record the model, the class of the heatmap (the top class unless given) and its score, the
+/// top class, and the score of every class
+fn record_scores(service: &ServiceConfig, heatmap: &mut LoadedHeatmap, scores: &[f32], labels: &[String], class: Option<usize>) {
+    let label = |index: usize| labels.get(index).cloned().unwrap_or_else(|| format!("class_{}", index));
+    let attributes = &mut heatmap.metadata.attributes;
+    if let Some(model) = &service.served.name {
+        attributes.insert("model".to_string(), model.clone());
+    }
+    let top = top_class(scores);
+    if let Some(class) = class.or(top) {
+        attributes.insert("class".to_string(), label(class));
+        if let Some(score) = scores.get(class) {
+            attributes.insert("score".to_string(), score.to_string());
+        }
+    }
+    if let Some(top) = top {
+        attributes.insert("top_class".to_string(), label(top));
+    }
+    for (index, score) in scores.iter().enumerate() {
+        attributes.insert(format!("score:{}", label(index)), score.to_string());
+    }
+}
+
+/// Path of TorchServe's prediction endpoint for the service's model, at `model_version` if set
+pub fn torchserve_path(service: &ServiceConfig) -> String {
+    let model = service.served.name.as_deref().unwrap_or_default();
+    match &service.model_version {
+        Some(version) => format!("/predictions/{}/{}", model, version),
+        None => format!("/predictions/{}", model),
+    }
+}
+
+/// Multipart form with the payload as its `data` field, and the form's content type
+pub fn multipart_form(content_type: &str, payload: &[u8]) -> (String, Vec<u8>) {
+    // Derived from the payload, so the boundary can't occur in it by chance
+    let boundary = format!("heatmap-processing-{:016x}", stable_hash(&payload));
+    let filename = match content_type {
+        "application/dicom" => "image.dcm",
+        _ => "image.png",
+    };
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"data\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+        boundary, filename, content_type
+    ).into_bytes();
+    body.extend_from_slice(payload);
+    body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// Heatmap from a TorchServe handler's output, with scores when it sends them
+///
+/// A JSON output, or the single element of a JSON list, is an object holding the heatmap (under
+/// `heatmap_output`, `heatmap` or `data`) as a `[height][width]` or `[classes][height][width]`
+/// array, and the scores (under `scores_output` or `scores`) as an array in class order or an
+/// object of label to score. A multipart output has them as parts of those names; the heatmap
+/// part may be in any format of `registry`, by its content type. Other outputs are read as a
+/// heatmap file.
+pub fn torchserve_heatmap(
+    service: &ServiceConfig,
+    headers: &HeaderMap,
+    body: &[u8],
+    registry: &HeatmapRegistry,
+) -> std::result::Result<LoadedHeatmap, String> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("application/json");
+    let served = &service.served;
+    let heatmap_key = served.heatmap_output.as_deref().unwrap_or("heatmap");
+    let scores_key = served.scores_output.as_deref().unwrap_or("scores");
+    if let Some(boundary) = multipart_boundary(content_type) {
+        let parts = multipart_parts(body, &boundary)?;
+        let scores = match parts.iter().find(|part| part.name == scores_key) {
+            Some(part) => {
+                let value: Value = serde_json::from_slice(&part.body).map_err(|e| format!("invalid {} part: {}", scores_key, e))?;
+                Some(json_scores(&value, &served.labels).ok_or_else(|| format!("{} part holds no scores", scores_key))?)
+            }
+            None => None,
+        };
+        let part = parts.iter()
+            .find(|part| part.name == heatmap_key)
+            .or_else(|| parts.iter().find(|part| part.name != scores_key))
+            .ok_or_else(|| format!("response has no {} part", heatmap_key))?;
+        let format = part.content_type.as_deref().map(heatmap_format).unwrap_or("json");
+        if format == "json" {
+            let value: Value = serde_json::from_slice(&part.body).map_err(|e| format!("invalid {} part: {}", part.name, e))?;
+            let (scores, labels) = scores.unwrap_or_default();
+            return json_heatmap(service, &part.name, &value, &scores, &labels);
+        }
+        let mut heatmap = registry.load_bytes(&part.body, format, &service.url).map_err(|e| e.to_string())?;
+        if let Some((scores, labels)) = scores {
+            record_scores(service, &mut heatmap, &scores, &labels, None);
+        }
+        return Ok(heatmap);
+    }
+    if heatmap_format(content_type) != "json" {
+        return registry.load_bytes(body, heatmap_format(content_type), &service.url).map_err(|e| e.to_string());
+    }
+    let mut value: Value = serde_json::from_slice(body).map_err(|e| format!("invalid response: {}", e))?;
+    // Handlers return one output per request of a batch
+    if let Value::Array(outputs) = &mut value
+        && outputs.len() == 1
+        && outputs[0].is_object()
+    {
+        value = outputs.remove(0);
+    }
+    let key = [heatmap_key, "data"].into_iter().find(|key| value.get(key).is_some())
+        .ok_or_else(|| format!("response has no {} field", heatmap_key))?;
+    let (scores, labels) = value.get(scores_key).and_then(|scores| json_scores(scores, &served.labels)).unwrap_or_default();
+    json_heatmap(service, key, &value[key], &scores, &labels)
+}
+
+/// Map of the top class from a JSON heatmap of one or more classes
+fn json_heatmap(service: &ServiceConfig, name: &str, value: &Value, scores: &[f32], labels: &[String]) -> std::result::Result<LoadedHeatmap, String> {
+    // A heatmap file's `{"data": ...}` object is accepted too
+    let mut tensor = json_tensor(name, value.get("data").unwrap_or(value))
+        .filter(|tensor| tensor.dims.len() >= 2)
+        .ok_or_else(|| format!("{} is not a [height][width] or [classes][height][width] array of numbers", name))?;
+    tensor.dims.insert(0, 1);
+    top_class_heatmap(service, scores, labels, &tensor)
+}
+
+/// Tensor of nested JSON arrays of numbers, with the dimensions of their nesting; None when the
+/// arrays are ragged or hold anything else
+fn json_tensor(name: &str, value: &Value) -> Option<Tensor> {
+    let mut dims = Vec::new();
+    let mut level = value;
+    while let Value::Array(values) = level {
+        dims.push(values.len());
+        match values.first() {
+            Some(first) => level = first,
+            None => break,
+        }
+    }
+    let values = flatten(value);
+    (values.len() == dims.iter().product::<usize>()).then(|| Tensor { name: name.to_string(), dims, values })
+}
+
+/// Scores of a JSON array in class order, or an object of label to score, with the labels that
+/// name them; an object's scores are put in the order of the configured labels, so the class
+/// index matches the heatmap's, with other labels after them
+fn json_scores(value: &Value, labels: &[String]) -> Option<(Vec<f32>, Vec<String>)> {
+    match value {
+        Value::Array(values) => {
+            let scores: Vec<f32> = values.iter().filter_map(Value::as_f64).map(|score| score as f32).collect();
+            (!scores.is_empty() && scores.len() == values.len()).then(|| (scores, labels.to_vec()))
+        }
+        Value::Object(object) => {
+            let mut scored: Vec<(String, f32)> = object.iter()
+                .filter_map(|(label, score)| score.as_f64().map(|score| (label.clone(), score as f32)))
+                .collect();
+            scored.sort_by_key(|(label, _)| labels.iter().position(|known| known == label).unwrap_or(labels.len()));
+            (!scored.is_empty()).then(|| scored.into_iter().map(|(label, score)| (score, label)).unzip())
+        }
+        _ => None,
+    }
+}
+
+/// Part of a multipart body
+struct Part {
+    name: String,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Boundary of a `multipart/*` content type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, parameters) = content_type.split_once(';')?;
+    if !mime.trim().to_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    parameters.split(';')
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary.trim().trim_matches('"').to_string())
+}
+
+/// Parts of a multipart body, named by their `Content-Disposition`
+fn multipart_parts(body: &[u8], boundary: &str) -> std::result::Result<Vec<Part>, String> {
+    let delimiter = format!("--{}", boundary);
+    let find = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|window| window == needle);
+    let start = find(body, delimiter.as_bytes()).ok_or("multipart response without its boundary")?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+    while !rest.starts_with(b"--") {
+        let rest_of_line = rest.strip_prefix(b"\r\n").ok_or("malformed multipart response")?;
+        let header_end = find(rest_of_line, b"\r\n\r\n").ok_or("malformed multipart headers")?;
+        let headers = String::from_utf8_lossy(&rest_of_line[..header_end]).into_owned();
+        let content = &rest_of_line[header_end + 4..];
+        let end = find(content, format!("\r\n{}", delimiter).as_bytes()).ok_or("multipart response cut short")?;
+        let mut part = Part { name: String::new(), content_type: None, body: content[..end].to_vec() };
+        for line in headers.lines() {
+            let Some((header, value)) = line.split_once(':') else { continue };
+            if header.trim().eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.trim().to_string());
+            } else if header.trim().eq_ignore_ascii_case("content-disposition") {
+                part.name = value.split(';')
+                    .filter_map(|parameter| parameter.trim().strip_prefix("name="))
+                    .map(|name| name.trim_matches('"').to_string())
+                    .next()
+                    .unwrap_or_default();
+            }
+        }
+        parts.push(part);
+        rest = &content[end + 2 + delimiter.len()..];
+    }
+    Ok(parts)
+}
+
+/// Path of TF Serving's predict endpoint for the service's model; a numeric `model_version` is
+/// a version and any other a version label
+pub fn tfserving_path(service: &ServiceConfig, method: &str) -> String {
+    let model = service.served.name.as_deref().unwrap_or_default();
+    let suffix = if method.is_empty() { String::new() } else { format!(":{}", method) };
+    match &service.model_version {
+        Some(version) if version.parse::<i64>().is_ok() => format!("/v1/models/{}/versions/{}{}", model, version, suffix),
+        Some(label) => format!("/v1/models/{}/labels/{}{}", model, label, suffix),
+        None => format!("/v1/models/{}{}", model, suffix),
+    }
+}
+
+/// Body of a TF Serving predict request, with the image as the named input in the columnar
+/// format, so outputs come back by name
+pub fn tfserving_request(service: &ServiceConfig, tensor: &ImageTensor) -> Vec<u8> {
+    let mut request = json!({ "inputs": { service.served.input.as_str(): nested(&tensor.values, &tensor.dims) } });
+    if let Some(signature) = &service.served.signature {
+        request["signature_name"] = json!(signature);
+    }
+    request.to_string().into_bytes()
+}
+
+/// Values in row-major order as nested JSON arrays of the given dimensions
+fn nested(values: &[f32], dims: &[usize]) -> Value {
+    match dims {
+        [] => json!(values.first().copied().unwrap_or_default()),
+        [_] => json!(values),
+        [first, rest @ ..] => {
+            let stride = values.len() / (*first).max(1);
+            Value::Array(values.chunks(stride.max(1)).map(|chunk| nested(chunk, rest)).collect())
+        }
+    }
+}
+
+/// Output tensors of a TF Serving predict response; a signature with a single output returns it
+/// unnamed, and it is named after the configured heatmap or scores output
+pub fn tfserving_response(service: &ServiceConfig, body: &[u8]) -> std::result::Result<Vec<Tensor>, String> {
+    let response: Value = serde_json::from_slice(body).map_err(|e| format!("invalid response: {}", e))?;
+    let invalid = |name: &str| format!("output {} is not an array of numbers", name);
+    match response.get("outputs") {
+        Some(Value::Object(outputs)) => outputs.iter()
+            .map(|(name, value)| json_tensor(name, value).ok_or_else(|| invalid(name)))
+            .collect(),
+        Some(value) => {
+            let served = &service.served;
+            let name = served.heatmap_output.as_deref().or(served.scores_output.as_deref()).unwrap_or("output");
+            Ok(vec![json_tensor(name, value).ok_or_else(|| invalid(name))?])
+        }
+        None => Err(response["error"].as_str()
+            .map(|error| format!("model server error: {}", error))
+            .unwrap_or_else(|| "response has no outputs".to_string())),
+    }
+}
+
+/// Readiness path of the model on a model server, probed over HTTP when the service has no `health_url`
+pub fn health_path(service: &ServiceConfig) -> Option<String> {
+    match service.kind {
+        ServiceKind::Heatmap => None,
+        ServiceKind::Triton => Some(format!("/v2/models/{}/ready", service.served.name.as_deref().unwrap_or_default())),
+        // TorchServe's inference API only reports the server's health
+        ServiceKind::TorchServe => Some("/ping".to_string()),
+        // The status of the model's versions, or 404 for an unknown model
+        ServiceKind::TfServing => Some(tfserving_path(service, "")),
+    }
+}
+
+/// Triton's KServe v2 gRPC protocol
+#[cfg(feature = "grpc-client")]
+pub mod triton_grpc {
+    use super::{raw_values, requested_outputs, ImageTensor, Tensor};
+    use crate::config::ServiceConfig;
+
+    /// Messages and client generated from `proto/triton.proto`
+    pub mod proto {
+        #![allow(clippy::all)]
+        tonic::include_proto!("inference");
+    }
+
+    use proto::model_infer_request::{InferInputTensor, InferRequestedOutputTensor};
+    use proto::{ModelInferRequest, ModelInferResponse};
+
+    /// Infer request with the image as raw input contents
+    pub fn request(service: &ServiceConfig, tensor: &ImageTensor) -> ModelInferRequest {
+        ModelInferRequest {
+            model_name: service.served.name.clone().unwrap_or_default(),
+            model_version: service.model_version.clone().unwrap_or_default(),
+            id: String::new(),
+            inputs: vec![InferInputTensor {
+                name: service.served.input.clone(),
+                datatype: "FP32".to_string(),
+                shape: tensor.dims.iter().map(|&dim| dim as i64).collect(),
+                contents: None,
+            }],
+            outputs: requested_outputs(service).into_iter()
+                .map(|name| InferRequestedOutputTensor { name: name.to_string() })
+                .collect(),
+            raw_input_contents: vec![tensor.raw()],
+        }
+    }
+
+    /// Output tensors from their raw contents, or the typed contents without them
+    pub fn response(response: ModelInferResponse) -> std::result::Result<Vec<Tensor>, String> {
+        let mut raw = response.raw_output_contents.into_iter();
+        response.outputs.into_iter()
+            .map(|output| {
+                let values = match (raw.next(), output.contents) {
+                    (Some(data), _) => raw_values(&output.name, &output.datatype, &data)?,
+                    (None, Some(contents)) if !contents.fp64_contents.is_empty() => {
+                        contents.fp64_contents.iter().map(|&value| value as f32).collect()
+                    }
+                    (None, Some(contents)) => contents.fp32_contents,
+                    (None, None) => Vec::new(),
+                };
+                let dims = output.shape.iter().map(|&dim| dim.max(0) as usize).collect();
+                Ok(Tensor { name: output.name, dims, values })
+            })
+            .collect()
+    }
+}
+
+/// TF Serving's prediction and model status gRPC services
+#[cfg(feature = "grpc-client")]
+pub mod tfserving_grpc {
+    use super::{raw_values, requested_outputs, ImageTensor, Tensor};
+    use crate::config::ServiceConfig;
+
+    /// Messages and clients generated from `proto/tensorflow_serving.proto`
+    pub mod proto {
+        #![allow(clippy::all)]
+        tonic::include_proto!("tensorflow.serving");
+    }
+
+    use proto::{DataType, Int64Value, ModelSpec, PredictRequest, PredictResponse, TensorProto, TensorShapeProto};
+
+    /// Model, version and signature of the service
+    pub fn model_spec(service: &ServiceConfig) -> ModelSpec {
+        let version = service.model_version.as_deref().and_then(|version| version.parse().ok());
+        ModelSpec {
+            name: service.served.name.clone().unwrap_or_default(),
+            version: version.map(|value| Int64Value { value }),
+            signature_name: service.served.signature.clone().unwrap_or_default(),
+            version_label: service.model_version.clone().filter(|_| version.is_none()).unwrap_or_default(),
+        }
+    }
+
+    /// Predict request with the image as the tensor content of the named input
+    pub fn request(service: &ServiceConfig, tensor: &ImageTensor) -> PredictRequest {
+        let input = TensorProto {
+            dtype: DataType::DtFloat as i32,
+            tensor_shape: Some(TensorShapeProto {
+                dim: tensor.dims.iter()
+                    .map(|&size| proto::tensor_shape_proto::Dim { size: size as i64, name: String::new() })
+                    .collect(),
+            }),
+            tensor_content: tensor.raw(),
+            float_val: Vec::new(),
+            double_val: Vec::new(),
+        };
+        PredictRequest {
+            model_spec: Some(model_spec(service)),
+            inputs: [(service.served.input.clone(), input)].into_iter().collect(),
+            output_filter: requested_outputs(service).into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Output tensors in name order, from their tensor content or typed values
+    pub fn response(response: PredictResponse) -> std::result::Result<Vec<Tensor>, String> {
+        response.outputs.into_iter()
+            .map(|(name, output)| {
+                let dims = output.tensor_shape.map(|shape| shape.dim.iter().map(|dim| dim.size.max(0) as usize).collect()).unwrap_or_default();
+                let values = match DataType::try_from(output.dtype) {
+                    Ok(DataType::DtFloat) if output.tensor_content.is_empty() => output.float_val,
+                    Ok(DataType::DtDouble) if output.tensor_content.is_empty() => output.double_val.iter().map(|&value| value as f32).collect(),
+                    Ok(DataType::DtFloat) => raw_values(&name, "FP32", &output.tensor_content)?,
+                    Ok(DataType::DtDouble) => raw_values(&name, "FP64", &output.tensor_content)?,
+                    _ => return Err(format!("output {} has dtype {}, expected DT_FLOAT or DT_DOUBLE", name, output.dtype)),
+                };
+                Ok(Tensor { name, dims, values })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,105 @@
+//! Node.js bindings via napi-rs, built with `--features node` and loaded as a `.node` addon.
+//!
+//! Rendering runs on the libuv thread pool so the reporting backend's event loop is never
+//! blocked; every call returns a Promise resolving to the fused PNG as a `Buffer`.
+
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::{Env, Status, Task};
+use napi_derive::napi;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::output::encode_png;
+use crate::pipeline::{HeatmapInput, HeatmapPipeline, ImageSource};
+use crate::render::{BlendMode, BlendOptions};
+use crate::{ColorMap, Normalization};
+
+/// Rendering options; omitted fields use the CLI defaults
+#[napi(object)]
+#[derive(Default)]
+pub struct RenderOptions {
+    /// red, hot, jet, viridis, plasma, coolwarm
+    pub colormap: Option<String>,
+    /// minmax, zscore, percentile
+    pub normalization: Option<String>,
+    /// Format of the heatmap buffer: json, csv, bin or npy
+    pub heatmap_format: Option<String>,
+    /// alpha, additive, screen
+    pub blend: Option<String>,
+    pub opacity: Option<f64>,
+    /// Normalized cut-off below which the heatmap is hidden
+    pub threshold: Option<f64>,
+}
+
+/// Pipeline run executed off the JavaScript thread
+pub struct RenderTask {
+    dicom: Vec<u8>,
+    heatmap: Option<Vec<u8>>,
+    options: RenderOptions,
+}
+
+impl RenderTask {
+    fn render(&self) -> crate::Result<Vec<u8>> {
+        let options = &self.options;
+        let colormap = ColorMap::from_str(options.colormap.as_deref().unwrap_or("red")).map_err(Error::InvalidOption)?;
+        let normalization = Normalization::from_str(options.normalization.as_deref().unwrap_or("minmax"))
+            .map_err(Error::InvalidOption)?;
+        let mode = BlendMode::from_str(options.blend.as_deref().unwrap_or("alpha")).map_err(Error::InvalidOption)?;
+        let defaults = BlendOptions::default();
+
+        let mut builder = HeatmapPipeline::builder()
+            .source(ImageSource::DicomBytes(self.dicom.clone()))
+            .colormap(colormap)
+            .normalization(normalization)
+            .blend(BlendOptions {
+                opacity: options.opacity.map_or(defaults.opacity, |opacity| opacity as f32),
+                mode,
+                threshold: options.threshold.map(|threshold| threshold as f32),
+            });
+        if let Some(heatmap) = &self.heatmap {
+            let format = options.heatmap_format.clone().unwrap_or_else(|| "json".to_string());
+            builder = builder.heatmap(HeatmapInput::Bytes { bytes: heatmap.clone(), format });
+        }
+
+        let result = builder.build()?.run()?;
+        encode_png(&result.image)
+    }
+}
+
+impl Task for RenderTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        self.render().map_err(to_napi_error)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.into())
+    }
+}
+
+/// Map a pipeline error to a JS error whose `code` is the error kind (e.g. "dicom_decode")
+fn to_napi_error(error: Error) -> napi::Error {
+    let status = match error {
+        Error::InvalidOption(_) => Status::InvalidArg,
+        _ => Status::GenericFailure,
+    };
+    napi::Error::new(status, format!("{}: {}", error.kind(), error))
+}
+
+/// Decode a DICOM buffer, overlay the heatmap buffer (if any) and resolve with the fused PNG
+#[napi(js_name = "renderOverlay")]
+pub fn render_overlay(dicom: Buffer, heatmap: Option<Buffer>, options: Option<RenderOptions>) -> AsyncTask<RenderTask> {
+    AsyncTask::new(RenderTask {
+        dicom: dicom.to_vec(),
+        heatmap: heatmap.map(|heatmap| heatmap.to_vec()).filter(|heatmap| !heatmap.is_empty()),
+        options: options.unwrap_or_default(),
+    })
+}
+
+/// Library version
+#[napi]
+pub fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
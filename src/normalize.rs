@@ -0,0 +1,144 @@
+//! Normalization of raw heatmap values prior to colorization.
+
+use ndarray::{Array2, ArrayViewMut2};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::simd::{mean_std, min_max, scale_in_place};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    MinMax,
+    ZScore,
+    Percentile,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "minmax" => Ok(Normalization::MinMax),
+            "zscore" => Ok(Normalization::ZScore),
+            "percentile" => Ok(Normalization::Percentile),
+            _ => Err(format!("Unknown normalization: {}. Available: minmax, zscore, percentile", s)),
+        }
+    }
+}
+
+/// Normalize heatmap data using different methods into a new array
+pub fn normalize_heatmap(data: &Array2<f32>, method: &Normalization) -> Array2<f32> {
+    let mut normalized = data.as_standard_layout().into_owned();
+    normalize_in_place(normalized.view_mut(), method, &mut Vec::new());
+    normalized
+}
+
+/// Normalize heatmap values in place, as [`normalize_heatmap`] would; percentile normalization
+/// selects its percentiles from a copy of the values in `scratch`. The scans and maps over the
+/// values are vectorized.
+pub fn normalize_in_place(mut data: ArrayViewMut2<f32>, method: &Normalization, scratch: &mut Vec<f32>) {
+    let Some(values) = data.as_slice_mut() else {
+        let normalized = normalize_heatmap(&data.to_owned(), method);
+        data.assign(&normalized);
+        return;
+    };
+    match method {
+        Normalization::MinMax => {
+            let (min_val, max_val) = min_max(values);
+            let range = max_val - min_val;
+            
+            if range != 0.0 {
+                scale_in_place(values, min_val, range, false);
+            }
+        }
+        Normalization::ZScore => {
+            let (mean, std_dev) = mean_std(values).unwrap_or((0.0, 1.0));
+            
+            if std_dev != 0.0 {
+                scale_in_place(values, mean, std_dev, false);
+            }
+        }
+        Normalization::Percentile => {
+            scratch.clear();
+            scratch.extend_from_slice(values);
+            
+            let len = scratch.len();
+            let p5_idx = (0.05 * len as f32) as usize;
+            let p95_idx = (0.95 * len as f32) as usize;
+            
+            // The values a full sort would put at both indices, with NaN values last as in `resized_normalizer`
+            let (below, &mut p95_val, _) = scratch.select_nth_unstable_by(p95_idx, f32::total_cmp);
+            let p5_val = match p5_idx < p95_idx {
+                true => *below.select_nth_unstable_by(p5_idx, f32::total_cmp).1,
+                false => p95_val,
+            };
+            let range = p95_val - p5_val;
+            
+            if range != 0.0 {
+                scale_in_place(values, p5_val, range, true);
+            }
+        }
+    }
+}
+
+/// Affine map `(x - offset) / scale` to normalized values, clamped to 0.0-1.0 for percentile
+/// normalization
+#[cfg(any(feature = "png", feature = "gpu"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Normalizer {
+    pub(crate) offset: f32,
+    pub(crate) scale: f32,
+    pub(crate) clamp: bool,
+}
+
+#[cfg(feature = "png")]
+impl Normalizer {
+    pub(crate) fn apply(self, value: f32) -> f32 {
+        let scaled = (value - self.offset) / self.scale;
+        if self.clamp { scaled.clamp(0.0, 1.0) } else { scaled }
+    }
+}
+
+/// Normalization of `data` as [`normalize_heatmap`] would compute it over the heatmap resized
+/// with the source `rows` and `cols` of `resize_indices`, from each source value and the number
+/// of pixels it covers; None when the values are all the same and are drawn unchanged
+#[cfg(any(feature = "png", feature = "gpu"))]
+pub(crate) fn resized_normalizer(data: &Array2<f32>, rows: &[usize], cols: &[usize], method: &Normalization) -> Option<Normalizer> {
+    let counts = |indices: &[usize], len: usize| {
+        let mut counts = vec![0u64; len];
+        indices.iter().for_each(|&index| counts[index] += 1);
+        counts
+    };
+    let (row_counts, col_counts) = (counts(rows, data.nrows()), counts(cols, data.ncols()));
+    let weighted = || data.indexed_iter()
+        .map(|((row, col), &value)| (value, row_counts[row] * col_counts[col]))
+        .filter(|&(_, weight)| weight > 0);
+
+    let normalizer = match method {
+        Normalization::MinMax => {
+            let (min, max) = weighted().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), (value, _)| (min.min(value), max.max(value)));
+            Normalizer { offset: min, scale: max - min, clamp: false }
+        }
+        Normalization::ZScore => {
+            let total = (rows.len() * cols.len()) as f64;
+            let mean = (weighted().map(|(value, weight)| f64::from(value) * weight as f64).sum::<f64>() / total) as f32;
+            let variance = weighted()
+                .map(|(value, weight)| f64::from(value - mean).powi(2) * weight as f64)
+                .sum::<f64>() / total;
+            Normalizer { offset: mean, scale: variance.sqrt() as f32, clamp: false }
+        }
+        Normalization::Percentile => {
+            let mut values: Vec<(f32, u64)> = weighted().collect();
+            values.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let len = rows.len() * cols.len();
+            let at = |rank: usize| {
+                let mut seen = 0;
+                values.iter().find(|&&(_, weight)| { seen += weight; seen > rank as u64 }).map_or(0.0, |&(value, _)| value)
+            };
+            let (p5, p95) = (at((0.05 * len as f32) as usize), at((0.95 * len as f32) as usize));
+            Normalizer { offset: p5, scale: p95 - p5, clamp: true }
+        }
+    };
+    (normalizer.scale != 0.0).then_some(normalizer)
+}
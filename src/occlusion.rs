@@ -0,0 +1,143 @@
+//! Occlusion sensitivity: a model-agnostic heatmap of how far the class score drops while a patch
+//! of the image is masked, for black-box services and models without gradients.
+
+use image::{GrayImage, Luma};
+use ndarray::{s, Array2};
+use std::str::FromStr;
+
+/// Patches an image is occluded with, written as `patch=64,stride=32,fill=0`; every setting is
+/// optional
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OcclusionOptions {
+    /// Side of the masked square in image pixels; None takes an eighth of the longer side
+    pub patch: Option<u32>,
+    /// Step between patches; None takes half the patch, so each pixel is masked about four times
+    pub stride: Option<u32>,
+    /// Gray level of the mask; None takes the image's mean, which disturbs the model least
+    pub fill: Option<u8>,
+}
+
+impl FromStr for OcclusionOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = OcclusionOptions::default();
+        for item in s.split([',', ';']).map(str::trim).filter(|item| !item.is_empty() && *item != "default") {
+            let Some((key, value)) = item.split_once('=') else {
+                return Err(format!("expected key=value, found '{}'", item));
+            };
+            let (key, value) = (key.trim().to_lowercase(), value.trim());
+            let side = || match value.parse::<u32>() {
+                Ok(pixels) if pixels > 0 => Ok(pixels),
+                _ => Err(format!("{} must be a positive number of pixels, found '{}'", key, value)),
+            };
+            match key.as_str() {
+                "patch" => options.patch = Some(side()?),
+                "stride" => options.stride = Some(side()?),
+                "fill" if value == "mean" => options.fill = None,
+                "fill" => options.fill = Some(value.parse().map_err(|_| format!("fill must be a gray level from 0 to 255 or mean, found '{}'", value))?),
+                _ => return Err(format!("Unknown occlusion setting: {}. Available: patch, stride, fill", key)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+impl std::fmt::Display for OcclusionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(patch) = self.patch {
+            write!(f, "patch={},", patch)?;
+        }
+        if let Some(stride) = self.stride {
+            write!(f, "stride={},", stride)?;
+        }
+        match self.fill {
+            Some(fill) => write!(f, "fill={}", fill),
+            None => write!(f, "fill=mean"),
+        }
+    }
+}
+
+/// Patches one image is occluded with, and how their scores are assembled into a heatmap
+#[derive(Debug, Clone)]
+pub struct OcclusionPlan {
+    patch: u32,
+    stride: u32,
+    fill: u8,
+    /// Width and height of the image, which the heatmap is assembled at
+    size: (u32, u32),
+    /// Top-left corners of the patches, row by row
+    corners: Vec<(u32, u32)>,
+}
+
+impl OcclusionPlan {
+    /// Patches every stride over `image`, the last ones in each row and column clipped at its edge
+    pub fn new(image: &GrayImage, options: &OcclusionOptions) -> Self {
+        let (width, height) = image.dimensions();
+        let longer = width.max(height).max(1);
+        let patch = options.patch.unwrap_or(longer.div_ceil(8)).clamp(1, longer);
+        let stride = options.stride.unwrap_or(patch.div_ceil(2)).max(1);
+        let fill = options.fill.unwrap_or_else(|| {
+            let sum: u64 = image.as_raw().iter().map(|&value| u64::from(value)).sum();
+            (sum / image.as_raw().len().max(1) as u64) as u8
+        });
+        let starts = |side: u32| (0..side.max(1)).step_by(stride as usize).collect::<Vec<_>>();
+        let (xs, ys) = (starts(width), starts(height));
+        let corners = ys.iter().flat_map(|&y| xs.iter().map(move |&x| (x, y))).collect();
+        OcclusionPlan { patch, stride, fill, size: (width, height), corners }
+    }
+
+    /// Number of occluded copies to score
+    pub fn len(&self) -> usize {
+        self.corners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.corners.is_empty()
+    }
+
+    pub fn patch(&self) -> u32 {
+        self.patch
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// Copy of `image` with patch `index` masked
+    pub fn occluded(&self, image: &GrayImage, index: usize) -> GrayImage {
+        let mut occluded = image.clone();
+        let (left, top) = self.corners[index];
+        for y in top..(top + self.patch).min(image.height()) {
+            for x in left..(left + self.patch).min(image.width()) {
+                occluded.put_pixel(x, y, Luma([self.fill]));
+            }
+        }
+        occluded
+    }
+
+    /// Heatmap of the image's size with each pixel holding the mean score drop from `baseline`
+    /// of the patches covering it, clipped at zero; `scores` are those of the occluded copies in
+    /// order
+    ///
+    /// Cells of a stride would reach past the image's right and bottom edges whenever the stride
+    /// doesn't divide its sides, and resizing them onto the image would shift the map.
+    pub fn assemble(&self, baseline: f32, scores: &[f32]) -> Array2<f32> {
+        let (width, height) = (self.size.0.max(1), self.size.1.max(1));
+        let mut drops = Array2::<f32>::zeros((height as usize, width as usize));
+        let mut counts = Array2::<f32>::zeros((height as usize, width as usize));
+        for (&(x, y), &score) in self.corners.iter().zip(scores) {
+            let (top, left) = (y as usize, x as usize);
+            let (bottom, right) = ((y + self.patch).min(height) as usize, (x + self.patch).min(width) as usize);
+            let drop = (baseline - score).max(0.0);
+            drops.slice_mut(s![top..bottom, left..right]).mapv_inplace(|value| value + drop);
+            counts.slice_mut(s![top..bottom, left..right]).mapv_inplace(|count| count + 1.0);
+        }
+        ndarray::Zip::from(&mut drops).and(&counts).for_each(|drop, &count| {
+            if count > 0.0 {
+                *drop /= count;
+            }
+        });
+        drops
+    }
+}
@@ -0,0 +1,198 @@
+//! Writing fused images to disk.
+
+use image::RgbaImage;
+use log::info;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::colormap::ColorMap;
+use crate::demo::{generate_demo_data, DemoOptions};
+use crate::error::{Error, Result};
+use crate::normalize::{normalize_in_place, Normalization};
+use crate::render::{blend_default_heatmap, blend_normalized, render_heatmap_overlay, BlendMode, BlendOptions};
+
+/// Render the heatmap overlay onto a decoded DICOM image and save it as PNG
+pub fn create_heatmap_with_real_data(
+    base_rgba_image: RgbaImage,
+    png_path: &Path,
+    heatmap_data: Option<Array2<f32>>,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    opacity: f32,
+) -> Result<()> {
+    let fused_image = render_heatmap_overlay(base_rgba_image, heatmap_data, colormap, normalization, opacity);
+
+    // Save the resulting image
+    save_png(&fused_image, png_path)?;
+
+    info!("Successfully created PNG with heatmap overlay on real DICOM data: {}", png_path.display());
+    
+    Ok(())
+}
+
+/// Render a demo overlay on a synthetic base image and save it as PNG
+pub fn create_demo_heatmap(
+    options: &DemoOptions,
+    png_path: &Path,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    opacity: f32,
+) -> Result<()> {
+    let (columns, rows) = (options.width, options.height);
+    info!("Creating demo heatmap with simulated data ({}x{}, pattern: {}, seed: {})", 
+          columns, rows, format!("{:?}", options.pattern).to_lowercase(), options.seed);
+    
+    let (mut base_rgba_image, heatmap_data) = generate_demo_data(options)?;
+
+    // Blend the demo heatmap with specified colormap into the base RGBA image
+    match heatmap_data {
+        Some(mut data) => {
+            let blend = BlendOptions { opacity, ..BlendOptions::default() };
+            normalize_in_place(data.view_mut(), normalization, &mut Vec::new());
+            blend_normalized(&mut base_rgba_image, &data, colormap, &blend);
+        }
+        None => blend_default_heatmap(&mut base_rgba_image, colormap, opacity, BlendMode::Alpha),
+    }
+
+    // Save the resulting image
+    save_png(&base_rgba_image, png_path)?;
+
+    info!("Successfully created demo PNG with {} heatmap overlay: {}", 
+          format!("{:?}", colormap).to_lowercase(), png_path.display());
+    info!("Note: Using simulated base image. Place a real DICOM file as 'sample.dcm' to process real medical data.");
+    
+    Ok(())
+}
+
+/// PNG compression effort, from none to the smallest files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    /// Stored deflate blocks: the fastest encode and the largest files
+    None,
+    /// fdeflate, a deflate encoder tuned for PNG; the image crate's default
+    #[default]
+    Fast,
+    /// zlib level 6: about half the size of `fast`, several times slower to encode
+    Balanced,
+    /// zlib level 9: slightly smaller files than `balanced`, much slower to encode
+    High,
+    /// zlib level 1-9
+    Level(u8),
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PngCompression::None),
+            "fast" => Ok(PngCompression::Fast),
+            "balanced" => Ok(PngCompression::Balanced),
+            "high" => Ok(PngCompression::High),
+            level => match level.parse() {
+                Ok(level @ 1..=9) => Ok(PngCompression::Level(level)),
+                _ => Err(format!("Unknown PNG compression: {}. Available: none, fast, balanced, high, 1-9", s)),
+            },
+        }
+    }
+}
+
+/// PNG row filter; `adaptive` picks the best one per row, the others apply one to every row
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    #[default]
+    Adaptive,
+}
+
+impl FromStr for PngFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PngFilter::None),
+            "sub" => Ok(PngFilter::Sub),
+            "up" => Ok(PngFilter::Up),
+            "avg" => Ok(PngFilter::Avg),
+            "paeth" => Ok(PngFilter::Paeth),
+            "adaptive" => Ok(PngFilter::Adaptive),
+            _ => Err(format!("Unknown PNG filter: {}. Available: none, sub, up, avg, paeth, adaptive", s)),
+        }
+    }
+}
+
+/// How fused images are PNG-encoded; the default matches the image crate's encoder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PngOptions {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+}
+
+impl PngOptions {
+    /// PNG encoder writing `width`×`height` RGBA pixels to `out` with these settings
+    pub(crate) fn encoder<W: Write>(&self, out: W, width: u32, height: u32) -> png::Encoder<'static, W> {
+        let mut encoder = png::Encoder::new(out, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        match self.compression {
+            PngCompression::None => encoder.set_compression(png::Compression::NoCompression),
+            PngCompression::Fast => encoder.set_compression(png::Compression::Fast),
+            PngCompression::Balanced => encoder.set_compression(png::Compression::Balanced),
+            PngCompression::High => encoder.set_compression(png::Compression::High),
+            PngCompression::Level(level) => encoder.set_deflate_compression(png::DeflateCompression::Level(level)),
+        }
+        encoder.set_filter(match self.filter {
+            PngFilter::None => png::Filter::NoFilter,
+            PngFilter::Sub => png::Filter::Sub,
+            PngFilter::Up => png::Filter::Up,
+            PngFilter::Avg => png::Filter::Avg,
+            PngFilter::Paeth => png::Filter::Paeth,
+            PngFilter::Adaptive => png::Filter::Adaptive,
+        });
+        encoder
+    }
+}
+
+/// Save an RGBA image as PNG
+pub fn save_png(image: &RgbaImage, png_path: &Path) -> Result<()> {
+    save_png_with(image, png_path, &PngOptions::default())
+}
+
+/// Save an RGBA image as PNG, encoded with `options`
+pub fn save_png_with(image: &RgbaImage, png_path: &Path, options: &PngOptions) -> Result<()> {
+    let file = std::fs::File::create(png_path).map_err(|e| Error::io(png_path, e))?;
+    let mut out = std::io::BufWriter::new(file);
+    write_png(image, &mut out, options).map_err(|e| match e {
+        png::EncodingError::IoError(source) => Error::io(png_path, source),
+        other => Error::Render(format!("failed to encode {}: {}", png_path.display(), other)),
+    })?;
+    out.flush().map_err(|e| Error::io(png_path, e))
+}
+
+/// Encode an RGBA image as PNG bytes
+pub fn encode_png(image: &RgbaImage) -> Result<Vec<u8>> {
+    encode_png_with(image, &PngOptions::default())
+}
+
+/// Encode an RGBA image as PNG bytes with `options`
+pub fn encode_png_with(image: &RgbaImage, options: &PngOptions) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    write_png(image, &mut bytes, options).map_err(|e| Error::Render(format!("failed to encode PNG: {}", e)))?;
+    Ok(bytes)
+}
+
+fn write_png(image: &RgbaImage, out: impl Write, options: &PngOptions) -> std::result::Result<(), png::EncodingError> {
+    let mut writer = options.encoder(out, image.width(), image.height()).write_header()?;
+    writer.write_image_data(image.as_raw())?;
+    writer.finish()
+}
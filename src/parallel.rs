@@ -0,0 +1,28 @@
+//! Row-parallel pixel loops and ordered maps: with the `parallel` feature rows and items are
+//! spread over the rayon thread pool, otherwise they run one after the other on the calling thread.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Call `fill` with the index and the elements of every `row_len`-long row of `data`
+pub(crate) fn for_each_row<T: Send>(data: &mut [T], row_len: usize, fill: impl Fn(usize, &mut [T]) + Sync + Send) {
+    if row_len == 0 {
+        return;
+    }
+    #[cfg(feature = "parallel")]
+    data.par_chunks_mut(row_len).enumerate().for_each(|(row, values)| fill(row, values));
+    #[cfg(not(feature = "parallel"))]
+    data.chunks_mut(row_len).enumerate().for_each(|(row, values)| fill(row, values));
+}
+
+/// `map` of every item, in the order of `items`
+#[cfg(all(feature = "parallel", feature = "dicom", feature = "fs"))]
+pub(crate) fn map_ordered<T: Sync, R: Send>(items: &[T], map: impl Fn(&T) -> R + Sync + Send) -> Vec<R> {
+    items.par_iter().map(map).collect()
+}
+
+/// `map` of every item, in the order of `items`
+#[cfg(all(not(feature = "parallel"), feature = "dicom", feature = "fs"))]
+pub(crate) fn map_ordered<T: Sync, R: Send>(items: &[T], map: impl Fn(&T) -> R + Sync + Send) -> Vec<R> {
+    items.iter().map(map).collect()
+}
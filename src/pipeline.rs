@@ -0,0 +1,2102 @@
+//! Builder-style API running the full decode → heatmap → render → output pipeline.
+
+use image::buffer::ConvertBuffer;
+use image::{Rgba, RgbaImage};
+use log::{info, warn};
+use ndarray::{Array2, Array3, Axis};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+#[cfg(feature = "async")]
+use crate::asynchronous::CpuPool;
+use crate::audit::{host_name, timestamp, AuditLog, AuditRecord, ModelRecord};
+use crate::buffers::BufferPool;
+use crate::cache::{DecodedImageCache, ImageKey};
+use crate::colormap::ColorMap;
+use crate::comparison::{compare_heatmaps, difference_layer, HeatmapComparison};
+#[cfg(feature = "service")]
+use crate::config::ServiceConfig;
+use crate::coverage::{activation_coverage, Coverage, CoverageOptions};
+use crate::decision::{Decision, OperatingPoints};
+use crate::demo::{generate_demo_data, DemoData, DemoOptions};
+#[cfg(feature = "dimse")]
+use crate::dicom_io::{decision_report, generate_uid, in_series, secondary_capture};
+use crate::dicom_io::{
+    check_frame, decode_dicom_frame, decode_dicom_values, frame_count, image_dimensions, instance_uids, object_sop_instance_uid, open_dicom,
+    open_dicom_bytes, open_dicom_header, open_dicom_header_bytes, pixel_spacing, slice_spacing, sop_instance_uid, DicomFile,
+};
+use crate::error::{Error, Result};
+use crate::evaluation::{evaluate_mask, load_label_mask, load_mask, MaskEvaluation, DEFAULT_MASK_THRESHOLD};
+#[cfg(feature = "gpu")]
+use crate::gpu::GpuRenderer;
+use crate::heatmap::{fuse_heatmaps, resize_heatmap_into, FusionMethod, HeatmapMetadata, HeatmapRegistry, LoadedHeatmap};
+use crate::histogram::{compute_histogram, draw_histogram_panel, Histogram, HistogramOptions, HistogramStage};
+use crate::hotspots::{find_hotspots, HotspotOptions, HotspotReport, DEFAULT_HOTSPOT_THRESHOLD};
+use crate::localization::{load_lesions, pointing_game, LesionAnnotations, PointingOptions, PointingResult};
+use crate::normalize::{normalize_in_place, Normalization};
+use crate::output::{save_png_with, PngOptions};
+use crate::parallel::map_ordered;
+#[cfg(feature = "plugins")]
+use crate::plugin::HeatmapPlugin;
+use crate::progress::{CancellationToken, ProgressSink, Stage};
+use crate::projection::{
+    grayscale, maximum_intensity_projection, peak_voxel, reformat, square_pixels, value_range, Plane, VoxelSpacing,
+};
+use crate::provenance::{sha256_file, sha256_values, HashedInput};
+use crate::quality::{check_quality, QualityOptions, QualityReport};
+use crate::regions::{region_stats, RegionMask, RegionReport};
+use crate::series::DicomSeries;
+#[cfg(feature = "service")]
+use crate::service::ServiceClient;
+use crate::render::{
+    blend_default_heatmap, blend_layer, blend_normalized, colorize_normalized, draw_annotations, generate_default_heatmap,
+    Annotation, Backend, BlendOptions,
+};
+use crate::slices::{slice_profile_of, SliceProfile, SliceRange};
+use crate::spec::{EnsembleMember, OverlaySpec, PipelineSpec, PluginSpec, RenderSidecar, SourceSpec};
+use crate::sweep::{sweep_thresholds, SweepOptions, ThresholdSweep};
+
+/// Where the base image comes from
+#[derive(Debug, Clone)]
+pub enum ImageSource {
+    /// DICOM file on disk
+    DicomFile(PathBuf),
+    /// DICOM Part 10 file contents already in memory
+    DicomBytes(Vec<u8>),
+    /// Pre-decoded image
+    Image(RgbaImage),
+    /// Synthetic demo data
+    Demo(DemoOptions),
+}
+
+/// Where the heatmap values come from
+#[derive(Debug, Clone)]
+pub enum HeatmapInput {
+    /// Heatmap file (.json, .csv, .bin, .npy)
+    File(PathBuf),
+    /// Heatmap file contents in memory, with their format name or extension (e.g. "json")
+    Bytes { bytes: Vec<u8>, format: String },
+    /// Heatmap values already in memory
+    Array(Array2<f32>),
+    /// Heatmap values with their metadata, e.g. from local inference
+    Loaded(Box<LoadedHeatmap>),
+    /// Heatmap returned by a DL service for the DICOM source; requires [`HeatmapPipeline::run_async`]
+    #[cfg(feature = "service")]
+    Service(Box<ServiceConfig>),
+    /// Heatmaps of several models, each with its weight, fused into one with [`fuse_heatmaps`]
+    Ensemble { members: Vec<(HeatmapInput, f32)>, fusion: FusionMethod },
+}
+
+/// Destination for the fused image
+#[derive(Debug, Clone)]
+pub enum OutputTarget {
+    Png(PathBuf),
+    /// JSON sidecar with the pipeline spec, heatmap summary and written files
+    Sidecar(PathBuf),
+    /// DICOM Enhanced SR with the classification decision and the region statistics, written
+    /// when the source is a DICOM and there is either
+    #[cfg(feature = "dimse")]
+    Report(PathBuf),
+    /// DICOM Secondary Capture of the fused image in a new series of the source's study, written
+    /// when the source is a DICOM; the slices rendered by [`HeatmapPipeline::run_series`] share
+    /// one series
+    #[cfg(feature = "dimse")]
+    SecondaryCapture(PathBuf),
+    /// JSON document with the hotspots of the heatmap, written when hotspot detection is enabled
+    /// and a heatmap was loaded
+    Hotspots(PathBuf),
+    /// Histogram of the heatmap values, as CSV for `.csv` paths and JSON otherwise, written when a
+    /// histogram is taken and a heatmap was loaded
+    Histogram(PathBuf),
+    /// Threshold sweep, as CSV for `.csv` paths and JSON otherwise, written when a sweep is run
+    /// and a heatmap was loaded
+    ThresholdSweep(PathBuf),
+    /// Slice profile of a volumetric heatmap, as CSV for `.csv` paths and JSON otherwise, written
+    /// once for the whole volume by [`HeatmapPipeline::run_volume`] and
+    /// [`HeatmapPipeline::run_series`]
+    SliceProfile(PathBuf),
+}
+
+/// Additional heatmap drawn over the primary one with its own colormap, e.g. another class or model
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub heatmap: HeatmapInput,
+    pub colormap: ColorMap,
+}
+
+/// Summary of the heatmap that was rendered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeatmapSummary {
+    /// Dimensions of the heatmap as loaded, before resizing (rows, cols)
+    pub source_shape: (usize, usize),
+    pub resized: bool,
+    /// Raw value range and mean before normalization
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Format details, absent for synthetic demo heatmaps
+    pub metadata: Option<HeatmapMetadata>,
+}
+
+/// Intermediate products of a run, kept when [`HeatmapPipelineBuilder::artifacts`] is enabled
+#[derive(Debug, Clone)]
+pub struct PipelineArtifacts {
+    /// Decoded base image as 8-bit grayscale (rows, cols), before the overlay
+    pub grayscale: Array2<u8>,
+    /// Heatmap resized to the image dimensions, before normalization
+    pub resized_heatmap: Option<Array2<f32>>,
+    /// Heatmap after normalization to 0.0-1.0
+    pub normalized_heatmap: Option<Array2<f32>>,
+    /// Colorized RGBA heatmap layer with opacity and threshold applied, before blending
+    pub layer: RgbaImage,
+}
+
+/// Structured result of a pipeline run
+#[derive(Debug, Clone)]
+pub struct PipelineResult {
+    /// Fused image with the heatmap overlay and annotations
+    pub image: RgbaImage,
+    pub width: u32,
+    pub height: u32,
+    /// None when no heatmap was supplied and the default gradient was drawn
+    pub heatmap: Option<HeatmapSummary>,
+    /// Overlay layers that were drawn, in order
+    pub overlays: Vec<HeatmapSummary>,
+    /// Files written by the configured outputs
+    pub outputs: Vec<PathBuf>,
+    /// Non-fatal problems that were worked around in lenient mode
+    pub warnings: Vec<String>,
+    /// Intermediate products, if requested
+    pub artifacts: Option<PipelineArtifacts>,
+    /// Decision at the configured operating points, when the heatmap came with a score
+    pub decision: Option<Decision>,
+    /// Scores against the ground-truth mask, when one was given
+    pub evaluation: Option<MaskEvaluation>,
+    /// Hotspots of the normalized heatmap, when detection is enabled and a heatmap was loaded
+    pub hotspots: Option<HotspotReport>,
+    /// Histogram of the heatmap values, when one is taken and a heatmap was loaded
+    pub histogram: Option<Histogram>,
+    /// Heatmap statistics per region of the region masks, when there are any and a heatmap was
+    /// loaded
+    pub regions: Option<RegionReport>,
+    /// Pointing-game outcome against the lesion annotations, when they were given
+    pub pointing: Option<PointingResult>,
+    /// Share of the high activation outside the anatomy mask, when one was given
+    pub coverage: Option<Coverage>,
+    /// Summary of the baseline heatmap, when one was loaded
+    pub baseline: Option<HeatmapSummary>,
+    /// Similarity of the heatmap to the baseline heatmap, when both were loaded
+    pub comparison: Option<HeatmapComparison>,
+    /// Area, regions and scores of the normalized heatmap at each level of the threshold sweep,
+    /// when one is run and a heatmap was loaded
+    pub threshold_sweep: Option<ThresholdSweep>,
+    /// Quality control of the input, when it is enabled
+    pub quality: Option<QualityReport>,
+    /// SHA-256 of the DICOM and heatmap inputs, when a sidecar, decision report or audit log
+    /// records them
+    pub inputs: Vec<HashedInput>,
+}
+
+/// Frames rendered with the slices of a volumetric heatmap, by [`HeatmapPipeline::run_volume`]
+#[derive(Debug, Clone)]
+pub struct VolumeResult {
+    /// One result per frame, in frame order
+    pub frames: Vec<PipelineResult>,
+    /// Statistics of each slice, ranked by their activation
+    pub profile: SliceProfile,
+    /// Files written for the whole volume, e.g. the slice profile
+    pub outputs: Vec<PathBuf>,
+}
+
+/// Planes of a volume through the peak of its heatmap, by [`HeatmapPipeline::run_volume_reformats`]
+#[derive(Debug, Clone)]
+pub struct ReformatResult {
+    /// Voxel (slice, row, column) of the image volume the planes cross at
+    pub center: [usize; 3],
+    /// The axial, coronal and sagittal results, in that order
+    pub planes: Vec<(Plane, PipelineResult)>,
+}
+
+/// Selected slices of an image volume and its heatmap, for projections and reformats
+struct SelectedVolume {
+    /// Rescaled values (slices, rows, cols) of the slices
+    images: Array3<f32>,
+    heatmap: Array3<f32>,
+    /// Spacing of the selected slices, e.g. doubled for every other slice
+    spacing: Option<VoxelSpacing>,
+    /// Index of each slice in the whole volume
+    slices: Vec<usize>,
+}
+
+/// File contents read ahead of the CPU-bound stages, e.g. asynchronously
+#[derive(Debug, Default)]
+pub(crate) struct Prefetched {
+    /// Contents of an `ImageSource::DicomFile`
+    pub source: Option<Vec<u8>>,
+    /// DICOM source already parsed, e.g. once for all of its frames
+    pub object: Option<Arc<DicomFile>>,
+    pub heatmap: PrefetchedHeatmap,
+    /// One entry per overlay, in order
+    pub overlays: Vec<PrefetchedHeatmap>,
+    pub baseline: PrefetchedHeatmap,
+}
+
+/// Heatmap input resolved ahead of the CPU-bound stages
+#[derive(Debug, Default)]
+pub(crate) struct PrefetchedHeatmap {
+    /// Contents of a `HeatmapInput::File`, or the read error
+    pub file: Option<Result<Vec<u8>>>,
+    /// Heatmap already fetched for a `HeatmapInput::Service`
+    pub fetched: Option<Result<LoadedHeatmap>>,
+    /// One entry per member of a `HeatmapInput::Ensemble`, in order
+    pub members: Vec<PrefetchedHeatmap>,
+}
+
+/// Optional sink, token and deadline carried by a pipeline
+#[derive(Clone, Default)]
+struct Monitor {
+    sink: Option<Arc<dyn ProgressSink>>,
+    cancellation: Option<CancellationToken>,
+    deadline: Option<Instant>,
+}
+
+impl Monitor {
+    /// Run one stage: check for cancellation and the deadline, then report its start and completion
+    fn stage<T>(&self, stage: Stage, work: impl FnOnce() -> Result<T>) -> Result<T> {
+        if let Some(token) = &self.cancellation {
+            token.check(stage)?;
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::Timeout { stage: stage.name() });
+        }
+        self.report(stage, 0.0);
+        #[cfg(feature = "telemetry")]
+        let value = crate::telemetry::in_span(stage.name(), work)?;
+        #[cfg(not(feature = "telemetry"))]
+        let value = work()?;
+        self.report(stage, 1.0);
+        Ok(value)
+    }
+
+    fn report(&self, stage: Stage, fraction: f32) {
+        if let Some(sink) = &self.sink {
+            sink.report(stage, fraction);
+        }
+    }
+}
+
+impl std::fmt::Debug for Monitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Monitor")
+            .field("sink", &self.sink.is_some())
+            .field("cancellation", &self.cancellation)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+/// Configured heatmap pipeline, created with [`HeatmapPipeline::builder`]
+#[derive(Debug, Clone)]
+pub struct HeatmapPipeline {
+    source: ImageSource,
+    frame: u32,
+    heatmap: Option<HeatmapInput>,
+    baseline: Option<HeatmapInput>,
+    normalization: Normalization,
+    colormap: ColorMap,
+    blend: BlendOptions,
+    annotations: Vec<Annotation>,
+    overlays: Vec<Overlay>,
+    outputs: Vec<OutputTarget>,
+    operating_points: Option<OperatingPoints>,
+    ground_truth: Option<PathBuf>,
+    hotspots: Option<HotspotOptions>,
+    histogram: Option<HistogramOptions>,
+    region_masks: Vec<RegionMask>,
+    pointing: Option<PointingOptions>,
+    coverage: Option<CoverageOptions>,
+    threshold_sweep: Option<SweepOptions>,
+    quality: Option<QualityOptions>,
+    audit: Option<AuditLog>,
+    slices: Option<SliceRange>,
+    /// Series UID and instance number of the Secondary Captures of a slice of a series
+    #[cfg(feature = "dimse")]
+    capture_series: Option<(String, u32)>,
+    lenient: bool,
+    keep_artifacts: bool,
+    registry: Arc<HeatmapRegistry>,
+    monitor: Monitor,
+    #[cfg(feature = "async")]
+    cpu_pool: CpuPool,
+    #[cfg(feature = "service")]
+    service_client: ServiceClient,
+    #[cfg(feature = "plugins")]
+    plugin: Option<Arc<HeatmapPlugin>>,
+    #[cfg(feature = "gpu")]
+    gpu: Option<Arc<GpuRenderer>>,
+    buffers: BufferPool,
+    image_cache: Option<Arc<DecodedImageCache>>,
+    image_scope: Option<String>,
+    png: PngOptions,
+}
+
+/// Builder for [`HeatmapPipeline`]
+#[derive(Debug, Clone, Default)]
+pub struct HeatmapPipelineBuilder {
+    source: Option<ImageSource>,
+    frame: u32,
+    heatmap: Option<HeatmapInput>,
+    baseline: Option<HeatmapInput>,
+    normalization: Option<Normalization>,
+    colormap: Option<ColorMap>,
+    blend: BlendOptions,
+    annotations: Vec<Annotation>,
+    overlays: Vec<Overlay>,
+    outputs: Vec<OutputTarget>,
+    operating_points: Option<OperatingPoints>,
+    ground_truth: Option<PathBuf>,
+    hotspots: Option<HotspotOptions>,
+    histogram: Option<HistogramOptions>,
+    region_masks: Vec<RegionMask>,
+    pointing: Option<PointingOptions>,
+    coverage: Option<CoverageOptions>,
+    threshold_sweep: Option<SweepOptions>,
+    quality: Option<QualityOptions>,
+    audit: Option<AuditLog>,
+    slices: Option<SliceRange>,
+    lenient: bool,
+    keep_artifacts: bool,
+    registry: Option<Arc<HeatmapRegistry>>,
+    monitor: Monitor,
+    #[cfg(feature = "async")]
+    cpu_pool: CpuPool,
+    #[cfg(feature = "service")]
+    service_client: Option<ServiceClient>,
+    #[cfg(feature = "plugins")]
+    plugin: Option<Arc<HeatmapPlugin>>,
+    /// Plugin a replayed spec was rendered with, which `plugin` has to match
+    spec_plugin: Option<PluginSpec>,
+    /// Backend a replayed spec was rendered on, which the pipeline renders on as well
+    spec_backend: Option<Backend>,
+    #[cfg(feature = "gpu")]
+    gpu: Option<Arc<GpuRenderer>>,
+    buffers: BufferPool,
+    image_cache: Option<Arc<DecodedImageCache>>,
+    image_scope: Option<String>,
+    png: PngOptions,
+}
+
+impl HeatmapPipelineBuilder {
+    pub fn source(mut self, source: ImageSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Frame of a multi-frame DICOM source to render, from 0; see [`HeatmapPipeline::run_frames`]
+    /// for all of them
+    pub fn frame(mut self, frame: u32) -> Self {
+        self.frame = frame;
+        self
+    }
+
+    /// Render only these frames or slices of a volume in [`HeatmapPipeline::run_frames`],
+    /// [`HeatmapPipeline::run_volume`], [`HeatmapPipeline::run_series`] and their projections and
+    /// reformats, instead of all of them
+    pub fn slices(mut self, slices: SliceRange) -> Self {
+        self.slices = Some(slices);
+        self
+    }
+
+    pub fn heatmap(mut self, heatmap: HeatmapInput) -> Self {
+        self.heatmap = Some(heatmap);
+        self
+    }
+
+    /// Render the difference of the heatmap from this baseline heatmap of the same image (e.g.
+    /// of the previous model version) in place of the heatmap, and record their similarity in
+    /// the result and sidecar
+    pub fn baseline(mut self, heatmap: HeatmapInput) -> Self {
+        self.baseline = Some(heatmap);
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+
+    pub fn colormap(mut self, colormap: ColorMap) -> Self {
+        self.colormap = Some(colormap);
+        self
+    }
+
+    pub fn blend(mut self, blend: BlendOptions) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    /// Shortcut for setting only the blend opacity
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.blend.opacity = opacity;
+        self
+    }
+
+    pub fn annotation(mut self, annotation: Annotation) -> Self {
+        self.annotations.push(annotation);
+        self
+    }
+
+    pub fn annotations(mut self, annotations: impl IntoIterator<Item = Annotation>) -> Self {
+        self.annotations.extend(annotations);
+        self
+    }
+
+    /// Draw another heatmap over the primary one, normalized and blended the same way
+    pub fn overlay(mut self, heatmap: HeatmapInput, colormap: ColorMap) -> Self {
+        self.overlays.push(Overlay { heatmap, colormap });
+        self
+    }
+
+    pub fn output(mut self, output: OutputTarget) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    /// Decide positive or negative from the heatmap's class score at these thresholds, recorded
+    /// in the result and sidecar and drawn in the top-left corner
+    pub fn operating_points(mut self, points: OperatingPoints) -> Self {
+        self.operating_points = Some(points).filter(|points| !points.is_empty());
+        self
+    }
+
+    /// Score the normalized heatmap against the ground-truth mask at `path` (PNG or `.npy`),
+    /// thresholded at the blend threshold or else 0.5, and record the scores in the result and
+    /// sidecar
+    pub fn ground_truth(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ground_truth = Some(path.into());
+        self
+    }
+
+    /// Detect the hotspots of the normalized heatmap, recorded in the result and sidecar and
+    /// written by `OutputTarget::Hotspots`
+    pub fn hotspots(mut self, options: HotspotOptions) -> Self {
+        self.hotspots = Some(options);
+        self
+    }
+
+    /// Summarize the normalized heatmap within each labeled region of this mask, recorded in the
+    /// result, sidecar and decision report
+    pub fn region_mask(mut self, mask: RegionMask) -> Self {
+        self.region_masks.push(mask);
+        self
+    }
+
+    pub fn region_masks(mut self, masks: impl IntoIterator<Item = RegionMask>) -> Self {
+        self.region_masks.extend(masks);
+        self
+    }
+
+    /// Score whether the maximum of the normalized heatmap falls on one of the annotated lesions
+    /// (the pointing game), recorded in the result and sidecar
+    pub fn pointing_game(mut self, options: PointingOptions) -> Self {
+        self.pointing = Some(options);
+        self
+    }
+
+    /// Threshold the normalized heatmap at each level of `options` and record the area above it,
+    /// its regions and, with a ground-truth mask, its scores in the result and sidecar, written by
+    /// `OutputTarget::ThresholdSweep`
+    pub fn threshold_sweep(mut self, options: SweepOptions) -> Self {
+        self.threshold_sweep = Some(options);
+        self
+    }
+
+    /// Check the decoded image and the DICOM source's attributes for blank or constant pixel
+    /// data, extreme exposure, missing critical attributes, a suspect aspect ratio and burned-in
+    /// annotations, recorded in the result and sidecar; with `options.gate` a failed check fails
+    /// the run with `Error::QualityControl` before any heatmap is loaded
+    pub fn quality_control(mut self, options: QualityOptions) -> Self {
+        self.quality = Some(options);
+        self
+    }
+
+    /// Measure the share of the high activation of the normalized heatmap outside the anatomy
+    /// mask of `options`, recorded in the result and sidecar; above the options' share it is
+    /// warned about, or with `options.strict` fails the run with `Error::QualityControl`
+    pub fn anatomy_coverage(mut self, options: CoverageOptions) -> Self {
+        self.coverage = Some(options);
+        self
+    }
+
+    /// Append an audit record of every run to `log`: who ran it and when, the SHA-256 of its
+    /// inputs and outputs, the models behind its heatmaps and its spec
+    pub fn audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    /// Take a histogram of the heatmap values, recorded in the result and sidecar, written by
+    /// `OutputTarget::Histogram` and drawn into the rendering if the options ask for a panel
+    pub fn histogram(mut self, options: HistogramOptions) -> Self {
+        self.histogram = Some(options);
+        self
+    }
+
+    /// Heatmap formats used for file inputs; defaults to the built-in JSON/CSV/binary registry
+    pub fn heatmap_registry(mut self, registry: Arc<HeatmapRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// HTTP client for `HeatmapInput::Service`, shared across pipelines to reuse connections
+    #[cfg(feature = "service")]
+    pub fn service_client(mut self, client: ServiceClient) -> Self {
+        self.service_client = Some(client);
+        self
+    }
+
+    /// Slots the CPU-bound stages of [`HeatmapPipeline::run_async`] wait for, shared across
+    /// pipelines to bound the renders running at once
+    #[cfg(feature = "async")]
+    pub fn cpu_pool(mut self, pool: CpuPool) -> Self {
+        self.cpu_pool = pool;
+        self
+    }
+
+    /// Post-process every normalized heatmap and its scores with `plugin` before it is colorized;
+    /// decisions are made on the scores it returns
+    #[cfg(feature = "plugins")]
+    pub fn plugin(mut self, plugin: Arc<HeatmapPlugin>) -> Self {
+        self.plugin = Some(plugin);
+        self
+    }
+
+    /// Resize, normalize, colorize and blend heatmaps with the compute shaders of `renderer`;
+    /// runs keeping their artifacts or using a plugin, which need the normalized heatmap on the
+    /// host, stay on the CPU
+    #[cfg(feature = "gpu")]
+    pub fn gpu(mut self, renderer: Arc<GpuRenderer>) -> Self {
+        self.gpu = Some(renderer);
+        self
+    }
+
+    /// Resize and normalize heatmaps in buffers taken from `pool` and hand them back once they
+    /// are rendered; share one pool between the pipelines of a server
+    pub fn buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.buffers = pool;
+        self
+    }
+
+    /// Look DICOM sources up in `cache` by SOP Instance UID before decoding their pixel data,
+    /// and store what is decoded there; share one cache between the pipelines that re-render
+    /// the same instances
+    pub fn image_cache(mut self, cache: Arc<DecodedImageCache>) -> Self {
+        self.image_cache = Some(cache);
+        self
+    }
+
+    /// Share cached images only with pipelines of the same `scope`, e.g. the digest of an
+    /// uploaded DICOM, when sources from untrusted callers may claim any SOP Instance UID
+    pub fn image_cache_scope(mut self, scope: impl Into<String>) -> Self {
+        self.image_scope = Some(scope.into());
+        self
+    }
+
+    /// Encode PNG outputs with `options`, e.g. a faster compression for large batch runs
+    pub fn png_options(mut self, options: PngOptions) -> Self {
+        self.png = options;
+        self
+    }
+
+    /// In lenient mode a heatmap that fails to load falls back to the default gradient and
+    /// undecodable DICOM pixel data falls back to a demo image of the same size, matching the CLI
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Return the grayscale base, resized and normalized heatmaps and colorized layer in the result
+    pub fn artifacts(mut self, keep: bool) -> Self {
+        self.keep_artifacts = keep;
+        self
+    }
+
+    /// Receive a report as each stage starts and finishes
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.monitor.sink = Some(sink);
+        self
+    }
+
+    /// Abort the run with `Error::Cancelled` at the next stage boundary once `token` is cancelled
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.monitor.cancellation = Some(token);
+        self
+    }
+
+    /// Fail with `Error::Timeout` at the next stage boundary after `deadline`; DL service calls
+    /// made by [`HeatmapPipeline::run_async`] are cut short at the deadline as well
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.monitor.deadline = Some(deadline);
+        self
+    }
+
+    /// Apply every parameter recorded in a spec; an empty source, heatmap (or ensemble) or list
+    /// of overlays leaves the current one, and a spec rendered on the CPU isn't replayed on a GPU
+    /// renderer
+    pub fn spec(mut self, spec: &PipelineSpec) -> Self {
+        match &spec.source {
+            Some(SourceSpec::Dicom { path }) => self.source = Some(ImageSource::DicomFile(path.clone())),
+            Some(SourceSpec::Demo(options)) => self.source = Some(ImageSource::Demo(options.clone())),
+            None => {}
+        }
+        self.frame = spec.frame;
+        self.slices = spec.slices;
+        if let Some(path) = &spec.heatmap {
+            self.heatmap = Some(HeatmapInput::File(path.clone()));
+        } else if !spec.ensemble.is_empty() {
+            self.heatmap = Some(HeatmapInput::Ensemble {
+                members: spec.ensemble.iter()
+                    .map(|member| (HeatmapInput::File(member.path.clone()), member.weight))
+                    .collect(),
+                fusion: spec.fusion.unwrap_or_default(),
+            });
+        }
+        if !spec.overlays.is_empty() {
+            self.overlays = spec.overlays.iter()
+                .map(|overlay| Overlay { heatmap: HeatmapInput::File(overlay.path.clone()), colormap: overlay.colormap.clone() })
+                .collect();
+        }
+        if let Some(path) = &spec.baseline {
+            self.baseline = Some(HeatmapInput::File(path.clone()));
+        }
+        self.normalization = Some(spec.normalization.clone());
+        self.colormap = Some(spec.colormap.clone());
+        self.blend = spec.blend.clone();
+        self.annotations = spec.annotations.clone();
+        self.operating_points = spec.operating_points.clone();
+        self.ground_truth = spec.ground_truth.clone();
+        self.hotspots = spec.hotspots.clone();
+        self.histogram = spec.histogram.clone();
+        self.region_masks = spec.region_masks.clone();
+        self.pointing = spec.pointing.clone();
+        self.coverage = spec.coverage.clone();
+        self.threshold_sweep = spec.threshold_sweep.clone();
+        self.quality = spec.quality.clone();
+        self.spec_plugin = spec.plugin.clone();
+        self.spec_backend = Some(spec.backend);
+        self.lenient = spec.lenient;
+        self
+    }
+
+    /// Validate the configuration and create the pipeline
+    pub fn build(self) -> Result<HeatmapPipeline> {
+        let source = self.source.ok_or_else(|| Error::InvalidOption("Pipeline requires an image source".to_string()))?;
+        if !(0.0..=1.0).contains(&self.blend.opacity) {
+            return Err(Error::InvalidOption("Opacity must be between 0.0 and 1.0".to_string()));
+        }
+        if let Some(threshold) = self.blend.threshold
+            && !(0.0..=1.0).contains(&threshold)
+        {
+            return Err(Error::InvalidOption("Threshold must be between 0.0 and 1.0".to_string()));
+        }
+        if self.histogram.as_ref().is_some_and(|options| options.bins == 0) {
+            return Err(Error::InvalidOption("Histogram bins must be greater than 0".to_string()));
+        }
+        if self.pointing.as_ref().is_some_and(|options| options.tolerance.is_nan() || options.tolerance < 0.0) {
+            return Err(Error::InvalidOption("Pointing-game tolerance must not be negative".to_string()));
+        }
+        if let Some(options) = &self.coverage {
+            if !(0.0..=1.0).contains(&options.max_outside) {
+                return Err(Error::InvalidOption("Coverage share outside the anatomy mask must be between 0.0 and 1.0".to_string()));
+            }
+            if options.threshold.is_some_and(|threshold| !(0.0..=1.0).contains(&threshold)) {
+                return Err(Error::InvalidOption("Coverage threshold must be between 0.0 and 1.0".to_string()));
+            }
+        }
+        if let Some(options) = &self.threshold_sweep {
+            options.check().map_err(Error::InvalidOption)?;
+        }
+        if let Some(recorded) = &self.spec_plugin {
+            #[cfg(feature = "plugins")]
+            let matches = self.plugin.as_ref().is_some_and(|plugin| plugin.sha256() == recorded.sha256);
+            #[cfg(not(feature = "plugins"))]
+            let matches = false;
+            if !matches {
+                return Err(Error::InvalidOption(format!(
+                    "Spec was rendered with plugin {} (SHA-256 {}); the same plugin is needed to replay it",
+                    recorded.name, recorded.sha256
+                )));
+            }
+        }
+        #[cfg(feature = "gpu")]
+        let gpu = self.gpu.filter(|_| self.spec_backend != Some(Backend::Cpu));
+        #[cfg(feature = "gpu")]
+        let on_gpu = gpu.is_some();
+        #[cfg(not(feature = "gpu"))]
+        let on_gpu = false;
+        if self.spec_backend == Some(Backend::Gpu) && !on_gpu {
+            return Err(Error::InvalidOption("Spec was rendered on the GPU backend; a GPU renderer is needed to replay it".to_string()));
+        }
+
+        Ok(HeatmapPipeline {
+            source,
+            frame: self.frame,
+            heatmap: self.heatmap,
+            baseline: self.baseline,
+            normalization: self.normalization.unwrap_or(Normalization::MinMax),
+            colormap: self.colormap.unwrap_or(ColorMap::Red),
+            blend: self.blend,
+            annotations: self.annotations,
+            overlays: self.overlays,
+            outputs: self.outputs,
+            operating_points: self.operating_points,
+            ground_truth: self.ground_truth,
+            hotspots: self.hotspots,
+            histogram: self.histogram,
+            region_masks: self.region_masks,
+            pointing: self.pointing,
+            coverage: self.coverage,
+            threshold_sweep: self.threshold_sweep,
+            quality: self.quality,
+            audit: self.audit,
+            slices: self.slices,
+            #[cfg(feature = "dimse")]
+            capture_series: None,
+            lenient: self.lenient,
+            keep_artifacts: self.keep_artifacts,
+            registry: self.registry.unwrap_or_default(),
+            monitor: self.monitor,
+            #[cfg(feature = "async")]
+            cpu_pool: self.cpu_pool,
+            #[cfg(feature = "service")]
+            service_client: self.service_client.unwrap_or_default(),
+            #[cfg(feature = "plugins")]
+            plugin: self.plugin,
+            #[cfg(feature = "gpu")]
+            gpu,
+            buffers: self.buffers,
+            image_cache: self.image_cache,
+            image_scope: self.image_scope,
+            png: self.png,
+        })
+    }
+}
+
+impl HeatmapPipeline {
+    pub fn builder() -> HeatmapPipelineBuilder {
+        HeatmapPipelineBuilder::default()
+    }
+
+    pub fn source(&self) -> &ImageSource {
+        &self.source
+    }
+
+    pub fn heatmap(&self) -> Option<&HeatmapInput> {
+        self.heatmap.as_ref()
+    }
+
+    pub fn overlays(&self) -> &[Overlay] {
+        &self.overlays
+    }
+
+    pub fn baseline(&self) -> Option<&HeatmapInput> {
+        self.baseline.as_ref()
+    }
+
+    pub fn heatmap_registry(&self) -> &Arc<HeatmapRegistry> {
+        &self.registry
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.monitor.deadline
+    }
+
+    /// Copy of this pipeline with its heatmap and overlays replaced, and optionally its colormap
+    #[cfg(feature = "service")]
+    pub(crate) fn with_layers(&self, heatmap: HeatmapInput, colormap: Option<ColorMap>, overlays: Vec<Overlay>) -> HeatmapPipeline {
+        HeatmapPipeline {
+            heatmap: Some(heatmap),
+            colormap: colormap.unwrap_or_else(|| self.colormap.clone()),
+            overlays,
+            ..self.clone()
+        }
+    }
+
+    /// Copy of this pipeline whose outputs have `suffix` appended to their file stems
+    pub(crate) fn with_output_suffix(mut self, suffix: &str) -> HeatmapPipeline {
+        let rename = |path: &PathBuf| {
+            let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+            let mut name = format!("{}{}", stem, suffix);
+            if let Some(extension) = path.extension() {
+                name = format!("{}.{}", name, extension.to_string_lossy());
+            }
+            path.with_file_name(name)
+        };
+        for output in &mut self.outputs {
+            *output = match output {
+                OutputTarget::Png(path) => OutputTarget::Png(rename(path)),
+                OutputTarget::Sidecar(path) => OutputTarget::Sidecar(rename(path)),
+                #[cfg(feature = "dimse")]
+                OutputTarget::Report(path) => OutputTarget::Report(rename(path)),
+                #[cfg(feature = "dimse")]
+                OutputTarget::SecondaryCapture(path) => OutputTarget::SecondaryCapture(rename(path)),
+                OutputTarget::Hotspots(path) => OutputTarget::Hotspots(rename(path)),
+                OutputTarget::Histogram(path) => OutputTarget::Histogram(rename(path)),
+                OutputTarget::ThresholdSweep(path) => OutputTarget::ThresholdSweep(rename(path)),
+                // Written once for all frames
+                OutputTarget::SliceProfile(path) => OutputTarget::SliceProfile(path.clone()),
+            };
+        }
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn cpu_pool(&self) -> &CpuPool {
+        &self.cpu_pool
+    }
+
+    #[cfg(feature = "service")]
+    pub(crate) fn client(&self) -> &ServiceClient {
+        &self.service_client
+    }
+
+    /// Serializable description of this pipeline, with file and demo inputs recorded; fails for
+    /// an ensemble or overlays mixing heatmap files with layers that cannot be recorded, which the
+    /// spec could not reproduce
+    pub fn spec(&self) -> Result<PipelineSpec> {
+        let source = match &self.source {
+            ImageSource::DicomFile(path) => Some(SourceSpec::Dicom { path: path.clone() }),
+            ImageSource::Demo(options) => Some(SourceSpec::Demo(options.clone())),
+            ImageSource::DicomBytes(_) | ImageSource::Image(_) => None,
+        };
+        let file = |input: &Option<HeatmapInput>| match input {
+            Some(HeatmapInput::File(path)) => Some(path.clone()),
+            _ => None,
+        };
+        let (ensemble, fusion) = match &self.heatmap {
+            Some(HeatmapInput::Ensemble { members, fusion }) => {
+                let paths = recorded_files(members.iter().map(|(member, _)| member), "Ensemble members")?;
+                let fusion = (!paths.is_empty()).then_some(*fusion);
+                let ensemble = paths.into_iter()
+                    .zip(members)
+                    .map(|(path, (_, weight))| EnsembleMember { path, weight: *weight })
+                    .collect();
+                (ensemble, fusion)
+            }
+            _ => (Vec::new(), None),
+        };
+        let overlays = recorded_files(self.overlays.iter().map(|overlay| &overlay.heatmap), "Overlays")?
+            .into_iter()
+            .zip(&self.overlays)
+            .map(|(path, overlay)| OverlaySpec { path, colormap: overlay.colormap.clone() })
+            .collect();
+        #[cfg(feature = "plugins")]
+        let plugin = self.plugin.as_ref().map(|plugin| PluginSpec {
+            name: plugin.name().to_string(),
+            path: plugin.path().map(|path| path.to_path_buf()),
+            sha256: plugin.sha256().to_string(),
+        });
+        #[cfg(not(feature = "plugins"))]
+        let plugin = None;
+        Ok(PipelineSpec {
+            source,
+            frame: self.frame,
+            slices: self.slices,
+            heatmap: file(&self.heatmap),
+            ensemble,
+            fusion,
+            overlays,
+            baseline: file(&self.baseline),
+            colormap: self.colormap.clone(),
+            normalization: self.normalization.clone(),
+            blend: self.blend.clone(),
+            annotations: self.annotations.clone(),
+            operating_points: self.operating_points.clone(),
+            ground_truth: self.ground_truth.clone(),
+            hotspots: self.hotspots.clone(),
+            histogram: self.histogram.clone(),
+            region_masks: self.region_masks.clone(),
+            pointing: self.pointing.clone(),
+            coverage: self.coverage.clone(),
+            threshold_sweep: self.threshold_sweep.clone(),
+            quality: self.quality.clone(),
+            plugin,
+            backend: if self.on_gpu() { Backend::Gpu } else { Backend::Cpu },
+            lenient: self.lenient,
+            ..PipelineSpec::default()
+        })
+    }
+
+    /// Run every stage and write the configured outputs
+    pub fn run(&self) -> Result<PipelineResult> {
+        self.run_prefetched(Prefetched::default())
+    }
+
+    /// Render every frame of a multi-frame DICOM source, decoded and rendered in parallel with the
+    /// `parallel` feature; the results come back in frame order, and the outputs of each frame
+    /// get a `_frame0000`, `_frame0001`, ... suffix. Other sources render as their only frame.
+    pub fn run_frames(&self) -> Result<Vec<PipelineResult>> {
+        let (object, frames) = self.open_frames()?;
+        let frames: Vec<u32> = self.selected_slices(frames.len())?.into_iter().map(|index| frames[index]).collect();
+        info!("Rendering {} frame(s)", frames.len());
+        map_ordered(&frames, |&frame| {
+            let pipeline = HeatmapPipeline { frame, ..self.clone() }.with_output_suffix(&format!("_frame{:04}", frame));
+            pipeline.run_prefetched(Prefetched { object: object.clone(), ..Prefetched::default() })
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Render every frame like [`HeatmapPipeline::run_frames`], each with its slice of `volume`
+    /// (slices, rows, cols) as the heatmap, which needs one slice per frame, and aggregate the
+    /// slices into a [`SliceProfile`] whose active pixels are at or above the blend threshold,
+    /// or else 0.5, of the volume's value range
+    pub fn run_volume(&self, volume: &Array3<f32>) -> Result<VolumeResult> {
+        let (object, frames) = self.open_frames()?;
+        if volume.len_of(Axis(0)) != frames.len() {
+            return Err(Error::ShapeMismatch {
+                context: "heatmap volume slices for the frames of the source".to_string(),
+                expected: frames.len(),
+                found: volume.len_of(Axis(0)),
+            });
+        }
+        let slices = self.selected_slices(frames.len())?;
+        info!("Rendering {} frame(s) with their heatmap slices", slices.len());
+        let profile = slice_profile_of(volume.view(), &slices, self.blend.threshold.unwrap_or(DEFAULT_MASK_THRESHOLD));
+        let frames = map_ordered(&slices, |&slice| {
+            let (frame, heatmap) = (frames[slice], HeatmapInput::Array(volume.index_axis(Axis(0), slice).to_owned()));
+            let pipeline = HeatmapPipeline { frame, heatmap: Some(heatmap), ..self.clone() }
+                .with_output_suffix(&format!("_frame{:04}", frame));
+            pipeline.run_prefetched(Prefetched { object: object.clone(), ..Prefetched::default() })
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        let outputs = self.save_slice_profile(&profile)?;
+        Ok(VolumeResult { frames, profile, outputs })
+    }
+
+    /// Render every instance of `series` in place of the source, each with its slice of `volume`
+    /// (slices, rows, cols) as the heatmap, which needs one slice per instance in the series'
+    /// order, and aggregate the slices like [`HeatmapPipeline::run_volume`]; the outputs of each
+    /// slice get a `_slice0000`, `_slice0001`, ... suffix
+    pub fn run_series(&self, series: &DicomSeries, volume: &Array3<f32>) -> Result<VolumeResult> {
+        if volume.len_of(Axis(0)) != series.len() {
+            return Err(Error::ShapeMismatch {
+                context: "heatmap volume slices for the instances of the series".to_string(),
+                expected: series.len(),
+                found: volume.len_of(Axis(0)),
+            });
+        }
+        let indices = self.selected_slices(series.len())?;
+        info!("Rendering {} slice(s) of series {} with their heatmap slices", indices.len(), series.series_instance_uid);
+        let profile = slice_profile_of(volume.view(), &indices, self.blend.threshold.unwrap_or(DEFAULT_MASK_THRESHOLD));
+        #[cfg(feature = "dimse")]
+        let capture_series = generate_uid();
+        let frames = map_ordered(&indices, |&index| {
+            let heatmap = HeatmapInput::Array(volume.index_axis(Axis(0), index).to_owned());
+            let pipeline = HeatmapPipeline {
+                source: ImageSource::DicomFile(series.slices[index].path.clone()),
+                frame: 0,
+                heatmap: Some(heatmap),
+                #[cfg(feature = "dimse")]
+                capture_series: Some((capture_series.clone(), index as u32 + 1)),
+                ..self.clone()
+            };
+            pipeline.with_output_suffix(&format!("_slice{:04}", index)).run()
+        })
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+        let outputs = self.save_slice_profile(&profile)?;
+        Ok(VolumeResult { frames, profile, outputs })
+    }
+
+    /// Render the maximum intensity projection of the frames of a multi-frame DICOM source across
+    /// `plane` in place of the source, with the projection of `volume` (slices, rows, cols), which
+    /// needs one slice per frame, as the heatmap; with a slice range only its slices are projected,
+    /// a slab MIP
+    ///
+    /// The frames are projected by their rescaled values and the projection scaled by its own
+    /// range; coronal and sagittal projections are stretched to square pixels when the source
+    /// declares its pixel and slice spacing.
+    pub fn run_volume_mip(&self, volume: &Array3<f32>, plane: Plane) -> Result<PipelineResult> {
+        self.run_projection(&self.frame_values(volume)?, plane)
+    }
+
+    /// Render the maximum intensity projection of `series` across `plane` like
+    /// [`HeatmapPipeline::run_volume_mip`], with the projection of `volume`, which needs one slice
+    /// per instance in the series' order, as the heatmap
+    pub fn run_series_mip(&self, series: &DicomSeries, volume: &Array3<f32>, plane: Plane) -> Result<PipelineResult> {
+        self.run_projection(&self.series_values(series, volume)?, plane)
+    }
+
+    /// Render the axial, coronal and sagittal planes of the frames of a multi-frame DICOM source
+    /// through the peak of `volume` (slices, rows, cols), which needs one slice per frame, each
+    /// with the same plane of `volume` as the heatmap; the outputs of each plane get an
+    /// `_axial`, `_coronal` or `_sagittal` suffix
+    ///
+    /// A heatmap of fewer rows or columns than the frames is cut where its grid covers the peak,
+    /// so both planes show the same anatomy. The three planes are scaled by the value range of the
+    /// whole volume and stretched to square pixels like [`HeatmapPipeline::run_volume_mip`]. With
+    /// a slice range the planes are those of its slices, through the peak among them.
+    pub fn run_volume_reformats(&self, volume: &Array3<f32>) -> Result<ReformatResult> {
+        self.run_reformats(&self.frame_values(volume)?)
+    }
+
+    /// Render the planes of `series` through the peak of `volume` like
+    /// [`HeatmapPipeline::run_volume_reformats`], with one slice of `volume` per instance in the
+    /// series' order
+    pub fn run_series_reformats(&self, series: &DicomSeries, volume: &Array3<f32>) -> Result<ReformatResult> {
+        self.run_reformats(&self.series_values(series, volume)?)
+    }
+
+    /// Rescaled values of the selected frames of the DICOM source, with their slices of `volume`
+    fn frame_values(&self, volume: &Array3<f32>) -> Result<SelectedVolume> {
+        let (Some(object), frames) = self.open_frames()? else {
+            return Err(Error::InvalidOption("Projections and reformats need a DICOM source".to_string()));
+        };
+        if volume.len_of(Axis(0)) != frames.len() {
+            return Err(Error::ShapeMismatch {
+                context: "heatmap volume slices for the frames of the source".to_string(),
+                expected: frames.len(),
+                found: volume.len_of(Axis(0)),
+            });
+        }
+        let slices = self.selected_slices(frames.len())?;
+        let (rows, columns) = image_dimensions(&object)?;
+        let frames = map_ordered(&slices, |&slice| decode_dicom_values(&object, rows, columns, frames[slice]))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let views: Vec<_> = frames.iter().map(|frame| frame.view()).collect();
+        let images = ndarray::stack(Axis(0), &views).map_err(|e| Error::Render(format!("Failed to stack the frames: {}", e)))?;
+        let spacing = pixel_spacing(&object).zip(slice_spacing(&object))
+            .map(|((row, column), slice)| VoxelSpacing { slice, row, column });
+        Ok(self.selected_volume(images, volume, spacing, slices))
+    }
+
+    /// Rescaled values of the selected instances of `series`, with their slices of `volume`
+    fn series_values(&self, series: &DicomSeries, volume: &Array3<f32>) -> Result<SelectedVolume> {
+        if volume.len_of(Axis(0)) != series.len() {
+            return Err(Error::ShapeMismatch {
+                context: "heatmap volume slices for the instances of the series".to_string(),
+                expected: series.len(),
+                found: volume.len_of(Axis(0)),
+            });
+        }
+        let slices = self.selected_slices(series.len())?;
+        let spacing = series.pixel_spacing.zip(series.slice_spacing)
+            .map(|((row, column), slice)| VoxelSpacing { slice, row, column });
+        Ok(self.selected_volume(series.load_values(&slices)?, volume, spacing, slices))
+    }
+
+    /// `images` of the selected `slices` with theirs of `volume`, and the spacing of every
+    /// `step`-th slice
+    fn selected_volume(&self, images: Array3<f32>, volume: &Array3<f32>, spacing: Option<VoxelSpacing>, slices: Vec<usize>) -> SelectedVolume {
+        let step = self.slices.map_or(1, |range| range.step) as f64;
+        SelectedVolume {
+            images,
+            heatmap: volume.select(Axis(0), &slices),
+            spacing: spacing.map(|spacing| VoxelSpacing { slice: spacing.slice * step, ..spacing }),
+            slices,
+        }
+    }
+
+    /// Render the projections of the images and the heatmap of `volume` across `plane` in place
+    /// of the source and the heatmap
+    fn run_projection(&self, volume: &SelectedVolume, plane: Plane) -> Result<PipelineResult> {
+        info!("Rendering the {} maximum intensity projection of {} slice(s)", plane.name(), volume.slices.len());
+        warn_unstretched(volume.spacing, &[plane]);
+        let image = maximum_intensity_projection(volume.images.view(), plane);
+        let range = value_range(&image);
+        self.run_view(&image, range, &maximum_intensity_projection(volume.heatmap.view(), plane), volume.spacing, plane)
+    }
+
+    /// Render the three planes of the images of `volume` through the peak of its heatmap, each
+    /// with the same plane of the heatmap
+    fn run_reformats(&self, volume: &SelectedVolume) -> Result<ReformatResult> {
+        let peak = peak_voxel(volume.heatmap.view())
+            .ok_or_else(|| Error::Render("The heatmap volume holds no values to reformat through".to_string()))?;
+        // The voxel of the frames inside the peak's heatmap voxel, at its center
+        let (_, rows, columns) = volume.images.dim();
+        let (_, heatmap_rows, heatmap_columns) = volume.heatmap.dim();
+        let scale = |index: usize, from: usize, to: usize| (((index as f64 + 0.5) * to as f64 / from as f64) as usize).min(to - 1);
+        let voxel = [peak[0], scale(peak[1], heatmap_rows, rows), scale(peak[2], heatmap_columns, columns)];
+        let center = [volume.slices[voxel[0]], voxel[1], voxel[2]];
+        info!("Reformatting {} slice(s) through slice {}, row {}, column {}, the heatmap's peak",
+              volume.slices.len(), center[0], center[1], center[2]);
+
+        let planes = [Plane::Axial, Plane::Coronal, Plane::Sagittal];
+        warn_unstretched(volume.spacing, &planes);
+        let range = value_range(&volume.images);
+        let planes = planes.into_iter()
+            .map(|plane| {
+                let axis = plane.normal().index();
+                let (image, heatmap) = (reformat(volume.images.view(), plane, voxel[axis]), reformat(volume.heatmap.view(), plane, peak[axis]));
+                let pipeline = self.clone().with_output_suffix(&format!("_{}", plane.name()));
+                Ok((plane, pipeline.run_view(&image, range, &heatmap, volume.spacing, plane)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ReformatResult { center, planes })
+    }
+
+    /// Render the `image` view in `plane`, scaled from `range` to 8 bits, with `heatmap` in place
+    /// of the source and the heatmap, both stretched to square pixels by `spacing`
+    fn run_view(&self, image: &Array2<f32>, range: (f32, f32), heatmap: &Array2<f32>, spacing: Option<VoxelSpacing>, plane: Plane) -> Result<PipelineResult> {
+        let square = |view: &Array2<f32>| match spacing {
+            Some(spacing) => square_pixels(view, plane.spacing(spacing)),
+            None => view.clone(),
+        };
+        let pipeline = HeatmapPipeline {
+            source: ImageSource::Image(grayscale(&square(image), range).convert()),
+            frame: 0,
+            heatmap: Some(HeatmapInput::Array(square(heatmap))),
+            ..self.clone()
+        };
+        pipeline.run()
+    }
+
+    /// Indices of the slices of a volume of `count` to render: those of the slice range, or all
+    fn selected_slices(&self, count: usize) -> Result<Vec<usize>> {
+        let Some(range) = &self.slices else {
+            return Ok((0..count).collect());
+        };
+        let slices = range.indices(count);
+        if slices.is_empty() {
+            return Err(Error::InvalidOption(format!("Slices {} select none of the {} slice(s) of the volume", range, count)));
+        }
+        info!("Selected {} of {} slice(s): {}", slices.len(), count, range);
+        Ok(slices)
+    }
+
+    /// Write `profile` to the configured slice profile outputs, returning the files written
+    fn save_slice_profile(&self, profile: &SliceProfile) -> Result<Vec<PathBuf>> {
+        let mut outputs = Vec::new();
+        for output in &self.outputs {
+            if let OutputTarget::SliceProfile(path) = output {
+                profile.save(path)?;
+                info!("Saved slice profile: {}", path.display());
+                outputs.push(path.clone());
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Parsed DICOM source, if it is one, and its frames
+    fn open_frames(&self) -> Result<(Option<Arc<DicomFile>>, Vec<u32>)> {
+        let object = match &self.source {
+            ImageSource::DicomFile(path) => Some(Arc::new(open_dicom(path)?)),
+            ImageSource::DicomBytes(bytes) => Some(Arc::new(open_dicom_bytes(bytes)?)),
+            ImageSource::Image(_) | ImageSource::Demo(_) => None,
+        };
+        let frames = (0..object.as_deref().map(frame_count).transpose()?.unwrap_or(1)).collect();
+        Ok((object, frames))
+    }
+
+    /// Run the pipeline, using already-read file contents where available
+    pub(crate) fn run_prefetched(&self, prefetched: Prefetched) -> Result<PipelineResult> {
+        let mut warnings = Vec::new();
+        let monitor = &self.monitor;
+
+        let object = prefetched.object;
+        let (mut base_image, demo_heatmap, quality) = monitor.stage(Stage::Decode, || {
+            let (base_image, demo_heatmap) = self.load_base_image(prefetched.source, object.clone(), &mut warnings)?;
+            let quality = self.quality.as_ref()
+                .map(|options| self.check_quality(options, &base_image, object.as_deref(), &mut warnings))
+                .transpose()?;
+            Ok((base_image, demo_heatmap, quality))
+        })?;
+        let (width, height) = base_image.dimensions();
+
+        let mut overlays = Vec::new();
+        let (heatmap_data, baseline, mask, region_masks, lesions, anatomy) = monitor.stage(Stage::Heatmap, || {
+            let heatmap_data = self.load_primary_heatmap(prefetched.heatmap, demo_heatmap, &mut warnings)?;
+            let baseline = self.load_baseline(prefetched.baseline, &mut warnings)?;
+            let mut prefetched_overlays = prefetched.overlays.into_iter();
+            for overlay in &self.overlays {
+                match self.load_heatmap(&overlay.heatmap, prefetched_overlays.next().unwrap_or_default()) {
+                    Ok(data) => overlays.push((data, &overlay.colormap)),
+                    Err(e) if self.lenient => {
+                        warn!("Failed to load overlay heatmap: {}", e);
+                        warnings.push(format!("overlay not loaded: {}", e));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let mask = self.load_ground_truth(&mut warnings)?;
+            let region_masks = self.load_region_masks(&mut warnings)?;
+            let lesions = self.load_lesions(&mut warnings)?;
+            Ok((heatmap_data, baseline, mask, region_masks, lesions, self.load_anatomy_mask(&mut warnings)?))
+        })?;
+
+        let mut summary = None;
+        let mut overlay_summaries = Vec::new();
+        let mut histogram = None;
+        let mut baseline_summary = None;
+        let (mut heatmap_data, baseline, overlays) = monitor.stage(Stage::Resize, || {
+            let overlays: Vec<(Array2<f32>, &ColorMap)> = overlays.into_iter()
+                .map(|(heatmap, colormap)| {
+                    let (data, overlay_summary) = fit_heatmap(heatmap, width, height, true, !self.on_gpu(), &self.buffers);
+                    overlay_summaries.push(overlay_summary);
+                    (data, colormap)
+                })
+                .collect();
+            let heatmap_data = heatmap_data.map(|heatmap| {
+                if let Some(options) = self.histogram.as_ref().filter(|options| options.stage == HistogramStage::Raw) {
+                    histogram = Some(compute_histogram(&heatmap.data, options.bins, HistogramStage::Raw));
+                }
+                let (data, heatmap_summary) = fit_heatmap(heatmap, width, height, self.heatmap.is_some(), !self.on_gpu(), &self.buffers);
+                summary = Some(heatmap_summary);
+                data
+            });
+            let baseline = baseline.map(|baseline| {
+                let (data, summary) = fit_heatmap(baseline, width, height, true, true, &self.buffers);
+                baseline_summary = Some(summary);
+                data
+            });
+            Ok((heatmap_data, baseline, overlays))
+        })?;
+
+        let (mut evaluation, mut hotspots, mut regions, mut pointing) = (None, None, None, None);
+        let (mut comparison, mut coverage, mut threshold_sweep) = (None, None, None);
+        let (artifacts, decision) = monitor.stage(Stage::Render, || {
+            let baseline = baseline.map(|data| self.normalize(data, baseline_summary.as_mut())).transpose()?;
+            let threshold = self.blend.threshold.unwrap_or(DEFAULT_MASK_THRESHOLD);
+            let mut evaluate = |normalized: &Array2<f32>| {
+                evaluation = mask.as_ref().map(|mask| evaluate_mask(normalized, mask, threshold));
+                regions = (!region_masks.is_empty()).then(|| region_stats(normalized, &region_masks, threshold));
+                hotspots = self.hotspots.as_ref().map(|options| self.detect_hotspots(normalized, options));
+                pointing = lesions.as_ref().zip(self.pointing.as_ref())
+                    .and_then(|(lesions, options)| pointing_game(normalized, lesions, options.tolerance));
+                coverage = anatomy.as_ref().zip(self.coverage.as_ref()).map(|(anatomy, options)| {
+                    activation_coverage(normalized, anatomy, options.threshold.unwrap_or(threshold), options.max_outside)
+                });
+                threshold_sweep = self.threshold_sweep.as_ref().map(|options| self.sweep(normalized, options, mask.as_ref()));
+                if let Some(options) = self.histogram.as_ref().filter(|options| options.stage == HistogramStage::Normalized) {
+                    histogram = Some(compute_histogram(normalized, options.bins, HistogramStage::Normalized));
+                }
+            };
+            let grayscale = self.keep_artifacts.then(|| {
+                Array2::from_shape_fn((height as usize, width as usize), |(y, x)| {
+                    base_image.get_pixel(x as u32, y as u32)[0]
+                })
+            });
+
+            // The layer is only built for the artifacts; otherwise it goes straight into the image
+            let (normalized, layer) = if let Some(data) = &heatmap_data {
+                info!("Using heatmap data with {} colormap and {} normalization",
+                      format!("{:?}", self.colormap).to_lowercase(),
+                      format!("{:?}", self.normalization).to_lowercase());
+                if self.blend_on_gpu(&mut base_image, data, &self.colormap)? {
+                    if let Some(data) = heatmap_data.take() {
+                        self.recycle(data);
+                    }
+                    (None, None)
+                } else if self.keep_artifacts || baseline.is_some() {
+                    // The artifacts keep the resized heatmap next to its normalized copy, and a
+                    // difference from the baseline is drawn from a layer
+                    let data = match self.keep_artifacts {
+                        true => data.clone(),
+                        false => heatmap_data.take().expect("heatmap data is present"),
+                    };
+                    let normalized = self.normalize(data, summary.as_mut())?;
+                    evaluate(&normalized);
+                    let layer = match &baseline {
+                        Some(baseline) => {
+                            comparison = Some(compare_heatmaps(&normalized, baseline));
+                            difference_layer(&normalized, baseline, &self.blend)
+                        }
+                        None => colorize_normalized(&normalized, &self.colormap, &self.blend),
+                    };
+                    blend_layer(&mut base_image, &layer, self.blend.mode);
+                    match self.keep_artifacts {
+                        true => (Some(normalized), Some(layer)),
+                        false => {
+                            self.recycle(normalized);
+                            (None, None)
+                        }
+                    }
+                } else {
+                    // Otherwise the resized heatmap is normalized in its own buffer
+                    let data = heatmap_data.take().expect("heatmap data is present");
+                    let normalized = self.normalize(data, summary.as_mut())?;
+                    evaluate(&normalized);
+                    blend_normalized(&mut base_image, &normalized, &self.colormap, &self.blend);
+                    self.recycle(normalized);
+                    (None, None)
+                }
+            } else {
+                info!("No heatmap data provided, generating default gradient with {} colormap",
+                      format!("{:?}", self.colormap).to_lowercase());
+                if mask.is_some() {
+                    warn!("Ground-truth mask not evaluated: no heatmap was loaded");
+                    warnings.push("ground-truth mask not evaluated: no heatmap was loaded".to_string());
+                }
+                if self.hotspots.is_some() {
+                    warn!("Hotspots not detected: no heatmap was loaded");
+                    warnings.push("hotspots not detected: no heatmap was loaded".to_string());
+                }
+                if self.histogram.is_some() {
+                    warn!("Histogram not taken: no heatmap was loaded");
+                    warnings.push("histogram not taken: no heatmap was loaded".to_string());
+                }
+                if !region_masks.is_empty() {
+                    warn!("Region statistics not computed: no heatmap was loaded");
+                    warnings.push("region statistics not computed: no heatmap was loaded".to_string());
+                }
+                if lesions.is_some() {
+                    warn!("Pointing game not scored: no heatmap was loaded");
+                    warnings.push("pointing game not scored: no heatmap was loaded".to_string());
+                }
+                if anatomy.is_some() {
+                    warn!("Anatomy coverage not measured: no heatmap was loaded");
+                    warnings.push("anatomy coverage not measured: no heatmap was loaded".to_string());
+                }
+                if self.threshold_sweep.is_some() {
+                    warn!("Threshold sweep not run: no heatmap was loaded");
+                    warnings.push("threshold sweep not run: no heatmap was loaded".to_string());
+                }
+                if baseline.is_some() {
+                    warn!("Heatmaps not compared: no heatmap was loaded");
+                    warnings.push("heatmaps not compared: no heatmap was loaded".to_string());
+                }
+                let layer = self.keep_artifacts.then(|| generate_default_heatmap(width, height, &self.colormap, self.blend.opacity));
+                match &layer {
+                    Some(layer) => blend_layer(&mut base_image, layer, self.blend.mode),
+                    None => blend_default_heatmap(&mut base_image, &self.colormap, self.blend.opacity, self.blend.mode),
+                }
+                (None, layer)
+            };
+            let decision = self.decide(summary.as_ref().and_then(|summary| summary.metadata.as_ref()));
+            if let Some(baseline) = baseline {
+                self.recycle(baseline);
+            }
+
+            for ((data, colormap), overlay_summary) in overlays.into_iter().zip(overlay_summaries.iter_mut()) {
+                let normalized = match self.blend_on_gpu(&mut base_image, &data, colormap)? {
+                    true => data,
+                    false => {
+                        let normalized = self.normalize(data, Some(overlay_summary))?;
+                        blend_normalized(&mut base_image, &normalized, colormap, &self.blend);
+                        normalized
+                    }
+                };
+                self.recycle(normalized);
+            }
+            if let Some(histogram) = &histogram {
+                if histogram.is_degenerate() {
+                    warn!("Heatmap is near-constant: {:.1}% of its values are in one of {} bins",
+                          histogram.peak_fraction * 100.0, histogram.counts.len());
+                    warnings.push(format!("heatmap is near-constant: {:.1}% of its values are in one bin", histogram.peak_fraction * 100.0));
+                }
+                if self.histogram.as_ref().is_some_and(|options| options.panel) {
+                    draw_histogram_panel(&mut base_image, histogram);
+                }
+            }
+            draw_annotations(&mut base_image, &self.annotations);
+            if let Some(decision) = &decision {
+                draw_annotations(&mut base_image, &[decision_label(decision, width, height)]);
+            }
+
+            let artifacts = grayscale.zip(layer).map(|(grayscale, layer)| PipelineArtifacts {
+                grayscale,
+                resized_heatmap: heatmap_data,
+                normalized_heatmap: normalized,
+                layer,
+            });
+            Ok((artifacts, decision))
+        })?;
+        if let Some(coverage) = coverage.as_ref().filter(|coverage| coverage.exceeded) {
+            if self.coverage.as_ref().is_some_and(|options| options.strict) {
+                return Err(Error::QualityControl(coverage.message()));
+            }
+            warn!("Heatmap may be misregistered: {}", coverage.message());
+            warnings.push(format!("heatmap may be misregistered: {}", coverage.message()));
+        }
+
+        let mut result = PipelineResult {
+            image: base_image,
+            width,
+            height,
+            heatmap: summary,
+            overlays: overlay_summaries,
+            outputs: Vec::new(),
+            warnings,
+            artifacts,
+            decision,
+            evaluation,
+            hotspots,
+            histogram,
+            regions,
+            pointing,
+            coverage,
+            baseline: baseline_summary,
+            comparison,
+            threshold_sweep,
+            quality,
+            inputs: match self.records_inputs() {
+                true => self.hash_inputs(),
+                false => Vec::new(),
+            },
+        };
+        result.outputs = monitor.stage(Stage::Encode, || self.write_outputs(&result))?;
+        if let Some(log) = &self.audit {
+            log.append(&self.audit_record(log, &result)?)?;
+            info!("Appended audit record: {}", log.path.display());
+        }
+        Ok(result)
+    }
+
+    /// Whether an output or the audit log records the digests of the inputs
+    fn records_inputs(&self) -> bool {
+        self.audit.is_some()
+            || self.outputs.iter().any(|output| match output {
+                OutputTarget::Sidecar(_) => true,
+                #[cfg(feature = "dimse")]
+                OutputTarget::Report(_) | OutputTarget::SecondaryCapture(_) => true,
+                _ => false,
+            })
+    }
+
+    /// SHA-256 of the source, heatmaps and masks of the run; inputs that can't be read are
+    /// recorded without a digest
+    fn hash_inputs(&self) -> Vec<HashedInput> {
+        let file = HashedInput::file;
+        let mut inputs = Vec::new();
+        match &self.source {
+            ImageSource::DicomFile(path) => inputs.push(file("source", path)),
+            ImageSource::DicomBytes(bytes) => inputs.push(HashedInput::bytes("source", bytes)),
+            ImageSource::Image(_) | ImageSource::Demo(_) => {}
+        }
+        if let Some(heatmap) = &self.heatmap {
+            hash_heatmap("heatmap", heatmap, &mut inputs);
+        }
+        for (index, overlay) in self.overlays.iter().enumerate() {
+            hash_heatmap(&format!("overlay {}", index + 1), &overlay.heatmap, &mut inputs);
+        }
+        if let Some(baseline) = &self.baseline {
+            hash_heatmap("baseline", baseline, &mut inputs);
+        }
+        if let Some(path) = &self.ground_truth {
+            inputs.push(file("ground_truth", path));
+        }
+        for mask in &self.region_masks {
+            inputs.push(file("region_mask", &mask.path));
+        }
+        if let Some(options) = &self.pointing {
+            inputs.push(file("lesions", &options.lesions));
+        }
+        if let Some(options) = &self.coverage {
+            inputs.push(file("anatomy_mask", &options.mask));
+        }
+        #[cfg(feature = "plugins")]
+        if let Some(plugin) = &self.plugin {
+            inputs.push(HashedInput {
+                role: "plugin".to_string(),
+                path: plugin.path().map(|path| path.to_path_buf()),
+                sha256: Some(plugin.sha256().to_string()),
+            });
+        }
+        inputs
+    }
+
+    /// Audit record of a run that produced `result`, hashing its outputs
+    fn audit_record(&self, log: &AuditLog, result: &PipelineResult) -> Result<AuditRecord> {
+        let layers = std::iter::once(("heatmap".to_string(), result.heatmap.as_ref()))
+            .chain(result.overlays.iter().enumerate().map(|(index, overlay)| (format!("overlay {}", index + 1), Some(overlay))))
+            .chain(std::iter::once(("baseline".to_string(), result.baseline.as_ref())));
+        let models = layers
+            .filter_map(|(layer, summary)| Some(ModelRecord::new(&layer, summary?.metadata.as_ref()?)))
+            .collect();
+        let uids = self.source_header()?.and_then(|header| instance_uids(&header).ok());
+        let outputs = result.outputs.iter()
+            .map(|path| Ok((path.clone(), sha256_file(path)?)))
+            .collect::<Result<_>>()?;
+        Ok(AuditRecord {
+            timestamp: timestamp(),
+            user: log.operator(),
+            host: host_name(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            study_instance_uid: uids.as_ref().map(|uids| uids.study.clone()),
+            series_instance_uid: uids.as_ref().map(|uids| uids.series.clone()),
+            sop_instance_uid: uids.map(|uids| uids.sop_instance),
+            inputs: result.inputs.clone(),
+            models,
+            parameters: self.spec()?,
+            outputs,
+            warnings: result.warnings.clone(),
+        })
+    }
+
+    /// Whether heatmaps are rendered by the GPU renderer, which resizes them itself
+    fn on_gpu(&self) -> bool {
+        #[cfg(feature = "plugins")]
+        if self.plugin.is_some() {
+            return false;
+        }
+        #[cfg(feature = "gpu")]
+        let gpu = self.gpu.is_some();
+        #[cfg(not(feature = "gpu"))]
+        let gpu = false;
+        // Analyses of the normalized heatmap need it on the CPU
+        let analyses = self.ground_truth.is_some()
+            || self.hotspots.is_some()
+            || !self.region_masks.is_empty()
+            || self.pointing.is_some()
+            || self.coverage.is_some()
+            || self.baseline.is_some()
+            || self.threshold_sweep.is_some()
+            || self.histogram.as_ref().is_some_and(|options| options.stage == HistogramStage::Normalized);
+        gpu && !self.keep_artifacts && !analyses
+    }
+
+    /// Hotspots of `normalized`, at the options' threshold or else the blend threshold, with areas
+    /// in mm² when the DICOM source records its pixel spacing
+    fn detect_hotspots(&self, normalized: &Array2<f32>, options: &HotspotOptions) -> HotspotReport {
+        let threshold = options.threshold.or(self.blend.threshold).unwrap_or(DEFAULT_HOTSPOT_THRESHOLD);
+        let header = self.source_header().unwrap_or_else(|e| {
+            warn!("Failed to read the DICOM header for hotspot areas: {}", e);
+            None
+        });
+        let spacing = header.as_ref().and_then(pixel_spacing);
+        let found = find_hotspots(normalized, threshold, options, spacing);
+        info!("Found {} hotspot(s) at threshold {}", found.len(), threshold);
+        HotspotReport {
+            sop_instance_uid: header.as_ref().and_then(|obj| object_sop_instance_uid(obj).ok()),
+            width: normalized.ncols() as u32,
+            height: normalized.nrows() as u32,
+            threshold,
+            pixel_spacing: spacing,
+            hotspots: found,
+        }
+    }
+
+    /// Quality control of the decoded base image and the attributes of the DICOM source, parsed
+    /// as `object` or else read again; fails when the options gate the run on a failed check
+    fn check_quality(&self, options: &QualityOptions, image: &RgbaImage, object: Option<&DicomFile>, warnings: &mut Vec<String>) -> Result<QualityReport> {
+        let header = match object {
+            Some(_) => None,
+            None => self.source_header().unwrap_or_else(|e| {
+                warn!("Failed to read the DICOM header for quality control: {}", e);
+                None
+            }),
+        };
+        let report = check_quality(image, object.or(header.as_ref()));
+        if report.passed() {
+            info!("Quality control passed");
+        } else if options.gate {
+            return Err(Error::QualityControl(report.summary()));
+        }
+        for flag in &report.flags {
+            warn!("Quality control: {}", flag.message);
+            warnings.push(format!("quality control: {}", flag.message));
+        }
+        Ok(report)
+    }
+
+    /// Threshold sweep of `normalized` over the levels of `options`, scored against the
+    /// ground-truth `mask` if there is one, with areas in mm² when the DICOM source records its
+    /// pixel spacing
+    fn sweep(&self, normalized: &Array2<f32>, options: &SweepOptions, mask: Option<&Array2<bool>>) -> ThresholdSweep {
+        let header = self.source_header().unwrap_or_else(|e| {
+            warn!("Failed to read the DICOM header for threshold sweep areas: {}", e);
+            None
+        });
+        let sweep = sweep_thresholds(normalized, options, mask, header.as_ref().and_then(pixel_spacing));
+        info!("Swept {} threshold level(s)", sweep.levels.len());
+        sweep
+    }
+
+    /// Attributes of a DICOM source up to its pixel data, None for other sources
+    fn source_header(&self) -> Result<Option<DicomFile>> {
+        match &self.source {
+            ImageSource::DicomFile(path) => open_dicom_header(path).map(Some),
+            ImageSource::DicomBytes(bytes) => open_dicom_header_bytes(bytes).map(Some),
+            ImageSource::Image(_) | ImageSource::Demo(_) => Ok(None),
+        }
+    }
+
+    /// Render `data` into the image on the GPU if the run does, returning whether it did
+    #[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
+    fn blend_on_gpu(&self, base_image: &mut RgbaImage, data: &Array2<f32>, colormap: &ColorMap) -> Result<bool> {
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = self.gpu.as_ref().filter(|_| self.on_gpu()) {
+            gpu.blend_heatmap(base_image, data, colormap, &self.normalization, &self.blend)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Normalize `data` in place, with a percentile scratch buffer from the pool, and post-process
+    /// it
+    fn normalize(&self, mut data: Array2<f32>, summary: Option<&mut HeatmapSummary>) -> Result<Array2<f32>> {
+        let mut scratch = match self.normalization {
+            Normalization::Percentile => self.buffers.take(data.len()),
+            _ => Vec::new(),
+        };
+        normalize_in_place(data.view_mut(), &self.normalization, &mut scratch);
+        self.buffers.give(scratch);
+        self.post_process(data, summary)
+    }
+
+    /// Hand the buffer of a heatmap that has been rendered back to the pool
+    fn recycle(&self, data: Array2<f32>) {
+        self.buffers.give(data.into_raw_vec_and_offset().0);
+    }
+
+    /// Normalized heatmap and the scores in its summary's metadata after the plugin, if any; the
+    /// values it returns are clamped to 0.0-1.0, and the plugin is recorded in the metadata
+    #[cfg_attr(not(feature = "plugins"), allow(unused_variables))]
+    fn post_process(&self, normalized: Array2<f32>, summary: Option<&mut HeatmapSummary>) -> Result<Array2<f32>> {
+        #[cfg(feature = "plugins")]
+        if let Some(plugin) = &self.plugin {
+            let mut normalized = normalized;
+            let metadata = summary.and_then(|summary| summary.metadata.as_mut());
+            // The class's `score` comes first, then the per-class scores in label order
+            let keys: Vec<String> = metadata.as_ref()
+                .map(|metadata| metadata.attributes.iter()
+                    .filter(|(key, value)| (*key == "score" || key.starts_with("score:")) && value.parse::<f32>().is_ok())
+                    .map(|(key, _)| key.clone())
+                    .collect())
+                .unwrap_or_default();
+            let mut scores: Vec<f32> = match &metadata {
+                Some(metadata) => keys.iter().map(|key| metadata.attributes[key].parse().unwrap_or_default()).collect(),
+                None => Vec::new(),
+            };
+            plugin.transform(&mut normalized, &mut scores)?;
+            normalized.mapv_inplace(|value| if value.is_finite() { value.clamp(0.0, 1.0) } else { 0.0 });
+            if let Some(metadata) = metadata {
+                for (key, score) in keys.into_iter().zip(scores) {
+                    metadata.attributes.insert(key, score.to_string());
+                }
+                metadata.attributes.insert("plugin".to_string(), plugin.name().to_string());
+                metadata.attributes.insert("plugin_sha256".to_string(), plugin.sha256().to_string());
+            }
+            return Ok(normalized);
+        }
+        Ok(normalized)
+    }
+
+    /// Decision at the operating points for the primary heatmap's score, if both are present
+    fn decide(&self, metadata: Option<&HeatmapMetadata>) -> Option<Decision> {
+        let (points, metadata) = (self.operating_points.as_ref()?, metadata?);
+        let decision = points.decide(metadata);
+        match &decision {
+            Some(decision) => info!("Decision: {}", decision.label()),
+            None => warn!("No decision made: the heatmap has no score, or its class no threshold"),
+        }
+        decision
+    }
+
+    /// Write the configured outputs of `result`, returning the files written
+    fn write_outputs(&self, result: &PipelineResult) -> Result<Vec<PathBuf>> {
+        let mut outputs = Vec::new();
+        for output in &self.outputs {
+            if let OutputTarget::Png(path) = output {
+                save_png_with(&result.image, path, &self.png)?;
+                info!("Saved fused PNG: {}", path.display());
+                outputs.push(path.clone());
+            }
+        }
+        #[cfg(feature = "dimse")]
+        for output in &self.outputs {
+            if let OutputTarget::Report(path) = output
+                && (result.decision.is_some() || result.regions.is_some())
+            {
+                let Some(source) = self.source_header()? else {
+                    warn!("No decision report written to {}: the source is not a DICOM", path.display());
+                    continue;
+                };
+                decision_report(&source, result.decision.as_ref(), result.regions.as_ref(), &result.inputs)?
+                    .write_to_file(path)
+                    .map_err(|e| Error::dicom("write decision report", e))?;
+                info!("Saved decision report: {}", path.display());
+                outputs.push(path.clone());
+            }
+            if let OutputTarget::SecondaryCapture(path) = output {
+                let Some(source) = self.source_header()? else {
+                    warn!("No Secondary Capture written to {}: the source is not a DICOM", path.display());
+                    continue;
+                };
+                let mut capture = secondary_capture(&source, &result.image, &result.inputs)?;
+                if let Some((series_uid, instance_number)) = &self.capture_series {
+                    in_series(&mut capture, &source, series_uid, *instance_number);
+                }
+                capture.write_to_file(path).map_err(|e| Error::dicom("write Secondary Capture", e))?;
+                info!("Saved Secondary Capture: {}", path.display());
+                outputs.push(path.clone());
+            }
+        }
+        for output in &self.outputs {
+            let path = match output {
+                OutputTarget::Hotspots(path) if let Some(hotspots) = &result.hotspots => {
+                    hotspots.save(path)?;
+                    info!("Saved hotspots: {}", path.display());
+                    path
+                }
+                OutputTarget::Histogram(path) if let Some(histogram) = &result.histogram => {
+                    histogram.save(path)?;
+                    info!("Saved histogram: {}", path.display());
+                    path
+                }
+                OutputTarget::ThresholdSweep(path) if let Some(sweep) = &result.threshold_sweep => {
+                    sweep.save(path)?;
+                    info!("Saved threshold sweep: {}", path.display());
+                    path
+                }
+                _ => continue,
+            };
+            outputs.push(path.clone());
+        }
+
+        // Sidecars go last so they can list the images written by this run
+        let sidecars: Vec<&PathBuf> = self.outputs.iter()
+            .filter_map(|output| match output {
+                OutputTarget::Sidecar(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        if !sidecars.is_empty() {
+            let sidecar = RenderSidecar {
+                spec: self.spec()?,
+                width: result.width,
+                height: result.height,
+                heatmap: result.heatmap.clone(),
+                overlays: result.overlays.clone(),
+                decision: result.decision.clone(),
+                evaluation: result.evaluation.clone(),
+                hotspots: result.hotspots.clone(),
+                histogram: result.histogram.clone(),
+                regions: result.regions.clone(),
+                pointing: result.pointing.clone(),
+                coverage: result.coverage.clone(),
+                baseline: result.baseline.clone(),
+                comparison: result.comparison.clone(),
+                threshold_sweep: result.threshold_sweep.clone(),
+                quality: result.quality.clone(),
+                inputs: result.inputs.clone(),
+                outputs: outputs.clone(),
+                warnings: result.warnings.clone(),
+            };
+            for path in sidecars {
+                sidecar.save(path)?;
+                info!("Saved sidecar: {}", path.display());
+                outputs.push(path.clone());
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// Produce the RGBA base image, plus the demo heatmap when the source is synthetic
+    fn load_base_image(&self, prefetched: Option<Vec<u8>>, object: Option<Arc<DicomFile>>, warnings: &mut Vec<String>) -> Result<DemoData> {
+        match &self.source {
+            ImageSource::Demo(options) => return generate_demo_data(options),
+            ImageSource::Image(image) => return Ok((image.clone(), None)),
+            ImageSource::DicomFile(_) | ImageSource::DicomBytes(_) => {}
+        }
+
+        // A cached image is looked up by the header alone, so the pixel data is never read
+        let key = match &self.image_cache {
+            Some(_) => self.image_key(object.as_deref(), prefetched.as_deref()),
+            None => None,
+        };
+        if let (Some(cache), Some(key)) = (&self.image_cache, &key)
+            && let Some(gray) = cache.get(key)
+        {
+            info!("Using the cached decoded image of instance {}", key.sop_instance);
+            return Ok((gray.convert(), None));
+        }
+
+        let obj = match object {
+            Some(object) => object,
+            None => Arc::new(match (&self.source, prefetched) {
+                (ImageSource::DicomFile(_), Some(bytes)) => open_dicom_bytes(&bytes)?,
+                (ImageSource::DicomFile(path), None) => open_dicom(path)?,
+                (ImageSource::DicomBytes(bytes), _) => open_dicom_bytes(bytes)?,
+                (ImageSource::Image(_) | ImageSource::Demo(_), _) => unreachable!("not a DICOM source"),
+            }),
+        };
+
+        check_frame(&obj, self.frame)?;
+        let (rows, columns) = image_dimensions(&obj)?;
+        info!("DICOM image dimensions: {}x{}", columns, rows);
+
+        match decode_dicom_frame(&obj, rows, columns, self.frame) {
+            Ok(gray) => {
+                info!("Successfully decoded DICOM pixel data");
+                let image = gray.convert();
+                if let (Some(cache), Some(key)) = (&self.image_cache, key) {
+                    cache.insert(key, Arc::new(gray));
+                }
+                Ok((image, None))
+            }
+            Err(e) if self.lenient => {
+                warn!("Failed to decode DICOM pixel data: {}", e);
+                warn!("Falling back to simulated data");
+                warnings.push(format!("pixel data not decoded, using simulated image: {}", e));
+                let options = DemoOptions { width: columns, height: rows, ..DemoOptions::default() };
+                let (image, _) = generate_demo_data(&options)?;
+                Ok((image, None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Image cache key of the DICOM source, from its parsed object or else its header alone;
+    /// None without a readable SOP Instance UID
+    fn image_key(&self, object: Option<&DicomFile>, prefetched: Option<&[u8]>) -> Option<ImageKey> {
+        let sop_instance = match (object, &self.source, prefetched) {
+            (Some(obj), _, _) => object_sop_instance_uid(obj),
+            (None, ImageSource::DicomFile(_), Some(bytes)) => sop_instance_uid(bytes),
+            (None, ImageSource::DicomFile(path), None) => open_dicom_header(path).and_then(|obj| object_sop_instance_uid(&obj)),
+            (None, ImageSource::DicomBytes(bytes), _) => sop_instance_uid(bytes),
+            (None, ImageSource::Image(_) | ImageSource::Demo(_), _) => return None,
+        };
+        sop_instance.ok()
+            .filter(|sop_instance| !sop_instance.is_empty())
+            .map(|sop_instance| ImageKey { sop_instance, frame: self.frame, scope: self.image_scope.clone() })
+    }
+
+    /// The ground-truth mask, if one is configured; in lenient mode one that fails to load is
+    /// skipped with a warning
+    fn load_ground_truth(&self, warnings: &mut Vec<String>) -> Result<Option<Array2<bool>>> {
+        let Some(path) = &self.ground_truth else {
+            return Ok(None);
+        };
+        match load_mask(path) {
+            Ok(mask) => Ok(Some(mask)),
+            Err(e) if self.lenient => {
+                warn!("Failed to load ground-truth mask: {}", e);
+                warnings.push(format!("ground-truth mask not loaded: {}", e));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The labels of every region mask; in lenient mode a mask that fails to load is skipped with
+    /// a warning
+    fn load_region_masks(&self, warnings: &mut Vec<String>) -> Result<Vec<(RegionMask, Array2<u32>)>> {
+        let mut masks = Vec::new();
+        for mask in &self.region_masks {
+            match load_label_mask(&mask.path) {
+                Ok(labels) => masks.push((mask.clone(), labels)),
+                Err(e) if self.lenient => {
+                    warn!("Failed to load region mask: {}", e);
+                    warnings.push(format!("region mask not loaded: {}", e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(masks)
+    }
+
+    /// The baseline heatmap, if one is configured; in lenient mode one that fails to load is
+    /// skipped with a warning, and the heatmap is rendered as usual
+    fn load_baseline(&self, prefetched: PrefetchedHeatmap, warnings: &mut Vec<String>) -> Result<Option<LoadedHeatmap>> {
+        let Some(input) = &self.baseline else {
+            return Ok(None);
+        };
+        match self.load_heatmap(input, prefetched) {
+            Ok(heatmap) => Ok(Some(heatmap)),
+            Err(e) if self.lenient => {
+                warn!("Failed to load baseline heatmap: {}", e);
+                warnings.push(format!("baseline heatmap not loaded: {}", e));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The lesion annotations of the pointing game, if it is configured; in lenient mode ones that
+    /// fail to load are skipped with a warning
+    fn load_lesions(&self, warnings: &mut Vec<String>) -> Result<Option<LesionAnnotations>> {
+        let Some(options) = &self.pointing else {
+            return Ok(None);
+        };
+        match load_lesions(&options.lesions) {
+            Ok(lesions) => Ok(Some(lesions)),
+            Err(e) if self.lenient => {
+                warn!("Failed to load lesion annotations: {}", e);
+                warnings.push(format!("lesion annotations not loaded: {}", e));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The anatomy mask of the coverage options, if any; in lenient mode a mask that fails to load
+    /// is skipped with a warning
+    fn load_anatomy_mask(&self, warnings: &mut Vec<String>) -> Result<Option<Array2<bool>>> {
+        let Some(options) = &self.coverage else {
+            return Ok(None);
+        };
+        match load_mask(&options.mask) {
+            Ok(mask) => Ok(Some(mask)),
+            Err(e) if self.lenient => {
+                warn!("Failed to load anatomy mask: {}", e);
+                warnings.push(format!("anatomy mask not loaded: {}", e));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The configured heatmap, falling back to none in lenient mode, or the demo heatmap
+    fn load_primary_heatmap(
+        &self,
+        prefetched: PrefetchedHeatmap,
+        demo_heatmap: Option<Array2<f32>>,
+        warnings: &mut Vec<String>,
+    ) -> Result<Option<LoadedHeatmap>> {
+        match &self.heatmap {
+            Some(input) => match self.load_heatmap(input, prefetched) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if self.lenient => {
+                    warn!("Failed to load heatmap data: {}", e);
+                    warn!("Proceeding without heatmap overlay");
+                    warnings.push(format!("heatmap not loaded: {}", e));
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            },
+            None => Ok(demo_heatmap.map(|data| LoadedHeatmap { data, metadata: HeatmapMetadata::default() })),
+        }
+    }
+
+    fn load_heatmap(&self, input: &HeatmapInput, prefetched: PrefetchedHeatmap) -> Result<LoadedHeatmap> {
+        #[allow(unused_variables)]
+        let PrefetchedHeatmap { file, fetched, members: prefetched_members } = prefetched;
+        match input {
+            HeatmapInput::File(path) => {
+                let heatmap = match file {
+                    Some(bytes) => {
+                        let format = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+                        self.registry.load_bytes(&bytes?, format, &path.display().to_string())?
+                    }
+                    None => self.registry.load(path)?,
+                };
+                info!("Successfully loaded heatmap data: {}x{}", heatmap.data.nrows(), heatmap.data.ncols());
+                Ok(heatmap)
+            }
+            HeatmapInput::Bytes { bytes, format } => {
+                let heatmap = self.registry.load_bytes(bytes, format, "in-memory heatmap")?;
+                info!("Successfully parsed heatmap data: {}x{}", heatmap.data.nrows(), heatmap.data.ncols());
+                Ok(heatmap)
+            }
+            HeatmapInput::Array(data) => Ok(LoadedHeatmap {
+                data: data.clone(),
+                metadata: HeatmapMetadata { format: "array".to_string(), shape: data.dim(), ..HeatmapMetadata::default() },
+            }),
+            HeatmapInput::Loaded(heatmap) => Ok(heatmap.as_ref().clone()),
+            #[cfg(feature = "service")]
+            HeatmapInput::Service(service) => fetched.unwrap_or_else(|| {
+                Err(Error::InvalidOption(format!("Heatmaps from service {} require HeatmapPipeline::run_async", service.name)))
+            }),
+            HeatmapInput::Ensemble { members, fusion } => {
+                let mut prefetched_members = prefetched_members.into_iter();
+                let heatmaps = members.iter()
+                    .map(|(member, weight)| {
+                        let heatmap = self.load_heatmap(member, prefetched_members.next().unwrap_or_default())?;
+                        Ok((heatmap, *weight))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                fuse_heatmaps(heatmaps, *fusion)
+            }
+        }
+    }
+}
+
+/// Warn that the coronal and sagittal views among `planes` keep one row per slice without a
+/// `spacing`
+fn warn_unstretched(spacing: Option<VoxelSpacing>, planes: &[Plane]) {
+    if spacing.is_none() && planes.iter().any(|&plane| plane != Plane::Axial) {
+        warn!("The source declares no pixel and slice spacing, so coronal and sagittal views aren't stretched to square pixels");
+    }
+}
+
+/// Paths of `layers` when every one is a heatmap file, none when no layer is, which the caller
+/// supplies again like any in-memory heatmap; `what` layers mixing both fail, as a spec could
+/// not reproduce them
+fn recorded_files<'a>(layers: impl ExactSizeIterator<Item = &'a HeatmapInput>, what: &str) -> Result<Vec<PathBuf>> {
+    let count = layers.len();
+    let paths: Vec<PathBuf> = layers
+        .filter_map(|layer| match layer {
+            HeatmapInput::File(path) => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+    if !paths.is_empty() && paths.len() < count {
+        return Err(Error::InvalidOption(format!(
+            "{} mix heatmap files with in-memory or DL service layers and cannot be recorded in a spec",
+            what
+        )));
+    }
+    Ok(paths)
+}
+
+/// Digests of the files, contents or values of a heatmap input; inputs fetched from a service
+/// are recorded by their model instead
+fn hash_heatmap(role: &str, input: &HeatmapInput, inputs: &mut Vec<HashedInput>) {
+    inputs.push(match input {
+        HeatmapInput::File(path) => HashedInput::file(role, path),
+        HeatmapInput::Bytes { bytes, .. } => HashedInput::bytes(role, bytes),
+        HeatmapInput::Array(data) => HashedInput { role: role.to_string(), path: None, sha256: Some(sha256_values(data.iter())) },
+        HeatmapInput::Loaded(heatmap) => HashedInput { role: role.to_string(), path: None, sha256: Some(sha256_values(heatmap.data.iter())) },
+        #[cfg(feature = "service")]
+        HeatmapInput::Service(_) => return,
+        HeatmapInput::Ensemble { members, .. } => {
+            for (member, _) in members {
+                hash_heatmap(role, member, inputs);
+            }
+            return;
+        }
+    });
+}
+
+/// Decision drawn in the top-left corner, red when positive and green when negative, in a font
+/// scaled with the image
+fn decision_label(decision: &Decision, width: u32, height: u32) -> Annotation {
+    let color = match decision.positive {
+        true => Rgba([255, 64, 64, 255]),
+        false => Rgba([64, 255, 64, 255]),
+    };
+    let scale = (width.min(height) / 256).max(1);
+    Annotation::Text { x: 4 * scale, y: 4 * scale, text: decision.label(), color, scale }
+}
+
+/// Resize a heatmap to the image dimensions if needed (and `resize` is set), into a buffer from
+/// `buffers` that the source values are handed back to, and summarize it as loaded
+fn fit_heatmap(
+    heatmap: LoadedHeatmap,
+    width: u32,
+    height: u32,
+    keep_metadata: bool,
+    resize: bool,
+    buffers: &BufferPool,
+) -> (Array2<f32>, HeatmapSummary) {
+    let LoadedHeatmap { data, metadata } = heatmap;
+    let source_shape = data.dim();
+    let resized = source_shape != (height as usize, width as usize);
+    let summary = HeatmapSummary {
+        source_shape,
+        resized,
+        min: data.iter().fold(f32::INFINITY, |a, &b| a.min(b)),
+        max: data.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)),
+        mean: data.mean().unwrap_or(0.0),
+        metadata: keep_metadata.then_some(metadata),
+    };
+
+    if resized {
+        warn!("Heatmap dimensions ({}x{}) don't match image dimensions ({}x{}), resizing...",
+              source_shape.0, source_shape.1, height, width);
+    }
+    if resized && resize {
+        let (width, height) = (width as usize, height as usize);
+        let resized = resize_heatmap_into(data.view(), width, height, buffers.take(width * height));
+        buffers.give(data.into_raw_vec_and_offset().0);
+        (resized, summary)
+    } else {
+        (data, summary)
+    }
+}
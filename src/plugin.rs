@@ -0,0 +1,146 @@
+//! Heatmap post-processing plugins: a user-supplied WebAssembly module transforms the normalized
+//! heatmap and its scores between normalization and rendering, sandboxed by wasmtime.
+//!
+//! A plugin exports its `memory` and two functions:
+//!
+//! - `heatmap_alloc(bytes: i32) -> i32`, returning the offset of `bytes` writable bytes
+//! - `heatmap_transform(heatmap: i32, rows: i32, cols: i32, scores: i32, count: i32) -> i32`,
+//!   rewriting in place the `rows`×`cols` row-major f32 heatmap at `heatmap` and the `count` f32
+//!   scores at `scores`, and returning 0 on success or an error code
+//!
+//! It gets no imports, so it can't reach the file system, network or clock, and every run gets a
+//! fresh instance with a fuel and memory budget.
+
+use ndarray::Array2;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::error::{Error, Result};
+
+/// Instructions (roughly) a run may execute before it is aborted, about a minute of work
+pub const DEFAULT_PLUGIN_FUEL: u64 = 10_000_000_000;
+
+/// Linear memory a run may grow to
+pub const DEFAULT_PLUGIN_MEMORY: usize = 1 << 30;
+
+const ALLOC_EXPORT: &str = "heatmap_alloc";
+const TRANSFORM_EXPORT: &str = "heatmap_transform";
+
+/// Compiled plugin module, cheap to clone and shared by every pipeline using it
+#[derive(Clone)]
+pub struct HeatmapPlugin {
+    engine: Engine,
+    module: Module,
+    name: String,
+    /// File the module was loaded from
+    path: Option<PathBuf>,
+    sha256: String,
+    fuel: u64,
+    max_memory: usize,
+}
+
+impl std::fmt::Debug for HeatmapPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeatmapPlugin")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .field("sha256", &self.sha256)
+            .field("fuel", &self.fuel)
+            .field("max_memory", &self.max_memory)
+            .finish()
+    }
+}
+
+impl HeatmapPlugin {
+    /// Compile the plugin at `path`, a `.wasm` binary or `.wat` text module
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut plugin = Self::from_bytes(name, &bytes)?;
+        plugin.path = Some(path.to_path_buf());
+        Ok(plugin)
+    }
+
+    /// Compile a plugin from its module bytes, checking that it exports the plugin functions
+    pub fn from_bytes(name: impl Into<String>, bytes: &[u8]) -> Result<Self> {
+        let name = name.into();
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| Error::Plugin(format!("Failed to start the WebAssembly engine: {}", e)))?;
+        let module = Module::new(&engine, bytes).map_err(|e| Error::Plugin(format!("Failed to compile plugin {}: {:#}", name, e)))?;
+        for export in ["memory", ALLOC_EXPORT, TRANSFORM_EXPORT] {
+            if module.get_export(export).is_none() {
+                return Err(Error::Plugin(format!("Plugin {} doesn't export {}", name, export)));
+            }
+        }
+        let sha256 = format!("{:x}", Sha256::digest(bytes));
+        Ok(HeatmapPlugin { engine, module, name, path: None, sha256, fuel: DEFAULT_PLUGIN_FUEL, max_memory: DEFAULT_PLUGIN_MEMORY })
+    }
+
+    /// Fuel each run gets; a run that uses it up fails
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    /// Bytes of linear memory each run may grow to
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// File name of the module, or the name it was created with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// File the module was loaded from, None when it was created from bytes
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// SHA-256 digest of the module bytes in lowercase hex
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Run the plugin on `heatmap` and `scores` in a fresh instance, replacing both with its
+    /// results; the shape of the heatmap stays the same
+    pub fn transform(&self, heatmap: &mut Array2<f32>, scores: &mut [f32]) -> Result<()> {
+        let fail = |e: wasmtime::Error| Error::Plugin(format!("Plugin {} failed: {}", self.name, e.root_cause()));
+        let limits = StoreLimitsBuilder::new().memory_size(self.max_memory).instances(1).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store.set_fuel(self.fuel).map_err(fail)?;
+        let instance = Instance::new(&mut store, &self.module, &[]).map_err(fail)?;
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or_else(|| Error::Plugin(format!("Plugin {} doesn't export its memory", self.name)))?;
+        let alloc: TypedFunc<i32, i32> = instance.get_typed_func(&mut store, ALLOC_EXPORT).map_err(fail)?;
+        let transform: TypedFunc<(i32, i32, i32, i32, i32), i32> = instance.get_typed_func(&mut store, TRANSFORM_EXPORT).map_err(fail)?;
+
+        let (rows, cols) = heatmap.dim();
+        let heatmap_bytes = heatmap.len() * 4;
+        let size = i32::try_from(heatmap_bytes + scores.len() * 4)
+            .map_err(|_| Error::Plugin(format!("Heatmap of {}x{} is too big for plugin {}", rows, cols, self.name)))?;
+        let offset = alloc.call(&mut store, size).map_err(fail)?;
+        let (heatmap_at, scores_at) = (offset as usize, offset as usize + heatmap_bytes);
+
+        let mut bytes = Vec::with_capacity(size as usize);
+        bytes.extend(heatmap.iter().flat_map(|value| value.to_le_bytes()));
+        bytes.extend(scores.iter().flat_map(|value| value.to_le_bytes()));
+        memory.write(&mut store, heatmap_at, &bytes)
+            .map_err(|_| Error::Plugin(format!("Plugin {} allocated {} bytes out of its memory", self.name, size)))?;
+
+        let code = transform.call(&mut store, (offset, rows as i32, cols as i32, scores_at as i32, scores.len() as i32)).map_err(fail)?;
+        if code != 0 {
+            return Err(Error::Plugin(format!("Plugin {} failed with code {}", self.name, code)));
+        }
+
+        memory.read(&store, heatmap_at, &mut bytes).map_err(|e| fail(e.into()))?;
+        let mut values = bytes.chunks_exact(4).map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        heatmap.iter_mut().zip(values.by_ref()).for_each(|(value, result)| *value = result);
+        scores.iter_mut().zip(values).for_each(|(score, result)| *score = result);
+        Ok(())
+    }
+}
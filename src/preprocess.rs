@@ -0,0 +1,398 @@
+//! Declarative preprocessing of the image a model sees (size, resize strategy, value range,
+//! normalization and channel order), so inference gets inputs prepared like the training data,
+//! and mapping of the heatmaps it returns back onto the original image.
+
+use image::{imageops, GrayImage, Luma};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How the image is brought to the input size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeStrategy {
+    /// Scale each axis to the input size, distorting the aspect ratio
+    #[default]
+    Stretch,
+    /// Scale to fit inside the input, keeping the aspect ratio, and pad the rest
+    Letterbox,
+    /// Scale to cover the input, keeping the aspect ratio, and cut the overhang evenly
+    Crop,
+}
+
+/// Resampling filter of the resize
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl Interpolation {
+    fn filter(self) -> imageops::FilterType {
+        match self {
+            Interpolation::Nearest => imageops::FilterType::Nearest,
+            Interpolation::Bilinear => imageops::FilterType::Triangle,
+            Interpolation::Bicubic => imageops::FilterType::CatmullRom,
+            Interpolation::Lanczos => imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Range 8-bit pixels are scaled to before normalization
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ValueRange {
+    /// 0.0 to 1.0
+    #[default]
+    #[serde(rename = "0-1")]
+    Unit,
+    /// 0.0 to 255.0
+    #[serde(rename = "0-255")]
+    Byte,
+    /// -1.0 to 1.0
+    #[serde(rename = "-1-1")]
+    Symmetric,
+}
+
+impl ValueRange {
+    fn scale(self, pixel: u8) -> f32 {
+        match self {
+            ValueRange::Unit => pixel as f32 / 255.0,
+            ValueRange::Byte => pixel as f32,
+            ValueRange::Symmetric => pixel as f32 / 127.5 - 1.0,
+        }
+    }
+}
+
+/// Order of the color channels the model was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    /// As loaded by OpenCV, e.g. for Caffe-style models
+    Bgr,
+}
+
+/// Memory order of an image tensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TensorLayout {
+    /// [batch, channels, height, width], as exported from PyTorch
+    #[default]
+    Nchw,
+    /// [batch, height, width, channels], as exported from TensorFlow
+    Nhwc,
+}
+
+/// How the image is prepared for a model, written as `key=value` pairs separated by `;`, e.g.
+/// `size=512x512;resize=letterbox;mean=0.485,0.456,0.406;std=0.229,0.224,0.225`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreprocessSpec {
+    /// Input (width, height); None keeps the model's input size, or for services the image size
+    pub size: Option<(u32, u32)>,
+    pub resize: ResizeStrategy,
+    pub interpolation: Interpolation,
+    /// Gray level of the letterbox padding
+    pub pad: u8,
+    pub range: ValueRange,
+    /// Per-channel mean subtracted after scaling, in RGB order, or one value for all channels
+    pub mean: Vec<f32>,
+    /// Per-channel standard deviation divided by after the mean, like `mean`
+    pub std: Vec<f32>,
+    pub channels: ChannelOrder,
+}
+
+/// Where the source image lies in the preprocessed input: input = source × scale + offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    /// (width, height) of the source image
+    pub source: (u32, u32),
+    /// (width, height) of the input
+    pub input: (u32, u32),
+    pub scale: (f32, f32),
+    /// Negative when the input is cropped
+    pub offset: (f32, f32),
+}
+
+impl PreprocessSpec {
+    /// Whether only the geometry is set, which is all an 8-bit image payload can carry
+    pub fn is_geometric(&self) -> bool {
+        self.range == ValueRange::default() && self.mean.is_empty() && self.std.is_empty() && self.channels == ChannelOrder::default()
+    }
+
+    /// Settings that only make sense together
+    pub fn check(&self, channels: usize) -> std::result::Result<(), String> {
+        for (name, values) in [("mean", &self.mean), ("std", &self.std)] {
+            if values.len() > 1 && values.len() != channels {
+                return Err(format!("{} has {} values for {} channel(s)", name, values.len(), channels));
+            }
+        }
+        if self.std.iter().any(|std| *std <= 0.0) {
+            return Err("std values must be above 0".to_string());
+        }
+        if self.size.is_some_and(|(width, height)| width == 0 || height == 0) {
+            return Err("size must be above 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Resize `image` to the spec's size, or else `size`, with the resize strategy
+    pub fn resize(&self, image: &GrayImage, size: (u32, u32)) -> (GrayImage, Placement) {
+        let (width, height) = self.size.unwrap_or(size);
+        let source = image.dimensions();
+        let (sx, sy) = (width as f32 / source.0.max(1) as f32, height as f32 / source.1.max(1) as f32);
+        let filter = self.interpolation.filter();
+        let placement = |scale: (f32, f32), offset: (f32, f32)| Placement { source, input: (width, height), scale, offset };
+        match self.resize {
+            ResizeStrategy::Stretch if source == (width, height) => (image.clone(), placement((1.0, 1.0), (0.0, 0.0))),
+            ResizeStrategy::Stretch => (imageops::resize(image, width, height, filter), placement((sx, sy), (0.0, 0.0))),
+            ResizeStrategy::Letterbox | ResizeStrategy::Crop => {
+                let scale = match self.resize {
+                    ResizeStrategy::Letterbox => sx.min(sy),
+                    _ => sx.max(sy),
+                };
+                let scaled_width = ((source.0 as f32 * scale).round() as u32).max(1);
+                let scaled_height = ((source.1 as f32 * scale).round() as u32).max(1);
+                let scaled = imageops::resize(image, scaled_width, scaled_height, filter);
+                let x = (width as i64 - scaled_width as i64) / 2;
+                let y = (height as i64 - scaled_height as i64) / 2;
+                let mut input = GrayImage::from_pixel(width, height, Luma([self.pad]));
+                imageops::replace(&mut input, &scaled, x, y);
+                (input, placement((scale, scale), (x as f32, y as f32)))
+            }
+        }
+    }
+
+    /// Values of a resized image for a float tensor with `channels` channels, interleaved when
+    /// `channels_last` and one plane per channel otherwise
+    pub fn tensor_values(&self, image: &GrayImage, channels: usize, channels_last: bool) -> Vec<f32> {
+        let stat = |values: &[f32], channel: usize, default: f32| match values.len() {
+            0 => default,
+            1 => values[0],
+            // Statistics are given in RGB order
+            _ => match self.channels {
+                ChannelOrder::Rgb => values[channel],
+                ChannelOrder::Bgr => values[channels - 1 - channel],
+            },
+        };
+        let normalized: Vec<Vec<f32>> = (0..channels)
+            .map(|channel| {
+                let (mean, std) = (stat(&self.mean, channel, 0.0), stat(&self.std, channel, 1.0));
+                let lut: Vec<f32> = (0..=255u8).map(|pixel| (self.range.scale(pixel) - mean) / std).collect();
+                image.as_raw().iter().map(|&pixel| lut[pixel as usize]).collect()
+            })
+            .collect();
+        match channels_last {
+            false => normalized.concat(),
+            true => (0..image.as_raw().len())
+                .flat_map(|index| normalized.iter().map(move |plane| plane[index]))
+                .collect(),
+        }
+    }
+
+    /// Part of a heatmap of the whole input that covers the source image, with the letterbox
+    /// padding cut off and the cropped margins filled with the heatmap minimum
+    pub fn restore(&self, heatmap: &Array2<f32>, placement: &Placement) -> Array2<f32> {
+        if placement.offset == (0.0, 0.0) {
+            return heatmap.clone();
+        }
+        let (rows, cols) = heatmap.dim();
+        let (kx, ky) = (cols as f32 / placement.input.0 as f32, rows as f32 / placement.input.1 as f32);
+        let x0 = placement.offset.0 * kx;
+        let y0 = placement.offset.1 * ky;
+        let width = ((placement.source.0 as f32 * placement.scale.0 * kx).round() as usize).max(1);
+        let height = ((placement.source.1 as f32 * placement.scale.1 * ky).round() as usize).max(1);
+        let fill = heatmap.iter().copied().fold(f32::INFINITY, f32::min);
+        Array2::from_shape_fn((height, width), |(row, col)| {
+            let (y, x) = ((y0 + row as f32).floor(), (x0 + col as f32).floor());
+            if y < 0.0 || x < 0.0 || y as usize >= rows || x as usize >= cols {
+                fill
+            } else {
+                heatmap[[y as usize, x as usize]]
+            }
+        })
+    }
+}
+
+impl FromStr for ResizeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "stretch" => Ok(ResizeStrategy::Stretch),
+            "letterbox" => Ok(ResizeStrategy::Letterbox),
+            "crop" => Ok(ResizeStrategy::Crop),
+            _ => Err(format!("Unknown resize strategy: {}. Available: stretch, letterbox, crop", s)),
+        }
+    }
+}
+
+impl ResizeStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ResizeStrategy::Stretch => "stretch",
+            ResizeStrategy::Letterbox => "letterbox",
+            ResizeStrategy::Crop => "crop",
+        }
+    }
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Interpolation::Nearest),
+            "bilinear" => Ok(Interpolation::Bilinear),
+            "bicubic" => Ok(Interpolation::Bicubic),
+            "lanczos" => Ok(Interpolation::Lanczos),
+            _ => Err(format!("Unknown interpolation: {}. Available: nearest, bilinear, bicubic, lanczos", s)),
+        }
+    }
+}
+
+impl Interpolation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Interpolation::Nearest => "nearest",
+            Interpolation::Bilinear => "bilinear",
+            Interpolation::Bicubic => "bicubic",
+            Interpolation::Lanczos => "lanczos",
+        }
+    }
+}
+
+impl FromStr for ValueRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "0-1" => Ok(ValueRange::Unit),
+            "0-255" => Ok(ValueRange::Byte),
+            "-1-1" => Ok(ValueRange::Symmetric),
+            _ => Err(format!("Unknown value range: {}. Available: 0-1, 0-255, -1-1", s)),
+        }
+    }
+}
+
+impl ValueRange {
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueRange::Unit => "0-1",
+            ValueRange::Byte => "0-255",
+            ValueRange::Symmetric => "-1-1",
+        }
+    }
+}
+
+impl FromStr for ChannelOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "rgb" => Ok(ChannelOrder::Rgb),
+            "bgr" => Ok(ChannelOrder::Bgr),
+            _ => Err(format!("Unknown channel order: {}. Available: rgb, bgr", s)),
+        }
+    }
+}
+
+impl ChannelOrder {
+    pub fn name(self) -> &'static str {
+        match self {
+            ChannelOrder::Rgb => "rgb",
+            ChannelOrder::Bgr => "bgr",
+        }
+    }
+}
+
+impl FromStr for TensorLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "nchw" => Ok(TensorLayout::Nchw),
+            "nhwc" => Ok(TensorLayout::Nhwc),
+            _ => Err(format!("Unknown tensor layout: {}. Available: nchw, nhwc", s)),
+        }
+    }
+}
+
+impl TensorLayout {
+    pub fn name(self) -> &'static str {
+        match self {
+            TensorLayout::Nchw => "nchw",
+            TensorLayout::Nhwc => "nhwc",
+        }
+    }
+
+    /// Dimensions of a tensor of `batch` images
+    pub fn dims(self, batch: usize, channels: usize, height: usize, width: usize) -> [usize; 4] {
+        match self {
+            TensorLayout::Nchw => [batch, channels, height, width],
+            TensorLayout::Nhwc => [batch, height, width, channels],
+        }
+    }
+}
+
+impl FromStr for PreprocessSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut spec = PreprocessSpec::default();
+        for pair in s.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| format!("expected key=value, found {}", pair))?;
+            let (key, value) = (key.trim().to_lowercase(), value.trim());
+            match key.as_str() {
+                "size" => {
+                    let (width, height) = value.split_once(['x', 'X']).unwrap_or((value, value));
+                    let dimension = |dim: &str| dim.trim().parse::<u32>().map_err(|_| format!("Invalid size: {}", value));
+                    spec.size = Some((dimension(width)?, dimension(height)?));
+                }
+                "resize" => spec.resize = value.parse()?,
+                "interpolation" => spec.interpolation = value.parse()?,
+                "pad" => spec.pad = value.parse().map_err(|_| format!("Invalid pad: {} (0-255)", value))?,
+                "range" => spec.range = value.parse()?,
+                "mean" | "std" => {
+                    let values = value.split(',')
+                        .map(|number| number.trim().parse::<f32>().map_err(|_| format!("Invalid {}: {}", key, value)))
+                        .collect::<std::result::Result<Vec<_>, _>>()?;
+                    if key == "mean" {
+                        spec.mean = values;
+                    } else {
+                        spec.std = values;
+                    }
+                }
+                "channels" => spec.channels = value.parse()?,
+                _ => {
+                    return Err(format!("Unknown preprocessing key: {}. Available: size, resize, interpolation, pad, range, mean, std, channels", key));
+                }
+            }
+        }
+        spec.check(spec.mean.len().max(spec.std.len()).max(1))?;
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for PreprocessSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((width, height)) = self.size {
+            write!(f, "size={}x{};", width, height)?;
+        }
+        write!(f, "resize={};interpolation={};pad={};range={}", self.resize.name(), self.interpolation.name(), self.pad, self.range.name())?;
+        let list = |values: &[f32]| values.iter().map(f32::to_string).collect::<Vec<_>>().join(",");
+        if !self.mean.is_empty() {
+            write!(f, ";mean={}", list(&self.mean))?;
+        }
+        if !self.std.is_empty() {
+            write!(f, ";std={}", list(&self.std))?;
+        }
+        write!(f, ";channels={}", self.channels.name())
+    }
+}
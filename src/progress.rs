@@ -0,0 +1,77 @@
+//! Progress reporting and cooperative cancellation for pipeline runs.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+
+/// Pipeline stages reported to a [`ProgressSink`], in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Opening the DICOM object and decoding its pixel data
+    Decode,
+    /// Reading and parsing the heatmap
+    Heatmap,
+    /// Resizing the heatmap to the image dimensions
+    Resize,
+    /// Normalizing, colorizing, blending and annotating
+    Render,
+    /// Encoding and writing the configured outputs
+    Encode,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Heatmap => "heatmap",
+            Stage::Resize => "resize",
+            Stage::Render => "render",
+            Stage::Encode => "encode",
+        }
+    }
+}
+
+/// Receives progress updates while a pipeline runs, e.g. to drive a progress bar
+pub trait ProgressSink: Send + Sync {
+    /// `fraction` is the completed share of `stage`: 0.0 when it starts, 1.0 when it finishes
+    fn report(&self, stage: Stage, fraction: f32);
+}
+
+impl<F> ProgressSink for F
+where
+    F: Fn(Stage, f32) + Send + Sync,
+{
+    fn report(&self, stage: Stage, fraction: f32) {
+        self(stage, fraction)
+    }
+}
+
+/// Shared flag checked between stages; cancelling makes the run fail with [`Error::Cancelled`]
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation; every clone of the token observes it
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with [`Error::Cancelled`] if cancellation was requested
+    pub fn check(&self, stage: Stage) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled { stage: stage.name() });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Projections and reformats of volumes for review: the maximum intensity projection (MIP) of an
+//! image volume and of its heatmap along one of the volume's axes, which summarizes the findings
+//! of a whole CT on a single image, e.g. the detections of a nodule model, and the axial, coronal
+//! and sagittal planes through a finding.
+
+use image::GrayImage;
+use ndarray::{Array2, ArrayView3, Axis};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Plane a volume of axial slices (slices, rows, cols) is viewed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Plane {
+    /// The plane of the slices, viewed across them
+    #[default]
+    Axial,
+    /// Across the rows, with the slices from top to bottom
+    Coronal,
+    /// Across the columns, with the slices from top to bottom
+    Sagittal,
+}
+
+impl FromStr for Plane {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "axial" | "z" => Ok(Plane::Axial),
+            "coronal" | "y" => Ok(Plane::Coronal),
+            "sagittal" | "x" => Ok(Plane::Sagittal),
+            _ => Err(format!("Unknown plane: {}. Available: axial, coronal, sagittal", s)),
+        }
+    }
+}
+
+impl Plane {
+    pub fn name(self) -> &'static str {
+        match self {
+            Plane::Axial => "axial",
+            Plane::Coronal => "coronal",
+            Plane::Sagittal => "sagittal",
+        }
+    }
+
+    /// Volume axis the plane is viewed across
+    pub fn normal(self) -> Axis {
+        match self {
+            Plane::Axial => Axis(0),
+            Plane::Coronal => Axis(1),
+            Plane::Sagittal => Axis(2),
+        }
+    }
+
+    /// Row and column spacing in mm of an image in the plane
+    pub fn spacing(self, voxel: VoxelSpacing) -> (f64, f64) {
+        match self {
+            Plane::Axial => (voxel.row, voxel.column),
+            Plane::Coronal => (voxel.slice, voxel.column),
+            Plane::Sagittal => (voxel.slice, voxel.row),
+        }
+    }
+}
+
+/// Size of the voxels of a volume in mm
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VoxelSpacing {
+    /// Distance of neighboring slices
+    pub slice: f64,
+    pub row: f64,
+    pub column: f64,
+}
+
+/// Maximum of `volume` (slices, rows, cols) across `plane`, with NaN values left out: (rows,
+/// cols) for the axial plane, (slices, cols) for the coronal and (slices, rows) for the sagittal
+/// one, which have the last slice at the top, the head for slices ordered from the feet up
+pub fn maximum_intensity_projection(volume: ArrayView3<f32>, plane: Plane) -> Array2<f32> {
+    // NaN.max(value) is the value, so only pixels without any value stay NaN
+    let projection = volume.fold_axis(plane.normal(), f32::NAN, |&max, &value| max.max(value));
+    upright(projection, plane)
+}
+
+/// Plane `index` of `volume` (slices, rows, cols) in `plane`, oriented like
+/// [`maximum_intensity_projection`]: slice `index` for the axial plane, row `index` for the
+/// coronal and column `index` for the sagittal one
+pub fn reformat(volume: ArrayView3<f32>, plane: Plane, index: usize) -> Array2<f32> {
+    upright(volume.index_axis(plane.normal(), index).to_owned(), plane)
+}
+
+/// `image` in `plane` with the last slice at the top
+fn upright(mut image: Array2<f32>, plane: Plane) -> Array2<f32> {
+    if plane != Plane::Axial {
+        image.invert_axis(Axis(0));
+    }
+    image
+}
+
+/// Voxel (slice, row, column) of the highest value of `volume`, the first of them on ties; None
+/// when it holds no value but NaN
+pub fn peak_voxel(volume: ArrayView3<f32>) -> Option<[usize; 3]> {
+    volume.indexed_iter()
+        .filter(|(_, value)| !value.is_nan())
+        .fold(None, |peak: Option<((usize, usize, usize), f32)>, (index, &value)| match peak {
+            Some((_, max)) if max >= value => peak,
+            _ => Some((index, value)),
+        })
+        .map(|((slice, row, column), _)| [slice, row, column])
+}
+
+/// `image` with its rows resampled by linear interpolation so its pixels, `spacing` (row,
+/// column) mm apart, are square, e.g. a coronal image of 5 mm slices with 0.7 mm columns
+pub fn square_pixels(image: &Array2<f32>, (row_spacing, column_spacing): (f64, f64)) -> Array2<f32> {
+    let (rows, cols) = image.dim();
+    let target = ((rows as f64 * row_spacing / column_spacing).round() as usize).max(1);
+    if target == rows || rows == 0 {
+        return image.clone();
+    }
+    Array2::from_shape_fn((target, cols), |(y, x)| {
+        let position = ((y as f64 + 0.5) * rows as f64 / target as f64 - 0.5).clamp(0.0, (rows - 1) as f64);
+        let (above, weight) = (position.floor() as usize, (position - position.floor()) as f32);
+        let below = (above + 1).min(rows - 1);
+        image[[above, x]] * (1.0 - weight) + image[[below, x]] * weight
+    })
+}
+
+/// Minimum and maximum of `values`, NaN values left out
+pub fn value_range<'a>(values: impl IntoIterator<Item = &'a f32>) -> (f32, f32) {
+    values.into_iter().filter(|value| !value.is_nan())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| (min.min(value), max.max(value)))
+}
+
+/// 8-bit image of `values` scaled from `min` to `max`, e.g. their [`value_range`], with NaN
+/// values black
+pub fn grayscale(values: &Array2<f32>, (min, max): (f32, f32)) -> GrayImage {
+    let range = if max > min { max - min } else { 1.0 };
+    let (rows, cols) = values.dim();
+    GrayImage::from_fn(cols as u32, rows as u32, |x, y| {
+        let value = values[[y as usize, x as usize]];
+        let level = if value.is_nan() { 0.0 } else { (value - min) / range * 255.0 };
+        image::Luma([level.round().clamp(0.0, 255.0) as u8])
+    })
+}
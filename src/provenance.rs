@@ -0,0 +1,68 @@
+//! Provenance of renderings: SHA-256 digests of the DICOM and heatmap inputs, recorded in
+//! sidecars, audit records and the DICOM instances derived from a rendering, so every overlay
+//! can be traced back to the exact inputs it was rendered from.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "dicom")]
+use crate::dicom_io::DicomFile;
+use crate::error::{Error, Result};
+
+/// Input of a rendering with its digest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashedInput {
+    /// What the input is, e.g. `source`, `heatmap`, `baseline` or `ground_truth`
+    pub role: String,
+    /// None for contents passed in memory
+    pub path: Option<PathBuf>,
+    /// SHA-256 in lowercase hex of the file or, for heatmap values passed in memory, of their
+    /// little-endian f32 values; None for a file that couldn't be read, e.g. a missing heatmap
+    /// a lenient run fell back from
+    pub sha256: Option<String>,
+}
+
+impl HashedInput {
+    /// Input read from `path`, without a digest if it can't be read
+    pub fn file(role: &str, path: &Path) -> Self {
+        HashedInput { role: role.to_string(), path: Some(path.to_path_buf()), sha256: sha256_file(path).ok() }
+    }
+
+    /// Input passed in memory as `bytes`
+    pub fn bytes(role: &str, bytes: &[u8]) -> Self {
+        HashedInput { role: role.to_string(), path: None, sha256: Some(sha256_bytes(bytes)) }
+    }
+}
+
+/// SHA-256 of a file in lowercase hex, read in chunks
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| Error::io(path, e))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// SHA-256 of `bytes` in lowercase hex
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// SHA-256 in lowercase hex of heatmap values as little-endian f32, in row-major order
+pub fn sha256_values<'a>(values: impl IntoIterator<Item = &'a f32>) -> String {
+    let mut hasher = Sha256::new();
+    for value in values {
+        hasher.update(value.to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// SHA-256 in lowercase hex of a DICOM object held in memory, e.g. one received over DIMSE, as
+/// its Part 10 encoding
+#[cfg(feature = "dicom")]
+pub fn sha256_dicom(obj: &DicomFile) -> Result<String> {
+    let mut hasher = Sha256::new();
+    obj.write_all(&mut hasher).map_err(|e| Error::dicom("encode DICOM for hashing", e))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
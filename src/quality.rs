@@ -0,0 +1,193 @@
+//! Quality control of input images: automated flags for blank or constant pixel data, extreme
+//! exposure, missing critical DICOM attributes, a suspect aspect ratio and burned-in
+//! annotations, so bad inputs don't silently produce meaningless overlays.
+
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+use crate::dicom_io::{pixel_spacing, DicomFile};
+
+/// Mean gray level of the decoded image below which it counts as underexposed
+pub const MIN_MEAN_LEVEL: f64 = 20.0;
+
+/// Mean gray level of the decoded image above which it counts as overexposed
+pub const MAX_MEAN_LEVEL: f64 = 235.0;
+
+/// Share of the pixels clipped to black or white from which the image counts as under- or
+/// overexposed
+pub const MAX_CLIPPED_FRACTION: f64 = 0.6;
+
+/// Ratio of the longer to the shorter side of the imaged area above which it is suspect
+pub const MAX_ASPECT_RATIO: f64 = 3.0;
+
+/// Attributes without which a DICOM input can't be traced back to its patient and study
+pub const CRITICAL_TAGS: [(Tag, &str); 6] = [
+    (tags::PATIENT_ID, "PatientID"),
+    (tags::STUDY_INSTANCE_UID, "StudyInstanceUID"),
+    (tags::SERIES_INSTANCE_UID, "SeriesInstanceUID"),
+    (tags::SOP_INSTANCE_UID, "SOPInstanceUID"),
+    (tags::MODALITY, "Modality"),
+    (tags::PHOTOMETRIC_INTERPRETATION, "PhotometricInterpretation"),
+];
+
+/// Gray level difference of neighboring pixels that counts as the edge of a text stroke
+const TEXT_EDGE: i16 = 128;
+
+/// Stroke edges in one row of a corner from which the row may cross a line of text
+const TEXT_ROW_EDGES: usize = 6;
+
+/// Gray level difference of neighboring pixels below which they are smooth background
+const SMOOTH_STEP: i16 = 16;
+
+/// Rows crossing text from which a corner holds a text-like mark
+const TEXT_ROWS: usize = 4;
+
+/// Check an input can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityCheck {
+    /// Every pixel has the same level, e.g. all-zero pixel data
+    ConstantPixels,
+    Underexposed,
+    Overexposed,
+    /// Critical attributes are missing or empty
+    MissingTags,
+    AspectRatio,
+    /// BurnedInAnnotation is YES, or the corners hold text-like marks
+    BurnedInAnnotation,
+}
+
+/// Failed check, with what was found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityFlag {
+    pub check: QualityCheck,
+    pub message: String,
+}
+
+/// Quality-control measurements and flags of one input
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    /// Mean gray level of the decoded 8-bit image
+    pub mean_level: f64,
+    /// Shares of the pixels at level 0 and at level 255
+    pub dark_fraction: f64,
+    pub bright_fraction: f64,
+    /// Width over height of the imaged area, from the pixel spacing when the source records one
+    pub aspect_ratio: f64,
+    /// Critical attributes missing from a DICOM source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing_tags: Vec<String>,
+    /// Failed checks, empty when the input passed
+    pub flags: Vec<QualityFlag>,
+}
+
+impl QualityReport {
+    pub fn passed(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    /// Messages of the failed checks, separated by semicolons
+    pub fn summary(&self) -> String {
+        self.flags.iter().map(|flag| flag.message.as_str()).collect::<Vec<_>>().join("; ")
+    }
+}
+
+/// Whether quality control runs and what a failed check does
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityOptions {
+    /// Fail the run with `Error::QualityControl` when a check fails, instead of only reporting it
+    pub gate: bool,
+}
+
+/// Check the decoded gray levels of `image` (its red channel) and, for a DICOM source, the
+/// attributes of its `header`
+pub fn check_quality(image: &RgbaImage, header: Option<&DicomFile>) -> QualityReport {
+    let (width, height) = image.dimensions();
+    let mut counts = [0u64; 256];
+    for pixel in image.pixels() {
+        counts[usize::from(pixel[0])] += 1;
+    }
+    let total = u64::from(width) * u64::from(height);
+    let share = |count: u64| if total > 0 { count as f64 / total as f64 } else { 0.0 };
+    let mean_level = share(counts.iter().enumerate().map(|(level, &count)| level as u64 * count).sum());
+    let (dark_fraction, bright_fraction) = (share(counts[0]), share(counts[255]));
+
+    let mut flags = Vec::new();
+    let mut flag = |check: QualityCheck, message: String| flags.push(QualityFlag { check, message });
+    let levels: Vec<usize> = (0..256).filter(|&level| counts[level] > 0).collect();
+    if let [level] = levels[..] {
+        match level {
+            0 => flag(QualityCheck::ConstantPixels, "pixel data is all zero".to_string()),
+            _ => flag(QualityCheck::ConstantPixels, format!("pixel data is constant at level {}", level)),
+        }
+    } else {
+        if mean_level < MIN_MEAN_LEVEL || dark_fraction >= MAX_CLIPPED_FRACTION {
+            flag(QualityCheck::Underexposed, format!("image is underexposed: mean level {:.1}, {:.1}% black", mean_level, dark_fraction * 100.0));
+        }
+        if mean_level > MAX_MEAN_LEVEL || bright_fraction >= MAX_CLIPPED_FRACTION {
+            flag(QualityCheck::Overexposed, format!("image is overexposed: mean level {:.1}, {:.1}% white", mean_level, bright_fraction * 100.0));
+        }
+    }
+
+    // Rows and columns are spaced by the row and column spacing respectively
+    let (row_spacing, column_spacing) = header.and_then(pixel_spacing).unwrap_or((1.0, 1.0));
+    let aspect_ratio = f64::from(width) * column_spacing / (f64::from(height.max(1)) * row_spacing);
+    if aspect_ratio.max(1.0 / aspect_ratio) > MAX_ASPECT_RATIO {
+        flag(QualityCheck::AspectRatio, format!("imaged area has a suspect aspect ratio of {:.2}", aspect_ratio));
+    }
+
+    let text = |tag: Tag| {
+        let element = header?.element_opt(tag).ok().flatten()?;
+        let value = element.to_str().ok()?.trim_end_matches(['\0', ' ']).to_string();
+        Some(value).filter(|value| !value.is_empty())
+    };
+    let missing_tags: Vec<String> = match header {
+        Some(_) => CRITICAL_TAGS.iter().filter(|(tag, _)| text(*tag).is_none()).map(|(_, name)| name.to_string()).collect(),
+        None => Vec::new(),
+    };
+    if !missing_tags.is_empty() {
+        flag(QualityCheck::MissingTags, format!("critical attributes missing: {}", missing_tags.join(", ")));
+    }
+
+    if text(tags::BURNED_IN_ANNOTATION).is_some_and(|value| value.eq_ignore_ascii_case("YES")) {
+        flag(QualityCheck::BurnedInAnnotation, "BurnedInAnnotation is YES".to_string());
+    } else if levels.len() > 1
+        && let Some(corner) = text_corner(image)
+    {
+        flag(QualityCheck::BurnedInAnnotation, format!("text-like marks in the {} corner", corner));
+    }
+
+    QualityReport { mean_level, dark_fraction, bright_fraction, aspect_ratio, missing_tags, flags }
+}
+
+/// First corner of `image` (a quarter of its width by a sixth of its height) with rows crossing
+/// the sharp strokes of burned-in text, e.g. patient details or side markers, on a mostly smooth
+/// background, unlike noise
+fn text_corner(image: &RgbaImage) -> Option<&'static str> {
+    let (width, height) = image.dimensions();
+    let (corner_width, corner_height) = ((width / 4).max(2), (height / 6).max(1));
+    let corners = [
+        ("top-left", 0, 0),
+        ("top-right", width.saturating_sub(corner_width), 0),
+        ("bottom-left", 0, height.saturating_sub(corner_height)),
+        ("bottom-right", width.saturating_sub(corner_width), height.saturating_sub(corner_height)),
+    ];
+    corners.into_iter()
+        .find(|&(_, left, top)| {
+            let text_rows = (top..(top + corner_height).min(height))
+                .filter(|&y| {
+                    let steps: Vec<i16> = (left + 1..(left + corner_width).min(width))
+                        .map(|x| (i16::from(image.get_pixel(x, y)[0]) - i16::from(image.get_pixel(x - 1, y)[0])).abs())
+                        .collect();
+                    let edges = steps.iter().filter(|&&step| step >= TEXT_EDGE).count();
+                    let smooth = steps.iter().filter(|&&step| step < SMOOTH_STEP).count();
+                    edges >= TEXT_ROW_EDGES && 2 * smooth >= steps.len()
+                })
+                .count();
+            text_rows >= TEXT_ROWS
+        })
+        .map(|(name, _, _)| name)
+}
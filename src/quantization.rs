@@ -0,0 +1,186 @@
+//! Quantized models: int8 and uint8 tensors are mapped to real values with the scale and zero
+//! point they were quantized with, and a quantized model can be checked against its
+//! full-precision original on a calibration set before it serves results.
+
+use image::GrayImage;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::batch::is_dicom_candidate;
+use crate::dicom_io::{decode_dicom_pixel_data, image_dimensions, open_dicom};
+use crate::error::{Error, Result};
+use crate::inference::{InferenceOptions, OnnxModel};
+
+/// Model metadata key with the quantization of its integer inputs and outputs, in the format of
+/// [`parse_quantization`]
+pub const QUANTIZATION_METADATA_KEY: &str = "quantization";
+
+/// Largest score difference to the full-precision model a quantized model may show when
+/// `MODEL_<NAME>_MAX_DRIFT` is not set
+pub const DEFAULT_MAX_DRIFT: f32 = 0.05;
+
+/// Calibration images scored per run by models with a dynamic batch dimension
+const CALIBRATION_BATCH: usize = 8;
+
+/// Affine quantization of an integer tensor: `real = (quantized - zero_point) * scale`, written
+/// as `scale:zero_point` or just `scale` for a zero point of 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    pub scale: f32,
+    pub zero_point: i32,
+}
+
+impl FromStr for Quantization {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scale, zero_point) = s.trim().split_once(':').unwrap_or((s.trim(), "0"));
+        let scale = match scale.trim().parse::<f32>() {
+            Ok(scale) if scale.is_finite() && scale > 0.0 => scale,
+            _ => return Err(format!("scale must be a positive number, found '{}'", scale.trim())),
+        };
+        let zero_point = zero_point.trim().parse()
+            .map_err(|_| format!("zero point must be an integer, found '{}'", zero_point.trim()))?;
+        Ok(Quantization { scale, zero_point })
+    }
+}
+
+impl std::fmt::Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.scale, self.zero_point)
+    }
+}
+
+impl Quantization {
+    pub fn dequantize(self, value: i32) -> f32 {
+        (value - self.zero_point) as f32 * self.scale
+    }
+
+    /// Nearest quantized value of `value`, saturated to `min..=max`
+    pub fn quantize(self, value: f32, min: i32, max: i32) -> i32 {
+        ((value / self.scale).round() as i32).saturating_add(self.zero_point).clamp(min, max)
+    }
+}
+
+/// Quantization of tensors by name, from `name=scale:zero_point` entries separated by `;`
+/// (e.g. `input=0.003921:0;heatmap=0.0078:128`)
+pub fn parse_quantization(spec: &str) -> std::result::Result<BTreeMap<String, Quantization>, String> {
+    let mut tensors = BTreeMap::new();
+    for item in spec.split(';').map(str::trim).filter(|item| !item.is_empty()) {
+        let Some((name, quantization)) = item.split_once('=') else {
+            return Err(format!("expected name=scale:zero_point, found '{}'", item));
+        };
+        let quantization = quantization.parse().map_err(|e| format!("{}: {}", name.trim(), e))?;
+        tensors.insert(name.trim().to_string(), quantization);
+    }
+    Ok(tensors)
+}
+
+/// Comparison of a quantized registry model with its full-precision original, run when the
+/// model is loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriftCheck {
+    /// Path of the full-precision model (`MODEL_<NAME>_REFERENCE`)
+    pub reference: PathBuf,
+    /// Directory of DICOM files both models score (`MODEL_<NAME>_CALIBRATION`)
+    pub calibration: PathBuf,
+    /// Largest score difference accepted (`MODEL_<NAME>_MAX_DRIFT`)
+    pub max_drift: f32,
+}
+
+/// How far the scores of a quantized model drifted from the full-precision ones
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftReport {
+    /// Calibration images scored by both models
+    pub images: usize,
+    /// Largest difference of any class score on any image; infinite when either model gave a
+    /// score that isn't finite
+    pub max_drift: f32,
+    /// Mean difference over every class score of every image
+    pub mean_drift: f32,
+    /// Share of the images both models give the same top class
+    pub top_agreement: f32,
+}
+
+/// Score the calibration images of `check` with `model` and its full-precision reference, loaded
+/// with `options`, and refuse the model when a score drifts further than the check allows
+pub fn verify_drift(model: &OnnxModel, check: &DriftCheck, options: &InferenceOptions) -> Result<DriftReport> {
+    let name = &model.info().name;
+    let reference = OnnxModel::load(&check.reference, options)?;
+    let images = calibration_images(&check.calibration)?;
+    if images.is_empty() {
+        return Err(Error::InvalidOption(format!("Calibration directory {} of model {} holds no DICOM images",
+                                                check.calibration.display(), name)));
+    }
+    let report = measure_drift(model, &reference, &images)?;
+    if !report.max_drift.is_finite() || report.max_drift > check.max_drift {
+        return Err(Error::Inference(format!(
+            "Model {} drifts up to {:.4} from {} on {} calibration image(s), more than the {} allowed",
+            name, report.max_drift, check.reference.display(), report.images, check.max_drift
+        )));
+    }
+    info!("Model {} is within {:.4} of {} on {} calibration image(s) (mean {:.4}, top class agreement {:.1}%)",
+          name, report.max_drift, check.reference.display(), report.images, report.mean_drift, report.top_agreement * 100.0);
+    Ok(report)
+}
+
+/// Score drift of `model` from `reference` on `images`
+pub fn measure_drift(model: &OnnxModel, reference: &OnnxModel, images: &[GrayImage]) -> Result<DriftReport> {
+    let size = model.batch_size().unwrap_or(CALIBRATION_BATCH);
+    let (mut max_drift, mut total, mut count, mut agreeing) = (0.0f32, 0.0f64, 0usize, 0usize);
+    for chunk in images.chunks(size) {
+        let (scores, expected) = (model.score_batch(chunk)?, reference.score_batch(chunk)?);
+        for (scores, expected) in scores.iter().zip(&expected) {
+            if scores.len() != expected.len() {
+                return Err(Error::ShapeMismatch {
+                    context: format!("scores of model {} and {}", model.info().name, reference.info().name),
+                    expected: expected.len(),
+                    found: scores.len(),
+                });
+            }
+            for (score, expected) in scores.iter().zip(expected) {
+                // A NaN would be passed over by f32::max
+                let drift = match score.is_finite() && expected.is_finite() {
+                    true => (score - expected).abs(),
+                    false => f32::INFINITY,
+                };
+                max_drift = max_drift.max(drift);
+                total += f64::from(drift);
+            }
+            count += scores.len();
+            agreeing += usize::from(top_class(scores) == top_class(expected));
+        }
+    }
+    Ok(DriftReport {
+        images: images.len(),
+        max_drift,
+        mean_drift: (total / count.max(1) as f64) as f32,
+        top_agreement: agreeing as f32 / images.len().max(1) as f32,
+    })
+}
+
+fn top_class(scores: &[f32]) -> Option<usize> {
+    scores.iter().enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b)).map(|(index, _)| index)
+}
+
+/// Grayscale pixels of every DICOM file in `dir`, sorted by path; files that don't decode are
+/// skipped with a warning
+fn calibration_images(dir: &Path) -> Result<Vec<GrayImage>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| Error::io(dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_dicom_candidate(path))
+        .collect();
+    paths.sort();
+    let decode = |path: &Path| -> Result<GrayImage> {
+        let obj = open_dicom(path)?;
+        let (rows, columns) = image_dimensions(&obj)?;
+        Ok(image::imageops::grayscale(&decode_dicom_pixel_data(&obj, rows, columns)?))
+    };
+    Ok(paths.iter()
+        .filter_map(|path| decode(path).map_err(|e| warn!("Skipping calibration image {}: {}", path.display(), e)).ok())
+        .collect())
+}
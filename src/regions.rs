@@ -0,0 +1,137 @@
+//! Heatmap statistics per anatomical region: the normalized heatmap is summarized within each
+//! labeled region of one or more masks (e.g. left and right lung, or lobes), so findings can be
+//! localized to the region holding most of the activation.
+
+use log::warn;
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::heatmap::resize_indices;
+
+/// Labeled region mask and the names of its labels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionMask {
+    /// PNG or `.npy` label map, see [`crate::evaluation::load_label_mask`]
+    pub path: PathBuf,
+    /// Region names by label; an unnamed label is named after the file stem, followed by the
+    /// label when the mask has several
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<u32, String>,
+}
+
+impl RegionMask {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        RegionMask { path: path.into(), names: BTreeMap::new() }
+    }
+
+    pub fn name(mut self, label: u32, name: impl Into<String>) -> Self {
+        self.names.insert(label, name.into());
+        self
+    }
+
+    fn region_name(&self, label: u32, labels: usize) -> String {
+        if let Some(name) = self.names.get(&label) {
+            return name.clone();
+        }
+        let stem = self.path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        if labels > 1 { format!("{}:{}", stem, label) } else { stem }
+    }
+}
+
+impl FromStr for RegionMask {
+    type Err = String;
+
+    /// `lungs.png`, or `lungs.png:1=right lung,2=left lung` to name its labels
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((path, names)) = s.rsplit_once(':').filter(|(_, names)| names.contains('=')) else {
+            return Ok(RegionMask::new(s));
+        };
+        let mut mask = RegionMask::new(path);
+        for entry in names.split(',') {
+            let (label, name) = entry.split_once('=').ok_or_else(|| format!("Invalid region label {}: expected LABEL=NAME", entry))?;
+            let label = label.trim().parse().map_err(|_| format!("Invalid region label {}: expected a non-negative integer", label.trim()))?;
+            if label == 0 {
+                return Err("Label 0 is the background and can't be named".to_string());
+            }
+            mask = mask.name(label, name.trim());
+        }
+        Ok(mask)
+    }
+}
+
+/// Statistics of the normalized heatmap within one region
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionStats {
+    pub name: String,
+    /// Mask the region comes from and its label there
+    pub mask: PathBuf,
+    pub label: u32,
+    pub pixels: u64,
+    pub mean: f64,
+    pub max: f32,
+    /// Share of the region's pixels at or above the threshold
+    pub above_threshold: f64,
+    /// Share of the whole heatmap's activation (its sum of values) inside the region
+    pub activation_share: f64,
+}
+
+/// Statistics of every region of every mask, in mask and label order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionReport {
+    pub threshold: f32,
+    pub regions: Vec<RegionStats>,
+    /// Region of each mask with the largest activation share, in mask order; masks without
+    /// activation inside any region have none
+    pub predominant: Vec<String>,
+}
+
+/// Statistics of `normalized` within every nonzero label of each mask, which is resized to the
+/// heatmap with nearest-neighbor sampling if their shapes differ
+pub fn region_stats(normalized: &Array2<f32>, masks: &[(RegionMask, Array2<u32>)], threshold: f32) -> RegionReport {
+    let (height, width) = normalized.dim();
+    let total: f64 = normalized.iter().map(|&value| f64::from(value)).sum();
+    let (mut regions, mut predominant) = (Vec::new(), Vec::new());
+
+    for (mask, labels) in masks {
+        if labels.dim() != (height, width) {
+            warn!("Region mask {} dimensions ({}x{}) don't match the heatmap ({}x{}), resizing...",
+                  mask.path.display(), labels.nrows(), labels.ncols(), height, width);
+        }
+        let (rows, cols) = resize_indices(labels.dim(), width, height);
+
+        // pixels, sum, max and pixels at or above the threshold per label
+        let mut sums: BTreeMap<u32, (u64, f64, f32, u64)> = BTreeMap::new();
+        for ((y, x), &value) in normalized.indexed_iter() {
+            let label = labels[[rows[y], cols[x]]];
+            if label == 0 {
+                continue;
+            }
+            let (pixels, sum, max, above) = sums.entry(label).or_insert((0, 0.0, f32::NEG_INFINITY, 0));
+            *pixels += 1;
+            *sum += f64::from(value);
+            *max = max.max(value);
+            *above += u64::from(value >= threshold);
+        }
+
+        let count = sums.len();
+        let first = regions.len();
+        regions.extend(sums.into_iter().map(|(label, (pixels, sum, max, above))| RegionStats {
+            name: mask.region_name(label, count),
+            mask: mask.path.clone(),
+            label,
+            pixels,
+            mean: sum / pixels as f64,
+            max,
+            above_threshold: above as f64 / pixels as f64,
+            activation_share: if total > 0.0 { sum / total } else { 0.0 },
+        }));
+        predominant.extend(regions[first..].iter()
+            .filter(|region| region.activation_share > 0.0)
+            .max_by(|a, b| a.activation_share.total_cmp(&b.activation_share))
+            .map(|region| region.name.clone()));
+    }
+    RegionReport { threshold, regions, predominant }
+}
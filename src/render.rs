@@ -0,0 +1,385 @@
+//! Heatmap colorization and compositing onto the base image.
+
+use image::{Pixel, Rgba, RgbaImage};
+use log::{info, warn};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::buffers::BufferPool;
+use crate::colormap::{apply_colormap, get_color_from_value, ColorMap};
+use crate::heatmap::{resize_heatmap, resize_heatmap_into};
+use crate::normalize::{normalize_heatmap, normalize_in_place, Normalization};
+use crate::parallel::for_each_row;
+
+/// Colorize the heatmap (or a default gradient) and overlay it onto the base image in place
+pub fn render_heatmap_overlay(
+    base_rgba_image: RgbaImage,
+    heatmap_data: Option<Array2<f32>>,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    opacity: f32,
+) -> RgbaImage {
+    render_heatmap_overlay_with(base_rgba_image, heatmap_data, colormap, normalization, opacity, &BufferPool::default())
+}
+
+/// [`render_heatmap_overlay`] resizing and normalizing the heatmap in buffers from `buffers`,
+/// which get the heatmap buffers back once the image is rendered
+pub fn render_heatmap_overlay_with(
+    mut base_rgba_image: RgbaImage,
+    heatmap_data: Option<Array2<f32>>,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    opacity: f32,
+    buffers: &BufferPool,
+) -> RgbaImage {
+    let (width, height) = base_rgba_image.dimensions();
+    
+    info!("Creating heatmap overlay on real DICOM data ({}x{})", width, height);
+    
+    if let Some(data) = heatmap_data {
+        // Use real heatmap data
+        info!("Using real heatmap data with {} colormap and {} normalization", 
+              format!("{:?}", colormap).to_lowercase(), 
+              format!("{:?}", normalization).to_lowercase());
+        
+        // Resize heatmap data to match image dimensions if needed
+        let mut normalized_data = if data.nrows() != height as usize || data.ncols() != width as usize {
+            warn!("Heatmap dimensions ({}x{}) don't match image dimensions ({}x{}), resizing...", 
+                  data.nrows(), data.ncols(), height, width);
+            let (width, height) = (width as usize, height as usize);
+            let resized = resize_heatmap_into(data.view(), width, height, buffers.take(width * height));
+            buffers.give(data.into_raw_vec_and_offset().0);
+            resized
+        } else {
+            data
+        };
+        
+        // Normalize the data in the same buffer
+        let mut scratch = match normalization {
+            Normalization::Percentile => buffers.take(normalized_data.len()),
+            _ => Vec::new(),
+        };
+        normalize_in_place(normalized_data.view_mut(), normalization, &mut scratch);
+        buffers.give(scratch);
+        
+        // Colorize straight into the base RGBA image
+        let blend = BlendOptions { opacity, ..BlendOptions::default() };
+        blend_normalized(&mut base_rgba_image, &normalized_data, colormap, &blend);
+        buffers.give(normalized_data.into_raw_vec_and_offset().0);
+    } else {
+        // Generate default gradient heatmap
+        info!("No heatmap data provided, generating default gradient with {} colormap", 
+              format!("{:?}", colormap).to_lowercase());
+        blend_default_heatmap(&mut base_rgba_image, colormap, opacity, BlendMode::Alpha);
+    }
+
+    base_rgba_image
+}
+
+/// Generate default gradient heatmap when no real data is provided, one row per task with the
+/// `parallel` feature
+pub fn generate_default_heatmap(width: u32, height: u32, colormap: &ColorMap, opacity: f32) -> RgbaImage {
+    let mut heatmap_rgba = RgbaImage::new(width, height);
+    let alpha = (opacity * 255.0) as u8;
+
+    for_each_row(&mut heatmap_rgba, width as usize * 4, |y, pixels| {
+        for (x, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            // Simple gradient: intensity increases with x and y
+            let value = ((x as f32 / width as f32) + (y as f32 / height as f32)) / 2.0;
+            let color = get_color_from_value(value, colormap);
+            pixel.copy_from_slice(&[color.0, color.1, color.2, alpha]);
+        }
+    });
+
+    heatmap_rgba
+}
+
+/// Blend the default gradient straight into the base image, as [`generate_default_heatmap`]
+/// followed by [`blend_layer`] would, without building the layer
+pub fn blend_default_heatmap(base_rgba_image: &mut RgbaImage, colormap: &ColorMap, opacity: f32, mode: BlendMode) {
+    let (width, height) = base_rgba_image.dimensions();
+    let alpha = (opacity * 255.0) as u8;
+
+    for_each_row(base_rgba_image, width as usize * 4, |y, pixels| {
+        for (x, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            let value = ((x as f32 / width as f32) + (y as f32 / height as f32)) / 2.0;
+            let color = get_color_from_value(value, colormap);
+            blend_pixel(pixel, [color.0, color.1, color.2, alpha], mode);
+        }
+    });
+}
+
+/// Where the heatmap is resized, normalized, colorized and blended, the GPU only with the `gpu`
+/// feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Row-parallel loops on the host
+    #[default]
+    Cpu,
+    /// Compute shaders on a wgpu adapter
+    Gpu,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "cpu" => Ok(Backend::Cpu),
+            "gpu" => Ok(Backend::Gpu),
+            _ => Err(format!("Unknown backend: {}. Available: cpu, gpu", s)),
+        }
+    }
+}
+
+/// How the colorized heatmap layer is combined with the base image
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    /// Standard alpha compositing (source-over)
+    Alpha,
+    /// Add the weighted heatmap color to the base, saturating at white
+    Additive,
+    /// Screen blending, brightens the base without blowing out highlights
+    Screen,
+}
+
+impl FromStr for BlendMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "alpha" => Ok(BlendMode::Alpha),
+            "additive" => Ok(BlendMode::Additive),
+            "screen" => Ok(BlendMode::Screen),
+            _ => Err(format!("Unknown blend mode: {}. Available: alpha, additive, screen", s)),
+        }
+    }
+}
+
+/// Blending parameters applied when fusing the heatmap layer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlendOptions {
+    /// Heatmap opacity (0.0 to 1.0)
+    pub opacity: f32,
+    pub mode: BlendMode,
+    /// Hide heatmap pixels whose normalized value is below this level
+    pub threshold: Option<f32>,
+}
+
+impl Default for BlendOptions {
+    fn default() -> Self {
+        BlendOptions {
+            opacity: 0.6,
+            mode: BlendMode::Alpha,
+            threshold: None,
+        }
+    }
+}
+
+/// Resize, normalize and colorize heatmap values into an RGBA layer of the given size,
+/// applying the opacity and threshold from `blend`
+pub fn colorize_heatmap(
+    data: &Array2<f32>,
+    width: u32,
+    height: u32,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    blend: &BlendOptions,
+) -> RgbaImage {
+    let normalized_data = normalized_at(data, width, height, normalization);
+    
+    colorize_normalized(&normalized_data, colormap, blend)
+}
+
+/// `data` resized to `width`×`height` if needed and normalized, in a single new array
+fn normalized_at(data: &Array2<f32>, width: u32, height: u32, normalization: &Normalization) -> Array2<f32> {
+    if data.dim() == (height as usize, width as usize) {
+        return normalize_heatmap(data, normalization);
+    }
+    let mut resized = resize_heatmap(data, width as usize, height as usize);
+    normalize_in_place(resized.view_mut(), normalization, &mut Vec::new());
+    resized
+}
+
+/// Colorize already normalized values at their own size, applying opacity and threshold
+pub fn colorize_normalized(normalized_data: &Array2<f32>, colormap: &ColorMap, blend: &BlendOptions) -> RgbaImage {
+    let mut layer = apply_colormap(normalized_data, colormap, blend.opacity);
+    if let Some(threshold) = blend.threshold {
+        apply_threshold(&mut layer, normalized_data, threshold);
+    }
+    layer
+}
+
+/// Make heatmap pixels fully transparent where the normalized value is below `threshold`
+pub fn apply_threshold(heatmap_rgba: &mut RgbaImage, normalized_data: &Array2<f32>, threshold: f32) {
+    for (x, y, pixel) in heatmap_rgba.enumerate_pixels_mut() {
+        if normalized_data[[y as usize, x as usize]] < threshold {
+            pixel[3] = 0;
+        }
+    }
+}
+
+/// Resize, normalize and colorize heatmap values and blend them into the base image in place,
+/// like [`colorize_heatmap`] followed by [`blend_layer`] without the intermediate layer
+pub fn blend_heatmap(
+    base_rgba_image: &mut RgbaImage,
+    data: &Array2<f32>,
+    colormap: &ColorMap,
+    normalization: &Normalization,
+    blend: &BlendOptions,
+) {
+    let (width, height) = base_rgba_image.dimensions();
+    let normalized_data = normalized_at(data, width, height, normalization);
+    
+    blend_normalized(base_rgba_image, &normalized_data, colormap, blend);
+}
+
+/// Colorize normalized values of the image's size and blend each pixel straight into the base
+/// image, with the opacity, threshold and mode of `blend`; the result matches
+/// [`colorize_normalized`] followed by [`blend_layer`]
+pub fn blend_normalized(base_rgba_image: &mut RgbaImage, normalized_data: &Array2<f32>, colormap: &ColorMap, blend: &BlendOptions) {
+    let width = base_rgba_image.width() as usize;
+    let alpha = (blend.opacity * 255.0) as u8;
+
+    for_each_row(base_rgba_image, width * 4, |row, pixels| {
+        for (pixel, &value) in pixels.chunks_exact_mut(4).zip(normalized_data.row(row)) {
+            let hidden = blend.threshold.is_some_and(|threshold| value < threshold);
+            let color = get_color_from_value(value, colormap);
+            blend_pixel(pixel, [color.0, color.1, color.2, if hidden { 0 } else { alpha }], blend.mode);
+        }
+    });
+}
+
+/// Blend a heatmap layer into the base image using its alpha channel as weight
+pub fn blend_layer(base_rgba_image: &mut RgbaImage, heatmap_rgba: &RgbaImage, mode: BlendMode) {
+    for (base, heat) in base_rgba_image.pixels_mut().zip(heatmap_rgba.pixels()) {
+        blend_pixel(&mut base.0, heat.0, mode);
+    }
+}
+
+/// Blend one RGBA heatmap color into one base pixel
+pub(crate) fn blend_pixel(base: &mut [u8], heat: [u8; 4], mode: BlendMode) {
+    if mode == BlendMode::Alpha {
+        // The source-over compositing of `imageops::overlay`
+        Rgba::from_slice_mut(base).blend(&Rgba(heat));
+        return;
+    }
+    
+    let weight = heat[3] as f32 / 255.0;
+    for channel in 0..3 {
+        let b = base[channel] as f32 / 255.0;
+        let h = heat[channel] as f32 / 255.0 * weight;
+        let blended = match mode {
+            BlendMode::Additive => b + h,
+            BlendMode::Screen => 1.0 - (1.0 - b) * (1.0 - h),
+            BlendMode::Alpha => unreachable!(),
+        };
+        base[channel] = (blended.clamp(0.0, 1.0) * 255.0) as u8;
+    }
+}
+
+/// Shapes drawn on top of the fused image, in image pixel coordinates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Annotation {
+    /// Rectangle outline, e.g. a detection bounding box
+    Rectangle {
+        x: u32, y: u32, width: u32, height: u32,
+        #[serde(with = "rgba_serde")]
+        color: Rgba<u8>,
+        thickness: u32,
+    },
+    /// Cross-shaped marker centered on a point of interest
+    Marker {
+        x: u32, y: u32, size: u32,
+        #[serde(with = "rgba_serde")]
+        color: Rgba<u8>,
+    },
+    /// Line of ASCII text in an 8×8 bitmap font enlarged `scale` times, with its top-left corner
+    /// at (x, y) and a dark shadow for contrast; other characters are drawn as `?`
+    Text {
+        x: u32, y: u32, text: String,
+        #[serde(with = "rgba_serde")]
+        color: Rgba<u8>,
+        #[serde(default = "default_text_scale")]
+        scale: u32,
+    },
+}
+
+fn default_text_scale() -> u32 {
+    1
+}
+
+/// Shadow color of `Annotation::Text`, drawn one font pixel down and to the right
+const TEXT_SHADOW: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Annotation colors are (de)serialized as `[r, g, b, a]`
+mod rgba_serde {
+    use image::Rgba;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(color: &Rgba<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        color.0.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Rgba<u8>, D::Error> {
+        <[u8; 4]>::deserialize(deserializer).map(Rgba)
+    }
+}
+
+/// Draw annotations onto the image, clipping anything outside its bounds
+pub fn draw_annotations(image: &mut RgbaImage, annotations: &[Annotation]) {
+    let (width, height) = image.dimensions();
+    let mut put = |x: i64, y: i64, color: Rgba<u8>| {
+        if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+            image.put_pixel(x as u32, y as u32, color);
+        }
+    };
+    
+    for annotation in annotations {
+        match *annotation {
+            Annotation::Rectangle { x, y, width: w, height: h, color, thickness } => {
+                let (x0, y0) = (x as i64, y as i64);
+                let (x1, y1) = (x0 + w as i64 - 1, y0 + h as i64 - 1);
+                for t in 0..thickness.max(1) as i64 {
+                    for px in x0..=x1 {
+                        put(px, y0 + t, color);
+                        put(px, y1 - t, color);
+                    }
+                    for py in y0..=y1 {
+                        put(x0 + t, py, color);
+                        put(x1 - t, py, color);
+                    }
+                }
+            }
+            Annotation::Marker { x, y, size, color } => {
+                let (cx, cy, half) = (x as i64, y as i64, (size / 2) as i64);
+                for d in -half..=half {
+                    put(cx + d, cy, color);
+                    put(cx, cy + d, color);
+                }
+            }
+            Annotation::Text { x, y, ref text, color, scale } => {
+                let scale = scale.max(1) as i64;
+                for (offset, color) in [(scale, TEXT_SHADOW), (0, color)] {
+                    for (index, ch) in text.chars().enumerate() {
+                        let glyph = font8x8::legacy::BASIC_LEGACY[if ch.is_ascii() { ch as usize } else { '?' as usize }];
+                        let left = x as i64 + index as i64 * 8 * scale + offset;
+                        for (row, bits) in glyph.iter().enumerate() {
+                            for col in (0..8).filter(|col| bits & (1 << col) != 0) {
+                                let (px, py) = (left + col * scale, y as i64 + row as i64 * scale + offset);
+                                for (dx, dy) in (0..scale).flat_map(|dx| (0..scale).map(move |dy| (dx, dy))) {
+                                    put(px + dx, py + dy, color);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
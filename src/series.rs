@@ -0,0 +1,240 @@
+//! DICOM series as volumes: the instances of a CT or MR series in a directory, ordered along
+//! their slice normal from the patient geometry and spaced by their positions, so a 3-D heatmap
+//! registered to the series can be rendered slice by slice.
+
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use log::{debug, info, warn};
+use ndarray::{Array3, Axis};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::batch::is_dicom_candidate;
+use crate::dicom_io::{
+    decode_dicom_values, frame_count, image_dimensions, open_dicom, open_dicom_header, pixel_spacing, slice_spacing, DicomFile,
+};
+use crate::error::{Error, Result};
+use crate::parallel::map_ordered;
+
+/// Share of the slice spacing by which a gap between neighboring slices may deviate from it
+/// before the series is reported as unevenly spaced
+pub const SPACING_TOLERANCE: f64 = 0.1;
+
+/// Distance in mm below which two slices count as sharing a position
+const SAME_POSITION: f64 = 1e-3;
+
+/// Difference of direction cosines below which two slices count as sharing an orientation
+const SAME_ORIENTATION: f64 = 1e-3;
+
+/// One instance of a series
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesSlice {
+    pub path: PathBuf,
+    pub sop_instance_uid: String,
+    pub instance_number: Option<i32>,
+    /// Position along the slice normal in mm, from ImagePositionPatient; None without geometry
+    pub location: Option<f64>,
+}
+
+/// Instances of one series, in slice order, with the geometry of their grid
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DicomSeries {
+    pub series_instance_uid: String,
+    /// Slices by their position along the normal of the first slice's orientation, from the
+    /// lowest up, or by InstanceNumber when the series has no patient geometry
+    pub slices: Vec<SeriesSlice>,
+    pub rows: u32,
+    pub columns: u32,
+    /// Row and column spacing in mm
+    pub pixel_spacing: Option<(f64, f64)>,
+    /// Median distance of neighboring slices in mm, or else SpacingBetweenSlices or SliceThickness
+    pub slice_spacing: Option<f64>,
+    /// Problems with the geometry: missing positions, mixed orientations, shared positions and
+    /// uneven gaps
+    pub warnings: Vec<String>,
+}
+
+impl DicomSeries {
+    pub fn len(&self) -> usize {
+        self.slices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+
+    /// Pixel values of the slices at `slices` after the modality rescale, as (slices, rows, cols)
+    /// in that order, decoded in parallel with the `parallel` feature
+    pub fn load_values(&self, slices: &[usize]) -> Result<Array3<f32>> {
+        let slices = map_ordered(slices, |&slice| decode_dicom_values(&open_dicom(&self.slices[slice].path)?, self.rows, self.columns, 0))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        let views: Vec<_> = slices.iter().map(|slice| slice.view()).collect();
+        ndarray::stack(Axis(0), &views).map_err(|e| Error::Render(format!("Failed to stack the slices of series {}: {}", self.series_instance_uid, e)))
+    }
+}
+
+/// Geometry read from one instance
+struct Instance {
+    slice: SeriesSlice,
+    /// Rows and columns
+    dimensions: (u32, u32),
+    position: Option<[f64; 3]>,
+    orientation: Option<[f64; 6]>,
+    pixel_spacing: Option<(f64, f64)>,
+    /// SpacingBetweenSlices, or else SliceThickness
+    declared_spacing: Option<f64>,
+}
+
+/// Load the series in `dir`, or the one of them with `series_uid` when the directory holds
+/// several; files that aren't DICOM are skipped
+///
+/// Only the headers are read. Every instance must be single-frame and have the dimensions of the
+/// others. Geometry problems are logged and kept in the series' warnings.
+pub fn load_series(dir: &Path, series_uid: Option<&str>) -> Result<DicomSeries> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| Error::io(dir, e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_dicom_candidate(path))
+        .collect();
+    paths.sort();
+
+    let mut series: BTreeMap<String, Vec<Instance>> = BTreeMap::new();
+    for path in paths {
+        let header = match open_dicom_header(&path) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let (Some(uid), Some(sop_instance_uid)) = (text(&header, tags::SERIES_INSTANCE_UID), text(&header, tags::SOP_INSTANCE_UID)) else {
+            debug!("Skipping {}: not an image instance", path.display());
+            continue;
+        };
+        if frame_count(&header)? > 1 {
+            return Err(Error::InvalidOption(format!(
+                "{} is a multi-frame instance; render it with --heatmap-volume and --input instead", path.display())));
+        }
+        let instance = Instance {
+            slice: SeriesSlice {
+                path,
+                sop_instance_uid,
+                instance_number: header.element_opt(tags::INSTANCE_NUMBER).ok().flatten().and_then(|element| element.to_int().ok()),
+                location: None,
+            },
+            dimensions: image_dimensions(&header)?,
+            position: floats(&header, tags::IMAGE_POSITION_PATIENT),
+            orientation: floats(&header, tags::IMAGE_ORIENTATION_PATIENT),
+            pixel_spacing: pixel_spacing(&header),
+            declared_spacing: slice_spacing(&header),
+        };
+        series.entry(uid).or_default().push(instance);
+    }
+
+    let (uid, instances) = match series_uid {
+        Some(wanted) => series.remove_entry(wanted).ok_or_else(|| Error::InvalidOption(format!(
+            "Series {} not found in {} (found: {})", wanted, dir.display(), series_list(&series))))?,
+        None if series.len() > 1 => {
+            return Err(Error::InvalidOption(format!(
+                "{} holds {} series, choose one by its SeriesInstanceUID: {}", dir.display(), series.len(), series_list(&series))));
+        }
+        None => series.pop_first().ok_or_else(|| Error::InvalidOption(format!("No DICOM series found in {}", dir.display())))?,
+    };
+    order_series(uid, instances)
+}
+
+/// Series of `instances`, sorted and spaced by their geometry
+fn order_series(series_instance_uid: String, instances: Vec<Instance>) -> Result<DicomSeries> {
+    let first = &instances[0];
+    let (rows, columns) = first.dimensions;
+    let (pixel_spacing, declared_spacing, reference) = (first.pixel_spacing, first.declared_spacing, first.orientation);
+    let mut warnings = Vec::new();
+
+    for instance in &instances {
+        if instance.dimensions != (rows, columns) {
+            let (found_rows, found_columns) = instance.dimensions;
+            return Err(Error::InvalidOption(format!("{} is {}x{}, the other slices of the series {}x{}",
+                instance.slice.path.display(), found_columns, found_rows, columns, rows)));
+        }
+    }
+
+    // The normal of the first slice's orientation orders the slices whatever the patient's axes
+    let normal = reference.map(|[rx, ry, rz, cx, cy, cz]| [ry * cz - rz * cy, rz * cx - rx * cz, rx * cy - ry * cx]);
+    let geometric = normal.is_some() && instances.iter().all(|instance| instance.position.is_some());
+    if !geometric {
+        warnings.push("slices lack ImagePositionPatient or ImageOrientationPatient, so they are ordered by InstanceNumber".to_string());
+    } else if instances.iter().any(|instance| {
+        instance.orientation.zip(reference).is_none_or(|(orientation, reference)| {
+            orientation.iter().zip(reference).any(|(a, b)| (a - b).abs() > SAME_ORIENTATION)
+        })
+    }) {
+        warnings.push("slices have different orientations, e.g. a localizer or a tilted gantry".to_string());
+    }
+
+    let mut slices: Vec<SeriesSlice> = instances.into_iter()
+        .map(|instance| {
+            let location = normal.zip(instance.position)
+                .filter(|_| geometric)
+                .map(|(normal, position)| normal.iter().zip(position).map(|(n, p)| n * p).sum());
+            SeriesSlice { location, ..instance.slice }
+        })
+        .collect();
+    slices.sort_by(|a, b| {
+        let by_location = a.location.zip(b.location).map(|(a, b)| a.total_cmp(&b)).unwrap_or(std::cmp::Ordering::Equal);
+        by_location.then(a.instance_number.cmp(&b.instance_number)).then(a.path.cmp(&b.path))
+    });
+
+    let gaps: Vec<f64> = slices.windows(2)
+        .filter_map(|pair| Some(pair[1].location? - pair[0].location?))
+        .collect();
+    if let Some(index) = gaps.iter().position(|gap| gap.abs() < SAME_POSITION) {
+        warnings.push(format!("slices {} and {} share their position", index, index + 1));
+    }
+    let mut sorted = gaps.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted.get(sorted.len() / 2).copied().filter(|gap| *gap >= SAME_POSITION);
+    if let Some(median) = median {
+        let uneven: Vec<usize> = (0..gaps.len()).filter(|&index| (gaps[index] - median).abs() > SPACING_TOLERANCE * median).collect();
+        if let Some(&index) = uneven.first() {
+            warnings.push(format!("{} slice gap(s) deviate from the slice spacing of {:.3} mm, e.g. {:.3} mm after slice {}",
+                                  uneven.len(), median, gaps[index], index));
+        }
+    }
+
+    for warning in &warnings {
+        warn!("Series {}: {}", series_instance_uid, warning);
+    }
+    let series = DicomSeries {
+        series_instance_uid,
+        slices,
+        rows,
+        columns,
+        pixel_spacing,
+        slice_spacing: median.or(declared_spacing),
+        warnings,
+    };
+    info!("Series {}: {} slice(s) of {}x{}, {} mm apart", series.series_instance_uid, series.len(), columns, rows,
+          series.slice_spacing.map(|spacing| format!("{:.3}", spacing)).unwrap_or_else(|| "unknown".to_string()));
+    Ok(series)
+}
+
+/// Trimmed text of an attribute, None when it is missing or empty
+fn text(header: &DicomFile, tag: Tag) -> Option<String> {
+    let value = header.element_opt(tag).ok().flatten()?.to_str().ok()?.trim_end_matches(['\0', ' ']).to_string();
+    Some(value).filter(|value| !value.is_empty())
+}
+
+/// The `N` numbers of a multi-valued decimal attribute
+fn floats<const N: usize>(header: &DicomFile, tag: Tag) -> Option<[f64; N]> {
+    header.element_opt(tag).ok().flatten()?.to_multi_float64().ok()?.try_into().ok()
+}
+
+fn series_list(series: &BTreeMap<String, Vec<Instance>>) -> String {
+    series.iter()
+        .map(|(uid, instances)| format!("{} ({} instance(s))", uid, instances.len()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,222 @@
+//! `/admin` endpoints for operators: the effective configuration without its secrets, the DL
+//! services with their health and circuit state, cache statistics and the recent jobs.
+
+use axum::extract::{Query, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::auth::Client;
+use super::jobs::JobStatus;
+use super::reference::without_credentials;
+use super::{error_response, request_id, ApiError, AppState};
+use crate::config::{redact_url, OrchestrateConfig, ServiceConfig};
+use crate::error::Error;
+
+/// Jobs listed by `/admin/jobs` unless the query asks for another number
+const DEFAULT_JOBS: usize = 50;
+
+/// Reject callers that aren't among the configured admins (403); runs after authentication, so
+/// without API keys or JWT validation nobody is admitted
+pub(crate) async fn require_admin(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let message = match request.extensions().get::<Client>() {
+        Some(client) if state.config().admins.contains(&client.identity()) => return next.run(request).await,
+        Some(client) => format!("Client {} is not an administrator", client.identity()),
+        None => "Admin endpoints require API keys or a JWT issuer to be configured".to_string(),
+    };
+    warn!("Rejected {} {}: {} (request {})", request.method(), request.uri().path(), message, request_id(&request));
+    error_response(StatusCode::FORBIDDEN, "forbidden", &message)
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs_f64()
+}
+
+/// Settings in effect, after reloads; keys, passwords, header values and blob URL queries are
+/// left out
+pub(crate) async fn config(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(config_view(&state.config()))
+}
+
+fn config_view(config: &OrchestrateConfig) -> Value {
+    let tenants: BTreeMap<&str, Value> = config.tenants.values()
+        .map(|tenant| (tenant.name.as_str(), json!({
+            "api_keys": tenant.api_keys,
+            "claim_values": tenant.claim_values,
+            "services": tenant.services,
+            "colormap": tenant.colormap,
+            "output": tenant.output.as_deref().map(without_credentials),
+            "requests_per_minute": tenant.requests_per_minute,
+        })))
+        .collect();
+    let (limits, pools, jobs, cache) = (&config.limits, &config.pools, &config.jobs, &config.cache);
+    json!({
+        "address": config.bind_address(),
+        "request_timeout_secs": secs(config.request_timeout),
+        "drain_timeout_secs": secs(config.drain_timeout),
+        "max_body_bytes": config.max_body_bytes,
+        "uploads": {
+            "dicom_bytes": config.uploads.dicom,
+            "heatmap_bytes": config.uploads.heatmap,
+            "options_bytes": config.uploads.options,
+        },
+        "service_db_path": config.service_db_path,
+        "services": config.config_services.keys().collect::<Vec<_>>(),
+        "api_keys": config.api_keys.iter()
+            .map(|key| json!({ "name": key.name, "requests_per_minute": key.requests_per_minute }))
+            .collect::<Vec<_>>(),
+        "jwt": config.jwt.as_ref().map(|jwt| json!({
+            "issuer": jwt.issuer,
+            "audiences": jwt.audiences,
+            "jwks_url": jwt.jwks_url,
+            "algorithms": jwt.algorithms,
+            "leeway_secs": secs(jwt.leeway),
+            "jwks_refresh_secs": secs(jwt.jwks_refresh),
+            "identity_claim": jwt.identity_claim,
+            "tenant_claim": jwt.tenant_claim,
+        })),
+        "admins": config.admins,
+        "tenants": tenants,
+        "limits": {
+            "global_per_minute": limits.global_per_minute,
+            "client_per_minute": limits.client_per_minute,
+            "max_concurrent": limits.max_concurrent,
+            "max_queued": limits.max_queued,
+            "queue_timeout_secs": secs(limits.queue_timeout),
+        },
+        "pools": {
+            "io_threads": pools.io_threads,
+            "cpu_threads": pools.cpu_threads,
+            "max_uploads": pools.max_uploads,
+            "max_service_calls": pools.max_service_calls,
+            "render_threads": pools.render_threads,
+            "cpu_affinity": pools.cpu_affinity.as_ref().map(ToString::to_string),
+        },
+        "jobs": {
+            "dir": jobs.dir,
+            "redis": jobs.redis.as_ref().map(|redis| json!({
+                "url": redis.redacted_url(),
+                "key_prefix": redis.key_prefix,
+                "result_ttl_secs": redis.result_ttl.map(secs),
+            })),
+            "ttl_secs": secs(jobs.ttl),
+            "timeout_secs": secs(jobs.timeout),
+            "max_pending": jobs.max_pending,
+        },
+        "stow_options": config.stow_options.as_deref().map(|options| serde_json::from_str::<Value>(options).unwrap_or_default()),
+        "dicomweb": config.dicomweb.as_ref().map(|dicomweb| json!({
+            "url": redact_url(&dicomweb.url),
+            "headers": dicomweb.headers.keys().collect::<Vec<_>>(),
+            "timeout_secs": secs(dicomweb.timeout),
+        })),
+        "tls": config.tls.as_ref().map(|tls| json!({ "cert": tls.cert, "key": tls.key })),
+        "reload_interval_secs": config.reload_interval.map(secs),
+        "cache": { "entries": cache.entries, "ttl_secs": secs(cache.ttl) },
+    })
+}
+
+fn service_view(service: &ServiceConfig) -> Value {
+    let (retry, circuit) = (&service.retry, &service.circuit);
+    json!({
+        "url": redact_url(&service.url),
+        "kind": service.kind.name(),
+        "health_url": service.health_url.as_deref().map(redact_url),
+        "model_version": service.model_version,
+        "payload_format": format!("{:?}", service.payload_format).to_lowercase(),
+        "headers": service.headers.keys().collect::<Vec<_>>(),
+        "colormap": service.colormap,
+        "weight": service.weight,
+        "occlusion": service.occlusion.as_ref().map(ToString::to_string),
+        "preprocess": service.preprocess.as_ref().map(ToString::to_string),
+        "served": service.kind.is_model_server().then(|| json!({
+            "model": service.served.name,
+            "input": service.served.input,
+            "input_layout": service.served.layout.name(),
+            "input_channels": service.served.channels,
+            "scores_output": service.served.scores_output,
+            "heatmap_output": service.served.heatmap_output,
+            "labels": service.served.labels,
+            "multipart": service.served.multipart,
+            "signature": service.served.signature,
+            "target_class": service.served.target_class.name(),
+        })),
+        "timeout_secs": secs(service.timeout),
+        "connect_timeout_secs": secs(service.connect_timeout),
+        "read_timeout_secs": service.read_timeout.map(secs),
+        "retry": {
+            "max_attempts": retry.max_attempts,
+            "initial_backoff_secs": secs(retry.initial_backoff),
+            "max_backoff_secs": secs(retry.max_backoff),
+            "jitter": retry.jitter,
+            "retry_on_status": retry.retry_on_status,
+        },
+        "circuit_policy": {
+            "window": circuit.window,
+            "min_calls": circuit.min_calls,
+            "failure_rate": circuit.failure_rate,
+            "open_for_secs": secs(circuit.open_for),
+            "fallback_to_cache": circuit.fallback_to_cache,
+        },
+    })
+}
+
+/// Configured DL services with their settings and, with the `service` feature, a fresh health
+/// probe and the circuit breaker state
+pub(crate) async fn services(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let config = state.config();
+    #[cfg(feature = "service")]
+    let mut health = super::health::probe_services(&state).await;
+    let services: BTreeMap<&str, Value> = config.config_services.values()
+        .map(|service| {
+            #[cfg_attr(not(feature = "service"), allow(unused_mut))]
+            let mut view = service_view(service);
+            #[cfg(feature = "service")]
+            if let Some(check) = health.remove(&service.name) {
+                view["health"] = json!(check);
+            }
+            (service.name.as_str(), view)
+        })
+        .collect();
+    Json(json!({ "services": services }))
+}
+
+/// Size, capacity and hit counts of the result caches since startup
+pub(crate) async fn cache(State(state): State<Arc<AppState>>) -> Json<Value> {
+    #[cfg_attr(not(feature = "service"), allow(unused_mut))]
+    let mut caches = json!({ "renders": state.renders.stats(), "decoded_images": state.images.stats() });
+    #[cfg(feature = "service")]
+    {
+        caches["service_heatmaps"] = json!(state.client.cache_stats());
+    }
+    Json(caches)
+}
+
+/// Query of `/admin/jobs`
+#[derive(Debug, Deserialize)]
+struct JobsQuery {
+    /// Jobs listed, most recently updated first
+    #[serde(default)]
+    limit: Option<usize>,
+    /// Only jobs in this state
+    #[serde(default)]
+    status: Option<JobStatus>,
+}
+
+/// Recent jobs of all clients known to this replica, with the number still pending
+pub(crate) async fn jobs(State(state): State<Arc<AppState>>, request: Request) -> Result<Json<Value>, ApiError> {
+    let Query(query) = Query::<JobsQuery>::try_from_uri(request.uri())
+        .map_err(|e| ApiError(Error::InvalidOption(format!("Invalid query string: {}", e))))?;
+    let jobs: Vec<_> = state.jobs.recent()
+        .into_iter()
+        .filter(|job| query.status.is_none_or(|status| job.status == status))
+        .take(query.limit.unwrap_or(DEFAULT_JOBS))
+        .collect();
+    Ok(Json(json!({ "pending": state.jobs.pending(), "jobs": jobs })))
+}
@@ -0,0 +1,176 @@
+//! AMQP worker mode (`worker --transport amqp`): consume processing requests from a RabbitMQ
+//! queue, acknowledging each once its result event is confirmed and dead-lettering poison ones.
+
+use futures_util::StreamExt;
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions, BasicRejectOptions,
+    ConfirmSelectOptions, QueueDeclareOptions,
+};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+use log::{info, warn};
+use std::sync::Arc;
+
+use super::worker::{self, WorkResult};
+use super::AppState;
+use crate::config::{AmqpConfig, OrchestrateConfig};
+use crate::error::{Error, Result};
+
+/// Delivery mode of messages the broker writes to disk
+const PERSISTENT: u8 = 2;
+
+fn amqp_error(message: impl std::fmt::Display) -> Error {
+    Error::Service { service: "amqp".to_string(), message: message.to_string() }
+}
+
+/// Process requests from `amqp.request_queue`, up to `amqp.prefetch` at once, until the
+/// process is stopped or the broker closes the channel
+///
+/// On SIGTERM the consumer is cancelled and the requests in flight get until the drain timeout
+/// to be settled; the broker requeues those still unacknowledged when the connection closes.
+///
+/// A request is acknowledged once its result event is confirmed by the broker. Requests that
+/// fail with a client error (4xx) would fail again, so they get a failed result and are
+/// rejected to the dead-letter queue. Server errors are requeued once, then dead-lettered the
+/// same way if the redelivery fails too.
+pub async fn consume(config: OrchestrateConfig, amqp: AmqpConfig) -> Result<()> {
+    let connection = Connection::connect(&amqp.url, ConnectionProperties::default())
+        .await
+        .map_err(|e| amqp_error(format!("failed to connect to {}: {}", amqp.redacted_url(), e)))?;
+    let channel = connection.create_channel().await.map_err(|e| amqp_error(format!("failed to open a channel: {}", e)))?;
+    declare_queues(&channel, &amqp).await?;
+    channel
+        .basic_qos(amqp.prefetch, BasicQosOptions::default())
+        .await
+        .map_err(|e| amqp_error(format!("failed to set the prefetch: {}", e)))?;
+    // Results are published with confirms so a request is only acknowledged once its result is safe
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await
+        .map_err(|e| amqp_error(format!("failed to enable publisher confirms: {}", e)))?;
+    let mut consumer = channel
+        .basic_consume(amqp.request_queue.as_str().into(), "".into(), BasicConsumeOptions::default(), FieldTable::default())
+        .await
+        .map_err(|e| amqp_error(format!("failed to consume {}: {}", amqp.request_queue, e)))?;
+    info!("Consuming {} from {} with prefetch {}, publishing to '{}' with routing key {}",
+          amqp.request_queue, amqp.redacted_url(), amqp.prefetch, amqp.result_exchange, amqp.result_routing_key);
+
+    let state = Arc::new(AppState::new(config).await?);
+    super::reload::watch(&state);
+    super::shutdown::listen(&state);
+    let amqp = Arc::new(amqp);
+    loop {
+        let delivery = tokio::select! {
+            delivery = consumer.next() => delivery,
+            _ = state.shutdown.requested() => break,
+        };
+        let Some(delivery) = delivery else {
+            return Err(amqp_error(format!("consumer of {} was cancelled", amqp.request_queue)));
+        };
+        let delivery = delivery.map_err(|e| amqp_error(format!("consumer of {} failed: {}", amqp.request_queue, e)))?;
+        let (state, amqp, channel) = (state.clone(), amqp.clone(), channel.clone());
+        let work = state.shutdown.track();
+        // The prefetch bounds the requests in flight: the broker delivers no more until one is settled
+        crate::asynchronous::spawn(async move {
+            let _work = work;
+            handle(&state, &amqp, &channel, delivery).await
+        });
+    }
+
+    if let Err(e) = channel.basic_cancel(consumer.tag(), BasicCancelOptions::default()).await {
+        warn!("Failed to cancel the consumer of {}: {}", amqp.request_queue, e);
+    }
+    if !state.shutdown.drained().await {
+        warn!("Drain timeout passed, {} request(s) go back to {}", state.shutdown.in_flight(), amqp.request_queue);
+    }
+    if let Err(e) = connection.close(0, "shutting down".into()).await {
+        warn!("Failed to close the connection: {}", e);
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Declare the request queue with dead-lettering to the dead-letter queue, and the result queue
+/// when results go through the default exchange
+async fn declare_queues(channel: &Channel, amqp: &AmqpConfig) -> Result<()> {
+    let durable = QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() };
+    let declare = |queue: &str, arguments: FieldTable| {
+        let queue = queue.to_string();
+        async move {
+            channel
+                .queue_declare(queue.as_str().into(), durable, arguments)
+                .await
+                .map_err(|e| amqp_error(format!("failed to declare queue {}: {}", queue, e)))
+        }
+    };
+    declare(&amqp.dead_letter_queue, FieldTable::default()).await?;
+    let mut arguments = FieldTable::default();
+    arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString("".into()));
+    arguments.insert("x-dead-letter-routing-key".into(), AMQPValue::LongString(amqp.dead_letter_queue.as_str().into()));
+    declare(&amqp.request_queue, arguments).await?;
+    if amqp.result_exchange.is_empty() {
+        declare(&amqp.result_routing_key, FieldTable::default()).await?;
+    }
+    Ok(())
+}
+
+/// Process one delivery and settle it
+async fn handle(state: &AppState, amqp: &AmqpConfig, channel: &Channel, delivery: Delivery) {
+    let properties = &delivery.properties;
+    let fallback_id = properties.message_id().as_ref()
+        .or(properties.correlation_id().as_ref())
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| format!("delivery-{}", delivery.delivery_tag));
+    let result = worker::process(state, &delivery.data, fallback_id).await;
+
+    // First server-side failure: put the request back for another attempt, without a result
+    if result.retryable() && !delivery.redelivered {
+        warn!("Requeueing {} after a server error", result.id);
+        if let Err(e) = delivery.acker.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await {
+            warn!("Failed to requeue {}: {}", result.id, e);
+        }
+        return;
+    }
+    if let Err(e) = publish(amqp, channel, &result).await {
+        warn!("Requeueing {}: {}", result.id, e);
+        if let Err(e) = delivery.acker.nack(BasicNackOptions { requeue: true, ..BasicNackOptions::default() }).await {
+            warn!("Failed to requeue {}: {}", result.id, e);
+        }
+        return;
+    }
+    let settled = if result.error.is_some() {
+        warn!("Dead-lettering {} to {}", result.id, amqp.dead_letter_queue);
+        delivery.acker.reject(BasicRejectOptions { requeue: false }).await
+    } else {
+        delivery.acker.ack(BasicAckOptions::default()).await
+    };
+    if let Err(e) = settled {
+        warn!("Failed to settle {}: {}", result.id, e);
+    }
+}
+
+/// Publish the result event and wait for the broker to confirm it
+async fn publish(amqp: &AmqpConfig, channel: &Channel, result: &WorkResult) -> Result<()> {
+    let event = serde_json::to_vec(result).map_err(|e| Error::Server(format!("Failed to serialize result event: {}", e)))?;
+    let properties = BasicProperties::default()
+        .with_content_type("application/json".into())
+        .with_correlation_id(result.id.as_str().into())
+        .with_delivery_mode(PERSISTENT);
+    let confirm = channel
+        .basic_publish(
+            amqp.result_exchange.as_str().into(),
+            amqp.result_routing_key.as_str().into(),
+            BasicPublishOptions::default(),
+            &event,
+            properties,
+        )
+        .await
+        .map_err(|e| amqp_error(format!("failed to publish the result: {}", e)))?
+        .await
+        .map_err(|e| amqp_error(format!("failed to publish the result: {}", e)))?;
+    if !confirm.is_ack() {
+        return Err(amqp_error("the broker refused the result event"));
+    }
+    Ok(())
+}
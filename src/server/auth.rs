@@ -0,0 +1,144 @@
+//! API key and bearer token authentication for the processing endpoints.
+
+use axum::extract::{Request, State};
+#[cfg(feature = "jwt")]
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+#[cfg(feature = "jwt")]
+use axum::http::HeaderValue;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use log::warn;
+use std::sync::Arc;
+
+use super::{error_response, request_id, AppState};
+use crate::config::ApiKey;
+
+/// Header carrying the client's API key
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// How a [`Client`] authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKind {
+    ApiKey,
+    Token,
+}
+
+/// Authenticated caller, attached to the request and its response for rate limiting and logging
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Name of the API key, or the identity claim of the token
+    pub name: String,
+    pub kind: ClientKind,
+    /// Limit set for the API key, replacing the default client limit
+    pub requests_per_minute: Option<u32>,
+    /// Tenant whose policy applies, from the key's tenant or the token's tenant claim
+    pub tenant: Option<String>,
+}
+
+impl Client {
+    /// `key:<name>` for an API key and `jwt:<identity>` for a token, so a token whose identity
+    /// equals a key's name is still another client, e.g. for job ownership and admin access
+    pub fn identity(&self) -> String {
+        match self.kind {
+            ClientKind::ApiKey => format!("key:{}", self.name),
+            ClientKind::Token => format!("jwt:{}", self.name),
+        }
+    }
+}
+
+/// Reject requests without a configured key or a valid bearer token (401), and tokens of no
+/// configured tenant once there are tenants (403); a no-op when neither keys nor JWT
+/// validation are configured
+pub(crate) async fn authenticate(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let keys = &state.config().api_keys;
+    if keys.is_empty() && state.config().jwt.is_none() {
+        return next.run(request).await;
+    }
+
+    #[cfg(feature = "jwt")]
+    if let Some(validator) = &state.jwt
+        && let Some(token) = bearer_token(&request)
+    {
+        let claims = match validator.validate(&token).await {
+            Ok(claims) => claims,
+            Err(super::jwt::Rejection::Invalid(message)) => return invalid_token(&request, &message),
+            Err(super::jwt::Rejection::Unavailable(message)) => {
+                return error_response(StatusCode::SERVICE_UNAVAILABLE, "unavailable", &message);
+            }
+        };
+        // Tokens without an identity would all share one client's rate limit and jobs
+        let Some(name) = claims.get(validator.identity_claim()).filter(|name| !name.is_empty()).map(str::to_string) else {
+            return invalid_token(&request, &format!("Token has no {} claim", validator.identity_claim()));
+        };
+        let tenant = claims.get(validator.tenant_claim())
+            .and_then(|value| state.config().tenant_of_claim(value).map(|tenant| tenant.name.clone()));
+        // Without a tenant no tenant policy would apply to the token at all
+        if tenant.is_none() && !state.config().tenants.is_empty() {
+            let message = format!("Token claim {} names no configured tenant", validator.tenant_claim());
+            warn!("Rejected {} {}: {} (request {})", request.method(), request.uri().path(), message, request_id(&request));
+            return error_response(StatusCode::FORBIDDEN, "forbidden", &message);
+        }
+        let client = Client {
+            name,
+            kind: ClientKind::Token,
+            requests_per_minute: None,
+            tenant,
+        };
+        let mut request = request;
+        request.extensions_mut().insert(claims);
+        return respond(request, next, client).await;
+    }
+
+    let presented = request.headers().get(API_KEY_HEADER).map(|value| value.as_bytes());
+    let Some(key) = presented.and_then(|presented| find_key(keys, presented)) else {
+        let message = match (presented.is_some(), keys.is_empty()) {
+            (true, _) => "Invalid API key",
+            (false, true) => "Missing bearer token",
+            (false, false) if state.config().jwt.is_some() => "Missing X-Api-Key header or bearer token",
+            (false, false) => "Missing X-Api-Key header",
+        };
+        warn!("Rejected {} {}: {} (request {})", request.method(), request.uri().path(), message, request_id(&request));
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized", message);
+    };
+    let client = Client {
+        name: key.name.clone(),
+        kind: ClientKind::ApiKey,
+        requests_per_minute: key.requests_per_minute,
+        tenant: state.config().tenant_of_key(&key.name).map(|tenant| tenant.name.clone()),
+    };
+    respond(request, next, client).await
+}
+
+/// Run the handler with `client` attached to the request and the response
+async fn respond(mut request: Request, next: Next, client: Client) -> Response {
+    request.extensions_mut().insert(client.clone());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(client);
+    response
+}
+
+/// 401 for a token that didn't validate, telling the client to get a new one
+#[cfg(feature = "jwt")]
+fn invalid_token(request: &Request, message: &str) -> Response {
+    warn!("Rejected {} {}: {} (request {})", request.method(), request.uri().path(), message, request_id(request));
+    let mut response = error_response(StatusCode::UNAUTHORIZED, "unauthorized", message);
+    response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer error=\"invalid_token\""));
+    response
+}
+
+#[cfg(feature = "jwt")]
+fn bearer_token(request: &Request) -> Option<String> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+/// Compare the presented key against every configured one in constant time per key
+fn find_key<'a>(keys: &'a [ApiKey], presented: &[u8]) -> Option<&'a ApiKey> {
+    keys.iter().fold(None, |found, key| if constant_time_eq(key.key.as_bytes(), presented) { Some(key) } else { found })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,174 @@
+//! gRPC API (`heatmap.v1.HeatmapProcessing` from `proto/heatmap.proto`), routed through the
+//! same authentication and admission control as the REST endpoints.
+
+use axum::http::StatusCode;
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use super::auth::Client;
+use super::jobs::{self, JobStatus};
+use super::process::{parse_options, render, Input, ProcessOptions, ProcessRequest};
+use super::{ApiError, AppState};
+use crate::error::Error;
+
+/// Messages generated from `proto/heatmap.proto`
+pub mod proto {
+    #![allow(clippy::all)]
+    tonic::include_proto!("heatmap.v1");
+}
+
+use proto::heatmap_processing_server::{HeatmapProcessing, HeatmapProcessingServer};
+use proto::process_chunk::Part;
+
+/// Path prefix of every method, `/<package>.<service>/`
+pub(crate) const SERVICE_PATH: &str = "/heatmap.v1.HeatmapProcessing";
+
+/// `HeatmapProcessing` backed by the server's shared state
+#[derive(Debug, Clone)]
+pub(crate) struct GrpcService {
+    state: Arc<AppState>,
+}
+
+/// The generated server, accepting messages up to the configured body limit
+pub(crate) fn service(state: Arc<AppState>) -> HeatmapProcessingServer<GrpcService> {
+    let limit = state.config().max_body_bytes;
+    HeatmapProcessingServer::new(GrpcService { state }).max_decoding_message_size(limit)
+}
+
+impl From<ApiError> for Status {
+    fn from(error: ApiError) -> Self {
+        let code = match error.status() {
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+            StatusCode::PAYLOAD_TOO_LARGE => Code::ResourceExhausted,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+            _ => Code::Internal,
+        };
+        Status::new(code, error.0.to_string())
+    }
+}
+
+fn options(json: &str) -> Result<ProcessOptions, Status> {
+    if json.trim().is_empty() {
+        return Ok(ProcessOptions::default());
+    }
+    Ok(parse_options(json.as_bytes())?)
+}
+
+/// Tenant of the authenticated caller, attached by the HTTP middleware
+fn tenant<T>(request: &Request<T>) -> Option<String> {
+    request.extensions().get::<Client>().and_then(|client| client.tenant.clone())
+}
+
+/// Identity of the authenticated caller
+fn client<T>(request: &Request<T>) -> Option<String> {
+    request.extensions().get::<Client>().map(Client::identity)
+}
+
+fn format(heatmap_format: String) -> Option<String> {
+    Some(heatmap_format).filter(|format| !format.is_empty())
+}
+
+impl GrpcService {
+    async fn run(&self, request: ProcessRequest, deadline: Instant) -> Result<Response<proto::ProcessResponse>, Status> {
+        let png = render(&self.state, request, deadline).await?;
+        Ok(Response::new(proto::ProcessResponse { png }))
+    }
+
+    /// Collect the header, DICOM and heatmap chunks, enforcing the body limit across all of them
+    /// and the DICOM and heatmap limits on each
+    async fn read_chunks(&self, mut stream: Streaming<proto::ProcessChunk>, tenant: Option<String>, client: Option<String>) -> Result<ProcessRequest, Status> {
+        let _upload = self.state.limits.upload().await;
+        let config = self.state.config();
+        let (limit, uploads) = (config.max_body_bytes, &config.uploads);
+        let header = match stream.message().await? {
+            Some(proto::ProcessChunk { part: Some(Part::Header(header)) }) => header,
+            _ => return Err(Status::invalid_argument("The first message of ProcessStream must be the header")),
+        };
+        let (mut dicom, mut heatmap, mut received) = (Vec::new(), Vec::new(), 0);
+        while let Some(chunk) = stream.message().await? {
+            let (target, input, data) = match chunk.part {
+                Some(Part::Dicom(data)) => (&mut dicom, Input::dicom(uploads), data),
+                Some(Part::Heatmap(data)) => (&mut heatmap, Input::heatmap(uploads), data),
+                Some(Part::Header(_)) => return Err(Status::invalid_argument("ProcessStream takes a single header")),
+                None => continue,
+            };
+            received += data.len();
+            if received > limit {
+                return Err(ApiError(Error::TooLarge { what: "Upload".to_string(), limit }).into());
+            }
+            target.extend_from_slice(&data);
+            input.check(target, false)?;
+        }
+        Ok(ProcessRequest {
+            dicom: Some(dicom),
+            heatmap: (!heatmap.is_empty()).then(|| (heatmap, format(header.heatmap_format))),
+            options: options(&header.options_json)?,
+            tenant,
+            client,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl HeatmapProcessing for GrpcService {
+    async fn process(&self, request: Request<proto::ProcessRequest>) -> Result<Response<proto::ProcessResponse>, Status> {
+        let deadline = Instant::now() + self.state.config().request_timeout;
+        let (tenant, client) = (tenant(&request), client(&request));
+        let request = request.into_inner();
+        let upload = ProcessRequest {
+            dicom: Some(request.dicom),
+            heatmap: (!request.heatmap.is_empty()).then(|| (request.heatmap, format(request.heatmap_format))),
+            options: options(&request.options_json)?,
+            tenant,
+            client,
+        };
+        self.run(upload, deadline).await
+    }
+
+    async fn process_stream(&self, request: Request<Streaming<proto::ProcessChunk>>) -> Result<Response<proto::ProcessResponse>, Status> {
+        let deadline = Instant::now() + self.state.config().request_timeout;
+        let (tenant, client) = (tenant(&request), client(&request));
+        let upload = tokio::time::timeout_at(deadline.into(), self.read_chunks(request.into_inner(), tenant, client))
+            .await
+            .map_err(|_| Status::from(ApiError(Error::Timeout { stage: "upload" })))??;
+        self.run(upload, deadline).await
+    }
+
+    async fn get_job(&self, request: Request<proto::GetJobRequest>) -> Result<Response<proto::Job>, Status> {
+        let caller = request.extensions().get::<Client>().map(Client::identity);
+        let request = request.into_inner();
+        let job = self.state.jobs
+            .get(&request.id)
+            .await
+            .map_err(ApiError)?
+            .filter(|job| jobs::visible_to(job, caller.as_deref()))
+            .ok_or_else(|| Status::not_found(format!("Unknown job: {}", request.id)))?;
+        let png = match job.status {
+            JobStatus::Succeeded if request.include_result => self.state.jobs
+                .result(&job.id)
+                .await
+                .map_err(ApiError)?
+                .ok_or_else(|| Status::not_found(format!("Result of job {} is no longer available", job.id)))?
+                .as_ref()
+                .clone(),
+            _ => Vec::new(),
+        };
+        let status = match job.status {
+            JobStatus::Queued => proto::JobStatus::Queued,
+            JobStatus::Running => proto::JobStatus::Running,
+            JobStatus::Succeeded => proto::JobStatus::Succeeded,
+            JobStatus::Failed => proto::JobStatus::Failed,
+        };
+        Ok(Response::new(proto::Job {
+            id: job.id,
+            status: status.into(),
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            error: job.error.map(|error| proto::JobError { error: error.error, message: error.message }),
+            png,
+        }))
+    }
+}
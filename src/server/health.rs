@@ -0,0 +1,137 @@
+//! `GET /healthz` and `GET /readyz` for liveness and readiness probes.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::AppState;
+#[cfg(feature = "service")]
+use crate::circuit::CircuitState;
+use crate::demo::DemoOptions;
+use crate::error::{Error, Result};
+use crate::output::encode_png;
+use crate::pipeline::{HeatmapPipeline, ImageSource};
+
+/// Heatmap formats `/process` accepts without a custom registry
+const REQUIRED_FORMATS: &[&str] = &["json", "csv", "bin"];
+
+/// Outcome of one readiness check
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+    #[cfg(feature = "service")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<CircuitState>,
+    /// `cold`, `warm` or `failed` for a local model
+    #[cfg(feature = "onnx")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<&'static str>,
+}
+
+impl Check {
+    fn from_result<T>(result: &Result<T>) -> Self {
+        Check { ok: result.is_ok(), error: result.as_ref().err().map(|e| e.to_string()), ..Check::default() }
+    }
+}
+
+/// Body of a `/readyz` response; `ready` is false when any check failed
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<String, Check>,
+}
+
+/// Liveness: the process is up and serving requests
+pub(crate) async fn healthz() -> Response {
+    Json(serde_json::json!({ "status": "ok" })).into_response()
+}
+
+/// Readiness: heatmap formats are registered, a demo image renders and encodes, every
+/// configured DL service answers its health probe, every local model is warm and Redis answers a
+/// ping; 503 when any check fails or the server is draining for a shutdown
+pub(crate) async fn readyz(State(state): State<Arc<AppState>>) -> Response {
+    let readiness = readiness(&state).await;
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness)).into_response()
+}
+
+pub(crate) async fn readiness(state: &AppState) -> Readiness {
+    let mut checks = BTreeMap::new();
+
+    if state.shutdown.is_draining() {
+        let draining = Err::<(), _>(Error::Server("Draining for a shutdown".to_string()));
+        checks.insert("shutdown".to_string(), Check::from_result(&draining));
+    }
+
+    let extensions = state.registry.extensions();
+    let missing: Vec<&str> = REQUIRED_FORMATS.iter().copied().filter(|format| !extensions.contains(format)).collect();
+    let formats = if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Render(format!("No heatmap source registered for: {}", missing.join(", "))))
+    };
+    checks.insert("heatmap_formats".to_string(), Check::from_result(&formats));
+
+    let rendered = tokio::task::spawn_blocking(render_sample)
+        .await
+        .unwrap_or_else(|e| Err(Error::Render(format!("Background task failed: {}", e))));
+    checks.insert("render".to_string(), Check::from_result(&rendered));
+
+    #[cfg(feature = "service")]
+    for (name, check) in probe_services(state).await {
+        checks.insert(format!("service:{}", name), check);
+    }
+
+    #[cfg(feature = "onnx")]
+    for (name, check) in state.models.checks() {
+        checks.insert(format!("model:{}", name), check);
+    }
+
+    #[cfg(feature = "redis")]
+    if let Some(ping) = state.jobs.ping().await {
+        let check = Check { latency_ms: ping.as_ref().ok().map(|latency| latency.as_millis()), ..Check::from_result(&ping) };
+        checks.insert("job_store".to_string(), check);
+    }
+
+    Readiness { ready: checks.values().all(|check| check.ok), checks }
+}
+
+/// Health probe and circuit state of every configured DL service, by service name
+#[cfg(feature = "service")]
+pub(crate) async fn probe_services(state: &AppState) -> BTreeMap<String, Check> {
+    // Probe all services at once so one slow service doesn't delay the others' results
+    let probes: Vec<_> = state.config().config_services.values()
+        .map(|service| {
+            let (client, service) = (state.client.clone(), service.clone());
+            (service.name.clone(), tokio::spawn(async move { (client.probe(&service).await, client.circuit_state(&service)) }))
+        })
+        .collect();
+    let mut checks = BTreeMap::new();
+    for (name, probe) in probes {
+        let check = match probe.await {
+            Ok((result, circuit)) => Check {
+                latency_ms: result.as_ref().ok().map(|latency| latency.as_millis()),
+                circuit: Some(circuit),
+                ..Check::from_result(&result)
+            },
+            Err(e) => Check::from_result(&Err::<(), _>(Error::Server(format!("Health probe failed: {}", e)))),
+        };
+        checks.insert(name, check);
+    }
+    checks
+}
+
+/// Render a small demo image and encode it, exercising the colormaps, blending and PNG encoder
+fn render_sample() -> Result<()> {
+    let options = DemoOptions { width: 16, height: 16, ..DemoOptions::default() };
+    let result = HeatmapPipeline::builder().source(ImageSource::Demo(options)).build()?.run()?;
+    encode_png(&result.image).map(|_| ())
+}
@@ -0,0 +1,455 @@
+//! `POST /jobs` and `GET /jobs/{id}`: processing in the background, with job records kept
+//! on disk or in Redis so clients can poll for results instead of holding the connection open.
+
+use axum::extract::{Path, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::auth::Client;
+use super::process::{read_request, render, ProcessRequest};
+use super::reference::{without_credentials, write_reference};
+use super::{error_response, ApiError, AppState};
+use crate::config::JobConfig;
+use crate::error::{Error, Result};
+
+/// Poll interval suggested in `Retry-After` while a job is pending
+const POLL_INTERVAL_SECS: u64 = 1;
+/// Finished jobs of this replica remembered for [`JobStore::recent`] while records live in Redis
+#[cfg(feature = "redis")]
+const HISTORY: usize = 100;
+
+/// Lifecycle of a background job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed)
+    }
+}
+
+/// Why a job failed, in the same shape as an error response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobError {
+    pub error: String,
+    pub message: String,
+    /// Status `/process` would have answered with
+    pub status: u16,
+}
+
+/// Job record returned by `GET /jobs/{id}`, stored as `<id>.json` in the job directory or in Redis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// Client that submitted the job; only it can read the job when authentication is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>,
+    /// Where to fetch the PNG once the job succeeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+    /// Copy of the PNG under the output prefix of the client's tenant, without blob URL credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl Job {
+    fn update(&mut self, status: JobStatus) {
+        self.status = status;
+        self.updated_at = now();
+    }
+}
+
+/// Fail `job` because the server running it stopped before it finished
+pub(crate) fn interrupt(job: &mut Job) {
+    job.update(JobStatus::Failed);
+    job.error = Some(JobError {
+        error: "cancelled".to_string(),
+        message: "Job was interrupted by a server restart".to_string(),
+        status: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Job records and results, in Redis or the job directory when one is configured and in
+/// memory otherwise
+#[derive(Debug)]
+pub(crate) struct JobStore {
+    config: JobConfig,
+    /// Jobs by ID; with Redis only the unfinished jobs of this replica, for [`JobStore::pending`]
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Results of jobs without a job directory or Redis
+    results: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+    #[cfg(feature = "redis")]
+    redis: Option<super::redis::RedisJobs>,
+    /// Latest jobs this replica finished, newest last, while their records live in Redis
+    #[cfg(feature = "redis")]
+    history: Mutex<std::collections::VecDeque<Job>>,
+}
+
+impl JobStore {
+    /// Connect to Redis, or load the records in the job directory, failing jobs that were
+    /// interrupted by a restart and dropping expired ones
+    pub async fn open(config: &JobConfig) -> Result<Self> {
+        #[cfg(feature = "redis")]
+        let redis = match &config.redis {
+            Some(redis) => Some(super::redis::RedisJobs::connect(config, redis).await?),
+            None => None,
+        };
+        #[cfg(not(feature = "redis"))]
+        if config.redis.is_some() {
+            return Err(Error::InvalidOption("REDIS_URL is set but the server was built without the redis feature".to_string()));
+        }
+        let mut jobs = HashMap::new();
+        if let Some(dir) = &config.dir {
+            std::fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+            for entry in std::fs::read_dir(dir).map_err(|e| Error::io(dir, e))? {
+                let path = entry.map_err(|e| Error::io(dir, e))?.path();
+                if path.extension().is_none_or(|extension| extension != "json") {
+                    continue;
+                }
+                let text = std::fs::read_to_string(&path).map_err(|e| Error::io(&path, e))?;
+                let mut job: Job = match serde_json::from_str(&text) {
+                    Ok(job) => job,
+                    Err(e) => {
+                        warn!("Skipping unreadable job record {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if !job.status.is_finished() {
+                    interrupt(&mut job);
+                    write_record(&path, &job)?;
+                }
+                jobs.insert(job.id.clone(), job);
+            }
+            info!("Loaded {} job(s) from {}", jobs.len(), dir.display());
+        }
+        let store = JobStore {
+            config: config.clone(),
+            jobs: Mutex::new(jobs),
+            results: Mutex::default(),
+            #[cfg(feature = "redis")]
+            redis,
+            #[cfg(feature = "redis")]
+            history: Mutex::default(),
+        };
+        store.purge_expired();
+        Ok(store)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Job>> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.get(id).await;
+        }
+        Ok(self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(id).cloned())
+    }
+
+    /// Jobs queued or running on this server
+    pub fn pending(&self) -> usize {
+        let jobs = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        jobs.values().filter(|job| !job.status.is_finished()).count()
+    }
+
+    /// Jobs known to this replica, most recently updated first: with Redis the pending ones and
+    /// the last 100 it finished, otherwise all jobs within the TTL
+    pub fn recent(&self) -> Vec<Job> {
+        #[cfg_attr(not(feature = "redis"), allow(unused_mut))]
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).values().cloned().collect();
+        #[cfg(feature = "redis")]
+        jobs.extend(self.history.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter().cloned());
+        jobs.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| b.created_at.cmp(&a.created_at)));
+        jobs
+    }
+
+    /// Fail the jobs still queued or running on this server, which is stopping before they
+    /// finish; returns how many there were
+    pub async fn interrupt_pending(&self) -> usize {
+        let pending: Vec<Job> = {
+            let jobs = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            jobs.values().filter(|job| !job.status.is_finished()).cloned().collect()
+        };
+        for mut job in pending.iter().cloned() {
+            interrupt(&mut job);
+            if let Err(e) = self.save(&job).await {
+                warn!("Failed to record job {}: {}", job.id, e);
+            }
+        }
+        pending.len()
+    }
+
+    /// Record the current state of `job`, persisting it when there is Redis or a job directory
+    pub async fn save(&self, job: &Job) -> Result<()> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            redis.save(job).await?;
+            let mut jobs = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if job.status.is_finished() {
+                jobs.remove(&job.id);
+                let mut history = self.history.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                history.retain(|finished| finished.id != job.id);
+                history.push_back(job.clone());
+                if history.len() > HISTORY {
+                    history.pop_front();
+                }
+            } else {
+                jobs.insert(job.id.clone(), job.clone());
+            }
+            return Ok(());
+        }
+        if let Some(dir) = &self.config.dir {
+            write_record(&dir.join(format!("{}.json", job.id)), job)?;
+        }
+        self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(job.id.clone(), job.clone());
+        Ok(())
+    }
+
+    pub async fn save_result(&self, id: &str, png: Vec<u8>) -> Result<()> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.save_result(id, &png).await;
+        }
+        match &self.config.dir {
+            Some(dir) => write_atomic(&dir.join(format!("{}.png", id)), &png),
+            None => {
+                self.results.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(id.to_string(), Arc::new(png));
+                Ok(())
+            }
+        }
+    }
+
+    /// PNG of a succeeded job; None when it is gone, having expired in Redis or been deleted
+    pub async fn result(&self, id: &str) -> Result<Option<Arc<Vec<u8>>>> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return Ok(redis.result(id).await?.map(Arc::new));
+        }
+        match &self.config.dir {
+            Some(dir) => {
+                let path = dir.join(format!("{}.png", id));
+                match std::fs::read(&path) {
+                    Ok(png) => Ok(Some(Arc::new(png))),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(Error::io(path, e)),
+                }
+            }
+            None => Ok(self.results.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(id).cloned()),
+        }
+    }
+
+    /// Round trip to Redis when jobs are kept there
+    #[cfg(feature = "redis")]
+    pub async fn ping(&self) -> Option<Result<std::time::Duration>> {
+        match &self.redis {
+            Some(redis) => Some(redis.ping().await),
+            None => None,
+        }
+    }
+
+    /// Forget finished jobs older than the TTL, deleting their files; Redis expires them itself
+    pub fn purge_expired(&self) {
+        let cutoff = now().saturating_sub(self.config.ttl.as_secs());
+        let expired: Vec<String> = {
+            let mut jobs = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let expired = jobs.values()
+                .filter(|job| job.status.is_finished() && job.updated_at < cutoff)
+                .map(|job| job.id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            expired
+        };
+        let mut results = self.results.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for id in &expired {
+            results.remove(id);
+            if let Some(dir) = &self.config.dir {
+                for file in [format!("{}.json", id), format!("{}.png", id)] {
+                    let path = dir.join(file);
+                    if let Err(e) = std::fs::remove_file(&path)
+                        && e.kind() != std::io::ErrorKind::NotFound
+                    {
+                        warn!("Failed to delete expired job file {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn write_record(path: &FsPath, job: &Job) -> Result<()> {
+    let json = serde_json::to_vec_pretty(job).map_err(|e| Error::Server(format!("Failed to serialize job {}: {}", job.id, e)))?;
+    write_atomic(path, &json)
+}
+
+/// Write through a temporary file so a crash never leaves a truncated record or result
+fn write_atomic(path: &FsPath, bytes: &[u8]) -> Result<()> {
+    let mut temporary = PathBuf::from(path);
+    temporary.set_extension("tmp");
+    std::fs::write(&temporary, bytes).map_err(|e| Error::io(&temporary, e))?;
+    std::fs::rename(&temporary, path).map_err(|e| Error::io(path, e))
+}
+
+/// Accept the same body as `/process`, answering 202 with the job record once the upload is read
+pub(crate) async fn submit(State(state): State<Arc<AppState>>, request: Request) -> std::result::Result<Response, ApiError> {
+    state.jobs.purge_expired();
+    if state.shutdown.is_draining() {
+        return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "shutting_down", "The server is shutting down, try another replica"));
+    }
+    if state.jobs.pending() >= state.config().jobs.max_pending {
+        return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "overloaded", "Too many pending jobs, try again later"));
+    }
+    let client = request.extensions().get::<Client>().map(Client::identity);
+    let upload_deadline = Instant::now() + state.config().request_timeout;
+    let upload = tokio::time::timeout_at(upload_deadline.into(), read_request(&state, request))
+        .await
+        .map_err(|_| ApiError(Error::Timeout { stage: "upload" }))??;
+
+    let job = enqueue(&state, upload, client).await?;
+    let location = format!("/jobs/{}", job.id);
+    Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(job)).into_response())
+}
+
+/// Record `upload` as a queued job of `client` and run it in the background once a processing
+/// slot is free; callers check [`JobStore::pending`] against the limit first
+pub(crate) async fn enqueue(state: &Arc<AppState>, upload: ProcessRequest, client: Option<String>) -> Result<Job> {
+    let created_at = now();
+    let mut job = Job {
+        id: uuid::Uuid::new_v4().to_string(),
+        status: JobStatus::Queued,
+        created_at,
+        updated_at: created_at,
+        client,
+        error: None,
+        result: None,
+        output: None,
+    };
+    let output = match &upload.tenant {
+        Some(name) => state.config().tenant(name)?.output_for(&format!("{}.png", job.id)),
+        None => None,
+    };
+    state.jobs.save(&job).await?;
+    info!("Queued job {}", job.id);
+
+    let queued = job.clone();
+    let state = state.clone();
+    // A shutdown waits for queued jobs as well as running ones
+    let work = state.shutdown.track();
+    crate::asynchronous::spawn(async move {
+        let _work = work;
+        let _slot = state.limits.queue.wait().await;
+        job.update(JobStatus::Running);
+        if let Err(e) = state.jobs.save(&job).await {
+            warn!("Failed to record job {}: {}", job.id, e);
+        }
+        let started = Instant::now();
+        let outcome = match render(&state, upload, started + state.config().jobs.timeout).await {
+            Ok(png) => deliver(&state, &job.id, output.as_deref(), png).await.map_err(ApiError),
+            Err(e) => Err(e),
+        };
+        match outcome {
+            Ok(()) => {
+                job.update(JobStatus::Succeeded);
+                job.result = Some(format!("/jobs/{}/result", job.id));
+                job.output = output.as_deref().map(|output| without_credentials(output).to_string());
+                info!("Job {} succeeded in {}ms", job.id, started.elapsed().as_millis());
+            }
+            Err(e) => {
+                job.update(JobStatus::Failed);
+                warn!("Job {} failed: {}", job.id, e.0);
+                job.error = Some(JobError { error: e.0.kind().to_string(), message: e.0.to_string(), status: e.status().as_u16() });
+            }
+        }
+        if let Err(e) = state.jobs.save(&job).await {
+            warn!("Failed to record job {}: {}", job.id, e);
+        }
+    });
+    Ok(queued)
+}
+
+/// Store the result of job `id`, and copy it to `output` for a tenant with an output prefix
+async fn deliver(state: &AppState, id: &str, output: Option<&str>, png: Vec<u8>) -> Result<()> {
+    if let Some(output) = output {
+        write_reference(state, output, png.clone()).await?;
+    }
+    state.jobs.save_result(id, png).await
+}
+
+/// Current record of job `id`
+pub(crate) async fn status(State(state): State<Arc<AppState>>, Path(id): Path<String>, request: Request) -> std::result::Result<Response, ApiError> {
+    Ok(match visible_job(&state, &id, request).await? {
+        Some(job) => {
+            let pending = !job.status.is_finished();
+            let mut response = Json(job).into_response();
+            if pending {
+                response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(POLL_INTERVAL_SECS));
+            }
+            response
+        }
+        None => not_found(&id),
+    })
+}
+
+/// PNG produced by job `id`; 409 while it is still pending or when it failed, 410 once the
+/// result expired
+pub(crate) async fn result(State(state): State<Arc<AppState>>, Path(id): Path<String>, request: Request) -> std::result::Result<Response, ApiError> {
+    let Some(job) = visible_job(&state, &id, request).await? else {
+        return Ok(not_found(&id));
+    };
+    if job.status != JobStatus::Succeeded {
+        let message = format!("Job {} has no result (status {})", id, job.status.name());
+        let mut response = error_response(StatusCode::CONFLICT, "not_ready", &message);
+        if !job.status.is_finished() {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(POLL_INTERVAL_SECS));
+        }
+        return Ok(response);
+    }
+    match state.jobs.result(&id).await? {
+        Some(png) => Ok(([(header::CONTENT_TYPE, "image/png")], png.as_ref().clone()).into_response()),
+        None => Ok(error_response(StatusCode::GONE, "expired", &format!("Result of job {} is no longer available", id))),
+    }
+}
+
+async fn visible_job(state: &AppState, id: &str, request: Request) -> Result<Option<Job>> {
+    let caller = request.extensions().get::<Client>().map(Client::identity);
+    Ok(state.jobs.get(id).await?.filter(|job| visible_to(job, caller.as_deref())))
+}
+
+/// Whether `caller` may read `job`: jobs of an authenticated client are hidden from other clients
+pub(crate) fn visible_to(job: &Job, caller: Option<&str>) -> bool {
+    !matches!((&job.client, caller), (Some(owner), Some(caller)) if owner != caller)
+}
+
+fn not_found(id: &str) -> Response {
+    error_response(StatusCode::NOT_FOUND, "not_found", &format!("Unknown job: {}", id))
+}
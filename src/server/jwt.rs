@@ -0,0 +1,155 @@
+//! JWT bearer token validation against an OIDC issuer's signing keys (JWKS).
+
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::JwtConfig;
+
+/// Timeout for discovery and JWKS requests
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest gap between refetches triggered by an unknown key ID, so forged tokens can't flood the issuer
+const MIN_REFETCH: Duration = Duration::from_secs(10);
+
+/// Claims of a validated token, attached to the request for handlers and logging
+#[derive(Debug, Clone)]
+pub struct Claims(pub Map<String, Value>);
+
+impl Claims {
+    /// String value of `claim`, if present
+    pub fn get(&self, claim: &str) -> Option<&str> {
+        self.0.get(claim).and_then(Value::as_str)
+    }
+}
+
+/// Why a token was not accepted
+#[derive(Debug)]
+pub(crate) enum Rejection {
+    /// Malformed, expired, wrongly signed or for another issuer or audience (401)
+    Invalid(String),
+    /// The signing keys could not be fetched (503)
+    Unavailable(String),
+}
+
+#[derive(Debug)]
+struct FetchedKeys {
+    set: JwkSet,
+    fetched: Instant,
+}
+
+/// Validates bearer tokens, caching the issuer's signing keys
+#[derive(Debug)]
+pub(crate) struct JwtValidator {
+    config: JwtConfig,
+    algorithms: Vec<Algorithm>,
+    http: reqwest::Client,
+    keys: Mutex<Option<FetchedKeys>>,
+}
+
+impl JwtValidator {
+    pub fn new(config: JwtConfig) -> Self {
+        // Names were checked against JWT_ALGORITHMS when the config was read
+        let algorithms = config.algorithms.iter().filter_map(|name| Algorithm::from_str(name).ok()).collect();
+        JwtValidator { config, algorithms, http: reqwest::Client::new(), keys: Mutex::new(None) }
+    }
+
+    pub fn identity_claim(&self) -> &str {
+        &self.config.identity_claim
+    }
+
+    pub fn tenant_claim(&self) -> &str {
+        &self.config.tenant_claim
+    }
+
+    /// Check the signature, issuer, audience, expiry and not-before time of `token`
+    pub async fn validate(&self, token: &str) -> std::result::Result<Claims, Rejection> {
+        let header = decode_header(token).map_err(|e| Rejection::Invalid(format!("Malformed token: {}", e)))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(Rejection::Invalid(format!("Token algorithm {:?} is not accepted", header.alg)));
+        }
+        let kid = header.kid.ok_or_else(|| Rejection::Invalid("Token has no key ID (kid)".to_string()))?;
+        let jwk = self.key(&kid).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| Rejection::Invalid(format!("Unusable signing key {}: {}", kid, e)))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.leeway = self.config.leeway.as_secs();
+        validation.validate_nbf = true;
+        if self.config.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audiences);
+        }
+        let data = decode::<Map<String, Value>>(token, &key, &validation)
+            .map_err(|e| Rejection::Invalid(format!("Invalid token: {}", e)))?;
+        Ok(Claims(data.claims))
+    }
+
+    /// Signing key `kid`, refetching the key set when it is stale or doesn't contain the key
+    async fn key(&self, kid: &str) -> std::result::Result<Jwk, Rejection> {
+        let refetch = {
+            let keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match keys.as_ref() {
+                Some(keys) if keys.fetched.elapsed() < self.config.jwks_refresh => match keys.set.find(kid) {
+                    Some(jwk) => return Ok(jwk.clone()),
+                    None => keys.fetched.elapsed() >= MIN_REFETCH,
+                },
+                _ => true,
+            }
+        };
+        if refetch {
+            match self.fetch().await {
+                Ok(set) => {
+                    let mut keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    *keys = Some(FetchedKeys { set, fetched: Instant::now() });
+                }
+                Err(e) => {
+                    warn!("Failed to fetch JWT signing keys: {}", e);
+                    // Keep using stale keys rather than locking every client out
+                    let keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    return match keys.as_ref().and_then(|keys| keys.set.find(kid)) {
+                        Some(jwk) => Ok(jwk.clone()),
+                        None => Err(Rejection::Unavailable(format!("Signing keys unavailable: {}", e))),
+                    };
+                }
+            }
+        }
+        let keys = self.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        keys.as_ref()
+            .and_then(|keys| keys.set.find(kid))
+            .cloned()
+            .ok_or_else(|| Rejection::Invalid(format!("Unknown signing key: {}", kid)))
+    }
+
+    /// The key set at `jwks_url`, or at the `jwks_uri` of the issuer's discovery document
+    async fn fetch(&self) -> std::result::Result<JwkSet, String> {
+        let jwks_url = match &self.config.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                let discovery = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+                let document: Value = self.get_json(&discovery).await?;
+                document.get("jwks_uri")
+                    .and_then(Value::as_str)
+                    .map(String::from)
+                    .ok_or_else(|| format!("{} has no jwks_uri", discovery))?
+            }
+        };
+        let set: JwkSet = self.get_json(&jwks_url).await?;
+        info!("Fetched {} JWT signing key(s) from {}", set.keys.len(), jwks_url);
+        Ok(set)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> std::result::Result<T, String> {
+        let response = self.http.get(url).timeout(FETCH_TIMEOUT).send().await.map_err(|e| format!("{}: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("{} returned HTTP {}", url, response.status().as_u16()));
+        }
+        let bytes = response.bytes().await.map_err(|e| format!("{}: {}", url, e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("{}: invalid JSON: {}", url, e))
+    }
+}
@@ -0,0 +1,110 @@
+//! Kafka worker mode (`worker --transport kafka`): consume processing requests from a topic and publish a result
+//! event for each, committing a request only once its result is acknowledged.
+
+use log::{info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::Message;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use std::sync::Arc;
+
+use super::worker;
+use super::AppState;
+use crate::config::{KafkaConfig, OrchestrateConfig};
+use crate::error::{Error, Result};
+
+fn kafka_error(message: impl std::fmt::Display) -> Error {
+    Error::Service { service: "kafka".to_string(), message: message.to_string() }
+}
+
+/// Client settings shared by the consumer and the producer; `KAFKA_PROPERTIES` come last so
+/// they can override the defaults
+fn client_config(kafka: &KafkaConfig, defaults: &[(&str, String)]) -> ClientConfig {
+    let mut client = ClientConfig::new();
+    client.set("bootstrap.servers", &kafka.brokers);
+    for (name, value) in defaults {
+        client.set(*name, value);
+    }
+    for (name, value) in &kafka.properties {
+        client.set(name, value);
+    }
+    client
+}
+
+/// Process requests from `kafka.request_topic` one at a time until the process is stopped
+///
+/// Offsets are stored after the result event is delivered, so a worker that dies mid-request
+/// leaves it to be redelivered to another member of the group (at-least-once). Requests that fail
+/// still get a result event and are committed; only a failure to publish stops the worker. On
+/// SIGTERM no more requests are taken, the one in flight gets until the drain timeout, and the
+/// stored offsets are committed before the worker leaves the group.
+pub async fn consume(config: OrchestrateConfig, kafka: KafkaConfig) -> Result<()> {
+    // A request may run for the whole job timeout without polling the consumer
+    let poll_interval = (config.jobs.timeout.as_millis() + 60_000).max(300_000);
+    let consumer: StreamConsumer = client_config(&kafka, &[
+        ("group.id", kafka.group_id.clone()),
+        ("enable.auto.commit", "true".to_string()),
+        ("enable.auto.offset.store", "false".to_string()),
+        ("auto.offset.reset", "earliest".to_string()),
+        ("max.poll.interval.ms", poll_interval.min(i32::MAX as u128).to_string()),
+    ])
+    .create()
+    .map_err(|e| kafka_error(format!("failed to create consumer: {}", e)))?;
+    let producer: FutureProducer = client_config(&kafka, &[("enable.idempotence", "true".to_string())])
+        .create()
+        .map_err(|e| kafka_error(format!("failed to create producer: {}", e)))?;
+    consumer
+        .subscribe(&[&kafka.request_topic])
+        .map_err(|e| kafka_error(format!("failed to subscribe to {}: {}", kafka.request_topic, e)))?;
+    info!("Consuming {} as group {} from {}, publishing to {}",
+          kafka.request_topic, kafka.group_id, kafka.brokers, kafka.result_topic);
+
+    let state = Arc::new(AppState::new(config).await?);
+    super::reload::watch(&state);
+    super::shutdown::listen(&state);
+    loop {
+        let received = tokio::select! {
+            received = consumer.recv() => received,
+            _ = state.shutdown.requested() => break,
+        };
+        let message = match received {
+            Ok(message) => message,
+            // Broker and rebalance errors are retried by the client
+            Err(e) => {
+                warn!("Kafka consumer error: {}", e);
+                continue;
+            }
+        };
+        let fallback_id = message.key()
+            .and_then(|key| std::str::from_utf8(key).ok())
+            .map(String::from)
+            .unwrap_or_else(|| format!("{}-{}-{}", message.topic(), message.partition(), message.offset()));
+        let result = tokio::select! {
+            result = worker::process(&state, message.payload().unwrap_or_default(), fallback_id) => result,
+            // Its offset isn't stored, so the request goes to another member of the group
+            _ = state.shutdown.expired() => {
+                warn!("Drain timeout passed, leaving offset {} of partition {} to be redelivered",
+                      message.offset(), message.partition());
+                break;
+            }
+        };
+
+        let event = serde_json::to_vec(&result).map_err(|e| Error::Server(format!("Failed to serialize result event: {}", e)))?;
+        producer
+            .send(FutureRecord::to(&kafka.result_topic).key(&result.id).payload(&event), kafka.publish_timeout)
+            .await
+            .map_err(|(e, _)| kafka_error(format!("failed to publish the result of {} to {}: {}", result.id, kafka.result_topic, e)))?;
+        consumer
+            .store_offset_from_message(&message)
+            .map_err(|e| kafka_error(format!("failed to store the offset of {}: {}", result.id, e)))?;
+    }
+
+    if let Err(e) = producer.flush(kafka.publish_timeout) {
+        warn!("Failed to flush result events: {}", e);
+    }
+    if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+        warn!("Failed to commit offsets on shutdown: {}", e);
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
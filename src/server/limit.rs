@@ -0,0 +1,244 @@
+//! Admission control: per-client and global rate limits, a bounded queue for processing slots and
+//! a bound on the uploads read at once.
+
+use axum::extract::connect_info::ConnectInfo;
+use axum::extract::{Request, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::auth::Client;
+use super::{error_response, request_id, AppState};
+use crate::config::{LimitConfig, PoolConfig};
+
+/// Retry hint sent when the queue is full or a queued request timed out
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// How often buckets that have refilled are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tokens at `now` of a bucket holding `capacity`, refilled over one minute
+    fn tokens_at(&self, now: Instant, capacity: f64) -> f64 {
+        (self.tokens + now.duration_since(self.updated).as_secs_f64() * capacity / 60.0).min(capacity)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+impl Buckets {
+    fn available(&self, key: &str, capacity: f64, now: Instant) -> f64 {
+        self.buckets.get(key).map_or(capacity, |bucket| bucket.tokens_at(now, capacity))
+    }
+
+    fn take(&mut self, key: &str, capacity: f64, now: Instant) {
+        let tokens = self.available(key, capacity, now) - 1.0;
+        match self.buckets.get_mut(key) {
+            Some(bucket) => *bucket = Bucket { tokens, capacity, updated: now },
+            None => {
+                self.buckets.insert(key.to_string(), Bucket { tokens, capacity, updated: now });
+            }
+        }
+        // A full bucket is no different from a new one, so peers that stopped calling don't pile up
+        if now.duration_since(self.swept) >= SWEEP_INTERVAL {
+            self.buckets.retain(|_, bucket| bucket.tokens_at(now, bucket.capacity) < bucket.capacity);
+            self.swept = now;
+        }
+    }
+}
+
+/// Token buckets that allow a burst of one minute's worth of requests
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter { buckets: Mutex::new(Buckets { buckets: HashMap::new(), swept: Instant::now() }) }
+    }
+}
+
+impl RateLimiter {
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Bucket of a [`RateLimiter`] a request counts against, with its requests per minute
+pub(crate) struct Limit<'a> {
+    pub limiter: &'a RateLimiter,
+    pub key: &'a str,
+    pub per_minute: u32,
+}
+
+/// Take a token from each of `limits` when every one has a token, and otherwise none, returning
+/// the position of the first exhausted limit and how long until its next token; the limiters are
+/// locked in order, so callers pass them in the same order
+pub(crate) fn acquire(limits: &[Limit<'_>]) -> std::result::Result<(), (usize, Duration)> {
+    let now = Instant::now();
+    let mut buckets: Vec<MutexGuard<'_, Buckets>> = limits.iter().map(|limit| limit.limiter.lock()).collect();
+    for (index, (limit, buckets)) in limits.iter().zip(&buckets).enumerate() {
+        let capacity = f64::from(limit.per_minute.max(1));
+        let tokens = buckets.available(limit.key, capacity, now);
+        if tokens < 1.0 {
+            return Err((index, Duration::from_secs_f64((1.0 - tokens) / (capacity / 60.0))));
+        }
+    }
+    for (limit, buckets) in limits.iter().zip(&mut buckets) {
+        buckets.take(limit.key, f64::from(limit.per_minute.max(1)), now);
+    }
+    Ok(())
+}
+
+/// Processing slots with a bounded number of waiting requests
+#[derive(Debug)]
+pub(crate) struct Queue {
+    slots: Semaphore,
+    waiting: AtomicUsize,
+}
+
+impl Queue {
+    fn new(limits: &LimitConfig) -> Self {
+        Queue { slots: Semaphore::new(limits.max_concurrent), waiting: AtomicUsize::new(0) }
+    }
+
+    /// Take a slot, waiting in line if all are busy; None when the line is full or the wait times out
+    async fn enter(&self, limits: &LimitConfig) -> Option<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Some(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= limits.max_queued {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let permit = tokio::time::timeout(limits.queue_timeout, self.slots.acquire()).await;
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        permit.ok().and_then(|permit| permit.ok())
+    }
+
+    /// Take a slot for a background job, waiting as long as it takes
+    pub async fn wait(&self) -> Option<SemaphorePermit<'_>> {
+        self.slots.acquire().await.ok()
+    }
+
+    /// Requests being processed and waiting
+    pub fn load(&self, limits: &LimitConfig) -> (usize, usize) {
+        (limits.max_concurrent.saturating_sub(self.slots.available_permits()), self.waiting.load(Ordering::SeqCst))
+    }
+
+    /// Change the number of slots from `from` to `to`; fewer slots take effect as busy ones
+    /// are given back
+    pub async fn resize(&self, from: usize, to: usize) {
+        if to > from {
+            self.slots.add_permits(to - from);
+        } else if from > to {
+            let missing = (from - to) - self.slots.forget_permits(from - to);
+            if missing > 0
+                && let Ok(permits) = self.slots.acquire_many(missing as u32).await
+            {
+                permits.forget();
+            }
+        }
+    }
+}
+
+/// Rate limits and the processing queue shared by all requests
+#[derive(Debug)]
+pub(crate) struct Limits {
+    pub clients: RateLimiter,
+    /// Buckets shared by all clients of a tenant
+    tenants: RateLimiter,
+    global: RateLimiter,
+    pub queue: Queue,
+    /// Slots for reading request bodies, so slow uploads can't tie up every processing slot's IO
+    uploads: Semaphore,
+}
+
+impl Limits {
+    pub fn new(limits: &LimitConfig, pools: &PoolConfig) -> Self {
+        Limits {
+            clients: RateLimiter::default(),
+            tenants: RateLimiter::default(),
+            global: RateLimiter::default(),
+            queue: Queue::new(limits),
+            uploads: Semaphore::new(pools.max_uploads),
+        }
+    }
+
+    /// Wait for a free upload slot, held while the request body is read
+    pub async fn upload(&self) -> Option<SemaphorePermit<'_>> {
+        self.uploads.acquire().await.ok()
+    }
+}
+
+/// Answer 429 when the client, tenant or global rate limit is exhausted and 503 when the queue is
+/// saturated, both with `Retry-After`; runs after authentication so keys can carry their own limit
+pub(crate) async fn admit(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let settings = state.config();
+    let config = &settings.limits;
+    let client = request.extensions().get::<Client>().cloned();
+    let client_name = match &client {
+        Some(client) => client.identity(),
+        None => request.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| peer.ip().to_string())
+            .unwrap_or_else(|| "-".to_string()),
+    };
+
+    // Every limit is checked before a token is taken, so a request turned away by one limit
+    // doesn't use up the others
+    let mut limits = Vec::new();
+    let client_limit = client.as_ref().and_then(|client| client.requests_per_minute).or(config.client_per_minute);
+    if let Some(limit) = client_limit {
+        let message = format!("Rate limit of {} requests per minute exceeded for client {}", limit, client_name);
+        limits.push((Limit { limiter: &state.limits.clients, key: &client_name, per_minute: limit }, message));
+    }
+    if let Some(tenant) = client.as_ref().and_then(|client| client.tenant.as_deref())
+        && let Some(limit) = settings.tenants.get(tenant).and_then(|tenant| tenant.requests_per_minute)
+    {
+        let message = format!("Rate limit of {} requests per minute exceeded for tenant {}", limit, tenant);
+        limits.push((Limit { limiter: &state.limits.tenants, key: tenant, per_minute: limit }, message));
+    }
+    if let Some(limit) = config.global_per_minute {
+        let message = format!("Server rate limit of {} requests per minute exceeded", limit);
+        limits.push((Limit { limiter: &state.limits.global, key: "", per_minute: limit }, message));
+    }
+    let (limits, messages): (Vec<Limit>, Vec<String>) = limits.into_iter().unzip();
+    if let Err((index, wait)) = acquire(&limits) {
+        return rejected(StatusCode::TOO_MANY_REQUESTS, "rate_limited", &messages[index], wait);
+    }
+
+    let Some(_slot) = state.limits.queue.enter(config).await else {
+        let (active, waiting) = state.limits.queue.load(config);
+        warn!("Turned away {} {}: {} requests active, {} queued (request {})",
+              request.method(), request.uri().path(), active, waiting, request_id(&request));
+        return rejected(StatusCode::SERVICE_UNAVAILABLE, "overloaded", "Server is at capacity, try again later", OVERLOADED_RETRY_AFTER);
+    };
+    next.run(request).await
+}
+
+fn rejected(status: StatusCode, kind: &str, message: &str, retry_after: Duration) -> Response {
+    let mut response = error_response(status, kind, message);
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs_f64().ceil().max(1.0) as u64));
+    response
+}
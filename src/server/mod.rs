@@ -0,0 +1,368 @@
+//! HTTP server mode (`serve`), exposing the pipeline as a processing microservice.
+
+mod admin;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod auth;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod health;
+pub mod jobs;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+mod limit;
+#[cfg(feature = "onnx")]
+mod models;
+mod process;
+#[cfg(feature = "redis")]
+mod redis;
+mod reference;
+mod reload;
+mod shutdown;
+mod stow;
+pub mod threads;
+#[cfg(feature = "tls")]
+mod tls;
+mod validate;
+#[cfg(any(feature = "kafka", feature = "amqp"))]
+pub mod worker;
+
+use axum::extract::{DefaultBodyLimit, Request};
+use axum::http::{HeaderName, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{info, warn};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer};
+
+use crate::asynchronous::CpuPool;
+use crate::buffers::BufferPool;
+use crate::cache::{DecodedImageCache, ResultCache};
+use crate::config::OrchestrateConfig;
+use crate::error::{Error, Result};
+use crate::heatmap::HeatmapRegistry;
+use limit::Limits;
+#[cfg(feature = "service")]
+use crate::service::ServiceClient;
+
+/// Header carrying the request ID, taken from the client when present and generated otherwise
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Shared state handed to every handler
+#[derive(Debug)]
+pub struct AppState {
+    /// Current settings, replaced when a reload changes the services or limits
+    config: RwLock<Arc<OrchestrateConfig>>,
+    pub registry: Arc<HeatmapRegistry>,
+    /// Shared so DL service connections are reused across requests
+    #[cfg(feature = "service")]
+    pub client: ServiceClient,
+    /// Local models kept loaded for requests naming them
+    #[cfg(feature = "onnx")]
+    pub(crate) models: Arc<models::ModelSessions>,
+    /// Post-processing plugin applied to every rendering
+    #[cfg(feature = "plugins")]
+    pub(crate) plugin: Option<Arc<crate::plugin::HeatmapPlugin>>,
+    /// GPU renderer of every rendering with `ORCHESTRATE_BACKEND=gpu`
+    #[cfg(feature = "gpu")]
+    pub(crate) gpu: Option<Arc<crate::gpu::GpuRenderer>>,
+    pub(crate) limits: Limits,
+    /// Slots for decoding, rendering and encoding, shared by every request
+    pub(crate) cpu: CpuPool,
+    /// Heatmap buffers reused by the pipelines of every request
+    pub(crate) buffers: BufferPool,
+    pub(crate) jobs: jobs::JobStore,
+    /// Set once the process was asked to stop; tracks the background work still running
+    pub(crate) shutdown: Arc<shutdown::Shutdown>,
+    /// Fused images by instance, models and options, for repeat requests
+    pub(crate) renders: ResultCache<Vec<u8>>,
+    /// Decoded images by instance, so re-rendering them with other options skips the decode
+    pub(crate) images: Arc<DecodedImageCache>,
+    /// Options for instances pushed to `/studies`
+    pub(crate) stow_options: process::ProcessOptions,
+    /// Where `wado` UIDs in the options are retrieved from
+    #[cfg(feature = "dicomweb")]
+    pub(crate) dicomweb: Option<crate::dicomweb::DicomWebClient>,
+    #[cfg(feature = "jwt")]
+    pub(crate) jwt: Option<jwt::JwtValidator>,
+    /// Clients for the object references of worker requests
+    #[cfg(feature = "storage")]
+    pub(crate) storage: crate::storage::ObjectStores,
+}
+
+impl AppState {
+    /// Shared state for `config`, compiling the plugin, opening the GPU, connecting to the job
+    /// store or loading the jobs stored in the job directory, and warming up the local models in
+    /// the background
+    pub async fn new(config: OrchestrateConfig) -> Result<Self> {
+        let stow_options = match &config.stow_options {
+            Some(options) => serde_json::from_str(options)
+                .map_err(|e| Error::InvalidOption(format!("Invalid ORCHESTRATE_STOW_OPTIONS: {}", e)))?,
+            None => process::ProcessOptions::default(),
+        };
+        let cpu = CpuPool::new(config.pools.cpu_threads);
+        #[cfg(feature = "onnx")]
+        let models = {
+            let models = Arc::new(models::ModelSessions::new(&config.models));
+            models.warm_up();
+            models
+        };
+        #[cfg(feature = "plugins")]
+        let plugin = match &config.plugin {
+            Some(path) => Some(Arc::new(crate::plugin::HeatmapPlugin::load(path)?.with_fuel(config.plugin_fuel))),
+            None => None,
+        };
+        #[cfg(feature = "gpu")]
+        let gpu = match config.backend {
+            crate::gpu::Backend::Gpu => Some(Arc::new(crate::gpu::GpuRenderer::new()?)),
+            crate::gpu::Backend::Cpu => None,
+        };
+        Ok(AppState {
+            #[cfg(feature = "onnx")]
+            models,
+            #[cfg(feature = "plugins")]
+            plugin,
+            #[cfg(feature = "gpu")]
+            gpu,
+            stow_options,
+            #[cfg(feature = "dicomweb")]
+            dicomweb: config.dicomweb.clone().map(crate::dicomweb::DicomWebClient::new).transpose()?,
+            #[cfg(feature = "jwt")]
+            jwt: config.jwt.clone().map(jwt::JwtValidator::new),
+            #[cfg(feature = "storage")]
+            storage: crate::storage::ObjectStores::default(),
+            limits: Limits::new(&config.limits, &config.pools),
+            cpu: cpu.clone(),
+            buffers: BufferPool::new(config.pools.heatmap_buffers),
+            jobs: jobs::JobStore::open(&config.jobs).await?,
+            shutdown: Arc::default(),
+            renders: ResultCache::new(&config.cache),
+            images: Arc::new(ResultCache::new(&config.cache.decoded_image_cache())),
+            #[cfg(feature = "service")]
+            client: ServiceClient::new()
+                .with_cache(&config.cache)
+                .with_max_calls(config.pools.max_service_calls)
+                .with_cpu_pool(cpu),
+            config: RwLock::new(Arc::new(config)),
+            registry: Arc::new(HeatmapRegistry::default()),
+        })
+    }
+
+    /// Settings in effect; a request keeps the snapshot it started with across reloads
+    pub fn config(&self) -> Arc<OrchestrateConfig> {
+        self.config.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+/// All routes with request-ID and access-log middleware; everything but the health probes
+/// requires an API key or bearer token when authentication is configured, submissions pass
+/// admission control (job polling and the admin endpoints don't), `/admin` is limited to the
+/// configured admins, and REST uploads are checked before they are read
+pub fn router(state: Arc<AppState>) -> Router {
+    let request_id = HeaderName::from_static(REQUEST_ID_HEADER);
+    let protected = Router::new()
+        .route("/process", post(process::process))
+        .route("/jobs", post(jobs::submit))
+        .route("/studies", post(stow::store))
+        .route("/studies/{study}", post(stow::store_in_study))
+        .route_layer(middleware::from_fn_with_state(state.clone(), validate::check_upload));
+    #[cfg(feature = "grpc")]
+    let protected = {
+        let grpc = grpc::service(state.clone());
+        protected
+            .route_service(&format!("{}/Process", grpc::SERVICE_PATH), grpc.clone())
+            .route_service(&format!("{}/ProcessStream", grpc::SERVICE_PATH), grpc.clone())
+    };
+    let protected = protected
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit::admit))
+        .route("/jobs/{id}", get(jobs::status))
+        .route("/jobs/{id}/result", get(jobs::result));
+    #[cfg(feature = "grpc")]
+    let protected = protected.route_service(&format!("{}/GetJob", grpc::SERVICE_PATH), grpc::service(state.clone()));
+    let admin = Router::new()
+        .route("/admin/config", get(admin::config))
+        .route("/admin/services", get(admin::services))
+        .route("/admin/cache", get(admin::cache))
+        .route("/admin/jobs", get(admin::jobs))
+        .route_layer(middleware::from_fn_with_state(state.clone(), admin::require_admin));
+    let protected = protected
+        .merge(admin)
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::authenticate));
+    let router = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .merge(protected)
+        .layer(DefaultBodyLimit::max(state.config().max_body_bytes));
+    #[cfg(feature = "telemetry")]
+    let router = router.layer(middleware::from_fn(trace_request));
+    router
+        .layer(middleware::from_fn(access_log))
+        .layer(PropagateRequestIdLayer::new(request_id.clone()))
+        .layer(SetRequestIdLayer::new(request_id, MakeRequestUuid))
+        .with_state(state)
+}
+
+/// Bind to the configured address and serve until the process is stopped, then drain: stop
+/// accepting connections, let open requests and background jobs finish up to the drain timeout
+/// and record the jobs it cut short
+pub async fn serve(config: OrchestrateConfig) -> Result<()> {
+    #[cfg(feature = "telemetry")]
+    let _telemetry = crate::telemetry::init()?;
+    #[cfg(not(feature = "jwt"))]
+    if config.jwt.is_some() {
+        return Err(Error::InvalidOption("ORCHESTRATE_JWT_ISSUER is set but the server was built without the jwt feature".to_string()));
+    }
+    #[cfg(not(feature = "tls"))]
+    if config.tls.is_some() {
+        return Err(Error::InvalidOption("ORCHESTRATE_TLS_CERT is set but the server was built without the tls feature".to_string()));
+    }
+    #[cfg(feature = "tls")]
+    let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
+    let address = config.bind_address();
+    let listener = tokio::net::TcpListener::bind(&address)
+        .await
+        .map_err(|e| Error::Server(format!("Failed to bind {}: {}", address, e)))?;
+    info!("Listening on {}://{}", if config.tls.is_some() { "https" } else { "http" }, address);
+    if config.api_keys.is_empty() && config.jwt.is_none() && !matches!(config.host.as_str(), "127.0.0.1" | "localhost" | "::1") {
+        warn!("No API keys or JWT issuer configured: /process accepts unauthenticated requests on {}", address);
+    }
+
+    let pools = &config.pools;
+    info!("Running up to {} CPU-bound task(s), {} upload(s) and {} DL service call(s) at once",
+          pools.cpu_threads, pools.max_uploads, pools.max_service_calls);
+
+    let state = Arc::new(AppState::new(config).await?);
+    reload::watch(&state);
+    shutdown::listen(&state);
+    let app = router(state.clone()).into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let stopping = {
+        let state = state.clone();
+        async move {
+            state.shutdown.requested().await;
+        }
+    };
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        use axum::serve::ListenerExt;
+        // Tapped listeners provide the peer address as ConnectInfo
+        let listener = tls::TlsListener::new(listener, tls)?.tap_io(|_| ());
+        return drain(&state, axum::serve(listener, app).with_graceful_shutdown(stopping)).await;
+    }
+    drain(&state, axum::serve(listener, app).with_graceful_shutdown(stopping)).await
+}
+
+/// Run `served` until its connections closed after a shutdown request or the drain timeout
+/// passed, then wait for the background jobs
+async fn drain(state: &AppState, served: impl std::future::IntoFuture<Output = std::io::Result<()>>) -> Result<()> {
+    tokio::select! {
+        served = served.into_future() => served.map_err(|e| Error::Server(e.to_string()))?,
+        _ = state.shutdown.expired() => warn!("Drain timeout passed with requests still open, closing their connections"),
+    }
+    shutdown::finish(state).await;
+    Ok(())
+}
+
+async fn access_log(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let request_id = request_id(&request);
+
+    let response = next.run(request).await;
+    let status = response.status();
+    let elapsed = started.elapsed().as_millis();
+    let client = response.extensions().get::<auth::Client>().map(|client| client.name.as_str()).unwrap_or("-");
+    if status.is_server_error() {
+        warn!("{} {} -> {} in {}ms (request {}, client {})", method, path, status.as_u16(), elapsed, request_id, client);
+    } else {
+        info!("{} {} -> {} in {}ms (request {}, client {})", method, path, status.as_u16(), elapsed, request_id, client);
+    }
+    response
+}
+
+/// Server span for the request, continuing the caller's `traceparent` and tagged with the request ID
+#[cfg(feature = "telemetry")]
+async fn trace_request(request: Request, next: Next) -> Response {
+    use opentelemetry::context::FutureExt;
+    use opentelemetry::trace::{SpanKind, Status, TraceContextExt};
+    use opentelemetry::KeyValue;
+
+    let request_id = request_id(&request);
+    let path = request.uri().path().to_string();
+    let attributes = vec![
+        KeyValue::new("http.request.method", request.method().to_string()),
+        KeyValue::new("url.path", path.clone()),
+        KeyValue::new("http.route", path.clone()),
+        KeyValue::new("request_id", request_id.clone()),
+    ];
+    let parent = crate::telemetry::extract(request.headers()).with_value(crate::telemetry::RequestId(request_id));
+    let cx = crate::telemetry::start(&parent, format!("{} {}", request.method(), path), SpanKind::Server, attributes);
+
+    let response = next.run(request).with_context(cx.clone()).await;
+    let span = cx.span();
+    span.set_attribute(KeyValue::new("http.response.status_code", i64::from(response.status().as_u16())));
+    if let Some(client) = response.extensions().get::<auth::Client>() {
+        span.set_attribute(KeyValue::new("client", client.name.clone()));
+    }
+    if response.status().is_server_error() {
+        span.set_status(Status::error(response.status().to_string()));
+    }
+    span.end();
+    response
+}
+
+/// Request ID assigned by [`SetRequestIdLayer`], or "-" outside the middleware stack
+pub(crate) fn request_id(request: &Request) -> String {
+    request.extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Pipeline error rendered as a JSON body with a matching status code
+#[derive(Debug)]
+pub struct ApiError(pub Error);
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        ApiError(error)
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self.0 {
+            Error::InvalidOption(_) => StatusCode::BAD_REQUEST,
+            Error::DicomDecode { .. } | Error::HeatmapLoad { .. } | Error::ShapeMismatch { .. } | Error::QualityControl(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::Service { .. } => StatusCode::BAD_GATEWAY,
+            Error::Cancelled { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            Error::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Render(_) | Error::Io { .. } | Error::Server(_) | Error::Inference(_) | Error::Plugin(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            warn!("Request failed: {}", self.0);
+        }
+        error_response(status, self.0.kind(), &self.0.to_string())
+    }
+}
+
+/// JSON error body shared by handler errors and middleware rejections
+pub(crate) fn error_response(status: StatusCode, kind: &str, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": kind, "message": message }))).into_response()
+}
@@ -0,0 +1,119 @@
+//! Local ONNX models kept resident by `serve`: each registry model is loaded and warmed up with a
+//! forward pass at startup, so the first request naming it doesn't pay for either.
+
+use image::GrayImage;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+use super::health::Check;
+use crate::config::{ModelConfig, ModelRegistryConfig};
+use crate::error::{Error, Result};
+use crate::inference::{InferenceOptions, OnnxModel, DEFAULT_INPUT_SIZE};
+
+/// A registry model, loaded once by the warm-up or the first request, whichever comes first;
+/// a model that failed to load stays failed until a restart
+#[derive(Debug)]
+struct ResidentModel {
+    config: ModelConfig,
+    loaded: OnceCell<std::result::Result<Warm, String>>,
+}
+
+/// Loaded session with how long loading and the first forward pass took
+#[derive(Debug, Clone)]
+struct Warm {
+    model: Arc<OnnxModel>,
+    warmup: Duration,
+}
+
+/// Resident sessions of the registry models by name
+#[derive(Debug, Default)]
+pub(crate) struct ModelSessions {
+    models: BTreeMap<String, Arc<ResidentModel>>,
+    cache_dir: PathBuf,
+}
+
+impl ModelSessions {
+    /// Sessions for every model of `registry`, all cold until [`ModelSessions::warm_up`] or a
+    /// request loads them
+    pub fn new(registry: &ModelRegistryConfig) -> Self {
+        let models = registry.models.iter()
+            .map(|(name, config)| (name.clone(), Arc::new(ResidentModel { config: config.clone(), loaded: OnceCell::new() })))
+            .collect();
+        ModelSessions { models, cache_dir: registry.cache_dir.clone() }
+    }
+
+    /// Load and warm every model in the background, one after the other so their downloads and GPU
+    /// allocations don't compete
+    pub fn warm_up(self: &Arc<Self>) {
+        if self.models.is_empty() {
+            return;
+        }
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            for model in sessions.models.values() {
+                let _ = sessions.load(model).await;
+            }
+        });
+    }
+
+    /// Session of model `name`, waiting for its warm-up to finish
+    pub async fn get(&self, name: &str) -> Result<Arc<OnnxModel>> {
+        let model = self.models.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.models.keys().map(String::as_str).collect();
+            Error::InvalidOption(format!("Unknown model: {}. Configured: {}", name,
+                                         if known.is_empty() { "none".to_string() } else { known.join(", ") }))
+        })?;
+        match self.load(model).await {
+            Ok(warm) => Ok(warm.model.clone()),
+            Err(e) => Err(Error::Inference(format!("Model {} failed to load: {}", name, e))),
+        }
+    }
+
+    async fn load<'a>(&self, model: &'a ResidentModel) -> &'a std::result::Result<Warm, String> {
+        model.loaded.get_or_init(|| async {
+            let (config, cache_dir) = (model.config.clone(), self.cache_dir.clone());
+            let name = config.name.clone();
+            let loaded = tokio::task::spawn_blocking(move || warm(&config, &cache_dir))
+                .await
+                .unwrap_or_else(|e| Err(Error::Server(format!("Background task failed: {}", e))));
+            match loaded {
+                Ok(warm) => {
+                    info!("Model {} is warm after {}ms", name, warm.warmup.as_millis());
+                    Ok(warm)
+                }
+                Err(e) => {
+                    warn!("Model {} failed to load: {}", name, e);
+                    Err(e.to_string())
+                }
+            }
+        }).await
+    }
+
+    /// Readiness of every model by name: ok once warm, with the warm-up time
+    pub fn checks(&self) -> BTreeMap<String, Check> {
+        self.models.iter()
+            .map(|(name, model)| {
+                let check = match model.loaded.get() {
+                    None => Check { error: Some("warming up".to_string()), state: Some("cold"), ..Check::default() },
+                    Some(Ok(warm)) => Check { ok: true, latency_ms: Some(warm.warmup.as_millis()), state: Some("warm"), ..Check::default() },
+                    Some(Err(e)) => Check { error: Some(e.clone()), state: Some("failed"), ..Check::default() },
+                };
+                (name.clone(), check)
+            })
+            .collect()
+    }
+}
+
+/// Load `config` and run it once on a blank image, which allocates its buffers and lets the
+/// execution providers compile their kernels
+fn warm(config: &ModelConfig, cache_dir: &std::path::Path) -> Result<Warm> {
+    let start = Instant::now();
+    let model = OnnxModel::from_registry(config, cache_dir, &InferenceOptions::default())?;
+    let side = DEFAULT_INPUT_SIZE as u32;
+    model.infer(&GrayImage::new(side, side), None)?;
+    Ok(Warm { model: Arc::new(model), warmup: start.elapsed() })
+}
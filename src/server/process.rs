@@ -0,0 +1,495 @@
+//! `POST /process`: DICOM + heatmap upload in, fused PNG out.
+
+use axum::body::Body;
+use axum::extract::multipart::{Field, Multipart, MultipartError};
+use axum::extract::{FromRequest, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use http_body_util::BodyExt;
+use log::info;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::auth::Client;
+use super::{ApiError, AppState};
+use crate::cache::{stable_hash, CacheKey};
+use crate::colormap::ColorMap;
+use crate::config::UploadLimits;
+use crate::decision::OperatingPoints;
+use crate::dicom_io::{has_dicom_prefix, sop_instance_uid, DICOM_PREFIX_LEN};
+#[cfg(feature = "onnx")]
+use crate::dicom_io::{decode_dicom_pixel_data, image_dimensions, open_dicom_bytes};
+#[cfg(feature = "dicomweb")]
+use crate::dicomweb::InstanceRef;
+use crate::error::Error;
+#[cfg(feature = "service")]
+use crate::fanout::FanoutMode;
+#[cfg(feature = "service")]
+use crate::heatmap::FusionMethod;
+use crate::normalize::Normalization;
+use crate::output::encode_png_with;
+use crate::pipeline::{HeatmapInput, HeatmapPipeline, ImageSource};
+use crate::provenance::sha256_bytes;
+use crate::render::{Annotation, BlendOptions};
+use crate::spec::PipelineSpec;
+
+/// Processing options sent as the JSON `options` part, the `options` query parameter with a
+/// raw DICOM body, or a JSON body; omitted fields use the CLI defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessOptions {
+    pub colormap: Option<ColorMap>,
+    pub normalization: Option<Normalization>,
+    pub blend: BlendOptions,
+    pub annotations: Vec<Annotation>,
+    /// Format of the `heatmap` part; defaults to its file name extension, then JSON
+    pub heatmap_format: Option<String>,
+    /// Fall back to the default gradient instead of failing when the heatmap can't be parsed
+    pub lenient: bool,
+    /// Thresholds the heatmap's class score is decided at, with the decision drawn onto the image
+    pub operating_points: Option<OperatingPoints>,
+    /// Configured DL services whose heatmaps are layered into one image, instead of a `heatmap` part
+    #[cfg(feature = "service")]
+    pub services: Vec<String>,
+    /// Fuse the `services` heatmaps into one with this method, weighted by the services' weights,
+    /// instead of layering them
+    #[cfg(feature = "service")]
+    pub fusion: Option<FusionMethod>,
+    /// Instance to retrieve from the configured DICOMweb server, instead of an uploaded DICOM
+    #[cfg(feature = "dicomweb")]
+    pub wado: Option<InstanceRef>,
+    /// Registry model run locally on the DICOM for the heatmap of its top class, instead of a
+    /// `heatmap` part or `services`
+    #[cfg(feature = "onnx")]
+    pub model: Option<String>,
+}
+
+/// Uploaded parts of a `/process` request
+#[derive(Debug, Default)]
+pub(crate) struct ProcessRequest {
+    pub dicom: Option<Vec<u8>>,
+    pub heatmap: Option<(Vec<u8>, Option<String>)>,
+    pub options: ProcessOptions,
+    /// Tenant whose policy applies to the request
+    pub tenant: Option<String>,
+    /// Identity of the authenticated client, see [`Client::identity`]
+    pub client: Option<String>,
+}
+
+/// Content types accepted as a bare DICOM body
+const RAW_CONTENT_TYPES: [&str; 2] = ["application/dicom", "application/octet-stream"];
+/// Content types of the upload routes but `/studies`
+pub(crate) const CONTENT_TYPES: [&str; 4] = ["multipart/form-data", "application/json", "application/dicom", "application/octet-stream"];
+
+/// Query string of a raw DICOM upload
+#[derive(Debug, Deserialize)]
+struct RawQuery {
+    options: Option<String>,
+}
+
+/// One input of a request, checked against its own limit while it arrives
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Input {
+    what: &'static str,
+    limit: usize,
+    /// Must open with the `DICM` prefix
+    dicom: bool,
+}
+
+impl Input {
+    pub fn dicom(limits: &UploadLimits) -> Self {
+        Input { what: "DICOM", limit: limits.dicom, dicom: true }
+    }
+
+    pub fn heatmap(limits: &UploadLimits) -> Self {
+        Input { what: "Heatmap", limit: limits.heatmap, dicom: false }
+    }
+
+    pub fn options(limits: &UploadLimits) -> Self {
+        Input { what: "Options", limit: limits.options, dicom: false }
+    }
+
+    /// Reject the input as soon as the bytes received so far show it is too large or not a
+    /// DICOM; `complete` once all of it arrived
+    pub fn check(&self, bytes: &[u8], complete: bool) -> Result<(), ApiError> {
+        if bytes.len() > self.limit {
+            return Err(ApiError(Error::TooLarge { what: self.what.to_string(), limit: self.limit }));
+        }
+        let decidable = bytes.len() >= DICOM_PREFIX_LEN || (complete && !bytes.is_empty());
+        if self.dicom && decidable && !has_dicom_prefix(bytes) {
+            return Err(ApiError(Error::dicom("check the upload", "no DICM prefix, not a DICOM Part 10 object")));
+        }
+        Ok(())
+    }
+}
+
+fn too_large(limit: usize) -> ApiError {
+    ApiError(Error::TooLarge { what: "Request body".to_string(), limit })
+}
+
+fn multipart_error(e: MultipartError, limit: usize) -> ApiError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return too_large(limit);
+    }
+    ApiError(Error::InvalidOption(format!("Invalid multipart body: {}", e)))
+}
+
+pub(crate) fn parse_options(bytes: &[u8]) -> Result<ProcessOptions, ApiError> {
+    serde_json::from_slice(bytes).map_err(|e| ApiError(Error::InvalidOption(format!("Invalid options JSON: {}", e))))
+}
+
+async fn field_bytes(mut field: Field<'_>, limit: usize, input: Input) -> Result<Vec<u8>, ApiError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(|e| multipart_error(e, limit))? {
+        bytes.extend_from_slice(&chunk);
+        input.check(&bytes, false)?;
+    }
+    input.check(&bytes, true)?;
+    Ok(bytes)
+}
+
+/// Reject a part whose declared Content-Type isn't one of `allowed`; parts without one pass
+fn check_part_type(field: &Field<'_>, allowed: &[&str]) -> Result<(), ApiError> {
+    let Some(content_type) = field.content_type() else {
+        return Ok(());
+    };
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if allowed.contains(&media_type.as_str()) {
+        return Ok(());
+    }
+    Err(ApiError(Error::InvalidOption(format!("Part '{}' has Content-Type {}. Expected: {}",
+                                              field.name().unwrap_or_default(), media_type, allowed.join(", ")))))
+}
+
+/// Collect the `dicom`, `heatmap` and `options` parts; each part has its own limit, and the body
+/// limit covers all parts together
+pub(crate) async fn read_multipart(mut multipart: Multipart, limit: usize, uploads: &UploadLimits) -> Result<ProcessRequest, ApiError> {
+    let mut request = ProcessRequest::default();
+    while let Some(field) = multipart.next_field().await.map_err(|e| multipart_error(e, limit))? {
+        match field.name().unwrap_or_default() {
+            "dicom" => {
+                check_part_type(&field, &RAW_CONTENT_TYPES)?;
+                request.dicom = Some(field_bytes(field, limit, Input::dicom(uploads)).await?);
+            }
+            "heatmap" => {
+                let extension = field.file_name()
+                    .and_then(|name| name.rsplit_once('.'))
+                    .map(|(_, extension)| extension.to_string());
+                request.heatmap = Some((field_bytes(field, limit, Input::heatmap(uploads)).await?, extension));
+            }
+            "options" => {
+                check_part_type(&field, &["application/json", "text/plain"])?;
+                request.options = parse_options(&field_bytes(field, limit, Input::options(uploads)).await?)?;
+            }
+            other => {
+                return Err(ApiError(Error::InvalidOption(format!(
+                    "Unexpected multipart field '{}'. Expected: dicom, heatmap, options", other))));
+            }
+        }
+    }
+    Ok(request)
+}
+
+/// Read a whole body frame by frame, rejecting it as soon as it passes `limit` or fails the
+/// checks of `input`
+pub(crate) async fn read_body(headers: &HeaderMap, body: Body, limit: usize, input: Input) -> Result<Vec<u8>, ApiError> {
+    let declared = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return Err(too_large(limit));
+    }
+
+    let mut bytes = Vec::with_capacity(declared.unwrap_or(0).min(input.limit));
+    let mut body = body;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| ApiError(Error::InvalidOption(format!("Failed to read request body: {}", e))))?;
+        if let Some(data) = frame.data_ref() {
+            if bytes.len() + data.len() > limit {
+                return Err(too_large(limit));
+            }
+            bytes.extend_from_slice(data);
+            input.check(&bytes, false)?;
+        }
+    }
+    input.check(&bytes, true)?;
+    Ok(bytes)
+}
+
+/// Read a multipart upload, a raw `application/dicom` body or bare JSON options, depending on
+/// the Content-Type, once an upload slot is free
+pub(crate) async fn read_request(state: &AppState, request: Request) -> Result<ProcessRequest, ApiError> {
+    let _upload = state.limits.upload().await;
+    let tenant = request.extensions().get::<Client>().and_then(|client| client.tenant.clone());
+    let client = request.extensions().get::<Client>().map(Client::identity);
+    let config = state.config();
+    let (limit, uploads) = (config.max_body_bytes, &config.uploads);
+    let content_type = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    if content_type == "multipart/form-data" {
+        let multipart = Multipart::from_request(request, state)
+            .await
+            .map_err(|e| ApiError(Error::InvalidOption(format!("Invalid multipart body: {}", e))))?;
+        return Ok(ProcessRequest { tenant, client, ..read_multipart(multipart, limit, uploads).await? });
+    }
+    if RAW_CONTENT_TYPES.contains(&content_type.as_str()) {
+        let Query(query) = Query::<RawQuery>::try_from_uri(request.uri())
+            .map_err(|e| ApiError(Error::InvalidOption(format!("Invalid query string: {}", e))))?;
+        let options = match query.options {
+            Some(options) => {
+                Input::options(uploads).check(options.as_bytes(), true)?;
+                parse_options(options.as_bytes())?
+            }
+            None => ProcessOptions::default(),
+        };
+        let (parts, body) = request.into_parts();
+        let dicom = read_body(&parts.headers, body, limit, Input::dicom(uploads)).await?;
+        return Ok(ProcessRequest { dicom: Some(dicom), heatmap: None, options, tenant, client });
+    }
+    if content_type == "application/json" {
+        let (parts, body) = request.into_parts();
+        let options = parse_options(&read_body(&parts.headers, body, limit, Input::options(uploads)).await?)?;
+        return Ok(ProcessRequest { dicom: None, heatmap: None, options, tenant, client });
+    }
+    Err(ApiError(Error::InvalidOption(format!(
+        "Unsupported Content-Type: {}. Available: {}",
+        if content_type.is_empty() { "none" } else { &content_type }, CONTENT_TYPES.join(", ")))))
+}
+
+/// Run the pipeline on the uploaded parts and encode the fused image, failing once `deadline` passes
+///
+/// A repeat request for the same instance, models and options is answered from the render cache,
+/// without retrieving the instance or calling the services again.
+pub(crate) async fn render(state: &AppState, request: ProcessRequest, deadline: Instant) -> Result<Vec<u8>, ApiError> {
+    let dicom = request.dicom.filter(|dicom| !dicom.is_empty());
+    let mut options = request.options;
+    let config = state.config();
+    let tenant = request.tenant.as_deref().map(|name| config.tenant(name)).transpose()?;
+    if let Some(tenant) = tenant {
+        // Checked before the lookup, whose error lists every configured service
+        #[cfg(feature = "service")]
+        if let Some(denied) = options.services.iter().find(|service| !tenant.allows_service(service)) {
+            return Err(ApiError(Error::Forbidden(format!("Service {} is not available to tenant {}", denied, tenant.name))));
+        }
+        options.colormap = options.colormap.or_else(|| tenant.colormap.clone());
+    }
+    // Uploads that didn't pass through read_request, such as gRPC messages and worker inputs
+    let uploads = &config.uploads;
+    if let Some(dicom) = &dicom {
+        Input::dicom(uploads).check(dicom, true)?;
+    }
+    if let Some((heatmap, _)) = &request.heatmap {
+        Input::heatmap(uploads).check(heatmap, true)?;
+    }
+    #[cfg(feature = "service")]
+    let services = match options.services.is_empty() {
+        true => Vec::new(),
+        false if request.heatmap.is_some() => {
+            return Err(ApiError(Error::InvalidOption("Send either a 'heatmap' part or 'services', not both".to_string())));
+        }
+        false => config.services(&options.services)?,
+    };
+    #[cfg(feature = "dicomweb")]
+    if dicom.is_some() && options.wado.is_some() {
+        return Err(ApiError(Error::InvalidOption("Send either a DICOM or 'wado' UIDs, not both".to_string())));
+    }
+    // Waits for the model's warm-up if it is still running
+    #[cfg(feature = "onnx")]
+    let model = match &options.model {
+        None => None,
+        Some(_) if request.heatmap.is_some() => {
+            return Err(ApiError(Error::InvalidOption("Send either a 'heatmap' part or a 'model', not both".to_string())));
+        }
+        #[cfg(feature = "service")]
+        Some(_) if !services.is_empty() => {
+            return Err(ApiError(Error::InvalidOption("Send either 'services' or a 'model', not both".to_string())));
+        }
+        Some(name) => Some(tokio::time::timeout_at(deadline.into(), state.models.get(name))
+            .await
+            .map_err(|_| ApiError(Error::Timeout { stage: "model" }))??),
+    };
+
+    let sop_instance = match &dicom {
+        Some(dicom) => sop_instance_uid(dicom).ok(),
+        #[cfg(feature = "dicomweb")]
+        None => options.wado.as_ref().map(|instance| instance.instance.clone()),
+        #[cfg(not(feature = "dicomweb"))]
+        None => None,
+    };
+    let scope = instance_scope(dicom.as_deref(), request.tenant.as_deref(), request.client.as_deref());
+    #[allow(unused_mut)]
+    let (mut models, mut layers) = (Vec::new(), Vec::new());
+    #[cfg(feature = "service")]
+    for service in &services {
+        let mut model = match options.fusion {
+            Some(_) => format!("{}={}*{}", service.name, service.model_key(), service.weight),
+            None => format!("{}={}", service.name, service.model_key()),
+        };
+        // Occlusion maps the same model differently
+        if let Some(occlusion) = &service.occlusion {
+            model.push_str(&format!(" occlusion={}", occlusion));
+        }
+        models.push(model);
+        layers.push(service.colormap.clone());
+    }
+    if let Some((bytes, extension)) = &request.heatmap {
+        let format = options.heatmap_format.as_ref().or(extension.as_ref());
+        models.push(format!("upload={:016x}", stable_hash(&(bytes, format))));
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(model) = &model {
+        models.push(format!("model={}={}", model.info().name, model.info().sha256));
+    }
+    #[cfg(feature = "plugins")]
+    if let Some(plugin) = &state.plugin {
+        models.push(format!("plugin={}", plugin.sha256()));
+    }
+    #[cfg(feature = "gpu")]
+    if state.gpu.is_some() {
+        models.push("backend=gpu".to_string());
+    }
+    let cache_key = sop_instance
+        .filter(|_| state.renders.is_enabled())
+        .map(|sop_instance| CacheKey { sop_instance, models, spec: spec_hash(&options, &layers), scope: scope.clone() });
+    if let Some(key) = &cache_key
+        && let Some(png) = state.renders.get(key)
+    {
+        info!("Serving the cached rendering of instance {}", key.sop_instance);
+        return Ok(png);
+    }
+
+    #[cfg(feature = "dicomweb")]
+    let dicom = match (dicom, &options.wado) {
+        (None, Some(instance)) => Some(retrieve(state, instance, deadline).await?),
+        (dicom, _) => dicom,
+    };
+    let dicom = dicom
+        .ok_or_else(|| ApiError(Error::InvalidOption("Missing 'dicom' part or application/dicom body".to_string())))?;
+    #[cfg(feature = "onnx")]
+    let inferred = match model {
+        Some(model) => {
+            let dicom = dicom.clone();
+            Some(state.cpu.run(move || {
+                let obj = open_dicom_bytes(&dicom)?;
+                let (rows, columns) = image_dimensions(&obj)?;
+                let image = decode_dicom_pixel_data(&obj, rows, columns)?;
+                Ok(model.infer(&image::imageops::grayscale(&image), None)?.heatmap)
+            }).await?)
+        }
+        None => None,
+    };
+
+    let mut builder = HeatmapPipeline::builder()
+        .source(ImageSource::DicomBytes(dicom))
+        .colormap(options.colormap.unwrap_or(ColorMap::Red))
+        .normalization(options.normalization.unwrap_or(Normalization::MinMax))
+        .blend(options.blend)
+        .annotations(options.annotations)
+        .heatmap_registry(state.registry.clone())
+        .lenient(options.lenient)
+        .cpu_pool(state.cpu.clone())
+        .buffer_pool(state.buffers.clone())
+        .image_cache(state.images.clone())
+        .image_cache_scope(scope)
+        .deadline(deadline);
+    #[cfg(feature = "service")]
+    {
+        builder = builder.service_client(state.client.clone());
+    }
+    if let Some(points) = options.operating_points.clone() {
+        builder = builder.operating_points(points);
+    }
+    #[cfg(feature = "plugins")]
+    if let Some(plugin) = &state.plugin {
+        builder = builder.plugin(plugin.clone());
+    }
+    #[cfg(feature = "gpu")]
+    if let Some(gpu) = &state.gpu {
+        builder = builder.gpu(gpu.clone());
+    }
+    if let Some((bytes, extension)) = request.heatmap {
+        let format = options.heatmap_format.or(extension).unwrap_or_else(|| "json".to_string());
+        builder = builder.heatmap(HeatmapInput::Bytes { bytes, format });
+    }
+    #[cfg(feature = "onnx")]
+    if let Some(heatmap) = inferred {
+        builder = builder.heatmap(HeatmapInput::Loaded(Box::new(heatmap)));
+    }
+
+    let pipeline = builder.build()?;
+    #[cfg(feature = "service")]
+    let result = match services.is_empty() {
+        true => pipeline.run_async().await?,
+        false => {
+            let mode = options.fusion.map(FanoutMode::Fused).unwrap_or(FanoutMode::Combined);
+            pipeline.run_fanout(&services, mode).await?.remove(0).1
+        }
+    };
+    #[cfg(not(feature = "service"))]
+    let result = pipeline.run_async().await?;
+    // Renderings that only succeeded in part aren't reused, so a later request tries again
+    let complete = result.warnings.is_empty();
+    let png_options = state.config().png;
+    let png = state.cpu.run(move || encode_png_with(&result.image, &png_options)).await?;
+    if let Some(key) = cache_key
+        && complete
+    {
+        state.renders.insert(key, png.clone());
+    }
+    Ok(png)
+}
+
+/// Who may share cached work on an instance: an upload only with uploads of the same bytes,
+/// since any client can claim any SOP Instance UID, and a retrieved instance with its tenant
+/// and client
+fn instance_scope(dicom: Option<&[u8]>, tenant: Option<&str>, client: Option<&str>) -> String {
+    match dicom {
+        Some(dicom) => format!("sha256={}", sha256_bytes(dicom)),
+        None => format!("tenant={} client={}", tenant.unwrap_or("-"), client.unwrap_or("-")),
+    }
+}
+
+/// Hash of the options that shape a rendering, as the pipeline spec records them, plus the
+/// colormaps the services draw their layers with and how their heatmaps are fused
+fn spec_hash(options: &ProcessOptions, layers: &[Option<ColorMap>]) -> u64 {
+    let spec = PipelineSpec {
+        colormap: options.colormap.clone().unwrap_or(ColorMap::Red),
+        normalization: options.normalization.clone().unwrap_or(Normalization::MinMax),
+        blend: options.blend.clone(),
+        annotations: options.annotations.clone(),
+        operating_points: options.operating_points.clone(),
+        lenient: options.lenient,
+        ..PipelineSpec::default()
+    };
+    let layers: Vec<String> = layers.iter().map(|colormap| format!("{:?}", colormap)).collect();
+    #[cfg(feature = "service")]
+    let fusion = options.fusion.map(|fusion| fusion.name());
+    #[cfg(not(feature = "service"))]
+    let fusion: Option<&str> = None;
+    stable_hash(&(spec.to_json().unwrap_or_default(), layers, fusion))
+}
+
+/// Fetch the instance from the configured DICOMweb server within the request deadline
+#[cfg(feature = "dicomweb")]
+async fn retrieve(state: &AppState, instance: &InstanceRef, deadline: Instant) -> Result<Vec<u8>, ApiError> {
+    let client = state.dicomweb.as_ref()
+        .ok_or_else(|| ApiError(Error::InvalidOption("'wado' needs DICOMWEB_URL to be configured".to_string())))?;
+    tokio::time::timeout_at(deadline.into(), client.retrieve(instance))
+        .await
+        .map_err(|_| ApiError(Error::Timeout { stage: "retrieve" }))?
+        .map_err(ApiError)
+}
+
+pub(crate) async fn process(State(state): State<Arc<AppState>>, request: Request) -> Result<Response, ApiError> {
+    let deadline = Instant::now() + state.config().request_timeout;
+    let request = tokio::time::timeout_at(deadline.into(), read_request(&state, request))
+        .await
+        .map_err(|_| ApiError(Error::Timeout { stage: "upload" }))??;
+    let png = render(&state, request, deadline).await?;
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
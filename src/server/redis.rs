@@ -0,0 +1,173 @@
+//! Job records and results kept in Redis, so the replicas of a deployment share them and a
+//! restart only loses the jobs the restarted replica was running.
+
+use log::{info, warn};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::time::{Duration, Instant};
+
+use super::jobs::{self, Job};
+use crate::config::{JobConfig, RedisConfig};
+use crate::error::{Error, Result};
+
+/// How long the heartbeat of a replica outlives its last refresh
+const HEARTBEAT_TTL_SECS: u64 = 30;
+/// How often a replica refreshes its heartbeat
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+fn redis_error(message: impl std::fmt::Display) -> Error {
+    Error::Service { service: "redis".to_string(), message: message.to_string() }
+}
+
+/// Connection to the job store, with the key layout:
+///
+/// - `<prefix>job:<id>`: hash of the `record` JSON and the `replica` running the job
+/// - `<prefix>result:<id>`: PNG of a succeeded job
+/// - `<prefix>replica:<replica>`: heartbeat of a live replica
+pub(crate) struct RedisJobs {
+    connection: ConnectionManager,
+    config: RedisConfig,
+    /// Lifetime of finished records, in seconds
+    ttl: u64,
+    /// Lifetime of unfinished records, bounding the records of jobs nobody polled after their
+    /// replica died
+    pending_ttl: u64,
+    /// Random ID of this process, recorded with the jobs it runs
+    replica: String,
+}
+
+impl std::fmt::Debug for RedisJobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisJobs")
+            .field("config", &self.config)
+            .field("replica", &self.replica)
+            .finish()
+    }
+}
+
+impl RedisJobs {
+    /// Connect and start the heartbeat that tells other replicas this one's jobs are still running
+    pub async fn connect(jobs: &JobConfig, config: &RedisConfig) -> Result<Self> {
+        let client = redis::Client::open(config.url.as_str())
+            .map_err(|e| Error::InvalidOption(format!("Invalid value for REDIS_URL: {} ({})", config.redacted_url(), e)))?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(|e| redis_error(format!("failed to connect to {}: {}", config.redacted_url(), e)))?;
+        // Redis refuses an expiry of 0
+        let ttl = jobs.ttl.as_secs().max(1);
+        let store = RedisJobs {
+            connection,
+            config: config.clone(),
+            ttl,
+            pending_ttl: ttl + jobs.timeout.as_secs(),
+            replica: uuid::Uuid::new_v4().to_string(),
+        };
+        store.beat().await?;
+        let (mut connection, key) = (store.connection.clone(), store.replica_key(&store.replica));
+        crate::asynchronous::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                if let Err(e) = connection.set_ex::<_, _, ()>(&key, 1, HEARTBEAT_TTL_SECS).await {
+                    warn!("Failed to refresh the heartbeat {}: {}", key, e);
+                }
+            }
+        });
+        info!("Keeping jobs in Redis at {} as replica {}", config.redacted_url(), store.replica);
+        Ok(store)
+    }
+
+    fn job_key(&self, id: &str) -> String {
+        format!("{}job:{}", self.config.key_prefix, id)
+    }
+
+    fn result_key(&self, id: &str) -> String {
+        format!("{}result:{}", self.config.key_prefix, id)
+    }
+
+    fn replica_key(&self, replica: &str) -> String {
+        format!("{}replica:{}", self.config.key_prefix, replica)
+    }
+
+    async fn beat(&self) -> Result<()> {
+        let key = self.replica_key(&self.replica);
+        self.connection.clone()
+            .set_ex::<_, _, ()>(&key, 1, HEARTBEAT_TTL_SECS)
+            .await
+            .map_err(|e| redis_error(format!("failed to write the heartbeat {}: {}", key, e)))
+    }
+
+    /// Record `job` as run by this replica
+    pub async fn save(&self, job: &Job) -> Result<()> {
+        let key = self.job_key(&job.id);
+        let record = serde_json::to_string(job).map_err(|e| Error::Server(format!("Failed to serialize job {}: {}", job.id, e)))?;
+        let ttl = if job.status.is_finished() { self.ttl } else { self.pending_ttl };
+        redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &[("record", record.as_str()), ("replica", self.replica.as_str())])
+            .ignore()
+            .expire(&key, ttl as i64)
+            .ignore()
+            .query_async::<()>(&mut self.connection.clone())
+            .await
+            .map_err(|e| redis_error(format!("failed to save job {}: {}", job.id, e)))
+    }
+
+    /// Record of job `id`; an unfinished job whose replica stopped sending heartbeats is failed
+    /// as interrupted
+    pub async fn get(&self, id: &str) -> Result<Option<Job>> {
+        let mut connection = self.connection.clone();
+        let (record, replica): (Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(self.job_key(id))
+            .arg("record")
+            .arg("replica")
+            .query_async(&mut connection)
+            .await
+            .map_err(|e| redis_error(format!("failed to read job {}: {}", id, e)))?;
+        let Some(record) = record else {
+            return Ok(None);
+        };
+        let mut job: Job = serde_json::from_str(&record)
+            .map_err(|e| Error::Server(format!("Unreadable record of job {}: {}", id, e)))?;
+        if !job.status.is_finished() {
+            let alive = match &replica {
+                Some(replica) => connection
+                    .exists::<_, bool>(self.replica_key(replica))
+                    .await
+                    .map_err(|e| redis_error(format!("failed to read the heartbeat of {}: {}", replica, e)))?,
+                None => false,
+            };
+            if !alive {
+                warn!("Job {} was left {} by replica {}", id, job.status.name(), replica.as_deref().unwrap_or("-"));
+                jobs::interrupt(&mut job);
+                self.save(&job).await?;
+            }
+        }
+        Ok(Some(job))
+    }
+
+    pub async fn save_result(&self, id: &str, png: &[u8]) -> Result<()> {
+        let ttl = self.config.result_ttl.map(|ttl| ttl.as_secs().max(1)).unwrap_or(self.ttl);
+        self.connection.clone()
+            .set_ex::<_, _, ()>(self.result_key(id), png, ttl)
+            .await
+            .map_err(|e| redis_error(format!("failed to save the result of job {}: {}", id, e)))
+    }
+
+    /// PNG of job `id`; None once it expired
+    pub async fn result(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        self.connection.clone()
+            .get(self.result_key(id))
+            .await
+            .map_err(|e| redis_error(format!("failed to read the result of job {}: {}", id, e)))
+    }
+
+    /// Round trip to the server, for readiness probes
+    pub async fn ping(&self) -> Result<Duration> {
+        let started = Instant::now();
+        redis::cmd("PING")
+            .query_async::<()>(&mut self.connection.clone())
+            .await
+            .map_err(|e| redis_error(format!("ping failed: {}", e)))?;
+        Ok(started.elapsed())
+    }
+}
@@ -0,0 +1,60 @@
+//! Inputs and outputs named by reference: local paths, `file://` URLs and, with the storage
+//! features, object storage URLs.
+
+use std::path::PathBuf;
+
+use super::AppState;
+use crate::error::{Error, Result};
+
+/// Reference without the query of a blob URL, which may hold a SAS token
+pub(crate) fn without_credentials(reference: &str) -> &str {
+    #[cfg(feature = "storage")]
+    return crate::storage::redact(reference);
+    #[cfg(not(feature = "storage"))]
+    reference
+}
+
+/// Local path of a reference: a plain path or a `file://` URL
+fn local_path(reference: &str) -> Result<PathBuf> {
+    match reference.split_once("://") {
+        None => Ok(PathBuf::from(reference)),
+        Some(("file", path)) => Ok(PathBuf::from(path)),
+        Some((scheme, _)) => {
+            #[cfg_attr(not(feature = "storage"), allow(unused_mut, clippy::useless_vec))]
+            let mut schemes = vec!["file"];
+            #[cfg(feature = "storage")]
+            schemes.extend(crate::storage::SCHEMES);
+            Err(Error::InvalidOption(format!("Unsupported reference scheme: {}. Available: {}", scheme, schemes.join(", "))))
+        }
+    }
+}
+
+/// Bytes behind a reference, refusing inputs larger than `limit`
+#[cfg_attr(not(any(feature = "kafka", feature = "amqp")), allow(dead_code))]
+#[cfg_attr(not(feature = "storage"), allow(unused_variables))]
+pub(crate) async fn read_reference(state: &AppState, reference: &str, limit: usize) -> Result<Vec<u8>> {
+    #[cfg(feature = "storage")]
+    if crate::storage::is_object(reference) {
+        return state.storage.read(reference, limit).await;
+    }
+    let path = local_path(reference)?;
+    let metadata = tokio::fs::metadata(&path).await.map_err(|e| Error::io(&path, e))?;
+    if metadata.len() > limit as u64 {
+        return Err(Error::TooLarge { what: format!("Input {}", reference), limit });
+    }
+    tokio::fs::read(&path).await.map_err(|e| Error::io(&path, e))
+}
+
+/// Store `bytes` at a reference, creating missing parent directories of local paths
+#[cfg_attr(not(feature = "storage"), allow(unused_variables))]
+pub(crate) async fn write_reference(state: &AppState, reference: &str, bytes: Vec<u8>) -> Result<()> {
+    #[cfg(feature = "storage")]
+    if crate::storage::is_object(reference) {
+        return state.storage.write(reference, bytes).await;
+    }
+    let path = local_path(reference)?;
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| Error::io(parent, e))?;
+    }
+    tokio::fs::write(&path, &bytes).await.map_err(|e| Error::io(&path, e))
+}
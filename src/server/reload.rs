@@ -0,0 +1,105 @@
+//! Configuration reloads: the service registry and the config file are polled for changes, and
+//! new service definitions, limits and tenant policies are swapped in without a restart.
+
+use log::{info, warn};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::AppState;
+use crate::config::{config_file_path, OrchestrateConfig};
+use crate::error::{Error, Result};
+
+/// Watched files with their modification time and size; None while a file is missing
+type Fingerprint = Vec<(PathBuf, Option<(SystemTime, u64)>)>;
+
+fn fingerprint(config: &OrchestrateConfig) -> Fingerprint {
+    let mut paths = vec![config.service_db_path.clone()];
+    paths.extend(config_file_path());
+    paths.into_iter()
+        .map(|path| {
+            let stamp = std::fs::metadata(&path).ok().and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+            (path, stamp)
+        })
+        .collect()
+}
+
+async fn load() -> Result<OrchestrateConfig> {
+    tokio::task::spawn_blocking(OrchestrateConfig::from_env)
+        .await
+        .map_err(|e| Error::Server(format!("Configuration reload failed: {}", e)))?
+}
+
+/// Check the watched files every `reload_interval` and apply the services, limits and tenants of a
+/// changed configuration; a configuration that fails to load leaves the current one in place
+pub(crate) fn watch(state: &Arc<AppState>) {
+    let loaded = state.config();
+    let Some(interval) = loaded.reload_interval else {
+        return;
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut loaded = (*loaded).clone();
+        let mut seen = fingerprint(&loaded);
+        loop {
+            tokio::time::sleep(interval).await;
+            let current = fingerprint(&loaded);
+            if current == seen {
+                continue;
+            }
+            seen = current;
+            match load().await {
+                Ok(fresh) => {
+                    apply(&state, &loaded, &fresh);
+                    seen = fingerprint(&fresh);
+                    loaded = fresh;
+                }
+                Err(e) => warn!("Keeping the current configuration, the changed one is invalid: {}", e),
+            }
+        }
+    });
+}
+
+/// Swap in the services, limits and tenants of `fresh`, warning about changes that need a restart
+fn apply(state: &Arc<AppState>, previous: &OrchestrateConfig, fresh: &OrchestrateConfig) {
+    let mut restart_only = fresh.clone();
+    restart_only.config_services = previous.config_services.clone();
+    restart_only.limits = previous.limits.clone();
+    restart_only.tenants = previous.tenants.clone();
+    // The command line may override the address
+    restart_only.host = previous.host.clone();
+    restart_only.port = previous.port;
+    if restart_only != *previous {
+        warn!("Configuration changes other than services, limits and tenants take effect after a restart");
+    }
+
+    let current = state.config();
+    let services = &current.config_services;
+    let added: Vec<&str> = fresh.config_services.keys().filter(|name| !services.contains_key(*name)).map(String::as_str).collect();
+    let removed: Vec<&str> = services.keys().filter(|name| !fresh.config_services.contains_key(*name)).map(String::as_str).collect();
+    let changed: Vec<&str> = fresh.config_services.iter()
+        .filter(|(name, service)| services.get(*name).is_some_and(|old| old != *service))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let limits_changed = current.limits != fresh.limits;
+    let tenants_changed = current.tenants != fresh.tenants;
+    if added.is_empty() && removed.is_empty() && changed.is_empty() && !limits_changed && !tenants_changed {
+        return;
+    }
+
+    let mut next = (*current).clone();
+    next.config_services = fresh.config_services.clone();
+    next.limits = fresh.limits.clone();
+    next.tenants = fresh.tenants.clone();
+    let (from, to) = (current.limits.max_concurrent, next.limits.max_concurrent);
+    *state.config.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(next);
+    if from != to {
+        // Shrinking waits for busy slots to be given back
+        let state = state.clone();
+        tokio::spawn(async move { state.limits.queue.resize(from, to).await });
+    }
+    let list = |names: &[&str]| if names.is_empty() { "none".to_string() } else { names.join(", ") };
+    let state_of = |changed: bool| if changed { "changed" } else { "unchanged" };
+    info!("Reloaded configuration: services added {}, removed {}, changed {}; limits {}; tenants {}",
+          list(&added), list(&removed), list(&changed), state_of(limits_changed), state_of(tenants_changed));
+}
@@ -0,0 +1,138 @@
+//! Graceful shutdown: on SIGTERM or Ctrl-C intake stops, work in flight gets until the drain
+//! timeout to finish, and jobs still unfinished after it are recorded as interrupted.
+
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use super::AppState;
+
+/// Drain state shared by the server or worker and the work it runs
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    /// When draining ends; None until a shutdown was requested
+    deadline: watch::Sender<Option<Instant>>,
+    /// Background jobs and worker requests currently running
+    in_flight: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown { deadline: watch::Sender::new(None), in_flight: watch::Sender::new(0) }
+    }
+}
+
+/// Marks one piece of work as in flight until dropped
+#[derive(Debug)]
+pub(crate) struct Work(Arc<Shutdown>);
+
+impl Drop for Work {
+    fn drop(&mut self) {
+        self.0.in_flight.send_modify(|count| *count -= 1);
+    }
+}
+
+impl Shutdown {
+    /// Start draining, giving work in flight `timeout` to finish; later calls keep the first deadline
+    pub fn begin(&self, timeout: Duration) {
+        self.deadline.send_if_modified(|deadline| {
+            if deadline.is_some() {
+                return false;
+            }
+            *deadline = Some(Instant::now() + timeout);
+            true
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.deadline.borrow().is_some()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.borrow()
+    }
+
+    /// Count work as in flight for as long as the guard lives
+    pub fn track(self: &Arc<Self>) -> Work {
+        self.in_flight.send_modify(|count| *count += 1);
+        Work(self.clone())
+    }
+
+    /// Wait for a shutdown to be requested, returning the end of the drain
+    pub async fn requested(&self) -> Instant {
+        let mut deadline = self.deadline.subscribe();
+        let Ok(deadline) = deadline.wait_for(Option::is_some).await else {
+            return std::future::pending().await;
+        };
+        deadline.unwrap_or_else(Instant::now)
+    }
+
+    /// Wait until the drain timeout of a requested shutdown has passed
+    pub async fn expired(&self) {
+        let deadline = self.requested().await;
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+
+    /// Wait for all tracked work to finish, up to the drain deadline; true when it did
+    pub async fn drained(&self) -> bool {
+        let mut in_flight = self.in_flight.subscribe();
+        tokio::select! {
+            _ = in_flight.wait_for(|count| *count == 0) => true,
+            _ = self.expired() => self.in_flight() == 0,
+        }
+    }
+}
+
+/// Resolve on SIGTERM, the signal Kubernetes and most supervisors stop a process with, or Ctrl-C
+async fn signal() -> &'static str {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => "Ctrl-C",
+        _ = terminate => "SIGTERM",
+    }
+}
+
+/// Start draining `state` when the process is asked to stop
+pub(crate) fn listen(state: &Arc<AppState>) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let name = signal().await;
+        let timeout = state.config().drain_timeout;
+        info!("Received {}, draining: no new work is taken, {} job(s) in flight, waiting up to {}s",
+              name, state.shutdown.in_flight(), timeout.as_secs());
+        state.shutdown.begin(timeout);
+    });
+}
+
+/// Wait for the background jobs to finish, then record the ones the drain timeout cut short as
+/// interrupted so their clients don't poll forever
+pub(crate) async fn finish(state: &AppState) {
+    if !state.shutdown.drained().await {
+        warn!("Drain timeout passed with {} job(s) still running", state.shutdown.in_flight());
+    }
+    let interrupted = state.jobs.interrupt_pending().await;
+    if interrupted > 0 {
+        warn!("Recorded {} unfinished job(s) as interrupted", interrupted);
+    }
+    info!("Shutdown complete");
+}
@@ -0,0 +1,195 @@
+//! `POST /studies` and `POST /studies/{study}`: DICOMweb STOW-RS ingestion, queueing a
+//! processing job for every stored instance.
+
+use axum::body::Body;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::auth::Client;
+use super::jobs;
+use super::process::ProcessRequest;
+use super::{error_response, ApiError, AppState};
+use crate::dicom_io::{instance_uids, open_dicom_header_bytes, InstanceUids};
+use crate::error::Error;
+
+/// Media type of STOW-RS responses
+const DICOM_JSON: &str = "application/dicom+json";
+
+/// Failure Reason (0008,1197) when the job queue is full
+const OUT_OF_RESOURCES: u16 = 0xA700;
+/// Failure Reason when the instance can't be parsed or belongs to another study
+const CANNOT_UNDERSTAND: u16 = 0xC000;
+/// Failure Reason when the job can't be recorded
+const PROCESSING_FAILURE: u16 = 0x0110;
+
+/// Outcome of storing one part of the request
+enum Stored {
+    Accepted { uids: InstanceUids, job: String },
+    Failed { uids: Option<InstanceUids>, reason: u16 },
+}
+
+pub(crate) async fn store(state: State<Arc<AppState>>, request: Request) -> Response {
+    store_instances(state, None, request).await
+}
+
+pub(crate) async fn store_in_study(state: State<Arc<AppState>>, Path(study): Path<String>, request: Request) -> Response {
+    store_instances(state, Some(study), request).await
+}
+
+/// Read every `application/dicom` part of a `multipart/related` body and queue a job for each
+/// instance, answering with the STOW-RS response dataset: 200 when all were accepted, 202 when
+/// some failed and 409 when none were accepted
+async fn store_instances(State(state): State<Arc<AppState>>, study: Option<String>, request: Request) -> Response {
+    let deadline = Instant::now() + state.config().request_timeout;
+    let client = request.extensions().get::<Client>().map(Client::identity);
+    let tenant = request.extensions().get::<Client>().and_then(|client| client.tenant.clone());
+    let content_type = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let boundary = match boundary(&content_type) {
+        Ok(Some(boundary)) => boundary,
+        Ok(None) => return ApiError(Error::InvalidOption("multipart/related body without a boundary".to_string())).into_response(),
+        Err(message) => return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", &message),
+    };
+
+    let parts = tokio::time::timeout_at(deadline.into(), async {
+        let _upload = state.limits.upload().await;
+        read_parts(request.into_body(), boundary, state.config().max_body_bytes, state.config().uploads.dicom).await
+    }).await;
+    let parts = match parts {
+        Ok(Ok(parts)) if parts.is_empty() => {
+            return ApiError(Error::InvalidOption("No application/dicom parts in the request".to_string())).into_response();
+        }
+        Ok(Ok(parts)) => parts,
+        Ok(Err(e)) => return e.into_response(),
+        Err(_) => return ApiError(Error::Timeout { stage: "upload" }).into_response(),
+    };
+
+    state.jobs.purge_expired();
+    let options = state.stow_options.clone();
+    let mut results = Vec::with_capacity(parts.len());
+    for dicom in parts {
+        let uids = match open_dicom_header_bytes(&dicom).and_then(|obj| instance_uids(&obj)) {
+            Ok(uids) => uids,
+            Err(e) => {
+                warn!("Rejected stored instance: {}", e);
+                results.push(Stored::Failed { uids: None, reason: CANNOT_UNDERSTAND });
+                continue;
+            }
+        };
+        if study.as_ref().is_some_and(|study| *study != uids.study) {
+            warn!("Rejected instance {}: it belongs to study {}", uids.sop_instance, uids.study);
+            results.push(Stored::Failed { uids: Some(uids), reason: CANNOT_UNDERSTAND });
+            continue;
+        }
+        if state.jobs.pending() >= state.config().jobs.max_pending {
+            results.push(Stored::Failed { uids: Some(uids), reason: OUT_OF_RESOURCES });
+            continue;
+        }
+        let upload = ProcessRequest { dicom: Some(dicom), heatmap: None, options: options.clone(), tenant: tenant.clone(), client: client.clone() };
+        results.push(match jobs::enqueue(&state, upload, client.clone()).await {
+            Ok(job) => Stored::Accepted { uids, job: job.id },
+            Err(e) => {
+                warn!("Failed to queue instance {}: {}", uids.sop_instance, e);
+                Stored::Failed { uids: Some(uids), reason: PROCESSING_FAILURE }
+            }
+        });
+    }
+
+    let accepted = results.iter().filter(|stored| matches!(stored, Stored::Accepted { .. })).count();
+    info!("Stored {} of {} instance(s)", accepted, results.len());
+    let status = match accepted {
+        0 => StatusCode::CONFLICT,
+        accepted if accepted < results.len() => StatusCode::ACCEPTED,
+        _ => StatusCode::OK,
+    };
+    (status, [(header::CONTENT_TYPE, DICOM_JSON)], response_dataset(&results).to_string()).into_response()
+}
+
+/// Boundary of a `multipart/related` body of DICOM instances, the only STOW-RS payload supported
+fn boundary(content_type: &str) -> std::result::Result<Option<String>, String> {
+    let mut params = content_type.split(';').map(str::trim);
+    let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+    if media_type != "multipart/related" {
+        return Err(format!("Unsupported Content-Type: {}. Available: multipart/related; type=\"application/dicom\"", media_type));
+    }
+    let params: Vec<(String, &str)> = params
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().trim_matches('"')))
+        .collect();
+    let param = |wanted: &str| params.iter().find(|(name, _)| name == wanted).map(|(_, value)| *value);
+    match param("type").map(str::to_ascii_lowercase).as_deref() {
+        None | Some("application/dicom") => Ok(param("boundary").map(String::from)),
+        Some(other) => Err(format!("Unsupported multipart/related type: {}. Available: application/dicom", other)),
+    }
+}
+
+/// Bytes of every part, skipping parts that declare a type other than `application/dicom`;
+/// `limit` caps the body and `part_limit` each instance
+async fn read_parts(body: Body, boundary: String, limit: usize, part_limit: usize) -> std::result::Result<Vec<Vec<u8>>, ApiError> {
+    let size_limit = multer::SizeLimit::new().whole_stream(limit as u64).per_field(part_limit as u64);
+    let constraints = multer::Constraints::new().size_limit(size_limit);
+    let mut multipart = multer::Multipart::with_constraints(body.into_data_stream(), boundary, constraints);
+    let mut parts = Vec::new();
+    let error = |e: multer::Error| match e {
+        multer::Error::StreamSizeExceeded { .. } => ApiError(Error::TooLarge { what: "Request body".to_string(), limit }),
+        multer::Error::FieldSizeExceeded { .. } => ApiError(Error::TooLarge { what: "DICOM".to_string(), limit: part_limit }),
+        e => ApiError(Error::InvalidOption(format!("Invalid multipart/related body: {}", e))),
+    };
+    while let Some(mut part) = multipart.next_field().await.map_err(error)? {
+        let is_dicom = part.content_type().is_none_or(|media_type| media_type.essence_str() == "application/dicom");
+        let mut bytes = Vec::new();
+        while let Some(chunk) = part.chunk().await.map_err(error)? {
+            bytes.extend_from_slice(&chunk);
+        }
+        if is_dicom {
+            parts.push(bytes);
+        } else {
+            warn!("Skipping multipart/related part of type {:?}", part.content_type().map(|media_type| media_type.to_string()));
+        }
+    }
+    Ok(parts)
+}
+
+/// STOW-RS response: Referenced SOP Sequence (0008,1199) with the job URL as Retrieve URL, and
+/// Failed SOP Sequence (0008,1198) with the failure reasons
+fn response_dataset(results: &[Stored]) -> Value {
+    let mut referenced = Vec::new();
+    let mut failed = Vec::new();
+    for stored in results {
+        match stored {
+            Stored::Accepted { uids, job } => {
+                let mut item = sop_reference(uids);
+                item.insert("00081190".to_string(), json!({ "vr": "UR", "Value": [format!("/jobs/{}", job)] }));
+                referenced.push(Value::Object(item));
+            }
+            Stored::Failed { uids, reason } => {
+                let mut item = uids.as_ref().map(sop_reference).unwrap_or_default();
+                item.insert("00081197".to_string(), json!({ "vr": "US", "Value": [reason] }));
+                failed.push(Value::Object(item));
+            }
+        }
+    }
+    let mut dataset = Map::new();
+    if !failed.is_empty() {
+        dataset.insert("00081198".to_string(), json!({ "vr": "SQ", "Value": failed }));
+    }
+    if !referenced.is_empty() {
+        dataset.insert("00081199".to_string(), json!({ "vr": "SQ", "Value": referenced }));
+    }
+    Value::Object(dataset)
+}
+
+fn sop_reference(uids: &InstanceUids) -> Map<String, Value> {
+    let mut item = Map::new();
+    item.insert("00081150".to_string(), json!({ "vr": "UI", "Value": [uids.sop_class] }));
+    item.insert("00081155".to_string(), json!({ "vr": "UI", "Value": [uids.sop_instance] }));
+    item
+}
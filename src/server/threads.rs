@@ -0,0 +1,68 @@
+//! Thread placement of the server and workers: the cores the process is pinned to and the size
+//! of the global rayon pool. Both are set up on the main thread before any runtime or pool
+//! thread starts, so every thread spawned afterwards inherits the pinning and the per-core
+//! defaults count only the pinned cores.
+
+use log::info;
+
+use crate::config::{CoreList, PoolConfig};
+use crate::error::{Error, Result};
+
+/// Pin the process to `pools.cpu_affinity` and size the rayon pool to `pools.render_threads`,
+/// for those that are set; call once, before the async runtime is built
+pub fn configure(pools: &PoolConfig) -> Result<()> {
+    if let Some(cores) = &pools.cpu_affinity {
+        pin_current_thread(cores)?;
+        info!("Pinned to core(s) {}", cores);
+    }
+    if let Some(threads) = pools.render_threads {
+        render_pool(threads)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parallel")]
+fn render_pool(threads: usize) -> Result<()> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("render-{}", index))
+        .build_global()
+        .map_err(|e| Error::Server(format!("Failed to start {} render thread(s): {}", threads, e)))?;
+    info!("Rendering on {} thread(s)", threads);
+    Ok(())
+}
+
+#[cfg(not(feature = "parallel"))]
+fn render_pool(_threads: usize) -> Result<()> {
+    log::warn!("ORCHESTRATE_RENDER_THREADS is ignored: built without the parallel feature, renderings run on one thread");
+    Ok(())
+}
+
+/// Restrict the calling thread, and the threads it spawns from now on, to `cores`
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cores: &CoreList) -> Result<()> {
+    let set_size = libc::CPU_SETSIZE as usize;
+    if let Some(core) = cores.0.iter().find(|&&core| core >= set_size) {
+        return Err(Error::InvalidOption(format!("Core {} is out of range: cores are numbered below {}", core, set_size)));
+    }
+    // SAFETY: cpu_set_t is a plain bit mask, valid when zeroed, and every core was checked to
+    // lie within it
+    let status = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in &cores.0 {
+            libc::CPU_SET(core, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if status != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(Error::Server(format!("Failed to pin to core(s) {}: {}", cores, e)));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(cores: &CoreList) -> Result<()> {
+    log::warn!("ORCHESTRATE_CPU_AFFINITY is ignored: pinning to core(s) {} is only supported on Linux", cores);
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! TLS termination for `serve`: handshakes run next to the accept loop and axum is handed the
+//! decrypted streams.
+
+use axum::serve::Listener;
+use log::{debug, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+use crate::error::{Error, Result};
+
+/// Longest a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Established connections waiting for the server to take them
+const BACKLOG: usize = 128;
+
+/// rustls settings for the certificate in `config`, offering HTTP/2 when the build serves gRPC
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| Error::InvalidOption(format!("Invalid value for ORCHESTRATE_TLS_CERT: {} ({})", config.cert.display(), e)))?;
+    if certs.is_empty() {
+        return Err(Error::InvalidOption(format!("Invalid value for ORCHESTRATE_TLS_CERT: {} (no certificate found)", config.cert.display())));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| Error::InvalidOption(format!("Invalid value for ORCHESTRATE_TLS_KEY: {} ({})", config.key.display(), e)))?;
+
+    let mut server = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Server(format!("Failed to set up TLS: {}", e)))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::InvalidOption(format!("Invalid TLS certificate {}: {}", config.cert.display(), e)))?;
+    server.alpn_protocols = vec![
+        #[cfg(feature = "grpc")]
+        b"h2".to_vec(),
+        b"http/1.1".to_vec(),
+    ];
+    Ok(server)
+}
+
+/// Listener yielding TLS streams; every handshake runs in its own task, so a slow or silent
+/// client holds up only its own connection
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: ServerConfig) -> Result<Self> {
+        let local_addr = listener.local_addr().map_err(|e| Error::Server(format!("Failed to read the bound address: {}", e)))?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (sender, connections) = mpsc::channel(BACKLOG);
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    // Usually out of file descriptors; give connections time to close
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let (acceptor, sender) = (acceptor.clone(), sender.clone());
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener { connections, local_addr })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept loop only ends once this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
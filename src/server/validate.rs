@@ -0,0 +1,40 @@
+//! Early checks of uploads: a body of an unsupported type or declared larger than the limit is
+//! rejected before any of it is read.
+
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+use super::process::CONTENT_TYPES;
+use super::{error_response, ApiError, AppState};
+use crate::error::Error;
+
+/// Answer 415 for a Content-Type the route doesn't take and 413 for a declared length above the
+/// body limit, leaving the per-input limits and the DICOM check to the handlers while reading
+pub(crate) async fn check_upload(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let media_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let allowed: &[&str] = match request.uri().path().starts_with("/studies") {
+        true => &["multipart/related"],
+        false => &CONTENT_TYPES,
+    };
+    if !allowed.contains(&media_type.as_str()) {
+        let message = format!("Unsupported Content-Type: {}. Available: {}",
+                              if media_type.is_empty() { "none" } else { &media_type }, allowed.join(", "));
+        return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", &message);
+    }
+
+    let limit = state.config().max_body_bytes;
+    let declared = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit as u64) {
+        return ApiError(Error::TooLarge { what: "Request body".to_string(), limit }).into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,108 @@
+//! Processing of requests taken from a message queue: the request and result event formats,
+//! with inputs read and outputs written by reference, local or in object storage.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use super::jobs::{JobError, JobStatus};
+use super::process::{render, ProcessOptions, ProcessRequest};
+use super::reference::{read_reference, without_credentials, write_reference};
+use super::{ApiError, AppState};
+use crate::error::Error;
+
+/// Processing request carried by a queue message; inputs and output are references the worker
+/// can read and write, so images never travel through the queue
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkRequest {
+    /// Echoed in the result event; defaults to the message key or position
+    #[serde(default)]
+    pub id: Option<String>,
+    /// DICOM input, unless `options.wado` names an instance to retrieve
+    #[serde(default)]
+    pub dicom: Option<String>,
+    /// Heatmap input, format chosen by extension unless `options.heatmap_format` is set
+    #[serde(default)]
+    pub heatmap: Option<String>,
+    /// Where the fused PNG is written; must lie under the tenant's output prefix
+    pub output: String,
+    /// Tenant the request is made for, whose policy applies to it
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub options: ProcessOptions,
+}
+
+/// Event published for every request taken from the queue
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkResult {
+    pub id: String,
+    pub status: JobStatus,
+    /// Reference the PNG was written to, without the query of a blob URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>,
+    pub elapsed_ms: u64,
+}
+
+impl WorkResult {
+    /// Whether the request failed with a server-side error (5xx status) that may pass on a retry
+    pub fn retryable(&self) -> bool {
+        self.error.as_ref().is_some_and(|error| error.status >= 500)
+    }
+}
+
+/// Process one message payload, turning every failure, including an unreadable request, into
+/// a failed result; `fallback_id` identifies requests without an `id`
+pub(crate) async fn process(state: &AppState, payload: &[u8], fallback_id: String) -> WorkResult {
+    let started = Instant::now();
+    let request: std::result::Result<WorkRequest, ApiError> = serde_json::from_slice(payload)
+        .map_err(|e| ApiError(Error::InvalidOption(format!("Invalid work request: {}", e))));
+    let id = request.as_ref().ok().and_then(|request| request.id.clone()).unwrap_or(fallback_id);
+    let outcome = match request {
+        Ok(request) => run(state, request, started).await,
+        Err(e) => Err(e),
+    };
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match outcome {
+        Ok(output) => {
+            let output = without_credentials(&output).to_string();
+            info!("Work request {} succeeded in {}ms: {}", id, elapsed_ms, output);
+            WorkResult { id, status: JobStatus::Succeeded, output: Some(output), error: None, elapsed_ms }
+        }
+        Err(e) => {
+            warn!("Work request {} failed: {}", id, e.0);
+            let error = JobError { error: e.0.kind().to_string(), message: e.0.to_string(), status: e.status().as_u16() };
+            WorkResult { id, status: JobStatus::Failed, output: None, error: Some(error), elapsed_ms }
+        }
+    }
+}
+
+/// Read the inputs, render within the job timeout and write the PNG, returning its reference
+async fn run(state: &AppState, request: WorkRequest, started: Instant) -> std::result::Result<String, ApiError> {
+    if let Some(name) = &request.tenant {
+        let config = state.config();
+        let tenant = config.tenant(name)?;
+        if !tenant.allows_output(&request.output) {
+            return Err(ApiError(Error::Forbidden(format!("Output {} is outside the results of tenant {}",
+                                                         without_credentials(&request.output), name))));
+        }
+    }
+    let dicom = match &request.dicom {
+        Some(reference) => Some(read_reference(state, reference, state.config().uploads.dicom).await?),
+        None => None,
+    };
+    let heatmap = match &request.heatmap {
+        Some(reference) => {
+            let extension = without_credentials(reference).rsplit_once('.').map(|(_, extension)| extension.to_string());
+            Some((read_reference(state, reference, state.config().uploads.heatmap).await?, extension))
+        }
+        None => None,
+    };
+    let upload = ProcessRequest { dicom, heatmap, options: request.options, tenant: request.tenant, client: None };
+    let png = render(state, upload, started + state.config().jobs.timeout).await?;
+    write_reference(state, &request.output, png).await?;
+    Ok(request.output)
+}
@@ -0,0 +1,810 @@
+//! HTTP (and with `grpc-client` gRPC) client for the upstream DL services that turn an image
+//! into a heatmap.
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use image::{imageops, DynamicImage};
+use log::{info, warn};
+#[cfg(feature = "telemetry")]
+use opentelemetry::trace::{SpanKind, TraceContextExt};
+#[cfg(feature = "telemetry")]
+use opentelemetry::{Context, KeyValue};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::asynchronous::CpuPool;
+use crate::cache::{stable_hash, CacheKey, CacheStats, ResultCache};
+use crate::circuit::{CircuitBreakers, CircuitState};
+use crate::config::{CacheConfig, ClientTls, PayloadFormat, RetryPolicy, ServiceConfig, ServiceKind};
+use crate::dicom_io::{decode_dicom_pixel_data, image_dimensions, open_dicom_bytes, sop_instance_uid, with_grayscale_pixels};
+use crate::error::{Error, Result};
+use crate::heatmap::{HeatmapRegistry, LoadedHeatmap};
+#[cfg(feature = "grpc-client")]
+use crate::model_server::{tfserving_grpc, triton_grpc, Tensor};
+use crate::model_server::{
+    health_path, image_tensor, multipart_form, tensor_heatmap, tfserving_path, tfserving_request, tfserving_response, torchserve_heatmap,
+    torchserve_path, triton_path, triton_request, triton_response, ImageTensor, INFERENCE_HEADER_LENGTH, TRITON_BINARY_CONTENT_TYPE,
+};
+use crate::occlusion::{OcclusionOptions, OcclusionPlan};
+use crate::output::encode_png;
+use crate::preprocess::{Placement, PreprocessSpec};
+use crate::progress::Stage;
+use crate::provenance::sha256_bytes;
+#[cfg(feature = "telemetry")]
+use crate::telemetry;
+
+/// Upper bound for a readiness probe, below the service's own timeout
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Occluded copies of an image sent to a service at once, within the client's call limit
+const OCCLUSION_CALLS: usize = 8;
+
+/// Client settings reqwest fixes per connection pool: (connect, read) timeout and TLS files
+type PoolKey = (Duration, Option<Duration>, ClientTls);
+
+/// Shared HTTP client for DL service calls; cheap to clone, and clones share connections and
+/// circuit breakers
+#[derive(Debug, Clone, Default)]
+pub struct ServiceClient {
+    http: Arc<Mutex<HashMap<PoolKey, reqwest::Client>>>,
+    circuits: Arc<CircuitBreakers>,
+    /// Heatmaps returned for an instance, reused while neither the model nor the payload changes
+    responses: Arc<ResultCache<LoadedHeatmap>>,
+    /// Slots for the calls in flight at once; None is unbounded
+    calls: Option<Arc<Semaphore>>,
+    /// Where PNG payloads and image tensors are rendered
+    cpu: CpuPool,
+    #[cfg(feature = "discovery")]
+    discovery: Arc<crate::discovery::Discovery>,
+    /// gRPC channels to model servers by URL and client settings
+    #[cfg(feature = "grpc-client")]
+    channels: Arc<Mutex<HashMap<(String, PoolKey), tonic::transport::Channel>>>,
+}
+
+fn service_error(service: &ServiceConfig, message: impl std::fmt::Display) -> Error {
+    Error::Service { service: service.name.clone(), message: message.to_string() }
+}
+
+impl ServiceClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Client reusing the heatmap of an earlier call for the same SOP Instance UID, service
+    /// model version and payload format; the default client calls the service every time
+    pub fn with_cache(mut self, config: &CacheConfig) -> Self {
+        self.responses = Arc::new(ResultCache::new(config));
+        self
+    }
+
+    /// Client keeping at most `calls` requests to the services in flight; further attempts wait
+    /// for a free slot, within their deadline
+    pub fn with_max_calls(mut self, calls: usize) -> Self {
+        self.calls = Some(Arc::new(Semaphore::new(calls.max(1))));
+        self
+    }
+
+    /// Client rendering PNG payloads in `pool` rather than right away on the blocking pool
+    pub fn with_cpu_pool(mut self, pool: CpuPool) -> Self {
+        self.cpu = pool;
+        self
+    }
+
+    /// Statistics of the cache of service heatmaps
+    pub fn cache_stats(&self) -> CacheStats {
+        self.responses.stats()
+    }
+
+    /// Current circuit breaker state for `service`
+    pub fn circuit_state(&self, service: &ServiceConfig) -> CircuitState {
+        self.circuits.state(service)
+    }
+
+    /// GET the service's health endpoint and return the round-trip time
+    ///
+    /// A `health_url` must answer with a 2xx status, and so must the model's readiness endpoint
+    /// probed on model servers without one. Otherwise the inference URL is probed, and any
+    /// response except 502, 503 or 504 shows the server is up, since it may not accept GET.
+    /// Model servers called over gRPC are asked whether the model is ready instead. Probes
+    /// bypass retries and the circuit breaker, so they neither wait for nor affect it.
+    pub async fn probe(&self, service: &ServiceConfig) -> Result<Duration> {
+        let endpoint = self.endpoint(service, service.health_url.as_deref().unwrap_or(&service.url)).await?;
+        let timeout = service.timeout.min(PROBE_TIMEOUT);
+        let started = Instant::now();
+        if service.health_url.is_none() && service.is_grpc() {
+            #[cfg(feature = "grpc-client")]
+            return self.probe_grpc(service, &endpoint.url, timeout).await.map(|()| started.elapsed());
+        }
+        let path = service.health_url.is_none().then(|| health_path(service)).flatten();
+        let url = match &path {
+            Some(path) => format!("{}{}", endpoint.url.trim_end_matches('/'), path),
+            None => endpoint.url.clone(),
+        };
+        let response = self.http_client(service)?
+            .get(&url)
+            .headers(request_headers(service)?)
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(|e| service_error(service, e))?;
+        let status = response.status();
+        let healthy = match service.health_url.is_some() || path.is_some() {
+            true => status.is_success(),
+            false => !matches!(status.as_u16(), 502..=504),
+        };
+        if !healthy {
+            return Err(service_error(service, format!("health check {} returned HTTP {}", url, status.as_u16())));
+        }
+        Ok(started.elapsed())
+    }
+
+    /// Send the DICOM to `service` (re-encoded as its payload format) and parse the heatmap it returns
+    ///
+    /// Transient failures are retried according to the service's [`RetryPolicy`], and calls fail
+    /// fast while its circuit is open (see [`CircuitPolicy`](crate::config::CircuitPolicy)). The
+    /// response format is taken from its `Content-Type`: JSON (the default), CSV or raw binary.
+    /// Model servers get the image tensor instead and return score and heatmap tensors (see
+    /// [`crate::model_server`]).
+    pub async fn fetch_heatmap(&self, service: &ServiceConfig, dicom: Arc<Vec<u8>>, registry: &HeatmapRegistry) -> Result<LoadedHeatmap> {
+        self.fetch_heatmap_until(service, dicom, registry, None).await
+    }
+
+    /// [`ServiceClient::fetch_heatmap`] giving up with `Error::Timeout` once `deadline` passes;
+    /// attempt timeouts and retry delays are shortened to fit
+    pub async fn fetch_heatmap_until(
+        &self,
+        service: &ServiceConfig,
+        dicom: Arc<Vec<u8>>,
+        registry: &HeatmapRegistry,
+        deadline: Option<Instant>,
+    ) -> Result<LoadedHeatmap> {
+        // DICOMs without a readable UID are always sent
+        let cache_key = self.responses.is_enabled()
+            .then(|| sop_instance_uid(&dicom).ok())
+            .flatten()
+            .map(|sop_instance| CacheKey {
+                sop_instance,
+                models: vec![service.name.clone(), service.model_key().to_string()],
+                spec: stable_hash(&format!("{:?} {:?} {:?} {:?} {:?}", service.payload_format, service.preprocess, service.kind, service.served, service.occlusion)),
+                scope: format!("sha256={}", sha256_bytes(&dicom)),
+            });
+        if let Some(key) = &cache_key
+            && let Some(heatmap) = self.responses.get(key)
+        {
+            info!("Reusing the heatmap service {} returned for instance {}", service.name, key.sop_instance);
+            return Ok(heatmap);
+        }
+        let fallback_key = service.circuit.fallback_to_cache.then(|| payload_key(&dicom));
+        #[cfg(feature = "telemetry")]
+        let result = {
+            let attributes = vec![KeyValue::new("dl.service", service.name.clone())];
+            let cx = telemetry::start(&Context::current(), format!("service {}", service.name), SpanKind::Internal, attributes);
+            telemetry::traced(cx, self.explain(service, dicom, registry, deadline)).await
+        };
+        #[cfg(not(feature = "telemetry"))]
+        let result = self.explain(service, dicom, registry, deadline).await;
+        if let (Some(key), Ok(heatmap)) = (cache_key, &result) {
+            self.responses.insert(key, heatmap.clone());
+        }
+        let Some(key) = fallback_key else {
+            return result;
+        };
+        match result {
+            Ok(heatmap) => {
+                self.circuits.remember(service, key, &heatmap);
+                Ok(heatmap)
+            }
+            Err(e) => match self.circuits.cached(service, key) {
+                Some(mut heatmap) => {
+                    warn!("{}; using the heatmap cached from an earlier call", e);
+                    heatmap.metadata.attributes.insert("cached".to_string(), "true".to_string());
+                    Ok(heatmap)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// The service's heatmap, or its occlusion map when configured
+    async fn explain(
+        &self,
+        service: &ServiceConfig,
+        dicom: Arc<Vec<u8>>,
+        registry: &HeatmapRegistry,
+        deadline: Option<Instant>,
+    ) -> Result<LoadedHeatmap> {
+        match &service.occlusion {
+            Some(options) => self.occlusion(service, options, dicom, registry, deadline).await,
+            None => self.call(service, dicom, registry, deadline).await,
+        }
+    }
+
+    /// Heatmap of the drops of the service's score while patches of the image are masked, from
+    /// one call for the image and one per patch, all with the image re-encoded as 8-bit grayscale
+    /// so that only the mask differs
+    ///
+    /// The score is that of the class the unmasked image is given, `score:<class>` when the
+    /// service returns every class, and the heatmap keeps the metadata of that first call.
+    async fn occlusion(
+        &self,
+        service: &ServiceConfig,
+        options: &OcclusionOptions,
+        dicom: Arc<Vec<u8>>,
+        registry: &HeatmapRegistry,
+        deadline: Option<Instant>,
+    ) -> Result<LoadedHeatmap> {
+        let options = *options;
+        let (source, image, plan, unmasked) = self.cpu.run(move || {
+            let source = open_dicom_bytes(&dicom)?;
+            let (rows, columns) = image_dimensions(&source)?;
+            let image = imageops::grayscale(&decode_dicom_pixel_data(&source, rows, columns)?);
+            let plan = OcclusionPlan::new(&image, &options);
+            let unmasked = with_grayscale_pixels(&source, &image)?;
+            Ok((Arc::new(source), Arc::new(image), Arc::new(plan), unmasked))
+        }).await?;
+        let mut heatmap = self.call(service, Arc::new(unmasked), registry, deadline).await?;
+        let class = heatmap.metadata.attributes.get("class").cloned();
+        let score = |heatmap: &LoadedHeatmap| -> Result<f32> {
+            let attributes = &heatmap.metadata.attributes;
+            class.as_ref().and_then(|class| attributes.get(&format!("score:{}", class)))
+                .or_else(|| attributes.get("score"))
+                .and_then(|score| score.parse().ok())
+                .ok_or_else(|| service_error(service, "occlusion needs a score in the response"))
+        };
+        let baseline = score(&heatmap)?;
+        info!("Occluding {} patches of {} pixels for service {}", plan.len(), plan.patch(), service.name);
+
+        let scores: Vec<f32> = stream::iter(0..plan.len())
+            .map(|index| {
+                let (source, image, plan) = (source.clone(), image.clone(), plan.clone());
+                async move {
+                    let masked = self.cpu.run(move || with_grayscale_pixels(&source, &plan.occluded(&image, index))).await?;
+                    score(&self.call(service, Arc::new(masked), registry, deadline).await?)
+                }
+            })
+            .buffered(OCCLUSION_CALLS)
+            .try_collect()
+            .await?;
+
+        heatmap.data = plan.assemble(baseline, &scores);
+        heatmap.metadata.shape = heatmap.data.dim();
+        heatmap.metadata.attributes.insert("method".to_string(), "occlusion".to_string());
+        heatmap.metadata.attributes.insert("occlusion".to_string(), format!("patch={},stride={},runs={}", plan.patch(), plan.stride(), plan.len()));
+        Ok(heatmap)
+    }
+
+    async fn call(
+        &self,
+        service: &ServiceConfig,
+        dicom: Arc<Vec<u8>>,
+        registry: &HeatmapRegistry,
+        deadline: Option<Instant>,
+    ) -> Result<LoadedHeatmap> {
+        let timed_out = || Error::Timeout { stage: Stage::Heatmap.name() };
+        let remaining = || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let mut placement = None;
+        let mut preprocess = service.preprocess.clone();
+        let payload = if service.kind.takes_tensors() {
+            let tensor = {
+                let service = service.clone();
+                self.cpu.run(move || image_tensor(&dicom, &service)).await?
+            };
+            placement = Some(tensor.placement);
+            preprocess = Some(tensor.preprocess.clone());
+            self.tensor_payload(service, &tensor)?
+        } else {
+            let (content_type, body) = match service.payload_format {
+                PayloadFormat::Dicom => ("application/dicom", dicom.to_vec()),
+                PayloadFormat::Png => {
+                    let preprocess = service.preprocess.clone();
+                    let (png, placed) = self.cpu.run(move || render_payload(&dicom, preprocess.as_ref())).await?;
+                    placement = placed;
+                    ("image/png", png)
+                }
+            };
+            let path = match service.kind {
+                ServiceKind::TorchServe => torchserve_path(service),
+                _ => String::new(),
+            };
+            let (content_type, body) = match service.served.multipart {
+                true => multipart_form(content_type, &body),
+                false => (content_type.to_string(), body),
+            };
+            Payload::Http { client: self.http_client(service)?, path, content_type, headers: request_headers(service)?, body }
+        };
+
+        let policy = &service.retry;
+        let mut attempt = 1;
+        let reply = loop {
+            // A call waits for a free slot until the deadline, or for the service's timeout without one
+            let slot = match &self.calls {
+                Some(calls) => match tokio::time::timeout(remaining().unwrap_or(service.timeout), calls.acquire()).await {
+                    Ok(slot) => slot.ok(),
+                    Err(_) if deadline.is_some() => return Err(timed_out()),
+                    Err(_) => return Err(service_error(service, "no free slot for the call within its timeout")),
+                },
+                None => None,
+            };
+            let timeout = match remaining() {
+                Some(remaining) if remaining.is_zero() => return Err(timed_out()),
+                Some(remaining) => remaining.min(service.timeout),
+                None => service.timeout,
+            };
+            let endpoint = self.endpoint(service, &service.url).await?;
+            let call = self.circuits.acquire(service).map_err(|reason| service_error(service, reason))?;
+            let outcome = match &payload {
+                Payload::Http { client, path, content_type, headers, body } => {
+                    let url = format!("{}{}", endpoint.url.trim_end_matches('/'), path);
+                    info!("Calling service {} at {} ({} bytes, {}, attempt {}/{})", service.name, url, body.len(),
+                          content_type, attempt, policy.max_attempts);
+                    send_once(client, service, &url, attempt, timeout, headers.clone(), content_type, body.clone()).await
+                        .map(|(headers, body)| Reply::Http(headers, body))
+                }
+                #[cfg(feature = "grpc-client")]
+                Payload::Grpc(request) => {
+                    info!("Calling service {} at {} over gRPC (attempt {}/{})", service.name, endpoint.url, attempt, policy.max_attempts);
+                    self.infer_grpc(service, &endpoint.url, timeout, request.clone()).await.map(Reply::Tensors)
+                }
+            };
+            drop(slot);
+            let failed = outcome.as_ref().err().is_some_and(|failure| failure.upstream);
+            #[cfg(feature = "discovery")]
+            if failed && let Some(endpoint) = &endpoint.discovered {
+                self.discovery.failed(endpoint);
+            }
+            if call.record(failed) {
+                warn!("Circuit for service {} opened for {}s", service.name, service.circuit.open_for.as_secs());
+            }
+            let failure = match outcome {
+                Ok(response) => break response,
+                Err(_) if remaining().is_some_and(|remaining| remaining.is_zero()) => return Err(timed_out()),
+                Err(failure) => failure,
+            };
+            if !failure.retryable || attempt >= policy.max_attempts {
+                if attempt > 1 {
+                    warn!("Giving up on service {} after {} attempts", service.name, attempt);
+                }
+                return Err(failure.error);
+            }
+            let delay = failure.retry_after.map(|delay| delay.min(policy.max_backoff)).unwrap_or_else(|| jittered(policy, attempt));
+            if remaining().is_some_and(|remaining| remaining <= delay) {
+                warn!("{}; no time left for a retry", failure.error);
+                return Err(timed_out());
+            }
+            warn!("{}; retrying in {}ms", failure.error, delay.as_millis());
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        };
+
+        let mut heatmap = match reply {
+            Reply::Http(_, body) if service.kind == ServiceKind::TfServing => {
+                tfserving_response(service, &body).and_then(|outputs| tensor_heatmap(service, &outputs))
+                    .map_err(|e| service_error(service, e))?
+            }
+            Reply::Http(headers, body) if service.kind.takes_tensors() => {
+                triton_response(&headers, &body).and_then(|outputs| tensor_heatmap(service, &outputs))
+                    .map_err(|e| service_error(service, e))?
+            }
+            Reply::Http(headers, body) if service.kind == ServiceKind::TorchServe => {
+                torchserve_heatmap(service, &headers, &body, registry).map_err(|e| service_error(service, e))?
+            }
+            Reply::Http(headers, body) => {
+                let format = headers.get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(heatmap_format)
+                    .unwrap_or("json");
+                registry.load_bytes(&body, format, &service.url)?
+            }
+            #[cfg(feature = "grpc-client")]
+            Reply::Tensors(outputs) => tensor_heatmap(service, &outputs).map_err(|e| service_error(service, e))?,
+        };
+        heatmap.metadata.attributes.insert("service".to_string(), service.name.clone());
+        if let (Some(preprocess), Some(placement)) = (&preprocess, placement) {
+            heatmap.data = preprocess.restore(&heatmap.data, &placement);
+            heatmap.metadata.shape = heatmap.data.dim();
+            heatmap.metadata.attributes.insert("preprocess".to_string(), preprocess.to_string());
+        }
+        info!("Service {} returned a {}x{} heatmap", service.name, heatmap.data.nrows(), heatmap.data.ncols());
+        Ok(heatmap)
+    }
+}
+
+/// A failed call and whether the retry policy may repeat it
+struct Failure {
+    error: Error,
+    retryable: bool,
+    /// Counts against the circuit breaker: the service is unreachable or failing
+    upstream: bool,
+    /// Delay requested by the service with `Retry-After`
+    retry_after: Option<Duration>,
+}
+
+/// URL an attempt goes to
+struct Endpoint {
+    url: String,
+    /// Instance picked by discovery, passed over for a while when it fails
+    #[cfg(feature = "discovery")]
+    discovered: Option<crate::discovery::Endpoint>,
+}
+
+/// Request of a call, built once and sent again on retries
+enum Payload {
+    /// POSTed to the endpoint URL followed by `path`
+    Http {
+        client: reqwest::Client,
+        path: String,
+        content_type: String,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    },
+    #[cfg(feature = "grpc-client")]
+    Grpc(GrpcRequest),
+}
+
+/// Infer request of a model server's gRPC API
+#[cfg(feature = "grpc-client")]
+#[derive(Clone)]
+enum GrpcRequest {
+    Triton(triton_grpc::proto::ModelInferRequest),
+    TfServing(tfserving_grpc::proto::PredictRequest),
+}
+
+/// Successful response of a call
+enum Reply {
+    /// Headers and body
+    Http(HeaderMap, Vec<u8>),
+    #[cfg(feature = "grpc-client")]
+    Tensors(Vec<Tensor>),
+}
+
+impl ServiceClient {
+    /// Infer request for a model server
+    fn tensor_payload(&self, service: &ServiceConfig, tensor: &ImageTensor) -> Result<Payload> {
+        if service.is_grpc() {
+            #[cfg(feature = "grpc-client")]
+            return Ok(Payload::Grpc(match service.kind {
+                ServiceKind::TfServing => GrpcRequest::TfServing(tfserving_grpc::request(service, tensor)),
+                _ => GrpcRequest::Triton(triton_grpc::request(service, tensor)),
+            }));
+            #[cfg(not(feature = "grpc-client"))]
+            return Err(Error::InvalidOption(format!("{} of {} needs a build with the grpc-client feature", service.url, service.name)));
+        }
+        let mut headers = request_headers(service)?;
+        let (path, content_type, body) = match service.kind {
+            ServiceKind::TfServing => (tfserving_path(service, "predict"), "application/json", tfserving_request(service, tensor)),
+            _ => {
+                let (body, length) = triton_request(service, tensor);
+                headers.insert(INFERENCE_HEADER_LENGTH, HeaderValue::from(length));
+                (triton_path(service), TRITON_BINARY_CONTENT_TYPE, body)
+            }
+        };
+        Ok(Payload::Http { client: self.http_client(service)?, path, content_type: content_type.to_string(), headers, body })
+    }
+
+    /// `url` of `service`, with a discovery URL resolved to one of its instances
+    async fn endpoint(&self, service: &ServiceConfig, url: &str) -> Result<Endpoint> {
+        #[cfg(feature = "discovery")]
+        if crate::discovery::is_discovered(url) {
+            let endpoint = self.discovery.endpoint(url).await.map_err(|e| service_error(service, e))?;
+            return Ok(Endpoint { url: endpoint.url.clone(), discovered: Some(endpoint) });
+        }
+        #[cfg(not(feature = "discovery"))]
+        if url.split_once("://").is_some_and(|(scheme, _)| crate::config::DISCOVERY_SCHEMES.contains(&scheme)) {
+            return Err(Error::InvalidOption(format!("{} of {} needs a build with the discovery feature", url, service.name)));
+        }
+        #[cfg(not(feature = "grpc-client"))]
+        if url.split_once("://").is_some_and(|(scheme, _)| crate::config::GRPC_SCHEMES.contains(&scheme)) {
+            return Err(Error::InvalidOption(format!("{} of {} needs a build with the grpc-client feature", url, service.name)));
+        }
+        Ok(Endpoint {
+            url: url.to_string(),
+            #[cfg(feature = "discovery")]
+            discovered: None,
+        })
+    }
+
+    /// HTTP client with the service's connect and read timeouts and TLS files, created on first use
+    fn http_client(&self, service: &ServiceConfig) -> Result<reqwest::Client> {
+        let key: PoolKey = (service.connect_timeout, service.read_timeout, service.tls.clone());
+        let mut clients = self.http.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+        let mut builder = reqwest::Client::builder().connect_timeout(service.connect_timeout);
+        if let Some(read_timeout) = service.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+        #[cfg(feature = "tls")]
+        {
+            builder = with_tls(builder, service)?;
+        }
+        #[cfg(not(feature = "tls"))]
+        if !service.tls.is_empty() {
+            return Err(Error::InvalidOption(format!("TLS files are set for {} but it was built without the tls feature", service.name)));
+        }
+        let client = builder.build().map_err(|e| service_error(service, e))?;
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+}
+
+/// Calls to model servers over gRPC
+#[cfg(feature = "grpc-client")]
+impl ServiceClient {
+    /// Channel to `url` (`grpc://` in plain text, `grpcs://` over TLS), connected on first use
+    fn channel(&self, service: &ServiceConfig, url: &str) -> Result<tonic::transport::Channel> {
+        use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+
+        let key = (url.to_string(), (service.connect_timeout, service.read_timeout, service.tls.clone()));
+        let mut channels = self.channels.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(channel) = channels.get(&key) {
+            return Ok(channel.clone());
+        }
+        let invalid = |e: tonic::transport::Error| Error::InvalidOption(format!("Invalid gRPC URL for {}: {} ({})", service.name, url, e));
+        let (scheme, rest) = url.split_once("://").unwrap_or(("grpc", url));
+        let secure = matches!(scheme, "grpcs" | "https");
+        let address = format!("{}://{}", if secure { "https" } else { "http" }, rest);
+        let mut endpoint = Endpoint::from_shared(address).map_err(invalid)?.connect_timeout(service.connect_timeout);
+        if secure {
+            let read = |path: &std::path::Path| std::fs::read(path).map_err(|e| Error::io(path, e));
+            let mut tls = ClientTlsConfig::new().with_webpki_roots();
+            if let (Some(cert), Some(key)) = (&service.tls.cert, &service.tls.key) {
+                tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+            }
+            if let Some(ca) = &service.tls.ca {
+                tls = tls.ca_certificate(Certificate::from_pem(read(ca)?));
+            }
+            endpoint = endpoint.tls_config(tls).map_err(invalid)?;
+        }
+        let channel = endpoint.connect_lazy();
+        channels.insert(key, channel.clone());
+        Ok(channel)
+    }
+
+    /// Request carrying the service's headers as metadata
+    fn grpc_request<T>(service: &ServiceConfig, message: T) -> Result<tonic::Request<T>> {
+        let mut request = tonic::Request::new(message);
+        for (name, value) in &service.headers {
+            let key = tonic::metadata::MetadataKey::from_bytes(name.to_lowercase().as_bytes())
+                .map_err(|_| service_error(service, format!("Invalid header name: {}", name)))?;
+            let value = value.parse().map_err(|_| service_error(service, format!("Invalid value for header {}", name)))?;
+            request.metadata_mut().insert(key, value);
+        }
+        Ok(request)
+    }
+
+    /// One inference call, returning the output tensors
+    async fn infer_grpc(&self, service: &ServiceConfig, url: &str, timeout: Duration, request: GrpcRequest) -> std::result::Result<Vec<Tensor>, Failure> {
+        let fail = |error: Error| Failure { error, retryable: false, upstream: false, retry_after: None };
+        let channel = self.channel(service, url).map_err(fail)?;
+        let outputs = match request {
+            GrpcRequest::Triton(request) => {
+                let mut client = triton_grpc::proto::grpc_inference_service_client::GrpcInferenceServiceClient::new(channel);
+                let request = Self::grpc_request(service, request).map_err(fail)?;
+                triton_grpc::response(within(service, timeout, client.model_infer(request)).await?)
+            }
+            GrpcRequest::TfServing(request) => {
+                let mut client = tfserving_grpc::proto::prediction_service_client::PredictionServiceClient::new(channel);
+                let request = Self::grpc_request(service, request).map_err(fail)?;
+                tfserving_grpc::response(within(service, timeout, client.predict(request)).await?)
+            }
+        };
+        outputs.map_err(|e| fail(service_error(service, e)))
+    }
+
+    /// Ask the model server whether the service's model is ready
+    async fn probe_grpc(&self, service: &ServiceConfig, url: &str, timeout: Duration) -> Result<()> {
+        let failed = |failure: Failure| failure.error;
+        let channel = self.channel(service, url)?;
+        let ready = match service.kind {
+            ServiceKind::TfServing => {
+                use tfserving_grpc::proto::model_version_status::State;
+
+                let mut client = tfserving_grpc::proto::model_service_client::ModelServiceClient::new(channel);
+                let request = tfserving_grpc::proto::GetModelStatusRequest { model_spec: Some(tfserving_grpc::model_spec(service)) };
+                let response = within(service, timeout, client.get_model_status(Self::grpc_request(service, request)?)).await.map_err(failed)?;
+                response.model_version_status.iter().any(|status| status.state == State::Available as i32)
+            }
+            _ => {
+                let mut client = triton_grpc::proto::grpc_inference_service_client::GrpcInferenceServiceClient::new(channel);
+                let request = triton_grpc::proto::ModelReadyRequest {
+                    name: service.served.name.clone().unwrap_or_default(),
+                    version: service.model_version.clone().unwrap_or_default(),
+                };
+                within(service, timeout, client.model_ready(Self::grpc_request(service, request)?)).await.map_err(failed)?.ready
+            }
+        };
+        if !ready {
+            return Err(service_error(service, format!("model {} is not ready", service.served.name.as_deref().unwrap_or_default())));
+        }
+        Ok(())
+    }
+}
+
+/// Message of a gRPC call answered within `timeout`
+#[cfg(feature = "grpc-client")]
+async fn within<T>(
+    service: &ServiceConfig,
+    timeout: Duration,
+    call: impl std::future::Future<Output = std::result::Result<tonic::Response<T>, tonic::Status>>,
+) -> std::result::Result<T, Failure> {
+    match tokio::time::timeout(timeout, call).await {
+        Ok(Ok(response)) => Ok(response.into_inner()),
+        Ok(Err(status)) => Err(status_failure(service, status)),
+        Err(_) => Err(Failure {
+            error: service_error(service, format!("no response within {}ms", timeout.as_millis())),
+            retryable: true,
+            upstream: true,
+            retry_after: None,
+        }),
+    }
+}
+
+/// Failure of a gRPC call from its status: unavailable servers, timeouts and exhausted or
+/// aborted calls are retried, and server-side errors count against the circuit
+#[cfg(feature = "grpc-client")]
+fn status_failure(service: &ServiceConfig, status: tonic::Status) -> Failure {
+    use tonic::Code;
+
+    let code = status.code();
+    Failure {
+        error: service_error(service, format!("gRPC {:?}: {}", code, status.message())),
+        retryable: matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted),
+        upstream: matches!(code, Code::Unavailable | Code::DeadlineExceeded | Code::Internal | Code::Unknown | Code::ResourceExhausted),
+        retry_after: None,
+    }
+}
+
+/// Add the service's client certificate and private CAs; files are read once per pool
+#[cfg(feature = "tls")]
+fn with_tls(mut builder: reqwest::ClientBuilder, service: &ServiceConfig) -> Result<reqwest::ClientBuilder> {
+    let read = |path: &std::path::Path| std::fs::read(path).map_err(|e| Error::io(path, e));
+    let invalid = |path: &std::path::Path, e: reqwest::Error| {
+        Error::InvalidOption(format!("Invalid TLS file for {}: {} ({})", service.name, path.display(), e))
+    };
+    if let (Some(cert), Some(key)) = (&service.tls.cert, &service.tls.key) {
+        // reqwest takes the chain and the key as one PEM document
+        let mut pem = read(cert)?;
+        pem.push(b'\n');
+        pem.extend(read(key)?);
+        builder = builder.identity(reqwest::Identity::from_pem(&pem).map_err(|e| invalid(cert, e))?);
+    }
+    if let Some(ca) = &service.tls.ca {
+        let certificates = reqwest::Certificate::from_pem_bundle(&read(ca)?).map_err(|e| invalid(ca, e))?;
+        if certificates.is_empty() {
+            return Err(Error::InvalidOption(format!("Invalid TLS file for {}: {} (no certificate found)", service.name, ca.display())));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder)
+}
+
+/// One attempt, traced as an HTTP client span that the service can continue via `traceparent`
+#[allow(unused_mut, unused_variables, clippy::too_many_arguments)]
+async fn send_once(
+    http: &reqwest::Client,
+    service: &ServiceConfig,
+    url: &str,
+    attempt: u32,
+    timeout: Duration,
+    mut headers: HeaderMap,
+    content_type: &str,
+    body: Vec<u8>,
+) -> std::result::Result<(HeaderMap, Vec<u8>), Failure> {
+    #[cfg(feature = "telemetry")]
+    {
+        let attributes = vec![
+            KeyValue::new("dl.service", service.name.clone()),
+            KeyValue::new("http.request.method", "POST"),
+            KeyValue::new("url.full", url.to_string()),
+            KeyValue::new("http.request.resend_count", i64::from(attempt - 1)),
+        ];
+        let cx = telemetry::start(&Context::current(), "POST", SpanKind::Client, attributes);
+        telemetry::inject(&cx, &mut headers);
+        let outcome = opentelemetry::context::FutureExt::with_context(
+            exchange(http, service, url, timeout, headers, content_type, body), cx.clone()).await;
+        telemetry::end(&cx, outcome.as_ref().err().map(|failure| &failure.error));
+        outcome
+    }
+    #[cfg(not(feature = "telemetry"))]
+    exchange(http, service, url, timeout, headers, content_type, body).await
+}
+
+/// One request, returning the headers and body of a successful response
+async fn exchange(
+    http: &reqwest::Client,
+    service: &ServiceConfig,
+    url: &str,
+    timeout: Duration,
+    headers: HeaderMap,
+    content_type: &str,
+    body: Vec<u8>,
+) -> std::result::Result<(HeaderMap, Vec<u8>), Failure> {
+    let transport = |e: reqwest::Error| Failure {
+        retryable: e.is_timeout() || e.is_connect() || e.is_request(),
+        upstream: true,
+        error: service_error(service, e),
+        retry_after: None,
+    };
+    let response = http
+        .post(url)
+        .headers(headers)
+        .header(CONTENT_TYPE, content_type)
+        .timeout(timeout)
+        .body(body)
+        .send()
+        .await
+        .map_err(transport)?;
+
+    let status = response.status();
+    #[cfg(feature = "telemetry")]
+    Context::current().span().set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+    let retry_after = response.headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs);
+    let headers = response.headers().clone();
+    let bytes = response.bytes().await.map_err(transport)?;
+    if !status.is_success() {
+        let detail = String::from_utf8_lossy(&bytes[..bytes.len().min(200)]).into_owned();
+        return Err(Failure {
+            error: service_error(service, format!("HTTP {}: {}", status.as_u16(), detail.trim())),
+            retryable: service.retry.retries_status(status.as_u16()),
+            upstream: status.is_server_error(),
+            retry_after,
+        });
+    }
+    Ok((headers, bytes.to_vec()))
+}
+
+/// Backoff for retry number `retry`, spread randomly by the policy's jitter
+fn jittered(policy: &RetryPolicy, retry: u32) -> Duration {
+    let random = RandomState::new().hash_one(retry) as f64 / u64::MAX as f64;
+    policy.backoff(retry).mul_f64(1.0 + policy.jitter * (2.0 * random - 1.0))
+}
+
+/// Stable key identifying a DICOM for the fallback cache
+fn payload_key(dicom: &[u8]) -> u64 {
+    stable_hash(&dicom)
+}
+
+/// PNG of the decoded image, resized with `preprocess` if given, and where the image lies in it
+fn render_payload(dicom: &[u8], preprocess: Option<&PreprocessSpec>) -> Result<(Vec<u8>, Option<Placement>)> {
+    let obj = open_dicom_bytes(dicom)?;
+    let (rows, columns) = image_dimensions(&obj)?;
+    let image = decode_dicom_pixel_data(&obj, rows, columns)?;
+    let Some(preprocess) = preprocess else {
+        return Ok((encode_png(&image)?, None));
+    };
+    let (resized, placement) = preprocess.resize(&imageops::grayscale(&image), (columns, rows));
+    Ok((encode_png(&DynamicImage::ImageLuma8(resized).to_rgba8())?, Some(placement)))
+}
+
+fn request_headers(service: &ServiceConfig) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &service.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| service_error(service, format!("Invalid header name: {}", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| service_error(service, format!("Invalid value for header {}", name)))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+/// Registry format for a response `Content-Type`
+pub(crate) fn heatmap_format(content_type: &str) -> &'static str {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    match mime.as_str() {
+        "text/csv" => "csv",
+        "application/octet-stream" => "bin",
+        _ => "json",
+    }
+}
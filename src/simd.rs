@@ -0,0 +1,96 @@
+//! Vectorized full-array scans and maps, eight lanes at a time with `wide` (SSE or AVX on x86,
+//! NEON on ARM, simd128 on WebAssembly, plain arrays elsewhere).
+
+#[cfg(feature = "dicom")]
+use wide::u16x8;
+use wide::f32x8;
+
+const LANES: usize = 8;
+
+/// Smallest and largest value, ignoring NaN; infinity and negative infinity without values
+pub(crate) fn min_max(values: &[f32]) -> (f32, f32) {
+    let (chunks, rest) = values.as_chunks::<LANES>();
+    let (mut min, mut max) = (f32x8::splat(f32::INFINITY), f32x8::splat(f32::NEG_INFINITY));
+    for chunk in chunks {
+        let chunk = f32x8::new(*chunk);
+        min = min.min(chunk);
+        max = max.max(chunk);
+    }
+    let min = min.to_array().into_iter().chain(rest.iter().copied()).fold(f32::INFINITY, f32::min);
+    let max = max.to_array().into_iter().chain(rest.iter().copied()).fold(f32::NEG_INFINITY, f32::max);
+    (min, max)
+}
+
+/// Mean and population standard deviation, None without values
+pub(crate) fn mean_std(values: &[f32]) -> Option<(f32, f32)> {
+    if values.is_empty() {
+        return None;
+    }
+    let (chunks, rest) = values.as_chunks::<LANES>();
+    let count = values.len() as f32;
+
+    let mut sum = f32x8::ZERO;
+    for chunk in chunks {
+        sum += f32x8::new(*chunk);
+    }
+    let mean = (sum.reduce_add() + rest.iter().sum::<f32>()) / count;
+
+    let (mut squares, center) = (f32x8::ZERO, f32x8::splat(mean));
+    for chunk in chunks {
+        let deviation = f32x8::new(*chunk) - center;
+        squares += deviation * deviation;
+    }
+    let variance = (squares.reduce_add() + rest.iter().map(|x| (x - mean).powi(2)).sum::<f32>()) / count;
+    Some((mean, variance.sqrt()))
+}
+
+/// Replace every value with `(x - offset) / scale`, clamped to 0.0-1.0 when `clamp` is set
+pub(crate) fn scale_in_place(values: &mut [f32], offset: f32, scale: f32, clamp: bool) {
+    let (chunks, rest) = values.as_chunks_mut::<LANES>();
+    let (offset_lanes, scale_lanes) = (f32x8::splat(offset), f32x8::splat(scale));
+    for chunk in chunks {
+        let scaled = (f32x8::new(*chunk) - offset_lanes) / scale_lanes;
+        let scaled = if clamp { scaled.clamp(f32x8::ZERO, f32x8::ONE) } else { scaled };
+        *chunk = scaled.to_array();
+    }
+    for x in rest {
+        let scaled = (*x - offset) / scale;
+        *x = if clamp { scaled.clamp(0.0, 1.0) } else { scaled };
+    }
+}
+
+/// Smallest and largest level of native-endian 16-bit samples, None without samples
+#[cfg(feature = "dicom")]
+pub(crate) fn min_max_u16(samples: &[[u8; 2]], level: impl Fn(u16) -> u16) -> Option<(u16, u16)> {
+    if samples.is_empty() {
+        return None;
+    }
+    let (chunks, rest) = samples.as_chunks::<LANES>();
+    let (mut min, mut max) = (u16x8::splat(u16::MAX), u16x8::splat(u16::MIN));
+    for chunk in chunks {
+        let chunk = u16x8::new(chunk.map(|sample| level(u16::from_ne_bytes(sample))));
+        min = min.min(chunk);
+        max = max.max(chunk);
+    }
+    let rest = rest.iter().map(|&sample| level(u16::from_ne_bytes(sample)));
+    let (min, max) = rest.fold((min.reduce_min(), max.reduce_max()), |(min, max), x| (min.min(x), max.max(x)));
+    Some((min, max))
+}
+
+/// Levels of native-endian 16-bit samples scaled linearly into `out`, one byte per sample, so
+/// `min` maps to 0 and `min + range` to 255
+#[cfg(feature = "dicom")]
+pub(crate) fn scale_u16_into(samples: &[[u8; 2]], level: impl Fn(u16) -> u16, min: f32, range: f32, out: &mut [u8]) {
+    let (chunks, rest) = samples.as_chunks::<LANES>();
+    let (out_chunks, out_rest) = out.as_chunks_mut::<LANES>();
+    let (min_lanes, range_lanes) = (f32x8::splat(min), f32x8::splat(range));
+    let white = f32x8::splat(255.0);
+    for (chunk, out) in chunks.iter().zip(out_chunks) {
+        let lanes = f32x8::new(chunk.map(|sample| f32::from(level(u16::from_ne_bytes(sample)))));
+        let levels = (((lanes - min_lanes) / range_lanes) * white).clamp(f32x8::ZERO, white);
+        *out = levels.to_array().map(|level| level as u8);
+    }
+    for (&sample, out) in rest.iter().zip(out_rest) {
+        *out = (((f32::from(level(u16::from_ne_bytes(sample))) - min) / range) * 255.0).clamp(0.0, 255.0) as u8;
+    }
+}
@@ -0,0 +1,428 @@
+//! Per-slice aggregation of volumetric heatmaps: summary statistics of every slice of a 3-D
+//! heatmap and its activation profile across the slices, ranked so reviewers can jump to the
+//! most suspicious ones.
+
+use flate2::read::GzDecoder;
+use ndarray::{Array3, ArrayView3, Axis};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::evaluation::parse_npy;
+
+/// Slices logged as the most suspicious
+pub const TOP_SLICES: usize = 5;
+
+/// Slices chosen from a volume, counted from 0, parsed from `START..END` with the end excluded,
+/// `START..=END`, either bound left out for the first or last slice, and an optional `:STEP`,
+/// e.g. `40..120:2`; a single index picks one slice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SliceRange {
+    pub start: usize,
+    /// First slice after the range, None for the end of the volume
+    pub end: Option<usize>,
+    pub step: usize,
+}
+
+impl FromStr for SliceRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let index = |value: &str| value.trim().parse::<usize>().map_err(|_| format!("Invalid slice index: {}", value));
+        // Index of the slice after `value`, the end of a range that includes it
+        let after = |value: &str| index(value)?.checked_add(1).ok_or_else(|| format!("Invalid slice index: {}", value));
+        let (range, step) = match s.split_once(':') {
+            Some((range, step)) => match step.trim().parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid slice step: {}. Expected a positive integer", step)),
+            },
+            None => (s, 1),
+        };
+        let (start, end) = match (range.split_once("..="), range.split_once("..")) {
+            (Some((start, end)), _) => (start, Some(after(end)?)),
+            (None, Some((start, ""))) => (start, None),
+            (None, Some((start, end))) => (start, Some(index(end)?)),
+            (None, None) => (range, Some(after(range)?)),
+        };
+        let start = match start.trim() {
+            "" => 0,
+            start => index(start)?,
+        };
+        if end.is_some_and(|end| end <= start) {
+            return Err(format!("Empty slice range: {}. Expected START..END with END after START", s));
+        }
+        Ok(SliceRange { start, end, step })
+    }
+}
+
+impl fmt::Display for SliceRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..", self.start)?;
+        if let Some(end) = self.end {
+            write!(f, "{}", end)?;
+        }
+        if self.step != 1 {
+            write!(f, ":{}", self.step)?;
+        }
+        Ok(())
+    }
+}
+
+impl SliceRange {
+    /// Indices of the range in a volume of `count` slices, in order
+    pub fn indices(&self, count: usize) -> Vec<usize> {
+        let end = self.end.map_or(count, |end| end.min(count));
+        (self.start..end).step_by(self.step).collect()
+    }
+}
+
+/// Load a 3-D heatmap of (slices, rows, cols) from a `.npy` array, a NIfTI-1 `.nii` or `.nii.gz`
+/// image, or JSON whose `data` is an array of slices of rows; a 2-D heatmap is a volume of one
+/// slice
+pub fn load_heatmap_volume(path: &Path) -> Result<Array3<f32>> {
+    let origin = path.display().to_string();
+    let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    let name = path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let extension = match name.ends_with(".nii.gz") {
+        true => Some("nii".to_string()),
+        false => path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase),
+    };
+    let (shape, values) = match extension.as_deref() {
+        Some("npy") => {
+            let array = parse_npy(&bytes).map_err(|e| Error::heatmap(&origin, e))?;
+            let shape = array.shape().to_vec();
+            (shape, array.iter().map(|&value| value as f32).collect())
+        }
+        Some("json") => {
+            let parsed: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| Error::heatmap(&origin, e))?;
+            let data = parsed.get("data").ok_or_else(|| Error::heatmap(&origin, "JSON must contain 'data' field with nested arrays"))?;
+            // The first array of each level gives its length
+            let (mut shape, mut first) = (Vec::new(), data);
+            while let Some(items) = first.as_array() {
+                shape.push(items.len());
+                match items.first() {
+                    Some(item) => first = item,
+                    None => break,
+                }
+            }
+            let mut values = Vec::new();
+            flatten_json(data, &shape, &mut values, &origin)?;
+            (shape, values)
+        }
+        Some("nii") => parse_nifti(&bytes).map_err(|e| Error::heatmap(&origin, e))?,
+        _ => return Err(Error::heatmap(&origin, "Unsupported heatmap volume format. Supported: .npy, .nii, .nii.gz, .json")),
+    };
+    let (slices, rows, cols) = match shape[..] {
+        [rows, cols] => (1, rows, cols),
+        [slices, rows, cols] => (slices, rows, cols),
+        _ => return Err(Error::heatmap(&origin, format!("Expected a 3-D heatmap volume, found {} dimension(s)", shape.len()))),
+    };
+    let found = values.len();
+    Array3::from_shape_vec((slices, rows, cols), values).map_err(|_| Error::ShapeMismatch {
+        context: origin.clone(),
+        expected: slices * rows * cols,
+        found,
+    })
+}
+
+/// Append the numbers of nested JSON arrays to `values` in row-major order, checking every array
+/// has the length of its level in `shape`
+fn flatten_json(value: &serde_json::Value, shape: &[usize], values: &mut Vec<f32>, origin: &str) -> Result<()> {
+    match (value, shape) {
+        (serde_json::Value::Array(items), [len, inner @ ..]) => {
+            if items.len() != *len {
+                return Err(Error::ShapeMismatch { context: origin.to_string(), expected: *len, found: items.len() });
+            }
+            items.iter().try_for_each(|item| flatten_json(item, inner, values, origin))
+        }
+        (value, []) if !value.is_array() => {
+            let value = value.as_f64().ok_or_else(|| Error::heatmap(origin, "JSON data must contain numeric values"))?;
+            values.push(value as f32);
+            Ok(())
+        }
+        _ => Err(Error::heatmap(origin, "JSON data must be evenly nested arrays")),
+    }
+}
+
+/// Shape, as (slices, rows, cols) or (rows, cols), and values of a single-file NIfTI-1 image,
+/// gzipped or not, scaled by its `scl_slope` and `scl_inter`
+///
+/// The first voxel axis runs along the columns, the second along the rows and the third across
+/// the slices, so the volume must be stored in the voxel order of the image it is registered to;
+/// its orientation matrices aren't applied.
+fn parse_nifti(bytes: &[u8]) -> std::result::Result<(Vec<usize>, Vec<f32>), String> {
+    let mut inflated = Vec::new();
+    let bytes = match bytes.starts_with(&[0x1f, 0x8b]) {
+        true => {
+            GzDecoder::new(bytes).read_to_end(&mut inflated).map_err(|e| format!("invalid gzip stream: {}", e))?;
+            &inflated[..]
+        }
+        false => bytes,
+    };
+    if bytes.len() < 348 {
+        return Err("NIfTI header is truncated".to_string());
+    }
+    // The header size is 348 in the file's byte order
+    let little = match (i32::from_le_bytes(bytes[0..4].try_into().unwrap()), i32::from_be_bytes(bytes[0..4].try_into().unwrap())) {
+        (348, _) => true,
+        (_, 348) => false,
+        (540, _) | (_, 540) => return Err("NIfTI-2 images are not supported".to_string()),
+        _ => return Err("not a NIfTI-1 image".to_string()),
+    };
+    if &bytes[344..348] != b"n+1\0" {
+        return Err("only single-file NIfTI-1 images (.nii) are supported".to_string());
+    }
+    let int16 = |offset: usize| {
+        let raw = [bytes[offset], bytes[offset + 1]];
+        if little { i16::from_le_bytes(raw) } else { i16::from_be_bytes(raw) }
+    };
+    let float32 = |offset: usize| {
+        let raw = bytes[offset..offset + 4].try_into().unwrap();
+        if little { f32::from_le_bytes(raw) } else { f32::from_be_bytes(raw) }
+    };
+
+    let dims = int16(40);
+    if !(1..=7).contains(&dims) {
+        return Err(format!("invalid NIfTI dimension count {}", dims));
+    }
+    let sizes: Vec<usize> = (1..=dims as usize).map(|axis| int16(40 + 2 * axis).max(1) as usize).collect();
+    if sizes.iter().skip(3).any(|&size| size > 1) {
+        return Err(format!("expected a 3-D NIfTI image, found dimensions {:?}", sizes));
+    }
+    let (cols, rows, slices) = (sizes[0], sizes.get(1).copied().unwrap_or(1), sizes.get(2).copied().unwrap_or(1));
+    // Voxels start at vox_offset, after the header and its 4-byte extension flag
+    let (datatype, vox_offset) = (int16(70), float32(108));
+    if !vox_offset.is_finite() || vox_offset > bytes.len() as f32 {
+        return Err(format!("invalid NIfTI vox_offset {} for a {}-byte file", vox_offset, bytes.len()));
+    }
+    let offset = vox_offset.max(352.0) as usize;
+    let width = match datatype {
+        2 | 256 => 1,
+        4 | 512 => 2,
+        8 | 16 | 768 => 4,
+        64 => 8,
+        _ => return Err(format!("unsupported NIfTI datatype {}", datatype)),
+    };
+    let count = slices.checked_mul(rows).and_then(|count| count.checked_mul(cols))
+        .ok_or_else(|| format!("NIfTI dimensions {:?} are too large", sizes))?;
+    let end = count.checked_mul(width).and_then(|size| size.checked_add(offset))
+        .ok_or_else(|| format!("NIfTI dimensions {:?} are too large", sizes))?;
+    let data = bytes.get(offset..end)
+        .ok_or_else(|| format!("NIfTI data is truncated: expected {} voxels", count))?;
+    let value = |raw: &[u8]| -> f64 {
+        macro_rules! read {
+            ($ty:ty) => {{
+                let raw = raw.try_into().unwrap();
+                (if little { <$ty>::from_le_bytes(raw) } else { <$ty>::from_be_bytes(raw) }) as f64
+            }};
+        }
+        match datatype {
+            2 => f64::from(raw[0]),
+            256 => f64::from(raw[0] as i8),
+            4 => read!(i16),
+            512 => read!(u16),
+            8 => read!(i32),
+            768 => read!(u32),
+            16 => read!(f32),
+            _ => read!(f64),
+        }
+    };
+    let (slope, intercept) = (f64::from(float32(112)), f64::from(float32(116)));
+    let scaled = slope != 0.0 && slope.is_finite();
+    let values = data.chunks_exact(width)
+        .map(|raw| {
+            let value = value(raw);
+            (if scaled { value * slope + intercept } else { value }) as f32
+        })
+        .collect();
+    let shape = match dims {
+        1 | 2 => vec![rows, cols],
+        _ => vec![slices, rows, cols],
+    };
+    Ok((shape, values))
+}
+
+/// Summary statistics of one slice of a volume
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SliceStats {
+    /// Index of the slice, from 0
+    pub slice: usize,
+    /// Raw value range and mean of the slice
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Pixels at or above the profile's threshold, and their share of the slice
+    pub active_pixels: u64,
+    pub active_fraction: f64,
+}
+
+/// Statistics of every slice of a volume, and the slices ranked by their activation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SliceProfile {
+    /// Level, a share of the value range of the profiled slices, at and above which a pixel is
+    /// active; a range shared across slices keeps their areas comparable
+    pub threshold: f32,
+    /// One entry per profiled slice, in slice order
+    pub slices: Vec<SliceStats>,
+    /// Positions in `slices`, most suspicious first: by maximum, then by active area
+    pub ranking: Vec<usize>,
+}
+
+impl SliceProfile {
+    /// Slice with the highest maximum, None for an empty volume
+    pub fn peak(&self) -> Option<&SliceStats> {
+        self.ranking.first().map(|&position| &self.slices[position])
+    }
+
+    /// Up to `count` of the most suspicious slices, most suspicious first
+    pub fn top(&self, count: usize) -> impl Iterator<Item = &SliceStats> {
+        self.ranking.iter().take(count).map(|&position| &self.slices[position])
+    }
+
+    /// Write the profile as CSV (one row per profiled slice, in slice order, with its rank from 1) for
+    /// `.csv` paths, as JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let csv = path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let text = if csv {
+            let mut ranks = vec![0; self.slices.len()];
+            for (rank, &position) in self.ranking.iter().enumerate() {
+                ranks[position] = rank + 1;
+            }
+            let rows = self.slices.iter().zip(ranks).map(|(stats, rank)| {
+                format!("{},{},{},{},{},{},{}\n", stats.slice, rank, stats.min, stats.max, stats.mean,
+                        stats.active_pixels, stats.active_fraction)
+            });
+            std::iter::once("slice,rank,min,max,mean,active_pixels,active_fraction\n".to_string())
+                .chain(rows)
+                .collect()
+        } else {
+            serde_json::to_string_pretty(self)
+                .map_err(|e| Error::Render(format!("Failed to serialize slice profile: {}", e)))?
+        };
+        std::fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Statistics of each slice of `volume` (slices, rows, cols), with pixels active at or above
+/// `threshold` of the volume's value range; NaN values are left out
+pub fn slice_profile(volume: ArrayView3<f32>, threshold: f32) -> SliceProfile {
+    let slices: Vec<usize> = (0..volume.len_of(Axis(0))).collect();
+    slice_profile_of(volume, &slices, threshold)
+}
+
+/// [`slice_profile`] of the slices of `volume` at `slices`, in that order, with the threshold
+/// taken of their value range
+pub fn slice_profile_of(volume: ArrayView3<f32>, slices: &[usize], threshold: f32) -> SliceProfile {
+    let (min, max) = slices.iter().flat_map(|&slice| volume.index_axis(Axis(0), slice)).filter(|value| !value.is_nan())
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| (min.min(value), max.max(value)));
+    // Nothing stands out of a constant volume
+    let level = match max > min {
+        true => min + threshold * (max - min),
+        false => f32::INFINITY,
+    };
+
+    let slices: Vec<SliceStats> = slices.iter()
+        .map(|&slice| {
+            let values = volume.index_axis(Axis(0), slice);
+            let (mut min, mut max, mut sum, mut count, mut active_pixels) = (f32::INFINITY, f32::NEG_INFINITY, 0.0f64, 0u64, 0u64);
+            for &value in values.iter().filter(|value| !value.is_nan()) {
+                min = min.min(value);
+                max = max.max(value);
+                sum += f64::from(value);
+                count += 1;
+                active_pixels += u64::from(value >= level);
+            }
+            let (min, max, mean) = match count {
+                0 => (0.0, 0.0, 0.0),
+                _ => (min, max, (sum / count as f64) as f32),
+            };
+            SliceStats {
+                slice,
+                min,
+                max,
+                mean,
+                active_pixels,
+                active_fraction: active_pixels as f64 / values.len().max(1) as f64,
+            }
+        })
+        .collect();
+
+    let mut ranking: Vec<usize> = (0..slices.len()).collect();
+    ranking.sort_by(|&a, &b| {
+        slices[b].max.total_cmp(&slices[a].max)
+            .then(slices[b].active_pixels.cmp(&slices[a].active_pixels))
+            .then(a.cmp(&b))
+    });
+    SliceProfile { threshold, slices, ranking }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(text: &str) -> std::result::Result<SliceRange, String> {
+        text.parse()
+    }
+
+    #[test]
+    fn parses_slice_ranges() {
+        assert_eq!(range("40..120:2"), Ok(SliceRange { start: 40, end: Some(120), step: 2 }));
+        assert_eq!(range("..=5"), Ok(SliceRange { start: 0, end: Some(6), step: 1 }));
+        assert_eq!(range("300.."), Ok(SliceRange { start: 300, end: None, step: 1 }));
+        assert_eq!(range("42"), Ok(SliceRange { start: 42, end: Some(43), step: 1 }));
+    }
+
+    #[test]
+    fn rejects_invalid_slice_ranges() {
+        assert!(range("5..3").is_err());
+        assert!(range("5..5").is_err());
+        assert!(range(":0").is_err());
+        assert!(range("1..4:0").is_err());
+        assert!(range("a..4").is_err());
+        assert!(range(&usize::MAX.to_string()).is_err());
+        assert!(range(&format!("..={}", usize::MAX)).is_err());
+    }
+
+    #[test]
+    fn indices_are_clamped_to_the_volume() {
+        assert_eq!(range("2..100:3").unwrap().indices(10), vec![2, 5, 8]);
+        assert_eq!(range("7..").unwrap().indices(10), vec![7, 8, 9]);
+        assert_eq!(range("..=3").unwrap().indices(2), vec![0, 1]);
+        assert!(range("12..20").unwrap().indices(10).is_empty());
+    }
+
+    /// Little-endian header of a `cols` x `rows` x `slices` float32 volume with voxels at `vox_offset`
+    fn nifti_header(cols: i16, rows: i16, slices: i16, vox_offset: f32) -> Vec<u8> {
+        let mut header = vec![0u8; 352];
+        header[0..4].copy_from_slice(&348i32.to_le_bytes());
+        for (offset, value) in [(40, 3), (42, cols), (44, rows), (46, slices), (70, 16)] {
+            header[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        header[108..112].copy_from_slice(&vox_offset.to_le_bytes());
+        header[344..348].copy_from_slice(b"n+1\0");
+        header
+    }
+
+    #[test]
+    fn reads_nifti_volumes() {
+        let mut bytes = nifti_header(2, 1, 2, 352.0);
+        bytes.extend([1.0f32, 2.0, 3.0, 4.0].iter().flat_map(|value| value.to_le_bytes()));
+        assert_eq!(parse_nifti(&bytes), Ok((vec![2, 1, 2], vec![1.0, 2.0, 3.0, 4.0])));
+        assert!(parse_nifti(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_nifti_offsets() {
+        for vox_offset in [f32::NAN, f32::INFINITY, 1e30, 4096.0] {
+            let mut bytes = nifti_header(2, 1, 2, vox_offset);
+            bytes.extend([0u8; 16]);
+            assert!(parse_nifti(&bytes).is_err(), "vox_offset {}", vox_offset);
+        }
+        let mut bytes = nifti_header(i16::MAX, i16::MAX, i16::MAX, 352.0);
+        bytes.extend([0u8; 16]);
+        assert!(parse_nifti(&bytes).is_err());
+    }
+}
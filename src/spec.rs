@@ -0,0 +1,261 @@
+//! Serializable pipeline specification, loadable from JSON/TOML and embedded in output sidecars.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::colormap::ColorMap;
+use crate::comparison::HeatmapComparison;
+use crate::coverage::{Coverage, CoverageOptions};
+use crate::decision::{Decision, OperatingPoints};
+use crate::demo::DemoOptions;
+use crate::error::{Error, Result};
+use crate::evaluation::MaskEvaluation;
+use crate::heatmap::FusionMethod;
+use crate::histogram::{Histogram, HistogramOptions};
+use crate::hotspots::{HotspotOptions, HotspotReport};
+use crate::localization::{PointingOptions, PointingResult};
+use crate::normalize::Normalization;
+use crate::pipeline::HeatmapSummary;
+use crate::provenance::HashedInput;
+use crate::quality::{QualityOptions, QualityReport};
+use crate::regions::{RegionMask, RegionReport};
+use crate::render::{Annotation, Backend, BlendOptions};
+use crate::slices::SliceRange;
+use crate::sweep::{SweepOptions, ThresholdSweep};
+
+/// Version written into new specs; older versions are still accepted
+pub const SPEC_VERSION: u32 = 1;
+
+/// Base image source that can be recorded in a spec
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceSpec {
+    Dicom { path: PathBuf },
+    Demo(DemoOptions),
+}
+
+/// Heatmap file fused into an ensemble, with its weight
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnsembleMember {
+    pub path: PathBuf,
+    pub weight: f32,
+}
+
+/// Heatmap file drawn over the heatmap with its own colormap
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OverlaySpec {
+    pub path: PathBuf,
+    pub colormap: ColorMap,
+}
+
+/// WebAssembly plugin that post-processed the normalized heatmap and its scores
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginSpec {
+    pub name: String,
+    /// None for a module created from bytes
+    pub path: Option<PathBuf>,
+    /// SHA-256 of the module bytes in lowercase hex; a replay needs the same module
+    pub sha256: String,
+}
+
+/// Every parameter that affects a rendering, so it can be reproduced exactly
+///
+/// In-memory inputs (DICOM bytes, pre-decoded images, heatmap arrays) and DL services cannot be
+/// recorded and leave `source`/`heatmap`/`ensemble`/`overlays` empty; the caller supplies them
+/// again when replaying the spec. An ensemble or overlays mixing such layers with files cannot be
+/// recorded at all.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipelineSpec {
+    pub version: u32,
+    pub source: Option<SourceSpec>,
+    /// Frame of a multi-frame DICOM source, from 0
+    pub frame: u32,
+    /// Slices rendered of a multi-frame source or heatmap volume, None for all of them
+    pub slices: Option<SliceRange>,
+    /// Heatmap file, format chosen by extension
+    pub heatmap: Option<PathBuf>,
+    /// Heatmap files fused into the heatmap, in place of `heatmap`
+    pub ensemble: Vec<EnsembleMember>,
+    /// How the `ensemble` is fused, the default method when None
+    pub fusion: Option<FusionMethod>,
+    /// Heatmap files drawn over the heatmap, in order
+    pub overlays: Vec<OverlaySpec>,
+    /// Baseline heatmap file the heatmap's difference is rendered from
+    pub baseline: Option<PathBuf>,
+    pub colormap: ColorMap,
+    pub normalization: Normalization,
+    pub blend: BlendOptions,
+    pub annotations: Vec<Annotation>,
+    /// Thresholds the heatmap's class score is decided at
+    pub operating_points: Option<OperatingPoints>,
+    /// Ground-truth mask the normalized heatmap is scored against
+    pub ground_truth: Option<PathBuf>,
+    /// How hotspots of the normalized heatmap are detected, None to skip detection
+    pub hotspots: Option<HotspotOptions>,
+    /// How the histogram of the heatmap values is taken, None to skip it
+    pub histogram: Option<HistogramOptions>,
+    /// Labeled region masks the normalized heatmap is summarized in
+    pub region_masks: Vec<RegionMask>,
+    /// Lesion annotations the maximum of the normalized heatmap is scored against
+    pub pointing: Option<PointingOptions>,
+    /// Anatomy mask the high activation of the normalized heatmap is expected inside
+    pub coverage: Option<CoverageOptions>,
+    /// Levels the normalized heatmap is thresholded at for a threshold sweep, None to skip it
+    pub threshold_sweep: Option<SweepOptions>,
+    /// Whether the input is quality-controlled and processing gated on it, None to skip it
+    pub quality: Option<QualityOptions>,
+    /// Plugin the normalized heatmap is post-processed with
+    pub plugin: Option<PluginSpec>,
+    /// Where the heatmap was rendered; the GPU's pixels differ from the CPU's by up to a color level
+    pub backend: Backend,
+    pub lenient: bool,
+}
+
+impl Default for PipelineSpec {
+    fn default() -> Self {
+        PipelineSpec {
+            version: SPEC_VERSION,
+            source: None,
+            frame: 0,
+            slices: None,
+            heatmap: None,
+            ensemble: Vec::new(),
+            fusion: None,
+            overlays: Vec::new(),
+            baseline: None,
+            colormap: ColorMap::Red,
+            normalization: Normalization::MinMax,
+            blend: BlendOptions::default(),
+            annotations: Vec::new(),
+            operating_points: None,
+            ground_truth: None,
+            hotspots: None,
+            histogram: None,
+            region_masks: Vec::new(),
+            pointing: None,
+            coverage: None,
+            threshold_sweep: None,
+            quality: None,
+            plugin: None,
+            backend: Backend::Cpu,
+            lenient: false,
+        }
+    }
+}
+
+impl PipelineSpec {
+    /// Parse a JSON spec, or the `spec` object of a render sidecar
+    pub fn from_json_str(text: &str) -> Result<Self> {
+        let mut value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| Error::InvalidOption(format!("Invalid pipeline spec: {}", e)))?;
+        if let Some(spec) = value.get_mut("spec").filter(|spec| spec.is_object()) {
+            value = spec.take();
+        }
+        let spec: PipelineSpec = serde_json::from_value(value)
+            .map_err(|e| Error::InvalidOption(format!("Invalid pipeline spec: {}", e)))?;
+        spec.check_version()
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self> {
+        let spec: PipelineSpec = toml::from_str(text)
+            .map_err(|e| Error::InvalidOption(format!("Invalid pipeline spec: {}", e)))?;
+        spec.check_version()
+    }
+
+    /// Load a spec file; `.toml` files are parsed as TOML, anything else as JSON
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        if is_toml(path) {
+            Self::from_toml_str(&text)
+        } else {
+            Self::from_json_str(&text)
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::Render(format!("Failed to serialize pipeline spec: {}", e)))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|e| Error::Render(format!("Failed to serialize pipeline spec: {}", e)))
+    }
+
+    /// Write the spec as TOML or JSON depending on the file extension
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = if is_toml(path) { self.to_toml()? } else { self.to_json()? };
+        fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+
+    fn check_version(self) -> Result<Self> {
+        if self.version > SPEC_VERSION {
+            return Err(Error::InvalidOption(format!(
+                "Pipeline spec version {} is newer than supported version {}", self.version, SPEC_VERSION)));
+        }
+        Ok(self)
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
+}
+
+/// JSON document written next to a rendering, describing how it was produced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenderSidecar {
+    pub spec: PipelineSpec,
+    pub width: u32,
+    pub height: u32,
+    pub heatmap: Option<HeatmapSummary>,
+    /// Overlay layers drawn over the primary heatmap
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlays: Vec<HeatmapSummary>,
+    /// Decision at the spec's operating points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<Decision>,
+    /// Scores against the spec's ground-truth mask
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evaluation: Option<MaskEvaluation>,
+    /// Hotspots detected with the spec's options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hotspots: Option<HotspotReport>,
+    /// Histogram taken with the spec's options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Histogram>,
+    /// Heatmap statistics per region of the spec's region masks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regions: Option<RegionReport>,
+    /// Pointing-game outcome against the spec's lesion annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointing: Option<PointingResult>,
+    /// Share of the high activation outside the spec's anatomy mask
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Coverage>,
+    /// Baseline heatmap of the spec, as loaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<HeatmapSummary>,
+    /// Similarity of the heatmap to the baseline heatmap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<HeatmapComparison>,
+    /// Threshold sweep over the spec's levels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold_sweep: Option<ThresholdSweep>,
+    /// Quality control of the spec's source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityReport>,
+    /// SHA-256 of the DICOM and heatmap inputs of the run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<HashedInput>,
+    /// Image files written by the same run
+    pub outputs: Vec<PathBuf>,
+    pub warnings: Vec<String>,
+}
+
+impl RenderSidecar {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Render(format!("Failed to serialize sidecar: {}", e)))?;
+        fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
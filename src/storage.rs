@@ -0,0 +1,189 @@
+//! Object storage references, `s3://bucket/key`, `gs://bucket/key` and Azure's
+//! `az://container/blob`, read and written with credentials from each provider's environment
+//! and instance metadata chain.
+
+use log::info;
+use object_store::path::Path;
+use object_store::{ObjectStore, ObjectStoreExt, WriteMultipart};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, Result};
+
+#[cfg(not(any(feature = "s3", feature = "gcs", feature = "azure")))]
+compile_error!("the storage feature is enabled through s3, gcs or azure");
+
+/// Reference schemes of the providers this build supports
+pub const SCHEMES: &[&str] = &[
+    #[cfg(feature = "s3")]
+    "s3",
+    #[cfg(feature = "gcs")]
+    "gs",
+    #[cfg(feature = "azure")]
+    "az",
+];
+
+/// Host suffix of Azure Blob Storage URLs, `https://<account>.blob.core.windows.net/<container>/<blob>`
+#[cfg(feature = "azure")]
+const AZURE_BLOB_HOST: &str = ".blob.core.windows.net";
+
+/// Objects larger than this are uploaded in parts of this size
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+fn storage_error(scheme: &str, message: impl std::fmt::Display) -> Error {
+    Error::Service { service: scheme.to_string(), message: message.to_string() }
+}
+
+/// Parts of an object reference
+#[derive(Debug, Clone, Copy)]
+struct Location<'a> {
+    scheme: &'a str,
+    /// Azure storage account named by the host of a blob URL
+    account: Option<&'a str>,
+    bucket: &'a str,
+    key: &'a str,
+    /// Shared access signature in the query of a blob URL
+    sas: Option<&'a str>,
+}
+
+/// Location of an object reference; None for other references
+fn parse(reference: &str) -> Option<Location<'_>> {
+    let (scheme, rest) = reference.split_once("://")?;
+    #[cfg(feature = "azure")]
+    if scheme == "https" {
+        let (rest, sas) = match rest.split_once('?') {
+            Some((rest, sas)) => (rest, Some(sas)),
+            None => (rest, None),
+        };
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let account = host.strip_suffix(AZURE_BLOB_HOST)?;
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        return Some(Location { scheme: "az", account: Some(account), bucket, key, sas });
+    }
+    if !SCHEMES.contains(&scheme) {
+        return None;
+    }
+    let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+    Some(Location { scheme, account: None, bucket, key, sas: None })
+}
+
+/// Whether `reference` names an object in a supported store
+pub fn is_object(reference: &str) -> bool {
+    parse(reference).is_some()
+}
+
+/// Scheme, account (empty but for blob URLs) and bucket a client is built for
+type StoreKey = (String, String, String);
+
+/// Store clients by scheme, account and bucket, built on first use and shared afterwards
+#[derive(Debug, Default)]
+pub struct ObjectStores {
+    stores: Mutex<HashMap<StoreKey, Arc<dyn ObjectStore>>>,
+}
+
+impl ObjectStores {
+    /// Client for the bucket of `location`, configured from the provider's variables (e.g.
+    /// `AWS_REGION`, `AWS_ENDPOINT`, `GOOGLE_APPLICATION_CREDENTIALS`, `AZURE_STORAGE_ACCOUNT_NAME`)
+    /// with instance metadata as the fallback
+    ///
+    /// Clients for references carrying their own SAS token aren't kept, as each request may
+    /// bring a new token.
+    fn store(&self, location: &Location) -> Result<Arc<dyn ObjectStore>> {
+        let Location { scheme, bucket, .. } = *location;
+        let key = (scheme.to_string(), location.account.unwrap_or_default().to_string(), bucket.to_string());
+        let mut stores = self.stores.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if location.sas.is_none()
+            && let Some(store) = stores.get(&key)
+        {
+            return Ok(store.clone());
+        }
+        let store: Arc<dyn ObjectStore> = match scheme {
+            #[cfg(feature = "s3")]
+            "s3" => Arc::new(
+                object_store::aws::AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()
+                    .map_err(|e| storage_error(scheme, e))?,
+            ),
+            #[cfg(feature = "gcs")]
+            "gs" => Arc::new(
+                object_store::gcp::GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()
+                    .map_err(|e| storage_error(scheme, e))?,
+            ),
+            #[cfg(feature = "azure")]
+            "az" => {
+                use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+                let mut builder = MicrosoftAzureBuilder::from_env().with_container_name(bucket);
+                if let Some(account) = location.account {
+                    builder = builder.with_account(account);
+                }
+                // A token in the URL wins over the credentials in the environment
+                if let Some(sas) = location.sas {
+                    builder = builder
+                        .with_config(AzureConfigKey::SasKey, sas)
+                        .with_config(AzureConfigKey::CredentialType, "sas_token");
+                }
+                Arc::new(builder.build().map_err(|e| storage_error(scheme, e))?)
+            }
+            _ => return Err(Error::InvalidOption(format!("Unknown storage scheme: {}. Available: {}", scheme, SCHEMES.join(", ")))),
+        };
+        if location.sas.is_none() {
+            info!("Created a client for {}://{}", scheme, bucket);
+            stores.insert(key, store.clone());
+        }
+        Ok(store)
+    }
+
+    fn locate<'a>(&self, reference: &'a str) -> Result<(&'a str, Arc<dyn ObjectStore>, Path)> {
+        let Some(location) = parse(reference) else {
+            return Err(Error::InvalidOption(format!("Not an object reference: {}", reference)));
+        };
+        if location.bucket.is_empty() || location.key.is_empty() {
+            let expected = match location.account {
+                Some(_) => "https://<account>.blob.core.windows.net/container/blob".to_string(),
+                None => format!("{}://bucket/key", location.scheme),
+            };
+            return Err(Error::InvalidOption(format!("Invalid object reference: {} (expected {})", redact(reference), expected)));
+        }
+        // Blob URLs are percent-encoded, the other references name keys as they are
+        let path = match location.account {
+            Some(_) => Path::from_url_path(location.key),
+            None => Path::parse(location.key),
+        };
+        let path = path.map_err(|e| Error::InvalidOption(format!("Invalid object reference: {} ({})", redact(reference), e)))?;
+        Ok((location.scheme, self.store(&location)?, path))
+    }
+
+    /// Bytes of the object, refusing objects larger than `limit`
+    pub async fn read(&self, reference: &str, limit: usize) -> Result<Vec<u8>> {
+        let (scheme, store, path) = self.locate(reference)?;
+        let object = store.get(&path).await.map_err(|e| storage_error(scheme, e))?;
+        if object.meta.size > limit as u64 {
+            return Err(Error::TooLarge { what: format!("Input {}", redact(reference)), limit });
+        }
+        let bytes = object.bytes().await.map_err(|e| storage_error(scheme, e))?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Store `bytes` as the object, in a multipart upload above [`PART_SIZE`]
+    pub async fn write(&self, reference: &str, bytes: Vec<u8>) -> Result<()> {
+        let (scheme, store, path) = self.locate(reference)?;
+        if bytes.len() <= PART_SIZE {
+            store.put(&path, bytes.into()).await.map_err(|e| storage_error(scheme, e))?;
+            return Ok(());
+        }
+        let upload = store.put_multipart(&path).await.map_err(|e| storage_error(scheme, e))?;
+        let mut parts = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+        parts.put(bytes.into());
+        // Parts already uploaded are aborted when one of them fails
+        parts.finish().await.map_err(|e| storage_error(scheme, e))?;
+        Ok(())
+    }
+}
+
+/// `reference` without the query of a blob URL, which may hold a SAS token
+pub fn redact(reference: &str) -> &str {
+    reference.split_once('?').map(|(reference, _)| reference).unwrap_or(reference)
+}
@@ -0,0 +1,158 @@
+//! Threshold sweep: the normalized heatmap thresholded at a range of levels, each reported with
+//! its area above the level, its connected regions and, against a ground-truth mask, its Dice
+//! score, for choosing a site's operating threshold.
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::evaluation::evaluate_mask;
+use crate::hotspots::{find_hotspots, HotspotOptions};
+
+/// Most levels a sweep may have, each of which floods the whole heatmap
+pub const MAX_SWEEP_LEVELS: usize = 1000;
+
+/// Levels a threshold sweep runs over, from `start` to `end` in steps of `step`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SweepOptions {
+    pub start: f32,
+    pub end: f32,
+    pub step: f32,
+    /// Regions of fewer pixels aren't counted
+    pub min_area: u64,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        SweepOptions { start: 0.1, end: 0.9, step: 0.1, min_area: 1 }
+    }
+}
+
+impl FromStr for SweepOptions {
+    type Err = String;
+
+    /// `START:END:STEP`, e.g. `0.1:0.9:0.1`
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("Invalid threshold sweep: {}. Expected START:END:STEP, e.g. 0.1:0.9:0.1", s);
+        let parts: Vec<f32> = s.split(':')
+            .map(|part| part.trim().parse().map_err(|_| invalid()))
+            .collect::<std::result::Result<_, _>>()?;
+        let [start, end, step] = parts[..] else {
+            return Err(invalid());
+        };
+        Ok(SweepOptions { start, end, step, ..SweepOptions::default() })
+    }
+}
+
+impl SweepOptions {
+    /// Normalized levels of the sweep, the end included when a step lands on it
+    pub fn levels(&self) -> Vec<f32> {
+        // Steps like 0.1 aren't exact in binary, so levels are rounded to the decimals they are
+        // named with
+        let steps = ((self.end - self.start) / self.step + 1e-4).floor() as usize;
+        (0..=steps)
+            .map(|index| {
+                let level = f64::from(self.start) + index as f64 * f64::from(self.step);
+                ((level * 1e6).round() / 1e6) as f32
+            })
+            .collect()
+    }
+
+    /// Why the options don't describe a sweep within 0.0-1.0 of at most `MAX_SWEEP_LEVELS`
+    /// levels, if they don't
+    pub fn check(&self) -> std::result::Result<(), String> {
+        if !(0.0..=1.0).contains(&self.start) || !(0.0..=1.0).contains(&self.end) || self.start > self.end {
+            return Err("Threshold sweep must run from a start to an end level within 0.0-1.0".to_string());
+        }
+        if self.step.is_nan() || self.step <= 0.0 {
+            return Err("Threshold sweep step must be greater than 0".to_string());
+        }
+        if (self.end - self.start) / self.step >= MAX_SWEEP_LEVELS as f32 {
+            return Err(format!("Threshold sweep must have at most {} levels", MAX_SWEEP_LEVELS));
+        }
+        Ok(())
+    }
+}
+
+/// Heatmap thresholded at one level of the sweep
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepLevel {
+    /// Normalized level at and above which pixels are positive
+    pub threshold: f32,
+    pub area_pixels: u64,
+    /// Share of the heatmap's pixels at or above the level
+    pub area_fraction: f64,
+    /// Area from the image's pixel spacing, None without one
+    pub area_mm2: Option<f64>,
+    /// Connected regions (8-connected) of at least the minimum area
+    pub regions: usize,
+    /// Scores against the ground-truth mask, None without one or when their denominator is zero
+    pub dice: Option<f64>,
+    pub sensitivity: Option<f64>,
+    pub specificity: Option<f64>,
+}
+
+/// Levels of a threshold sweep, lowest first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdSweep {
+    pub levels: Vec<SweepLevel>,
+    /// Level with the highest Dice score (the lowest of a tie), None without a ground-truth mask
+    pub best_dice_threshold: Option<f32>,
+}
+
+impl ThresholdSweep {
+    /// Write the sweep as CSV (one row per level, empty cells for missing values) for `.csv`
+    /// paths, as JSON otherwise
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let csv = path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        let text = if csv {
+            let cell = |value: Option<f64>| value.map_or(String::new(), |value| value.to_string());
+            let rows = self.levels.iter().map(|level| {
+                format!("{},{},{},{},{},{},{},{}\n", level.threshold, level.area_pixels, level.area_fraction,
+                        cell(level.area_mm2), level.regions, cell(level.dice), cell(level.sensitivity), cell(level.specificity))
+            });
+            std::iter::once("threshold,area_pixels,area_fraction,area_mm2,regions,dice,sensitivity,specificity\n".to_string())
+                .chain(rows)
+                .collect()
+        } else {
+            serde_json::to_string_pretty(self)
+                .map_err(|e| Error::Render(format!("Failed to serialize threshold sweep: {}", e)))?
+        };
+        std::fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Threshold `normalized` at every level of `options`, scoring each against `mask` if given;
+/// areas in mm² use `pixel_spacing`, the row and column spacing in mm
+pub fn sweep_thresholds(normalized: &Array2<f32>, options: &SweepOptions, mask: Option<&Array2<bool>>, pixel_spacing: Option<(f64, f64)>) -> ThresholdSweep {
+    let regions = HotspotOptions { threshold: None, min_area: options.min_area, max_hotspots: None };
+    let total = normalized.len().max(1) as f64;
+    let levels: Vec<SweepLevel> = options.levels().into_iter()
+        .map(|threshold| {
+            let area_pixels = normalized.iter().filter(|&&value| value >= threshold).count() as u64;
+            let evaluation = mask.map(|mask| evaluate_mask(normalized, mask, threshold));
+            SweepLevel {
+                threshold,
+                area_pixels,
+                area_fraction: area_pixels as f64 / total,
+                area_mm2: pixel_spacing.map(|(row, column)| area_pixels as f64 * row * column),
+                regions: find_hotspots(normalized, threshold, &regions, None).len(),
+                dice: evaluation.as_ref().and_then(|evaluation| evaluation.dice),
+                sensitivity: evaluation.as_ref().and_then(|evaluation| evaluation.sensitivity),
+                specificity: evaluation.as_ref().and_then(|evaluation| evaluation.specificity),
+            }
+        })
+        .collect();
+
+    let best_dice_threshold = levels.iter()
+        .filter_map(|level| level.dice.map(|dice| (level.threshold, dice)))
+        .fold(None, |best: Option<(f32, f64)>, (threshold, dice)| match best {
+            Some((_, highest)) if highest >= dice => best,
+            _ => Some((threshold, dice)),
+        })
+        .map(|(threshold, _)| threshold);
+    ThresholdSweep { levels, best_dice_threshold }
+}
@@ -0,0 +1,124 @@
+//! OpenTelemetry tracing: spans for server requests, pipeline stages and DL service calls,
+//! exported via OTLP and linked to callers and model servers through `traceparent`.
+
+use axum::http::{HeaderMap, HeaderValue};
+use log::info;
+use opentelemetry::context::FutureExt;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::borrow::Cow;
+use std::env;
+use std::future::Future;
+
+use crate::error::{Error, Result};
+
+/// Instrumentation scope of every span this crate creates
+const TRACER_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Request ID of the server request a span belongs to, forwarded to DL services
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub String);
+
+/// Flushes and shuts down the exporter when dropped
+#[derive(Debug)]
+pub struct TelemetryGuard(Option<SdkTracerProvider>);
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take()
+            && let Err(e) = provider.shutdown()
+        {
+            log::warn!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Install the `traceparent` propagator and, when `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set, an OTLP/HTTP exporter for all spans
+///
+/// Without an endpoint spans are not recorded, but incoming trace context is still passed on
+/// to the DL services. The service name defaults to the crate name unless `OTEL_SERVICE_NAME`
+/// is set.
+pub fn init() -> Result<TelemetryGuard> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|variable| env::var_os(variable).is_some());
+    if !configured {
+        return Ok(TelemetryGuard(None));
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| Error::Server(format!("Failed to create OTLP exporter: {}", e)))?;
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(TRACER_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    info!("Exporting traces via OTLP");
+    Ok(TelemetryGuard(Some(provider)))
+}
+
+pub(crate) fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// Trace context sent by the caller in `traceparent`, or an empty context
+pub(crate) fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Add `traceparent` for `cx`, plus the request ID it carries, to outgoing headers
+pub(crate) fn inject(cx: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut HeaderInjector(headers)));
+    if let Some(RequestId(id)) = cx.get::<RequestId>()
+        && let Ok(value) = HeaderValue::from_str(id)
+    {
+        headers.insert(crate::server::REQUEST_ID_HEADER, value);
+    }
+}
+
+/// Start a span as a child of `parent`, returning the context that contains it
+pub(crate) fn start(parent: &Context, name: impl Into<Cow<'static, str>>, kind: SpanKind, attributes: Vec<KeyValue>) -> Context {
+    let span = tracer().span_builder(name).with_kind(kind).with_attributes(attributes).start_with_context(&tracer(), parent);
+    parent.with_span(span)
+}
+
+/// End the span in `cx`, marking it as failed when there is an error
+pub(crate) fn end(cx: &Context, error: Option<&Error>) {
+    let span = cx.span();
+    if let Some(e) = error {
+        span.set_attribute(KeyValue::new("error.type", e.kind()));
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+}
+
+/// Run synchronous work inside a child span of the current context
+pub(crate) fn in_span<T>(name: &'static str, work: impl FnOnce() -> Result<T>) -> Result<T> {
+    let cx = start(&Context::current(), name, SpanKind::Internal, Vec::new());
+    let result = {
+        let _attached = cx.clone().attach();
+        work()
+    };
+    end(&cx, result.as_ref().err());
+    result
+}
+
+/// Await `future` inside the span in `cx` and end the span with its outcome
+pub(crate) async fn traced<T>(cx: Context, future: impl Future<Output = Result<T>>) -> Result<T> {
+    let result = future.with_context(cx.clone()).await;
+    end(&cx, result.as_ref().err());
+    result
+}
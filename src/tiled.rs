@@ -0,0 +1,144 @@
+//! Tiled rendering of very large images, such as 30k×30k pathology captures, in bounded memory.
+//!
+//! A first pass takes the normalization statistics from the source heatmap, counting every value
+//! as often as resizing it to the image would repeat it. The image is then rendered a row of
+//! tiles at a time: each tile row is resized, colorized and blended from the grayscale base and
+//! streamed to the PNG encoder before the next one starts, so no image-sized float array or RGBA
+//! buffer is ever held. The pixels match a whole-image rendering with the same options, up to
+//! rounding of z-score statistics.
+
+use image::GrayImage;
+use log::info;
+use ndarray::Array2;
+use std::io::Write;
+use std::path::Path;
+
+use crate::colormap::{get_color_from_value, ColorMap};
+use crate::error::{Error, Result};
+use crate::heatmap::resize_indices;
+use crate::normalize::{resized_normalizer, Normalization, Normalizer};
+use crate::output::PngOptions;
+use crate::parallel::for_each_row;
+use crate::render::{blend_pixel, BlendOptions};
+
+/// Tile side used when none is given
+pub const DEFAULT_TILE_SIZE: u32 = 512;
+
+/// How a tiled rendering colorizes and blends the heatmap
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileOptions {
+    /// Side of a tile in pixels; one row of tiles is held in memory at a time
+    pub tile_size: u32,
+    pub colormap: ColorMap,
+    pub normalization: Normalization,
+    pub blend: BlendOptions,
+    pub png: PngOptions,
+}
+
+impl Default for TileOptions {
+    fn default() -> Self {
+        TileOptions {
+            tile_size: DEFAULT_TILE_SIZE,
+            colormap: ColorMap::Red,
+            normalization: Normalization::MinMax,
+            blend: BlendOptions::default(),
+            png: PngOptions::default(),
+        }
+    }
+}
+
+/// Outcome of a tiled rendering
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiledRender {
+    pub width: u32,
+    pub height: u32,
+    /// Tiles the image was rendered in
+    pub tiles: u32,
+    /// Rows of tiles, each encoded before the next was rendered
+    pub tile_rows: u32,
+}
+
+/// Source heatmap with the source row of every image row and the source column of every image
+/// column
+struct Layer<'a> {
+    data: &'a Array2<f32>,
+    rows: Vec<usize>,
+    cols: Vec<usize>,
+    /// None when the values are all the same, which are then drawn unchanged
+    normalizer: Option<Normalizer>,
+}
+
+impl Layer<'_> {
+    fn value(&self, y: usize, x: usize) -> f32 {
+        let value = self.data[[self.rows[y], self.cols[x]]];
+        self.normalizer.map_or(value, |normalizer| normalizer.apply(value))
+    }
+}
+
+/// Render `heatmap` over `base` (or the default gradient without one) in tiles and write the
+/// fused image to `out` as PNG
+pub fn render_tiled(base: &GrayImage, heatmap: Option<&Array2<f32>>, options: &TileOptions, out: impl Write) -> Result<TiledRender> {
+    let (width, height) = base.dimensions();
+    let tile_size = options.tile_size;
+    if tile_size == 0 {
+        return Err(Error::InvalidOption("Tile size must be greater than 0".to_string()));
+    }
+    if heatmap.is_some_and(|data| data.is_empty()) {
+        return Err(Error::Render("Heatmap has no values".to_string()));
+    }
+    let layer = heatmap.map(|data| layer(data, width as usize, height as usize, &options.normalization));
+
+    let encoder = options.png.encoder(out, width, height);
+    let encode_error = |e: png::EncodingError| Error::Render(format!("failed to encode PNG: {}", e));
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    let mut stream = writer.stream_writer().map_err(encode_error)?;
+
+    let (blend, colormap) = (&options.blend, &options.colormap);
+    let alpha = (blend.opacity * 255.0) as u8;
+    let row_bytes = width as usize * 4;
+    let mut tile_row = vec![0u8; row_bytes * tile_size.min(height) as usize];
+    let mut tile_rows = 0;
+    for top in (0..height).step_by(tile_size as usize) {
+        let pixels = &mut tile_row[..row_bytes * tile_size.min(height - top) as usize];
+        for_each_row(pixels, row_bytes, |offset, pixels| {
+            let y = top as usize + offset;
+            let gray = &base.as_raw()[y * width as usize..(y + 1) * width as usize];
+            for (x, (pixel, &luma)) in pixels.chunks_exact_mut(4).zip(gray).enumerate() {
+                pixel.copy_from_slice(&[luma, luma, luma, 255]);
+                let (value, hidden) = match &layer {
+                    Some(layer) => {
+                        let value = layer.value(y, x);
+                        (value, blend.threshold.is_some_and(|threshold| value < threshold))
+                    }
+                    // The default gradient of `generate_default_heatmap`, which ignores the threshold
+                    None => (((x as f32 / width as f32) + (y as f32 / height as f32)) / 2.0, false),
+                };
+                let color = get_color_from_value(value, colormap);
+                blend_pixel(pixel, [color.0, color.1, color.2, if hidden { 0 } else { alpha }], blend.mode);
+            }
+        });
+        stream.write_all(pixels).map_err(|e| Error::Render(format!("failed to encode PNG: {}", e)))?;
+        tile_rows += 1;
+    }
+    stream.finish().map_err(encode_error)?;
+    writer.finish().map_err(encode_error)?;
+
+    let tiles = tile_rows * width.div_ceil(tile_size);
+    info!("Rendered {}x{} in {} tile(s) of {} pixels", width, height, tiles, tile_size);
+    Ok(TiledRender { width, height, tiles, tile_rows })
+}
+
+/// [`render_tiled`] into a PNG file at `png_path`
+pub fn save_tiled(base: &GrayImage, heatmap: Option<&Array2<f32>>, options: &TileOptions, png_path: &Path) -> Result<TiledRender> {
+    let file = std::fs::File::create(png_path).map_err(|e| Error::io(png_path, e))?;
+    let mut out = std::io::BufWriter::new(file);
+    let render = render_tiled(base, heatmap, options, &mut out)?;
+    out.flush().map_err(|e| Error::io(png_path, e))?;
+    Ok(render)
+}
+
+fn layer<'a>(data: &'a Array2<f32>, width: usize, height: usize, method: &Normalization) -> Layer<'a> {
+    let (rows, cols) = resize_indices(data.dim(), width, height);
+    let normalizer = resized_normalizer(data, &rows, &cols, method);
+    Layer { data, rows, cols, normalizer }
+}
@@ -0,0 +1,119 @@
+//! Test-time augmentation: the image is also classified flipped and slightly rotated, and the
+//! heatmaps of the augmented copies are mapped back onto the original and averaged.
+
+use image::GrayImage;
+use ndarray::{s, Array2};
+use std::str::FromStr;
+
+use crate::heatmap::resize_heatmap;
+
+/// Transformation applied to the image before inference and undone on its heatmap
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Augmentation {
+    /// Mirrored left to right
+    HorizontalFlip,
+    /// Rotated counterclockwise about the center by this many degrees, with black corners
+    Rotate(f32),
+}
+
+impl FromStr for Augmentation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if let Some(degrees) = s.strip_prefix("rotate=") {
+            return match degrees.trim().parse::<f32>() {
+                Ok(degrees) if degrees.is_finite() && degrees.abs() <= 45.0 => Ok(Augmentation::Rotate(degrees)),
+                _ => Err(format!("Rotation must be a number of degrees between -45 and 45, found '{}'", degrees)),
+            };
+        }
+        match s.as_str() {
+            "hflip" | "flip" => Ok(Augmentation::HorizontalFlip),
+            _ => Err(format!("Unknown augmentation: {}. Available: hflip, rotate=<degrees>", s)),
+        }
+    }
+}
+
+impl Augmentation {
+    pub fn name(&self) -> String {
+        match self {
+            Augmentation::HorizontalFlip => "hflip".to_string(),
+            Augmentation::Rotate(degrees) => format!("rotate={}", degrees),
+        }
+    }
+
+    /// Augmented copy of `image`, of the same size
+    pub fn apply(&self, image: &GrayImage) -> GrayImage {
+        match *self {
+            Augmentation::HorizontalFlip => image::imageops::flip_horizontal(image),
+            Augmentation::Rotate(degrees) => {
+                let (width, height) = image.dimensions();
+                let values = Array2::from_shape_fn((height as usize, width as usize), |(y, x)| image.get_pixel(x as u32, y as u32)[0] as f32);
+                let rotated = rotate(&values, degrees);
+                GrayImage::from_fn(width, height, |x, y| image::Luma([rotated[[y as usize, x as usize]].round().clamp(0.0, 255.0) as u8]))
+            }
+        }
+    }
+
+    /// Heatmap of the augmented image mapped back onto the original, with the share of each
+    /// pixel the augmented image covered; `map` must have the image's aspect ratio
+    fn invert(&self, map: &Array2<f32>) -> (Array2<f32>, Array2<f32>) {
+        match *self {
+            Augmentation::HorizontalFlip => (map.slice(s![.., ..;-1]).to_owned(), Array2::ones(map.dim())),
+            Augmentation::Rotate(degrees) => (rotate(map, -degrees), rotate(&Array2::ones(map.dim()), -degrees)),
+        }
+    }
+}
+
+/// Average the heatmaps of the augmented copies of a `width`×`height` image, in the order of
+/// `augmentations` where None is the unaugmented image
+///
+/// The maps are brought to the image's aspect ratio at the size of the largest one, so rotations
+/// are undone without distortion. Pixels rotated out of view are averaged over the copies that
+/// kept them.
+pub fn average_heatmaps(maps: &[Array2<f32>], augmentations: &[Option<Augmentation>], width: u32, height: u32) -> Array2<f32> {
+    let side = maps.iter().map(|map| map.nrows().max(map.ncols())).max().unwrap_or(1).max(1) as f32;
+    let scale = side / width.max(height).max(1) as f32;
+    let (rows, cols) = (((height as f32 * scale).round() as usize).max(1), ((width as f32 * scale).round() as usize).max(1));
+
+    let mut sum = Array2::<f32>::zeros((rows, cols));
+    let mut coverage = Array2::<f32>::zeros((rows, cols));
+    for (map, augmentation) in maps.iter().zip(augmentations) {
+        let map = match map.dim() == (rows, cols) {
+            true => map.clone(),
+            false => resize_heatmap(map, cols, rows),
+        };
+        let (map, covered) = match augmentation {
+            Some(augmentation) => augmentation.invert(&map),
+            None => (map, Array2::ones((rows, cols))),
+        };
+        sum += &map;
+        coverage += &covered;
+    }
+    ndarray::Zip::from(&mut sum).and(&coverage).for_each(|value, &covered| {
+        *value = if covered > f32::EPSILON { *value / covered } else { 0.0 };
+    });
+    sum
+}
+
+/// `values` rotated counterclockwise about the center with bilinear sampling, zero outside
+fn rotate(values: &Array2<f32>, degrees: f32) -> Array2<f32> {
+    let (rows, cols) = values.dim();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (cx, cy) = ((cols as f32 - 1.0) / 2.0, (rows as f32 - 1.0) / 2.0);
+    let at = |x: isize, y: isize| match x >= 0 && y >= 0 && (x as usize) < cols && (y as usize) < rows {
+        true => values[[y as usize, x as usize]],
+        false => 0.0,
+    };
+    // Each output pixel samples the source point that the rotation moves onto it; y points down
+    Array2::from_shape_fn((rows, cols), |(y, x)| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let (sx, sy) = (cos * dx - sin * dy + cx, sin * dx + cos * dy + cy);
+        let (x0, y0) = (sx.floor(), sy.floor());
+        let (fx, fy) = (sx - x0, sy - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = at(x0, y0) * (1.0 - fx) + at(x0 + 1, y0) * fx;
+        let bottom = at(x0, y0 + 1) * (1.0 - fx) + at(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    })
+}
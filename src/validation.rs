@@ -0,0 +1,398 @@
+//! Model validation over a manifest of labeled cases: each case's class score, given in the
+//! manifest or read from its heatmap file, is compared with its ground-truth label across the
+//! batch, giving the ROC curve, its AUC, and the metrics at the configured operating point and at
+//! the Youden-optimal threshold, and the calibration of the scores. Cases with lesion annotations
+//! are also scored with the pointing game.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::calibration::{calibrate, Calibration, DEFAULT_CALIBRATION_BINS};
+use crate::decision::OperatingPoints;
+#[cfg(feature = "dicom")]
+use crate::dicom_io::{image_dimensions, open_dicom_header};
+use crate::error::{Error, Result};
+use crate::heatmap::{HeatmapMetadata, HeatmapRegistry};
+use crate::localization::{load_lesions, pointing_game, summarize_pointing, PointingResult, PointingSummary, DEFAULT_POINTING_TOLERANCE};
+
+/// Row of a validation manifest, as written; see [`load_manifest`]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ManifestRow {
+    /// Line in the manifest, from 1 for the header
+    pub line: u64,
+    pub image: Option<PathBuf>,
+    pub heatmap: Option<PathBuf>,
+    pub score: Option<String>,
+    pub label: Option<String>,
+    /// Lesion annotations for the pointing game, see [`crate::localization::load_lesions`]
+    pub annotations: Option<PathBuf>,
+}
+
+/// Score and label of one case
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaseScore {
+    pub line: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heatmap: Option<PathBuf>,
+    pub score: f32,
+    /// Ground truth: whether the case is positive
+    pub positive: bool,
+    /// Pointing-game outcome of the heatmap, for cases with lesion annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointing: Option<PointingResult>,
+}
+
+#[cfg(test)]
+impl CaseScore {
+    /// Cases of the given scores and labels, on manifest lines from 2 without images or heatmaps
+    pub(crate) fn scored(scores: &[(f32, bool)]) -> Vec<CaseScore> {
+        scores.iter()
+            .enumerate()
+            .map(|(index, &(score, positive))| CaseScore { line: index as u64 + 2, image: None, heatmap: None, score, positive, pointing: None })
+            .collect()
+    }
+}
+
+/// Manifest row left out of the metrics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkippedCase {
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Point of the ROC curve: the rates when scores at or above `threshold` are called positive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RocPoint {
+    pub threshold: f32,
+    pub true_positive_rate: f64,
+    pub false_positive_rate: f64,
+}
+
+/// Confusion counts and metrics when scores at or above `threshold` are called positive; a
+/// metric is None when its denominator is zero
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThresholdMetrics {
+    pub threshold: f32,
+    pub sensitivity: Option<f64>,
+    pub specificity: Option<f64>,
+    /// Positive predictive value (precision)
+    pub ppv: Option<f64>,
+    /// Negative predictive value
+    pub npv: Option<f64>,
+    pub accuracy: Option<f64>,
+    pub f1: Option<f64>,
+    pub true_positives: u64,
+    pub false_positives: u64,
+    pub false_negatives: u64,
+    pub true_negatives: u64,
+}
+
+/// Metrics report of a validation run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Class whose scores were evaluated; None for the heatmaps' own `score`
+    pub class: Option<String>,
+    pub positives: u64,
+    pub negatives: u64,
+    /// Area under the ROC curve; None unless there are both positive and negative cases
+    pub auc: Option<f64>,
+    /// One point per distinct score, from the highest threshold down; the curve starts at (0, 0)
+    pub roc: Vec<RocPoint>,
+    /// Metrics at the operating point's threshold for the class, if one is configured
+    pub operating_point: Option<ThresholdMetrics>,
+    /// Metrics at the threshold with the highest sensitivity + specificity - 1
+    pub youden: Option<ThresholdMetrics>,
+    /// Reliability diagram and calibration errors of the scores
+    pub calibration: Option<Calibration>,
+    /// Pointing-game hit rate, when any case has lesion annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointing: Option<PointingSummary>,
+    pub cases: Vec<CaseScore>,
+    pub skipped: Vec<SkippedCase>,
+}
+
+impl ValidationReport {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Render(format!("Failed to serialize metrics report: {}", e)))?;
+        std::fs::write(path, text).map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Read a CSV manifest with a header naming its `image`, `heatmap`, `score`, `label` and
+/// `annotations` columns, in any order; `label` and one of `score` or `heatmap` are required.
+/// Relative paths are resolved against the manifest's directory.
+pub fn load_manifest(path: &Path) -> Result<Vec<ManifestRow>> {
+    let invalid = |e: &dyn std::fmt::Display| Error::InvalidOption(format!("Invalid manifest {}: {}", path.display(), e));
+    let mut reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| invalid(&e))?;
+    let headers = reader.headers().map_err(|e| invalid(&e))?.clone();
+    let column = |name: &str| headers.iter().position(|header| header.eq_ignore_ascii_case(name));
+    let (image, heatmap, score, label) = (column("image"), column("heatmap"), column("score"), column("label"));
+    let annotations = column("annotations");
+    if label.is_none() || (score.is_none() && heatmap.is_none()) {
+        return Err(invalid(&"the header needs a label column and a score or heatmap column"));
+    }
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| invalid(&e))?;
+        let field = |index: Option<usize>| index.and_then(|index| record.get(index)).filter(|value| !value.is_empty());
+        rows.push(ManifestRow {
+            line: record.position().map_or(0, |position| position.line()),
+            image: field(image).map(|value| directory.join(value)),
+            heatmap: field(heatmap).map(|value| directory.join(value)),
+            score: field(score).map(str::to_string),
+            label: field(label).map(str::to_string),
+            annotations: field(annotations).map(|value| directory.join(value)),
+        });
+    }
+    Ok(rows)
+}
+
+/// Score and label of every row, skipping those without a valid label or score; a row without
+/// a score takes the `class` score (or the `score`) of its heatmap file. Rows with lesion
+/// annotations are also scored with the pointing game at `tolerance`, and skipped when that fails.
+pub fn score_cases(rows: &[ManifestRow], registry: &HeatmapRegistry, class: Option<&str>, tolerance: f64) -> (Vec<CaseScore>, Vec<SkippedCase>) {
+    let mut cases = Vec::new();
+    let mut skipped = Vec::new();
+    for row in rows {
+        match score_case(row, registry, class, tolerance) {
+            Ok(case) => cases.push(case),
+            Err(reason) => {
+                warn!("Skipping manifest line {}: {}", row.line, reason);
+                skipped.push(SkippedCase { line: row.line, reason });
+            }
+        }
+    }
+    (cases, skipped)
+}
+
+fn score_case(row: &ManifestRow, registry: &HeatmapRegistry, class: Option<&str>, tolerance: f64) -> std::result::Result<CaseScore, String> {
+    let label = row.label.as_deref().ok_or("no label")?;
+    let positive = match label.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "positive" => true,
+        "0" | "false" | "no" | "negative" => false,
+        _ => return Err(format!("invalid label {}: expected 1/0, true/false, yes/no or positive/negative", label)),
+    };
+    // The heatmap is only read when the score or the pointing game needs it
+    let loaded = match &row.heatmap {
+        Some(heatmap) if row.score.is_none() || row.annotations.is_some() => Some(registry.load(heatmap).map_err(|e| e.to_string())?),
+        _ => None,
+    };
+    let score = match (&row.score, loaded.as_ref().zip(row.heatmap.as_ref())) {
+        (Some(score), _) => score.parse().map_err(|_| format!("invalid score {}", score))?,
+        (None, Some((loaded, heatmap))) => {
+            class_score(&loaded.metadata, class)
+                .ok_or_else(|| format!("heatmap {} has no score{}", heatmap.display(), class.map(|class| format!(" for {}", class)).unwrap_or_default()))?
+        }
+        (None, None) => return Err("no score or heatmap".to_string()),
+    };
+    if !f32::is_finite(score) {
+        return Err(format!("score {} is not finite", score));
+    }
+
+    let pointing = match &row.annotations {
+        Some(path) => {
+            let loaded = loaded.as_ref().ok_or("lesion annotations but no heatmap")?;
+            let mut annotations = load_lesions(path).map_err(|e| e.to_string())?;
+            if annotations.width.is_none() || annotations.height.is_none() {
+                let (width, height) = annotated_size(row, path)?;
+                annotations = annotations.or_size(width, height);
+            }
+            Some(pointing_game(&loaded.data, &annotations, tolerance).ok_or("heatmap has no finite value")?)
+        }
+        None => None,
+    };
+    Ok(CaseScore { line: row.line, image: row.image.clone(), heatmap: row.heatmap.clone(), score, positive, pointing })
+}
+
+/// Size of the image whose lesions `annotations` gives without one, from the row's DICOM image
+fn annotated_size(row: &ManifestRow, annotations: &Path) -> std::result::Result<(u32, u32), String> {
+    let image = row.image.as_deref()
+        .ok_or_else(|| format!("lesion annotations {} give no image size and the row has no image", annotations.display()))?;
+    #[cfg(feature = "dicom")]
+    {
+        let obj = open_dicom_header(image).map_err(|e| e.to_string())?;
+        let (rows, columns) = image_dimensions(&obj).map_err(|e| e.to_string())?;
+        Ok((columns, rows))
+    }
+    #[cfg(not(feature = "dicom"))]
+    Err(format!("the size of image {} can't be read without DICOM support", image.display()))
+}
+
+/// The per-class `score:<class>` of a heatmap, or its `score` when it is for `class` or no class
+/// is asked for
+fn class_score(metadata: &HeatmapMetadata, class: Option<&str>) -> Option<f32> {
+    let attributes = &metadata.attributes;
+    let score = match class {
+        Some(class) => attributes.get(&format!("score:{}", class))
+            .or_else(|| attributes.get("score").filter(|_| attributes.get("class").is_none_or(|own| own == class))),
+        None => attributes.get("score"),
+    };
+    score?.parse().ok()
+}
+
+/// Metrics of the `class` scores of `cases`, with those at `threshold` as the operating point and
+/// the calibration in `calibration_bins` bins; the report lists no skipped cases
+pub fn evaluate_cases(cases: Vec<CaseScore>, class: Option<String>, threshold: Option<f32>, calibration_bins: usize) -> ValidationReport {
+    let positives = cases.iter().filter(|case| case.positive).count() as u64;
+    let negatives = cases.len() as u64 - positives;
+
+    let mut sorted: Vec<&CaseScore> = cases.iter().collect();
+    sorted.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut roc = Vec::new();
+    let (mut true_positives, mut false_positives) = (0u64, 0u64);
+    for (index, case) in sorted.iter().enumerate() {
+        true_positives += u64::from(case.positive);
+        false_positives += u64::from(!case.positive);
+        // Tied scores are one threshold, so they make a single diagonal step
+        if sorted.get(index + 1).is_some_and(|next| next.score == case.score) {
+            continue;
+        }
+        roc.push(RocPoint {
+            threshold: case.score,
+            true_positive_rate: rate(true_positives, positives),
+            false_positive_rate: rate(false_positives, negatives),
+        });
+    }
+
+    let auc = (positives > 0 && negatives > 0).then(|| {
+        let mut previous = (0.0, 0.0);
+        roc.iter().fold(0.0, |area, point| {
+            let (fpr, tpr) = (point.false_positive_rate, point.true_positive_rate);
+            let area = area + (fpr - previous.0) * (tpr + previous.1) / 2.0;
+            previous = (fpr, tpr);
+            area
+        })
+    });
+    let youden = (positives > 0 && negatives > 0)
+        .then(|| roc.iter().max_by(|a, b| {
+            (a.true_positive_rate - a.false_positive_rate).total_cmp(&(b.true_positive_rate - b.false_positive_rate))
+        }))
+        .flatten()
+        .map(|point| threshold_metrics(&cases, point.threshold));
+    let operating_point = threshold.filter(|_| !cases.is_empty()).map(|threshold| threshold_metrics(&cases, threshold));
+    let calibration = calibrate(&cases, calibration_bins);
+    let pointing = cases.iter().any(|case| case.pointing.is_some())
+        .then(|| summarize_pointing(cases.iter().filter_map(|case| case.pointing.as_ref())));
+    ValidationReport { class, positives, negatives, auc, roc, operating_point, youden, calibration, pointing, cases, skipped: Vec::new() }
+}
+
+fn rate(count: u64, total: u64) -> f64 {
+    if total > 0 { count as f64 / total as f64 } else { 0.0 }
+}
+
+/// Confusion counts and metrics of `cases` at `threshold`
+pub fn threshold_metrics(cases: &[CaseScore], threshold: f32) -> ThresholdMetrics {
+    let mut counts = [[0u64; 2]; 2];
+    for case in cases {
+        counts[usize::from(case.score >= threshold)][usize::from(case.positive)] += 1;
+    }
+    let [[true_negatives, false_negatives], [false_positives, true_positives]] = counts;
+    let ratio = |numerator: u64, denominator: u64| (denominator > 0).then(|| numerator as f64 / denominator as f64);
+    ThresholdMetrics {
+        threshold,
+        sensitivity: ratio(true_positives, true_positives + false_negatives),
+        specificity: ratio(true_negatives, true_negatives + false_positives),
+        ppv: ratio(true_positives, true_positives + false_positives),
+        npv: ratio(true_negatives, true_negatives + false_negatives),
+        accuracy: ratio(true_positives + true_negatives, cases.len() as u64),
+        f1: ratio(2 * true_positives, 2 * true_positives + false_positives + false_negatives),
+        true_positives,
+        false_positives,
+        false_negatives,
+        true_negatives,
+    }
+}
+
+/// How [`validate`] scores a manifest beyond the ROC curve
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationOptions {
+    /// Operating points whose threshold for the class is also reported
+    pub operating_points: Option<OperatingPoints>,
+    /// Distance in pixels within which a heatmap maximum still hits a lesion
+    pub pointing_tolerance: f64,
+    /// Score bins of the reliability diagram
+    pub calibration_bins: usize,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        ValidationOptions {
+            operating_points: None,
+            pointing_tolerance: DEFAULT_POINTING_TOLERANCE,
+            calibration_bins: DEFAULT_CALIBRATION_BINS,
+        }
+    }
+}
+
+/// Score every case of the manifest at `manifest` and compute the metrics of their `class`
+/// scores across them
+pub fn validate(manifest: &Path, class: Option<&str>, options: &ValidationOptions) -> Result<ValidationReport> {
+    let rows = load_manifest(manifest)?;
+    info!("Validating {} case(s) from manifest: {}", rows.len(), manifest.display());
+    let (cases, skipped) = score_cases(&rows, &HeatmapRegistry::default(), class, options.pointing_tolerance);
+    if cases.is_empty() {
+        return Err(Error::InvalidOption(format!("Manifest {} has no case with a valid label and score", manifest.display())));
+    }
+    let threshold = options.operating_points.as_ref().and_then(|points| points.threshold(class));
+    let report = evaluate_cases(cases, class.map(str::to_string), threshold, options.calibration_bins);
+    if report.auc.is_none() {
+        warn!("No AUC: the manifest has {} positive and {} negative case(s)", report.positives, report.negatives);
+    }
+    Ok(ValidationReport { skipped, ..report })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(scores: &[(f32, bool)]) -> ValidationReport {
+        evaluate_cases(CaseScore::scored(scores), None, None, DEFAULT_CALIBRATION_BINS)
+    }
+
+    #[test]
+    fn perfect_separation_has_an_auc_of_one() {
+        let report = evaluate(&[(0.9, true), (0.8, true), (0.3, false), (0.1, false)]);
+        assert_eq!(report.auc, Some(1.0));
+        let youden = report.youden.expect("youden point");
+        assert_eq!(youden.threshold, 0.8);
+        assert_eq!((youden.sensitivity, youden.specificity), (Some(1.0), Some(1.0)));
+    }
+
+    #[test]
+    fn inverted_scores_have_an_auc_of_zero() {
+        let report = evaluate(&[(0.1, true), (0.2, true), (0.8, false), (0.9, false)]);
+        assert_eq!(report.auc, Some(0.0));
+    }
+
+    #[test]
+    fn tied_scores_make_one_diagonal_step() {
+        let report = evaluate(&[(0.5, true), (0.5, false), (0.5, true), (0.5, false)]);
+        assert_eq!(report.auc, Some(0.5));
+        assert_eq!(report.roc.len(), 1);
+        assert_eq!((report.roc[0].true_positive_rate, report.roc[0].false_positive_rate), (1.0, 1.0));
+    }
+
+    #[test]
+    fn auc_counts_ordered_pairs() {
+        // 3 of the 4 positive-negative pairs are ordered
+        assert_eq!(evaluate(&[(0.9, true), (0.7, false), (0.6, true), (0.2, false)]).auc, Some(0.75));
+        // A tied pair counts half
+        assert_eq!(evaluate(&[(0.8, true), (0.5, true), (0.5, false), (0.2, false)]).auc, Some(0.875));
+    }
+
+    #[test]
+    fn one_class_has_no_auc() {
+        let report = evaluate(&[(0.9, true), (0.4, true)]);
+        assert_eq!((report.positives, report.negatives), (2, 0));
+        assert_eq!(report.auc, None);
+        assert!(report.youden.is_none());
+    }
+}
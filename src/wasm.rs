@@ -0,0 +1,99 @@
+//! JavaScript bindings for the rendering core, built with `--features wasm` for wasm32.
+//!
+//! The viewer hands over the decoded base image and raw heatmap once, then calls
+//! `render` whenever the user changes colormap, normalization or blending settings.
+
+use image::RgbaImage;
+use ndarray::Array2;
+use std::str::FromStr;
+use wasm_bindgen::prelude::*;
+
+use crate::colormap::ColorMap;
+use crate::normalize::Normalization;
+use crate::render::{blend_heatmap, colorize_heatmap, BlendMode, BlendOptions};
+
+/// Base image and heatmap kept on the WASM side for repeated re-rendering
+#[wasm_bindgen]
+pub struct OverlayRenderer {
+    base: RgbaImage,
+    heatmap: Array2<f32>,
+}
+
+#[wasm_bindgen]
+impl OverlayRenderer {
+    /// `base_rgba` is row-major RGBA (as in `ImageData.data`), `heatmap` is row-major f32 values
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        base_rgba: Vec<u8>,
+        width: u32,
+        height: u32,
+        heatmap: Vec<f32>,
+        heatmap_rows: usize,
+        heatmap_cols: usize,
+    ) -> Result<OverlayRenderer, JsError> {
+        let base = RgbaImage::from_raw(width, height, base_rgba)
+            .ok_or_else(|| JsError::new("base_rgba length does not match width * height * 4"))?;
+        let heatmap = Array2::from_shape_vec((heatmap_rows, heatmap_cols), heatmap)
+            .map_err(|_| JsError::new("heatmap length does not match heatmap_rows * heatmap_cols"))?;
+        Ok(OverlayRenderer { base, heatmap })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.base.width()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.base.height()
+    }
+
+    /// Replace the heatmap, e.g. when switching between model outputs
+    #[wasm_bindgen(js_name = setHeatmap)]
+    pub fn set_heatmap(&mut self, heatmap: Vec<f32>, rows: usize, cols: usize) -> Result<(), JsError> {
+        self.heatmap = Array2::from_shape_vec((rows, cols), heatmap)
+            .map_err(|_| JsError::new("heatmap length does not match rows * cols"))?;
+        Ok(())
+    }
+
+    /// Render the fused overlay and return RGBA bytes suitable for `new ImageData(...)`
+    pub fn render(
+        &self,
+        colormap: &str,
+        normalization: &str,
+        opacity: f32,
+        blend: &str,
+        threshold: Option<f32>,
+    ) -> Result<Vec<u8>, JsError> {
+        let colormap = ColorMap::from_str(colormap).map_err(|e| JsError::new(&e))?;
+        let normalization = Normalization::from_str(normalization).map_err(|e| JsError::new(&e))?;
+        let mode = BlendMode::from_str(blend).map_err(|e| JsError::new(&e))?;
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(JsError::new("Opacity must be between 0.0 and 1.0"));
+        }
+
+        let options = BlendOptions { opacity, mode, threshold };
+        let mut fused = self.base.clone();
+        blend_heatmap(&mut fused, &self.heatmap, &colormap, &normalization, &options);
+        Ok(fused.into_raw())
+    }
+}
+
+/// Colorize a heatmap on its own (no base image) and return RGBA bytes
+#[wasm_bindgen(js_name = colorizeHeatmap)]
+pub fn colorize_heatmap_js(
+    heatmap: Vec<f32>,
+    rows: usize,
+    cols: usize,
+    colormap: &str,
+    normalization: &str,
+    opacity: f32,
+) -> Result<Vec<u8>, JsError> {
+    let data = Array2::from_shape_vec((rows, cols), heatmap)
+        .map_err(|_| JsError::new("heatmap length does not match rows * cols"))?;
+    let colormap = ColorMap::from_str(colormap).map_err(|e| JsError::new(&e))?;
+    let normalization = Normalization::from_str(normalization).map_err(|e| JsError::new(&e))?;
+    let options = BlendOptions { opacity, ..BlendOptions::default() };
+    let layer = colorize_heatmap(&data, cols as u32, rows as u32, &colormap, &normalization, &options);
+    Ok(layer.into_raw())
+}
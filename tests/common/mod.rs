@@ -0,0 +1,130 @@
+//! Fixtures shared by the integration tests: synthetic DICOM files, synthetic heatmaps and
+//! golden-image comparison.
+
+#![allow(dead_code)]
+
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use image::RgbaImage;
+use ndarray::Array2;
+use std::path::PathBuf;
+
+/// Largest per-channel difference accepted against a golden image
+pub const CHANNEL_TOLERANCE: u8 = 1;
+
+/// Deterministic phantom intensity in 0.0-1.0: a vertical gradient with a bright ellipse and a
+/// dark insert, so windowing and overlays have structure to act on
+fn phantom_intensity(row: u32, col: u32, rows: u32, columns: u32) -> f32 {
+    let y = (row as f32 + 0.5) / rows as f32 * 2.0 - 1.0;
+    let x = (col as f32 + 0.5) / columns as f32 * 2.0 - 1.0;
+    let mut value = 0.15 + 0.2 * (y + 1.0) / 2.0;
+    if (x / 0.8).powi(2) + (y / 0.9).powi(2) <= 1.0 {
+        value = 0.7;
+    }
+    if ((x - 0.3) / 0.2).powi(2) + ((y + 0.2) / 0.25).powi(2) <= 1.0 {
+        value = 0.35;
+    }
+    value
+}
+
+/// Uncompressed, explicit VR little endian MONOCHROME2 DICOM Part 10 file
+pub fn synthetic_dicom(rows: u32, columns: u32, bits_allocated: u16) -> Vec<u8> {
+    let intensities = (0..rows).flat_map(|row| (0..columns).map(move |col| phantom_intensity(row, col, rows, columns)));
+    let (vr, pixel_data) = match bits_allocated {
+        8 => (VR::OB, PrimitiveValue::U8(intensities.map(|v| (v * 255.0) as u8).collect())),
+        16 => (VR::OW, PrimitiveValue::U16(intensities.map(|v| (v * 4095.0) as u16).collect())),
+        bits => panic!("unsupported bits allocated: {}", bits),
+    };
+    let bits_stored = if bits_allocated == 16 { 12 } else { 8 };
+
+    let mut obj = InMemDicomObject::new_empty();
+    obj.put(DataElement::new(tags::SOP_CLASS_UID, VR::UI, uids::SECONDARY_CAPTURE_IMAGE_STORAGE));
+    obj.put(DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, "1.2.826.0.1.3680043.2.1125.1"));
+    obj.put(DataElement::new(tags::MODALITY, VR::CS, "OT"));
+    obj.put(DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)));
+    obj.put(DataElement::new(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2"));
+    obj.put(DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(rows as u16)));
+    obj.put(DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(columns as u16)));
+    obj.put(DataElement::new(tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(bits_allocated)));
+    obj.put(DataElement::new(tags::BITS_STORED, VR::US, PrimitiveValue::from(bits_stored)));
+    obj.put(DataElement::new(tags::HIGH_BIT, VR::US, PrimitiveValue::from(bits_stored - 1)));
+    obj.put(DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, PrimitiveValue::from(0_u16)));
+    obj.put(DataElement::new(tags::PIXEL_DATA, vr, pixel_data));
+
+    let file = obj
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+                .media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.826.0.1.3680043.2.1125.1"),
+        )
+        .expect("valid file meta");
+    let mut bytes = Vec::new();
+    file.write_all(&mut bytes).expect("serialize synthetic DICOM");
+    bytes
+}
+
+/// Two overlapping Gaussian activations, deliberately smaller than the image to exercise resizing
+pub fn synthetic_heatmap(rows: usize, cols: usize) -> Array2<f32> {
+    Array2::from_shape_fn((rows, cols), |(row, col)| {
+        let y = row as f32 / rows as f32;
+        let x = col as f32 / cols as f32;
+        let blob = |cx: f32, cy: f32, sigma: f32, weight: f32| {
+            weight * (-((x - cx).powi(2) + (y - cy).powi(2)) / (2.0 * sigma * sigma)).exp()
+        };
+        blob(0.35, 0.4, 0.15, 1.0) + blob(0.7, 0.65, 0.1, 0.6) - 0.05
+    })
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.png", name))
+}
+
+/// Compare `image` with `tests/golden/<name>.png`, returning a description of any mismatch
+///
+/// Set `UPDATE_GOLDEN=1` to write the current rendering as the new golden instead. On mismatch the
+/// actual image is saved to `target/golden-failures/<name>.png` for inspection.
+pub fn check_golden(name: &str, image: &RgbaImage) -> Result<(), String> {
+    let path = golden_path(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        image.save(&path).map_err(|e| format!("{}: could not write golden: {}", name, e))?;
+        return Ok(());
+    }
+
+    let golden = image::open(&path)
+        .map_err(|e| format!("{}: missing golden {} ({}); run with UPDATE_GOLDEN=1", name, path.display(), e))?
+        .to_rgba8();
+
+    let result = if golden.dimensions() != image.dimensions() {
+        Err(format!("{}: size {:?} differs from golden {:?}", name, image.dimensions(), golden.dimensions()))
+    } else {
+        let mut max_diff = 0;
+        let mut differing = 0;
+        for (actual, expected) in image.pixels().zip(golden.pixels()) {
+            let diff = actual.0.iter().zip(expected.0.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0);
+            if diff > CHANNEL_TOLERANCE {
+                differing += 1;
+            }
+            max_diff = max_diff.max(diff);
+        }
+        if differing == 0 {
+            Ok(())
+        } else {
+            Err(format!("{}: {} pixel(s) exceed tolerance {} (max channel difference {})",
+                        name, differing, CHANNEL_TOLERANCE, max_diff))
+        }
+    };
+
+    if result.is_err() {
+        let failures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("golden-failures");
+        let _ = std::fs::create_dir_all(&failures);
+        let _ = image.save(failures.join(format!("{}.png", name)));
+    }
+    result
+}
+
+/// Panic with every mismatch at once, so a single run shows the full extent of a regression
+pub fn assert_all_golden(failures: Vec<String>) {
+    assert!(failures.is_empty(), "{} golden mismatch(es):\n{}", failures.len(), failures.join("\n"));
+}
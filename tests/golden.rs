@@ -0,0 +1,113 @@
+//! Pixel-exact (within `CHANNEL_TOLERANCE`) golden comparisons of the rendering pipeline.
+//!
+//! Regenerate after an intentional rendering change with `UPDATE_GOLDEN=1 cargo test --test golden`.
+
+mod common;
+
+use common::{assert_all_golden, check_golden, synthetic_dicom, synthetic_heatmap};
+use image::Rgba;
+use rust_dl_heatmap_processing::{
+    Annotation, BlendMode, BlendOptions, ColorMap, DemoOptions, DemoPattern, HeatmapInput, HeatmapPipeline,
+    ImageSource, Normalization,
+};
+
+const COLORMAPS: [ColorMap; 6] = [
+    ColorMap::Red, ColorMap::Hot, ColorMap::Jet, ColorMap::Viridis, ColorMap::Plasma, ColorMap::Coolwarm,
+];
+const NORMALIZATIONS: [Normalization; 3] = [Normalization::MinMax, Normalization::ZScore, Normalization::Percentile];
+const BLEND_MODES: [BlendMode; 3] = [BlendMode::Alpha, BlendMode::Additive, BlendMode::Screen];
+
+fn name<T: std::fmt::Debug>(value: &T) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+fn render(source: ImageSource, heatmap: Option<HeatmapInput>, colormap: ColorMap, normalization: Normalization, blend: BlendOptions) -> image::RgbaImage {
+    let mut builder = HeatmapPipeline::builder()
+        .source(source)
+        .colormap(colormap)
+        .normalization(normalization)
+        .blend(blend);
+    if let Some(heatmap) = heatmap {
+        builder = builder.heatmap(heatmap);
+    }
+    builder.build().expect("valid pipeline").run().expect("pipeline run").image
+}
+
+#[test]
+fn every_colormap_normalization_and_blend_mode_matches_golden() {
+    let dicom = synthetic_dicom(48, 64, 16);
+    let heatmap = synthetic_heatmap(12, 16);
+
+    let mut failures = Vec::new();
+    for colormap in &COLORMAPS {
+        for normalization in &NORMALIZATIONS {
+            for mode in BLEND_MODES {
+                let image = render(
+                    ImageSource::DicomBytes(dicom.clone()),
+                    Some(HeatmapInput::Array(heatmap.clone())),
+                    colormap.clone(),
+                    normalization.clone(),
+                    BlendOptions { opacity: 0.6, mode, threshold: None },
+                );
+                let golden = format!("{}_{}_{}", name(colormap), name(normalization), name(&mode));
+                if let Err(failure) = check_golden(&golden, &image) {
+                    failures.push(failure);
+                }
+            }
+        }
+    }
+    assert_all_golden(failures);
+}
+
+#[test]
+fn threshold_and_annotations_match_golden() {
+    let image = HeatmapPipeline::builder()
+        .source(ImageSource::DicomBytes(synthetic_dicom(48, 64, 16)))
+        .heatmap(HeatmapInput::Array(synthetic_heatmap(12, 16)))
+        .colormap(ColorMap::Viridis)
+        .blend(BlendOptions { opacity: 0.8, mode: BlendMode::Alpha, threshold: Some(0.4) })
+        .annotation(Annotation::Rectangle { x: 10, y: 8, width: 20, height: 16, color: Rgba([0, 255, 0, 255]), thickness: 2 })
+        .annotation(Annotation::Marker { x: 45, y: 30, size: 5, color: Rgba([255, 255, 0, 255]) })
+        .build()
+        .expect("valid pipeline")
+        .run()
+        .expect("pipeline run")
+        .image;
+    assert_all_golden(check_golden("threshold_annotations", &image).err().into_iter().collect());
+}
+
+#[test]
+fn eight_bit_dicom_and_default_gradient_match_golden() {
+    let image = render(
+        ImageSource::DicomBytes(synthetic_dicom(40, 40, 8)),
+        None,
+        ColorMap::Hot,
+        Normalization::MinMax,
+        BlendOptions::default(),
+    );
+    assert_all_golden(check_golden("eight_bit_default_gradient", &image).err().into_iter().collect());
+}
+
+#[test]
+fn demo_patterns_match_golden() {
+    let mut failures = Vec::new();
+    for pattern in [DemoPattern::Gradient, DemoPattern::Blobs, DemoPattern::Phantom, DemoPattern::Noise] {
+        let options = DemoOptions { width: 64, height: 48, seed: 7, pattern: pattern.clone() };
+        let image = render(ImageSource::Demo(options), None, ColorMap::Plasma, Normalization::Percentile, BlendOptions::default());
+        if let Err(failure) = check_golden(&format!("demo_{}", name(&pattern)), &image) {
+            failures.push(failure);
+        }
+    }
+    assert_all_golden(failures);
+}
+
+#[test]
+fn rendering_is_deterministic() {
+    let dicom = synthetic_dicom(48, 64, 16);
+    let heatmap = synthetic_heatmap(12, 16);
+    let first = render(ImageSource::DicomBytes(dicom.clone()), Some(HeatmapInput::Array(heatmap.clone())),
+                       ColorMap::Jet, Normalization::ZScore, BlendOptions::default());
+    let second = render(ImageSource::DicomBytes(dicom), Some(HeatmapInput::Array(heatmap)),
+                        ColorMap::Jet, Normalization::ZScore, BlendOptions::default());
+    assert_eq!(first, second);
+}